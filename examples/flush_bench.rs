@@ -0,0 +1,168 @@
+//! Compares flush policies on a loopback connection: a client pipelines small queries through
+//! a `Pipe` to a stand-in backend that writes each packet of its result sets on its own, and
+//! the proxy's writes to the client are counted.
+//!
+//! cargo run --release --example flush_bench [QUERIES] [PIPELINE_DEPTH]
+extern crate mysql_proxy;
+use mysql_proxy::*;
+use mysql_proxy::flush::FlushPolicy;
+
+extern crate futures;
+extern crate tokio_core;
+
+use std::cell::Cell;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future};
+use futures::stream::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+
+/// A socket that counts the writes made to it
+struct Counted {
+    stream: TcpStream,
+    writes: Rc<Cell<usize>>,
+}
+
+impl Transport for Counted {
+
+    fn poll_read(&self) -> Async<()> {
+        self.stream.poll_read()
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        self.stream.poll_write()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(&self.stream, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        Transport::write(&self.stream, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Transport::flush(&self.stream)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        Transport::shutdown(&self.stream, how)
+    }
+}
+
+struct Forward;
+
+impl PacketHandler for Forward {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+}
+
+/// What the backend answers each query with: a result set with one column and one row
+fn result_set() -> Vec<Packet> {
+    vec![
+        Packet::new(1, &[0x01]),
+        Packet::new(2, b"\x03def\x00\x00\x00\x01a\x00\x0c\x21\x00\x01\x00\x00\x00\xfd\x00\x00\x00\x00\x00"),
+        Packet::new(3, &[0xfe, 0x00, 0x00, 0x02, 0x00]),
+        Packet::new(4, b"\x011"),
+        Packet::new(5, &[0xfe, 0x00, 0x00, 0x02, 0x00]),
+    ]
+}
+
+/// Answer every command on the first connection, a packet per write, until it closes
+fn backend(listener: net::TcpListener) {
+    let (mut socket, _) = listener.accept().unwrap();
+    socket.set_nodelay(true).unwrap();
+    let mut header = [0_u8; 4];
+    while socket.read_exact(&mut header).is_ok() {
+        let len = header[0] as usize | (header[1] as usize) << 8 | (header[2] as usize) << 16;
+        let mut payload = vec![0_u8; len];
+        if socket.read_exact(&mut payload).is_err() || payload.first() == Some(&0x01) {
+            break;
+        }
+        for p in result_set() {
+            socket.write_all(&p.bytes).unwrap();
+        }
+    }
+}
+
+/// Send `queries` queries, `depth` at a time, returning the mean time to answer each batch
+fn client(proxy: SocketAddr, queries: usize, depth: usize) -> Duration {
+    let mut socket = net::TcpStream::connect(proxy).unwrap();
+    socket.set_nodelay(true).unwrap();
+    let batch: Vec<u8> = (0..depth).flat_map(|_| Packet::com_query("SELECT 1").bytes).collect();
+    let answer_len = result_set().iter().map(|p| p.bytes.len()).sum::<usize>() * depth;
+    let mut answer = vec![0_u8; answer_len];
+    let (mut total, batches) = (Duration::default(), queries / depth);
+    for _ in 0..batches {
+        let started = Instant::now();
+        socket.write_all(&batch).unwrap();
+        socket.read_exact(&mut answer).unwrap();
+        total += started.elapsed();
+    }
+    socket.write_all(&Packet::com_quit().bytes).unwrap();
+    total / batches.max(1) as u32
+}
+
+fn run(policy: FlushPolicy, queries: usize, depth: usize) {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let backend_listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_addr = backend_listener.local_addr().unwrap();
+    let backend = thread::spawn(move || backend(backend_listener));
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || client(proxy_addr, queries, depth));
+
+    let writes = Rc::new(Cell::new(0));
+    let counted = writes.clone();
+    let started = Instant::now();
+    let proxied = listener.incoming().into_future().map_err(|(e, _)| e)
+        .and_then(|(accepted, _)| {
+            let (socket, _) = accepted.unwrap();
+            TcpStream::connect(&backend_addr, &handle).map(|server| (socket, server))
+        })
+        .and_then(|(socket, server)| {
+            socket.set_nodelay(true)?;
+            server.set_nodelay(true)?;
+            Ok(Pipe::new(Rc::new(Counted { stream: socket, writes: counted }), Rc::new(server), Forward)
+                .with_flush_policy(policy, &handle))
+        })
+        .and_then(|pipe| pipe.or_else(|_| Ok(())));
+    core.run(proxied).unwrap();
+    let elapsed = started.elapsed();
+    let latency = client.join().unwrap();
+    backend.join().unwrap();
+
+    println!("{:<60} {:>10.0} queries/s {:>8} us/batch {:>6.2} writes/query",
+             format!("{:?}", policy),
+             queries as f64 / elapsed.as_secs_f64(),
+             latency.as_micros(),
+             writes.get() as f64 / queries as f64);
+}
+
+fn main() {
+    let queries = env::args().nth(1).map(|n| n.parse().unwrap()).unwrap_or(100_000);
+    let depth = env::args().nth(2).map(|n| n.parse().unwrap()).unwrap_or(8);
+    println!("{} queries, {} at a time", queries, depth);
+    for policy in &[
+        FlushPolicy::Immediate,
+        FlushPolicy::PerResponse { max_bytes: 65536 },
+        FlushPolicy::Batch { delay_us: 50, max_bytes: 65536 },
+        FlushPolicy::Batch { delay_us: 200, max_bytes: 65536 },
+    ] {
+        run(*policy, queries, depth);
+    }
+}
@@ -2,19 +2,13 @@
 extern crate mysql_proxy;
 use mysql_proxy::*;
 
-#[macro_use]
-extern crate log;
 extern crate env_logger;
-#[macro_use]
 extern crate futures;
-#[macro_use]
 extern crate tokio_core;
-extern crate byteorder;
 
 use std::rc::Rc;
 use std::env;
 use std::net::{SocketAddr};
-use std::str;
 
 use futures::{Future};
 use futures::stream::Stream;
@@ -78,22 +72,3 @@ impl PacketHandler for PassthroughHandler {
     }
 
 }
-
-#[allow(dead_code)]
-pub fn print_packet_chars(buf: &[u8]) {
-    print!("[");
-    for i in 0..buf.len() {
-        print!("{} ", buf[i] as char);
-    }
-    println!("]");
-}
-
-#[allow(dead_code)]
-pub fn print_packet_bytes(buf: &[u8]) {
-    print!("[");
-    for i in 0..buf.len() {
-        if i%8==0 { println!(""); }
-        print!("{:#04x} ",buf[i]);
-    }
-    println!("]");
-}
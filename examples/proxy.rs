@@ -1,20 +1,20 @@
 //! MySQL Proxy Server
 extern crate mysql_proxy;
 use mysql_proxy::*;
+use mysql_proxy::attrs::ConnectAttrsHandler;
+use mysql_proxy::dump::{DumpHandler, PacketDumper};
+use mysql_proxy::greeting::{GreetingConfig, GreetingHandler};
+use mysql_proxy::variables::VariablesHandler;
+use mysql_proxy::xprotocol::XProtocolGuard;
 
-#[macro_use]
-extern crate log;
 extern crate env_logger;
-#[macro_use]
 extern crate futures;
-#[macro_use]
 extern crate tokio_core;
-extern crate byteorder;
 
 use std::rc::Rc;
 use std::env;
+use std::io;
 use std::net::{SocketAddr};
-use std::str;
 
 use futures::{Future};
 use futures::stream::Stream;
@@ -33,6 +33,15 @@ fn main() {
     let mysql_addr = env::args().nth(2).unwrap_or("127.0.0.1:3306".to_string());
     let mysql_addr = mysql_addr.parse::<SocketAddr>().unwrap();
 
+    // dump every packet as JSON to stdout when PACKET_DUMP is set
+    let dumper = PacketDumper::to_writer(Box::new(io::stdout()));
+    if env::var_os("PACKET_DUMP").is_some() {
+        dumper.enable();
+    }
+
+    // report a different server version to clients when SERVER_VERSION is set
+    let greeting = GreetingConfig { server_version: env::var("SERVER_VERSION").ok(), ..Default::default() };
+
     // Create the tokio event loop that will drive this server
     let mut l = Core::new().unwrap();
 
@@ -44,13 +53,18 @@ fn main() {
     println!("Listening on: {}", bind_addr);
 
     // for each incoming connection
-    let done = socket.incoming().for_each(move |(socket, _)| {
+    let done = socket.incoming().for_each(move |(socket, addr)| {
+        let handler = VariablesHandler::new(DemoHandler {}).with_variable("proxy_backend", &mysql_addr.to_string());
+        let handler = GreetingHandler::new(greeting.clone(), handler);
+        let handler = ConnectAttrsHandler::new(Default::default(), addr, handler);
+        let handler = XProtocolGuard::new(handler);
+        let handler = DumpHandler::new(dumper.clone(), &addr.to_string(), handler);
 
         // create a future to serve requests
         let future = TcpStream::connect(&mysql_addr, &handle)
             .and_then(move |mysql| { Ok((socket, mysql)) })
             .and_then(move |(client, server)|
-                { Pipe::new(Rc::new(client), Rc::new(server), DemoHandler {})
+                { Pipe::new(Rc::new(client), Rc::new(server), handler)
         });
 
         // tell the tokio reactor to run the future
@@ -70,7 +84,6 @@ struct DemoHandler {}
 impl PacketHandler for DemoHandler {
 
     fn handle_request(&mut self, p: &Packet) -> Action {
-        print_packet_chars(&p.bytes);
         match p.packet_type() {
             Ok(PacketType::ComQuery) => {
                 // ComQuery packets just contain a SQL string as the payload
@@ -106,23 +119,3 @@ impl PacketHandler for DemoHandler {
     }
 
 }
-#[allow(dead_code)]
-pub fn print_packet_chars(buf: &[u8]) {
-    print!("[");
-    for i in 0..buf.len() {
-        print!("{} ", buf[i] as char);
-    }
-    println!("]");
-}
-
-#[allow(dead_code)]
-pub fn print_packet_bytes(buf: &[u8]) {
-    print!("[");
-    for i in 0..buf.len() {
-        if i%8==0 { println!(""); }
-        print!("{:#04x} ",buf[i]);
-    }
-    println!("]");
-}
-
-
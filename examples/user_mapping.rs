@@ -0,0 +1,25 @@
+//! MySQL Proxy Server that authenticates clients itself and maps them to backend credentials
+extern crate mysql_proxy;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::server;
+
+extern crate env_logger;
+
+use std::env;
+use std::net::{SocketAddr};
+
+fn main() {
+    env_logger::init().unwrap();
+
+    // determine addresses for the proxy to bind to, e.g. "0.0.0.0:3307,[::]:3307"
+    let bind_addrs = env::args().nth(1).unwrap_or("127.0.0.1:3307".to_string());
+    let bind_addrs = bind_addrs.split(',')
+        .map(|addr| addr.trim().parse::<SocketAddr>().unwrap())
+        .collect::<Vec<_>>();
+
+    // load the user mappings, routing groups and listener profiles
+    let config_file = env::args().nth(2).unwrap_or("proxy.toml".to_string());
+    let config = ProxyConfig::from_file(&config_file).unwrap();
+
+    server::run(&config, bind_addrs).unwrap();
+}
@@ -0,0 +1,178 @@
+//! Source address allow/deny lists.
+//!
+//! A listener's `AccessList` is checked as soon as a connection is accepted, before the proxy
+//! sends its greeting. Each user mapping may carry its own `AccessList` as well, which is
+//! checked once the client has authenticated, along with the certificate identities it
+//! requires of the user's clients.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// MySQL error ER_HOST_NOT_PRIVILEGED
+pub const ER_HOST_NOT_PRIVILEGED: u16 = 1130;
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8`. A bare address matches only itself.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid prefix length /{} for {}", prefix_len, addr)));
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Whether `ip` is within this network. IPv4-mapped IPv6 addresses match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix_len)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = (bits - prefix_len) as u32;
+    net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid network '{}'", s));
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(s[i + 1..].parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Networks that may and may not connect. Deny rules take precedence, and an empty allow
+/// list allows everything that isn't denied.
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct AccessList {
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+    /// certificate identities a user's clients must present one of, empty for any client.
+    /// Only user access lists can have them, as listeners check theirs before TLS.
+    #[serde(default)]
+    pub identities: Vec<String>,
+}
+
+impl AccessList {
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    /// Whether a client with a verified certificate for `identity`, if it has one, may log in
+    pub fn permits_identity(&self, identity: Option<&str>) -> bool {
+        self.identities.is_empty() || identity.is_some_and(|identity| self.identities.iter().any(|i| i == identity))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.identities.is_empty()
+    }
+}
+
+#[derive(Debug,Default)]
+struct Counters {
+    rejected_connections: AtomicUsize,
+    rejected_logins: AtomicUsize,
+}
+
+/// Enforces a listener's access list, counting the attempts it turns away. Clones share
+/// their counters.
+#[derive(Clone,Debug,Default)]
+pub struct AccessControl {
+    listener: AccessList,
+    counters: Arc<Counters>,
+}
+
+impl AccessControl {
+
+    pub fn new(listener: AccessList) -> Self {
+        AccessControl { listener, counters: Arc::default() }
+    }
+
+    /// Check a newly accepted connection against the listener's access list
+    pub fn check_connection(&self, peer: &SocketAddr) -> bool {
+        let permitted = self.listener.permits(peer.ip());
+        if !permitted {
+            info!("Refusing connection from {}", peer);
+            self.counters.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        permitted
+    }
+
+    /// Check an authenticated user's own access list, for a client with a verified certificate
+    /// for `identity` if it presented one
+    pub fn check_user(&self, user: &str, access: &AccessList, peer: &SocketAddr, identity: Option<&str>) -> bool {
+        let permitted = access.permits(peer.ip()) && access.permits_identity(identity);
+        if !permitted {
+            match identity {
+                Some(identity) => info!("Refusing user '{}' ({}) from {}", user, identity, peer),
+                None => info!("Refusing user '{}' from {}", user, peer),
+            }
+            self.counters.rejected_logins.fetch_add(1, Ordering::Relaxed);
+        }
+        permitted
+    }
+
+    /// Connections refused by the listener's access list
+    pub fn rejected_connections(&self) -> usize {
+        self.counters.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Logins refused by per-user access lists
+    pub fn rejected_logins(&self) -> usize {
+        self.counters.rejected_logins.load(Ordering::Relaxed)
+    }
+}
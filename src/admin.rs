@@ -0,0 +1,1195 @@
+//! MySQL admin interface to the proxy's configuration.
+//!
+//! With a `[management.admin]` section, the proxy also answers MySQL clients logged in as
+//! the admin user, on a listener of its own, where the users, backends and table rules the
+//! management API changes are tables in three layers, as in ProxySQL:
+//!
+//! - `users`, `backends` and `table_rules` are the memory layer, read with `SELECT` and
+//!   changed with `INSERT`, `UPDATE` and `DELETE` without affecting the proxy
+//! - `runtime_users`, `runtime_backends` and `runtime_table_rules` are what the proxy uses,
+//!   and can only be read
+//! - the disk layer is a JSON file at `path`
+//!
+//! `stats_digests` lists the query statistics by fingerprint, if they're kept, with the sizes
+//! of their results: the total, median, 99th percentile and largest rows and bytes returned.
+//! `stats_connections` lists the sessions running, with their labels as `name=value` pairs
+//! separated by commas.
+//!
+//! `LOAD USERS TO RUNTIME` puts the users in memory to use and `SAVE USERS FROM RUNTIME`
+//! copies the ones in use back to memory, while `SAVE USERS TO DISK` and `LOAD USERS FROM
+//! DISK` copy them between memory and disk. `BACKENDS` and `TABLE RULES` work the same way.
+//! The memory layer starts as a copy of the runtime one and the disk layer is only read when
+//! asked to, so the configuration file still decides how the proxy starts.
+//!
+//! Clients have `login_timeout_secs` to log in, and at most `max_sessions` of them are
+//! served at once, logging in or logged in: the others are told there are too many
+//! connections.
+//!
+//! This isn't a SQL engine: `SELECT` takes `*` or a list of columns, `WHERE` takes
+//! `column = value` conditions joined by `AND`, and `INSERT` needs a list of columns. Users'
+//! access lists, tenants, attributes and quotas aren't columns, so a user keeps the ones it
+//! has and new users have none. Row filters are left as they are.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use super::{Packet, PacketType};
+use super::codec::{ok_packet, text_result_set, write_lenenc_int, AuthSwitchRequest, ColumnDefinition,
+                   HandshakeResponse, HandshakeV10, TextRow, SERVER_STATUS_AUTOCOMMIT};
+use super::connect::BackendAddr;
+use super::listener::ER_CON_COUNT_ERROR;
+use super::management::{ConnectionInfo, Deadline, Management};
+use super::protocol::{generate_scramble, native_password_hash, verify_native_password, CLIENT_PLUGIN_AUTH,
+                      CLIENT_PROTOCOL_41, CLIENT_SECURE_CONNECTION, ER_ACCESS_DENIED_ERROR, NATIVE_PASSWORD_PLUGIN};
+use super::rules::{RuleAction, TableRule};
+use super::sidechannel::read_packet;
+use super::stats::DigestStats;
+use super::unknown::ER_UNKNOWN_COM_ERROR;
+use super::users::UserMapping;
+
+/// MySQL error ER_DUP_ENTRY
+pub const ER_DUP_ENTRY: u16 = 1062;
+/// MySQL error ER_BAD_FIELD_ERROR
+pub const ER_BAD_FIELD_ERROR: u16 = 1054;
+/// MySQL error ER_PARSE_ERROR
+pub const ER_PARSE_ERROR: u16 = 1064;
+/// MySQL error ER_UNKNOWN_ERROR
+pub const ER_UNKNOWN_ERROR: u16 = 1105;
+/// MySQL error ER_WRONG_VALUE_COUNT_ON_ROW
+pub const ER_WRONG_VALUE_COUNT_ON_ROW: u16 = 1136;
+/// MySQL error ER_NO_SUCH_TABLE
+pub const ER_NO_SUCH_TABLE: u16 = 1146;
+
+/// The tables of the memory layer, each with a `runtime_` counterpart
+pub const TABLES: [&str; 3] = ["users", "backends", "table_rules"];
+
+/// The tables that show what the proxy is doing, which can only be read
+pub const STATS_TABLES: [&str; 2] = ["stats_digests", "stats_connections"];
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    /// the account admin clients log in as, with the password in plain text or as a `*HEX`
+    /// mysql_native_password hash
+    pub user: String,
+    pub password: String,
+    /// where `SAVE ... TO DISK` writes the tables, as JSON
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// how long a client has to log in
+    #[serde(default = "AdminConfig::default_login_timeout_secs")]
+    pub login_timeout_secs: u64,
+    /// the most clients served at once, logging in or logged in
+    #[serde(default = "AdminConfig::default_max_sessions")]
+    pub max_sessions: usize,
+}
+
+impl AdminConfig {
+
+    fn default_login_timeout_secs() -> u64 {
+        10
+    }
+
+    fn default_max_sessions() -> usize {
+        8
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.user.is_empty() || self.password.is_empty() {
+            return Err("user and password must not be empty".to_string());
+        }
+        if self.login_timeout_secs == 0 || self.max_sessions == 0 {
+            return Err("login_timeout_secs and max_sessions must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    fn password_hash(&self) -> String {
+        if self.password.len() == 41 && self.password.starts_with('*') {
+            self.password.clone()
+        } else {
+            native_password_hash(&self.password)
+        }
+    }
+}
+
+/// The answer to a statement
+#[derive(Clone,Debug,PartialEq)]
+pub enum Reply {
+    Rows { columns: Vec<String>, rows: Vec<Vec<Option<String>>> },
+    /// how many rows a statement changed
+    Affected(u64),
+}
+
+/// A statement that failed, with the MySQL error a server would have answered it with
+#[derive(Clone,Debug,PartialEq)]
+pub struct AdminError {
+    pub code: u16,
+    pub state: [u8; 5],
+    pub msg: String,
+}
+
+impl AdminError {
+
+    fn syntax(msg: String) -> Self {
+        AdminError { code: ER_PARSE_ERROR, state: *b"42000", msg }
+    }
+
+    fn no_such_table(table: &str) -> Self {
+        AdminError { code: ER_NO_SUCH_TABLE, state: *b"42S02", msg: format!("Table '{}' doesn't exist", table) }
+    }
+
+    fn bad_field(column: &str) -> Self {
+        AdminError { code: ER_BAD_FIELD_ERROR, state: *b"42S22", msg: format!("Unknown column '{}'", column) }
+    }
+
+    fn invalid(msg: String) -> Self {
+        AdminError { code: ER_UNKNOWN_ERROR, state: *b"HY000", msg }
+    }
+
+    fn to_packet(&self) -> Packet {
+        Packet::error_packet(self.code, self.state, self.msg.clone())
+    }
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ERROR {} ({}): {}", self.code, String::from_utf8_lossy(&self.state), self.msg)
+    }
+}
+
+/// A row of one of the tables
+trait Row: Clone {
+
+    fn columns() -> &'static [&'static str];
+
+    /// A row with every column at its default
+    fn blank() -> Self;
+
+    fn get(&self, column: &str) -> Option<String>;
+
+    fn set(&mut self, column: &str, value: Option<String>) -> Result<(), String>;
+
+    fn validate(&self) -> Result<(), String>;
+
+    /// What no two rows of the table may share
+    fn key(&self) -> Option<String> {
+        None
+    }
+
+    /// Take what the table has no columns for from the row with the same key in `previous`
+    fn restore(&mut self, _previous: &[Self]) {}
+}
+
+impl Row for UserMapping {
+
+    fn columns() -> &'static [&'static str] {
+        &["user", "password", "backend_user", "backend_password", "default_group", "auth_passthrough", "old_password"]
+    }
+
+    fn blank() -> Self {
+        UserMapping::default()
+    }
+
+    fn get(&self, column: &str) -> Option<String> {
+        match column {
+            "user" => Some(self.user.clone()),
+            "password" => self.password.clone(),
+            "backend_user" => Some(self.backend_user.clone()),
+            "backend_password" => Some(self.backend_password.clone()),
+            "default_group" => Some(self.default_group.clone()),
+            "auth_passthrough" => Some(flag(self.auth_passthrough)),
+            "old_password" => Some(flag(self.old_password)),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, column: &str, value: Option<String>) -> Result<(), String> {
+        match column {
+            "user" => self.user = value.unwrap_or_default(),
+            "password" => self.password = value,
+            "backend_user" => self.backend_user = value.unwrap_or_default(),
+            "backend_password" => self.backend_password = value.unwrap_or_default(),
+            "default_group" => self.default_group = value.unwrap_or_default(),
+            "auth_passthrough" => self.auth_passthrough = boolean(column, value)?,
+            "old_password" => self.old_password = boolean(column, value)?,
+            _ => return Err(format!("Unknown column '{}'", column)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.user.is_empty() || self.default_group.is_empty() {
+            return Err("user and default_group must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Option<String> {
+        Some(self.user.clone())
+    }
+
+    fn restore(&mut self, previous: &[Self]) {
+        if let Some(previous) = previous.iter().find(|u| u.user == self.user) {
+            self.access = previous.access.clone();
+            self.tenant = previous.tenant.clone();
+            self.attributes = previous.attributes.clone();
+            self.quota = previous.quota.clone();
+        }
+    }
+}
+
+/// A backend of a routing group, with its weight
+#[derive(Clone,Debug,PartialEq)]
+struct BackendRow {
+    routing_group: String,
+    backend: String,
+    weight: u32,
+}
+
+impl Row for BackendRow {
+
+    fn columns() -> &'static [&'static str] {
+        &["routing_group", "backend", "weight"]
+    }
+
+    fn blank() -> Self {
+        BackendRow { routing_group: String::new(), backend: String::new(), weight: 1 }
+    }
+
+    fn get(&self, column: &str) -> Option<String> {
+        match column {
+            "routing_group" => Some(self.routing_group.clone()),
+            "backend" => Some(self.backend.clone()),
+            "weight" => Some(self.weight.to_string()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, column: &str, value: Option<String>) -> Result<(), String> {
+        match column {
+            "routing_group" => self.routing_group = value.unwrap_or_default(),
+            "backend" => self.backend = value.unwrap_or_default(),
+            "weight" => {
+                self.weight = value.and_then(|v| v.parse().ok()).ok_or("weight must be a whole number")?;
+            },
+            _ => return Err(format!("Unknown column '{}'", column)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.routing_group.is_empty() {
+            return Err("routing_group must not be empty".to_string());
+        }
+        self.backend.parse::<BackendAddr>().map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn key(&self) -> Option<String> {
+        Some(format!("{}-{}", self.routing_group, self.backend))
+    }
+}
+
+impl Row for TableRule {
+
+    fn columns() -> &'static [&'static str] {
+        &["table_name", "columns", "allow_users", "action"]
+    }
+
+    fn blank() -> Self {
+        TableRule { table: String::new(), columns: vec![], allow_users: vec![], action: RuleAction::Block }
+    }
+
+    fn get(&self, column: &str) -> Option<String> {
+        match column {
+            "table_name" => Some(self.table.clone()),
+            "columns" => Some(self.columns.join(",")),
+            "allow_users" => Some(self.allow_users.join(",")),
+            "action" => serde_json::to_value(self.action).ok().and_then(|v| v.as_str().map(|s| s.to_string())),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, column: &str, value: Option<String>) -> Result<(), String> {
+        match column {
+            "table_name" => self.table = value.unwrap_or_default(),
+            "columns" => self.columns = list(value),
+            "allow_users" => self.allow_users = list(value),
+            "action" => {
+                let action = value.unwrap_or_default().to_lowercase();
+                self.action = serde_json::from_value(Value::String(action.clone()))
+                    .map_err(|_| format!("Unknown action '{}'", action))?;
+            },
+            _ => return Err(format!("Unknown column '{}'", column)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        TableRule::validate(self)
+    }
+}
+
+/// A running session, as `stats_connections` lists it
+#[derive(Clone,Debug)]
+struct ConnectionRow(ConnectionInfo);
+
+impl Row for ConnectionRow {
+
+    fn columns() -> &'static [&'static str] {
+        &["id", "listener", "user", "client", "backend", "connected_at", "labels"]
+    }
+
+    fn blank() -> Self {
+        ConnectionRow(ConnectionInfo {
+            id: 0,
+            listener: String::new(),
+            user: String::new(),
+            client: ([0, 0, 0, 0], 0).into(),
+            backend: String::new(),
+            connected_at: 0,
+            labels: BTreeMap::new(),
+        })
+    }
+
+    fn get(&self, column: &str) -> Option<String> {
+        let connection = &self.0;
+        match column {
+            "id" => Some(connection.id.to_string()),
+            "listener" => Some(connection.listener.clone()),
+            "user" => Some(connection.user.clone()),
+            "client" => Some(connection.client.to_string()),
+            "backend" => Some(connection.backend.clone()),
+            "connected_at" => Some(connection.connected_at.to_string()),
+            "labels" => {
+                let labels: Vec<String> = connection.labels.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+                Some(labels.join(","))
+            },
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, column: &str, _: Option<String>) -> Result<(), String> {
+        Err(format!("{} can't be changed", column))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A fingerprint's statistics, as `stats_digests` lists them
+#[derive(Clone,Debug)]
+struct DigestRow(DigestStats);
+
+impl Row for DigestRow {
+
+    fn columns() -> &'static [&'static str] {
+        &["fingerprint", "count", "errors", "total_time_us", "max_time_us", "rows_sum", "rows_p50", "rows_p99",
+          "rows_max", "bytes_sum", "bytes_p50", "bytes_p99", "bytes_max"]
+    }
+
+    fn blank() -> Self {
+        DigestRow(DigestStats::default())
+    }
+
+    fn get(&self, column: &str) -> Option<String> {
+        let digest = &self.0;
+        let value = match column {
+            "fingerprint" => return Some(digest.fingerprint.clone()),
+            "count" => digest.count,
+            "errors" => digest.errors,
+            "total_time_us" => digest.total_time_us,
+            "max_time_us" => digest.max_time_us,
+            "rows_sum" => digest.rows.sum,
+            "rows_p50" => digest.rows.quantile(0.5)?,
+            "rows_p99" => digest.rows.quantile(0.99)?,
+            "rows_max" => digest.rows.max,
+            "bytes_sum" => digest.bytes.sum,
+            "bytes_p50" => digest.bytes.quantile(0.5)?,
+            "bytes_p99" => digest.bytes.quantile(0.99)?,
+            "bytes_max" => digest.bytes.max,
+            _ => return None,
+        };
+        Some(value.to_string())
+    }
+
+    fn set(&mut self, column: &str, _: Option<String>) -> Result<(), String> {
+        Err(format!("{} can't be changed", column))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn flag(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
+
+fn boolean(column: &str, value: Option<String>) -> Result<bool, String> {
+    match value.map(|v| v.to_lowercase()).as_deref() {
+        Some("1") | Some("true") => Ok(true),
+        Some("0") | Some("false") => Ok(false),
+        _ => Err(format!("{} must be 0 or 1", column)),
+    }
+}
+
+/// A comma separated list
+fn list(value: Option<String>) -> Vec<String> {
+    value.unwrap_or_default().split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+/// A row as saved to disk, by column
+type SavedRow = BTreeMap<String, Option<String>>;
+
+/// What statements do to a table, whatever its rows
+trait Table {
+
+    fn select(&self, columns: Option<&[String]>, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError>;
+
+    fn insert(&mut self, columns: &[String], values: Vec<Vec<Option<String>>>) -> Result<Reply, AdminError>;
+
+    fn update(&mut self, changes: Vec<(String, Option<String>)>, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError>;
+
+    fn delete(&mut self, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError>;
+
+    fn save_rows(&self) -> Vec<SavedRow>;
+
+    fn load_rows(&mut self, saved: &[SavedRow]) -> Result<(), AdminError>;
+}
+
+/// The name of column `name` of `R`, whatever its case
+fn column<R: Row>(name: &str) -> Result<&'static str, AdminError> {
+    R::columns().iter().find(|c| c.eq_ignore_ascii_case(name)).cloned().ok_or_else(|| AdminError::bad_field(name))
+}
+
+fn matches<R: Row>(row: &R, filter: &[(&str, Option<String>)]) -> bool {
+    filter.iter().all(|&(column, ref value)| row.get(column) == *value)
+}
+
+fn resolve<R: Row>(filter: &[(String, Option<String>)]) -> Result<Vec<(&'static str, Option<String>)>, AdminError> {
+    filter.iter().map(|(name, value)| Ok((column::<R>(name)?, value.clone()))).collect()
+}
+
+/// Fail if two rows share a key
+fn check_keys<R: Row>(rows: &[R]) -> Result<(), AdminError> {
+    let mut keys = HashSet::new();
+    for key in rows.iter().filter_map(|r| r.key()) {
+        if !keys.insert(key.clone()) {
+            return Err(AdminError { code: ER_DUP_ENTRY, state: *b"23000", msg: format!("Duplicate entry '{}'", key) });
+        }
+    }
+    Ok(())
+}
+
+impl<R: Row> Table for Vec<R> {
+
+    fn select(&self, columns: Option<&[String]>, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError> {
+        let columns = match columns {
+            Some(names) => names.iter().map(|name| column::<R>(name)).collect::<Result<Vec<_>, _>>()?,
+            None => R::columns().to_vec(),
+        };
+        let filter = resolve::<R>(filter)?;
+        let rows = self.iter().filter(|r| matches(*r, &filter))
+            .map(|r| columns.iter().map(|c| r.get(c)).collect())
+            .collect();
+        Ok(Reply::Rows { columns: columns.iter().map(|c| c.to_string()).collect(), rows })
+    }
+
+    fn insert(&mut self, columns: &[String], values: Vec<Vec<Option<String>>>) -> Result<Reply, AdminError> {
+        let columns = columns.iter().map(|name| column::<R>(name)).collect::<Result<Vec<_>, _>>()?;
+        let mut rows = self.clone();
+        for (i, values) in values.into_iter().enumerate() {
+            if values.len() != columns.len() {
+                let msg = format!("Column count doesn't match value count at row {}", i + 1);
+                return Err(AdminError { code: ER_WRONG_VALUE_COUNT_ON_ROW, state: *b"21S01", msg });
+            }
+            let mut row = R::blank();
+            for (column, value) in columns.iter().zip(values) {
+                row.set(column, value).map_err(AdminError::invalid)?;
+            }
+            row.validate().map_err(AdminError::invalid)?;
+            rows.push(row);
+        }
+        check_keys(&rows)?;
+        let inserted = rows.len() - self.len();
+        *self = rows;
+        Ok(Reply::Affected(inserted as u64))
+    }
+
+    fn update(&mut self, changes: Vec<(String, Option<String>)>, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError> {
+        let changes = resolve::<R>(&changes)?;
+        let filter = resolve::<R>(filter)?;
+        let mut rows = self.clone();
+        let mut updated = 0;
+        for row in rows.iter_mut().filter(|r| matches(&**r, &filter)) {
+            for (column, value) in &changes {
+                row.set(column, value.clone()).map_err(AdminError::invalid)?;
+            }
+            row.validate().map_err(AdminError::invalid)?;
+            updated += 1;
+        }
+        check_keys(&rows)?;
+        *self = rows;
+        Ok(Reply::Affected(updated))
+    }
+
+    fn delete(&mut self, filter: &[(String, Option<String>)]) -> Result<Reply, AdminError> {
+        let filter = resolve::<R>(filter)?;
+        let before = self.len();
+        self.retain(|r| !matches(r, &filter));
+        Ok(Reply::Affected((before - self.len()) as u64))
+    }
+
+    fn save_rows(&self) -> Vec<SavedRow> {
+        self.iter().map(|r| R::columns().iter().map(|c| (c.to_string(), r.get(c))).collect()).collect()
+    }
+
+    fn load_rows(&mut self, saved: &[SavedRow]) -> Result<(), AdminError> {
+        let mut rows = vec![];
+        for values in saved {
+            let mut row = R::blank();
+            for (name, value) in values {
+                row.set(column::<R>(name)?, value.clone()).map_err(AdminError::invalid)?;
+            }
+            row.validate().map_err(AdminError::invalid)?;
+            row.restore(self);
+            rows.push(row);
+        }
+        check_keys(&rows)?;
+        *self = rows;
+        Ok(())
+    }
+}
+
+/// Every table of a layer
+#[derive(Clone,Debug,Default)]
+struct Tables {
+    users: Vec<UserMapping>,
+    backends: Vec<BackendRow>,
+    table_rules: Vec<TableRule>,
+}
+
+impl Tables {
+
+    fn table(&self, name: &str) -> Option<&dyn Table> {
+        match name {
+            "users" => Some(&self.users),
+            "backends" => Some(&self.backends),
+            "table_rules" => Some(&self.table_rules),
+            _ => None,
+        }
+    }
+
+    fn table_mut(&mut self, name: &str) -> Option<&mut dyn Table> {
+        match name {
+            "users" => Some(&mut self.users),
+            "backends" => Some(&mut self.backends),
+            "table_rules" => Some(&mut self.table_rules),
+            _ => None,
+        }
+    }
+
+    /// Replace table `name` with the one in `other`
+    fn copy_from(&mut self, name: &str, other: Tables) {
+        match name {
+            "users" => self.users = other.users,
+            "backends" => self.backends = other.backends,
+            "table_rules" => self.table_rules = other.table_rules,
+            _ => {},
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Layer {
+    Disk,
+    Memory,
+    Runtime,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Statement {
+    Select { table: String, columns: Option<Vec<String>>, filter: Vec<(String, Option<String>)> },
+    Insert { table: String, columns: Vec<String>, values: Vec<Vec<Option<String>>> },
+    Update { table: String, changes: Vec<(String, Option<String>)>, filter: Vec<(String, Option<String>)> },
+    Delete { table: String, filter: Vec<(String, Option<String>)> },
+    /// `LOAD` or `SAVE` a table from one layer to another
+    Copy { table: String, from: Layer, to: Layer },
+    ShowTables,
+    /// `SET`, which clients send when they connect, and which changes nothing here
+    Set,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Token {
+    Word(String),
+    /// a backquoted identifier, which is never a keyword
+    Quoted(String),
+    Str(String),
+    Number(String),
+    Punct(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Word(ref s) | Token::Number(ref s) => write!(f, "{}", s),
+            Token::Quoted(ref s) => write!(f, "`{}`", s),
+            Token::Str(ref s) => write!(f, "'{}'", s),
+            Token::Punct(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, AdminError> {
+    let mut tokens = vec![];
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') if c != '`' => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('0') => s.push('\0'),
+                            Some(escaped) => s.push(escaped),
+                            None => break,
+                        },
+                        // doubled quotes stand for themselves
+                        Some(q) if q == c && chars.peek() == Some(&c) => {
+                            chars.next();
+                            s.push(c);
+                        },
+                        Some(q) if q == c => {
+                            tokens.push(if c == '`' { Token::Quoted(s) } else { Token::Str(s) });
+                            break;
+                        },
+                        Some(other) => s.push(other),
+                        None => return Err(AdminError::syntax(format!("Unterminated {}", c))),
+                    }
+                }
+            },
+            _ if c.is_whitespace() => {},
+            _ if c.is_ascii_digit() => {
+                let mut s = c.to_string();
+                while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit() || **d == '.') {
+                    s.push(d);
+                    chars.next();
+                }
+                tokens.push(Token::Number(s));
+            },
+            _ if c.is_alphanumeric() || c == '_' || c == '$' || c == '@' => {
+                let mut s = c.to_string();
+                while let Some(&d) = chars.peek().filter(|d| d.is_alphanumeric() || **d == '_' || **d == '$' || **d == '@') {
+                    s.push(d);
+                    chars.next();
+                }
+                tokens.push(Token::Word(s));
+            },
+            _ => tokens.push(Token::Punct(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn unexpected(&self) -> AdminError {
+        match self.peek() {
+            Some(token) => AdminError::syntax(format!("Syntax error near '{}'", token)),
+            None => AdminError::syntax("Unexpected end of statement".to_string()),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(ref w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), AdminError> {
+        if self.keyword(keyword) { Ok(()) } else { Err(self.unexpected()) }
+    }
+
+    fn punct(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(c));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, c: char) -> Result<(), AdminError> {
+        if self.punct(c) { Ok(()) } else { Err(self.unexpected()) }
+    }
+
+    /// A table or column name, lowercased
+    fn identifier(&mut self) -> Result<String, AdminError> {
+        match self.peek().cloned() {
+            Some(Token::Word(name)) | Some(Token::Quoted(name)) => {
+                self.pos += 1;
+                Ok(name.to_lowercase())
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn identifiers(&mut self) -> Result<Vec<String>, AdminError> {
+        let mut names = vec![self.identifier()?];
+        while self.punct(',') {
+            names.push(self.identifier()?);
+        }
+        Ok(names)
+    }
+
+    /// A literal, `None` for `NULL`
+    fn value(&mut self) -> Result<Option<String>, AdminError> {
+        let value = match self.peek().cloned() {
+            Some(Token::Str(s)) | Some(Token::Number(s)) => Some(s),
+            Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("NULL") => None,
+            Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("TRUE") => Some("1".to_string()),
+            Some(Token::Word(ref w)) if w.eq_ignore_ascii_case("FALSE") => Some("0".to_string()),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        Ok(value)
+    }
+
+    /// `column = value`
+    fn assignment(&mut self) -> Result<(String, Option<String>), AdminError> {
+        let column = self.identifier()?;
+        self.expect_punct('=')?;
+        Ok((column, self.value()?))
+    }
+
+    /// An optional `WHERE` clause
+    fn filter(&mut self) -> Result<Vec<(String, Option<String>)>, AdminError> {
+        let mut filter = vec![];
+        if self.keyword("WHERE") {
+            filter.push(self.assignment()?);
+            while self.keyword("AND") {
+                filter.push(self.assignment()?);
+            }
+        }
+        Ok(filter)
+    }
+
+    fn layer(&mut self) -> Result<Layer, AdminError> {
+        if self.keyword("DISK") {
+            Ok(Layer::Disk)
+        } else if self.keyword("MEMORY") {
+            Ok(Layer::Memory)
+        } else if self.keyword("RUNTIME") {
+            Ok(Layer::Runtime)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    /// The rest of `LOAD` or `SAVE`: the table, in words, then where it's copied
+    fn copy(&mut self, load: bool) -> Result<Statement, AdminError> {
+        let mut words = vec![];
+        while !self.is_keyword("TO") && !self.is_keyword("FROM") {
+            words.push(self.identifier()?);
+        }
+        let table = words.join("_");
+        if !TABLES.contains(&&table[..]) {
+            return Err(AdminError::no_such_table(&table));
+        }
+        let to = self.keyword("TO");
+        if !to {
+            self.expect_keyword("FROM")?;
+        }
+        let (from, to) = match (load, to, self.layer()?) {
+            (true, true, Layer::Runtime) | (true, false, Layer::Memory) => (Layer::Memory, Layer::Runtime),
+            (true, false, Layer::Disk) | (true, true, Layer::Memory) => (Layer::Disk, Layer::Memory),
+            (false, true, Layer::Disk) | (false, false, Layer::Memory) => (Layer::Memory, Layer::Disk),
+            (false, false, Layer::Runtime) | (false, true, Layer::Memory) => (Layer::Runtime, Layer::Memory),
+            _ => return Err(AdminError::syntax(format!("Can't {} {} that way", if load { "LOAD" } else { "SAVE" }, table))),
+        };
+        Ok(Statement::Copy { table, from, to })
+    }
+
+    fn statement(&mut self) -> Result<Statement, AdminError> {
+        let statement = if self.keyword("SELECT") {
+            let columns = if self.punct('*') { None } else { Some(self.identifiers()?) };
+            self.expect_keyword("FROM")?;
+            let table = self.identifier()?;
+            Statement::Select { table, columns, filter: self.filter()? }
+        } else if self.keyword("INSERT") {
+            self.expect_keyword("INTO")?;
+            let table = self.identifier()?;
+            self.expect_punct('(')?;
+            let columns = self.identifiers()?;
+            self.expect_punct(')')?;
+            self.expect_keyword("VALUES")?;
+            let mut values = vec![];
+            loop {
+                self.expect_punct('(')?;
+                let mut row = vec![self.value()?];
+                while self.punct(',') {
+                    row.push(self.value()?);
+                }
+                self.expect_punct(')')?;
+                values.push(row);
+                if !self.punct(',') {
+                    break;
+                }
+            }
+            Statement::Insert { table, columns, values }
+        } else if self.keyword("UPDATE") {
+            let table = self.identifier()?;
+            self.expect_keyword("SET")?;
+            let mut changes = vec![self.assignment()?];
+            while self.punct(',') {
+                changes.push(self.assignment()?);
+            }
+            Statement::Update { table, changes, filter: self.filter()? }
+        } else if self.keyword("DELETE") {
+            self.expect_keyword("FROM")?;
+            let table = self.identifier()?;
+            Statement::Delete { table, filter: self.filter()? }
+        } else if self.keyword("LOAD") {
+            self.copy(true)?
+        } else if self.keyword("SAVE") {
+            self.copy(false)?
+        } else if self.keyword("SHOW") {
+            self.expect_keyword("TABLES")?;
+            Statement::ShowTables
+        } else if self.keyword("SET") {
+            self.pos = self.tokens.len();
+            Statement::Set
+        } else {
+            return Err(self.unexpected());
+        };
+        self.punct(';');
+        if self.peek().is_some() {
+            return Err(self.unexpected());
+        }
+        Ok(statement)
+    }
+}
+
+fn parse(sql: &str) -> Result<Statement, AdminError> {
+    Parser { tokens: tokenize(sql)?, pos: 0 }.statement()
+}
+
+/// Runs admin statements against the configuration the management API changes. Clones share
+/// the memory layer.
+#[derive(Clone)]
+pub struct Admin {
+    management: Management,
+    path: Option<PathBuf>,
+    /// copied from the runtime layer when first used, since listeners are added to the
+    /// management API after it starts
+    memory: Arc<Mutex<Option<Tables>>>,
+}
+
+impl Admin {
+
+    pub fn new(management: Management) -> Self {
+        Admin { management, path: None, memory: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Save tables to and load them from the JSON file at `path`
+    pub fn with_path(mut self, path: Option<PathBuf>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Run a single statement
+    pub fn execute(&self, sql: &str) -> Result<Reply, AdminError> {
+        let mut memory = self.memory.lock().unwrap();
+        let memory = memory.get_or_insert_with(|| self.runtime());
+        match parse(sql)? {
+            Statement::Select { table, columns, filter } => {
+                if table == "stats_digests" {
+                    let digests: Vec<DigestRow> = self.management.stats()
+                        .map(|stats| stats.snapshot().digests.into_iter().map(DigestRow).collect())
+                        .unwrap_or_default();
+                    return digests.select(columns.as_deref(), &filter);
+                }
+                if table == "stats_connections" {
+                    let connections: Vec<ConnectionRow> = self.management.connections.list().into_iter().map(ConnectionRow).collect();
+                    return connections.select(columns.as_deref(), &filter);
+                }
+                let runtime;
+                let (tables, name) = match table.strip_prefix("runtime_") {
+                    Some(name) => {
+                        runtime = self.runtime();
+                        (&runtime, name)
+                    },
+                    None => (&*memory, &table[..]),
+                };
+                let table = tables.table(name).ok_or_else(|| AdminError::no_such_table(&table))?;
+                table.select(columns.as_deref(), &filter)
+            },
+            Statement::Insert { table, columns, values } => table_mut(memory, &table)?.insert(&columns, values),
+            Statement::Update { table, changes, filter } => table_mut(memory, &table)?.update(changes, &filter),
+            Statement::Delete { table, filter } => table_mut(memory, &table)?.delete(&filter),
+            Statement::Copy { table, from, to } => {
+                match (from, to) {
+                    (Layer::Memory, Layer::Runtime) => self.apply(&table, memory)?,
+                    (Layer::Runtime, Layer::Memory) => memory.copy_from(&table, self.runtime()),
+                    (Layer::Memory, Layer::Disk) => self.save(&table, memory)?,
+                    _ => self.load(&table, memory)?,
+                }
+                Ok(Reply::Affected(0))
+            },
+            Statement::ShowTables => {
+                let runtime = TABLES.iter().map(|t| format!("runtime_{}", t));
+                let rows = TABLES.iter().map(|t| t.to_string()).chain(runtime).chain(STATS_TABLES.iter().map(|t| t.to_string()))
+                    .map(|t| vec![Some(t)])
+                    .collect();
+                Ok(Reply::Rows { columns: vec!["tables".to_string()], rows })
+            },
+            Statement::Set => Ok(Reply::Affected(0)),
+        }
+    }
+
+    /// The tables the proxy is using
+    fn runtime(&self) -> Tables {
+        let weights = &self.management.weights;
+        let backends = self.management.backend_pool().groups().into_iter().flat_map(|(group, backends)| {
+            backends.iter().enumerate().map(|(i, b)| {
+                BackendRow { routing_group: group.clone(), backend: b.to_string(), weight: weights.weight(b, i) }
+            }).collect::<Vec<_>>()
+        }).collect();
+        Tables { users: self.management.user_mappings(), backends, table_rules: self.management.table_rules() }
+    }
+
+    /// Put a table of the memory layer to use
+    fn apply(&self, table: &str, memory: &Tables) -> Result<(), AdminError> {
+        let pool = self.management.backend_pool();
+        match table {
+            "users" => {
+                if let Some(u) = memory.users.iter().find(|u| !pool.has_group(&u.default_group)) {
+                    return Err(AdminError::invalid(format!("Unknown routing group '{}' for user '{}'", u.default_group, u.user)));
+                }
+                self.management.replace_users(memory.users.clone()).map_err(AdminError::invalid)?;
+            },
+            "backends" => {
+                // groups left without rows are left without backends
+                let mut groups: BTreeMap<String, Vec<BackendAddr>> = pool.groups().into_keys().map(|g| (g, vec![])).collect();
+                let mut weights = vec![];
+                for row in &memory.backends {
+                    let backend: BackendAddr = row.backend.parse().map_err(|e: Error| AdminError::invalid(e.to_string()))?;
+                    groups.entry(row.routing_group.clone()).or_default().push(backend.clone());
+                    weights.push((backend, row.weight));
+                }
+                for (group, backends) in groups {
+                    pool.set(&group, backends);
+                }
+                for (backend, weight) in weights {
+                    self.management.weights.set(&backend, weight);
+                }
+            },
+            _ => self.management.replace_table_rules(memory.table_rules.clone()),
+        }
+        info!("Admin interface loaded {} to runtime", table);
+        Ok(())
+    }
+
+    fn disk(&self) -> Result<(&Path, BTreeMap<String, Vec<SavedRow>>), AdminError> {
+        let path = self.path.as_ref().ok_or_else(|| AdminError::invalid("No path to save tables to is configured".to_string()))?;
+        let saved = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| AdminError::invalid(format!("Invalid tables in {}: {}", path.display(), e)))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(AdminError::invalid(format!("Can't read {}: {}", path.display(), e))),
+        };
+        Ok((path, saved))
+    }
+
+    /// Write a table of the memory layer to disk, keeping the others saved there
+    fn save(&self, table: &str, memory: &Tables) -> Result<(), AdminError> {
+        let (path, mut saved) = self.disk()?;
+        saved.insert(table.to_string(), memory.table(table).map(|t| t.save_rows()).unwrap_or_default());
+        write_tables(path, &saved).map_err(|e| AdminError::invalid(format!("Can't write {}: {}", path.display(), e)))?;
+        info!("Admin interface saved {} to {}", table, path.display());
+        Ok(())
+    }
+
+    fn load(&self, table: &str, memory: &mut Tables) -> Result<(), AdminError> {
+        let (path, saved) = self.disk()?;
+        let rows = saved.get(table).ok_or_else(|| AdminError::invalid(format!("No {} saved in {}", table, path.display())))?;
+        table_mut(memory, table)?.load_rows(rows)
+    }
+}
+
+fn table_mut<'a>(tables: &'a mut Tables, name: &str) -> Result<&'a mut dyn Table, AdminError> {
+    if STATS_TABLES.contains(&name) {
+        return Err(AdminError::invalid(format!("{} can't be changed", name)));
+    }
+    if name.starts_with("runtime_") && tables.table(&name["runtime_".len()..]).is_some() {
+        let msg = format!("{} can't be changed, change {} and load it to runtime instead", name, &name["runtime_".len()..]);
+        return Err(AdminError::invalid(msg));
+    }
+    tables.table_mut(name).ok_or_else(|| AdminError::no_such_table(name))
+}
+
+/// Write the tables to `path`, replacing it only once they're all written
+fn write_tables(path: &Path, saved: &BTreeMap<String, Vec<SavedRow>>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer_pretty(&mut writer, saved)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// A session counted towards `max_sessions` until it's dropped
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl SessionSlot {
+
+    /// Take a slot, unless `max` are taken
+    fn take(sessions: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        sessions.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < max { Some(n + 1) } else { None })
+            .ok()
+            .map(|_| SessionSlot(sessions.clone()))
+    }
+}
+
+impl Drop for SessionSlot {
+
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answer admin clients on a thread of their own, each connection on a thread of its own,
+/// returning the address listened on
+pub fn run_in_thread(config: &AdminConfig, admin: Admin) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(config.listen)?;
+    let addr = listener.local_addr()?;
+    let config = Arc::new(config.clone());
+    let sessions = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new().name("mysql-proxy-admin".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let (admin, config) = (admin.clone(), config.clone());
+            let spawned = stream.and_then(|mut stream| {
+                let slot = match SessionSlot::take(&sessions, config.max_sessions) {
+                    Some(slot) => slot,
+                    None => {
+                        debug!("Admin connection refused, {} sessions already", config.max_sessions);
+                        stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+                        let refusal = Packet::error_packet(ER_CON_COUNT_ERROR, *b"08004", "Too many connections".to_string());
+                        return stream.write_all(&refusal.bytes);
+                    },
+                };
+                thread::Builder::new().name("mysql-proxy-admin-session".to_string()).spawn(move || {
+                    if let Err(e) = serve(&admin, &config, stream) {
+                        debug!("Admin session failed: {}", e);
+                    }
+                    drop(slot);
+                }).map(|_| ())
+            });
+            if let Err(e) = spawned {
+                warn!("Admin connection not accepted: {}", e);
+            }
+        }
+    })?;
+    Ok(addr)
+}
+
+fn serve(admin: &Admin, config: &AdminConfig, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let timeout = Duration::from_secs(config.login_timeout_secs);
+    let deadline = Instant::now() + timeout;
+    stream.set_write_timeout(Some(timeout))?;
+    let scramble = generate_scramble()?;
+    let greeting = HandshakeV10 {
+        server_version: format!("{}-mysql-proxy-admin", env!("CARGO_PKG_VERSION")),
+        connection_id: 1,
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        character_set: 0x21,
+        status_flags: SERVER_STATUS_AUTOCOMMIT,
+        auth_plugin_data: scramble.clone(),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    stream.write_all(&greeting.to_packet(0).bytes)?;
+    let p = read_packet(&mut Deadline { stream: &stream, deadline })?;
+    let response = HandshakeResponse::parse(&p)?;
+    let (mut auth, mut seq) = (response.auth_response.clone(), p.sequence_id().wrapping_add(1));
+    if response.auth_plugin_name.as_ref().map(|name| name != NATIVE_PASSWORD_PLUGIN).unwrap_or(false) {
+        let switch = AuthSwitchRequest { plugin_name: NATIVE_PASSWORD_PLUGIN.to_string(), plugin_data: scramble.clone() };
+        stream.write_all(&switch.to_packet(seq).bytes)?;
+        let p = read_packet(&mut Deadline { stream: &stream, deadline })?;
+        auth = p.payload().to_vec();
+        seq = p.sequence_id().wrapping_add(1);
+    }
+    if response.username != config.user || !verify_native_password(&config.password_hash(), &scramble, &auth) {
+        let msg = format!("Access denied for user '{}'", response.username);
+        stream.write_all(&Packet::error_packet(ER_ACCESS_DENIED_ERROR, *b"28000", msg.clone()).with_sequence_id(seq).bytes)?;
+        return Err(Error::new(ErrorKind::PermissionDenied, msg));
+    }
+    stream.write_all(&ok_packet(seq).bytes)?;
+    // logged in clients may take their time
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    loop {
+        let p = match read_packet(&mut stream) {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            p => p?,
+        };
+        let response = match p.packet_type() {
+            Ok(PacketType::ComQuit) => return Ok(()),
+            Ok(PacketType::ComQuery) => match admin.execute(&String::from_utf8_lossy(&p.payload()[1..])) {
+                Ok(Reply::Rows { columns, rows }) => {
+                    let columns: Vec<ColumnDefinition> = columns.iter().map(|c| ColumnDefinition::varchar(c, 255)).collect();
+                    let rows: Vec<TextRow> = rows.into_iter()
+                        .map(|row| row.into_iter().map(|v| v.map(|v| v.into_bytes())).collect())
+                        .collect();
+                    text_result_set(&columns, &rows, CLIENT_PROTOCOL_41)
+                },
+                Ok(Reply::Affected(n)) => vec![affected(n)],
+                Err(e) => vec![e.to_packet()],
+            },
+            Ok(PacketType::ComPing) | Ok(PacketType::ComInitDb) => vec![ok_packet(1)],
+            _ => vec![Packet::error_packet(ER_UNKNOWN_COM_ERROR, *b"08S01", "Unknown command".to_string())],
+        };
+        for p in response {
+            stream.write_all(&p.bytes)?;
+        }
+    }
+}
+
+/// An OK packet for a statement that changed `n` rows
+fn affected(n: u64) -> Packet {
+    let mut payload = vec![0x00];
+    write_lenenc_int(&mut payload, n);
+    // no last insert id, autocommit and no warnings
+    payload.extend_from_slice(&[0x00, 0x02, 0x00, 0x00, 0x00]);
+    Packet::new(1, &payload)
+}
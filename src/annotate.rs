@@ -0,0 +1,95 @@
+//! Query annotations, so backends can tell where each query came from.
+//!
+//! Behind a proxy every query reaches the backend from the proxy's address and as a mapped
+//! backend user, which makes slow logs and the processlist hard to trace back to a client.
+//! `AnnotateHandler` prepends a comment such as
+//! `/* proxy_conn=12 client=10.1.2.3 app=billing */` to each query it forwards.
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::MAX_PAYLOAD_LEN;
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct AnnotateConfig {
+    /// the application name to report, otherwise the client's `program_name` attribute
+    #[serde(default)]
+    pub application: Option<String>,
+}
+
+/// Wraps another handler and prepends a comment to every query sent to the backend
+pub struct AnnotateHandler<H: PacketHandler> {
+    comment: Vec<u8>,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> AnnotateHandler<H> where H: PacketHandler {
+
+    /// Prepend `/* <annotation> */ ` to queries
+    pub fn new(annotation: &str, inner: H) -> Self {
+        let comment = format!("/* {} */ ", sanitize(annotation)).into_bytes();
+        AnnotateHandler { comment, phase: PhaseTracker::new(), inner }
+    }
+
+    /// Annotate queries with the session's proxy connection id, client address and application
+    pub fn for_session(config: &AnnotateConfig, session: &Session, inner: H) -> Self {
+        let mut annotation = format!("proxy_conn={} client={}", session.connection_id, session.client.ip());
+        let program_name = session.connect_attrs.iter()
+            .find(|(key, _)| key == "program_name")
+            .map(|(_, value)| value);
+        if let Some(app) = config.application.as_ref().or(program_name) {
+            annotation.push_str(" app=");
+            annotation.push_str(&app.replace(char::is_whitespace, "_"));
+        }
+        AnnotateHandler::new(&annotation, inner)
+    }
+
+    fn annotate(&self, p: &Packet) -> Option<Packet> {
+        // queries split over several packets are left alone rather than re-split
+        let payload = p.payload();
+        if p.sequence_id() != 0 || payload.len() + self.comment.len() >= MAX_PAYLOAD_LEN {
+            return None;
+        }
+        let mut annotated = Vec::with_capacity(payload.len() + self.comment.len());
+        annotated.push(payload[0]);
+        annotated.extend_from_slice(&self.comment);
+        annotated.extend_from_slice(&payload[1..]);
+        Some(Packet::new(0, &annotated))
+    }
+}
+
+impl<H> PacketHandler for AnnotateHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() == ConnectionPhase::Command {
+            if let Ok(PacketType::ComQuery) = p.packet_type() {
+                if let Some(annotated) = self.annotate(p) {
+                    return match self.inner.handle_request(&annotated) {
+                        Action::Forward => Action::Mutate(annotated),
+                        action => action,
+                    };
+                }
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+/// Keep client supplied values from ending the comment early or garbling the log line
+fn sanitize(annotation: &str) -> String {
+    annotation.chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .replace("*/", "*_/")
+}
@@ -0,0 +1,226 @@
+//! Detecting packets that break the protocol.
+//!
+//! With a `[protocol_checks]` section, each session's packets are checked as they are read
+//! from either side for sequence ids that don't follow the exchange they belong to, packets
+//! the connection's phase doesn't allow, such as a backend speaking while no command awaits
+//! an answer, frames longer than `max_frame_bytes`, and command bytes the proxy doesn't know.
+//! Each anomaly is counted in the statistics, by kind, and passed to the session's handler
+//! with `PacketHandler::handle_anomaly`, which decides whether the connection is killed or
+//! tolerated. Anomalies of the kinds listed in `kill` always end the session, as do client
+//! packets out of sequence, such as a command that doesn't start at sequence id 0, so a
+//! confused or hostile client can't make the proxy lose track of the exchange.
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::{ConnectionPhase, Packet, PacketType};
+use super::codec::MAX_PAYLOAD_LEN;
+use super::dump::Direction;
+use super::stats::Stats;
+
+/// The kinds of anomalies, as named in the configuration and the statistics
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq,Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    BadSequenceId,
+    UnexpectedPacket,
+    OversizedFrame,
+    UnknownCommand,
+}
+
+impl AnomalyKind {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AnomalyKind::BadSequenceId => "bad_sequence_id",
+            AnomalyKind::UnexpectedPacket => "unexpected_packet",
+            AnomalyKind::OversizedFrame => "oversized_frame",
+            AnomalyKind::UnknownCommand => "unknown_command",
+        }
+    }
+}
+
+/// A packet that breaks the protocol
+#[derive(Clone,Debug,PartialEq)]
+pub enum Anomaly {
+    /// the packet's sequence id doesn't follow the previous packet of its exchange
+    BadSequenceId { from: Direction, expected: u8, actual: u8 },
+    /// a packet the connection's phase doesn't allow, such as an empty command or a response
+    /// while no command awaits one
+    UnexpectedPacket { from: Direction, phase: ConnectionPhase },
+    /// a frame longer than the checks allow
+    OversizedFrame { from: Direction, length: usize, max: usize },
+    /// a command starting with a byte the proxy doesn't know
+    UnknownCommand(u8),
+}
+
+impl Anomaly {
+
+    pub fn kind(&self) -> AnomalyKind {
+        match *self {
+            Anomaly::BadSequenceId { .. } => AnomalyKind::BadSequenceId,
+            Anomaly::UnexpectedPacket { .. } => AnomalyKind::UnexpectedPacket,
+            Anomaly::OversizedFrame { .. } => AnomalyKind::OversizedFrame,
+            Anomaly::UnknownCommand(_) => AnomalyKind::UnknownCommand,
+        }
+    }
+}
+
+fn side(from: Direction) -> &'static str {
+    match from {
+        Direction::Request => "client",
+        Direction::Response => "server",
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Anomaly::BadSequenceId { from, expected, actual } =>
+                write!(f, "{} sent sequence id {} where {} was expected", side(from), actual, expected),
+            Anomaly::UnexpectedPacket { from, phase } =>
+                write!(f, "{} sent an unexpected packet in the {:?} phase", side(from), phase),
+            Anomaly::OversizedFrame { from, length, max } =>
+                write!(f, "{} sent a frame of {} bytes, more than {}", side(from), length, max),
+            Anomaly::UnknownCommand(byte) => write!(f, "client sent unknown command 0x{:02x}", byte),
+        }
+    }
+}
+
+/// What to do with a connection a packet broke the protocol on
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Verdict {
+    /// carry on relaying its packets
+    Tolerate,
+    /// close both sides of it
+    Kill,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ProtocolChecksConfig {
+    /// the longest frame allowed from either side, without its header
+    #[serde(default = "ProtocolChecksConfig::default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// the kinds of anomalies that end the session whatever the handler decides
+    #[serde(default)]
+    pub kill: Vec<AnomalyKind>,
+}
+
+impl ProtocolChecksConfig {
+
+    fn default_max_frame_bytes() -> usize {
+        MAX_PAYLOAD_LEN
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_frame_bytes == 0 || self.max_frame_bytes > MAX_PAYLOAD_LEN {
+            return Err(format!("max_frame_bytes must be between 1 and {}", MAX_PAYLOAD_LEN));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProtocolChecksConfig {
+    fn default() -> Self {
+        ProtocolChecksConfig { max_frame_bytes: ProtocolChecksConfig::default_max_frame_bytes(), kill: vec![] }
+    }
+}
+
+/// Checks the packets of a session as they are read
+pub struct ProtocolChecks {
+    config: Arc<ProtocolChecksConfig>,
+    stats: Option<Stats>,
+    /// the sequence id the next packet of the current exchange should have
+    next_sequence_id: u8,
+}
+
+impl ProtocolChecks {
+
+    pub fn new(config: &ProtocolChecksConfig) -> Self {
+        ProtocolChecks { config: Arc::new(config.clone()), stats: None, next_sequence_id: 0 }
+    }
+
+    /// Count the anomalies found in `stats`
+    pub fn with_stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Check a packet from the client, read in `phase` with `in_flight` commands awaiting
+    /// responses, once the phase has observed it
+    pub fn request(&mut self, p: &Packet, phase: ConnectionPhase, in_flight: usize) -> Vec<Anomaly> {
+        let mut anomalies = self.frame(p, Direction::Request);
+        let sequence_id = p.sequence_id();
+        if phase == ConnectionPhase::Command && sequence_id == 0 {
+            // a command starts a new exchange
+            match p.packet_type() {
+                Err(_) => anomalies.push(Anomaly::UnexpectedPacket { from: Direction::Request, phase }),
+                Ok(PacketType::Unknown(command)) => anomalies.push(Anomaly::UnknownCommand(command)),
+                Ok(_) => {},
+            }
+        } else if phase == ConnectionPhase::Command && in_flight == 0 {
+            // nothing awaits more from the client, so this can only be a command out of sequence
+            anomalies.push(Anomaly::BadSequenceId { from: Direction::Request, expected: 0, actual: sequence_id });
+        } else if sequence_id != self.next_sequence_id {
+            // such as the rest of a long command, a file the server asked for, or the client's
+            // side of the handshake
+            anomalies.push(Anomaly::BadSequenceId {
+                from: Direction::Request,
+                expected: self.next_sequence_id,
+                actual: sequence_id,
+            });
+        }
+        self.next_sequence_id = sequence_id.wrapping_add(1);
+        self.record(&anomalies);
+        anomalies
+    }
+
+    /// Check a packet from the server, read in `phase` with `in_flight` commands awaiting
+    /// responses, before the phase has observed it
+    pub fn response(&mut self, p: &Packet, phase: ConnectionPhase, in_flight: usize) -> Vec<Anomaly> {
+        let mut anomalies = self.frame(p, Direction::Response);
+        let sequence_id = p.sequence_id();
+        if phase == ConnectionPhase::Command {
+            if in_flight == 0 {
+                anomalies.push(Anomaly::UnexpectedPacket { from: Direction::Response, phase });
+            } else if sequence_id != 1 && sequence_id != self.next_sequence_id {
+                // responses to pipelined commands each start at 1
+                anomalies.push(Anomaly::BadSequenceId {
+                    from: Direction::Response,
+                    expected: self.next_sequence_id,
+                    actual: sequence_id,
+                });
+            }
+        }
+        self.next_sequence_id = sequence_id.wrapping_add(1);
+        self.record(&anomalies);
+        anomalies
+    }
+
+    /// Whether an anomaly ends the session whatever the handler decides. Clients sending
+    /// sequence ids out of order always do, as the backend would drop them too, and the
+    /// proxy can no longer tell their commands from the rest of an exchange.
+    pub fn kills(&self, anomaly: &Anomaly) -> bool {
+        match *anomaly {
+            Anomaly::BadSequenceId { from: Direction::Request, .. } => true,
+            _ => self.config.kill.contains(&anomaly.kind()),
+        }
+    }
+
+    fn frame(&self, p: &Packet, from: Direction) -> Vec<Anomaly> {
+        let length = p.payload().len();
+        if length > self.config.max_frame_bytes {
+            vec![Anomaly::OversizedFrame { from, length, max: self.config.max_frame_bytes }]
+        } else {
+            vec![]
+        }
+    }
+
+    fn record(&self, anomalies: &[Anomaly]) {
+        if let Some(ref stats) = self.stats {
+            for anomaly in anomalies {
+                stats.record_anomaly(anomaly.kind());
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Connection attributes sent to backends.
+//!
+//! Clients describe themselves in the handshake response with connection attributes such as
+//! `_client_name` and `program_name`, which servers show in
+//! `performance_schema.session_connect_attrs`. The proxy passes them on to the backend and
+//! adds its own, so the backend can still see which client, and which proxy, a session is for.
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use super::{Action, Packet, PacketHandler};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::{HandshakeResponse, HandshakeV10};
+use super::protocol::CLIENT_CONNECT_ATTRS;
+use super::variables::PROXY_VERSION;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ConnectAttrsConfig {
+    /// pass the client's attributes on to the backend
+    #[serde(default = "ConnectAttrsConfig::default_forward")]
+    pub forward: bool,
+    /// add `proxy_version`, `proxy_client_ip` and `proxy_client_port`
+    #[serde(default = "ConnectAttrsConfig::default_add_proxy")]
+    pub add_proxy: bool,
+}
+
+impl ConnectAttrsConfig {
+
+    fn default_forward() -> bool {
+        true
+    }
+
+    fn default_add_proxy() -> bool {
+        true
+    }
+
+    /// The attributes to send to the backend for a client connecting from `client`
+    pub fn backend_attrs(&self, client_attrs: &[(String, String)], client: &SocketAddr) -> Vec<(String, String)> {
+        let mut attrs = vec![];
+        if self.forward {
+            // a client can't pass itself off as the proxy
+            attrs.extend(client_attrs.iter().filter(|(key, _)| !key.starts_with("proxy_")).cloned());
+        }
+        if self.add_proxy {
+            attrs.extend(proxy_attrs(client));
+        }
+        attrs
+    }
+}
+
+impl Default for ConnectAttrsConfig {
+    fn default() -> Self {
+        ConnectAttrsConfig {
+            forward: ConnectAttrsConfig::default_forward(),
+            add_proxy: ConnectAttrsConfig::default_add_proxy(),
+        }
+    }
+}
+
+/// The attributes the proxy adds for a client connecting from `client`
+pub fn proxy_attrs(client: &SocketAddr) -> Vec<(String, String)> {
+    vec![
+        ("proxy_version".to_string(), PROXY_VERSION.to_string()),
+        ("proxy_client_ip".to_string(), client.ip().to_string()),
+        ("proxy_client_port".to_string(), client.port().to_string()),
+    ]
+}
+
+/// Shared view of the attributes a client sent, readable once the handshake has been relayed
+#[derive(Clone,Debug,Default)]
+pub struct ConnectAttrs {
+    attrs: Rc<RefCell<Vec<(String, String)>>>,
+}
+
+impl ConnectAttrs {
+
+    pub fn get(&self) -> Vec<(String, String)> {
+        self.attrs.borrow().clone()
+    }
+
+    /// The value of the attribute `key`, such as `program_name`
+    pub fn value(&self, key: &str) -> Option<String> {
+        self.attrs.borrow().iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+}
+
+/// Wraps another handler and rewrites the attributes in a relayed handshake response
+pub struct ConnectAttrsHandler<H: PacketHandler> {
+    config: ConnectAttrsConfig,
+    client: SocketAddr,
+    attrs: ConnectAttrs,
+    server_flags: Option<u32>,
+    responded: bool,
+    inner: H,
+}
+
+impl<H> ConnectAttrsHandler<H> where H: PacketHandler {
+
+    pub fn new(config: ConnectAttrsConfig, client: SocketAddr, inner: H) -> Self {
+        ConnectAttrsHandler {
+            config,
+            client,
+            attrs: ConnectAttrs::default(),
+            server_flags: None,
+            responded: false,
+            inner,
+        }
+    }
+
+    /// The attributes the client sent
+    pub fn attrs(&self) -> ConnectAttrs {
+        self.attrs.clone()
+    }
+
+    fn rewrite(&self, p: &Packet) -> Option<Packet> {
+        // SSL requests don't parse, and the rest of their handshake is encrypted
+        let mut response = HandshakeResponse::parse(p).ok()?;
+        // leave alone anything this codec can't re-encode exactly, such as trailing fields
+        if response.to_packet(p.sequence_id()).bytes != p.bytes {
+            return None;
+        }
+        let client_attrs = response.parse_connect_attrs().ok()?;
+        *self.attrs.attrs.borrow_mut() = client_attrs.clone();
+        if self.server_flags? & CLIENT_CONNECT_ATTRS == 0 {
+            return None;
+        }
+        response.set_connect_attrs(&self.config.backend_attrs(&client_attrs, &self.client));
+        Some(response.to_packet(p.sequence_id()))
+    }
+}
+
+impl<H> PacketHandler for ConnectAttrsHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        // the handshake response is the first packet the client sends
+        if self.responded {
+            return self.inner.handle_request(p);
+        }
+        self.responded = true;
+        match self.rewrite(p) {
+            Some(response) => match self.inner.handle_request(&response) {
+                Action::Forward => Action::Mutate(response),
+                action => action,
+            },
+            None => self.inner.handle_request(p),
+        }
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        if self.server_flags.is_none() {
+            self.server_flags = Some(HandshakeV10::parse(p).map(|g| g.capability_flags).unwrap_or(0));
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
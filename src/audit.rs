@@ -0,0 +1,575 @@
+//! Tamper-evident audit trail for DDL and administrative statements.
+//!
+//! Each record is written as a line of JSON that includes the SHA-256 hash of the previous
+//! record, so removing, reordering or editing a record breaks the chain from that point on.
+//! `verify` recomputes the chain for an existing log. Records of sessions with labels carry
+//! them too, see `labels`.
+//!
+//! A query is recorded, as sent, when any statement of it is audited, so an audited statement
+//! can't hide behind another in a multi-statement query. Statements are recorded when they're
+//! prepared too, since executing them only refers to the prepared statement.
+//!
+//! With `AuditFiles`, a log file is renamed once it reaches a size or an age, to the log's
+//! path with the time in milliseconds appended, e.g. `audit.log.1700000000000`, and a new
+//! file continues the chain. Renamed files can be gzipped and removed after a number of files
+//! or an age, in a background thread, and records can be synced to disk as they are written.
+//! `verify_files` checks the chain across the renamed files and the current one; the oldest
+//! file kept may start partway through the chain once older ones have been removed.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::labels::Labels;
+use super::sink::EventSink;
+use super::sql;
+
+/// The `prev_hash` of the first record in a log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The audited fields of a record, in the order they are hashed
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+struct RecordBody {
+    seq: u64,
+    timestamp_ms: u64,
+    user: String,
+    /// left out when empty, so records from before labels hash as they did
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    statement: String,
+    prev_hash: String,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    body: RecordBody,
+    hash: String,
+}
+
+impl RecordBody {
+
+    fn hash(&self) -> String {
+        let json = serde_json::to_string(self).expect("audit record serializes");
+        let digest = Sha256::digest(json.as_bytes());
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// How renamed audit log files are compressed
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// When records are synced to disk, beyond being written to the operating system
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    #[default]
+    Never,
+    /// before a file is renamed
+    Rotate,
+    /// after each record
+    Always,
+}
+
+/// How audit log files are rotated, compressed, kept and synced
+#[derive(Clone,Debug,Deserialize,PartialEq,Default)]
+pub struct AuditFiles {
+    /// start a new file once the current one reaches this size, 0 for no limit
+    #[serde(default)]
+    pub max_bytes: u64,
+    /// start a new file once the current one is this old, checked as records are written,
+    /// 0 for no limit
+    #[serde(default)]
+    pub max_age_secs: u64,
+    #[serde(default)]
+    pub compress: AuditCompression,
+    /// how many renamed files to keep, 0 for all
+    #[serde(default)]
+    pub keep_files: usize,
+    /// remove renamed files older than this, 0 to keep them
+    #[serde(default)]
+    pub keep_secs: u64,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+impl AuditFiles {
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !self.rotates() && (self.compress != AuditCompression::None || self.keep_files > 0 || self.keep_secs > 0) {
+            return Err("compress, keep_files and keep_secs need max_bytes or max_age_secs".to_string());
+        }
+        Ok(())
+    }
+
+    fn rotates(&self) -> bool {
+        self.max_bytes > 0 || self.max_age_secs > 0
+    }
+}
+
+/// The current file of a log with `AuditFiles`
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    bytes: u64,
+    opened: SystemTime,
+    policy: AuditFiles,
+}
+
+impl AuditFile {
+
+    fn open(path: &Path, policy: AuditFiles) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let bytes = file.metadata()?.len();
+        Ok(AuditFile { path: path.to_path_buf(), file, bytes, opened: SystemTime::now(), policy })
+    }
+
+    fn due(&self) -> bool {
+        let age = SystemTime::now().duration_since(self.opened).unwrap_or_default();
+        self.bytes > 0 && ((self.policy.max_bytes > 0 && self.bytes >= self.policy.max_bytes)
+            || (self.policy.max_age_secs > 0 && age >= Duration::from_secs(self.policy.max_age_secs)))
+    }
+
+    /// Rename the current file and start a new one, then compress and prune in the background
+    fn rotate(&mut self) -> Result<()> {
+        if self.policy.fsync == FsyncPolicy::Rotate {
+            self.file.sync_data()?;
+        }
+        // files rotated within the same millisecond keep their order
+        let mut at = now_ms();
+        while rotated_path(&self.path, at).exists() || gzipped_path(&rotated_path(&self.path, at)).exists() {
+            at += 1;
+        }
+        let rotated = rotated_path(&self.path, at);
+        fs::rename(&self.path, &rotated)?;
+        *self = AuditFile::open(&self.path, self.policy.clone())?;
+        let (path, policy) = (self.path.clone(), self.policy.clone());
+        let spawned = thread::Builder::new().name("mysql-proxy-audit-rotate".to_string()).spawn(move || {
+            if policy.compress == AuditCompression::Gzip {
+                if let Err(e) = gzip(&rotated) {
+                    warn!("Failed to compress audit log {}: {}", rotated.display(), e);
+                }
+            }
+            if let Err(e) = prune(&path, &policy) {
+                warn!("Failed to remove old audit logs of {}: {}", path.display(), e);
+            }
+        });
+        if let Err(e) = spawned {
+            warn!("Failed to start compressing audit log: {}", e);
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.due() {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.bytes += line.len() as u64 + 1;
+        if self.policy.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write + Send>),
+    File(AuditFile),
+}
+
+impl Output {
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        match *self {
+            Output::Writer(ref mut writer) => {
+                writeln!(writer, "{}", line)?;
+                writer.flush()
+            },
+            Output::File(ref mut file) => file.write_line(line),
+        }
+    }
+}
+
+struct AuditState {
+    output: Output,
+    next_seq: u64,
+    last_hash: String,
+}
+
+/// Append-only, hash-chained audit log, shared between connections
+#[derive(Clone)]
+pub struct AuditLog {
+    state: Arc<Mutex<AuditState>>,
+    /// where records are published as well as written
+    sink: Option<EventSink>,
+}
+
+impl AuditLog {
+
+    /// Open a log file for appending, continuing the chain of any records already in it.
+    /// Fails if the existing records don't verify.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        AuditLog::open_files(path, AuditFiles::default())
+    }
+
+    /// Open a log file for appending, rotating it as `policy` says. The chain continues from
+    /// the last renamed file if the current one has no records yet.
+    pub fn open_files<P: AsRef<Path>>(path: P, policy: AuditFiles) -> Result<Self> {
+        let path = path.as_ref();
+        let start = match rotated_files(path)?.pop() {
+            Some(last) => Chain::Continuing(read_chain(&last, Chain::Any)?),
+            None if policy.rotates() => Chain::Any,
+            None => Chain::Genesis,
+        };
+        let (next_seq, last_hash) = match File::open(path) {
+            Ok(f) => chain_tail(BufReader::new(f), start)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => start.tail(),
+            Err(e) => return Err(e),
+        };
+        let file = AuditFile::open(path, policy)?;
+        Ok(AuditLog::from_parts(Output::File(file), next_seq, last_hash))
+    }
+
+    /// Start a new chain on an arbitrary writer
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        AuditLog::from_parts(Output::Writer(writer), 0, GENESIS_HASH.to_string())
+    }
+
+    fn from_parts(output: Output, next_seq: u64, last_hash: String) -> Self {
+        AuditLog { state: Arc::new(Mutex::new(AuditState { output, next_seq, last_hash })), sink: None }
+    }
+
+    /// Publish records to `sink` once they're written
+    pub fn with_sink(mut self, sink: EventSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Append a record for a statement run by `user`
+    pub fn record(&self, user: &str, statement: &str) -> Result<()> {
+        self.record_labelled(user, &BTreeMap::new(), statement)
+    }
+
+    /// Append a record for a statement run by `user` in a session with `labels`
+    pub fn record_labelled(&self, user: &str, labels: &BTreeMap<String, String>, statement: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp_ms = now_ms();
+        let body = RecordBody {
+            seq: state.next_seq,
+            timestamp_ms,
+            user: user.to_string(),
+            labels: labels.clone(),
+            statement: statement.to_string(),
+            prev_hash: state.last_hash.clone(),
+        };
+        let hash = body.hash();
+        let line = serde_json::to_string(&Record { body, hash: hash.clone() })
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        state.output.write_line(&line)?;
+        state.next_seq += 1;
+        state.last_hash = hash;
+        if let Some(ref sink) = self.sink {
+            sink.send_audit(&line);
+        }
+        Ok(())
+    }
+}
+
+/// Verify the hash chain of an audit log, returning the number of records in it
+pub fn verify<R: BufRead>(reader: R) -> Result<u64> {
+    chain_tail(reader, Chain::Genesis).map(|(n, _)| n)
+}
+
+/// Verify the hash chain across a log's renamed files, gzipped or not, and its current file,
+/// returning the number of records in them
+pub fn verify_files<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let mut files = rotated_files(path)?;
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    let mut chain = Chain::Any;
+    let mut first = None;
+    for file in &files {
+        let (next_seq, last_hash) = read_chain(file, chain)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+        if first.is_none() {
+            first = Some(chain_head(file)?.unwrap_or(next_seq));
+        }
+        chain = Chain::Continuing((next_seq, last_hash));
+    }
+    Ok(match chain {
+        Chain::Continuing((next_seq, _)) => next_seq - first.unwrap_or(0),
+        _ => 0,
+    })
+}
+
+/// Where a file's records are expected to pick up the chain
+#[derive(Clone,Debug)]
+enum Chain {
+    /// at the first record
+    Genesis,
+    /// after the record with this sequence number and hash
+    Continuing((u64, String)),
+    /// anywhere, the records before it having been removed
+    Any,
+}
+
+impl Chain {
+
+    fn tail(self) -> (u64, String) {
+        match self {
+            Chain::Continuing(tail) => tail,
+            _ => (0, GENESIS_HASH.to_string()),
+        }
+    }
+}
+
+/// A log file's records, decompressed if it is gzipped
+fn open_records(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension().map(|e| e == "gz").unwrap_or(false) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+fn read_chain(path: &Path, start: Chain) -> Result<(u64, String)> {
+    chain_tail(open_records(path)?, start)
+}
+
+/// The sequence number of a file's first record
+fn chain_head(path: &Path) -> Result<Option<u64>> {
+    for line in open_records(path)?.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            let record: Record = serde_json::from_str(&line).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            return Ok(Some(record.body.seq));
+        }
+    }
+    Ok(None)
+}
+
+/// Walk the chain, returning the next sequence number and the hash of the last record
+fn chain_tail<R: BufRead>(reader: R, start: Chain) -> Result<(u64, String)> {
+    let mut any_start = matches!(start, Chain::Any);
+    let (mut expected_seq, mut last_hash) = start.tail();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let broken = |what: &str| Error::new(ErrorKind::InvalidData, format!("Audit log line {}: {}", i + 1, what));
+        let record: Record = serde_json::from_str(&line).map_err(|e| broken(&e.to_string()))?;
+        if any_start {
+            expected_seq = record.body.seq;
+            last_hash = record.body.prev_hash.clone();
+            any_start = false;
+        }
+        if record.body.seq != expected_seq {
+            return Err(broken(&format!("expected record {} but found {}", expected_seq, record.body.seq)));
+        }
+        if record.body.prev_hash != last_hash {
+            return Err(broken("chain broken, previous hash does not match"));
+        }
+        if record.body.hash() != record.hash {
+            return Err(broken("record hash does not match its contents"));
+        }
+        expected_seq += 1;
+        last_hash = record.hash;
+    }
+    Ok((expected_seq, last_hash))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn rotated_path(path: &Path, timestamp_ms: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", timestamp_ms));
+    PathBuf::from(name)
+}
+
+fn gzipped_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// The time a renamed file of the log at `path` was renamed, from its name
+fn rotated_at(path: &Path, file_name: &str) -> Option<u64> {
+    let prefix = format!("{}.", path.file_name()?.to_str()?);
+    let rest = file_name.strip_prefix(&prefix[..])?;
+    rest.strip_suffix(".gz").unwrap_or(rest).parse().ok()
+}
+
+/// The renamed files of the log at `path`, oldest first, preferring gzipped ones
+fn rotated_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut files: BTreeMap<u64, PathBuf> = BTreeMap::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let at = match name.to_str().and_then(|name| rotated_at(path, name)) {
+            Some(at) => at,
+            None => continue,
+        };
+        let file = dir.join(&name);
+        // a file still being compressed is read uncompressed until it is replaced
+        let compressed = file.extension().map(|e| e == "gz").unwrap_or(false);
+        if !compressed || !files.contains_key(&at) {
+            files.insert(at, file);
+        }
+    }
+    Ok(files.into_values().collect())
+}
+
+/// Replace a file with a gzipped copy
+fn gzip(path: &Path) -> Result<()> {
+    let compressed = gzipped_path(path);
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+/// Remove renamed files beyond the policy's count or age
+fn prune(path: &Path, policy: &AuditFiles) -> Result<()> {
+    let files = rotated_files(path)?;
+    let excess = if policy.keep_files > 0 { files.len().saturating_sub(policy.keep_files) } else { 0 };
+    let cutoff = now_ms().saturating_sub(policy.keep_secs * 1000);
+    for (i, file) in files.iter().enumerate() {
+        let at = file.file_name().and_then(|name| name.to_str()).and_then(|name| rotated_at(path, name));
+        let expired = policy.keep_secs > 0 && at.map(|at| at < cutoff).unwrap_or(false);
+        if i < excess || expired {
+            debug!("Removing old audit log {}", file.display());
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether any statement of a query changes schema, privileges or global server settings
+pub fn is_audited(sql: &str) -> bool {
+    sql::split(sql).into_iter().any(statement_is_audited)
+}
+
+fn statement_is_audited(sql: &str) -> bool {
+    let mut words = strip_leading_comments(sql).split_whitespace().map(|w| w.to_uppercase());
+    match words.next().as_ref().map(|w| &w[..]) {
+        Some("CREATE") | Some("ALTER") | Some("DROP") | Some("TRUNCATE") | Some("RENAME")
+        | Some("GRANT") | Some("REVOKE") => true,
+        Some("SET") => match words.next() {
+            Some(ref w) => w == "GLOBAL" || w == "PERSIST" || w.starts_with("@@GLOBAL."),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn strip_leading_comments(mut sql: &str) -> &str {
+    loop {
+        sql = sql.trim_start();
+        if sql.starts_with("/*") {
+            match sql.find("*/") {
+                Some(end) => sql = &sql[end + 2..],
+                None => return "",
+            }
+        } else if sql.starts_with("--") || sql.starts_with('#') {
+            match sql.find('\n') {
+                Some(end) => sql = &sql[end + 1..],
+                None => return "",
+            }
+        } else {
+            return sql;
+        }
+    }
+}
+
+/// Wraps another handler and records audited statements before they are forwarded
+pub struct AuditHandler<H: PacketHandler> {
+    log: AuditLog,
+    user: String,
+    labels: Labels,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> AuditHandler<H> where H: PacketHandler {
+
+    pub fn new(log: AuditLog, user: &str, inner: H) -> Self {
+        AuditHandler { log, user: user.to_string(), labels: Labels::default(), phase: PhaseTracker::new(), inner }
+    }
+
+    /// Record the session's labels with its statements
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+}
+
+impl<H> PacketHandler for AuditHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return self.inner.handle_request(p);
+        }
+        let arg = String::from_utf8_lossy(p.payload().get(1..).unwrap_or(&[]));
+        let statement = match p.packet_type() {
+            Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtPrepare) if is_audited(&arg) => Some(arg.into_owned()),
+            Ok(PacketType::ComCreateDb) => Some(format!("CREATE DATABASE `{}`", arg)),
+            Ok(PacketType::ComDropDb) => Some(format!("DROP DATABASE `{}`", arg)),
+            Ok(PacketType::ComShutdown) => Some("SHUTDOWN".to_string()),
+            _ => None,
+        };
+        if let Some(statement) = statement {
+            if let Err(e) = self.log.record_labelled(&self.user, &self.labels.snapshot(), &statement) {
+                // refuse statements that can't be audited rather than run them unrecorded
+                warn!("Failed to write audit record: {}", e);
+                return Action::Error {
+                    code: 1105, // ER_UNKNOWN_ERROR
+                    state: *b"HY000",
+                    msg: "Statement rejected: audit log unavailable".to_string(),
+                };
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,1450 @@
+//! Proxy-side authentication.
+//!
+//! Instead of relaying the handshake between client and server, the proxy greets the client
+//! itself, checks the client's credentials against a `UserStore`, and then logs in to the
+//! backend chosen for the user with the mapped backend credentials. Clients authenticate
+//! with mysql_native_password, or with mysql_clear_password when an external `Authenticator`
+//! is used. Backends must accept mysql_native_password.
+//!
+//! With the `tls` feature, clients may upgrade their connection to TLS before logging in, and
+//! a verified client certificate can stand in for the password of the user its identity maps
+//! to. The identity is checked against the user's access list, may send the user's sessions to
+//! a routing group of its own, and is kept in the `Session`.
+//!
+//! Clients have `DEFAULT_HANDSHAKE_TIMEOUT` from the greeting to log in, so connections that
+//! never send anything don't hold on to the proxy's resources. A client that hasn't answered
+//! the greeting by then is sent ER_NET_READ_INTERRUPTED, and one that has is disconnected
+//! without a word, since it may have switched to TLS. Either way the timeout is counted.
+//!
+//! Scrambles come from the operating system's random source. Clients the proxy authenticated
+//! can't COM_CHANGE_USER, which would log them in to the backend past the proxy's user map.
+
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::future::Either;
+use futures::future;
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker, Transport};
+use super::anomaly::{Anomaly, Verdict};
+use super::acl::{AccessControl, ER_HOST_NOT_PRIVILEGED};
+use super::attrs::ConnectAttrsConfig;
+use super::breaker::CircuitBreaker;
+use super::authenticator::AuthenticatorPool;
+use super::config::CertUser;
+#[cfg(feature = "tls")]
+use super::config::TlsConfig;
+use super::codec::*;
+use super::capabilities::CapabilityPolicy;
+use super::charset::Collation;
+use super::compress::CompressionConfig;
+use super::connect::BackendAddr;
+use super::credentials::{BackendCredentials, CredentialProvider};
+use super::errors::ErrorRules;
+use super::events::{Event, EventBus};
+use super::greeting::GreetingConfig;
+use super::honeypot::ER_DBACCESS_DENIED_ERROR;
+use super::labels::Labels;
+use super::protocol::*;
+use super::quota::{QuotaLease, Quotas, ER_TOO_MANY_USER_CONNECTIONS};
+use super::retry::{retry_connect, ConnectRetryPolicy};
+use super::rowfilter::ER_NOT_SUPPORTED_YET;
+use super::sockopt::SocketOptions;
+use super::tarpit::{Admission, Tarpit, ER_HOST_IS_BLOCKED};
+use super::schemas::{self, SchemaPolicy};
+use super::tenant::TenantSchemas;
+use super::upstream::{connect_through, UpstreamProxy};
+use super::users::{UserMapping, UserStore};
+use super::warmpool::{WarmConnection, WarmKey, WarmPool};
+use super::xprotocol;
+#[cfg(feature = "tls")]
+use super::tls::{self, TlsStream};
+
+/// The server version the proxy reports to clients
+pub const PROXY_SERVER_VERSION: &str = "5.7.0-mysql-proxy";
+
+/// Capabilities the proxy offers to clients. These are limited to ones that every supported
+/// backend understands, since packets are relayed verbatim once authentication completes.
+pub const PROXY_CAPABILITIES: u32 = CLIENT_LONG_PASSWORD | CLIENT_FOUND_ROWS | CLIENT_LONG_FLAG
+    | CLIENT_CONNECT_WITH_DB | CLIENT_NO_SCHEMA | CLIENT_ODBC | CLIENT_LOCAL_FILES
+    | CLIENT_IGNORE_SPACE | CLIENT_PROTOCOL_41 | CLIENT_INTERACTIVE | CLIENT_IGNORE_SIGPIPE
+    | CLIENT_TRANSACTIONS | CLIENT_SECURE_CONNECTION | CLIENT_MULTI_STATEMENTS
+    | CLIENT_MULTI_RESULTS | CLIENT_PS_MULTI_RESULTS | CLIENT_PLUGIN_AUTH;
+
+/// How long clients have from the greeting to log in, unless configured otherwise
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub type AuthFuture<T> = Box<dyn Future<Item = T, Error = io::Error>>;
+
+/// An authenticated client session
+#[derive(Clone,Debug,PartialEq)]
+pub struct Session {
+    /// the proxy connection id reported to the client
+    pub connection_id: u32,
+    /// the user the client authenticated to the proxy as
+    pub user: String,
+    /// the user the proxy logged in to the backend as
+    pub backend_user: String,
+    /// the routing group the session was sent to
+    pub group: String,
+    /// the backend address the session is connected to
+    pub backend: SocketAddr,
+    /// the backend as its routing group lists it
+    pub backend_name: BackendAddr,
+    /// the capabilities the proxy logged in to the backend with
+    pub backend_capabilities: u32,
+    /// the collation the client logged in with
+    pub character_set: u8,
+    /// the default schema requested by the client
+    pub database: Option<String>,
+    /// the identity from the client's TLS certificate, if it presented one
+    pub tls_identity: Option<String>,
+    /// the address the client connected from
+    pub client: SocketAddr,
+    /// the connection attributes the client sent, such as `program_name`
+    pub connect_attrs: Vec<(String, String)>,
+    /// the user's virtual databases, with the prefix for this user
+    pub tenant: Option<TenantSchemas>,
+    /// the schemas the user may switch to
+    pub schemas: Option<SchemaPolicy>,
+    /// the attributes of the user's mapping, such as its tenant id
+    pub attributes: HashMap<String, String>,
+    /// counts the connection against the user's quota while the session holds it
+    pub quota: Option<Arc<QuotaLease>>,
+    /// the client asked for the compressed protocol, which the proxy offered
+    pub client_compressed: bool,
+    /// key/value pairs to slice the session's logs, metrics and audit records by
+    pub labels: Labels,
+    /// the client logged in to the backend itself, rather than to the proxy
+    pub auth_passthrough: bool,
+}
+
+/// Wraps another handler and refuses `COM_CHANGE_USER` from clients the proxy authenticated,
+/// which would otherwise log in to the backend as whichever of its users they knew the
+/// password of, outside the proxy's user mapping. Sessions whose authentication is passed
+/// through may change user, as the backend checks them either way.
+pub struct ChangeUserHandler<H: PacketHandler> {
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> ChangeUserHandler<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        ChangeUserHandler { phase: PhaseTracker::new(), inner }
+    }
+}
+
+impl<H> PacketHandler for ChangeUserHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0
+            && p.packet_type().ok() == Some(PacketType::ComChangeUser) {
+            return Action::Error {
+                code: ER_NOT_SUPPORTED_YET,
+                state: *b"42000",
+                msg: "COM_CHANGE_USER isn't supported for users the proxy authenticates".to_string(),
+            };
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+/// A client connection, upgraded to TLS if the client asked for it
+pub enum ClientStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl ClientStream {
+
+    fn is_tls(&self) -> bool {
+        match *self {
+            ClientStream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(_) => true,
+        }
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        match *self {
+            ClientStream::Plain(ref s) => s,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(ref s) => &**s,
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport().read(buf)
+    }
+}
+
+impl Write for ClientStream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport().flush()
+    }
+}
+
+impl AsyncRead for ClientStream {}
+
+impl AsyncWrite for ClientStream {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.transport().shutdown(Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Transport for ClientStream {
+
+    fn poll_read(&self) -> Async<()> {
+        self.transport().poll_read()
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        self.transport().poll_write()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport().read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.transport().write(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.transport().flush()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.transport().shutdown(how)
+    }
+}
+
+/// A client that has passed authentication at the proxy, but isn't connected to a backend yet
+struct ClientLogin {
+    connection_id: u32,
+    response: HandshakeResponse,
+    mapping: UserMapping,
+    next_sequence_id: u8,
+    tls_identity: Option<String>,
+    /// the client relays its own authentication exchange to the backend
+    passthrough: bool,
+}
+
+impl ClientLogin {
+
+    fn session(&self, backend: SocketAddr, backend_name: BackendAddr, backend_capabilities: u32, client: SocketAddr,
+               connect_attrs: Vec<(String, String)>, quota: Option<Arc<QuotaLease>>) -> Session {
+        Session {
+            connection_id: self.connection_id,
+            user: self.mapping.user.clone(),
+            backend_user: if self.passthrough { self.response.username.clone() } else { self.mapping.backend_user.clone() },
+            group: self.mapping.default_group.clone(),
+            backend,
+            backend_name,
+            backend_capabilities,
+            character_set: self.response.character_set,
+            database: self.response.database.clone(),
+            tls_identity: self.tls_identity.clone(),
+            client,
+            connect_attrs,
+            tenant: self.mapping.tenant.as_ref().map(|t| t.for_user(&self.mapping.user)),
+            schemas: self.mapping.schemas.clone(),
+            attributes: self.mapping.attributes.clone(),
+            quota,
+            client_compressed: self.response.capability_flags & CLIENT_COMPRESS != 0,
+            auth_passthrough: self.passthrough,
+            labels: Labels::default(),
+        }
+    }
+}
+
+/// How a client proved who it is to the proxy
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Verification {
+    Password,
+    Certificate,
+    /// not at all, the backend authenticates it
+    Passthrough,
+}
+
+/// TLS settings for client connections
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct ClientTls {
+    certificates: tls::ServerCertificates,
+    config: TlsConfig,
+}
+
+/// Proxy-side authentication settings shared by all connections
+#[derive(Clone)]
+pub struct ProxyAuth {
+    users: Arc<dyn UserStore>,
+    authenticator: Option<AuthenticatorPool>,
+    access: AccessControl,
+    client_socket: SocketOptions,
+    backend_socket: SocketOptions,
+    greeting: GreetingConfig,
+    capabilities: CapabilityPolicy,
+    connect_attrs: ConnectAttrsConfig,
+    tolerant_backends: bool,
+    backend_deprecate_eof: bool,
+    compression: Option<CompressionConfig>,
+    backend_collation: Option<Collation>,
+    upstream: Option<UpstreamProxy>,
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    quotas: Quotas,
+    error_rules: ErrorRules,
+    tarpit: Option<Tarpit>,
+    breaker: Option<CircuitBreaker>,
+    connect_retry: Option<ConnectRetryPolicy>,
+    events: EventBus,
+    handshake_timeout: Option<Duration>,
+    handshake_timeouts: Arc<AtomicUsize>,
+    warm_pool: Option<WarmPool>,
+    #[cfg(feature = "tls")]
+    tls: Option<ClientTls>,
+}
+
+impl ProxyAuth {
+
+    /// Authenticate clients with the proxy passwords in the user mappings
+    pub fn new(users: Arc<dyn UserStore>) -> Self {
+        ProxyAuth {
+            users,
+            authenticator: None,
+            access: AccessControl::default(),
+            client_socket: SocketOptions::default(),
+            backend_socket: SocketOptions::default(),
+            greeting: GreetingConfig::default(),
+            capabilities: CapabilityPolicy::default(),
+            connect_attrs: ConnectAttrsConfig::default(),
+            tolerant_backends: false,
+            backend_deprecate_eof: false,
+            compression: None,
+            backend_collation: None,
+            upstream: None,
+            credentials: None,
+            quotas: Quotas::new(),
+            error_rules: ErrorRules::default(),
+            tarpit: None,
+            breaker: None,
+            connect_retry: None,
+            events: EventBus::default(),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            handshake_timeouts: Arc::default(),
+            warm_pool: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Validate client passwords with an external authenticator instead. Clients must still
+    /// have a user mapping, which provides their backend credentials.
+    pub fn with_authenticator(mut self, authenticator: AuthenticatorPool) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Enforce per-user access lists, counting rejections with the listener's access control
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Socket options for client connections, applied before the client is greeted
+    pub fn with_client_socket(mut self, options: SocketOptions) -> Self {
+        self.client_socket = options;
+        self
+    }
+
+    /// Socket options for backend connections
+    pub fn with_backend_socket(mut self, options: SocketOptions) -> Self {
+        self.backend_socket = options;
+        self
+    }
+
+    /// Rewrite the proxy's greeting. Capabilities can only be removed, not added, since the
+    /// proxy logs in to the backend with capabilities it knows every backend supports.
+    pub fn with_greeting(mut self, greeting: GreetingConfig) -> Self {
+        self.greeting = GreetingConfig {
+            set_capabilities: 0,
+            // the proxy relies on these to authenticate clients
+            clear_capabilities: greeting.clear_capabilities
+                & !(CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH),
+            ..greeting
+        };
+        self
+    }
+
+    /// Offer the compressed protocol to clients, ask backends for it, or both, as configured
+    pub fn with_compression(mut self, compression: Option<CompressionConfig>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Never negotiate the capabilities the policy disables, with clients or backends
+    pub fn with_capability_policy(mut self, policy: CapabilityPolicy) -> Self {
+        self.capabilities = policy;
+        self
+    }
+
+    /// Choose the connection attributes sent to backends
+    pub fn with_connect_attrs(mut self, config: ConnectAttrsConfig) -> Self {
+        self.connect_attrs = config;
+        self
+    }
+
+    /// Log in to backends that only approximate the MySQL protocol, accepting incomplete
+    /// greetings and only using the capabilities they advertise
+    pub fn with_tolerant_backends(mut self, tolerant: bool) -> Self {
+        self.tolerant_backends = tolerant;
+        self
+    }
+
+    /// Log in to backends with CLIENT_DEPRECATE_EOF when they support it, whatever the
+    /// client's capabilities, for handlers such as `LegacyEofHandler` to translate
+    pub fn with_backend_deprecate_eof(mut self, deprecate_eof: bool) -> Self {
+        self.backend_deprecate_eof = deprecate_eof;
+        self
+    }
+
+    /// Log in to backends with `collation` whatever the client's, for `CharsetHandler` to
+    /// convert between them
+    pub fn with_backend_collation(mut self, collation: Option<Collation>) -> Self {
+        self.backend_collation = collation;
+        self
+    }
+
+    /// Log in to backends with credentials from `provider`, such as IAM authentication
+    /// tokens, rather than the backend passwords in the user mappings
+    pub fn with_credential_provider(mut self, provider: Arc<dyn CredentialProvider>) -> Self {
+        self.credentials = Some(provider);
+        self
+    }
+
+    /// Connect to backends through an upstream SOCKS5 or HTTP proxy
+    pub fn with_upstream(mut self, upstream: Option<UpstreamProxy>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Count connections against the quotas of the users that have them in `quotas`, which
+    /// may be shared with other listeners
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Rewrite the errors of failed backend logins by `rules` before clients see them
+    pub fn with_error_rules(mut self, rules: ErrorRules) -> Self {
+        self.error_rules = rules;
+        self
+    }
+
+    /// Delay the greeting to addresses with failed logins, and block those with too many
+    pub fn with_tarpit(mut self, tarpit: Tarpit) -> Self {
+        self.tarpit = Some(tarpit);
+        self
+    }
+
+    /// Report connections and logins to backends to their circuits
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Try connecting to backends again, for sessions and warm connections alike, after the
+    /// failures `policy` retries
+    pub fn with_connect_retry(mut self, policy: Option<ConnectRetryPolicy>) -> Self {
+        self.connect_retry = policy;
+        self
+    }
+
+    /// Publish failed logins and clients breaking the protocol during the handshake
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Give clients `timeout` from the greeting to log in, or as long as they like with `None`
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Hand sessions backend connections that `pool` logged in ahead, see `warmpool`
+    pub fn with_warm_pool(mut self, pool: WarmPool) -> Self {
+        self.warm_pool = Some(pool);
+        self
+    }
+
+    /// Clients disconnected for taking too long to log in. Clones share the count.
+    pub fn handshake_timeouts(&self) -> usize {
+        self.handshake_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Offer TLS to clients, and optionally authenticate them by certificate
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: &TlsConfig) -> io::Result<Self> {
+        self.tls = Some(ClientTls {
+            certificates: tls::ServerCertificates::new(config)?,
+            config: config.clone(),
+        });
+        Ok(self)
+    }
+
+    /// The certificates offered to clients, to reload them while connections carry on
+    #[cfg(feature = "tls")]
+    pub fn tls_certificates(&self) -> Option<&tls::ServerCertificates> {
+        self.tls.as_ref().map(|tls| &tls.certificates)
+    }
+
+    fn capabilities(&self) -> u32 {
+        // connection attributes are kept for the session rather than passed to the backend
+        let mut capabilities = PROXY_CAPABILITIES | CLIENT_CONNECT_ATTRS;
+        if self.compression.as_ref().map(|c| c.client).unwrap_or(false) {
+            capabilities |= CLIENT_COMPRESS;
+        }
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                capabilities |= CLIENT_SSL;
+            }
+        }
+        self.capabilities.apply(capabilities)
+    }
+
+    fn tls_required(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref tls) = self.tls {
+                return tls.config.required || tls.config.require_client_cert;
+            }
+        }
+        false
+    }
+
+    /// Whether a verified certificate for `identity` lets the client skip the password check
+    fn cert_auth(&self, identity: Option<&str>, user: &str) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref tls) = self.tls {
+                return tls.config.cert_auth && identity.is_some_and(|identity| tls.config.cert_user(identity).user == user);
+            }
+        }
+        let _ = (identity, user);
+        false
+    }
+
+    /// The proxy user a verified certificate for `identity` stands for
+    fn cert_user(&self, identity: Option<&str>) -> Option<CertUser> {
+        #[cfg(feature = "tls")]
+        {
+            if let (Some(tls), Some(identity)) = (self.tls.as_ref(), identity) {
+                return Some(tls.config.cert_user(identity));
+            }
+        }
+        let _ = identity;
+        None
+    }
+
+    /// Upgrade the client to TLS if it sent an SSLRequest, and read its handshake response
+    #[cfg(feature = "tls")]
+    fn upgrade(&self, client: TcpStream, p: Packet) -> AuthFuture<(ClientStream, Packet, Option<String>)> {
+        match self.tls {
+            Some(ref tls) if is_ssl_request(&p) => {
+                let identity_source = tls.config.identity;
+                let accept = match tls::accept(client, tls.certificates.current()) {
+                    Ok(accept) => accept,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                Box::new(accept.and_then(read_packet).map(move |(client, p)| {
+                    let identity = client.peer_identity(identity_source);
+                    debug!("Client upgraded to TLS, certificate identity {:?}", identity);
+                    (ClientStream::Tls(Box::new(client)), p, identity)
+                }))
+            },
+            _ => Box::new(future::ok((ClientStream::Plain(client), p, None))),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn upgrade(&self, client: TcpStream, p: Packet) -> AuthFuture<(ClientStream, Packet, Option<String>)> {
+        Box::new(future::ok((ClientStream::Plain(client), p, None)))
+    }
+
+    /// Authenticate a client at the proxy, connect it to the backend returned by `route` and
+    /// log in with the mapped backend credentials, or relay the client's own authentication
+    /// exchange for `auth_passthrough` users. Resolves to the client and server streams,
+    /// ready to be passed to a `Pipe`, once the client has been sent its OK packet.
+    pub fn establish<F>(&self,
+                        client: TcpStream,
+                        route: F,
+                        handle: &Handle) -> AuthFuture<(ClientStream, TcpStream, Session)>
+        where F: FnOnce(&UserMapping) -> Option<BackendAddr> + 'static
+    {
+        Box::new(self.establish_with_relogin(client, route, handle).map(|(client, server, session, _)| (client, server, session)))
+    }
+
+    /// Like `establish`, also resolving to a way to log the session in to another backend,
+    /// unless the user's authentication was passed through
+    pub fn establish_with_relogin<F>(&self,
+                                     client: TcpStream,
+                                     route: F,
+                                     handle: &Handle) -> AuthFuture<(ClientStream, TcpStream, Session, Option<BackendRelogin>)>
+        where F: FnOnce(&UserMapping) -> Option<BackendAddr> + 'static
+    {
+        let handle = handle.clone();
+        let access = self.access.clone();
+        let backend_socket = self.backend_socket;
+        let disabled = self.capabilities.disabled();
+        let attrs_config = self.connect_attrs.clone();
+        let tolerant = self.tolerant_backends;
+        let deprecate_eof = self.backend_deprecate_eof;
+        let compress = self.compression.as_ref().map(|c| c.backend).unwrap_or(false);
+        let collation = self.backend_collation;
+        let upstream = self.upstream.clone();
+        let provider = self.credentials.clone();
+        let quotas = self.quotas.clone();
+        let error_rules = self.error_rules.clone();
+        let breaker = self.breaker.clone();
+        let connect_retry = self.connect_retry.clone();
+        // compressed backends would need COM_INIT_DB compressed too
+        let warm_pool = self.warm_pool.clone().filter(|_| !compress);
+        let peer = match client.peer_addr().and_then(|addr| self.client_socket.apply(&client).map(|_| addr)) {
+            Ok(addr) => addr,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let tarpit = self.tarpit.clone();
+        let delay = match tarpit.as_ref().map(|tarpit| tarpit.admit(peer.ip())) {
+            Some(Admission::Blocked(msg)) => return reject_with_state(client, 0, ER_HOST_IS_BLOCKED, *b"HY000", msg),
+            Some(Admission::After(delay)) => Some(delay),
+            _ => None,
+        };
+        let proxy_auth = self.clone();
+        let authenticated: AuthFuture<_> = match delay {
+            Some(delay) => match Timeout::new(delay, &handle) {
+                Ok(timer) => {
+                    let handle = handle.clone();
+                    Box::new(timer.and_then(move |_| proxy_auth.authenticate_before_deadline(client, peer, &handle)))
+                },
+                Err(e) => return Box::new(future::err(e)),
+            },
+            None => proxy_auth.authenticate_before_deadline(client, peer, &handle),
+        };
+        let failures = self.clone();
+        Box::new(authenticated.and_then(move |(client, mut login)| {
+            let identity = login.tls_identity.clone();
+            if !access.check_user(&login.mapping.user, &login.mapping.access, &peer, identity.as_ref().map(|s| &s[..])) {
+                let msg = format!("Host '{}' is not allowed to connect as '{}'", peer.ip(), login.mapping.user);
+                return reject(client, login.next_sequence_id, ER_HOST_NOT_PRIVILEGED, msg);
+            }
+            // the certificate the user logged in with may route its sessions elsewhere
+            let cert_user = failures.cert_user(identity.as_ref().map(|s| &s[..]));
+            if let Some(CertUser { user, group: Some(group), .. }) = cert_user {
+                if user == login.mapping.user {
+                    login.mapping.default_group = group;
+                }
+            }
+            if let Some(ref policy) = login.mapping.schemas {
+                match policy.login_schema(login.response.database.as_ref().map(|db| &db[..])) {
+                    Ok(database) => login.response.database = database,
+                    Err(database) => {
+                        let msg = schemas::access_denied(&login.mapping.user, &peer.ip().to_string(), &database);
+                        return reject_with_state(client, login.next_sequence_id, ER_DBACCESS_DENIED_ERROR, *b"42000", msg);
+                    },
+                }
+            }
+            let quota = match login.mapping.quota {
+                Some(ref limits) => match quotas.connect(&login.mapping.user, limits) {
+                    Ok(lease) => Some(Arc::new(lease)),
+                    Err(msg) => return reject_with_state(client, login.next_sequence_id, ER_TOO_MANY_USER_CONNECTIONS, *b"42000", msg),
+                },
+                None => None,
+            };
+            let backend = match route(&login.mapping) {
+                Some(addr) => addr,
+                None => {
+                    let mut msg = format!("No backend available for routing group '{}'", login.mapping.default_group);
+                    let open = breaker.as_ref().map(|breaker| breaker.open_circuits()).unwrap_or_default();
+                    if !open.is_empty() {
+                        let open: Vec<String> = open.iter().map(|b| b.to_string()).collect();
+                        msg = format!("{}: the circuit breaker is open for {}", msg, open.join(", "));
+                    }
+                    return reject(client, login.next_sequence_id, ER_ACCESS_DENIED_ERROR, msg);
+                }
+            };
+            debug!("Routing user '{}' to {}", login.mapping.user, backend);
+            let backend_name = backend.clone();
+            let client_attrs = login.response.parse_connect_attrs().unwrap_or_else(|e| {
+                debug!("Ignoring malformed connection attributes: {}", e);
+                vec![]
+            });
+            let backend_attrs = attrs_config.backend_attrs(&client_attrs, &peer);
+            // the backend knows a tenant's default schema by its prefixed name
+            let mut response = login.response.clone();
+            if let Some(ref tenant) = login.mapping.tenant {
+                let tenant = tenant.for_user(&login.mapping.user);
+                response.database = response.database.map(|db| tenant.to_backend(&db));
+            }
+            if let Some(collation) = collation {
+                response.character_set = collation.id();
+            }
+
+            let backend_login = BackendLogin {
+                backend,
+                upstream: upstream.clone(),
+                socket: backend_socket,
+                handle: handle.clone(),
+                provider: provider.clone(),
+                mapping: login.mapping.clone(),
+                response,
+                disabled,
+                attrs: backend_attrs,
+                tolerant,
+                deprecate_eof,
+                compress,
+                error_rules: error_rules.clone(),
+                breaker: breaker.clone(),
+                retry: connect_retry.clone(),
+            };
+            if login.passthrough {
+                // the backend's OK or error reaches the client as part of the exchange
+                return Box::new(backend_login.connect().and_then(move |(server, backend)| {
+                    let user = login.response.username.clone();
+                    let (failed_backend, failed_breaker) = (backend_login.backend.clone(), backend_login.breaker.clone());
+                    let (ok_backend, ok_breaker) = (failed_backend.clone(), failed_breaker.clone());
+                    passthrough_backend(client, server, backend_login, login.next_sequence_id)
+                        .map_err(move |e| {
+                            if e.kind() == ErrorKind::PermissionDenied {
+                                failures.login_failed(peer, Some(&user), &e.to_string());
+                            } else if let Some(ref breaker) = failed_breaker {
+                                breaker.connect_failed(&failed_backend);
+                            }
+                            e
+                        })
+                        .map(move |(client, server, capabilities)| {
+                            if let Some(ref breaker) = ok_breaker {
+                                breaker.connected(&ok_backend);
+                            }
+                            (client, server, login.session(backend, backend_name, capabilities, peer, client_attrs, quota), None)
+                        })
+                }));
+            }
+
+            let relogin = BackendRelogin { login: backend_login.clone() };
+            let logged_in = match warm_pool {
+                Some(ref pool) => {
+                    let key = backend_login.warm_key();
+                    let warm = pool.take(&key);
+                    backend_login.warm_up(pool, &key);
+                    match warm {
+                        Some(connection) => backend_login.adopt(connection),
+                        None => backend_login.login(true),
+                    }
+                },
+                None => backend_login.login(true),
+            };
+            Box::new(logged_in.then(move |result| match result {
+                Ok((server, backend, capabilities)) => {
+                    let session = login.session(backend, backend_name, capabilities, peer, client_attrs, quota);
+                    let ok = ok_packet(login.next_sequence_id);
+                    Box::new(write_packet(client, ok).map(move |client| (client, server, session, Some(relogin))))
+                        as AuthFuture<_>
+                },
+                Err(e) => {
+                    let msg = format!("Backend login failed: {}", e);
+                    let (code, state, msg) = error_rules.rewrite_fields(ER_ACCESS_DENIED_ERROR, *b"28000", msg, &login.mapping.user);
+                    reject_with_state(client, login.next_sequence_id, code, state, msg)
+                }
+            }))
+        }).map(move |established| {
+            if let Some(ref tarpit) = tarpit {
+                tarpit.succeeded(peer.ip());
+            }
+            established
+        }))
+    }
+
+    /// Count a failed login from `peer` with the tarpit, and publish it
+    fn login_failed(&self, peer: SocketAddr, user: Option<&str>, reason: &str) {
+        if let Some(ref tarpit) = self.tarpit {
+            tarpit.failed(peer.ip());
+        }
+        self.events.publish_with(|| Event::AuthFailed {
+            client: peer,
+            user: user.map(|u| u.to_string()),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Authenticate a client, disconnecting it if it takes longer than the handshake timeout
+    fn authenticate_before_deadline(&self, client: TcpStream, peer: SocketAddr, handle: &Handle) -> AuthFuture<(ClientStream, ClientLogin)> {
+        let deadline = self.handshake_timeout.map(|timeout| Instant::now() + timeout);
+        let timer = match deadline.map(|deadline| Timeout::new_at(deadline, handle)) {
+            Some(Ok(timer)) => timer,
+            Some(Err(e)) => return Box::new(future::err(e)),
+            None => return self.authenticate_client(client, peer, None, handle),
+        };
+        let timeouts = self.handshake_timeouts.clone();
+        let authenticated = self.authenticate_client(client, peer, deadline, handle);
+        Box::new(authenticated.select2(timer).then(move |result| match result {
+            Ok(Either::A((authenticated, _))) => Ok(authenticated),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+            // the client answered the greeting and may have switched to TLS, so it isn't told
+            Ok(Either::B((_, authentication))) => {
+                info!("Client {} took too long to log in", peer);
+                timeouts.fetch_add(1, Ordering::Relaxed);
+                drop(authentication);
+                Err(Error::new(ErrorKind::TimedOut, "Client took too long to log in"))
+            },
+        }))
+    }
+
+    /// Send the proxy's greeting to the client and check the credentials it responds with,
+    /// telling it it took too long if it hasn't answered the greeting by `deadline`
+    fn authenticate_client(&self, client: TcpStream, peer: SocketAddr, deadline: Option<Instant>, handle: &Handle) -> AuthFuture<(ClientStream, ClientLogin)> {
+        let answer_timer = match deadline.map(|deadline| Timeout::new_at(deadline, handle)) {
+            Some(Ok(timer)) => Some(timer),
+            Some(Err(e)) => return Box::new(future::err(e)),
+            None => None,
+        };
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u32;
+        let scramble = match generate_scramble() {
+            Ok(scramble) => scramble,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let mut greeting = HandshakeV10 {
+            server_version: PROXY_SERVER_VERSION.to_string(),
+            connection_id,
+            capability_flags: self.capabilities(),
+            character_set: 0x21, // utf8_general_ci
+            status_flags: 0x0002, // SERVER_STATUS_AUTOCOMMIT
+            auth_plugin_data: scramble.clone(),
+            auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+        };
+        self.greeting.apply(&mut greeting);
+        let offered = greeting.capability_flags;
+
+        // external authenticators need the password itself rather than a scrambled hash
+        let (plugin, plugin_data) = match self.authenticator {
+            Some(_) => (CLEAR_PASSWORD_PLUGIN, vec![]),
+            None => (NATIVE_PASSWORD_PLUGIN, scramble.clone()),
+        };
+        let users = self.users.clone();
+        let authenticator = self.authenticator.clone();
+        let proxy_auth = self.clone();
+        let failures = self.clone();
+        let events = self.events.clone();
+        let tls_required = self.tls_required();
+        let timeouts = self.handshake_timeouts.clone();
+
+        Box::new(write_packet(client, greeting.to_packet(0))
+            .and_then(move |client| read_answer(client, answer_timer, peer, timeouts))
+            .and_then(move |(client, p)| -> AuthFuture<_> {
+                // X Protocol clients don't wait for the greeting, so their first message is
+                // read where the handshake response should be
+                if xprotocol::is_x_protocol(&p) {
+                    let msg = "X Protocol client connected to the classic protocol port";
+                    info!("Rejecting client: {}", msg);
+                    events.publish_with(|| Event::ProtocolViolation { client: peer, reason: msg.to_string() });
+                    return Box::new(write_packet(client, xprotocol::wrong_port_error())
+                        .and_then(move |_| future::err(Error::new(ErrorKind::InvalidData, msg))));
+                }
+                if p.sequence_id() != 1 {
+                    return out_of_order(client, &p, 1, &events, peer);
+                }
+                Box::new(future::ok((client, p)))
+            })
+            .and_then(move |(client, p)| proxy_auth.upgrade(client, p)
+                .map(move |(client, p, identity)| (proxy_auth, client, p, identity)))
+            .and_then(move |(proxy_auth, client, p, identity)| {
+                // the handshake response follows the SSLRequest after an upgrade to TLS
+                if client.is_tls() && p.sequence_id() != 2 {
+                    return out_of_order(client, &p, 2, &proxy_auth.events, peer);
+                }
+                if tls_required && !client.is_tls() {
+                    let msg = "Connections to this proxy must use TLS".to_string();
+                    proxy_auth.login_failed(peer, None, &msg);
+                    return reject(client, p.sequence_id().wrapping_add(1), ER_ACCESS_DENIED_ERROR, msg);
+                }
+                let mut response = match HandshakeResponse::parse(&p) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        proxy_auth.events.publish_with(|| Event::ProtocolViolation {
+                            client: peer,
+                            reason: format!("Malformed handshake response: {}", e),
+                        });
+                        return Box::new(future::err(e)) as AuthFuture<_>;
+                    },
+                };
+                // clients only get compression if the proxy offered it
+                response.capability_flags &= offered | !CLIENT_COMPRESS;
+
+                // a verified certificate vouches for the user, so no password is needed
+                if proxy_auth.cert_auth(identity.as_ref().map(|s| &s[..]), &response.username) {
+                    return Box::new(future::ok((client, response, p.sequence_id().wrapping_add(1), identity, Verification::Certificate)));
+                }
+
+                // the backend authenticates clients that relay their own exchange, so they
+                // must keep the plugin they chose
+                if proxy_auth.users.lookup(&response.username).map(|m| m.auth_passthrough).unwrap_or(false) {
+                    return Box::new(future::ok((client, response, p.sequence_id().wrapping_add(1), identity, Verification::Passthrough)));
+                }
+
+                // legacy clients of old_password users are checked against the pre-4.1 hash
+                let (plugin, plugin_data) = match proxy_auth.users.lookup(&response.username) {
+                    Some(ref m) if m.old_password && plugin == NATIVE_PASSWORD_PLUGIN => {
+                        let mut data = plugin_data[..8].to_vec();
+                        data.push(0);
+                        (OLD_PASSWORD_PLUGIN, data)
+                    },
+                    _ => (plugin, plugin_data),
+                };
+
+                // ask the client to switch if it didn't respond with the plugin we need
+                if response.auth_plugin_name.as_ref().map(|n| &n[..]) != Some(plugin) {
+                    let sequence_id = p.sequence_id().wrapping_add(1);
+                    let switch = if plugin == OLD_PASSWORD_PLUGIN && response.capability_flags & CLIENT_PLUGIN_AUTH == 0 {
+                        // clients from before pluggable authentication only know this request
+                        Packet::new(sequence_id, &[0xfe])
+                    } else {
+                        AuthSwitchRequest { plugin_name: plugin.to_string(), plugin_data }.to_packet(sequence_id)
+                    };
+                    let events = proxy_auth.events.clone();
+                    Box::new(write_packet(client, switch)
+                        .and_then(read_packet)
+                        .and_then(move |(client, p)| -> AuthFuture<_> {
+                            if p.sequence_id() != sequence_id.wrapping_add(1) {
+                                return out_of_order(client, &p, sequence_id.wrapping_add(1), &events, peer);
+                            }
+                            let mut response = response;
+                            response.auth_response = p.payload().to_vec();
+                            Box::new(future::ok((client, response, p.sequence_id().wrapping_add(1), identity, Verification::Password)))
+                        }))
+                } else {
+                    Box::new(future::ok((client, response, p.sequence_id().wrapping_add(1), identity, Verification::Password)))
+                }
+            })
+            .and_then(move |(client, response, next_sequence_id, tls_identity, verification)| {
+                let mapping = users.lookup(&response.username);
+                let valid: AuthFuture<bool> = match (mapping.clone(), authenticator) {
+                    (None, _) => Box::new(future::ok(false)),
+                    (Some(_), _) if verification != Verification::Password => Box::new(future::ok(true)),
+                    (Some(_), Some(authenticator)) => {
+                        let mut password = response.auth_response.clone();
+                        if password.last() == Some(&0) {
+                            password.pop();
+                        }
+                        let password = String::from_utf8_lossy(&password).into_owned();
+                        run_authenticator(authenticator, response.username.clone(), password)
+                    },
+                    (Some(ref m), None) => {
+                        Box::new(future::ok(verify_password(m, &scramble, &response.auth_response)))
+                    },
+                };
+                valid.then(move |valid| {
+                    let reason = match valid {
+                        Ok(true) => {
+                            return Box::new(future::ok((client, ClientLogin {
+                                connection_id,
+                                response,
+                                mapping: mapping.unwrap(),
+                                next_sequence_id,
+                                tls_identity,
+                                passthrough: verification == Verification::Passthrough,
+                            }))) as AuthFuture<_>;
+                        },
+                        Ok(false) if mapping.is_none() => "unknown user".to_string(),
+                        Ok(false) => "wrong password".to_string(),
+                        Err(e) => {
+                            warn!("Could not authenticate user '{}': {}", response.username, e);
+                            format!("authenticator failed: {}", e)
+                        },
+                    };
+                    failures.login_failed(peer, Some(&response.username), &reason);
+                    let msg = format!("Access denied for user '{}'", response.username);
+                    reject(client, next_sequence_id, ER_ACCESS_DENIED_ERROR, msg)
+                })
+            }))
+    }
+}
+
+/// Run a blocking authenticator on the pool's threads, so it doesn't stall the reactor
+fn run_authenticator(authenticator: AuthenticatorPool, user: String, password: String) -> AuthFuture<bool> {
+    let (tx, rx) = oneshot::channel();
+    if let Err(e) = authenticator.authenticate(user, password, move |valid| {
+        let _ = tx.send(valid);
+    }) {
+        return Box::new(future::err(e));
+    }
+    Box::new(rx
+        .map_err(|_| Error::other("Authenticator thread failed"))
+        .and_then(|r| r))
+}
+
+fn verify_password(mapping: &UserMapping, scramble: &[u8], auth_response: &[u8]) -> bool {
+    // users without a password can only be let in by an external authenticator
+    if mapping.password.as_ref().map(|p| p.is_empty()).unwrap_or(true) {
+        false
+    } else if mapping.old_password {
+        verify_old_password(&mapping.old_password_hash(), scramble, auth_response)
+    } else {
+        verify_native_password(&mapping.password_hash(), scramble, auth_response)
+    }
+}
+
+/// Everything needed to connect a client to its backend and log in there
+#[derive(Clone)]
+struct BackendLogin {
+    backend: BackendAddr,
+    upstream: Option<UpstreamProxy>,
+    socket: SocketOptions,
+    handle: Handle,
+    provider: Option<Arc<dyn CredentialProvider>>,
+    mapping: UserMapping,
+    response: HandshakeResponse,
+    disabled: u32,
+    attrs: Vec<(String, String)>,
+    tolerant: bool,
+    deprecate_eof: bool,
+    /// ask for the compressed protocol
+    compress: bool,
+    /// for the errors of failed logins
+    error_rules: ErrorRules,
+    breaker: Option<CircuitBreaker>,
+    retry: Option<ConnectRetryPolicy>,
+}
+
+impl BackendLogin {
+
+    /// Connect to the backend, resolving to the stream and the address it's connected to
+    fn connect(&self) -> AuthFuture<(TcpStream, SocketAddr)> {
+        let (socket, backend, breaker) = (self.socket, self.backend.clone(), self.breaker.clone());
+        let (upstream, target, handle) = (self.upstream.clone(), self.backend.clone(), self.handle.clone());
+        let attempt = move || connect_through(upstream.as_ref(), &target, &handle);
+        Box::new(retry_connect(self.retry.as_ref(), &self.backend.to_string(), &self.handle, attempt).and_then(move |server| {
+            socket.apply(&server)?;
+            let addr = server.peer_addr()?;
+            Ok((server, addr))
+        }).map_err(move |e| {
+            if let Some(ref breaker) = breaker {
+                breaker.connect_failed(&backend);
+            }
+            e
+        }))
+    }
+
+    /// Connect and log in. If the backend rejects credentials from a provider and `retry` is
+    /// set, the provider forgets them and the login is tried again, in case they were rotated.
+    /// Resolves to the stream, the address and the capabilities the login used.
+    fn login(self, retry: bool) -> AuthFuture<(TcpStream, SocketAddr, u32)> {
+        let credentials = backend_credentials(self.provider.clone(), &self.mapping, &self.backend);
+        let (response, disabled, attrs, tolerant) = (self.response.clone(), self.disabled, self.attrs.clone(), self.tolerant);
+        let mut optional = 0;
+        if self.deprecate_eof {
+            optional |= CLIENT_DEPRECATE_EOF;
+        }
+        if self.compress {
+            optional |= CLIENT_COMPRESS;
+        }
+        let (backend, breaker) = (self.backend.clone(), self.breaker.clone());
+        Box::new(self.connect().join(credentials)
+            .and_then(move |((server, addr), credentials)| {
+                login_backend(server, response, credentials, disabled, attrs, tolerant, optional)
+                    .map(move |(server, capabilities)| (server, addr, capabilities))
+                    .then(move |result| {
+                        // rejected credentials say nothing about the backend's health
+                        match (&result, breaker) {
+                            (Ok(_), Some(ref breaker)) => breaker.connected(&backend),
+                            (Err(e), Some(ref breaker)) if e.kind() != ErrorKind::PermissionDenied => {
+                                breaker.connect_failed(&backend)
+                            },
+                            _ => {},
+                        }
+                        result
+                    })
+            })
+            .then(move |result| -> AuthFuture<_> {
+                match (result, self.provider.clone()) {
+                    (Err(ref e), Some(ref provider)) if retry && e.kind() == ErrorKind::PermissionDenied => {
+                        info!("Backend {} rejected the credentials for '{}', retrying with fresh ones: {}",
+                              self.backend, self.mapping.backend_user, e);
+                        provider.invalidate(&self.mapping, &self.backend);
+                        self.login(false)
+                    },
+                    (result, _) => Box::new(future::result(result)),
+                }
+            }))
+    }
+}
+
+impl BackendLogin {
+
+    /// What a warm connection needs in common with this login to stand in for it
+    fn warm_key(&self) -> WarmKey {
+        WarmKey {
+            reactor: self.handle.id(),
+            backend: self.backend.clone(),
+            user: self.mapping.backend_user.clone(),
+            password: self.mapping.backend_password.clone(),
+            capability_flags: self.response.capability_flags,
+            character_set: self.response.character_set,
+        }
+    }
+
+    /// Log in to as many warm connections as `pool` is short of for `key`, in the background
+    fn warm_up(&self, pool: &WarmPool, key: &WarmKey) {
+        for _ in 0..pool.reserve(key) {
+            let mut login = self.clone();
+            // warm connections are made for no client in particular
+            login.response.database = None;
+            login.attrs = vec![];
+            let (pool, key, recycle, handle) = (pool.clone(), key.clone(), self.clone(), self.handle.clone());
+            self.handle.spawn(login.login(true).then(move |result| {
+                match result {
+                    Ok((server, addr, capabilities)) => {
+                        let id = pool.add(&key, server, addr, capabilities);
+                        // replaced once it's been idle too long
+                        match Timeout::new(pool.config().max_age(), &handle) {
+                            Ok(timer) => handle.spawn(timer.then(move |_| {
+                                if pool.retire(&key, id) {
+                                    recycle.warm_up(&pool, &key);
+                                }
+                                Ok(())
+                            })),
+                            Err(e) => warn!("Failed to time a warm connection to {}: {}", key.backend, e),
+                        }
+                    },
+                    Err(e) => {
+                        pool.failed(&key);
+                        debug!("Failed to log in a warm connection to {}: {}", key.backend, e);
+                    },
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    /// Use a warm connection for this login, switching it to the default schema the client
+    /// asked for
+    fn adopt(&self, connection: WarmConnection) -> AuthFuture<(TcpStream, SocketAddr, u32)> {
+        let WarmConnection { server, addr, capabilities, .. } = connection;
+        debug!("Using a warm connection to {} for '{}'", self.backend, self.mapping.user);
+        let database = match self.response.database {
+            Some(ref database) if !database.is_empty() => database.clone(),
+            _ => return Box::new(future::ok((server, addr, capabilities))),
+        };
+        Box::new(write_packet(server, Packet::com_init_db(&database)).and_then(read_packet).and_then(move |(server, p)| {
+            match p.payload().first() {
+                Some(&0x00) => Ok((server, addr, capabilities)),
+                Some(&0xff) => {
+                    let msg = String::from_utf8_lossy(&p.payload()[p.payload().len().min(9)..]).into_owned();
+                    Err(Error::new(ErrorKind::PermissionDenied, msg))
+                },
+                _ => Err(Error::new(ErrorKind::InvalidData, "Unexpected response to COM_INIT_DB")),
+            }
+        }))
+    }
+}
+
+/// Logs a session in to another backend the way it was logged in to its first, for moving
+/// it when its backend connection is lost
+#[derive(Clone)]
+pub struct BackendRelogin {
+    login: BackendLogin,
+}
+
+impl BackendRelogin {
+
+    /// Connect and log in to `backend`, resolving to the stream, the address and the
+    /// capabilities the login used
+    pub fn login(&self, backend: BackendAddr) -> AuthFuture<(TcpStream, SocketAddr, u32)> {
+        let mut login = self.login.clone();
+        login.backend = backend;
+        login.login(true)
+    }
+}
+
+/// The credentials to log in to `backend` with, from the provider on a thread of its own if
+/// there is one, otherwise from the user mapping
+fn backend_credentials(provider: Option<Arc<dyn CredentialProvider>>,
+                       mapping: &UserMapping,
+                       backend: &BackendAddr) -> AuthFuture<BackendCredentials> {
+    let provider = match provider {
+        Some(provider) => provider,
+        None => return Box::new(future::ok(BackendCredentials::mapped(mapping))),
+    };
+    let (tx, rx) = oneshot::channel();
+    let (mapping, backend) = (mapping.clone(), backend.clone());
+    thread::spawn(move || {
+        let _ = tx.send(provider.credentials(&mapping, &backend));
+    });
+    Box::new(rx
+        .map_err(|_| Error::other("Credential provider thread failed"))
+        .and_then(|r| r))
+}
+
+/// Log in to the backend with `credentials`, keeping the client's capabilities,
+/// character set and default schema, less any `disabled` capabilities, and sending `attrs`
+/// if the backend supports connection attributes. `tolerant` logins accept incomplete
+/// greetings and only ask for capabilities the backend advertises. The `optional`
+/// capabilities, such as CLIENT_DEPRECATE_EOF, are asked for if the backend supports them.
+/// Resolves to the stream and the capabilities the login used.
+fn login_backend(server: TcpStream,
+                 client: HandshakeResponse,
+                 credentials: BackendCredentials,
+                 disabled: u32,
+                 attrs: Vec<(String, String)>,
+                 tolerant: bool,
+                 optional: u32) -> AuthFuture<(TcpStream, u32)> {
+    Box::new(read_greeting(server, tolerant).and_then(move |(server, greeting)| {
+        let mut response = backend_response(&greeting, &client, disabled, &attrs, tolerant);
+        response.capability_flags |= greeting.capability_flags & optional & !disabled;
+        let capabilities = response.capability_flags;
+        response.username = credentials.user.clone();
+        if credentials.cleartext {
+            response.auth_response = clear_password_auth(&credentials.password);
+            response.auth_plugin_name = Some(CLEAR_PASSWORD_PLUGIN.to_string());
+        } else {
+            response.auth_response = native_password_auth(&credentials.password, &greeting.auth_plugin_data);
+            response.auth_plugin_name = Some(NATIVE_PASSWORD_PLUGIN.to_string());
+        }
+        write_packet(server, response.to_packet(1))
+            .and_then(move |server| backend_auth_result(server, credentials))
+            .map(move |server| (server, capabilities))
+    }))
+}
+
+/// Log in to the backend as the client, with the client's own authentication plugin, and
+/// relay the exchange until the backend accepts or rejects it. The client's first response
+/// was made for the proxy's greeting, so this suits plugins the backend switches the client
+/// to and that don't depend on the greeting's scramble, such as Kerberos and GSSAPI.
+/// Resolves to the streams and the capabilities the login used.
+fn passthrough_backend(client: ClientStream,
+                       server: TcpStream,
+                       login: BackendLogin,
+                       next_sequence_id: u8) -> AuthFuture<(ClientStream, TcpStream, u32)> {
+    // the client's sequence ids run ahead of the backend's by the TLS upgrade, if any
+    let offset = next_sequence_id.wrapping_sub(2);
+    Box::new(read_greeting(server, login.tolerant).and_then(move |(server, greeting)| {
+        let mut response = backend_response(&greeting, &login.response, login.disabled, &login.attrs, login.tolerant);
+        if login.compress {
+            response.capability_flags |= greeting.capability_flags & CLIENT_COMPRESS & !login.disabled;
+        }
+        debug!("Relaying authentication of '{}' with {:?} to the backend", response.username, response.auth_plugin_name);
+        let capabilities = response.capability_flags;
+        let (errors, user) = (login.error_rules, login.mapping.user);
+        write_packet(server, response.to_packet(1))
+            .and_then(move |server| relay_auth(client, server, offset, errors, user))
+            .map(move |(client, server)| (client, server, capabilities))
+    }))
+}
+
+/// Relay an authentication exchange between the client and the backend until it ends in an
+/// OK or an error, which the client is sent too, rewritten by `errors`
+fn relay_auth(client: ClientStream,
+              server: TcpStream,
+              offset: u8,
+              errors: ErrorRules,
+              user: String) -> AuthFuture<(ClientStream, TcpStream)> {
+    Box::new(read_packet(server).and_then(move |(server, p)| {
+        let header = p.payload().first().cloned();
+        let p = match header {
+            Some(0xff) => errors.rewrite(&p, &user).unwrap_or(p),
+            _ => p,
+        };
+        // caching_sha2_password's fast authentication success is followed by the OK
+        let more_from_server = p.payload() == [0x01, 0x03];
+        let error = String::from_utf8_lossy(&p.payload()[p.payload().len().min(9)..]).into_owned();
+        let sequence_id = p.sequence_id().wrapping_add(offset);
+        write_packet(client, p.with_sequence_id(sequence_id)).and_then(move |client| -> AuthFuture<_> {
+            match header {
+                Some(0x00) => Box::new(future::ok((client, server))),
+                Some(0xff) => Box::new(future::err(Error::new(ErrorKind::PermissionDenied, error))),
+                _ if more_from_server => relay_auth(client, server, offset, errors, user),
+                // auth switch requests and plugin data, which the client answers
+                _ => Box::new(read_packet(client).and_then(move |(client, p)| -> AuthFuture<_> {
+                    if p.sequence_id() != sequence_id.wrapping_add(1) {
+                        let msg = format!("Client sent sequence id {} where {} was expected", p.sequence_id(), sequence_id.wrapping_add(1));
+                        return reject_with_state(client, p.sequence_id().wrapping_add(1), ER_NET_PACKETS_OUT_OF_ORDER, *b"08S01", msg);
+                    }
+                    let sequence_id = p.sequence_id().wrapping_sub(offset);
+                    Box::new(write_packet(server, p.with_sequence_id(sequence_id))
+                        .and_then(move |server| relay_auth(client, server, offset, errors, user)))
+                })),
+            }
+        })
+    }))
+}
+
+fn read_greeting(server: TcpStream, tolerant: bool) -> AuthFuture<(TcpStream, HandshakeV10)> {
+    Box::new(read_packet(server).and_then(move |(server, p)| {
+        let greeting = if tolerant { HandshakeV10::parse_lenient(&p) } else { HandshakeV10::parse(&p) };
+        greeting.map(|g| (server, g))
+    }))
+}
+
+/// The handshake response for a backend, from the client's, keeping its capabilities,
+/// character set and default schema, less any `disabled` capabilities, and sending `attrs`
+/// if the backend supports connection attributes
+fn backend_response(greeting: &HandshakeV10,
+                    client: &HandshakeResponse,
+                    disabled: u32,
+                    attrs: &[(String, String)],
+                    tolerant: bool) -> HandshakeResponse {
+    let mut capability_flags = client.capability_flags & greeting.capability_flags & PROXY_CAPABILITIES & !disabled;
+    let mut required = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH;
+    if tolerant {
+        required &= greeting.capability_flags | CLIENT_PROTOCOL_41;
+    }
+    capability_flags |= required;
+    if client.database.is_some() {
+        capability_flags |= CLIENT_CONNECT_WITH_DB;
+    }
+    let mut response = HandshakeResponse {
+        capability_flags,
+        connect_attrs: None,
+        ..client.clone()
+    };
+    if greeting.capability_flags & CLIENT_CONNECT_ATTRS != 0 {
+        response.set_connect_attrs(attrs);
+    }
+    response
+}
+
+/// Wait for the backend to accept the login, answering any auth switch requests
+fn backend_auth_result(server: TcpStream, credentials: BackendCredentials) -> AuthFuture<TcpStream> {
+    Box::new(read_packet(server).and_then(move |(server, p)| -> AuthFuture<TcpStream> {
+        match p.payload().first() {
+            Some(&0x00) => Box::new(future::ok(server)),
+            Some(&0xfe) => {
+                let switch = match AuthSwitchRequest::parse(&p) {
+                    Ok(s) => s,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                // only tokens meant for it are ever sent in clear text
+                let auth = match (&switch.plugin_name[..], credentials.cleartext) {
+                    (NATIVE_PASSWORD_PLUGIN, false) => native_password_auth(&credentials.password, &switch.plugin_data),
+                    (CLEAR_PASSWORD_PLUGIN, true) => clear_password_auth(&credentials.password),
+                    _ => {
+                        let msg = format!("Unsupported backend auth plugin '{}'", switch.plugin_name);
+                        return Box::new(future::err(Error::new(ErrorKind::PermissionDenied, msg)));
+                    },
+                };
+                Box::new(write_packet(server, Packet::new(p.sequence_id().wrapping_add(1), &auth))
+                    .and_then(move |server| backend_auth_result(server, credentials)))
+            },
+            Some(&0xff) => {
+                let msg = String::from_utf8_lossy(&p.payload()[p.payload().len().min(9)..]).into_owned();
+                Box::new(future::err(Error::new(ErrorKind::PermissionDenied, msg)))
+            },
+            _ => Box::new(future::err(Error::new(ErrorKind::InvalidData, "Unexpected packet during backend login"))),
+        }
+    }))
+}
+
+/// Send an error to the client and fail the connection
+fn reject<S, T>(client: S, sequence_id: u8, code: u16, msg: String) -> AuthFuture<T>
+    where S: AsyncWrite + 'static, T: 'static
+{
+    reject_with_state(client, sequence_id, code, *b"28000", msg)
+}
+
+/// Tell a client whose packet `p` should have had `expected` as its sequence id that its
+/// packets are out of order, publish the violation and fail the connection
+fn out_of_order<S, T>(client: S, p: &Packet, expected: u8, events: &EventBus, peer: SocketAddr) -> AuthFuture<T>
+    where S: AsyncWrite + 'static, T: 'static
+{
+    let msg = format!("Client sent sequence id {} where {} was expected", p.sequence_id(), expected);
+    events.publish_with(|| Event::ProtocolViolation { client: peer, reason: msg.clone() });
+    reject_with_state(client, p.sequence_id().wrapping_add(1), ER_NET_PACKETS_OUT_OF_ORDER, *b"08S01", msg)
+}
+
+/// Send an error with a SQL state other than access denied's to the client and fail the
+/// connection
+fn reject_with_state<S, T>(client: S, sequence_id: u8, code: u16, state: [u8; 5], msg: String) -> AuthFuture<T>
+    where S: AsyncWrite + 'static, T: 'static
+{
+    info!("Rejecting client: {}", msg);
+    let error = Packet::error_packet(code, state, msg.clone()).with_sequence_id(sequence_id);
+    Box::new(write_packet(client, error)
+        .and_then(move |_| future::err(Error::new(ErrorKind::PermissionDenied, msg))))
+}
+
+/// Read the client's answer to the greeting, telling it with ER_NET_READ_INTERRUPTED if
+/// `timer` expires first. The socket is split so the error can be written through its write
+/// half while the read still holds the other.
+fn read_answer(client: TcpStream, timer: Option<Timeout>, peer: SocketAddr, timeouts: Arc<AtomicUsize>) -> AuthFuture<(TcpStream, Packet)> {
+    let timer = match timer {
+        Some(timer) => timer,
+        None => return read_packet(client),
+    };
+    let (reader, mut writer) = client.split();
+    Box::new(read_packet(reader).select2(timer).then(move |result| match result {
+        Ok(Either::A(((reader, p), _))) => Ok((reader.unsplit(writer), p)),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        Ok(Either::B((_, read))) => {
+            info!("Client {} took too long to log in", peer);
+            timeouts.fetch_add(1, Ordering::Relaxed);
+            drop(read);
+            // without waiting, so whatever doesn't fit in the socket's send buffer is left out
+            let error = Packet::error_packet(ER_NET_READ_INTERRUPTED, *b"08S01", "Got timeout reading communication packets".to_string())
+                .with_sequence_id(1);
+            if let Err(e) = writer.write(&error.bytes) {
+                debug!("Could not tell {} it took too long to log in: {}", peer, e);
+            }
+            Err(Error::new(ErrorKind::TimedOut, "Client took too long to log in"))
+        },
+    }))
+}
+
+/// Read a single packet from a stream
+pub fn read_packet<S: AsyncRead + 'static>(stream: S) -> AuthFuture<(S, Packet)> {
+    Box::new(read_exact(stream, [0_u8; 4]).and_then(|(stream, header)| {
+        let len = parse_packet_length(&header);
+        read_exact(stream, vec![0_u8; len]).map(move |(stream, payload)| {
+            let mut bytes = header.to_vec();
+            bytes.extend_from_slice(&payload);
+            (stream, Packet { bytes })
+        })
+    }))
+}
+
+/// Write a single packet to a stream
+pub fn write_packet<S: AsyncWrite + 'static>(stream: S, p: Packet) -> AuthFuture<S> {
+    Box::new(write_all(stream, p.bytes).map(|(stream, _)| stream))
+}
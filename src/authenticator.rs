@@ -0,0 +1,572 @@
+//! Pluggable validation of client credentials at the proxy.
+//!
+//! Authenticators need the client's password in plain text, so when one is configured the
+//! proxy asks clients to switch to the mysql_clear_password plugin. Clients have to allow
+//! this, e.g. `mysql --enable-cleartext-plugin`, and the configuration is only valid if
+//! clients must use TLS. The password is passed on to LDAP servers over LDAPS or StartTLS,
+//! and to webhooks over HTTPS, which need the `tls` feature.
+//!
+//! Authenticators are blocking, so an `AuthenticatorPool` runs them on a fixed number of
+//! threads. Logins beyond those waiting in its bounded queue fail at once, so a slow external
+//! system can't make the proxy start a thread for every login attempt.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha1::Sha1;
+
+/// Validates a user's credentials against an external system
+pub trait Authenticator: Send + Sync {
+    /// Returns `Ok(false)` for invalid credentials and `Err` if the check couldn't be made
+    fn authenticate(&self, user: &str, password: &str) -> Result<bool>;
+}
+
+/// A check waiting for a thread, with what to do with its result
+type Check = (String, String, Box<dyn FnOnce(Result<bool>) + Send>);
+
+/// Runs an authenticator on a fixed number of threads
+#[derive(Clone)]
+pub struct AuthenticatorPool {
+    sender: SyncSender<Check>,
+}
+
+impl AuthenticatorPool {
+
+    /// Run `authenticator` on `threads` threads, with at most `max_queued` checks waiting
+    pub fn new(authenticator: Arc<dyn Authenticator>, threads: usize, max_queued: usize) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Check>(max_queued);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let (authenticator, receiver) = (authenticator.clone(), receiver.clone());
+            thread::Builder::new().name("mysql-proxy-auth".to_string()).spawn(move || loop {
+                let check = receiver.lock().unwrap().recv();
+                match check {
+                    Ok((user, password, done)) => done(authenticator.authenticate(&user, &password)),
+                    Err(_) => break,
+                }
+            })?;
+        }
+        Ok(AuthenticatorPool { sender })
+    }
+
+    /// Check a user's credentials on one of the threads, which calls `done` with the result,
+    /// or fail at once if the queue is full
+    pub fn authenticate<F>(&self, user: String, password: String, done: F) -> Result<()>
+        where F: FnOnce(Result<bool>) + Send + 'static
+    {
+        self.sender.try_send((user, password, Box::new(done))).map_err(|e| match e {
+            TrySendError::Full(_) => Error::new(ErrorKind::WouldBlock, "too many logins waiting for the authenticator"),
+            TrySendError::Disconnected(_) => Error::other("the authenticator's threads have stopped"),
+        })
+    }
+}
+
+/// Caches successful authentications for a fixed time, so that applications opening many
+/// connections don't hit the external system for every one of them
+pub struct CachingAuthenticator<A: Authenticator> {
+    inner: A,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, ([u8; 20], Instant)>>,
+}
+
+impl<A> CachingAuthenticator<A> where A: Authenticator {
+
+    pub fn new(inner: A, ttl: Duration) -> Self {
+        CachingAuthenticator { inner, ttl, cache: Mutex::new(HashMap::new()) }
+    }
+
+    /// Forget all cached authentications, e.g. after a password change
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<A> Authenticator for CachingAuthenticator<A> where A: Authenticator {
+
+    fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        // only a hash of the password is kept in memory
+        let hash = Sha1::from(password).digest().bytes();
+        if let Some(&(cached, expires)) = self.cache.lock().unwrap().get(user) {
+            if cached == hash && Instant::now() < expires {
+                return Ok(true);
+            }
+        }
+        let valid = self.inner.authenticate(user, password)?;
+        let mut cache = self.cache.lock().unwrap();
+        if valid {
+            cache.insert(user.to_string(), (hash, Instant::now() + self.ttl));
+        } else {
+            cache.remove(user);
+        }
+        Ok(valid)
+    }
+}
+
+impl Authenticator for Arc<dyn Authenticator> {
+
+    fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        (**self).authenticate(user, password)
+    }
+}
+
+/// How the connection to an LDAP server is secured
+#[derive(Clone,Copy,Debug,Default,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LdapTls {
+    /// plain LDAP, the password crosses the network in clear text
+    #[default]
+    None,
+    /// TLS from the start, usually on port 636
+    Ldaps,
+    /// plain LDAP upgraded with the StartTLS extended operation before binding
+    StartTls,
+}
+
+/// Validates credentials with an LDAP simple bind
+pub struct LdapAuthenticator {
+    /// address of the LDAP server, e.g. `ldap.example.com:389`
+    pub addr: String,
+    /// DN to bind as, with `{user}` replaced by the user name,
+    /// e.g. `uid={user},ou=people,dc=example,dc=com`
+    pub bind_dn: String,
+    pub tls: LdapTls,
+    /// PEM encoded CA certificates the server's certificate is verified against, if not the
+    /// well-known roots
+    pub ca: Option<PathBuf>,
+    pub timeout: Duration,
+}
+
+impl LdapAuthenticator {
+
+    pub fn new(addr: &str, bind_dn: &str) -> Self {
+        LdapAuthenticator {
+            addr: addr.to_string(),
+            bind_dn: bind_dn.to_string(),
+            tls: LdapTls::None,
+            ca: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Secure the connection with `tls`, verifying the server against `ca`
+    pub fn with_tls(mut self, tls: LdapTls, ca: Option<&Path>) -> Self {
+        self.tls = tls;
+        self.ca = ca.map(|ca| ca.to_path_buf());
+        self
+    }
+}
+
+/// LDAP resultCode for a successful operation
+const LDAP_SUCCESS: u64 = 0;
+/// LDAP resultCode for invalidCredentials
+const LDAP_INVALID_CREDENTIALS: u64 = 49;
+
+/// [APPLICATION 1] BindResponse
+const LDAP_BIND_RESPONSE: u8 = 0x61;
+/// [APPLICATION 24] ExtendedResponse
+const LDAP_EXTENDED_RESPONSE: u8 = 0x78;
+
+/// The requestName of the StartTLS extended operation
+const LDAP_START_TLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+impl Authenticator for LdapAuthenticator {
+
+    fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        // an empty password would be an unauthenticated bind, which always succeeds
+        if password.is_empty() {
+            return Ok(false);
+        }
+        let dn = self.bind_dn.replace("{user}", &escape_dn_value(user));
+        let mut tcp = connect(&self.addr, self.timeout)?;
+        let (mut stream, message_id): (Box<dyn Stream>, u8) = match self.tls {
+            LdapTls::None => (Box::new(tcp), 1),
+            LdapTls::Ldaps => (tls_connect(tcp, &self.addr, self.ca.as_deref())?, 1),
+            LdapTls::StartTls => {
+                tcp.write_all(&ldap_start_tls_request(1))?;
+                match read_ldap_result(&mut tcp, LDAP_EXTENDED_RESPONSE)? {
+                    LDAP_SUCCESS => {},
+                    code => return Err(Error::other(format!("LDAP StartTLS failed with result code {}", code))),
+                }
+                (tls_connect(tcp, &self.addr, self.ca.as_deref())?, 2)
+            },
+        };
+        stream.write_all(&ldap_bind_request(message_id, &dn, password))?;
+        stream.flush()?;
+        match read_ldap_result(&mut stream, LDAP_BIND_RESPONSE)? {
+            LDAP_SUCCESS => Ok(true),
+            LDAP_INVALID_CREDENTIALS => Ok(false),
+            code => Err(Error::other(format!("LDAP bind failed with result code {}", code))),
+        }
+    }
+}
+
+/// Read a response of type `op` and return its resultCode
+fn read_ldap_result<S: Read + ?Sized>(stream: &mut S, op: u8) -> Result<u64> {
+    let mut response = vec![0_u8; 1024];
+    let mut len = 0;
+    loop {
+        let n = stream.read(&mut response[len..])?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "LDAP server closed connection"));
+        }
+        len += n;
+        if let Some(code) = ldap_result(&response[0..len], op)? {
+            return Ok(code);
+        }
+        if len == response.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "LDAP response too large"));
+        }
+    }
+}
+
+/// Escape the characters that are special in an RFC 4514 distinguished name
+fn escape_dn_value(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if ",+\"\\<>;=#".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn ber_length(buf: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        buf.push(len as u8);
+        return;
+    }
+    // the long form: the number of length bytes, then the length big-endian
+    let n = match len {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x10000..=0xff_ffff => 3,
+        _ => 4,
+    };
+    buf.push(0x80 | n as u8);
+    for i in (0..n).rev() {
+        buf.push((len >> (8 * i)) as u8);
+    }
+}
+
+fn ber(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut buf = vec![tag];
+    ber_length(&mut buf, contents.len());
+    buf.extend_from_slice(contents);
+    buf
+}
+
+/// Encode an LDAPv3 simple BindRequest message
+fn ldap_bind_request(message_id: u8, dn: &str, password: &str) -> Vec<u8> {
+    let mut bind = ber(0x02, &[3]); // version
+    bind.extend(ber(0x04, dn.as_bytes())); // name
+    bind.extend(ber(0x80, password.as_bytes())); // simple authentication
+    let mut message = ber(0x02, &[message_id]);
+    message.extend(ber(0x60, &bind)); // [APPLICATION 0] BindRequest
+    ber(0x30, &message)
+}
+
+/// Encode an LDAPv3 StartTLS ExtendedRequest message
+fn ldap_start_tls_request(message_id: u8) -> Vec<u8> {
+    let mut message = ber(0x02, &[message_id]);
+    message.extend(ber(0x77, &ber(0x80, LDAP_START_TLS_OID))); // [APPLICATION 23] ExtendedRequest
+    ber(0x30, &message)
+}
+
+/// Read a BER tag and length, returning the length and the position of the contents, or
+/// `None` if more data is needed
+fn ber_header(buf: &[u8], pos: usize) -> Result<Option<(u8, usize, usize)>> {
+    let (tag, first) = match (buf.get(pos), buf.get(pos + 1)) {
+        (Some(&tag), Some(&first)) => (tag, first as usize),
+        _ => return Ok(None),
+    };
+    if first < 0x80 {
+        return Ok(Some((tag, first, pos + 2)));
+    }
+    // LDAP doesn't allow the indefinite form, and no message needs more than 4 length bytes
+    let n = first & 0x7f;
+    if n == 0 || n > 4 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Invalid BER length with {} bytes", n)));
+    }
+    let mut len = 0;
+    for i in 0..n {
+        len = match buf.get(pos + 2 + i) {
+            Some(&b) => (len << 8) | b as usize,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some((tag, len, pos + 2 + n)))
+}
+
+/// Extract the resultCode from a response of type `op`, or `None` if more data is needed
+fn ldap_result(buf: &[u8], op: u8) -> Result<Option<u64>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid LDAP response");
+    let (tag, len, pos) = match ber_header(buf, 0)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    if tag != 0x30 {
+        return Err(invalid());
+    }
+    if buf.len() < pos + len {
+        return Ok(None);
+    }
+    // skip the message id
+    let (_, id_len, id_pos) = ber_header(buf, pos)?.ok_or_else(invalid)?;
+    let (response_op, _, op_pos) = ber_header(buf, id_pos + id_len)?.ok_or_else(invalid)?;
+    if response_op != op {
+        return Err(invalid());
+    }
+    let (code_tag, code_len, code_pos) = ber_header(buf, op_pos)?.ok_or_else(invalid)?;
+    if code_tag != 0x0a || code_len == 0 || buf.len() < code_pos + code_len {
+        return Err(invalid());
+    }
+    Ok(Some(buf[code_pos..code_pos + code_len].iter().fold(0, |n, b| (n << 8) | *b as u64)))
+}
+
+/// Validates credentials by POSTing them as JSON to an HTTP endpoint. A 2xx response means
+/// the credentials are valid, 401 and 403 mean they are not.
+pub struct WebhookAuthenticator {
+    /// the endpoint, e.g. `https://auth.internal:8443/mysql-login`
+    pub url: String,
+    /// PEM encoded CA certificates an HTTPS endpoint's certificate is verified against, if
+    /// not the well-known roots
+    pub ca: Option<PathBuf>,
+    pub timeout: Duration,
+}
+
+impl WebhookAuthenticator {
+
+    pub fn new(url: &str) -> Self {
+        WebhookAuthenticator { url: url.to_string(), ca: None, timeout: Duration::from_secs(5) }
+    }
+
+    /// Verify an HTTPS endpoint against `ca`
+    pub fn with_ca(mut self, ca: &Path) -> Self {
+        self.ca = Some(ca.to_path_buf());
+        self
+    }
+}
+
+impl Authenticator for WebhookAuthenticator {
+
+    fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        let (https, host, path) = parse_http_url(&self.url)?;
+        let body = json!({ "user": user, "password": password }).to_string();
+        let request = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                               Content-Length: {}\r\n\r\n{}", path, host, body.len(), body);
+
+        let tcp = connect(&host, self.timeout)?;
+        let mut stream: Box<dyn Stream> = if https {
+            tls_connect(tcp, &host, self.ca.as_deref())?
+        } else {
+            Box::new(tcp)
+        };
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        match stream.read_to_end(&mut response) {
+            Ok(_) => {},
+            // servers that close without close_notify have still sent the whole response
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof && https && !response.is_empty() => {},
+            Err(e) => return Err(e),
+        }
+        let response = String::from_utf8_lossy(&response);
+
+        let status = response.split_whitespace().nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid HTTP response"))?;
+        match status {
+            200..=299 => Ok(true),
+            401 | 403 => Ok(false),
+            _ => Err(Error::other(format!("Webhook returned HTTP {}", status))),
+        }
+    }
+}
+
+/// Split an `http://host:port/path` or `https://` URL into whether it's HTTPS, the host and
+/// the path
+fn parse_http_url(url: &str) -> Result<(bool, String, String)> {
+    let (https, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported URL '{}'", url))),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[0..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = match (host.rsplit_once(':'), https) {
+        (Some((_, port)), _) if !port.ends_with(']') => host.to_string(),
+        (_, true) => format!("{}:443", host),
+        (_, false) => format!("{}:80", host),
+    };
+    Ok((https, host, path.to_string()))
+}
+
+/// A connection to an external system, over TLS or not
+trait Stream: Read + Write {}
+
+impl<T> Stream for T where T: Read + Write {}
+
+/// Start TLS on `stream` to `host`, given as `name:port`, verifying its certificate against
+/// `ca` or the well-known roots
+#[cfg(feature = "tls")]
+fn tls_connect(stream: TcpStream, host: &str, ca: Option<&Path>) -> Result<Box<dyn Stream>> {
+    use std::convert::TryFrom;
+
+    let invalid_input = |e: String| Error::new(ErrorKind::InvalidInput, e);
+    let mut roots = rustls::RootCertStore::empty();
+    match ca {
+        Some(ca) => for cert in super::tls::load_certs(ca)? {
+            roots.add(cert).map_err(|e| invalid_input(e.to_string()))?;
+        },
+        #[cfg(any(feature = "vault", feature = "aws-secrets"))]
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        #[cfg(not(any(feature = "vault", feature = "aws-secrets")))]
+        None => return Err(invalid_input(format!("No CA to verify {} against", host))),
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_input(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host).trim_matches(|c| c == '[' || c == ']');
+    let name = rustls::pki_types::ServerName::try_from(name.to_string()).map_err(|e| invalid_input(e.to_string()))?;
+    let connection = rustls::ClientConnection::new(Arc::new(config), name).map_err(|e| invalid_input(e.to_string()))?;
+    Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_connect(_stream: TcpStream, _host: &str, _ca: Option<&Path>) -> Result<Box<dyn Stream>> {
+    Err(Error::new(ErrorKind::Unsupported, "LDAPS, StartTLS and HTTPS require the 'tls' feature"))
+}
+
+/// Connect to `addr` with `timeout` applying to the connection and to every read and write
+pub fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let addr = addr.to_socket_addrs()?.next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Could not resolve {}", addr)))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+#[cfg(feature = "pam")]
+pub use self::pam::PamAuthenticator;
+
+#[cfg(feature = "pam")]
+mod pam {
+    use std::ffi::CString;
+    use std::io::{Error, Result};
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::ptr;
+
+    use super::Authenticator;
+
+    const PAM_SUCCESS: c_int = 0;
+    const PAM_BUF_ERR: c_int = 5;
+    const PAM_CONV_ERR: c_int = 19;
+    const PAM_PROMPT_ECHO_OFF: c_int = 1;
+
+    #[repr(C)]
+    struct PamMessage {
+        msg_style: c_int,
+        msg: *const c_char,
+    }
+
+    #[repr(C)]
+    struct PamResponse {
+        resp: *mut c_char,
+        resp_retcode: c_int,
+    }
+
+    #[repr(C)]
+    struct PamConv {
+        conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+        appdata_ptr: *mut c_void,
+    }
+
+    #[link(name = "pam")]
+    extern "C" {
+        fn pam_start(service: *const c_char, user: *const c_char, conv: *const PamConv,
+                     handle: *mut *mut c_void) -> c_int;
+        fn pam_authenticate(handle: *mut c_void, flags: c_int) -> c_int;
+        fn pam_acct_mgmt(handle: *mut c_void, flags: c_int) -> c_int;
+        fn pam_end(handle: *mut c_void, status: c_int) -> c_int;
+    }
+
+    extern "C" {
+        fn calloc(n: usize, size: usize) -> *mut c_void;
+        fn free(p: *mut c_void);
+        fn strdup(s: *const c_char) -> *mut c_char;
+    }
+
+    /// Answers password prompts with the password passed as `appdata_ptr`
+    extern "C" fn converse(n: c_int, msgs: *mut *const PamMessage, resp: *mut *mut PamResponse,
+                           appdata: *mut c_void) -> c_int {
+        unsafe {
+            // PAM frees the responses with free(), so they must come from the C allocator
+            let responses = calloc(n as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+            if responses.is_null() {
+                return PAM_BUF_ERR;
+            }
+            for i in 0..n as isize {
+                let msg = &**msgs.offset(i);
+                if msg.msg_style == PAM_PROMPT_ECHO_OFF {
+                    (*responses.offset(i)).resp = strdup(appdata as *const c_char);
+                } else if msg.msg_style < 1 || msg.msg_style > 4 {
+                    for j in 0..i {
+                        free((*responses.offset(j)).resp as *mut c_void);
+                    }
+                    free(responses as *mut c_void);
+                    return PAM_CONV_ERR;
+                }
+            }
+            *resp = responses;
+        }
+        PAM_SUCCESS
+    }
+
+    /// Validates credentials with the system's PAM stack, using the given service name
+    pub struct PamAuthenticator {
+        pub service: String,
+    }
+
+    impl PamAuthenticator {
+
+        pub fn new(service: &str) -> Self {
+            PamAuthenticator { service: service.to_string() }
+        }
+    }
+
+    impl Authenticator for PamAuthenticator {
+
+        fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+            let service = CString::new(self.service.clone())?;
+            let user = CString::new(user)?;
+            let password = CString::new(password)?;
+            let conv = PamConv { conv: converse, appdata_ptr: password.as_ptr() as *mut c_void };
+            unsafe {
+                let mut handle = ptr::null_mut();
+                let status = pam_start(service.as_ptr(), user.as_ptr(), &conv, &mut handle);
+                if status != PAM_SUCCESS {
+                    return Err(Error::other(format!("pam_start failed with {}", status)));
+                }
+                let mut status = pam_authenticate(handle, 0);
+                if status == PAM_SUCCESS {
+                    status = pam_acct_mgmt(handle, 0);
+                }
+                pam_end(handle, status);
+                Ok(status == PAM_SUCCESS)
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! The backends of routing groups, and spreading a group's sessions over them by weight.
+//!
+//! A `BackendPool` holds every group's backends, as configured or as discovery finds them.
+//! Every backend has a weight, 1 for the first backend in its group and 0 for the others
+//! unless it's been given one, e.g. by discovery, so by default every session goes to the
+//! first backend. Giving other backends a weight spreads sessions over them in proportion,
+//! with smooth weighted round robin, and a weight of 0 drains a backend of new sessions.
+//! Weights are shared by every listener and can be changed at runtime, e.g. through the
+//! management API. A backend that comes back up after being down can be warmed up: its
+//! weight starts at nothing and grows to its full weight over a window, so its caches fill
+//! under a trickle of sessions rather than all of its share at once.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::config::RoutingGroup;
+use super::connect::BackendAddr;
+
+/// Shared backends of every routing group
+#[derive(Clone,Debug,Default)]
+pub struct BackendPool {
+    groups: Arc<RwLock<BTreeMap<String, Vec<BackendAddr>>>>,
+}
+
+impl BackendPool {
+
+    pub fn new(groups: &HashMap<String, RoutingGroup>) -> Self {
+        let groups = groups.iter().map(|(name, group)| (name.clone(), group.backends.clone())).collect();
+        BackendPool { groups: Arc::new(RwLock::new(groups)) }
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.read().unwrap().contains_key(group)
+    }
+
+    /// A group's backends, none if there's no such group
+    pub fn backends(&self, group: &str) -> Vec<BackendAddr> {
+        self.groups.read().unwrap().get(group).cloned().unwrap_or_default()
+    }
+
+    /// Every group's backends
+    pub fn groups(&self) -> BTreeMap<String, Vec<BackendAddr>> {
+        self.groups.read().unwrap().clone()
+    }
+
+    /// Every backend in any group, once each
+    pub fn all(&self) -> Vec<BackendAddr> {
+        let mut backends: Vec<BackendAddr> = self.groups.read().unwrap().values().flatten().cloned().collect();
+        backends.sort_by_key(|b| b.to_string());
+        backends.dedup();
+        backends
+    }
+
+    /// Replace a group's backends, returning whether they changed
+    pub fn set(&self, group: &str, backends: Vec<BackendAddr>) -> bool {
+        let mut groups = self.groups.write().unwrap();
+        if groups.get(group) == Some(&backends) {
+            return false;
+        }
+        groups.insert(group.to_string(), backends);
+        true
+    }
+}
+
+#[derive(Debug,Default)]
+struct WeightState {
+    weights: HashMap<BackendAddr, u32>,
+    /// weights for backends that haven't been set one
+    defaults: HashMap<BackendAddr, u32>,
+    /// how far each backend is owed sessions
+    current: HashMap<BackendAddr, i64>,
+    /// backends warming up, with when they started and how long it takes
+    warming: HashMap<BackendAddr, (Instant, Duration)>,
+}
+
+/// Shared backend weights
+#[derive(Clone,Debug,Default)]
+pub struct BackendWeights {
+    state: Arc<Mutex<WeightState>>,
+}
+
+impl BackendWeights {
+
+    pub fn new() -> Self {
+        BackendWeights::default()
+    }
+
+    pub fn set(&self, backend: &BackendAddr, weight: u32) {
+        info!("Backend {} weight set to {}", backend, weight);
+        self.state.lock().unwrap().weights.insert(backend.clone(), weight);
+    }
+
+    /// Give a backend a weight for as long as it isn't set one
+    pub fn set_default(&self, backend: &BackendAddr, weight: u32) {
+        self.state.lock().unwrap().defaults.insert(backend.clone(), weight);
+    }
+
+    /// Ramp a backend's weight up from nothing over `window`, e.g. once it's back up
+    pub fn warm_up(&self, backend: &BackendAddr, window: Duration) {
+        info!("Backend {} warming up over {}s", backend, window.as_secs());
+        self.state.lock().unwrap().warming.insert(backend.clone(), (Instant::now(), window));
+    }
+
+    /// The weight a backend has in a group where it comes at `position`
+    pub fn weight(&self, backend: &BackendAddr, position: usize) -> u32 {
+        weight(&self.state.lock().unwrap(), backend, position)
+    }
+
+    /// The backend the next session for a group with `backends` goes to, or `None` if they
+    /// all have a weight of 0
+    pub fn choose(&self, backends: &[BackendAddr]) -> Option<BackendAddr> {
+        self.choose_scaled(backends, &vec![1.0; backends.len()])
+    }
+
+    /// Like `choose`, with each backend's weight multiplied by the matching scale, e.g. to
+    /// favour faster backends, and by how far backends warming up are. If that leaves every
+    /// backend without weight, their own weights are used instead.
+    pub fn choose_scaled(&self, backends: &[BackendAddr], scales: &[f64]) -> Option<BackendAddr> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.warming.retain(|_, &mut (started, window)| now.duration_since(started) < window);
+        // scaled weights keep three decimals, so that small weights still scale
+        let scaled: Vec<i64> = backends.iter().zip(scales).enumerate()
+            .map(|(i, (b, s))| {
+                let warmth = match state.warming.get(b) {
+                    Some(&(started, window)) => now.duration_since(started).as_secs_f64() / window.as_secs_f64(),
+                    None => 1.0,
+                };
+                (weight(&state, b, i) as f64 * s * warmth * 1000.0).round() as i64
+            })
+            .collect();
+        if scaled.iter().any(|&w| w > 0) {
+            return pick(&mut state, backends, &scaled);
+        }
+        let weights: Vec<i64> = backends.iter().enumerate().map(|(i, b)| weight(&state, b, i) as i64).collect();
+        pick(&mut state, backends, &weights)
+    }
+}
+
+/// Smooth weighted round robin: every backend is owed its weight, and the one owed the most
+/// is picked and pays back the total
+fn pick(state: &mut WeightState, backends: &[BackendAddr], weights: &[i64]) -> Option<BackendAddr> {
+    let total: i64 = weights.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut chosen: Option<(&BackendAddr, i64)> = None;
+    for (backend, &w) in backends.iter().zip(weights) {
+        if w == 0 {
+            continue;
+        }
+        let current = state.current.entry(backend.clone()).or_insert(0);
+        *current += w;
+        if chosen.map(|(_, best)| *current > best).unwrap_or(true) {
+            chosen = Some((backend, *current));
+        }
+    }
+    let backend = chosen.map(|(b, _)| b.clone())?;
+    *state.current.get_mut(&backend).unwrap() -= total;
+    Some(backend)
+}
+
+fn weight(state: &WeightState, backend: &BackendAddr, position: usize) -> u32 {
+    state.weights.get(backend).or_else(|| state.defaults.get(backend)).cloned()
+        .unwrap_or(if position == 0 { 1 } else { 0 })
+}
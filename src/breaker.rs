@@ -0,0 +1,371 @@
+//! Circuit breakers that stop sending sessions to struggling backends.
+//!
+//! With a `[circuit_breaker]` section, the proxy keeps a circuit for each backend. It opens
+//! when `connect_failures` connections or logins to the backend fail in a row, or when at
+//! least `error_rate` of the commands it answered in a `window_secs` window, once there were
+//! `min_commands` of them, failed with an error that says the server is overloaded, such as
+//! too many connections, out of memory or a lock wait timeout. While a circuit is open, new
+//! sessions go to the group's other backends, and with none left they are refused at once
+//! with an error saying so, rather than piling retries onto a database that is already
+//! struggling. After `cooldown_secs` the circuit is half open: up to `probes` sessions are let
+//! through, and the first to log in closes the circuit again, while a failure opens it for
+//! another cool-down.
+//!
+//! The thresholds apply to every backend, and a backend can have some of its own in
+//! `[circuit_breaker.backends."host:port"]`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::ErrPacket;
+use super::connect::BackendAddr;
+use super::pipeline::{Correlator, ResponseKind};
+
+/// Errors that say the server is struggling rather than that the command was wrong:
+/// out of memory, out of sort memory, too many connections, out of resources, shutting
+/// down, can't create a thread, lock wait timeout and statement timeout
+pub const OVERLOAD_ERRORS: &[u16] = &[1037, 1038, 1040, 1041, 1053, 1135, 1205, 3024];
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// connections or logins failing in a row that open the circuit
+    #[serde(default = "CircuitBreakerConfig::default_connect_failures")]
+    pub connect_failures: u32,
+    /// the share of commands failing with overload errors that opens the circuit
+    #[serde(default = "CircuitBreakerConfig::default_error_rate")]
+    pub error_rate: f64,
+    /// commands in a window before its error rate counts
+    #[serde(default = "CircuitBreakerConfig::default_min_commands")]
+    pub min_commands: u64,
+    #[serde(default = "CircuitBreakerConfig::default_window_secs")]
+    pub window_secs: u64,
+    /// how long a circuit stays open before sessions probe the backend again
+    #[serde(default = "CircuitBreakerConfig::default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// sessions let through at a time while the circuit is half open
+    #[serde(default = "CircuitBreakerConfig::default_probes")]
+    pub probes: u32,
+    /// thresholds of particular backends, by address
+    #[serde(default)]
+    pub backends: HashMap<String, BackendThresholds>,
+}
+
+/// Thresholds of a backend that differ from those of every other
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct BackendThresholds {
+    pub connect_failures: Option<u32>,
+    pub error_rate: Option<f64>,
+    pub min_commands: Option<u64>,
+    pub window_secs: Option<u64>,
+    pub cooldown_secs: Option<u64>,
+    pub probes: Option<u32>,
+}
+
+impl CircuitBreakerConfig {
+
+    fn default_connect_failures() -> u32 {
+        5
+    }
+
+    fn default_error_rate() -> f64 {
+        0.5
+    }
+
+    fn default_min_commands() -> u64 {
+        20
+    }
+
+    fn default_window_secs() -> u64 {
+        10
+    }
+
+    fn default_cooldown_secs() -> u64 {
+        30
+    }
+
+    fn default_probes() -> u32 {
+        1
+    }
+
+    /// The thresholds for `backend`, with its own in place of the shared ones
+    pub fn for_backend(&self, backend: &BackendAddr) -> CircuitBreakerConfig {
+        let own = self.backends.get(&backend.to_string()).cloned().unwrap_or_default();
+        CircuitBreakerConfig {
+            connect_failures: own.connect_failures.unwrap_or(self.connect_failures),
+            error_rate: own.error_rate.unwrap_or(self.error_rate),
+            min_commands: own.min_commands.unwrap_or(self.min_commands),
+            window_secs: own.window_secs.unwrap_or(self.window_secs),
+            cooldown_secs: own.cooldown_secs.unwrap_or(self.cooldown_secs),
+            probes: own.probes.unwrap_or(self.probes),
+            backends: HashMap::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut all = vec![("".to_string(), self.clone())];
+        for name in self.backends.keys() {
+            match name.parse::<BackendAddr>() {
+                Ok(backend) => all.push((format!("backend '{}': ", name), self.for_backend(&backend))),
+                Err(e) => return Err(format!("backend '{}': {}", name, e)),
+            }
+        }
+        for (prefix, config) in all {
+            if config.connect_failures == 0 {
+                return Err(format!("{}connect_failures must be at least 1", prefix));
+            }
+            if !(config.error_rate > 0.0 && config.error_rate <= 1.0) {
+                return Err(format!("{}error_rate must be above 0 and at most 1, not {}", prefix, config.error_rate));
+            }
+            if config.window_secs == 0 || config.cooldown_secs == 0 || config.probes == 0 {
+                return Err(format!("{}window_secs, cooldown_secs and probes must be at least 1", prefix));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            connect_failures: CircuitBreakerConfig::default_connect_failures(),
+            error_rate: CircuitBreakerConfig::default_error_rate(),
+            min_commands: CircuitBreakerConfig::default_min_commands(),
+            window_secs: CircuitBreakerConfig::default_window_secs(),
+            cooldown_secs: CircuitBreakerConfig::default_cooldown_secs(),
+            probes: CircuitBreakerConfig::default_probes(),
+            backends: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    open_until: Option<Instant>,
+    /// connections or logins that failed since the last that succeeded
+    connect_failures: u32,
+    window_start: Instant,
+    commands: u64,
+    errors: u64,
+    /// sessions let through while half open
+    probes: u32,
+}
+
+impl Circuit {
+
+    fn new(now: Instant) -> Self {
+        Circuit { open_until: None, connect_failures: 0, window_start: now, commands: 0, errors: 0, probes: 0 }
+    }
+
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.open_until {
+            Some(until) if now < until => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+}
+
+/// The circuits of every backend, shared by every listener
+#[derive(Clone,Debug)]
+pub struct CircuitBreaker {
+    config: Arc<CircuitBreakerConfig>,
+    circuits: Arc<Mutex<HashMap<BackendAddr, Circuit>>>,
+}
+
+impl CircuitBreaker {
+
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker { config: Arc::new(config), circuits: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn state(&self, backend: &BackendAddr) -> CircuitState {
+        let circuits = self.circuits.lock().unwrap();
+        circuits.get(backend).map(|c| c.state(Instant::now())).unwrap_or(CircuitState::Closed)
+    }
+
+    /// The backends whose circuits are open, waiting out their cool-down
+    pub fn open_circuits(&self) -> Vec<BackendAddr> {
+        let now = Instant::now();
+        let circuits = self.circuits.lock().unwrap();
+        let mut open: Vec<BackendAddr> = circuits.iter()
+            .filter(|&(_, c)| c.state(now) == CircuitState::Open)
+            .map(|(b, _)| b.clone())
+            .collect();
+        open.sort_by_key(|b| b.to_string());
+        open
+    }
+
+    /// Those of `backends` that could take a new session: their circuit is closed, or half
+    /// open with room for another probe
+    pub fn available(&self, backends: &[BackendAddr]) -> Vec<BackendAddr> {
+        let now = Instant::now();
+        let circuits = self.circuits.lock().unwrap();
+        backends.iter().filter(|b| match circuits.get(b) {
+            Some(circuit) => match circuit.state(now) {
+                CircuitState::Closed => true,
+                CircuitState::Open => false,
+                CircuitState::HalfOpen => circuit.probes < self.config.for_backend(b).probes,
+            },
+            None => true,
+        }).cloned().collect()
+    }
+
+    /// Send a new session to `backend`, as a probe if its circuit is half open. Returns
+    /// false if the circuit won't take it.
+    pub fn admit(&self, backend: &BackendAddr) -> bool {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(backend) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+        match circuit.state(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                if circuit.probes >= self.config.for_backend(backend).probes {
+                    return false;
+                }
+                circuit.probes += 1;
+                true
+            },
+        }
+    }
+
+    /// A connection and login to `backend` succeeded
+    pub fn connected(&self, backend: &BackendAddr) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(backend) {
+            if circuit.open_until.is_some() {
+                info!("Closing the circuit of {} after a successful login", backend);
+            }
+            *circuit = Circuit::new(Instant::now());
+        }
+    }
+
+    /// A connection or login to `backend` failed
+    pub fn connect_failed(&self, backend: &BackendAddr) {
+        let now = Instant::now();
+        let config = self.config.for_backend(backend);
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(backend.clone()).or_insert_with(|| Circuit::new(now));
+        circuit.connect_failures += 1;
+        match circuit.state(now) {
+            CircuitState::HalfOpen => open(backend, circuit, &config, now, "a probe failed to log in"),
+            CircuitState::Closed if circuit.connect_failures >= config.connect_failures => {
+                let reason = format!("{} connections failed in a row", circuit.connect_failures);
+                open(backend, circuit, &config, now, &reason);
+            },
+            _ => {},
+        }
+    }
+
+    /// `backend` answered a command, with an overload error if `overloaded`
+    pub fn answered(&self, backend: &BackendAddr, overloaded: bool) {
+        let now = Instant::now();
+        let config = self.config.for_backend(backend);
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(backend.clone()).or_insert_with(|| Circuit::new(now));
+        match circuit.state(now) {
+            CircuitState::Closed => {},
+            CircuitState::HalfOpen if overloaded => {
+                open(backend, circuit, &config, now, "a probe got an overload error");
+                return;
+            },
+            _ => return,
+        }
+        if now.duration_since(circuit.window_start) >= Duration::from_secs(config.window_secs) {
+            circuit.window_start = now;
+            circuit.commands = 0;
+            circuit.errors = 0;
+        }
+        circuit.commands += 1;
+        circuit.errors += overloaded as u64;
+        if circuit.commands >= config.min_commands.max(1)
+            && circuit.errors as f64 >= config.error_rate * circuit.commands as f64 {
+            let reason = format!("{} of {} commands failed with overload errors", circuit.errors, circuit.commands);
+            open(backend, circuit, &config, now, &reason);
+        }
+    }
+}
+
+fn open(backend: &BackendAddr, circuit: &mut Circuit, config: &CircuitBreakerConfig, now: Instant, reason: &str) {
+    warn!("Opening the circuit of {} for {} seconds: {}", backend, config.cooldown_secs, reason);
+    *circuit = Circuit::new(now);
+    circuit.open_until = Some(now + Duration::from_secs(config.cooldown_secs));
+}
+
+/// Wraps another handler and reports the commands a backend answers to its circuit
+pub struct BreakerHandler<H: PacketHandler> {
+    breaker: CircuitBreaker,
+    backend: BackendAddr,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    inner: H,
+}
+
+impl<H> BreakerHandler<H> where H: PacketHandler {
+
+    pub fn new(breaker: CircuitBreaker, backend: BackendAddr, inner: H) -> Self {
+        BreakerHandler {
+            breaker,
+            backend,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            inner,
+        }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+}
+
+impl<H> PacketHandler for BreakerHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        match action {
+            Action::Forward => self.correlator.request(p),
+            Action::Mutate(ref p2) => self.correlator.request(p2),
+            _ => {},
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                if answered.last {
+                    let overloaded = answered.kind == ResponseKind::Err
+                        && ErrPacket::parse(p).map(|e| OVERLOAD_ERRORS.contains(&e.code)).unwrap_or(false);
+                    self.breaker.answered(&self.backend, overloaded);
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
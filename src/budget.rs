@@ -0,0 +1,150 @@
+//! Per-connection work budgets, so connections on one reactor share it fairly.
+//!
+//! A `Pipe` relays packets until its sockets would block. Without a limit, a connection that
+//! always has more data, such as a client streaming a large result set, would never return
+//! control to the reactor and every other connection on it would stall. Each poll of a pipe
+//! is given a `PollBudget`; once it is used up the pipe schedules itself to be polled again
+//! and yields, which puts it behind the other connections that are ready to run.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll};
+use futures::task;
+
+/// Limits on the work a `Pipe` does in a single poll before yielding to other connections
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+pub struct PollBudget {
+    /// packets handled, in both directions
+    #[serde(default = "PollBudget::default_max_packets")]
+    pub max_packets: usize,
+    /// bytes read, from both sockets
+    #[serde(default = "PollBudget::default_max_bytes")]
+    pub max_bytes: usize,
+    /// time spent relaying
+    #[serde(default = "PollBudget::default_max_time_us")]
+    pub max_time_us: u64,
+}
+
+impl PollBudget {
+
+    fn default_max_packets() -> usize {
+        256
+    }
+
+    fn default_max_bytes() -> usize {
+        256 * 1024
+    }
+
+    fn default_max_time_us() -> u64 {
+        2000
+    }
+
+    /// Start tracking the work done in one poll
+    pub fn start(&self) -> Work {
+        Work { budget: *self, started: Instant::now(), packets: 0, bytes: 0, yielded: false }
+    }
+}
+
+impl Default for PollBudget {
+    fn default() -> Self {
+        PollBudget {
+            max_packets: PollBudget::default_max_packets(),
+            max_bytes: PollBudget::default_max_bytes(),
+            max_time_us: PollBudget::default_max_time_us(),
+        }
+    }
+}
+
+/// The work done so far in one poll
+#[derive(Debug)]
+pub struct Work {
+    budget: PollBudget,
+    started: Instant,
+    packets: usize,
+    bytes: usize,
+    yielded: bool,
+}
+
+impl Work {
+
+    pub fn add_packet(&mut self) {
+        self.packets += 1;
+    }
+
+    pub fn add_bytes(&mut self, n: usize) {
+        self.bytes += n;
+    }
+
+    /// How many more bytes may be read in this poll
+    pub fn remaining_bytes(&self) -> usize {
+        self.budget.max_bytes.saturating_sub(self.bytes)
+    }
+
+    /// Whether the pipe should yield before doing any more work
+    pub fn exhausted(&self) -> bool {
+        self.packets >= self.budget.max_packets
+            || self.bytes >= self.budget.max_bytes
+            || self.elapsed() >= Duration::from_micros(self.budget.max_time_us)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Give up the rest of this poll, asking to be polled again once other tasks have run
+    pub fn yield_now<T, E>(&mut self) -> Poll<T, E> {
+        self.yielded = true;
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
+
+/// Totals of the work done by one connection
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct UsageStats {
+    /// time spent relaying packets and running handlers
+    pub busy: Duration,
+    pub polls: u64,
+    /// polls that ended because the budget ran out rather than because the sockets would block
+    pub yields: u64,
+    pub packets: u64,
+    pub bytes: u64,
+    /// commands waiting for a response from the server
+    pub queue_depth: usize,
+    /// the most commands that have been waiting for a response at once
+    pub max_queue_depth: usize,
+}
+
+/// Shared view of a connection's `UsageStats`, which stays readable while the `Pipe` runs
+#[derive(Clone,Debug,Default)]
+pub struct Usage {
+    stats: Rc<Cell<UsageStats>>,
+}
+
+impl Usage {
+
+    pub fn stats(&self) -> UsageStats {
+        self.stats.get()
+    }
+
+    /// Add the work done in a poll
+    pub fn record(&self, work: &Work) {
+        let mut stats = self.stats.get();
+        stats.busy += work.elapsed();
+        stats.polls += 1;
+        stats.yields += work.yielded as u64;
+        stats.packets += work.packets as u64;
+        stats.bytes += work.bytes as u64;
+        self.stats.set(stats);
+    }
+
+    /// Note how many pipelined commands are waiting for a response
+    pub fn record_queue_depth(&self, depth: usize) {
+        let mut stats = self.stats.get();
+        stats.queue_depth = depth;
+        stats.max_queue_depth = stats.max_queue_depth.max(depth);
+        self.stats.set(stats);
+    }
+}
@@ -0,0 +1,170 @@
+//! Enforcing a capability policy on the handshake.
+//!
+//! Some protocol features are risky enough to switch off for everyone behind the proxy, such
+//! as `LOAD DATA LOCAL INFILE`, which lets a server read files from the client, or multiple
+//! statements per query, which makes SQL injection worse. Disabled capabilities are removed
+//! from the greeting sent to the client and from the client's handshake response before it
+//! reaches the server, so neither side can turn them on, whatever the other advertises.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, Packet, PacketHandler};
+use super::anomaly::{Anomaly, Verdict};
+use super::protocol::*;
+
+/// Capabilities that can be disabled. These don't change the layout of the handshake
+/// packets, so flags can be cleared without re-encoding them.
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `LOAD DATA LOCAL INFILE`
+    LocalFiles,
+    /// several statements in one COM_QUERY
+    MultiStatements,
+    MultiResults,
+    PsMultiResults,
+    /// the compressed protocol
+    Compress,
+    FoundRows,
+    IgnoreSpace,
+    Interactive,
+    SessionTrack,
+    DeprecateEof,
+}
+
+impl Capability {
+
+    pub fn flag(self) -> u32 {
+        match self {
+            Capability::LocalFiles => CLIENT_LOCAL_FILES,
+            Capability::MultiStatements => CLIENT_MULTI_STATEMENTS,
+            Capability::MultiResults => CLIENT_MULTI_RESULTS,
+            Capability::PsMultiResults => CLIENT_PS_MULTI_RESULTS,
+            Capability::Compress => CLIENT_COMPRESS,
+            Capability::FoundRows => CLIENT_FOUND_ROWS,
+            Capability::IgnoreSpace => CLIENT_IGNORE_SPACE,
+            Capability::Interactive => CLIENT_INTERACTIVE,
+            Capability::SessionTrack => CLIENT_SESSION_TRACK,
+            Capability::DeprecateEof => CLIENT_DEPRECATE_EOF,
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct CapabilityPolicy {
+    /// capabilities that are never negotiated
+    #[serde(default)]
+    pub disable: Vec<Capability>,
+}
+
+impl CapabilityPolicy {
+
+    /// The flags this policy disables
+    pub fn disabled(&self) -> u32 {
+        self.disable.iter().fold(0, |mask, c| mask | c.flag())
+    }
+
+    /// Remove disabled capabilities from a set of capability flags
+    pub fn apply(&self, flags: u32) -> u32 {
+        flags & !self.disabled()
+    }
+
+    /// Clear disabled capabilities in a server greeting. Returns `None` if the packet
+    /// isn't a greeting or already complies.
+    pub fn rewrite_greeting(&self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        if payload.first() != Some(&10) {
+            return None;
+        }
+        // protocol version, NUL terminated server version, connection id, scramble, filler
+        let lower = 1 + payload[1..].iter().position(|&b| b == 0)? + 1 + 4 + 8 + 1;
+        // character set and status flags sit between the two halves of the flags
+        let upper = lower + 2 + 1 + 2;
+        if payload.len() < lower + 2 {
+            return None;
+        }
+        let mut flags = LittleEndian::read_u16(&payload[lower..]) as u32;
+        if payload.len() >= upper + 2 {
+            flags |= (LittleEndian::read_u16(&payload[upper..]) as u32) << 16;
+        }
+        if flags & self.disabled() == 0 {
+            return None;
+        }
+        let flags = self.apply(flags);
+        let mut bytes = p.bytes.clone();
+        LittleEndian::write_u16(&mut bytes[4 + lower..], flags as u16);
+        if payload.len() >= upper + 2 {
+            LittleEndian::write_u16(&mut bytes[4 + upper..], (flags >> 16) as u16);
+        }
+        Some(Packet { bytes })
+    }
+
+    /// Clear disabled capabilities in a client's handshake response or SSL request. Returns
+    /// `None` if the packet isn't a 4.1 handshake response or already complies.
+    pub fn rewrite_response(&self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        if payload.len() < 32 {
+            return None;
+        }
+        let flags = LittleEndian::read_u32(payload);
+        if flags & CLIENT_PROTOCOL_41 == 0 || flags & self.disabled() == 0 {
+            return None;
+        }
+        let mut bytes = p.bytes.clone();
+        LittleEndian::write_u32(&mut bytes[4..], self.apply(flags));
+        Some(Packet { bytes })
+    }
+}
+
+/// Wraps another handler and enforces a capability policy on a relayed handshake
+pub struct CapabilityHandler<H: PacketHandler> {
+    policy: CapabilityPolicy,
+    greeted: bool,
+    responded: bool,
+    inner: H,
+}
+
+impl<H> CapabilityHandler<H> where H: PacketHandler {
+
+    pub fn new(policy: CapabilityPolicy, inner: H) -> Self {
+        CapabilityHandler { policy, greeted: false, responded: false, inner }
+    }
+}
+
+impl<H> PacketHandler for CapabilityHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        // the handshake response is the first packet the client sends. After an SSL request
+        // the rest of the connection is encrypted, so there's nothing more to rewrite.
+        if self.responded {
+            return self.inner.handle_request(p);
+        }
+        self.responded = true;
+        match self.policy.rewrite_response(p) {
+            Some(response) => forward_rewritten(self.inner.handle_request(&response), response),
+            None => self.inner.handle_request(p),
+        }
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        if self.greeted {
+            return self.inner.handle_response(p);
+        }
+        self.greeted = true;
+        match self.policy.rewrite_greeting(p) {
+            Some(greeting) => forward_rewritten(self.inner.handle_response(&greeting), greeting),
+            None => self.inner.handle_response(p),
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+fn forward_rewritten(action: Action, rewritten: Packet) -> Action {
+    match action {
+        Action::Forward => Action::Mutate(rewritten),
+        action => action,
+    }
+}
@@ -0,0 +1,248 @@
+//! The last exchanges of each connection, kept for explaining how it ended.
+//!
+//! When an application reports a lost connection, the proxy's logs say when it was lost but
+//! not what the connection was doing. With a `[capture]` section, `CaptureHandler` keeps the
+//! last `exchanges` commands of each connection in memory: the fingerprint of each query, or
+//! the command for others, when it was sent, how long it took and what it returned. Only
+//! fingerprints are kept, never the literals in queries. If the connection ends with an
+//! error, `SessionCapture::dump` writes them to the audit log, along with the commands still
+//! waiting for a response, which are often the ones that matter.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::audit::AuditLog;
+use super::codec::{ErrPacket, OkPacket, QueryResponse, QueryResponseDecoder};
+use super::pipeline::{Correlator, ResponseKind};
+use super::sql;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct CaptureConfig {
+    /// how many of each connection's last commands are kept
+    #[serde(default = "CaptureConfig::default_exchanges")]
+    pub exchanges: usize,
+}
+
+impl CaptureConfig {
+
+    fn default_exchanges() -> usize {
+        32
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.exchanges == 0 {
+            return Err("exchanges must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a command returned
+#[derive(Clone,Debug,PartialEq)]
+pub enum Outcome {
+    Ok { affected_rows: u64 },
+    /// the rows of a query's result sets
+    Rows(u64),
+    Err { code: u16, message: String },
+    /// no response yet
+    Pending,
+}
+
+/// A command sent to the backend and its response
+#[derive(Clone,Debug,PartialEq)]
+pub struct Exchange {
+    /// the fingerprint of a query, or the command
+    pub command: String,
+    /// when it was sent, in milliseconds since the epoch
+    pub sent_ms: u64,
+    /// how long the response took, once it's complete
+    pub elapsed_ms: Option<u64>,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Exchange {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}: ", self.command, self.sent_ms)?;
+        match self.outcome {
+            Outcome::Ok { affected_rows } => write!(f, "ok, {} rows affected", affected_rows)?,
+            Outcome::Rows(rows) => write!(f, "{} rows", rows)?,
+            Outcome::Err { code, ref message } => write!(f, "error {}: {}", code, message)?,
+            Outcome::Pending => return write!(f, "no response"),
+        }
+        match self.elapsed_ms {
+            Some(ms) => write!(f, " in {} ms", ms),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CaptureState {
+    limit: usize,
+    /// the last complete exchanges, oldest first
+    complete: VecDeque<Exchange>,
+    /// exchanges waiting for their response, in order
+    pending: VecDeque<Exchange>,
+}
+
+/// The exchanges of one connection, shared between its handler and whatever reports how the
+/// connection ended
+#[derive(Clone,Debug)]
+pub struct SessionCapture {
+    state: Rc<RefCell<CaptureState>>,
+}
+
+impl SessionCapture {
+
+    pub fn new(config: &CaptureConfig) -> Self {
+        let state = CaptureState { limit: config.exchanges, complete: VecDeque::new(), pending: VecDeque::new() };
+        SessionCapture { state: Rc::new(RefCell::new(state)) }
+    }
+
+    /// The last complete exchanges followed by those still waiting for a response
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        let state = self.state.borrow();
+        state.complete.iter().chain(state.pending.iter()).cloned().collect()
+    }
+
+    /// Write the exchanges to `log` for `user`, after a record of how the connection from
+    /// `client` ended
+    pub fn dump(&self, log: &AuditLog, user: &str, client: &str, error: &str) -> io::Result<()> {
+        let exchanges = self.exchanges();
+        log.record(user, &format!("-- connection from {} ended: {}; its last {} commands follow", client, error, exchanges.len()))?;
+        for exchange in exchanges {
+            log.record(user, &format!("-- {}", exchange))?;
+        }
+        Ok(())
+    }
+
+    fn sent(&self, command: String) {
+        let sent_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        self.state.borrow_mut().pending.push_back(Exchange { command, sent_ms, elapsed_ms: None, outcome: Outcome::Pending });
+    }
+
+    fn answered(&self, elapsed_ms: u64, outcome: Outcome) {
+        let mut state = self.state.borrow_mut();
+        if let Some(mut exchange) = state.pending.pop_front() {
+            exchange.elapsed_ms = Some(elapsed_ms);
+            exchange.outcome = outcome;
+            state.complete.push_back(exchange);
+            while state.complete.len() > state.limit {
+                state.complete.pop_front();
+            }
+        }
+    }
+}
+
+/// A forwarded command waiting for its response
+struct PendingCommand {
+    sent: Instant,
+    /// follows the result sets of queries, to count their rows
+    decoder: Option<QueryResponseDecoder>,
+    rows: u64,
+}
+
+/// Wraps another handler and captures the commands the session sends the backend
+pub struct CaptureHandler<H: PacketHandler> {
+    capture: SessionCapture,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    pending: VecDeque<PendingCommand>,
+    inner: H,
+}
+
+impl<H> CaptureHandler<H> where H: PacketHandler {
+
+    pub fn new(capture: SessionCapture, inner: H) -> Self {
+        CaptureHandler {
+            capture,
+            capability_flags: 0,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: VecDeque::new(),
+            inner,
+        }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+}
+
+impl<H> PacketHandler for CaptureHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        let issued = self.correlator.issued();
+        self.correlator.request(forwarded);
+        if self.correlator.issued() > issued {
+            let (command, decoder) = match p.packet_type() {
+                Ok(PacketType::ComQuery) => {
+                    let fingerprint = sql::fingerprint(&String::from_utf8_lossy(&p.payload()[1..]));
+                    (fingerprint, Some(QueryResponseDecoder::new(self.capability_flags)))
+                },
+                Ok(PacketType::Unknown(_)) | Err(_) => ("unknown command".to_string(), None),
+                Ok(t) => (format!("{:?}", t), None),
+            };
+            self.capture.sent(command);
+            self.pending.push_back(PendingCommand { sent: Instant::now(), decoder, rows: 0 });
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                if let Some(command) = self.pending.front_mut() {
+                    if let Some(Ok(QueryResponse::Row(_))) = command.decoder.as_mut().map(|d| d.decode(p)) {
+                        command.rows += 1;
+                    }
+                }
+                if answered.last {
+                    if let Some(command) = self.pending.pop_front() {
+                        let outcome = match answered.kind {
+                            ResponseKind::Err => match ErrPacket::parse(p) {
+                                Ok(e) => Outcome::Err { code: e.code, message: e.message },
+                                Err(_) => Outcome::Err { code: 0, message: String::new() },
+                            },
+                            _ if command.rows > 0 => Outcome::Rows(command.rows),
+                            ResponseKind::Ok => {
+                                let affected_rows = OkPacket::parse(p, self.capability_flags).map(|ok| ok.affected_rows).unwrap_or(0);
+                                Outcome::Ok { affected_rows }
+                            },
+                            _ => Outcome::Rows(0),
+                        };
+                        self.capture.answered(command.sent.elapsed().as_millis() as u64, outcome);
+                    }
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
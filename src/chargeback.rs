@@ -0,0 +1,184 @@
+//! Periodic usage reports by user, for charging tenants for the load they put on databases.
+//!
+//! With a `[chargeback]` section and statistics kept, the proxy writes a report every
+//! `interval_secs`: for each user that did anything in the period, its connections, queries,
+//! errors, the bytes the backends returned to it and the time they spent answering it. Reports
+//! are appended to `path` as CSV rows, one per user with the period they cover, or as one
+//! JSON object per line. The management API serves the period running so far at
+//! `GET /chargeback`.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::stats::{Stats, UserStats};
+
+/// The header of CSV reports
+pub const CSV_HEADER: &str = "period_start,period_end,user,connections,queries,errors,bytes_returned,backend_time_us";
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ChargebackConfig {
+    /// the file reports are appended to
+    pub path: PathBuf,
+    #[serde(default = "ChargebackConfig::default_format")]
+    pub format: ReportFormat,
+    /// the length of the period each report covers
+    #[serde(default = "ChargebackConfig::default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl ChargebackConfig {
+
+    fn default_format() -> ReportFormat {
+        ReportFormat::Csv
+    }
+
+    fn default_interval_secs() -> u64 {
+        3600
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 {
+            return Err("interval_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The usage of each user over a period
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct ChargebackReport {
+    /// seconds since the epoch
+    pub period_start: u64,
+    pub period_end: u64,
+    /// the users with any usage in the period
+    pub users: BTreeMap<String, UserStats>,
+}
+
+impl ChargebackReport {
+
+    /// Write the report as CSV rows, without the header
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for (user, usage) in &self.users {
+            writeln!(writer, "{},{},{},{},{},{},{},{}", self.period_start, self.period_end, csv_field(user),
+                     usage.connections, usage.queries, usage.errors, usage.bytes_returned, usage.backend_time_us)?;
+        }
+        Ok(())
+    }
+
+    /// Write the report as a line of JSON
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        serde_json::to_writer(&mut *writer, self)?;
+        writeln!(writer)
+    }
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[derive(Debug)]
+struct Period {
+    start: u64,
+    /// the users' totals when the period started
+    baseline: BTreeMap<String, UserStats>,
+}
+
+/// Reports the usage recorded in `Stats` period by period. Clones share the period.
+#[derive(Clone,Debug)]
+pub struct Chargeback {
+    stats: Stats,
+    period: Arc<Mutex<Period>>,
+}
+
+impl Chargeback {
+
+    /// Start the first period now
+    pub fn new(stats: Stats) -> Self {
+        let baseline = stats.snapshot().users;
+        Chargeback { stats, period: Arc::new(Mutex::new(Period { start: now_secs(), baseline })) }
+    }
+
+    /// The usage in the period so far
+    pub fn current(&self) -> ChargebackReport {
+        let period = self.period.lock().unwrap();
+        usage_since(&period, self.stats.snapshot().users)
+    }
+
+    /// End the period, returning its usage, and start the next
+    pub fn close_period(&self) -> ChargebackReport {
+        let mut period = self.period.lock().unwrap();
+        let totals = self.stats.snapshot().users;
+        let report = usage_since(&period, totals.clone());
+        *period = Period { start: report.period_end, baseline: totals };
+        report
+    }
+
+    /// Append a report to `config.path` every `config.interval_secs`, on a thread of its own
+    pub fn run_in_thread(&self, config: &ChargebackConfig) -> io::Result<()> {
+        let chargeback = self.clone();
+        let config = config.clone();
+        let interval = Duration::from_secs(config.interval_secs.max(1));
+        thread::Builder::new().name("mysql-proxy-chargeback".to_string()).spawn(move || loop {
+            thread::sleep(interval);
+            let report = chargeback.close_period();
+            if let Err(e) = append_report(&config, &report) {
+                warn!("Failed to write the chargeback report to {}: {}", config.path.display(), e);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+fn usage_since(period: &Period, totals: BTreeMap<String, UserStats>) -> ChargebackReport {
+    let users = totals.into_iter().filter_map(|(user, total)| {
+        let before = period.baseline.get(&user).cloned().unwrap_or_default();
+        let usage = UserStats {
+            connections: total.connections.saturating_sub(before.connections),
+            queries: total.queries.saturating_sub(before.queries),
+            errors: total.errors.saturating_sub(before.errors),
+            bytes_returned: total.bytes_returned.saturating_sub(before.bytes_returned),
+            backend_time_us: total.backend_time_us.saturating_sub(before.backend_time_us),
+        };
+        if usage == UserStats::default() {
+            None
+        } else {
+            Some((user, usage))
+        }
+    }).collect();
+    ChargebackReport { period_start: period.start, period_end: now_secs(), users }
+}
+
+/// Append a report to the file, starting a CSV file with its header
+fn append_report(config: &ChargebackConfig, report: &ChargebackReport) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+    match config.format {
+        ReportFormat::Csv => {
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", CSV_HEADER)?;
+            }
+            report.write_csv(&mut file)
+        },
+        ReportFormat::Json => report.write_json(&mut file),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
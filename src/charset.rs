@@ -0,0 +1,742 @@
+//! Converting text between a client's character set and its backend's.
+//!
+//! Legacy applications often talk latin1 while newer backends store, and may only accept,
+//! utf8mb4. With `backend_collation` set, the proxy logs in to backends with that collation
+//! whatever the client asked for, and `CharsetHandler` converts the text passing through:
+//! statements, schema and table names, prepared statement parameters, column definitions,
+//! the values of text columns in text and binary result sets, and error messages. Characters
+//! the client's character set can't represent become `?`, as they would on MySQL itself.
+//! Binary literals in statements, `_binary'...'`, `X'...'`, `B'...'` and `0x...`, hold bytes
+//! rather than text, so they're passed on as they are.
+//!
+//! `SET NAMES` changes the character set the handler converts to and from, while the backend
+//! stays on its collation. Long data sent with COM_STMT_SEND_LONG_DATA and the contents of
+//! LOAD DATA LOCAL files are passed on unconverted.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::{write_lenenc_int, write_lenenc_str, PayloadReader, MAX_PAYLOAD_LEN};
+use super::pipeline::{Correlator, ResponseKind};
+use super::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+
+/// MySQL error ER_UNKNOWN_CHARACTER_SET
+pub const ER_UNKNOWN_CHARACTER_SET: u16 = 1115;
+
+/// MySQL error ER_UNKNOWN_COLLATION
+pub const ER_UNKNOWN_COLLATION: u16 = 1273;
+
+/// The collation id of the binary character set, which numbers and temporal values report
+const BINARY_COLLATION: u16 = 63;
+
+/// The character sets the proxy can convert between
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Charset {
+    /// MySQL's latin1, which is Windows-1252
+    Latin1,
+    Ascii,
+    /// utf8mb3, without characters outside the Basic Multilingual Plane
+    Utf8,
+    Utf8mb4,
+    Binary,
+}
+
+/// Windows-1252 characters for bytes 0x80 to 0x9f, of which MySQL maps the five Windows
+/// leaves undefined to the control characters with the same code
+const LATIN1_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl Charset {
+
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match &name.to_lowercase()[..] {
+            "latin1" => Some(Charset::Latin1),
+            "ascii" => Some(Charset::Ascii),
+            "utf8" | "utf8mb3" => Some(Charset::Utf8),
+            "utf8mb4" => Some(Charset::Utf8mb4),
+            "binary" => Some(Charset::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Charset::Latin1 => "latin1",
+            Charset::Ascii => "ascii",
+            Charset::Utf8 => "utf8mb3",
+            Charset::Utf8mb4 => "utf8mb4",
+            Charset::Binary => "binary",
+        }
+    }
+
+    /// The most bytes a character takes
+    pub fn max_len(&self) -> u32 {
+        match *self {
+            Charset::Utf8 => 3,
+            Charset::Utf8mb4 => 4,
+            _ => 1,
+        }
+    }
+
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match *self {
+            Charset::Utf8 | Charset::Utf8mb4 => String::from_utf8_lossy(bytes),
+            _ if bytes.is_ascii() => String::from_utf8_lossy(bytes),
+            Charset::Latin1 => Cow::Owned(bytes.iter().map(|&b| match b {
+                0x80..=0x9f => LATIN1_HIGH[(b - 0x80) as usize],
+                _ => b as char,
+            }).collect()),
+            Charset::Ascii | Charset::Binary => Cow::Owned(bytes.iter().map(|&b| if b < 0x80 { b as char } else { '?' }).collect()),
+        }
+    }
+
+    pub fn encode(&self, s: &str) -> Vec<u8> {
+        match *self {
+            Charset::Utf8mb4 | Charset::Binary => s.as_bytes().to_vec(),
+            _ if s.is_ascii() => s.as_bytes().to_vec(),
+            Charset::Utf8 => s.chars().map(|c| if c > '\u{ffff}' { '?' } else { c }).collect::<String>().into_bytes(),
+            Charset::Latin1 => s.chars().map(|c| match c as u32 {
+                n @ 0xa0..=0xff => n as u8,
+                n if n < 0x80 => n as u8,
+                _ => LATIN1_HIGH.iter().position(|&h| h == c).map(|i| 0x80 + i as u8).unwrap_or(b'?'),
+            }).collect(),
+            Charset::Ascii => s.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).collect(),
+        }
+    }
+
+    /// Convert text in this character set to `to`
+    pub fn convert<'a>(&self, to: Charset, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        if *self == to || *self == Charset::Binary || to == Charset::Binary || bytes.is_ascii() {
+            return Cow::Borrowed(bytes);
+        }
+        Cow::Owned(to.encode(&self.decode(bytes)))
+    }
+}
+
+/// Collations the proxy knows, by id
+const COLLATIONS: &[(u8, &str)] = &[
+    (5, "latin1_german1_ci"),
+    (8, "latin1_swedish_ci"),
+    (11, "ascii_general_ci"),
+    (15, "latin1_danish_ci"),
+    (31, "latin1_german2_ci"),
+    (33, "utf8_general_ci"),
+    (45, "utf8mb4_general_ci"),
+    (46, "utf8mb4_bin"),
+    (47, "latin1_bin"),
+    (48, "latin1_general_ci"),
+    (49, "latin1_general_cs"),
+    (63, "binary"),
+    (65, "ascii_bin"),
+    (83, "utf8_bin"),
+    (94, "latin1_spanish_ci"),
+    (192, "utf8_unicode_ci"),
+    (224, "utf8mb4_unicode_ci"),
+    (255, "utf8mb4_0900_ai_ci"),
+];
+
+/// A collation, e.g. `utf8mb4_general_ci`, as sent in the handshake
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(try_from = "String")]
+pub struct Collation {
+    id: u8,
+}
+
+impl Collation {
+
+    pub fn from_id(id: u8) -> Option<Collation> {
+        COLLATIONS.iter().find(|c| c.0 == id).map(|&(id, _)| Collation { id })
+    }
+
+    /// The collation a `SET NAMES` without `COLLATE` selects
+    pub fn default_for(charset: Charset) -> Collation {
+        let id = match charset {
+            Charset::Latin1 => 8,
+            Charset::Ascii => 11,
+            Charset::Utf8 => 33,
+            Charset::Utf8mb4 => 45,
+            Charset::Binary => 63,
+        };
+        Collation::from_id(id).unwrap()
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        COLLATIONS.iter().find(|c| c.0 == self.id).map(|c| c.1).unwrap_or("binary")
+    }
+
+    pub fn charset(&self) -> Charset {
+        self.name().split('_').next().and_then(Charset::from_name).unwrap_or(Charset::Binary)
+    }
+}
+
+impl FromStr for Collation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        COLLATIONS.iter()
+            .find(|c| c.1.eq_ignore_ascii_case(s))
+            .map(|&(id, _)| Collation { id })
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Unsupported collation '{}'", s)))
+    }
+}
+
+impl TryFrom<String> for Collation {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The character set a `SET NAMES x [COLLATE y]` or `SET CHARACTER SET x` statement selects,
+/// and the collation if given
+fn set_names(sql: &str) -> Option<(String, Option<String>)> {
+    let unquote = |s: &str| s.trim_matches(|c| c == '\'' || c == '"' || c == '`').to_string();
+    let words: Vec<&str> = sql.trim().trim_end_matches(';').split_whitespace().collect();
+    let is = |i: usize, word: &str| words.get(i).map(|w| w.eq_ignore_ascii_case(word)).unwrap_or(false);
+    if !is(0, "set") {
+        return None;
+    }
+    let at = if is(1, "names") || is(1, "charset") {
+        2
+    } else if is(1, "character") && is(2, "set") {
+        3
+    } else {
+        return None;
+    };
+    match words.len() - at {
+        1 => Some((unquote(words[at]), None)),
+        3 if is(at + 1, "collate") => Some((unquote(words[at]), Some(unquote(words[at + 2])))),
+        _ => None,
+    }
+}
+
+/// The byte ranges of the binary literals in a statement: strings introduced with `_binary`,
+/// and hexadecimal and bit literals. Works on the statement's bytes, since it may not be
+/// UTF-8, which is safe for the character sets the proxy converts as none of them use ASCII
+/// bytes within multibyte characters.
+fn binary_literals(sql: &[u8]) -> Vec<(usize, usize)> {
+    let is_word = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80;
+    // the end of the string literal starting at `i`
+    let string_end = |mut i: usize| {
+        let quote = sql[i];
+        i += 1;
+        while i < sql.len() && sql[i] != quote {
+            i += if sql[i] == b'\\' { 2 } else { 1 };
+        }
+        (i + 1).min(sql.len())
+    };
+    let mut literals = vec![];
+    let mut i = 0;
+    while i < sql.len() {
+        let c = sql[i];
+        if c == b'\'' || c == b'"' {
+            i = string_end(i);
+        } else if c == b'`' {
+            i += 1;
+            while i < sql.len() && sql[i] != b'`' {
+                i += 1;
+            }
+            i += 1;
+        } else if c == b'#' || (sql[i..].starts_with(b"--") && sql.get(i + 2).map(|c| c.is_ascii_whitespace()).unwrap_or(true)) {
+            while i < sql.len() && sql[i] != b'\n' {
+                i += 1;
+            }
+        } else if sql[i..].starts_with(b"/*") {
+            i = sql[i + 2..].windows(2).position(|w| w == b"*/").map(|end| i + end + 4).unwrap_or(sql.len());
+        } else if is_word(c) {
+            let start = i;
+            while i < sql.len() && is_word(sql[i]) {
+                i += 1;
+            }
+            let word = &sql[start..i];
+            let quoted = sql.get(i) == Some(&b'\'');
+            if word.eq_ignore_ascii_case(b"_binary") {
+                let mut at = i;
+                while at < sql.len() && sql[at].is_ascii_whitespace() {
+                    at += 1;
+                }
+                if sql.get(at) == Some(&b'\'') || sql.get(at) == Some(&b'"') {
+                    i = string_end(at);
+                    literals.push((start, i));
+                }
+            } else if quoted && (word.eq_ignore_ascii_case(b"x") || word.eq_ignore_ascii_case(b"b")) {
+                i = string_end(i);
+                literals.push((start, i));
+            } else if word.len() > 2 && (word.starts_with(b"0x") || word.starts_with(b"0b")) {
+                literals.push((start, i));
+            }
+        } else {
+            i += 1;
+        }
+    }
+    literals
+}
+
+/// The size of a binary protocol value of `column_type`, or `None` if it is length-encoded
+fn binary_len(column_type: u8, r: &mut PayloadReader) -> Result<Option<usize>> {
+    Ok(match column_type {
+        0x06 => Some(0), // NULL
+        0x01 => Some(1), // TINY
+        0x02 | 0x0d => Some(2), // SHORT, YEAR
+        0x03 | 0x04 | 0x09 => Some(4), // LONG, FLOAT, INT24
+        0x05 | 0x08 => Some(8), // DOUBLE, LONGLONG
+        // DATE, TIME, DATETIME, TIMESTAMP, with a length byte
+        0x07 | 0x0a | 0x0b | 0x0c => Some(1 + r.peek().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated value"))? as usize),
+        _ => None,
+    })
+}
+
+/// Column types whose parameter values are strings rather than bytes
+fn is_string_param(column_type: u8) -> bool {
+    matches!(column_type, 0x0f | 0xfd | 0xfe) // VARCHAR, VAR_STRING, STRING
+}
+
+/// A command awaiting its response
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Command {
+    Query,
+    Execute(u32),
+    Fetch(u32),
+    Prepare,
+    FieldList,
+    Other,
+}
+
+/// Where the response to a query or execute is up to
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Part {
+    Start,
+    Columns { remaining: u64 },
+    /// the EOF after the column definitions, without CLIENT_DEPRECATE_EOF
+    ColumnsEnd,
+    Rows,
+}
+
+#[derive(Clone,Debug,Default)]
+struct Statement {
+    params: usize,
+    /// the parameter types sent with the latest execute
+    types: Vec<u8>,
+    /// the types of the columns of its result, and whether they hold text
+    columns: Vec<(u8, bool)>,
+}
+
+/// Wraps another handler and converts text between the client's character set and the
+/// backend's
+pub struct CharsetHandler<H: PacketHandler> {
+    client: Collation,
+    backend: Collation,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    pending: VecDeque<Command>,
+    part: Part,
+    /// the columns of the result set being received
+    columns: Vec<(u8, bool)>,
+    statements: HashMap<u32, Statement>,
+    inner: H,
+}
+
+impl<H> CharsetHandler<H> where H: PacketHandler {
+
+    pub fn new(client: Collation, backend: Collation, inner: H) -> Self {
+        CharsetHandler {
+            client,
+            backend,
+            capability_flags: CLIENT_PROTOCOL_41,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: VecDeque::new(),
+            part: Part::Start,
+            columns: vec![],
+            statements: HashMap::new(),
+            inner,
+        }
+    }
+
+    /// Convert between the session's client, whose collation is taken to be the backend's
+    /// if the proxy doesn't know it, and a backend logged in to with `backend`
+    pub fn for_session(backend: Collation, session: &Session, inner: H) -> Self {
+        let client = Collation::from_id(session.character_set).unwrap_or(backend);
+        CharsetHandler::new(client, backend, inner).with_capabilities(session.backend_capabilities)
+    }
+
+    /// Capabilities the backend's responses follow
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    fn to_backend<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        self.client.charset().convert(self.backend.charset(), bytes)
+    }
+
+    fn to_client<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        self.backend.charset().convert(self.client.charset(), bytes)
+    }
+
+    /// A statement converted for the backend, but for its binary literals
+    fn statement_to_backend<'a>(&self, sql: &'a [u8]) -> Cow<'a, [u8]> {
+        let literals = binary_literals(sql);
+        if literals.is_empty() {
+            return self.to_backend(sql);
+        }
+        let mut converted = Vec::with_capacity(sql.len());
+        let mut changed = false;
+        let mut at = 0;
+        for (start, end) in literals {
+            let text = self.to_backend(&sql[at..start]);
+            changed |= matches!(text, Cow::Owned(_));
+            converted.extend_from_slice(&text);
+            converted.extend_from_slice(&sql[start..end]);
+            at = end;
+        }
+        let text = self.to_backend(&sql[at..]);
+        changed |= matches!(text, Cow::Owned(_));
+        converted.extend_from_slice(&text);
+        if changed { Cow::Owned(converted) } else { Cow::Borrowed(sql) }
+    }
+
+    /// A command converted for the backend, or the error to answer it with
+    fn convert_request(&mut self, p: &Packet) -> ::std::result::Result<Option<Packet>, Action> {
+        let payload = p.payload();
+        // statements split over several packets are left alone rather than re-split
+        if p.sequence_id() != 0 || payload.is_empty() || payload.len() >= MAX_PAYLOAD_LEN {
+            return Ok(None);
+        }
+        let converted = match p.packet_type() {
+            Ok(PacketType::ComQuery) => {
+                let sql = self.client.charset().decode(&payload[1..]);
+                if let Some((charset, collation)) = set_names(&sql) {
+                    let unknown = |code, kind, name| Action::Error { code, state: *b"42000", msg: format!("Unknown {}: '{}'", kind, name) };
+                    self.client = match (Charset::from_name(&charset), collation) {
+                        (Some(_), Some(collation)) => collation.parse()
+                            .map_err(|_| unknown(ER_UNKNOWN_COLLATION, "collation", collation))?,
+                        (Some(charset), None) => Collation::default_for(charset),
+                        (None, _) => return Err(unknown(ER_UNKNOWN_CHARACTER_SET, "character set", charset)),
+                    };
+                    debug!("Client switched to {}, backend stays on {}", self.client, self.backend);
+                    let sql = format!("SET NAMES {} COLLATE {}", self.backend.charset().name(), self.backend);
+                    return Ok(Some(Packet::new(0, &[&[payload[0]], sql.as_bytes()].concat())));
+                }
+                self.statement_to_backend(&payload[1..])
+            },
+            Ok(PacketType::ComStmtPrepare) => self.statement_to_backend(&payload[1..]),
+            Ok(PacketType::ComInitDb) | Ok(PacketType::ComFieldList) => {
+                self.to_backend(&payload[1..])
+            },
+            Ok(PacketType::ComStmtExecute) => return Ok(self.convert_execute(p)),
+            _ => return Ok(None),
+        };
+        match converted {
+            Cow::Borrowed(_) => Ok(None),
+            Cow::Owned(converted) => Ok(Some(Packet::new(0, &[&[payload[0]], &converted[..]].concat()))),
+        }
+    }
+
+    /// A COM_STMT_EXECUTE with its string parameters converted
+    fn convert_execute(&mut self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        let id = LittleEndian::read_u32(payload.get(1..5)?);
+        let statement = self.statements.get_mut(&id)?;
+        if statement.params == 0 {
+            return None;
+        }
+        // statement id, flags and iteration count, then the NULL bitmap
+        let bitmap_at = 10;
+        let bound_at = bitmap_at + statement.params.div_ceil(8);
+        let nulls = payload.get(bitmap_at..bound_at)?.to_vec();
+        let mut values_at = bound_at + 1;
+        if *payload.get(bound_at)? == 1 {
+            let types = payload.get(values_at..values_at + 2 * statement.params)?;
+            statement.types = types.chunks(2).map(|t| t[0]).collect();
+            values_at += 2 * statement.params;
+        }
+        let types = statement.types.clone();
+        let client = self.client.charset();
+        let backend = self.backend.charset();
+        let mut r = PayloadReader::new(payload.get(values_at..)?);
+        let mut values = Vec::with_capacity(payload.len());
+        let mut changed = false;
+        for (i, &column_type) in types.iter().enumerate() {
+            if nulls[i / 8] & (1 << (i % 8)) != 0 {
+                continue;
+            }
+            match binary_len(column_type, &mut r).ok()? {
+                Some(len) => values.extend_from_slice(r.bytes(len).ok()?),
+                None => {
+                    let value = r.lenenc_bytes().ok()?;
+                    let converted = if is_string_param(column_type) { client.convert(backend, value) } else { Cow::Borrowed(value) };
+                    changed |= matches!(converted, Cow::Owned(_));
+                    write_lenenc_str(&mut values, &converted);
+                },
+            }
+        }
+        if !changed {
+            return None;
+        }
+        let mut converted = payload[..values_at].to_vec();
+        converted.extend_from_slice(&values);
+        converted.extend_from_slice(r.rest());
+        if converted.len() >= MAX_PAYLOAD_LEN {
+            return None;
+        }
+        Some(Packet::new(0, &converted))
+    }
+
+    /// Follow a response packet from the backend, returning `out`, what the inner handler
+    /// passes on for it, converted for the client if it holds text
+    fn convert_response(&mut self, p: &Packet, out: Option<&Packet>) -> Option<Packet> {
+        let answered = self.correlator.response(p)?;
+        let command = self.pending.front().cloned().unwrap_or(Command::Other);
+        if answered.last {
+            self.pending.pop_front();
+        }
+        let payload = p.payload();
+        let mut converted = None;
+        match (command, answered.kind, self.part) {
+            (_, ResponseKind::Err, _) => converted = out.and_then(|out| self.convert_error(out)),
+            (Command::Query, ResponseKind::Data, Part::Start) | (Command::Execute(_), ResponseKind::Data, Part::Start) => {
+                // the start of a result set, unless the server asks for a LOCAL INFILE
+                if payload.first() != Some(&0xfb) {
+                    let columns = PayloadReader::new(payload).lenenc_int().ok()?;
+                    self.columns.clear();
+                    self.part = Part::Columns { remaining: columns };
+                }
+            },
+            (Command::Query, ResponseKind::Data, Part::Columns { remaining }) |
+            (Command::Execute(_), ResponseKind::Data, Part::Columns { remaining }) => {
+                self.follow_column(p);
+                converted = out.and_then(|out| self.convert_column(out));
+                self.part = match remaining {
+                    r if r > 1 => Part::Columns { remaining: r - 1 },
+                    _ if self.capability_flags & CLIENT_DEPRECATE_EOF != 0 => Part::Rows,
+                    _ => Part::ColumnsEnd,
+                };
+                if let Command::Execute(id) = command {
+                    // the rows of a cursor are fetched later
+                    if let Some(statement) = self.statements.get_mut(&id) {
+                        statement.columns = self.columns.clone();
+                    }
+                }
+            },
+            (_, ResponseKind::Eof, Part::ColumnsEnd) => self.part = Part::Rows,
+            // tolerant backends may go straight to the rows
+            (Command::Query, ResponseKind::Data, Part::Rows) | (Command::Query, ResponseKind::Data, Part::ColumnsEnd) => {
+                self.part = Part::Rows;
+                converted = out.and_then(|out| self.convert_text_row(out));
+            },
+            (Command::Execute(_), ResponseKind::Data, Part::Rows) | (Command::Execute(_), ResponseKind::Data, Part::ColumnsEnd) => {
+                self.part = Part::Rows;
+                converted = out.and_then(|out| self.convert_binary_row(out));
+            },
+            (Command::Fetch(id), ResponseKind::Data, _) => {
+                self.columns = self.statements.get(&id).map(|s| s.columns.clone()).unwrap_or_default();
+                converted = out.and_then(|out| self.convert_binary_row(out));
+            },
+            (Command::Prepare, ResponseKind::Data, Part::Start) => {
+                // PREPARE_OK: status, statement id, columns, parameters
+                if payload.len() >= 9 {
+                    let id = LittleEndian::read_u32(&payload[1..5]);
+                    let params = LittleEndian::read_u16(&payload[7..9]) as usize;
+                    self.statements.insert(id, Statement { params, ..Statement::default() });
+                }
+                self.columns.clear();
+                self.part = Part::Columns { remaining: u64::MAX };
+            },
+            (Command::Prepare, ResponseKind::Data, _) | (Command::FieldList, ResponseKind::Data, _) => {
+                self.follow_column(p);
+                converted = out.and_then(|out| self.convert_column(out));
+            },
+            // the EOFs after parameter and column definitions
+            (Command::Prepare, _, _) | (Command::FieldList, _, _) => {},
+            // the end of a result set, another may follow
+            _ => self.part = Part::Start,
+        }
+        if answered.last {
+            self.part = Part::Start;
+        }
+        converted
+    }
+
+    fn convert_error(&self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        // code, then the SQL state marker and state
+        let at = if payload.get(3) == Some(&b'#') { 9 } else { 3 };
+        match self.to_client(payload.get(at..)?) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(message) => Some(Packet::new(p.sequence_id(), &[&payload[..at], &message[..]].concat())),
+        }
+    }
+
+    /// Note the type of a column of the result being received, and whether it holds text
+    fn follow_column(&mut self, p: &Packet) {
+        let mut r = PayloadReader::new(p.payload());
+        for _ in 0..6 {
+            if r.lenenc_bytes().is_err() {
+                return;
+            }
+        }
+        let fixed = (r.lenenc_int(), r.u16(), r.u32(), r.u8());
+        if let (Ok(_), Ok(character_set), Ok(_), Ok(column_type)) = fixed {
+            self.columns.push((column_type, character_set != BINARY_COLLATION));
+        }
+    }
+
+    /// A column definition with its names converted and a text column's character set and
+    /// length changed to the client's
+    fn convert_column(&self, p: &Packet) -> Option<Packet> {
+        let mut r = PayloadReader::new(p.payload());
+        let mut converted = Vec::with_capacity(p.payload().len());
+        for _ in 0..6 {
+            let name = r.lenenc_bytes().ok()?;
+            write_lenenc_str(&mut converted, &self.to_client(name));
+        }
+        let fixed = r.lenenc_int().ok()?;
+        let character_set = r.u16().ok()?;
+        let column_length = r.u32().ok()?;
+        let column_type = r.u8().ok()?;
+        write_lenenc_int(&mut converted, fixed);
+        if character_set != BINARY_COLLATION {
+            let chars = column_length / self.backend.charset().max_len();
+            converted.extend_from_slice(&(self.client.id() as u16).to_le_bytes());
+            converted.extend_from_slice(&chars.saturating_mul(self.client.charset().max_len()).to_le_bytes());
+        } else {
+            converted.extend_from_slice(&character_set.to_le_bytes());
+            converted.extend_from_slice(&column_length.to_le_bytes());
+        }
+        converted.push(column_type);
+        converted.extend_from_slice(r.rest());
+        Some(Packet::new(p.sequence_id(), &converted))
+    }
+
+    fn convert_text_row(&self, p: &Packet) -> Option<Packet> {
+        let mut r = PayloadReader::new(p.payload());
+        let mut converted = Vec::with_capacity(p.payload().len());
+        let mut changed = false;
+        for &(_, text) in &self.columns {
+            if r.peek() == Some(0xfb) {
+                converted.push(r.u8().ok()?);
+                continue;
+            }
+            let value = r.lenenc_bytes().ok()?;
+            let value = if text { self.to_client(value) } else { Cow::Borrowed(value) };
+            changed |= matches!(value, Cow::Owned(_));
+            write_lenenc_str(&mut converted, &value);
+        }
+        if changed { Some(Packet::new(p.sequence_id(), &converted)) } else { None }
+    }
+
+    fn convert_binary_row(&self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        // the header, then the NULL bitmap, offset by two bits
+        let bitmap_len = (self.columns.len() + 2).div_ceil(8);
+        let nulls = payload.get(1..1 + bitmap_len)?;
+        let mut r = PayloadReader::new(&payload[1 + bitmap_len..]);
+        let mut converted = payload[..1 + bitmap_len].to_vec();
+        let mut changed = false;
+        for (i, &(column_type, text)) in self.columns.iter().enumerate() {
+            if nulls[(i + 2) / 8] & (1 << ((i + 2) % 8)) != 0 {
+                continue;
+            }
+            match binary_len(column_type, &mut r).ok()? {
+                Some(len) => converted.extend_from_slice(r.bytes(len).ok()?),
+                None => {
+                    let value = r.lenenc_bytes().ok()?;
+                    let value = if text { self.to_client(value) } else { Cow::Borrowed(value) };
+                    changed |= matches!(value, Cow::Owned(_));
+                    write_lenenc_str(&mut converted, &value);
+                },
+            }
+        }
+        if changed { Some(Packet::new(p.sequence_id(), &converted)) } else { None }
+    }
+}
+
+impl<H> PacketHandler for CharsetHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return self.inner.handle_request(p);
+        }
+        let action = match self.convert_request(p) {
+            Ok(Some(converted)) => match self.inner.handle_request(&converted) {
+                Action::Forward => Action::Mutate(converted),
+                action => action,
+            },
+            Ok(None) => self.inner.handle_request(p),
+            Err(error) => return error,
+        };
+        // only commands the backend will answer
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        let issued = self.correlator.issued();
+        self.correlator.request(forwarded);
+        if self.correlator.issued() > issued {
+            let payload = forwarded.payload();
+            let id = || payload.get(1..5).map(LittleEndian::read_u32).unwrap_or(0);
+            self.pending.push_back(match forwarded.packet_type() {
+                Ok(PacketType::ComQuery) => Command::Query,
+                Ok(PacketType::ComStmtExecute) => Command::Execute(id()),
+                Ok(PacketType::ComStmtFetch) => Command::Fetch(id()),
+                Ok(PacketType::ComStmtPrepare) => Command::Prepare,
+                Ok(PacketType::ComFieldList) => Command::FieldList,
+                _ => Command::Other,
+            });
+        } else if forwarded.packet_type().ok() == Some(PacketType::ComStmtClose) {
+            self.statements.remove(&forwarded.payload().get(1..5).map(LittleEndian::read_u32).unwrap_or(0));
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        let action = self.inner.handle_response(p);
+        if phase != ConnectionPhase::Command {
+            return action;
+        }
+        let converted = match action {
+            Action::Forward => self.convert_response(p, Some(p)),
+            Action::Mutate(ref p2) => self.convert_response(p, Some(p2)),
+            _ => self.convert_response(p, None),
+        };
+        match converted {
+            Some(converted) => Action::Mutate(converted),
+            None => action,
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,329 @@
+//! Coalescing identical reads that are in flight at the same time.
+//!
+//! When a cache expires, many clients tend to send the same query at once. With a
+//! `[coalesce]` section, the first session to send a read becomes the leader of a flight
+//! for it, and sessions sending the same query while it runs follow it instead of sending
+//! the query to their own backend: they get a copy of the leader's result, which passes
+//! through their handlers as if their backend had sent it. Once the result is complete the
+//! flight lands, and the next identical query starts a new one, so results are never reused
+//! after the fact.
+//!
+//! Only queries that would get the same result on every session are coalesced: `SELECT`s
+//! without locking clauses, variables or functions such as `NOW()` and `RAND()`, sent by the
+//! same user and with the same default schema, character set and capabilities. A session
+//! that changes its state, with `USE`, `SET`, a transaction or a write, is never coalesced
+//! again. Results larger than `max_result_bytes` aren't shared: the followers send the query
+//! themselves, as they do if the leader's session ends before its result is complete.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use futures::Async;
+use futures::task::{self, Task};
+
+use super::{Packet, PacketType};
+use super::auth::Session;
+use super::codec::{EofPacket, OkPacket};
+use super::pipeline::{ResponseKind, ResponsePacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sql::{self, Statement, StatementKind};
+use super::state::SERVER_STATUS_IN_TRANS;
+
+/// Functions whose results differ between calls or sessions
+const VOLATILE_FUNCTIONS: &[&str] = &["now", "sysdate", "curdate", "curtime", "current_date", "current_time",
+    "current_timestamp", "localtime", "localtimestamp", "unix_timestamp", "utc_date", "utc_time", "utc_timestamp",
+    "rand", "uuid", "uuid_short", "connection_id", "last_insert_id", "found_rows", "row_count", "sleep",
+    "get_lock", "release_lock", "is_free_lock", "is_used_lock", "benchmark", "database", "schema", "user",
+    "current_user", "session_user", "system_user", "nextval", "lastval"];
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct CoalesceConfig {
+    /// the largest result copied to followers
+    #[serde(default = "CoalesceConfig::default_max_result_bytes")]
+    pub max_result_bytes: usize,
+}
+
+impl CoalesceConfig {
+
+    fn default_max_result_bytes() -> usize {
+        1024 * 1024
+    }
+}
+
+/// What makes two queries get the same result
+#[derive(Clone,Debug,Hash,PartialEq,Eq)]
+pub struct FlightKey {
+    pub user: String,
+    pub group: String,
+    pub database: Option<String>,
+    pub character_set: u8,
+    pub backend_capabilities: u32,
+    /// the query as sent to the backend
+    pub query: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum FlightStatus {
+    Running,
+    Landed,
+    Abandoned,
+}
+
+#[derive(Debug)]
+struct Flight {
+    status: FlightStatus,
+    packets: Vec<Vec<u8>>,
+    bytes: usize,
+    waiting: Vec<Task>,
+}
+
+type SharedFlight = Arc<Mutex<Flight>>;
+
+/// The flights in progress, shared by a listener's connections
+#[derive(Clone,Debug)]
+pub struct Coalescer {
+    max_result_bytes: usize,
+    flights: Arc<Mutex<HashMap<FlightKey, SharedFlight>>>,
+}
+
+/// How a session takes part in a flight
+pub enum Joined {
+    /// the session sends the query and shares its result
+    Leader(Leader),
+    /// the session waits for the leader's result
+    Follower(Follower),
+}
+
+impl Coalescer {
+
+    pub fn new(config: &CoalesceConfig) -> Self {
+        Coalescer { max_result_bytes: config.max_result_bytes, flights: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Follow the flight for `key` if one is in progress, otherwise lead a new one
+    pub fn join(&self, key: FlightKey) -> Joined {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(&key) {
+            return Joined::Follower(Follower { flight: flight.clone() });
+        }
+        let flight = Arc::new(Mutex::new(Flight { status: FlightStatus::Running, packets: vec![], bytes: 0, waiting: vec![] }));
+        flights.insert(key.clone(), flight.clone());
+        Joined::Leader(Leader { coalescer: self.clone(), key, flight, done: false })
+    }
+
+    /// How many flights are in progress
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+/// The session whose query a flight is waiting for. Dropping it before the result is
+/// complete sends the followers on their own way.
+pub struct Leader {
+    coalescer: Coalescer,
+    key: FlightKey,
+    flight: SharedFlight,
+    done: bool,
+}
+
+impl Leader {
+
+    /// Add a packet of the result, giving up on sharing it once it's too large
+    pub fn record(&mut self, p: &Packet) {
+        if self.done {
+            return;
+        }
+        let too_large = {
+            let mut flight = self.flight.lock().unwrap();
+            flight.bytes += p.bytes.len();
+            flight.packets.push(p.bytes.clone());
+            flight.bytes > self.coalescer.max_result_bytes
+        };
+        if too_large {
+            self.land(FlightStatus::Abandoned);
+        }
+    }
+
+    /// The result is complete, hand it to the followers
+    pub fn finish(mut self) {
+        self.land(FlightStatus::Landed);
+    }
+
+    fn land(&mut self, status: FlightStatus) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        self.coalescer.flights.lock().unwrap().remove(&self.key);
+        let waiting = {
+            let mut flight = self.flight.lock().unwrap();
+            if let FlightStatus::Abandoned = status {
+                flight.packets.clear();
+            }
+            flight.status = status;
+            mem::take(&mut flight.waiting)
+        };
+        for t in waiting {
+            t.notify();
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.land(FlightStatus::Abandoned);
+    }
+}
+
+/// A session waiting for the result of another's query
+pub struct Follower {
+    flight: SharedFlight,
+}
+
+impl Follower {
+
+    /// The leader's result once it's complete, `None` if the leader gave up on sharing it,
+    /// or `NotReady`, arranging for the current task to be notified when it lands
+    pub fn poll(&self) -> Async<Option<Vec<Packet>>> {
+        let mut flight = self.flight.lock().unwrap();
+        match flight.status {
+            FlightStatus::Running => {
+                if !flight.waiting.iter().any(|t| t.will_notify_current()) {
+                    flight.waiting.push(task::current());
+                }
+                Async::NotReady
+            },
+            FlightStatus::Landed => Async::Ready(Some(flight.packets.iter().map(|bytes| Packet { bytes: bytes.clone() }).collect())),
+            FlightStatus::Abandoned => Async::Ready(None),
+        }
+    }
+}
+
+/// Whether a query gets the same result on any session in the same state
+pub fn is_coalescable(sql: &str) -> bool {
+    let statement = Statement::parse(sql);
+    if statement.kind != StatementKind::Select || !statement.read_only {
+        return false;
+    }
+    !sql::tokenize(sql).iter().any(|t| {
+        t.is_punct(b'@') || t.is_punct(b';') || t.is_keyword("into") || t.is_keyword("lock")
+            || VOLATILE_FUNCTIONS.iter().any(|f| t.is_keyword(f))
+    })
+}
+
+/// A session's part in coalescing: leading flights for the reads it sends, or following
+/// others' flights in their place
+pub struct SessionCoalescing {
+    coalescer: Coalescer,
+    key: FlightKey,
+    /// the session hasn't changed its state, so its reads can be shared
+    eligible: bool,
+    /// the flight led, with the number of the command whose result it shares
+    leading: Option<(u64, Leader)>,
+    /// the flight followed, with the query to send if the leader gives up
+    following: Option<(Packet, Follower)>,
+}
+
+impl SessionCoalescing {
+
+    pub fn for_session(coalescer: Coalescer, session: &Session) -> Self {
+        SessionCoalescing {
+            coalescer,
+            key: FlightKey {
+                user: session.user.clone(),
+                group: session.group.clone(),
+                database: session.database.clone(),
+                character_set: session.character_set,
+                backend_capabilities: session.backend_capabilities,
+                query: vec![],
+            },
+            eligible: true,
+            leading: None,
+            following: None,
+        }
+    }
+
+    /// Observe a command about to be sent to the backend as command number `issued + 1`,
+    /// with `in_flight` commands before it waiting for their response. Returns true if the
+    /// session follows another's flight for it instead, so it mustn't be sent.
+    pub fn request(&mut self, p: &Packet, in_flight: usize, issued: u64) -> bool {
+        if p.sequence_id() != 0 || p.payload().is_empty() {
+            return false;
+        }
+        let coalescable = match p.packet_type() {
+            Ok(PacketType::ComQuery) => {
+                let sql = String::from_utf8_lossy(&p.payload()[1..]);
+                // anything but a single read may change what the session's reads return
+                self.eligible &= Statement::parse(&sql).kind == StatementKind::Select && !sql::tokenize(&sql).iter().any(|t| t.is_punct(b';'));
+                is_coalescable(&sql)
+            },
+            Ok(PacketType::ComInitDb) | Ok(PacketType::ComChangeUser) => {
+                self.eligible = false;
+                false
+            },
+            _ => false,
+        };
+        if !coalescable || !self.eligible || in_flight > 0 || self.leading.is_some() {
+            return false;
+        }
+        let key = FlightKey { query: p.payload()[1..].to_vec(), ..self.key.clone() };
+        match self.coalescer.join(key) {
+            Joined::Leader(leader) => {
+                self.leading = Some((issued + 1, leader));
+                false
+            },
+            Joined::Follower(follower) => {
+                self.following = Some((Packet { bytes: p.bytes.clone() }, follower));
+                true
+            },
+        }
+    }
+
+    /// Whether the session is waiting for a flight to land, so client commands must wait
+    pub fn following(&self) -> bool {
+        self.following.is_some()
+    }
+
+    /// Observe a packet from the backend answering command number `command`, before the
+    /// handlers see it
+    pub fn response(&mut self, p: &Packet, answered: Option<ResponsePacket>, command: u64) {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return,
+        };
+        if answered.last {
+            let status_flags = match answered.kind {
+                ResponseKind::Ok => OkPacket::parse(p, CLIENT_PROTOCOL_41).ok().map(|ok| ok.status_flags),
+                ResponseKind::Eof => EofPacket::parse(p).ok().map(|eof| eof.status_flags),
+                _ => None,
+            };
+            if status_flags.map(|flags| flags & SERVER_STATUS_IN_TRANS != 0).unwrap_or(false) {
+                self.eligible = false;
+            }
+        }
+        match self.leading {
+            Some((led, ref mut leader)) if led == command => leader.record(p),
+            _ => return,
+        }
+        if answered.last {
+            if let Some((_, leader)) = self.leading.take() {
+                leader.finish();
+            }
+        }
+    }
+
+    /// The result of the flight followed, once it has landed: the packets to answer the
+    /// client with, or the query to send after all if the leader gave up on sharing it
+    pub fn poll_result(&mut self) -> Option<Result<Vec<Packet>, Packet>> {
+        let result = match self.following {
+            Some((_, ref follower)) => match follower.poll() {
+                Async::Ready(result) => result,
+                Async::NotReady => return None,
+            },
+            None => return None,
+        };
+        let (request, _) = self.following.take().unwrap();
+        Some(result.ok_or(request))
+    }
+}
@@ -0,0 +1,952 @@
+//! Sans-io decoding and encoding of the MySQL wire protocol.
+//!
+//! Nothing in this module performs I/O or depends on tokio: bytes go in, packets and decoded
+//! messages come out. The proxy's connections are built on it, and it can equally be used by
+//! tests, command line tools or another runtime.
+
+use std::io::{Error, ErrorKind, Result};
+
+use byteorder::*;
+
+use super::Packet;
+use super::protocol::*;
+
+/// The largest payload a single packet can carry. Larger payloads are split over several
+/// packets, each but the last carrying exactly this many bytes.
+pub const MAX_PAYLOAD_LEN: usize = 0xff_ffff;
+
+/// Status flag set on OK and EOF packets while autocommit is enabled
+pub const SERVER_STATUS_AUTOCOMMIT: u16 = 0x0002;
+
+/// Status flag set on OK and EOF packets when another result set follows
+pub const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
+
+/// Status flag set on OK packets that carry session state changes
+pub const SERVER_SESSION_STATE_CHANGED: u16 = 0x4000;
+
+/// Column type of VARCHAR values
+pub const MYSQL_TYPE_VAR_STRING: u8 = 0xfd;
+
+/// Parse the MySQL packet length (3 byte little-endian)
+pub fn parse_packet_length(header: &[u8]) -> usize {
+    (((header[2] as u32) << 16) |
+        ((header[1] as u32) << 8) |
+        header[0] as u32) as usize
+}
+
+/// The size of the packet at the start of `buf`, including its header, if all of it is there
+pub fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let n = 4 + parse_packet_length(buf);
+    if buf.len() < n {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+/// Reassembles packets from a stream of bytes, however they were split up on the way
+#[derive(Debug,Default)]
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+}
+
+impl PacketDecoder {
+
+    pub fn new() -> Self {
+        PacketDecoder { buf: Vec::with_capacity(4096) }
+    }
+
+    /// Add bytes received from the connection
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Take the next complete packet, if one has been received
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        let n = frame_len(&self.buf)?;
+        Some(Packet { bytes: self.buf.drain(0..n).collect() })
+    }
+
+    /// Number of bytes received that aren't part of a complete packet yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+/// The initial handshake packet sent by the server (protocol version 10)
+#[derive(Clone,Debug,PartialEq)]
+pub struct HandshakeV10 {
+    pub server_version: String,
+    pub connection_id: u32,
+    pub capability_flags: u32,
+    pub character_set: u8,
+    pub status_flags: u16,
+    pub auth_plugin_data: Vec<u8>,
+    pub auth_plugin_name: Option<String>,
+}
+
+impl HandshakeV10 {
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        HandshakeV10::parse_with(p, false)
+    }
+
+    /// Parse greetings from servers that only approximate the MySQL protocol, which may cut
+    /// the scramble short or end the packet early
+    pub fn parse_lenient(p: &Packet) -> Result<Self> {
+        HandshakeV10::parse_with(p, true)
+    }
+
+    fn parse_with(p: &Packet, lenient: bool) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        let protocol_version = r.u8()?;
+        if protocol_version != 10 {
+            return Err(invalid(format!("Unsupported protocol version {}", protocol_version)));
+        }
+        let server_version = r.null_str()?;
+        let connection_id = r.u32()?;
+        let mut auth_plugin_data = r.bytes(8)?.to_vec();
+        r.u8()?; // filler
+        let mut capability_flags = r.u16()? as u32;
+
+        let mut h = HandshakeV10 {
+            server_version,
+            connection_id,
+            capability_flags,
+            character_set: 0,
+            status_flags: 0,
+            auth_plugin_data: vec![],
+            auth_plugin_name: None,
+        };
+
+        // lenient parsing skips the extended fields unless they're all there
+        let extended = if lenient { r.remaining() >= 16 } else { !r.is_empty() };
+        if extended {
+            h.character_set = r.u8()?;
+            h.status_flags = r.u16()?;
+            capability_flags |= (r.u16()? as u32) << 16;
+            h.capability_flags = capability_flags;
+            let auth_plugin_data_len = r.u8()? as usize;
+            r.bytes(10)?; // reserved
+            if capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+                let n = if auth_plugin_data_len > 8 { auth_plugin_data_len - 8 } else { 13 };
+                let n = if lenient { n.max(13).min(r.remaining()) } else { n.max(13) };
+                let part2 = r.bytes(n)?;
+                auth_plugin_data.extend_from_slice(part2);
+            }
+            if capability_flags & CLIENT_PLUGIN_AUTH != 0 && !(lenient && r.is_empty()) {
+                h.auth_plugin_name = Some(r.null_str_or_eof()?);
+            }
+        }
+
+        // the scramble is sent with a trailing NUL that isn't part of it
+        if auth_plugin_data.last() == Some(&0) {
+            auth_plugin_data.pop();
+        }
+        h.auth_plugin_data = auth_plugin_data;
+        Ok(h)
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = vec![10];
+        payload.extend_from_slice(self.server_version.as_bytes());
+        payload.push(0);
+        payload.write_u32::<LittleEndian>(self.connection_id).unwrap();
+        let (part1, part2) = self.auth_plugin_data.split_at(8.min(self.auth_plugin_data.len()));
+        payload.extend_from_slice(part1);
+        payload.push(0); // filler
+        payload.write_u16::<LittleEndian>(self.capability_flags as u16).unwrap();
+        payload.push(self.character_set);
+        payload.write_u16::<LittleEndian>(self.status_flags).unwrap();
+        payload.write_u16::<LittleEndian>((self.capability_flags >> 16) as u16).unwrap();
+        if self.capability_flags & CLIENT_PLUGIN_AUTH != 0 {
+            payload.push(self.auth_plugin_data.len() as u8 + 1);
+        } else {
+            payload.push(0);
+        }
+        payload.extend_from_slice(&[0; 10]);
+        if self.capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+            payload.extend_from_slice(part2);
+            payload.push(0);
+        }
+        if let Some(ref name) = self.auth_plugin_name {
+            payload.extend_from_slice(name.as_bytes());
+            payload.push(0);
+        }
+        Packet::new(sequence_id, &payload)
+    }
+}
+
+/// The client's response to the initial handshake (Protocol::HandshakeResponse41)
+#[derive(Clone,Debug,PartialEq)]
+pub struct HandshakeResponse {
+    pub capability_flags: u32,
+    pub max_packet_size: u32,
+    pub character_set: u8,
+    pub username: String,
+    pub auth_response: Vec<u8>,
+    pub database: Option<String>,
+    pub auth_plugin_name: Option<String>,
+    /// the raw key/value block sent when CLIENT_CONNECT_ATTRS is set
+    pub connect_attrs: Option<Vec<u8>>,
+}
+
+impl HandshakeResponse {
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        let capability_flags = r.u32()?;
+        if capability_flags & CLIENT_PROTOCOL_41 == 0 {
+            return Err(invalid("Pre-4.1 handshake responses are not supported".to_string()));
+        }
+        let max_packet_size = r.u32()?;
+        let character_set = r.u8()?;
+        r.bytes(23)?; // filler
+        let username = r.null_str()?;
+
+        let auth_response = if capability_flags & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+            let n = r.lenenc_int()? as usize;
+            r.bytes(n)?.to_vec()
+        } else if capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+            let n = r.u8()? as usize;
+            r.bytes(n)?.to_vec()
+        } else {
+            r.null_bytes()?.to_vec()
+        };
+
+        let database = if capability_flags & CLIENT_CONNECT_WITH_DB != 0 && !r.is_empty() {
+            Some(r.null_str_or_eof()?)
+        } else {
+            None
+        };
+
+        let auth_plugin_name = if capability_flags & CLIENT_PLUGIN_AUTH != 0 && !r.is_empty() {
+            Some(r.null_str_or_eof()?)
+        } else {
+            None
+        };
+
+        let connect_attrs = if capability_flags & CLIENT_CONNECT_ATTRS != 0 && !r.is_empty() {
+            let n = r.lenenc_int()? as usize;
+            Some(r.bytes(n)?.to_vec())
+        } else {
+            None
+        };
+
+        Ok(HandshakeResponse {
+            capability_flags,
+            max_packet_size,
+            character_set,
+            username,
+            auth_response,
+            database,
+            auth_plugin_name,
+            connect_attrs,
+        })
+    }
+
+    /// Decode the connection attributes, such as `_client_name` and `program_name`, as
+    /// key/value pairs in the order the client sent them
+    pub fn parse_connect_attrs(&self) -> Result<Vec<(String, String)>> {
+        let mut attrs = vec![];
+        if let Some(ref raw) = self.connect_attrs {
+            let mut r = PayloadReader::new(raw);
+            while !r.is_empty() {
+                let key = r.lenenc_str()?;
+                let value = r.lenenc_str()?;
+                attrs.push((key, value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// Replace the connection attributes, asking for CLIENT_CONNECT_ATTRS if there are any
+    pub fn set_connect_attrs(&mut self, attrs: &[(String, String)]) {
+        if attrs.is_empty() {
+            self.capability_flags &= !CLIENT_CONNECT_ATTRS;
+            self.connect_attrs = None;
+            return;
+        }
+        let mut raw = vec![];
+        for (key, value) in attrs {
+            write_lenenc_str(&mut raw, key.as_bytes());
+            write_lenenc_str(&mut raw, value.as_bytes());
+        }
+        self.capability_flags |= CLIENT_CONNECT_ATTRS;
+        self.connect_attrs = Some(raw);
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = Vec::with_capacity(64);
+        payload.write_u32::<LittleEndian>(self.capability_flags).unwrap();
+        payload.write_u32::<LittleEndian>(self.max_packet_size).unwrap();
+        payload.push(self.character_set);
+        payload.extend_from_slice(&[0; 23]);
+        payload.extend_from_slice(self.username.as_bytes());
+        payload.push(0);
+        if self.capability_flags & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+            write_lenenc_int(&mut payload, self.auth_response.len() as u64);
+            payload.extend_from_slice(&self.auth_response);
+        } else if self.capability_flags & CLIENT_SECURE_CONNECTION != 0 {
+            payload.push(self.auth_response.len() as u8);
+            payload.extend_from_slice(&self.auth_response);
+        } else {
+            payload.extend_from_slice(&self.auth_response);
+            payload.push(0);
+        }
+        if self.capability_flags & CLIENT_CONNECT_WITH_DB != 0 {
+            if let Some(ref db) = self.database {
+                payload.extend_from_slice(db.as_bytes());
+            }
+            payload.push(0);
+        }
+        if self.capability_flags & CLIENT_PLUGIN_AUTH != 0 {
+            if let Some(ref name) = self.auth_plugin_name {
+                payload.extend_from_slice(name.as_bytes());
+            }
+            payload.push(0);
+        }
+        if self.capability_flags & CLIENT_CONNECT_ATTRS != 0 {
+            let attrs = self.connect_attrs.as_ref().map(|a| &a[..]).unwrap_or(&[]);
+            write_lenenc_int(&mut payload, attrs.len() as u64);
+            payload.extend_from_slice(attrs);
+        }
+        Packet::new(sequence_id, &payload)
+    }
+}
+
+/// A request from the server to continue authentication with a different plugin
+#[derive(Clone,Debug,PartialEq)]
+pub struct AuthSwitchRequest {
+    pub plugin_name: String,
+    pub plugin_data: Vec<u8>,
+}
+
+impl AuthSwitchRequest {
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        if r.u8()? != 0xfe {
+            return Err(invalid("Not an auth switch request".to_string()));
+        }
+        let plugin_name = r.null_str_or_eof()?;
+        let mut plugin_data = r.rest().to_vec();
+        if plugin_data.last() == Some(&0) {
+            plugin_data.pop();
+        }
+        Ok(AuthSwitchRequest { plugin_name, plugin_data })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = vec![0xfe];
+        payload.extend_from_slice(self.plugin_name.as_bytes());
+        payload.push(0);
+        payload.extend_from_slice(&self.plugin_data);
+        payload.push(0);
+        Packet::new(sequence_id, &payload)
+    }
+}
+
+/// Create an OK packet with no affected rows and the autocommit status flag set
+pub fn ok_packet(sequence_id: u8) -> Packet {
+    ok_packet_with_status(sequence_id, SERVER_STATUS_AUTOCOMMIT)
+}
+
+/// Create an OK packet with no affected rows and `status_flags`
+pub fn ok_packet_with_status(sequence_id: u8, status_flags: u16) -> Packet {
+    let mut payload = vec![0x00, 0x00, 0x00];
+    payload.write_u16::<LittleEndian>(status_flags).unwrap();
+    payload.extend_from_slice(&[0x00, 0x00]);
+    Packet::new(sequence_id, &payload)
+}
+
+/// Create a classic EOF packet
+pub fn eof_packet(sequence_id: u8, status_flags: u16) -> Packet {
+    let mut payload = vec![0xfe, 0x00, 0x00];
+    payload.write_u16::<LittleEndian>(status_flags).unwrap();
+    Packet::new(sequence_id, &payload)
+}
+
+/// Encode a complete text protocol result set, numbering its packets from sequence id 1 as
+/// in a response to COM_QUERY. With CLIENT_DEPRECATE_EOF the column definitions aren't
+/// followed by an EOF packet, and the rows are ended by an OK packet with an EOF header.
+pub fn text_result_set(columns: &[ColumnDefinition], rows: &[TextRow], capability_flags: u32) -> Vec<Packet> {
+    let deprecate_eof = capability_flags & CLIENT_DEPRECATE_EOF != 0;
+    let mut packets = Vec::with_capacity(columns.len() + rows.len() + 3);
+    let mut payload = vec![];
+    write_lenenc_int(&mut payload, columns.len() as u64);
+    packets.push(Packet::new(1, &payload));
+    for column in columns {
+        let seq = packets.len() as u8 + 1;
+        packets.push(column.to_packet(seq));
+    }
+    if !deprecate_eof {
+        let seq = packets.len() as u8 + 1;
+        packets.push(eof_packet(seq, SERVER_STATUS_AUTOCOMMIT));
+    }
+    for row in rows {
+        let seq = packets.len() as u8 + 1;
+        packets.push(text_row_packet(seq, row));
+    }
+    let seq = packets.len() as u8 + 1;
+    if deprecate_eof {
+        let mut payload = vec![0xfe, 0x00, 0x00];
+        payload.write_u16::<LittleEndian>(SERVER_STATUS_AUTOCOMMIT).unwrap();
+        payload.extend_from_slice(&[0x00, 0x00]); // warnings
+        packets.push(Packet::new(seq, &payload));
+    } else {
+        packets.push(eof_packet(seq, SERVER_STATUS_AUTOCOMMIT));
+    }
+    packets
+}
+
+/// Split the packets of a response whose payloads are too long for one packet, as
+/// `Packet::new` builds them from any payload, into packets of `MAX_PAYLOAD_LEN` bytes and one
+/// with the rest, numbering the response's packets on from the first one's sequence id. A
+/// payload of exactly `MAX_PAYLOAD_LEN` bytes is taken to be split already. Responses without
+/// longer payloads are returned as they are.
+pub fn chunk_response(packets: Vec<Packet>) -> Vec<Packet> {
+    if !packets.iter().any(|p| p.payload().len() > MAX_PAYLOAD_LEN) {
+        return packets;
+    }
+    let mut sequence_id = packets[0].sequence_id();
+    let mut chunked = Vec::with_capacity(packets.len());
+    for p in packets {
+        if p.payload().len() <= MAX_PAYLOAD_LEN {
+            chunked.push(p.with_sequence_id(sequence_id));
+            sequence_id = sequence_id.wrapping_add(1);
+            continue;
+        }
+        for chunk in p.payload().chunks(MAX_PAYLOAD_LEN) {
+            chunked.push(Packet::new(sequence_id, chunk));
+            sequence_id = sequence_id.wrapping_add(1);
+        }
+        // a payload ends with a packet shorter than the maximum, if need be an empty one
+        if p.payload().len() % MAX_PAYLOAD_LEN == 0 {
+            chunked.push(Packet::new(sequence_id, &[]));
+            sequence_id = sequence_id.wrapping_add(1);
+        }
+    }
+    chunked
+}
+
+/// Is this an SSLRequest, sent by clients that want to upgrade to TLS before logging in
+pub fn is_ssl_request(p: &Packet) -> bool {
+    let payload = p.payload();
+    payload.len() == 32 && LittleEndian::read_u32(&payload[0..4]) & CLIENT_SSL != 0
+}
+
+/// Is this an OK packet (as opposed to a result set header starting with a zero column count)
+pub fn is_ok_packet(p: &Packet) -> bool {
+    p.bytes.len() > 4 && p.bytes[4] == 0x00
+}
+
+/// Is this a classic EOF packet (as opposed to a row starting with a long length)
+pub fn is_eof_packet(p: &Packet) -> bool {
+    p.bytes.len() > 4 && p.bytes[4] == 0xfe && p.bytes.len() < 4 + 9
+}
+
+/// Is this an ERR packet
+pub fn is_err_packet(p: &Packet) -> bool {
+    p.bytes.len() > 4 && p.bytes[4] == 0xff
+}
+
+/// An OK packet, or an EOF packet in its CLIENT_DEPRECATE_EOF form
+#[derive(Clone,Debug,PartialEq)]
+pub struct OkPacket {
+    pub affected_rows: u64,
+    pub last_insert_id: u64,
+    pub status_flags: u16,
+    pub warnings: u16,
+    pub info: String,
+    /// session state changes, reported when CLIENT_SESSION_TRACK is negotiated
+    pub state_changes: Vec<StateChange>,
+}
+
+impl OkPacket {
+
+    pub fn parse(p: &Packet, capability_flags: u32) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        match r.u8()? {
+            0x00 | 0xfe => {},
+            _ => return Err(invalid("Not an OK packet".to_string())),
+        }
+        let affected_rows = r.lenenc_int()?;
+        let last_insert_id = r.lenenc_int()?;
+        let (status_flags, warnings) = if capability_flags & CLIENT_PROTOCOL_41 != 0 {
+            (r.u16()?, r.u16()?)
+        } else {
+            (0, 0)
+        };
+        let session_track = capability_flags & CLIENT_SESSION_TRACK != 0;
+        let info = if session_track && !r.is_empty() {
+            r.lenenc_str()?
+        } else {
+            String::from_utf8_lossy(r.rest()).into_owned()
+        };
+        let state_changes = if session_track && status_flags & SERVER_SESSION_STATE_CHANGED != 0 && !r.is_empty() {
+            StateChange::parse_all(r.lenenc_bytes()?)?
+        } else {
+            vec![]
+        };
+        Ok(OkPacket { affected_rows, last_insert_id, status_flags, warnings, info, state_changes })
+    }
+}
+
+/// A change to the session's state, reported by a session state tracker
+#[derive(Clone,Debug,PartialEq)]
+pub enum StateChange {
+    /// a tracked system variable was set
+    SystemVariable { name: String, value: String },
+    /// the default schema changed
+    Schema(String),
+    /// some other session state changed, e.g. a user variable or temporary table was created
+    StateChanged(bool),
+    /// the GTIDs of transactions committed by the statement
+    Gtids(String),
+    /// the `SET TRANSACTION` statements that would recreate the transaction's characteristics
+    TransactionCharacteristics(String),
+    /// the transaction state, as 8 characters such as `T_______`
+    TransactionState(String),
+    /// a tracker this codec doesn't know
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl StateChange {
+
+    /// Parse the session state information block of an OK packet
+    pub fn parse_all(buf: &[u8]) -> Result<Vec<StateChange>> {
+        let mut r = PayloadReader::new(buf);
+        let mut changes = vec![];
+        while !r.is_empty() {
+            let kind = r.u8()?;
+            let data = r.lenenc_bytes()?;
+            let mut d = PayloadReader::new(data);
+            changes.push(match kind {
+                0x00 => StateChange::SystemVariable { name: d.lenenc_str()?, value: d.lenenc_str()? },
+                0x01 => StateChange::Schema(d.lenenc_str()?),
+                0x02 => StateChange::StateChanged(d.lenenc_str()? == "1"),
+                0x03 => {
+                    d.u8()?; // encoding specification, always 0
+                    StateChange::Gtids(d.lenenc_str()?)
+                },
+                0x04 => StateChange::TransactionCharacteristics(d.lenenc_str()?),
+                0x05 => StateChange::TransactionState(d.lenenc_str()?),
+                kind => StateChange::Unknown { kind, data: data.to_vec() },
+            });
+        }
+        Ok(changes)
+    }
+}
+
+/// An ERR packet
+#[derive(Clone,Debug,PartialEq)]
+pub struct ErrPacket {
+    pub code: u16,
+    /// the SQL state, absent in pre-4.1 error packets
+    pub state: Option<[u8; 5]>,
+    pub message: String,
+}
+
+impl ErrPacket {
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        if r.u8()? != 0xff {
+            return Err(invalid("Not an ERR packet".to_string()));
+        }
+        let code = r.u16()?;
+        let rest = r.rest();
+        let (state, message) = if rest.len() >= 6 && rest[0] == b'#' {
+            let mut state = [0_u8; 5];
+            state.copy_from_slice(&rest[1..6]);
+            (Some(state), &rest[6..])
+        } else {
+            (None, rest)
+        };
+        Ok(ErrPacket { code, state, message: String::from_utf8_lossy(message).into_owned() })
+    }
+}
+
+/// A classic EOF packet
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct EofPacket {
+    pub warnings: u16,
+    pub status_flags: u16,
+}
+
+impl EofPacket {
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        if !is_eof_packet(p) {
+            return Err(invalid("Not an EOF packet".to_string()));
+        }
+        let mut r = PayloadReader::new(&p.payload()[1..]);
+        if r.is_empty() {
+            // pre-4.1 servers send a bare 0xfe
+            return Ok(EofPacket { warnings: 0, status_flags: 0 });
+        }
+        Ok(EofPacket { warnings: r.u16()?, status_flags: r.u16()? })
+    }
+}
+
+/// A column of a result set (Protocol::ColumnDefinition41)
+#[derive(Clone,Debug,PartialEq)]
+pub struct ColumnDefinition {
+    pub catalog: String,
+    pub schema: String,
+    pub table: String,
+    pub org_table: String,
+    pub name: String,
+    pub org_name: String,
+    pub character_set: u16,
+    pub column_length: u32,
+    pub column_type: u8,
+    pub flags: u16,
+    pub decimals: u8,
+}
+
+impl ColumnDefinition {
+
+    /// A VARCHAR column that isn't from a table, as in the result of `SELECT 'text'`
+    pub fn varchar(name: &str, column_length: u32) -> Self {
+        ColumnDefinition {
+            catalog: "def".to_string(),
+            schema: String::new(),
+            table: String::new(),
+            org_table: String::new(),
+            name: name.to_string(),
+            org_name: String::new(),
+            character_set: 0x21, // utf8_general_ci
+            column_length,
+            column_type: MYSQL_TYPE_VAR_STRING,
+            flags: 0,
+            decimals: 0x1f,
+        }
+    }
+
+    pub fn parse(p: &Packet) -> Result<Self> {
+        let mut r = PayloadReader::new(p.payload());
+        let catalog = r.lenenc_str()?;
+        let schema = r.lenenc_str()?;
+        let table = r.lenenc_str()?;
+        let org_table = r.lenenc_str()?;
+        let name = r.lenenc_str()?;
+        let org_name = r.lenenc_str()?;
+        r.lenenc_int()?; // length of the fixed length fields, always 0x0c
+        Ok(ColumnDefinition {
+            catalog,
+            schema,
+            table,
+            org_table,
+            name,
+            org_name,
+            character_set: r.u16()?,
+            column_length: r.u32()?,
+            column_type: r.u8()?,
+            flags: r.u16()?,
+            decimals: r.u8()?,
+        })
+    }
+
+    pub fn to_packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = Vec::with_capacity(64);
+        for s in &[&self.catalog, &self.schema, &self.table, &self.org_table, &self.name, &self.org_name] {
+            write_lenenc_str(&mut payload, s.as_bytes());
+        }
+        payload.push(0x0c);
+        payload.write_u16::<LittleEndian>(self.character_set).unwrap();
+        payload.write_u32::<LittleEndian>(self.column_length).unwrap();
+        payload.push(self.column_type);
+        payload.write_u16::<LittleEndian>(self.flags).unwrap();
+        payload.push(self.decimals);
+        payload.extend_from_slice(&[0, 0]);
+        Packet::new(sequence_id, &payload)
+    }
+}
+
+/// A row of a text protocol result set, with `None` for NULL values
+pub type TextRow = Vec<Option<Vec<u8>>>;
+
+/// Parse a text protocol row with the given number of columns
+pub fn parse_text_row(p: &Packet, columns: usize) -> Result<TextRow> {
+    let mut r = PayloadReader::new(p.payload());
+    let mut row = Vec::with_capacity(columns);
+    for _ in 0..columns {
+        if r.peek() == Some(0xfb) {
+            r.u8()?;
+            row.push(None);
+        } else {
+            row.push(Some(r.lenenc_bytes()?.to_vec()));
+        }
+    }
+    Ok(row)
+}
+
+/// Encode a text protocol row
+pub fn text_row_packet(sequence_id: u8, row: &[Option<Vec<u8>>]) -> Packet {
+    let mut payload = Vec::new();
+    for value in row {
+        match *value {
+            Some(ref v) => write_lenenc_str(&mut payload, v),
+            None => payload.push(0xfb),
+        }
+    }
+    Packet::new(sequence_id, &payload)
+}
+
+/// One decoded packet of the server's response to a COM_QUERY
+#[derive(Clone,Debug,PartialEq)]
+pub enum QueryResponse {
+    Ok(OkPacket),
+    Err(ErrPacket),
+    /// the server wants the client to send it the named file
+    LocalInfile(String),
+    ColumnCount(u64),
+    Column(ColumnDefinition),
+    /// the EOF packet between the column definitions and the rows
+    ColumnsEnd,
+    Row(TextRow),
+    /// the end of the rows; another result set follows if `more_results` is set
+    End { status_flags: u16, more_results: bool },
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum ResponseState {
+    Start,
+    Columns { remaining: u64, total: u64 },
+    ColumnsEnd { total: u64 },
+    Rows { columns: u64 },
+    Done,
+}
+
+/// Follows the server's response to a COM_QUERY packet by packet, through any number of
+/// result sets
+#[derive(Clone,Debug)]
+pub struct QueryResponseDecoder {
+    capability_flags: u32,
+    state: ResponseState,
+}
+
+impl QueryResponseDecoder {
+
+    /// `capability_flags` are those agreed for the connection during the handshake
+    pub fn new(capability_flags: u32) -> Self {
+        QueryResponseDecoder { capability_flags, state: ResponseState::Start }
+    }
+
+    /// Whether the response is complete
+    pub fn is_done(&self) -> bool {
+        self.state == ResponseState::Done
+    }
+
+    pub fn decode(&mut self, p: &Packet) -> Result<QueryResponse> {
+        let deprecate_eof = self.capability_flags & CLIENT_DEPRECATE_EOF != 0;
+        let (response, next) = match self.state {
+            ResponseState::Start => match p.payload().first() {
+                Some(&0x00) => {
+                    let ok = OkPacket::parse(p, self.capability_flags)?;
+                    let next = self.after_result(ok.status_flags);
+                    (QueryResponse::Ok(ok), next)
+                },
+                Some(&0xff) => (QueryResponse::Err(ErrPacket::parse(p)?), ResponseState::Done),
+                Some(&0xfb) => {
+                    let file = String::from_utf8_lossy(&p.payload()[1..]).into_owned();
+                    // the server answers the file contents with OK or ERR
+                    (QueryResponse::LocalInfile(file), ResponseState::Start)
+                },
+                Some(_) => {
+                    let total = PayloadReader::new(p.payload()).lenenc_int()?;
+                    (QueryResponse::ColumnCount(total), ResponseState::Columns { remaining: total, total })
+                },
+                None => return Err(invalid("Empty response packet".to_string())),
+            },
+            ResponseState::Columns { remaining, total } => {
+                let column = ColumnDefinition::parse(p)?;
+                let next = match (remaining - 1, deprecate_eof) {
+                    (0, true) => ResponseState::Rows { columns: total },
+                    (0, false) => ResponseState::ColumnsEnd { total },
+                    (n, _) => ResponseState::Columns { remaining: n, total },
+                };
+                (QueryResponse::Column(column), next)
+            },
+            ResponseState::ColumnsEnd { total } => {
+                EofPacket::parse(p)?;
+                (QueryResponse::ColumnsEnd, ResponseState::Rows { columns: total })
+            },
+            ResponseState::Rows { columns } => {
+                let payload = p.payload();
+                match payload.first() {
+                    Some(&0xff) => (QueryResponse::Err(ErrPacket::parse(p)?), ResponseState::Done),
+                    Some(&0xfe) if deprecate_eof && payload.len() < MAX_PAYLOAD_LEN => {
+                        let ok = OkPacket::parse(p, self.capability_flags)?;
+                        let next = self.after_result(ok.status_flags);
+                        (self.end(ok.status_flags), next)
+                    },
+                    Some(&0xfe) if !deprecate_eof && payload.len() < 9 => {
+                        let eof = EofPacket::parse(p)?;
+                        let next = self.after_result(eof.status_flags);
+                        (self.end(eof.status_flags), next)
+                    },
+                    _ => (QueryResponse::Row(parse_text_row(p, columns as usize)?), ResponseState::Rows { columns }),
+                }
+            },
+            ResponseState::Done => return Err(invalid("Packet received after the end of the response".to_string())),
+        };
+        self.state = next;
+        Ok(response)
+    }
+
+    fn after_result(&self, status_flags: u16) -> ResponseState {
+        if status_flags & SERVER_MORE_RESULTS_EXISTS != 0 {
+            ResponseState::Start
+        } else {
+            ResponseState::Done
+        }
+    }
+
+    fn end(&self, status_flags: u16) -> QueryResponse {
+        QueryResponse::End {
+            status_flags,
+            more_results: status_flags & SERVER_MORE_RESULTS_EXISTS != 0,
+        }
+    }
+}
+
+/// Append a length-encoded integer
+pub fn write_lenenc_int(buf: &mut Vec<u8>, n: u64) {
+    if n < 251 {
+        buf.push(n as u8);
+    } else if n < 1 << 16 {
+        buf.push(0xfc);
+        buf.write_u16::<LittleEndian>(n as u16).unwrap();
+    } else if n < 1 << 24 {
+        buf.push(0xfd);
+        buf.write_u32::<LittleEndian>(n as u32).unwrap();
+        buf.pop();
+    } else {
+        buf.push(0xfe);
+        buf.write_u64::<LittleEndian>(n).unwrap();
+    }
+}
+
+/// Append a length-encoded string
+pub fn write_lenenc_str(buf: &mut Vec<u8>, s: &[u8]) {
+    write_lenenc_int(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Cursor over a packet payload
+pub struct PayloadReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+
+    pub fn new(buf: &'a [u8]) -> Self {
+        PayloadReader { buf, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.remaining() < n {
+            return Err(invalid("Unexpected end of packet".to_string()));
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    /// The next byte, without consuming it
+    pub fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).cloned()
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let b = &self.buf[self.pos.min(self.buf.len())..];
+        self.pos = self.buf.len();
+        b
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    pub fn u24(&mut self) -> Result<u32> {
+        let b = self.bytes(3)?;
+        Ok((b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+
+    pub fn lenenc_int(&mut self) -> Result<u64> {
+        match self.u8()? {
+            0xfc => Ok(self.u16()? as u64),
+            0xfd => Ok(self.u24()? as u64),
+            0xfe => self.u64(),
+            n => Ok(n as u64),
+        }
+    }
+
+    pub fn lenenc_bytes(&mut self) -> Result<&'a [u8]> {
+        let n = self.lenenc_int()? as usize;
+        self.bytes(n)
+    }
+
+    pub fn lenenc_str(&mut self) -> Result<String> {
+        let b = self.lenenc_bytes()?;
+        Ok(String::from_utf8_lossy(b).into_owned())
+    }
+
+    pub fn null_str(&mut self) -> Result<String> {
+        self.null_bytes().map(|b| String::from_utf8_lossy(b).into_owned())
+    }
+
+    /// Bytes up to a terminating NUL, which is skipped
+    pub fn null_bytes(&mut self) -> Result<&'a [u8]> {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        match rest.iter().position(|b| *b == 0) {
+            Some(n) => {
+                self.pos += n + 1;
+                Ok(&rest[0..n])
+            },
+            None => Err(invalid("Unterminated string".to_string())),
+        }
+    }
+
+    /// Some servers omit the terminating NUL of the last string in a packet
+    pub fn null_str_or_eof(&mut self) -> Result<String> {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        if rest.contains(&0) {
+            self.null_str()
+        } else {
+            self.pos = self.buf.len();
+            Ok(String::from_utf8_lossy(rest).into_owned())
+        }
+    }
+}
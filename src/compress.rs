@@ -0,0 +1,185 @@
+//! The compressed protocol, negotiated separately with clients and backends.
+//!
+//! The proxy completes the handshake with each side itself, so it can offer compression to
+//! clients while logging in to backends without it, or the other way round: clients across
+//! a WAN save bandwidth, while backends in the same datacenter don't pay for compressing
+//! every result. With `client` set in the `[compression]` section, the greeting advertises
+//! CLIENT_COMPRESS and clients that ask for it get compressed packets once logged in. With
+//! `backend` set, the proxy asks backends that support it for compression as well.
+//!
+//! On a compressed connection MySQL packets travel inside compressed packets with a header
+//! of their own: the length of the payload, a sequence id and the length before compression,
+//! zero for payloads too short to be worth compressing. `Decompressor` and `Compressor` turn
+//! one into the other for a connection's reading and writing halves.
+
+use std::cell::Cell;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::rc::Rc;
+
+use bytes::BytesMut;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+/// Length, sequence id and length before compression
+const HEADER_LEN: usize = 7;
+
+/// The largest payload a compressed packet can carry
+const MAX_PAYLOAD_LEN: usize = 0xff_ffff;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct CompressionConfig {
+    /// offer the compressed protocol to clients
+    #[serde(default = "CompressionConfig::default_client")]
+    pub client: bool,
+    /// ask backends for the compressed protocol, if they support it
+    #[serde(default)]
+    pub backend: bool,
+    /// the zlib level, from 1 for the fastest to 9 for the smallest
+    #[serde(default = "CompressionConfig::default_level")]
+    pub level: u32,
+    /// payloads shorter than this are sent as they are
+    #[serde(default = "CompressionConfig::default_min_size")]
+    pub min_size: usize,
+}
+
+impl CompressionConfig {
+
+    fn default_client() -> bool {
+        true
+    }
+
+    fn default_level() -> u32 {
+        6
+    }
+
+    /// what MySQL itself uses
+    fn default_min_size() -> usize {
+        50
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=9).contains(&self.level) {
+            return Err(format!("level must be between 1 and 9, not {}", self.level));
+        }
+        Ok(())
+    }
+}
+
+/// The sequence ids of a connection's compressed packets, which the reading and writing
+/// halves share: a reply continues the sequence of what it replies to
+#[derive(Clone,Debug,Default)]
+pub struct CompressedSequence {
+    next: Rc<Cell<u8>>,
+}
+
+impl CompressedSequence {
+
+    pub fn new() -> Self {
+        CompressedSequence::default()
+    }
+
+    fn take(&self) -> u8 {
+        let id = self.next.get();
+        self.next.set(id.wrapping_add(1));
+        id
+    }
+}
+
+/// Unpacks the MySQL packets from the compressed packets read from a connection
+#[derive(Debug)]
+pub struct Decompressor {
+    buf: Vec<u8>,
+    sequence: CompressedSequence,
+}
+
+impl Decompressor {
+
+    pub fn new(sequence: CompressedSequence) -> Self {
+        Decompressor { buf: Vec::with_capacity(4096), sequence }
+    }
+
+    /// Add bytes read from the connection, appending the contents of every compressed
+    /// packet completed by them to `out`
+    pub fn decompress(&mut self, bytes: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        self.buf.extend_from_slice(bytes);
+        let mut start = 0;
+        while self.buf.len() - start >= HEADER_LEN {
+            let frame = &self.buf[start..];
+            let len = read_u24(frame);
+            if frame.len() < HEADER_LEN + len {
+                break;
+            }
+            self.sequence.next.set(frame[3].wrapping_add(1));
+            let uncompressed_len = read_u24(&frame[4..]);
+            let payload = &frame[HEADER_LEN..HEADER_LEN + len];
+            if uncompressed_len == 0 {
+                out.extend_from_slice(payload);
+            } else {
+                let mut plain = Vec::with_capacity(uncompressed_len);
+                ZlibDecoder::new(payload).take(uncompressed_len as u64 + 1).read_to_end(&mut plain)?;
+                if plain.len() != uncompressed_len {
+                    return Err(Error::new(ErrorKind::InvalidData, "Compressed packet doesn't match its length"));
+                }
+                out.extend_from_slice(&plain);
+            }
+            start += HEADER_LEN + len;
+        }
+        self.buf.drain(..start);
+        Ok(())
+    }
+}
+
+/// Packs MySQL packets into compressed packets for writing to a connection
+#[derive(Debug)]
+pub struct Compressor {
+    level: Compression,
+    min_size: usize,
+    sequence: CompressedSequence,
+}
+
+impl Compressor {
+
+    pub fn new(config: &CompressionConfig, sequence: CompressedSequence) -> Self {
+        Compressor { level: Compression::new(config.level), min_size: config.min_size, sequence }
+    }
+
+    /// Append compressed packets carrying `packets`, the bytes of one or more whole MySQL
+    /// packets, to `out`. Packets starting a command start the compressed sequence over.
+    pub fn compress(&self, packets: &[u8], out: &mut BytesMut) {
+        if packets.len() > 3 && packets[3] == 0 {
+            self.sequence.next.set(0);
+        }
+        for chunk in packets.chunks(MAX_PAYLOAD_LEN) {
+            let sequence_id = self.sequence.take();
+            let compressed = if chunk.len() >= self.min_size { self.deflate(chunk) } else { None };
+            match compressed {
+                Some(compressed) => {
+                    write_header(out, compressed.len(), sequence_id, chunk.len());
+                    out.extend_from_slice(&compressed);
+                },
+                None => {
+                    write_header(out, chunk.len(), sequence_id, 0);
+                    out.extend_from_slice(chunk);
+                },
+            }
+        }
+    }
+
+    /// The compressed payload, if compressing makes it smaller
+    fn deflate(&self, chunk: &[u8]) -> Option<Vec<u8>> {
+        let mut encoder = ZlibEncoder::new(Vec::with_capacity(chunk.len() / 2), self.level);
+        encoder.write_all(chunk).ok()?;
+        encoder.finish().ok().filter(|compressed| compressed.len() < chunk.len())
+    }
+}
+
+fn read_u24(bytes: &[u8]) -> usize {
+    bytes[0] as usize | (bytes[1] as usize) << 8 | (bytes[2] as usize) << 16
+}
+
+fn write_header(out: &mut BytesMut, len: usize, sequence_id: u8, uncompressed_len: usize) {
+    out.reserve(HEADER_LEN + len);
+    out.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, sequence_id,
+                            uncompressed_len as u8, (uncompressed_len >> 8) as u8, (uncompressed_len >> 16) as u8]);
+}
@@ -0,0 +1,1388 @@
+//! Proxy configuration, loaded from a TOML file.
+//!
+//! ```toml
+//! # optional, record DDL and administrative statements in a hash-chained log
+//! audit_log = "audit.log"
+//! # optional, start a new audit log file at 64 MiB or after a day, gzip the old ones and keep
+//! # 30 of them, syncing each record to disk. fsync is "never", "rotate" or "always".
+//! audit_files = { max_bytes = 67108864, max_age_secs = 86400, compress = "gzip", keep_files = 30, fsync = "always" }
+//! # optional, accept connections on 4 reactor threads sharing the address with SO_REUSEPORT
+//! workers = 4
+//! # optional, how many connections may wait to be accepted
+//! backlog = 1024
+//! # optional, how long clients have from the greeting to log in, 10000 by default and 0
+//! # for as long as they like
+//! handshake_timeout_ms = 5000
+//! # optional, answer COM_PING without a backend round trip
+//! answer_ping = true
+//! # optional, relax protocol checks for backends such as ClickHouse, Doris, TiDB or Vitess
+//! tolerant_backends = true
+//! # optional, answer commands the proxy doesn't know with an error rather than forwarding them
+//! unknown_commands = "reject"
+//! # optional, log in to backends with CLIENT_DEPRECATE_EOF and add the EOF packets back
+//! # for clients, which never get the capability from the proxy
+//! backend_deprecate_eof = true
+//! # optional, log in to backends with this collation and convert text between it and the
+//! # character sets of clients, e.g. for latin1 applications on utf8mb4 backends
+//! backend_collation = "utf8mb4_general_ci"
+//! # optional, when a client disconnects with commands in flight, keep reading their responses
+//! # from the backend for up to this long, so handlers see them complete
+//! drain_on_close_ms = 2000
+//!
+//! [groups.primary]
+//! # host names may resolve to IPv4 and IPv6 addresses, IPv6 addresses go in brackets
+//! backends = ["db1.example.com:3306", "[2001:db8::10]:3306"]
+//!
+//! [groups.replicas]
+//! backends = ["db2.example.com:3306", "db3.example.com:3306"]
+//! # optional, send more sessions to the replicas that answer fastest, and none to those
+//! # answering over outlier_factor times slower than the median
+//! latency = { decay = 0.1, outlier_factor = 3.0 }
+//!
+//! # optional, a group whose backends are looked up while the proxy runs, either every
+//! # address of a host name, e.g. a Kubernetes headless service, an SRV record, or, with the
+//! # consul or etcd feature, a service registry
+//! [groups.analytics.discovery]
+//! host = "mysql-analytics.db.svc.cluster.local"
+//! port = 3306
+//! # srv = "_mysql._tcp.mysql-analytics.db.svc.cluster.local"
+//! # consul = { service = "mysql-analytics", tag = "replica" }
+//! # etcd = { addr = "http://etcd:2379", prefix = "/services/mysql-analytics/" }
+//! # or the online secondaries of a Group Replication or InnoDB Cluster group
+//! # group_replication = { seeds = ["db1:3306", "db2:3306"], user = "monitor", password = "secret", role = "secondary" }
+//! interval_secs = 10
+//!
+//! # optional, listeners with settings of their own, instead of the addresses given on the
+//! # command line. A listener may override any top-level setting or section; arrays such as
+//! # `users` are replaced, not extended.
+//! [listeners.rw]
+//! listen = ["0.0.0.0:3307"]
+//! group = "primary"
+//!
+//! [listeners.ro]
+//! listen = ["0.0.0.0:3308"]
+//! group = "replicas"
+//! max_connections = 500
+//! answer_ping = true
+//! [listeners.ro.poll_budget]
+//! max_packets = 64
+//!
+//! # optional, sizing of the pool of packet buffers shared by all connections
+//! [buffer_pool]
+//! buffer_size = 4096
+//! max_buffers = 1024
+//! max_buffer_size = 65536
+//!
+//! # optional, work each connection may do per turn before yielding to others
+//! [poll_budget]
+//! max_packets = 256
+//! max_bytes = 262144
+//! max_time_us = 2000
+//!
+//! # optional, when queued packets are written: "immediate" (the default), "per_response",
+//! # which writes to clients once a response is complete, or "batch", which holds packets for
+//! # up to delay_us; either writes at once when max_bytes are queued
+//! [flush]
+//! policy = "batch"
+//! delay_us = 200
+//! max_bytes = 65536
+//!
+//! # optional, prepend `/* proxy_conn=.. client=.. app=.. */` to queries sent to backends,
+//! # the application defaults to the client's `program_name` connection attribute
+//! [annotate]
+//! application = "billing"
+//!
+//! # optional, connection attributes sent to backends, both default to true
+//! [connect_attrs]
+//! # pass on the client's attributes, such as program_name
+//! forward = true
+//! # add proxy_version, proxy_client_ip and proxy_client_port
+//! add_proxy = true
+//!
+//! # optional, reach backends through a SOCKS5 or HTTP CONNECT proxy, which resolves their
+//! # host names, with optional credentials
+//! [upstream]
+//! kind = "socks5"
+//! addr = "bastion.example.com:1080"
+//! username = "proxy"
+//! password = "secret"
+//! # or, with the ssh feature, through an SSH jump host with key authentication
+//! # kind = "ssh"
+//! # addr = "bastion.example.com:22"
+//! # username = "tunnel"
+//! # identity_file = "/etc/mysql-proxy/id_ed25519"
+//! # known_hosts = "/etc/mysql-proxy/known_hosts"
+//!
+//! # optional, log in to backends with short-lived tokens rather than the backend passwords
+//! # below, here AWS RDS IAM tokens signed with the AWS_* environment credentials
+//! [credentials]
+//! provider = "rds_iam"
+//! region = "eu-west-1"
+//! # or Cloud SQL IAM access tokens from the GCE metadata server
+//! # provider = "gcp_metadata"
+//! # or the first line a command prints, valid for ttl_secs
+//! # provider = "command"
+//! # command = ["gcloud", "sql", "generate-login-token"]
+//! # ttl_secs = 900
+//! # or the password from a file, re-read when a backend rejects it, {user} is the backend user
+//! # provider = "file"
+//! # path = "/run/secrets/mysql-{user}"
+//! # or a secret with `username` and `password` fields, also re-read when rejected,
+//! # from HashiCorp Vault's KV store, with the vault feature and VAULT_TOKEN or token_file
+//! # provider = "vault"
+//! # addr = "https://vault.example.com:8200"
+//! # path = "secret/data/mysql/{user}"
+//! # or from AWS Secrets Manager, with the aws-secrets feature
+//! # provider = "aws_secrets_manager"
+//! # region = "eu-west-1"
+//! # secret_id = "mysql/{user}"
+//!
+//! # optional, relay X Protocol clients to the backends' X Protocol port
+//! [x_protocol]
+//! listen = "0.0.0.0:33060"
+//! backends = ["db1.example.com:33060"]
+//!
+//! # optional, present the same server version to clients whatever the backend
+//! [greeting]
+//! server_version = "8.0.36-proxy"
+//! # CLIENT_DEPRECATE_EOF
+//! clear_capabilities = 0x01000000
+//!
+//! # optional, HTTP and TCP health endpoint reporting backend health, and query statistics
+//! # at /stats
+//! [health]
+//! listen = "127.0.0.1:8080"
+//! interval_secs = 5
+//! timeout_ms = 2000
+//! # ramp up the share of new sessions a backend gets over 60s once it's back up
+//! slow_start_secs = 60
+//! # optionally log in as well, and mark replicas down once they stop replicating or lag
+//! [health.replication]
+//! user = "monitor"
+//! password = "secret"
+//! max_lag_secs = 30
+//!
+//! # optional, save query statistics every interval_secs and carry on from them on start
+//! [stats]
+//! path = "/var/lib/mysql-proxy/stats.json"
+//! interval_secs = 60
+//! # also count connections, queries, errors, bytes and backend time by these connection
+//! # labels, each with at most max_label_values values before the rest count as "other"
+//! labels = ["app", "team"]
+//! max_label_values = 100
+//!
+//! # optional, keep size backend connections logged in ahead for each backend, user and
+//! # shape of login, replaced once taken or after max_age_secs, so sessions skip the login
+//! [warm_pool]
+//! size = 4
+//! max_age_secs = 300
+//!
+//! # optional, labels on every session, for its logs, events, audit records, listings and
+//! # statistics: fixed ones, and ones taken from connection attributes and user attributes
+//! [labels]
+//! fixed = { team = "payments" }
+//! connect_attrs = { app = "program_name" }
+//! user_attributes = { tenant = "tenant_id" }
+//!
+//! # optional, append each user's connections, queries, errors, bytes returned and backend
+//! # time over every interval_secs to path, as csv or json, for charging tenants back
+//! [chargeback]
+//! path = "/var/lib/mysql-proxy/chargeback.csv"
+//! format = "csv"
+//! interval_secs = 3600
+//!
+//! # optional, with the profiling feature, time one in sample_every reads, packet splits,
+//! # handler runs and writes of every pipe, served at the management API's /profile
+//! [profiling]
+//! sample_every = 100
+//!
+//! # optional, log the full text of the first query of each fingerprint taking at least
+//! # min_time_ms and sample_rate of the others, with literals replaced by ? unless scrub
+//! # is false
+//! [query_log]
+//! path = "/var/log/mysql-proxy/queries.log"
+//! sample_rate = 0.01
+//! min_time_ms = 100
+//! scrub = true
+//!
+//! # optional, HTTP API for changing users, rules, backend weights and maintenance mode,
+//! # and listing and killing sessions, while the proxy runs
+//! [management]
+//! listen = "127.0.0.1:8081"
+//! # required unless listening on a loopback address
+//! token = "change-me"
+//!
+//! # optional, a MySQL admin interface to users, backends and table rules, changed in memory
+//! # with SQL and then loaded to runtime or saved to path
+//! [management.admin]
+//! listen = "127.0.0.1:6032"
+//! user = "admin"
+//! password = "change-me"
+//! path = "/var/lib/mysql-proxy/admin.json"
+//! # how long clients have to log in, and the most served at once
+//! login_timeout_secs = 10
+//! max_sessions = 8
+//!
+//! # optional, the compressed protocol for clients that ask for it, e.g. across a WAN, and
+//! # for backends, each negotiated on its own
+//! [compression]
+//! client = true
+//! backend = false
+//! level = 6
+//!
+//! # optional, capabilities disabled for every client and backend, compress included
+//! [capabilities]
+//! disable = ["local_files", "multi_statements"]
+//!
+//! # optional, socket options for client and backend connections
+//! [client_socket]
+//! nodelay = true
+//! keepalive = { time_secs = 300, interval_secs = 30, probes = 4 }
+//!
+//! [backend_socket]
+//! nodelay = true
+//! send_buffer_size = 262144
+//! recv_buffer_size = 262144
+//! linger_secs = 5
+//! keepalive = { time_secs = 60 }
+//!
+//! # optional, networks allowed to connect to the listener
+//! [access]
+//! allow = ["10.0.0.0/8", "192.168.1.0/24"]
+//! deny = ["10.0.13.0/24"]
+//!
+//! # optional, slow down and then block addresses that keep failing to log in: the
+//! # greeting waits delay_ms after the first failure, doubling with each one up to
+//! # max_delay_ms, and after max_failures the address is refused for ban_secs
+//! [tarpit]
+//! max_failures = 10
+//! delay_ms = 200
+//! max_delay_ms = 10000
+//! ban_secs = 300
+//! window_secs = 600
+//! exempt = ["10.0.5.0/24"]
+//!
+//! # optional, usually on a listener profile of its own, e.g. [listeners.trap.honeypot]:
+//! # pretend to be a server, accepting any login without a backend and answering with empty
+//! # results, or "denied" errors, while publishing honeypot_login and honeypot_command events
+//! [honeypot]
+//! server_version = "5.7.44-log"
+//! greeting_delay_ms = 50
+//! idle_timeout_secs = 300
+//! answer = "empty"
+//!
+//! # optional, write failed logins and protocol violations as lines of JSON with the
+//! # client's address, for fail2ban to ban it or a SIEM to collect, to a file, a UDP
+//! # socket or both; events lists the kinds of events written
+//! [event_log]
+//! path = "/var/log/mysql-proxy/security.json"
+//! udp = "10.0.9.4:5514"
+//! events = ["auth_failed", "protocol_violation"]
+//!
+//! # optional, with the nats feature, publish events as JSON to NATS subjects named after
+//! # them, and audit records to audit_subject, batched, retried and dropped when max_queued
+//! # are waiting
+//! [event_sink]
+//! nats = "nats.internal:4222"
+//! subject = "mysql_proxy.events"
+//! audit_subject = "mysql_proxy.audit"
+//! batch_size = 100
+//! max_queued = 10000
+//!
+//! # optional, tables only some users may use, others get MySQL's access denied error
+//! [[table_rules]]
+//! table = "payments.cards"
+//! allow_users = ["billing_svc"]
+//!
+//! # or columns, with `*` matching any schema or table, and statements audited instead
+//! [[table_rules]]
+//! table = "*.users"
+//! columns = ["password_hash"]
+//! action = "audit"
+//!
+//! # optional, users only see and change the rows of shop.orders with their tenant_id
+//! # attribute, or their user name with value = "{user}"
+//! [[row_filters]]
+//! table = "shop.orders"
+//! column = "tenant_id"
+//! value = "{tenant_id}"
+//! exempt_users = ["admin"]
+//!
+//! # optional, append the rows of the reports user's queries like this one, whatever their
+//! # literals, to a CSV file, dropping rows beyond max_queued_rows waiting to be written
+//! [[taps]]
+//! query = "SELECT id, total FROM shop.orders WHERE created > '2024-01-01'"
+//! users = ["reports"]
+//! path = "/var/lib/mysql-proxy/orders.csv"
+//! max_queued_rows = 10000
+//!
+//! # optional, rewrite backend errors before clients see them: the first rule matching an
+//! # error by code, by part of its message or both changes it
+//! [[error_rules]]
+//! code = 1045
+//! message = "Login failed for {user}, ask your tenant administrator for access"
+//!
+//! # or hides internal host names, keeping the rest of the message as {message}
+//! [[error_rules]]
+//! contains = ".db.internal"
+//! redact = ["db1.db.internal", "db2.db.internal"]
+//!
+//! # optional, keep the fingerprints, timings and results of each connection's last 32
+//! # commands, and write them to the audit_log if the connection ends with an error
+//! [capture]
+//! exchanges = 32
+//!
+//! # optional, write the timeline of each of app's connections to a file of its own in dir,
+//! # with the kinds, sizes and payload hashes of its packets but not their contents, to
+//! # attach to bug reports
+//! [trace]
+//! dir = "/var/tmp/mysql-proxy-traces"
+//! users = ["app"]
+//!
+//! # optional, explain analysts' queries on a side connection first and reject those that
+//! # would scan a whole table or examine more than max_rows rows, or only log them with
+//! # action = "flag"
+//! [explain]
+//! users = ["analyst"]
+//! tables = ["shop.*"]
+//! backend_user = "explainer"
+//! backend_password = "explainer-secret"
+//! max_rows = 1000000
+//! timeout_ms = 500
+//!
+//! # optional, send a read that sessions of the same user send at the same time to the
+//! # backend once and give all of them its result, unless it's larger than max_result_bytes
+//! [coalesce]
+//! max_result_bytes = 1048576
+//!
+//! # optional, keep the results of metadata queries ORMs send on every connection for
+//! # ttl_ms, per backend, and answer the same query in the same session state with them
+//! [metadata_cache]
+//! ttl_ms = 5000
+//! max_entries = 1024
+//! max_result_bytes = 65536
+//! queries = ["SELECT @@version_comment", "SELECT DATABASE()", "SHOW VARIABLES LIKE"]
+//!
+//! # optional, once sessions sessions of a user in a row start with the same SET statements,
+//! # send them to the backend as soon as the user's next session logs in
+//! [session_priming]
+//! sessions = 3
+//! max_statements = 16
+//!
+//! # optional, count packets that break the protocol in the statistics, by kind, and let
+//! # handlers decide whether to kill the connection, always killing it for those in kill
+//! [protocol_checks]
+//! max_frame_bytes = 16777215
+//! kill = ["oversized_frame", "unknown_command"]
+//!
+//! # optional, retry autocommit statements that deadlock or time out waiting for a lock,
+//! # backing off 20ms, 40ms, 80ms... with jitter, before the client sees the error
+//! [deadlock_retry]
+//! max_retries = 3
+//! base_delay_ms = 20
+//! max_delay_ms = 1000
+//!
+//! # optional, try connecting to a backend up to max_attempts times in all, for sessions, warm
+//! # connections and health checks alike, backing off 100ms, 200ms... with jitter, after the
+//! # failures in retry_on, of refused, reset, timed_out, unreachable and other
+//! [connect_retry]
+//! max_attempts = 3
+//! base_delay_ms = 100
+//! max_delay_ms = 2000
+//! retry_on = ["refused", "reset", "timed_out", "unreachable"]
+//!
+//! # optional, log sessions that lose their backend while idle outside a transaction in to
+//! # another backend of their group, replaying their schema, SET and prepared statements
+//! [session_resume]
+//! max_statements = 64
+//! timeout_ms = 5000
+//!
+//! # optional, stop sending sessions to a backend for cooldown_secs once connect_failures
+//! # connections to it fail in a row, or error_rate of the commands it answers fail with
+//! # overload errors, then let probes sessions through to see whether it has recovered
+//! [circuit_breaker]
+//! connect_failures = 5
+//! error_rate = 0.5
+//! min_commands = 20
+//! window_secs = 10
+//! cooldown_secs = 30
+//! probes = 1
+//!
+//! # optional, a backend's own thresholds
+//! [circuit_breaker.backends."10.0.0.3:3306"]
+//! cooldown_secs = 120
+//!
+//! # optional, close sessions that leave a transaction open with nothing running for
+//! # idle_secs, or roll the transaction back and keep them with action = "rollback"
+//! [idle_transaction]
+//! idle_secs = 60
+//! action = "kill"
+//!
+//! # optional, close sessions once they have been open for max_lifetime_secs or run nothing
+//! # for max_idle_secs, between statements and outside transactions
+//! [connection_lifetime]
+//! max_lifetime_secs = 3600
+//! max_idle_secs = 600
+//!
+//! [[users]]
+//! user = "app"
+//! password = "secret"
+//! backend_user = "app_rw"
+//! backend_password = "backend-secret"
+//! default_group = "primary"
+//! # certificate identities may be required too, of clients that log in over TLS
+//! access = { allow = ["10.1.0.0/16"], identities = ["app@example.com"] }
+//! attributes = { tenant_id = "42" }
+//! # optional, refused with error 1203 or 1226 over any of these, all users matching a `*`
+//! # mapping get limits of their own
+//! quota = { max_connections = 100, max_concurrent_queries = 20, max_queries_per_hour = 100000 }
+//!
+//! # a tenant whose schema `app` is `tenant_acme_app` on the backend, {user} is the user
+//! [[users]]
+//! user = "acme"
+//! password = "secret"
+//! backend_user = "tenant_acme"
+//! backend_password = "backend-secret"
+//! default_group = "primary"
+//! tenant = { prefix = "tenant_{user}_", schemas = ["app"] }
+//!
+//! # a reporting user sharing a backend user, starting in `shop` and refused other schemas
+//! # with error 1044 at login and on USE or COM_INIT_DB
+//! [[users]]
+//! user = "reports"
+//! password = "secret"
+//! backend_user = "app_rw"
+//! backend_password = "backend-secret"
+//! default_group = "primary"
+//! schemas = { default = "shop", allowed = ["shop_archive"] }
+//!
+//! # a legacy application whose client only does pre-4.1 authentication, password is the
+//! # OLD_PASSWORD() hash and the proxy logs in to the backend with the new authentication
+//! [[users]]
+//! user = "legacy"
+//! password = "5d2e19393cc5ef67"
+//! old_password = true
+//! backend_user = "legacy_app"
+//! backend_password = "backend-secret"
+//! default_group = "primary"
+//!
+//! # any other user logs in to the backend as itself, e.g. with Kerberos or GSSAPI, the
+//! # proxy relaying the authentication exchange
+//! [[users]]
+//! user = "*"
+//! default_group = "primary"
+//! auth_passthrough = true
+//!
+//! # optional, check passwords against LDAP instead, which needs [tls] to be required as
+//! # clients then send their passwords in clear text
+//! [auth]
+//! provider = "ldap"
+//! addr = "ldap.example.com:636"
+//! bind_dn = "uid={user},ou=people,dc=example,dc=com"
+//! # "ldaps", "starttls" or "none", and the CA certificates the server is verified against
+//! tls = "ldaps"
+//! ca = "ldap-ca.pem"
+//! cache_ttl_secs = 300
+//! # how many logins are checked at once, and how many more may wait
+//! threads = 4
+//! max_queued = 64
+//!
+//! # optional, accept TLS from clients (requires the `tls` feature)
+//! [tls]
+//! cert = "server-cert.pem"
+//! key = "server-key.pem"
+//! client_ca = "client-ca.pem"
+//! require_client_cert = true
+//! identity = "san_email"
+//! cert_auth = true
+//! # reload the files when they change, for certificates renewed while the proxy runs
+//! reload_secs = 60
+//!
+//! # optional, the proxy user a certificate's identity logs in as when it isn't the user's own
+//! # name, and a routing group for its sessions other than the user's default
+//! [[tls.users]]
+//! identity = "batch@example.com"
+//! user = "reports"
+//! group = "replicas"
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::acl::AccessList;
+use super::annotate::AnnotateConfig;
+use super::attrs::ConnectAttrsConfig;
+use super::audit::AuditFiles;
+use super::auth::DEFAULT_HANDSHAKE_TIMEOUT;
+use super::authenticator::*;
+use super::breaker::CircuitBreakerConfig;
+use super::anomaly::ProtocolChecksConfig;
+use super::budget::PollBudget;
+use super::capture::CaptureConfig;
+use super::chargeback::ChargebackConfig;
+use super::capabilities::CapabilityPolicy;
+use super::charset::Collation;
+use super::coalesce::CoalesceConfig;
+use super::compress::CompressionConfig;
+use super::connect::BackendAddr;
+use super::credentials::CredentialsConfig;
+use super::discovery::DiscoveryConfig;
+use super::errors::ErrorRule;
+use super::eventlog::EventLogConfig;
+use super::explain::ExplainConfig;
+use super::flush::FlushPolicy;
+use super::greeting::GreetingConfig;
+use super::health::HealthConfig;
+use super::honeypot::HoneypotConfig;
+use super::labels::LabelsConfig;
+use super::warmpool::WarmPoolConfig;
+use super::idle::IdleTransactionConfig;
+use super::lifetime::ConnectionLifetimeConfig;
+use super::latency::LatencyConfig;
+use super::management::ManagementConfig;
+use super::metacache::MetadataCacheConfig;
+use super::pool::PoolConfig;
+use super::priming::SessionPrimingConfig;
+use super::profile::ProfilingConfig;
+use super::querylog::QueryLogConfig;
+use super::resume::SessionResumeConfig;
+use super::retry::{ConnectRetryPolicy, RetryPolicy};
+use super::rowfilter::RowFilter;
+use super::rules::TableRule;
+use super::sink::EventSinkConfig;
+use super::sockopt::SocketOptions;
+use super::stats::StatsConfig;
+use super::tap::TapConfig;
+use super::tarpit::TarpitConfig;
+use super::trace::TraceConfig;
+use super::unknown::UnknownCommandPolicy;
+use super::upstream::UpstreamProxy;
+use super::users::UserMapping;
+use super::xprotocol::XProtocolConfig;
+
+/// A minimal configuration to start from, as printed by `mysql-proxy print-default-config`
+pub const DEFAULT_CONFIG: &str = r#"# listen on 127.0.0.1:3307 and send every session to a local MySQL server
+[listeners.default]
+listen = ["127.0.0.1:3307"]
+
+[groups.primary]
+backends = ["127.0.0.1:3306"]
+
+# clients log in to the proxy as `app`, which logs in to the backend as `app_backend`
+[[users]]
+user = "app"
+password = "change-me"
+backend_user = "app_backend"
+backend_password = "change-me"
+default_group = "primary"
+"#;
+
+/// A named set of backends that sessions can be routed to
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct RoutingGroup {
+    /// the backends, until discovery finds others
+    #[serde(default)]
+    pub backends: Vec<BackendAddr>,
+    /// look the backends up in DNS, and keep looking them up while the proxy runs
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    /// weigh the backends by how fast they answer
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+}
+
+/// A listener with its own settings, e.g. read-write on :3307 to the primary group and
+/// read-only on :3308 to replicas
+#[derive(Clone,Debug,PartialEq)]
+pub struct ListenerProfile {
+    pub name: String,
+    pub listen: Vec<SocketAddr>,
+    /// the routing group every session goes to, instead of its user's default group
+    pub group: Option<String>,
+    /// how many connections may be open at once, unlimited if not set
+    pub max_connections: Option<usize>,
+    /// the configuration for the listener's connections: the top-level settings with the
+    /// profile's own on top
+    pub config: ProxyConfig,
+}
+
+/// The settings of a profile that aren't proxy configuration
+#[derive(Deserialize)]
+struct ListenerSettings {
+    listen: Vec<SocketAddr>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    max_connections: Option<usize>,
+}
+
+impl ListenerProfile {
+
+    /// Parse the profile `name`, whose settings in `profile` override those in `base`
+    fn parse(name: String, base: &toml::Value, profile: toml::Value) -> Result<Self> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("Listener '{}': {}", name, msg));
+        let mut overrides = match profile {
+            toml::Value::Table(table) => table,
+            _ => return Err(invalid("must be a table".to_string())),
+        };
+        let settings: toml::value::Table = ["listen", "group", "max_connections"].iter()
+            .filter_map(|key| overrides.remove(*key).map(|value| (key.to_string(), value)))
+            .collect();
+        let settings: ListenerSettings = toml::Value::Table(settings).try_into().map_err(|e| invalid(e.to_string()))?;
+        let mut merged = base.clone();
+        merge(&mut merged, toml::Value::Table(overrides));
+        let config: ProxyConfig = merged.try_into().map_err(|e| invalid(e.to_string()))?;
+        if settings.listen.is_empty() {
+            return Err(invalid("no addresses to listen on".to_string()));
+        }
+        if let Some(ref group) = settings.group {
+            if !config.groups.contains_key(group) {
+                return Err(invalid(format!("unknown routing group '{}'", group)));
+            }
+        }
+        Ok(ListenerProfile {
+            name,
+            listen: settings.listen,
+            group: settings.group,
+            max_connections: settings.max_connections,
+            config,
+        })
+    }
+
+    /// The backend for a new session of a user whose default group is `default_group`
+    pub fn backend_for_group(&self, default_group: &str) -> Option<BackendAddr> {
+        self.config.backend_for_group(self.group.as_ref().map(|g| &g[..]).unwrap_or(default_group))
+    }
+}
+
+/// Merge `overrides` into `base`, table by table. Anything else, arrays included, is replaced.
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (&mut toml::Value::Table(ref mut base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, value) => *base = value,
+    }
+}
+
+/// External validator for proxy user passwords
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum AuthProvider {
+    /// LDAP simple bind, e.g. `bind_dn = "uid={user},ou=people,dc=example,dc=com"`, secured
+    /// with `tls = "ldaps"` or `"starttls"` and verified against the CA certificates in `ca`
+    Ldap {
+        addr: String,
+        bind_dn: String,
+        #[serde(default)]
+        tls: LdapTls,
+        #[serde(default)]
+        ca: Option<PathBuf>,
+    },
+    /// HTTP POST of the credentials as JSON, to an `https://` URL verified against `ca`
+    Webhook {
+        url: String,
+        #[serde(default)]
+        ca: Option<PathBuf>,
+    },
+    /// the system's PAM stack, requires the `pam` feature
+    Pam { service: String },
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct AuthConfig {
+    #[serde(flatten)]
+    pub provider: AuthProvider,
+    /// how long successful authentications are cached for, 0 disables caching
+    #[serde(default)]
+    pub cache_ttl_secs: u64,
+    /// how many logins are checked at once
+    #[serde(default = "AuthConfig::default_threads")]
+    pub threads: usize,
+    /// the most logins waiting to be checked, beyond which logins are refused
+    #[serde(default = "AuthConfig::default_max_queued")]
+    pub max_queued: usize,
+}
+
+impl AuthConfig {
+
+    fn default_threads() -> usize {
+        4
+    }
+
+    fn default_max_queued() -> usize {
+        64
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.threads == 0 {
+            return Err("threads must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Start the threads the configured authenticator runs on
+    pub fn pool(&self) -> Result<AuthenticatorPool> {
+        AuthenticatorPool::new(self.authenticator()?, self.threads, self.max_queued)
+    }
+
+    /// Create the configured authenticator
+    pub fn authenticator(&self) -> Result<Arc<dyn Authenticator>> {
+        let authenticator: Arc<dyn Authenticator> = match self.provider {
+            AuthProvider::Ldap { ref addr, ref bind_dn, tls, ref ca } => {
+                Arc::new(LdapAuthenticator::new(addr, bind_dn).with_tls(tls, ca.as_deref()))
+            },
+            AuthProvider::Webhook { ref url, ref ca } => match *ca {
+                Some(ref ca) => Arc::new(WebhookAuthenticator::new(url).with_ca(ca)),
+                None => Arc::new(WebhookAuthenticator::new(url)),
+            },
+            #[cfg(feature = "pam")]
+            AuthProvider::Pam { ref service } => Arc::new(PamAuthenticator::new(service)),
+            #[cfg(not(feature = "pam"))]
+            AuthProvider::Pam { .. } => {
+                return Err(Error::new(ErrorKind::InvalidInput, "PAM support requires the 'pam' feature"));
+            },
+        };
+        if self.cache_ttl_secs > 0 {
+            let ttl = Duration::from_secs(self.cache_ttl_secs);
+            Ok(Arc::new(CachingAuthenticator::new(authenticator, ttl)))
+        } else {
+            Ok(authenticator)
+        }
+    }
+}
+
+/// Which part of a client certificate names the user it was issued to
+#[derive(Clone,Copy,Debug,Default,Deserialize,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CertIdentity {
+    /// the subject common name
+    #[default]
+    Cn,
+    /// the first DNS name in the subject alternative names
+    SanDns,
+    /// the first email address in the subject alternative names
+    SanEmail,
+    /// the first URI in the subject alternative names
+    SanUri,
+}
+
+/// A certificate identity and the proxy user it logs in as
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct CertUser {
+    /// the identity named by the client's certificate, as `TlsConfig::identity` picks it
+    pub identity: String,
+    /// the proxy user the certificate stands for
+    pub user: String,
+    /// the routing group the certificate's sessions go to, rather than the user's default
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain presented to clients
+    pub cert: PathBuf,
+    /// PEM encoded private key for `cert`
+    pub key: PathBuf,
+    /// PEM encoded CA certificates that client certificates are verified against
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// refuse clients that do not present a certificate signed by `client_ca`
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// refuse clients that do not upgrade the connection to TLS
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub identity: CertIdentity,
+    /// accept a verified certificate in place of a password, if the login user matches its identity
+    #[serde(default)]
+    pub cert_auth: bool,
+    /// the proxy users certificate identities stand for, an identity not listed here stands
+    /// for the user of the same name
+    #[serde(default)]
+    pub users: Vec<CertUser>,
+    /// check `cert`, `key` and `client_ca` for changes this often and reload them, 0 to only
+    /// reload them through the management API
+    #[serde(default)]
+    pub reload_secs: u64,
+}
+
+impl TlsConfig {
+
+    /// The proxy user a verified certificate for `identity` stands for
+    pub fn cert_user(&self, identity: &str) -> CertUser {
+        self.users.iter().find(|u| u.identity == identity).cloned().unwrap_or_else(|| CertUser {
+            identity: identity.to_string(),
+            user: identity.to_string(),
+            group: None,
+        })
+    }
+
+    /// Load the certificate, key and client CA, as the listener will
+    #[cfg(feature = "tls")]
+    pub fn check(&self) -> Result<()> {
+        super::tls::server_config(self).map(|_| ())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn check(&self) -> Result<()> {
+        Err(Error::new(ErrorKind::InvalidInput, "TLS support requires the 'tls' feature"))
+    }
+}
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct ProxyConfig {
+    /// reactor threads accepting connections, 0 or 1 runs everything on a single thread
+    #[serde(default)]
+    pub workers: usize,
+    /// connections that may wait to be accepted on each listener, `DEFAULT_BACKLOG` if not set
+    #[serde(default)]
+    pub backlog: Option<i32>,
+    /// how long clients have from the greeting to log in, `DEFAULT_HANDSHAKE_TIMEOUT` if not
+    /// set and without a limit if 0
+    #[serde(default)]
+    pub handshake_timeout_ms: Option<u64>,
+    /// answer COM_PING in the proxy rather than forwarding it to the backend
+    #[serde(default)]
+    pub answer_ping: bool,
+    /// backends only approximate the MySQL protocol, so relax the proxy's assumptions about it
+    #[serde(default)]
+    pub tolerant_backends: bool,
+    /// what to do with commands the proxy doesn't know
+    #[serde(default)]
+    pub unknown_commands: UnknownCommandPolicy,
+    /// log in to backends with CLIENT_DEPRECATE_EOF when they support it
+    #[serde(default)]
+    pub backend_deprecate_eof: bool,
+    /// log in to backends with this collation rather than the client's
+    #[serde(default)]
+    pub backend_collation: Option<Collation>,
+    /// the compressed protocol with clients, backends or both
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// comments added to queries to show backends where they came from
+    #[serde(default)]
+    pub annotate: Option<AnnotateConfig>,
+    /// connection attributes sent to backends
+    #[serde(default)]
+    pub connect_attrs: ConnectAttrsConfig,
+    /// SOCKS5 or HTTP proxy that backends are reached through
+    #[serde(default)]
+    pub upstream: Option<UpstreamProxy>,
+    /// where backend credentials come from, if not the user mappings
+    #[serde(default)]
+    pub credentials: Option<CredentialsConfig>,
+    /// listener relaying X Protocol clients
+    #[serde(default)]
+    pub x_protocol: Option<XProtocolConfig>,
+    /// health endpoint for load balancers
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+    /// where query statistics are kept across restarts
+    #[serde(default)]
+    pub stats: Option<StatsConfig>,
+    /// backend connections logged in ahead of the sessions that need them
+    #[serde(default)]
+    pub warm_pool: Option<WarmPoolConfig>,
+    /// key/value pairs sessions are labelled with
+    #[serde(default)]
+    pub labels: Option<LabelsConfig>,
+    /// periodic reports of each user's usage, from the statistics
+    #[serde(default)]
+    pub chargeback: Option<ChargebackConfig>,
+    /// sampling of the time pipes spend in each stage
+    #[serde(default)]
+    pub profiling: Option<ProfilingConfig>,
+    /// where samples of full query text are written
+    #[serde(default)]
+    pub query_log: Option<QueryLogConfig>,
+    /// HTTP API for changing the proxy while it runs
+    #[serde(default)]
+    pub management: Option<ManagementConfig>,
+    /// how long to keep reading the responses to commands in flight once their client has
+    /// disconnected, they are dropped with the backend connection if not set
+    #[serde(default)]
+    pub drain_on_close_ms: Option<u64>,
+    /// capabilities that are never negotiated
+    #[serde(default)]
+    pub capabilities: CapabilityPolicy,
+    /// changes to the greeting presented to clients
+    #[serde(default)]
+    pub greeting: GreetingConfig,
+    #[serde(default)]
+    pub client_socket: SocketOptions,
+    #[serde(default)]
+    pub backend_socket: SocketOptions,
+    #[serde(default)]
+    pub buffer_pool: PoolConfig,
+    /// how much work each connection may do before letting others on its reactor run
+    #[serde(default)]
+    pub poll_budget: PollBudget,
+    /// when packets queued for clients and backends are written
+    #[serde(default)]
+    pub flush: FlushPolicy,
+    /// where to record DDL and administrative statements
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// how audit log files are rotated, compressed, kept and synced
+    #[serde(default)]
+    pub audit_files: Option<AuditFiles>,
+    /// networks that may connect to the listener, checked before the proxy greets the client
+    #[serde(default)]
+    pub access: AccessList,
+    /// delay and block addresses with failed logins
+    #[serde(default)]
+    pub tarpit: Option<TarpitConfig>,
+    /// accept any login and answer clients without a backend, recording what they attempt
+    #[serde(default)]
+    pub honeypot: Option<HoneypotConfig>,
+    /// write failed logins and other events as JSON for fail2ban or a SIEM
+    #[serde(default)]
+    pub event_log: Option<EventLogConfig>,
+    /// publish events and audit records to NATS
+    #[serde(default)]
+    pub event_sink: Option<EventSinkConfig>,
+    /// tables and columns only some users may use
+    #[serde(default)]
+    pub table_rules: Vec<TableRule>,
+    /// tables whose rows each user only partly sees
+    #[serde(default)]
+    pub row_filters: Vec<RowFilter>,
+    /// queries whose results are copied to files
+    #[serde(default)]
+    pub taps: Vec<TapConfig>,
+    /// changes to the errors backends send
+    #[serde(default)]
+    pub error_rules: Vec<ErrorRule>,
+    /// keep each connection's last commands, for the audit log if it ends with an error
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+    /// vet some users' queries with EXPLAIN before running them
+    #[serde(default)]
+    pub explain: Option<ExplainConfig>,
+    /// share the results of identical reads running at the same time
+    #[serde(default)]
+    pub coalesce: Option<CoalesceConfig>,
+    /// answer frequent metadata queries from results kept for a short while
+    #[serde(default)]
+    pub metadata_cache: Option<MetadataCacheConfig>,
+    /// send the statements each user's sessions start with for them
+    #[serde(default)]
+    pub session_priming: Option<SessionPrimingConfig>,
+    /// check packets for protocol anomalies
+    #[serde(default)]
+    pub protocol_checks: Option<ProtocolChecksConfig>,
+    /// write a protocol trace of some users' connections, for bug reports
+    #[serde(default)]
+    pub trace: Option<TraceConfig>,
+    /// retry autocommit statements that fail with a deadlock or lock wait timeout
+    #[serde(default)]
+    pub deadlock_retry: Option<RetryPolicy>,
+    /// try connecting to backends again after failures that may well pass
+    #[serde(default)]
+    pub connect_retry: Option<ConnectRetryPolicy>,
+    /// move idle sessions to another backend when theirs is lost
+    #[serde(default)]
+    pub session_resume: Option<SessionResumeConfig>,
+    /// stop sending sessions to backends that keep failing
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// end transactions that clients leave open while idle
+    #[serde(default)]
+    pub idle_transaction: Option<IdleTransactionConfig>,
+    /// close sessions between statements once they have lived, or idled, for too long
+    #[serde(default)]
+    pub connection_lifetime: Option<ConnectionLifetimeConfig>,
+    /// validate proxy passwords with an external provider instead of the user mappings
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub groups: HashMap<String, RoutingGroup>,
+    #[serde(default)]
+    pub users: Vec<UserMapping>,
+    /// listeners with settings of their own, from the `[listeners]` tables
+    #[serde(skip)]
+    pub listeners: Vec<ListenerProfile>,
+}
+
+impl ProxyConfig {
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        ProxyConfig::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = |e: toml::de::Error| Error::new(ErrorKind::InvalidData, e.to_string());
+        let mut value: toml::Value = toml::from_str(s).map_err(invalid)?;
+        let profiles = value.as_table_mut().and_then(|t| t.remove("listeners"));
+        let mut config: ProxyConfig = value.clone().try_into().map_err(invalid)?;
+        match profiles {
+            Some(toml::Value::Table(profiles)) => {
+                for (name, profile) in profiles {
+                    config.listeners.push(ListenerProfile::parse(name, &value, profile)?);
+                }
+            },
+            Some(_) => return Err(Error::new(ErrorKind::InvalidData, "listeners must be a table of profiles")),
+            None => {},
+        }
+        Ok(config)
+    }
+
+    /// Mistakes that parsing doesn't catch, such as users of routing groups that don't
+    /// exist, each described in a message. Nothing outside the configuration is looked at,
+    /// see `dry_run` for that.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|&(name, _)| name);
+        for (name, group) in groups {
+            match group.discovery {
+                Some(ref discovery) => if let Err(e) = discovery.validate() {
+                    problems.push(format!("Routing group '{}': {}", name, e));
+                },
+                None if group.backends.is_empty() => problems.push(format!("Routing group '{}' has no backends", name)),
+                None => {},
+            }
+            if let Some(ref latency) = group.latency {
+                if let Err(e) = latency.validate() {
+                    problems.push(format!("Routing group '{}': {}", name, e));
+                }
+            }
+        }
+        if let Some(ref auth) = self.auth {
+            if let Err(e) = auth.validate() {
+                problems.push(format!("Authenticator: {}", e));
+            }
+            // clients are asked for their passwords in clear text
+            if !self.tls.as_ref().is_some_and(|tls| tls.required || tls.require_client_cert) {
+                problems.push("Authenticator: needs [tls] with `required = true`, as clients send it their passwords in clear text".to_string());
+            }
+        }
+        let mut users = HashSet::new();
+        for user in &self.users {
+            if !users.insert(&user.user) {
+                problems.push(format!("User '{}' is mapped more than once", user.user));
+            }
+            if !self.groups.contains_key(&user.default_group) {
+                problems.push(format!("User '{}': unknown routing group '{}'", user.user, user.default_group));
+            }
+            if let Err(e) = user.check_password(self.auth.is_some()) {
+                problems.push(format!("User '{}': {}", user.user, e));
+            }
+            if !user.access.identities.is_empty() && self.tls.is_none() {
+                problems.push(format!("User '{}': certificate identities need [tls]", user.user));
+            }
+            if let Some(ref schemas) = user.schemas {
+                if let Err(e) = schemas.validate() {
+                    problems.push(format!("User '{}': {}", user.user, e));
+                }
+            }
+        }
+        if let Some(ref tarpit) = self.tarpit {
+            if let Err(e) = tarpit.validate() {
+                problems.push(format!("Tarpit: {}", e));
+            }
+        }
+        if let Some(ref honeypot) = self.honeypot {
+            if let Err(e) = honeypot.validate() {
+                problems.push(format!("Honeypot: {}", e));
+            }
+        }
+        if let Some(ref retry) = self.connect_retry {
+            if let Err(e) = retry.validate() {
+                problems.push(format!("Connect retry: {}", e));
+            }
+        }
+        if let Some(ref lifetime) = self.connection_lifetime {
+            if let Err(e) = lifetime.validate() {
+                problems.push(format!("Connection lifetime: {}", e));
+            }
+        }
+        if let Some(ref warm_pool) = self.warm_pool {
+            if let Err(e) = warm_pool.validate() {
+                problems.push(format!("Warm pool: {}", e));
+            }
+        }
+        if let Some(ref labels) = self.labels {
+            if let Err(e) = labels.validate() {
+                problems.push(format!("Labels: {}", e));
+            }
+        }
+        if let Some(ref stats) = self.stats {
+            if let Err(e) = stats.validate() {
+                problems.push(format!("Stats: {}", e));
+            }
+        }
+        if let Some(ref chargeback) = self.chargeback {
+            if let Err(e) = chargeback.validate() {
+                problems.push(format!("Chargeback: {}", e));
+            }
+            if self.stats.is_none() {
+                problems.push("Chargeback: needs [stats] to report from".to_string());
+            }
+        }
+        if let Some(ref profiling) = self.profiling {
+            if let Err(e) = profiling.validate() {
+                problems.push(format!("Profiling: {}", e));
+            }
+            if !cfg!(feature = "profiling") {
+                problems.push("Profiling: requires the 'profiling' feature".to_string());
+            }
+        }
+        if let Some(ref resume) = self.session_resume {
+            if let Err(e) = resume.validate() {
+                problems.push(format!("Session resume: {}", e));
+            }
+        }
+        if let Some(ref cache) = self.metadata_cache {
+            if let Err(e) = cache.validate() {
+                problems.push(format!("Metadata cache: {}", e));
+            }
+        }
+        if let Some(ref priming) = self.session_priming {
+            if let Err(e) = priming.validate() {
+                problems.push(format!("Session priming: {}", e));
+            }
+        }
+        if let Some(ref checks) = self.protocol_checks {
+            if let Err(e) = checks.validate() {
+                problems.push(format!("Protocol checks: {}", e));
+            }
+        }
+        if let Some(ref breaker) = self.circuit_breaker {
+            if let Err(e) = breaker.validate() {
+                problems.push(format!("Circuit breaker: {}", e));
+            }
+        }
+        if let Some(ref event_log) = self.event_log {
+            if let Err(e) = event_log.validate() {
+                problems.push(format!("Event log: {}", e));
+            }
+        }
+        if let Some(ref sink) = self.event_sink {
+            if let Err(e) = sink.validate() {
+                problems.push(format!("Event sink: {}", e));
+            }
+            if !cfg!(feature = "nats") {
+                problems.push("Event sink: requires the 'nats' feature".to_string());
+            }
+        }
+        for rule in &self.table_rules {
+            if let Err(e) = rule.validate() {
+                problems.push(format!("Table rule: {}", e));
+            }
+        }
+        for filter in &self.row_filters {
+            if let Err(e) = filter.validate() {
+                problems.push(format!("Row filter: {}", e));
+            }
+        }
+        for tap in &self.taps {
+            if let Err(e) = tap.validate() {
+                problems.push(format!("Tap: {}", e));
+            }
+        }
+        for rule in &self.error_rules {
+            if let Err(e) = rule.validate() {
+                problems.push(format!("Error rule: {}", e));
+            }
+        }
+        if let Some(ref query_log) = self.query_log {
+            if let Err(e) = query_log.validate() {
+                problems.push(format!("Query log: {}", e));
+            }
+        }
+        if let Some(ref compression) = self.compression {
+            if let Err(e) = compression.validate() {
+                problems.push(format!("Compression: {}", e));
+            }
+        }
+        if let Err(e) = self.flush.validate() {
+            problems.push(format!("Flush: {}", e));
+        }
+        if let Some(ref files) = self.audit_files {
+            if let Err(e) = files.validate() {
+                problems.push(format!("Audit files: {}", e));
+            }
+            if self.audit_log.is_none() {
+                problems.push("Audit files: needs an audit_log to rotate".to_string());
+            }
+        }
+        if let Some(ref capture) = self.capture {
+            if let Err(e) = capture.validate() {
+                problems.push(format!("Capture: {}", e));
+            }
+            if self.audit_log.is_none() {
+                problems.push("Capture: needs an audit_log to write to".to_string());
+            }
+        }
+        if let Some(ref trace) = self.trace {
+            if let Err(e) = trace.validate() {
+                problems.push(format!("Trace: {}", e));
+            }
+        }
+        if !self.access.identities.is_empty() {
+            problems.push("Access: the listener's access list can't require certificate identities".to_string());
+        }
+        if let Some(ref tls) = self.tls {
+            if tls.client_ca.is_none() && (tls.require_client_cert || tls.cert_auth) {
+                problems.push("TLS: require_client_cert and cert_auth need a client_ca".to_string());
+            }
+            let mut identities = HashSet::new();
+            for cert_user in &tls.users {
+                if !identities.insert(&cert_user.identity) {
+                    problems.push(format!("TLS: identity '{}' is mapped more than once", cert_user.identity));
+                }
+                if !self.users.iter().any(|u| u.user == cert_user.user || u.user == "*") {
+                    problems.push(format!("TLS: identity '{}' maps to unknown user '{}'", cert_user.identity, cert_user.user));
+                }
+                if let Some(ref group) = cert_user.group {
+                    if !self.groups.contains_key(group) {
+                        problems.push(format!("TLS: identity '{}': unknown routing group '{}'", cert_user.identity, group));
+                    }
+                }
+            }
+        }
+        if let Some(ref management) = self.management {
+            if management.token.is_none() && !management.listen.ip().is_loopback() {
+                problems.push(format!("Management API on {} needs a token", management.listen));
+            }
+            if let Some(ref admin) = management.admin {
+                if let Err(e) = admin.validate() {
+                    problems.push(format!("Admin interface: {}", e));
+                }
+            }
+        }
+        let mut addrs: Vec<(SocketAddr, String)> = vec![];
+        addrs.extend(self.health.as_ref().map(|h| (h.listen, "the health endpoint".to_string())));
+        addrs.extend(self.management.as_ref().map(|m| (m.listen, "the management API".to_string())));
+        addrs.extend(self.management.as_ref().and_then(|m| m.admin.as_ref()).map(|a| (a.listen, "the admin interface".to_string())));
+        addrs.extend(self.x_protocol.as_ref().map(|x| (x.listen, "the X Protocol relay".to_string())));
+        for profile in &self.listeners {
+            for &addr in &profile.listen {
+                let listener = format!("listener '{}'", profile.name);
+                match addrs.iter().find(|&&(a, _)| a == addr) {
+                    Some((_, other)) => problems.push(format!("{} is used by both {} and {}", addr, other, listener)),
+                    None => addrs.push((addr, listener)),
+                }
+            }
+            // only report what the profile got wrong itself, not what it inherits
+            let inherited = problems.clone();
+            for problem in profile.config.validate() {
+                if !inherited.contains(&problem) {
+                    problems.push(format!("Listener '{}': {}", profile.name, problem));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Every problem the proxy would run into on starting with this configuration, without
+    /// binding any sockets: those `validate` finds, backends that don't resolve, TLS
+    /// certificates and keys that don't load, and providers that can't be set up
+    pub fn dry_run(&self) -> Vec<String> {
+        let mut problems = self.validate();
+        // backends are resolved by the upstream proxy, if there is one
+        let mut names = match self.upstream {
+            Some(ref upstream) => vec![upstream.addr.clone()],
+            None => self.backends(),
+        };
+        names.extend(self.x_protocol.iter().flat_map(|x| x.backends.iter().cloned()));
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|&(name, _)| name);
+        for (name, group) in groups {
+            match group.discovery.as_ref().filter(|d| d.validate().is_ok()).map(|d| d.lookup()) {
+                Some(Ok(ref found)) if found.is_empty() => problems.push(format!("Routing group '{}': discovery finds no backends", name)),
+                Some(Err(e)) => problems.push(format!("Routing group '{}': discovery failed: {}", name, e)),
+                _ => {},
+            }
+        }
+        for name in names {
+            match (name.host(), name.port()).to_socket_addrs() {
+                Ok(addrs) if addrs.len() > 0 => {},
+                Ok(_) => problems.push(format!("Backend {} resolves to no addresses", name)),
+                Err(e) => problems.push(format!("Backend {} doesn't resolve: {}", name, e)),
+            }
+        }
+        let configs = Some(self).into_iter().chain(self.listeners.iter().map(|l| &l.config));
+        let mut checked_tls = vec![];
+        for config in configs {
+            if let Some(ref tls) = config.tls {
+                if !checked_tls.contains(&tls) {
+                    checked_tls.push(tls);
+                    if let Err(e) = tls.check() {
+                        problems.push(format!("TLS: {}", e));
+                    }
+                }
+            }
+            if let Err(e) = config.auth.as_ref().map(|a| a.authenticator()).transpose() {
+                problems.push(format!("Authentication: {}", e));
+            }
+            if let Err(e) = config.credentials.as_ref().map(|c| c.provider()).transpose() {
+                problems.push(format!("Credentials: {}", e));
+            }
+            if let Some(dir) = config.audit_log.as_ref().and_then(|path| path.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    problems.push(format!("Audit log: no directory {}", dir.display()));
+                }
+            }
+            for dir in config.taps.iter().filter_map(|tap| tap.path.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    problems.push(format!("Tap: no directory {}", dir.display()));
+                }
+            }
+            if let Some(dir) = config.query_log.as_ref().and_then(|query_log| query_log.path.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    problems.push(format!("Query log: no directory {}", dir.display()));
+                }
+            }
+            if let Some(dir) = config.stats.as_ref().and_then(|stats| stats.path.parent()) {
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    problems.push(format!("Statistics: no directory {}", dir.display()));
+                }
+            }
+        }
+        problems.dedup();
+        problems
+    }
+
+    /// How long clients have from the greeting to log in, if there's a limit
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        match self.handshake_timeout_ms {
+            Some(0) => None,
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+
+    /// Every backend in any routing group
+    pub fn backends(&self) -> Vec<BackendAddr> {
+        let mut backends: Vec<BackendAddr> = self.groups.values()
+            .flat_map(|g| g.backends.iter().cloned())
+            .collect();
+        backends.sort_by_key(|b| b.to_string());
+        backends.dedup();
+        backends
+    }
+
+    /// The backend to use for a new session in the given routing group
+    pub fn backend_for_group(&self, group: &str) -> Option<BackendAddr> {
+        self.groups.get(group).and_then(|g| g.backends.first().cloned())
+    }
+}
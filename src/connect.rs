@@ -0,0 +1,204 @@
+//! Connecting to backends by name, over IPv4 or IPv6.
+//!
+//! A backend name may resolve to several addresses of both families. Connection attempts
+//! follow Happy Eyeballs (RFC 8305): addresses are tried in turn, alternating between the
+//! families, and each attempt gets a head start of `CONNECTION_ATTEMPT_DELAY` before the next
+//! one is started alongside it. The first connection to succeed is used, so a backend with a
+//! broken IPv6 route costs a quarter of a second rather than a connect timeout.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use futures::future;
+use futures::sync::oneshot;
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// How long a connection attempt runs on its own before the next address is tried as well
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A backend's host name or IP address, and port
+#[derive(Clone,Debug,PartialEq,Eq,Hash,Deserialize)]
+#[serde(try_from = "String")]
+pub struct BackendAddr {
+    host: String,
+    port: u16,
+}
+
+impl BackendAddr {
+
+    pub fn new(host: &str, port: u16) -> Self {
+        BackendAddr { host: host.to_string(), port }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Resolve the host name on its own thread, so a slow DNS server doesn't stall the
+    /// reactor. IP addresses resolve immediately.
+    pub fn resolve(&self) -> Box<dyn Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Box::new(future::ok(vec![SocketAddr::new(ip, self.port)]));
+        }
+        let (tx, rx) = oneshot::channel();
+        let (host, port) = (self.host.clone(), self.port);
+        thread::spawn(move || {
+            let _ = tx.send((&host[..], port).to_socket_addrs().map(|addrs| addrs.collect()));
+        });
+        Box::new(rx
+            .map_err(|_| Error::other("Resolver thread failed"))
+            .and_then(|r| r))
+    }
+
+    /// The first address the host resolves to, blocking while it's looked up
+    pub fn resolve_blocking(&self) -> io::Result<SocketAddr> {
+        (&self.host[..], self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} resolves to no addresses", self)))
+    }
+}
+
+impl From<SocketAddr> for BackendAddr {
+    fn from(addr: SocketAddr) -> Self {
+        BackendAddr { host: addr.ip().to_string(), port: addr.port() }
+    }
+}
+
+impl FromStr for BackendAddr {
+    type Err = Error;
+
+    /// Parses `host:port`, `1.2.3.4:port` or `[::1]:port`
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid backend address '{}'", s));
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(BackendAddr::from(addr));
+        }
+        let (host, port) = match s.rfind(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(invalid()),
+        };
+        if host.is_empty() || host.contains(':') {
+            return Err(invalid());
+        }
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(BackendAddr::new(host, port))
+    }
+}
+
+impl TryFrom<String> for BackendAddr {
+    type Error = Error;
+
+    fn try_from(s: String) -> io::Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Resolve a backend and connect to the first of its addresses to accept
+pub fn connect_backend(backend: &BackendAddr, handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    let handle = handle.clone();
+    Box::new(backend.resolve().and_then(move |addrs| connect(addrs, &handle)))
+}
+
+/// Connect to the first of `addrs` to accept, trying them in Happy Eyeballs order
+pub fn connect(addrs: Vec<SocketAddr>, handle: &Handle) -> HappyEyeballs {
+    HappyEyeballs {
+        handle: handle.clone(),
+        pending: interleave(addrs).into(),
+        attempts: vec![],
+        delay: None,
+        last_error: None,
+    }
+}
+
+/// Alternate between IPv6 and IPv4 addresses, starting with the family of the first address
+/// and otherwise keeping the resolver's order
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(|a| a.is_ipv6()).unwrap_or(false);
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => {
+                ordered.extend(a);
+                ordered.extend(b);
+            },
+        }
+    }
+}
+
+/// Future of the first successful connection among staggered attempts
+pub struct HappyEyeballs {
+    handle: Handle,
+    pending: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, TcpStreamNew)>,
+    delay: Option<Timeout>,
+    last_error: Option<Error>,
+}
+
+impl Future for HappyEyeballs {
+    type Item = TcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, Error> {
+        loop {
+            // start the next attempt when nothing is in flight, or the last one has had its head start
+            let head_start_over = match self.delay {
+                Some(ref mut delay) => delay.poll()?.is_ready(),
+                None => true,
+            };
+            if self.attempts.is_empty() || head_start_over {
+                if let Some(addr) = self.pending.pop_front() {
+                    debug!("Connecting to {}", addr);
+                    self.attempts.push((addr, TcpStream::connect(&addr, &self.handle)));
+                    self.delay = Some(Timeout::new(CONNECTION_ATTEMPT_DELAY, &self.handle)?);
+                    continue;
+                }
+            }
+
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        let (addr, _) = self.attempts.remove(i);
+                        debug!("Connecting to {} failed: {}", addr, e);
+                        self.last_error = Some(e);
+                    },
+                }
+            }
+            if !self.attempts.is_empty() {
+                return Ok(Async::NotReady);
+            }
+            if self.pending.is_empty() {
+                return Err(self.last_error.take()
+                    .unwrap_or_else(|| Error::new(ErrorKind::NotFound, "No addresses to connect to")));
+            }
+            // every attempt in flight has failed, so don't wait to start the next one
+            self.delay = None;
+        }
+    }
+}
@@ -0,0 +1,653 @@
+//! Credentials the proxy logs in to backends with.
+//!
+//! By default the proxy logs in with the backend password from the user mapping. A
+//! `CredentialProvider` can supply short-lived credentials instead, such as AWS RDS IAM
+//! authentication tokens or Cloud SQL IAM access tokens, so no static database passwords
+//! need to exist. Tokens are sent with the mysql_clear_password plugin, as those backends
+//! expect, so backend connections should be encrypted or tunnelled. A token is only needed to
+//! log in, sessions stay connected after it expires.
+//!
+//! Static passwords can be kept out of the configuration too, in environment variables,
+//! files, HashiCorp Vault (with the `vault` feature) or AWS Secrets Manager (with the
+//! `aws-secrets` feature). When a backend rejects credentials the provider is asked to forget
+//! them and the login is retried once with fresh ones, so rotated secrets are picked up.
+//!
+//! Providers are blocking and are run on a worker thread by the auth module.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use super::authenticator::connect;
+use super::connect::BackendAddr;
+use super::users::UserMapping;
+
+/// How long RDS accepts an IAM authentication token for
+pub const RDS_TOKEN_LIFETIME: Duration = Duration::from_secs(900);
+
+/// The credentials to log in to a backend with
+#[derive(Clone,Debug,PartialEq)]
+pub struct BackendCredentials {
+    pub user: String,
+    pub password: String,
+    /// send the password with mysql_clear_password rather than mysql_native_password
+    pub cleartext: bool,
+    /// when the password stops being accepted, `None` if it doesn't expire
+    pub expires: Option<SystemTime>,
+}
+
+impl BackendCredentials {
+
+    /// The static credentials in a user mapping
+    pub fn mapped(mapping: &UserMapping) -> Self {
+        BackendCredentials {
+            user: mapping.backend_user.clone(),
+            password: mapping.backend_password.clone(),
+            cleartext: false,
+            expires: None,
+        }
+    }
+}
+
+/// Supplies the credentials for logging in to a backend as a mapped user
+pub trait CredentialProvider: Send + Sync {
+    fn credentials(&self, mapping: &UserMapping, backend: &BackendAddr) -> Result<BackendCredentials>;
+
+    /// Forget any credentials kept for `mapping` on `backend`, after the backend rejected them
+    fn invalidate(&self, _mapping: &UserMapping, _backend: &BackendAddr) {}
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum CredentialsConfig {
+    /// AWS RDS IAM authentication tokens, signed with the `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and optional `AWS_SESSION_TOKEN` environment variables
+    RdsIam { region: String },
+    /// Cloud SQL IAM database authentication, with the instance service account's access
+    /// tokens from the GCE metadata server
+    GcpMetadata,
+    /// the first line a command prints, e.g. `["gcloud", "sql", "generate-login-token"]`.
+    /// The backend is passed in `MYSQL_PROXY_BACKEND_HOST`, `MYSQL_PROXY_BACKEND_PORT` and
+    /// `MYSQL_PROXY_BACKEND_USER`.
+    Command {
+        command: Vec<String>,
+        /// how long the printed password is valid for
+        #[serde(default = "CredentialsConfig::default_ttl_secs")]
+        ttl_secs: u64,
+    },
+    /// the password in an environment variable, `{user}` in `var` is replaced by the backend
+    /// user, e.g. `MYSQL_PASSWORD_{user}`
+    Env { var: String },
+    /// the password in a file, such as a mounted Kubernetes secret, `{user}` in `path` is
+    /// replaced by the backend user
+    File { path: String },
+    /// a secret read from HashiCorp Vault, requires the `vault` feature. `path` is relative
+    /// to `/v1/`, e.g. `secret/data/mysql/{user}` for a KV secret or `database/creds/{user}`
+    /// for dynamic credentials, and has `password` and optionally `username` fields.
+    Vault {
+        addr: String,
+        path: String,
+        /// file holding the Vault token, such as a Vault Agent sink, otherwise `VAULT_TOKEN`
+        #[serde(default)]
+        token_file: Option<PathBuf>,
+    },
+    /// a secret read from AWS Secrets Manager, requires the `aws-secrets` feature. The secret
+    /// is JSON with `password` and optionally `username` fields, as for RDS secrets.
+    AwsSecretsManager { region: String, secret_id: String },
+}
+
+impl CredentialsConfig {
+
+    fn default_ttl_secs() -> u64 {
+        900
+    }
+
+    /// Create the configured provider, reusing credentials until they are about to expire
+    pub fn provider(&self) -> Result<Arc<dyn CredentialProvider>> {
+        let provider: Arc<dyn CredentialProvider> = match *self {
+            CredentialsConfig::RdsIam { ref region } => Arc::new(RdsIamCredentials::from_env(region)?),
+            CredentialsConfig::GcpMetadata => Arc::new(GcpMetadataCredentials::new()),
+            CredentialsConfig::Command { ref command, ttl_secs } => {
+                Arc::new(CommandCredentials::new(command.clone(), Duration::from_secs(ttl_secs))?)
+            },
+            CredentialsConfig::Env { ref var } => Arc::new(EnvCredentials { var: var.clone() }),
+            CredentialsConfig::File { ref path } => Arc::new(FileCredentials { path: path.clone() }),
+            #[cfg(feature = "vault")]
+            CredentialsConfig::Vault { ref addr, ref path, ref token_file } => {
+                Arc::new(VaultCredentials::new(addr, path, token_file.clone()))
+            },
+            #[cfg(not(feature = "vault"))]
+            CredentialsConfig::Vault { .. } => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Vault support requires the 'vault' feature"));
+            },
+            #[cfg(feature = "aws-secrets")]
+            CredentialsConfig::AwsSecretsManager { ref region, ref secret_id } => {
+                Arc::new(AwsSecretsManagerCredentials::from_env(region, secret_id)?)
+            },
+            #[cfg(not(feature = "aws-secrets"))]
+            CredentialsConfig::AwsSecretsManager { .. } => {
+                return Err(Error::new(ErrorKind::InvalidInput, "AWS Secrets Manager support requires the 'aws-secrets' feature"));
+            },
+        };
+        Ok(Arc::new(CachingCredentials::new(provider)))
+    }
+}
+
+impl CredentialProvider for Arc<dyn CredentialProvider> {
+
+    fn credentials(&self, mapping: &UserMapping, backend: &BackendAddr) -> Result<BackendCredentials> {
+        (**self).credentials(mapping, backend)
+    }
+
+    fn invalidate(&self, mapping: &UserMapping, backend: &BackendAddr) {
+        (**self).invalidate(mapping, backend)
+    }
+}
+
+/// Reuses credentials for each backend user and backend until shortly before they expire
+pub struct CachingCredentials<P: CredentialProvider> {
+    inner: P,
+    /// how long before expiry credentials are replaced
+    refresh_before: Duration,
+    cache: Mutex<HashMap<(String, String), BackendCredentials>>,
+}
+
+impl<P> CachingCredentials<P> where P: CredentialProvider {
+
+    pub fn new(inner: P) -> Self {
+        CachingCredentials { inner, refresh_before: Duration::from_secs(60), cache: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn with_refresh_before(mut self, refresh_before: Duration) -> Self {
+        self.refresh_before = refresh_before;
+        self
+    }
+}
+
+impl<P> CredentialProvider for CachingCredentials<P> where P: CredentialProvider {
+
+    fn credentials(&self, mapping: &UserMapping, backend: &BackendAddr) -> Result<BackendCredentials> {
+        let key = (mapping.backend_user.clone(), backend.to_string());
+        let now = SystemTime::now();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.expires.map(|expires| now + self.refresh_before < expires).unwrap_or(true) {
+                return Ok(cached.clone());
+            }
+        }
+        let credentials = self.inner.credentials(mapping, backend)?;
+        debug!("Refreshed credentials for '{}' on {}", credentials.user, backend);
+        self.cache.lock().unwrap().insert(key, credentials.clone());
+        Ok(credentials)
+    }
+
+    fn invalidate(&self, mapping: &UserMapping, backend: &BackendAddr) {
+        self.cache.lock().unwrap().remove(&(mapping.backend_user.clone(), backend.to_string()));
+        self.inner.invalidate(mapping, backend);
+    }
+}
+
+/// Generates AWS RDS IAM authentication tokens
+pub struct RdsIamCredentials {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl RdsIamCredentials {
+
+    pub fn from_env(region: &str) -> Result<Self> {
+        let var = |name: &str| env::var(name)
+            .map_err(|_| Error::new(ErrorKind::NotFound, format!("{} is not set", name)));
+        Ok(RdsIamCredentials {
+            region: region.to_string(),
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// The token for logging in to `backend` as `user`, as `aws rds generate-db-auth-token`
+    /// makes it: a SigV4 presigned `connect` request, without its scheme
+    pub fn token(&self, backend: &BackendAddr, user: &str, now: SystemTime) -> String {
+        let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let amz_date = format_amz_date(secs);
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/rds-db/aws4_request", date, self.region);
+        let host = format!("{}:{}", backend.host(), backend.port());
+
+        let mut query = vec![
+            ("Action", "connect".to_string()),
+            ("DBUser", user.to_string()),
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            ("X-Amz-Credential", format!("{}/{}", self.access_key_id, scope)),
+            ("X-Amz-Date", amz_date.clone()),
+            ("X-Amz-Expires", RDS_TOKEN_LIFETIME.as_secs().to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ];
+        if let Some(ref token) = self.session_token {
+            query.push(("X-Amz-Security-Token", token.clone()));
+        }
+        query.sort();
+        let query = query.iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k), uri_encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical_request = format!("GET\n/\n{}\nhost:{}\n\nhost\n{}", query, host, hex(&Sha256::digest(b"")));
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+        let key = signing_key(&self.secret_access_key, date, &self.region, "rds-db");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        format!("{}/?{}&X-Amz-Signature={}", host, query, signature)
+    }
+}
+
+impl CredentialProvider for RdsIamCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, backend: &BackendAddr) -> Result<BackendCredentials> {
+        let now = SystemTime::now();
+        Ok(BackendCredentials {
+            user: mapping.backend_user.clone(),
+            password: self.token(backend, &mapping.backend_user, now),
+            cleartext: true,
+            expires: Some(now + RDS_TOKEN_LIFETIME),
+        })
+    }
+}
+
+/// Fetches OAuth2 access tokens for Cloud SQL IAM database authentication from the GCE
+/// metadata server
+pub struct GcpMetadataCredentials {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+impl Default for GcpMetadataCredentials {
+    fn default() -> Self {
+        GcpMetadataCredentials::new()
+    }
+}
+
+impl GcpMetadataCredentials {
+
+    pub fn new() -> Self {
+        GcpMetadataCredentials {
+            url: "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token".to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl CredentialProvider for GcpMetadataCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, _backend: &BackendAddr) -> Result<BackendCredentials> {
+        let token = http_json("GET", &self.url, &[("Metadata-Flavor", "Google".to_string())], "", self.timeout)
+            .map_err(|e| Error::new(e.kind(), format!("Metadata server: {}", e)))?;
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("Metadata server {}", msg));
+        let access_token = token["access_token"].as_str().ok_or_else(|| invalid("sent no access_token"))?;
+        let expires_in = token["expires_in"].as_u64().unwrap_or(0);
+        Ok(BackendCredentials {
+            user: mapping.backend_user.clone(),
+            password: access_token.to_string(),
+            cleartext: true,
+            expires: Some(SystemTime::now() + Duration::from_secs(expires_in)),
+        })
+    }
+}
+
+/// Runs a command and logs in with the first line it prints
+pub struct CommandCredentials {
+    pub command: Vec<String>,
+    pub ttl: Duration,
+}
+
+impl CommandCredentials {
+
+    pub fn new(command: Vec<String>, ttl: Duration) -> Result<Self> {
+        if command.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Credentials command is empty"));
+        }
+        Ok(CommandCredentials { command, ttl })
+    }
+}
+
+impl CredentialProvider for CommandCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, backend: &BackendAddr) -> Result<BackendCredentials> {
+        let output = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .env("MYSQL_PROXY_BACKEND_HOST", backend.host())
+            .env("MYSQL_PROXY_BACKEND_PORT", backend.port().to_string())
+            .env("MYSQL_PROXY_BACKEND_USER", &mapping.backend_user)
+            .output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(Error::other(format!("Credentials command failed with {}: {}", output.status, stderr.trim())));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let password = stdout.lines().next().unwrap_or("").trim();
+        if password.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Credentials command printed nothing"));
+        }
+        Ok(BackendCredentials {
+            user: mapping.backend_user.clone(),
+            password: password.to_string(),
+            cleartext: true,
+            expires: Some(SystemTime::now() + self.ttl),
+        })
+    }
+}
+
+/// Reads the password from an environment variable
+pub struct EnvCredentials {
+    /// the variable, with `{user}` standing for the backend user
+    pub var: String,
+}
+
+impl CredentialProvider for EnvCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, _backend: &BackendAddr) -> Result<BackendCredentials> {
+        let var = self.var.replace("{user}", &mapping.backend_user);
+        let password = env::var(&var).map_err(|_| Error::new(ErrorKind::NotFound, format!("{} is not set", var)))?;
+        Ok(BackendCredentials { password, ..BackendCredentials::mapped(mapping) })
+    }
+}
+
+/// Reads the password from a file, without any trailing newline
+pub struct FileCredentials {
+    /// the file, with `{user}` standing for the backend user
+    pub path: String,
+}
+
+impl CredentialProvider for FileCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, _backend: &BackendAddr) -> Result<BackendCredentials> {
+        let path = self.path.replace("{user}", &mapping.backend_user);
+        let password = fs::read_to_string(&path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not read {}: {}", path, e)))?;
+        let password = password.trim_end_matches(['\r', '\n']).to_string();
+        Ok(BackendCredentials { password, ..BackendCredentials::mapped(mapping) })
+    }
+}
+
+/// Reads credentials from HashiCorp Vault
+#[cfg(feature = "vault")]
+pub struct VaultCredentials {
+    /// e.g. `https://vault.internal:8200`
+    pub addr: String,
+    /// the secret's path after `/v1/`, with `{user}` standing for the backend user
+    pub path: String,
+    pub token_file: Option<PathBuf>,
+    pub timeout: Duration,
+}
+
+#[cfg(feature = "vault")]
+impl VaultCredentials {
+
+    pub fn new(addr: &str, path: &str, token_file: Option<PathBuf>) -> Self {
+        VaultCredentials {
+            addr: addr.trim_end_matches('/').to_string(),
+            path: path.trim_start_matches('/').to_string(),
+            token_file,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Read afresh each time, since Vault Agent renews the token in place
+    fn token(&self) -> Result<String> {
+        match self.token_file {
+            Some(ref path) => Ok(fs::read_to_string(path)?.trim().to_string()),
+            None => env::var("VAULT_TOKEN").map_err(|_| Error::new(ErrorKind::NotFound, "VAULT_TOKEN is not set")),
+        }
+    }
+}
+
+#[cfg(feature = "vault")]
+impl CredentialProvider for VaultCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, _backend: &BackendAddr) -> Result<BackendCredentials> {
+        let url = format!("{}/v1/{}", self.addr, self.path.replace("{user}", &mapping.backend_user));
+        let secret = http_json("GET", &url, &[("X-Vault-Token", self.token()?)], "", self.timeout)
+            .map_err(|e| Error::new(e.kind(), format!("Vault: {}", e)))?;
+        // KV version 2 nests the secret's fields one level deeper
+        let data = if secret["data"]["data"].is_object() { &secret["data"]["data"] } else { &secret["data"] };
+        let mut credentials = secret_credentials(mapping, data)?;
+        match secret["lease_duration"].as_u64() {
+            Some(lease) if lease > 0 => credentials.expires = Some(SystemTime::now() + Duration::from_secs(lease)),
+            _ => {},
+        }
+        Ok(credentials)
+    }
+}
+
+/// Reads credentials from AWS Secrets Manager
+#[cfg(feature = "aws-secrets")]
+pub struct AwsSecretsManagerCredentials {
+    pub region: String,
+    /// the secret's name or ARN, with `{user}` standing for the backend user
+    pub secret_id: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    pub timeout: Duration,
+}
+
+#[cfg(feature = "aws-secrets")]
+impl AwsSecretsManagerCredentials {
+
+    /// Sign requests with the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optional
+    /// `AWS_SESSION_TOKEN` environment variables
+    pub fn from_env(region: &str, secret_id: &str) -> Result<Self> {
+        let signer = RdsIamCredentials::from_env(region)?;
+        Ok(AwsSecretsManagerCredentials {
+            region: region.to_string(),
+            secret_id: secret_id.to_string(),
+            access_key_id: signer.access_key_id,
+            secret_access_key: signer.secret_access_key,
+            session_token: signer.session_token,
+            timeout: Duration::from_secs(5),
+        })
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+impl CredentialProvider for AwsSecretsManagerCredentials {
+
+    fn credentials(&self, mapping: &UserMapping, _backend: &BackendAddr) -> Result<BackendCredentials> {
+        let host = format!("secretsmanager.{}.amazonaws.com", self.region);
+        let body = json!({ "SecretId": self.secret_id.replace("{user}", &mapping.backend_user) }).to_string();
+        let amz_date = format_amz_date(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/secretsmanager/aws4_request", date, self.region);
+
+        // SigV4 signed headers, in order
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(ref token) = self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", "secretsmanager.GetSecretValue".to_string()));
+        let signed_headers = headers.iter().map(|h| h.0).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect();
+        let canonical_request = format!("POST\n/\n\n{}\n{}\n{}",
+                                        canonical_headers, signed_headers, hex(&Sha256::digest(body.as_bytes())));
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+        let key = signing_key(&self.secret_access_key, date, &self.region, "secretsmanager");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        headers.push(("authorization", format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                                               self.access_key_id, scope, signed_headers, signature)));
+        headers.retain(|h| h.0 != "host");
+
+        let response = http_json("POST", &format!("https://{}/", host), &headers, &body, self.timeout)
+            .map_err(|e| Error::new(e.kind(), format!("Secrets Manager: {}", e)))?;
+        let secret = response["SecretString"].as_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Secrets Manager secret has no SecretString"))?;
+        let secret: serde_json::Value = serde_json::from_str(secret)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Secrets Manager secret isn't JSON: {}", e)))?;
+        secret_credentials(mapping, &secret)
+    }
+}
+
+/// Credentials from a secret's `password` and optional `username` fields
+#[cfg(any(feature = "vault", feature = "aws-secrets"))]
+fn secret_credentials(mapping: &UserMapping, secret: &serde_json::Value) -> Result<BackendCredentials> {
+    let password = secret["password"].as_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Secret has no password field"))?;
+    Ok(BackendCredentials {
+        user: secret["username"].as_str().unwrap_or(&mapping.backend_user).to_string(),
+        password: password.to_string(),
+        ..BackendCredentials::mapped(mapping)
+    })
+}
+
+/// A blocking HTTP/1.0 request with a JSON response, which must have a 2xx status. `https`
+/// URLs are supported with the `vault` or `aws-secrets` features.
+pub fn http_json(method: &str,
+                 url: &str,
+                 headers: &[(&str, String)],
+                 body: &str,
+                 timeout: Duration) -> Result<serde_json::Value> {
+    let (https, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Unsupported URL '{}'", url))),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = match (host.rsplit_once(':'), https) {
+        (Some((_, port)), _) if !port.ends_with(']') => host.to_string(),
+        (_, true) => format!("{}:443", host),
+        (_, false) => format!("{}:80", host),
+    };
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let stream = connect(&host, timeout)?;
+    let response = if https { https_exchange(stream, &host, &request)? } else { http_exchange(stream, &request)? };
+
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+    let status = response.split_whitespace().nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| invalid("Invalid HTTP response".to_string()))?;
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("");
+    if !(200..300).contains(&status) {
+        let kind = if status == 401 || status == 403 { ErrorKind::PermissionDenied } else { ErrorKind::Other };
+        return Err(Error::new(kind, format!("HTTP {}: {}", status, body.trim())));
+    }
+    serde_json::from_str(body).map_err(|e| invalid(format!("Invalid JSON response: {}", e)))
+}
+
+fn http_exchange(mut stream: std::net::TcpStream, request: &str) -> Result<String> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(any(feature = "vault", feature = "aws-secrets"))]
+fn https_exchange(stream: std::net::TcpStream, host: &str, request: &str) -> Result<String> {
+    use std::convert::TryFrom;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host).trim_matches(|c| c == '[' || c == ']');
+    let name = rustls::pki_types::ServerName::try_from(name.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let connection = rustls::ClientConnection::new(Arc::new(config), name)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let mut tls = rustls::StreamOwned::new(connection, stream);
+    tls.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    match tls.read_to_end(&mut response) {
+        Ok(_) => {},
+        // servers that close without close_notify have still sent the whole response
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => {},
+        Err(e) => return Err(e),
+    }
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+#[cfg(not(any(feature = "vault", feature = "aws-secrets")))]
+fn https_exchange(_stream: std::net::TcpStream, _host: &str, _request: &str) -> Result<String> {
+    Err(Error::new(ErrorKind::Unsupported, "HTTPS requires the 'vault' or 'aws-secrets' feature"))
+}
+
+/// The SigV4 key for signing requests to `service` in `region` on `date`
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    key
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0_u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encode everything but the unreserved characters, as SigV4 requires
+fn uri_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'` for seconds since the epoch
+fn format_amz_date(secs: u64) -> String {
+    // civil date from days since the epoch, after Howard Hinnant's algorithm
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let t = secs % 86400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, t / 3600, t / 60 % 60, t % 60)
+}
@@ -0,0 +1,611 @@
+//! Discovering a routing group's backends from a registry.
+//!
+//! A group with `discovery` looks its backends up every `interval_secs` through a
+//! `ServiceDiscovery`, one of:
+//!
+//! - `host`, every address of a host name, such as a Kubernetes headless service selecting
+//!   database pods by label
+//! - `srv`, a DNS SRV record. Backends get their SRV weight, and records of a lower priority
+//!   than the best get 0, as standbys.
+//! - `consul`, a Consul service's instances that pass their health checks, with their
+//!   passing weight. Requires the `consul` feature.
+//! - `etcd`, the backends registered under a key prefix in etcd, see `EtcdConfig`. Requires
+//!   the `etcd` feature.
+//! - `group_replication`, the online members of a MySQL Group Replication or InnoDB Cluster
+//!   group, optionally only its primary or its secondaries, as one of its `seeds` lists them
+//!   in `performance_schema`.
+//!
+//! Backends found get a weight of 1 unless the registry gives them one, or the management
+//! API sets them another. When a lookup fails, the group keeps the backends it had.
+
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::balance::{BackendPool, BackendWeights};
+use super::connect::BackendAddr;
+use super::sidechannel::{SideChannel, SideChannelConfig};
+use super::topology;
+#[cfg(any(feature = "consul", feature = "etcd"))]
+use super::credentials::http_json;
+
+/// How long the DNS server has to answer an SRV query
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long Consul and etcd have to answer
+#[cfg(any(feature = "consul", feature = "etcd"))]
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS record type SRV
+const TYPE_SRV: u16 = 33;
+
+/// DNS class IN
+const CLASS_IN: u16 = 1;
+
+/// A registry of backends
+pub trait ServiceDiscovery: Send {
+    /// The backends the registry has now, with their weights, leaving out any it knows are
+    /// unhealthy
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>>;
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct DiscoveryConfig {
+    /// a host name whose addresses are the backends, e.g. `mysql.db.svc.cluster.local`
+    #[serde(default)]
+    pub host: Option<String>,
+    /// the port of backends found by `host`
+    #[serde(default = "DiscoveryConfig::default_port")]
+    pub port: u16,
+    /// an SRV record listing the backends, e.g. `_mysql._tcp.mysql.db.svc.cluster.local`
+    #[serde(default)]
+    pub srv: Option<String>,
+    #[serde(default)]
+    pub consul: Option<ConsulConfig>,
+    #[serde(default)]
+    pub etcd: Option<EtcdConfig>,
+    #[serde(default)]
+    pub group_replication: Option<GroupReplicationConfig>,
+    /// time between lookups
+    #[serde(default = "DiscoveryConfig::default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl DiscoveryConfig {
+
+    fn default_port() -> u16 {
+        3306
+    }
+
+    fn default_interval_secs() -> u64 {
+        10
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let sources = [self.host.is_some(), self.srv.is_some(), self.consul.is_some(), self.etcd.is_some(),
+                       self.group_replication.is_some()];
+        if sources.iter().filter(|&&source| source).count() != 1 {
+            return Err("discovery needs one of host, srv, consul, etcd or group_replication".to_string());
+        }
+        if self.group_replication.as_ref().map(|g| g.seeds.is_empty()).unwrap_or(false) {
+            return Err("group_replication needs at least one seed".to_string());
+        }
+        match (&self.host, &self.srv) {
+            (Some(name), _) | (_, Some(name)) if name.is_empty() => Err("discovery needs a host or srv name".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The registry the configuration names
+    pub fn discovery(&self) -> io::Result<Box<dyn ServiceDiscovery>> {
+        if let Some(ref host) = self.host {
+            return Ok(Box::new(HostDiscovery { host: host.clone(), port: self.port }));
+        }
+        if let Some(ref srv) = self.srv {
+            return Ok(Box::new(SrvDiscovery { name: srv.clone() }));
+        }
+        if let Some(ref consul) = self.consul {
+            return consul_discovery(consul);
+        }
+        if let Some(ref group) = self.group_replication {
+            return Ok(Box::new(GroupReplicationDiscovery::new(group)));
+        }
+        match self.etcd {
+            Some(ref etcd) => etcd_discovery(etcd),
+            None => Err(Error::new(ErrorKind::InvalidInput, "No discovery configured")),
+        }
+    }
+
+    /// Look the backends up once, with their weights
+    pub fn lookup(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        self.discovery()?.discover()
+    }
+}
+
+/// Look up a group's backends now and then every `config.interval_secs`, on a thread of its
+/// own
+pub fn run_in_thread(group: &str, config: &DiscoveryConfig, pool: BackendPool, weights: BackendWeights) -> io::Result<()> {
+    let discovery = config.discovery()?;
+    let discover = move |group: &str| match discovery.discover() {
+        Ok(found) => {
+            for &(ref backend, weight) in &found {
+                weights.set_default(backend, weight);
+            }
+            let backends: Vec<BackendAddr> = found.into_iter().map(|(backend, _)| backend).collect();
+            let names: Vec<String> = backends.iter().map(|b| b.to_string()).collect();
+            if pool.set(group, backends) {
+                info!("Routing group '{}' has backends: {}", group, names.join(", "));
+            }
+        },
+        Err(e) => warn!("Failed to discover backends for routing group '{}': {}", group, e),
+    };
+    // find the backends before any session needs them
+    discover(group);
+    let group = group.to_string();
+    let interval = Duration::from_secs(config.interval_secs.max(1));
+    thread::Builder::new().name(format!("mysql-proxy-discovery-{}", group)).spawn(move || loop {
+        thread::sleep(interval);
+        discover(&group);
+    })?;
+    Ok(())
+}
+
+/// Every address of a host name, on one port
+pub struct HostDiscovery {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ServiceDiscovery for HostDiscovery {
+
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        let backends = (&self.host[..], self.port).to_socket_addrs()?.map(BackendAddr::from).collect();
+        Ok(sorted(backends).into_iter().map(|b| (b, 1)).collect())
+    }
+}
+
+/// The targets of a DNS SRV record
+pub struct SrvDiscovery {
+    pub name: String,
+}
+
+impl ServiceDiscovery for SrvDiscovery {
+
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        let records = lookup_srv(&self.name)?;
+        let best = records.iter().map(|r| r.priority).min();
+        Ok(records.into_iter().map(|r| {
+            let weight = if Some(r.priority) == best { r.weight.max(1) as u32 } else { 0 };
+            (BackendAddr::new(&r.target, r.port), weight)
+        }).collect())
+    }
+}
+
+/// Which members of a replication group are backends
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    #[default]
+    Any,
+    Primary,
+    Secondary,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct GroupReplicationConfig {
+    /// members to ask for the group's members, in turn until one answers
+    pub seeds: Vec<BackendAddr>,
+    /// an account that may read `performance_schema`
+    pub user: String,
+    pub password: String,
+    #[serde(default)]
+    pub role: MemberRole,
+    /// how long each seed has to answer
+    #[serde(default = "GroupReplicationConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl GroupReplicationConfig {
+
+    fn default_timeout_ms() -> u64 {
+        2000
+    }
+}
+
+/// The online members of a replication group, in the role asked for
+pub struct GroupReplicationDiscovery {
+    seeds: Vec<BackendAddr>,
+    role: MemberRole,
+    channel: SideChannel,
+}
+
+impl GroupReplicationDiscovery {
+
+    pub fn new(config: &GroupReplicationConfig) -> Self {
+        let channel = SideChannel::new(&SideChannelConfig {
+            backend_user: config.user.clone(),
+            backend_password: config.password.clone(),
+            timeout_ms: config.timeout_ms.max(1),
+            max_concurrent: 1,
+            max_idle: 1,
+            max_rows: 1000,
+            max_lifetime_secs: None,
+            max_idle_secs: None,
+        });
+        GroupReplicationDiscovery { seeds: config.seeds.clone(), role: config.role, channel }
+    }
+}
+
+impl ServiceDiscovery for GroupReplicationDiscovery {
+
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        let mut last = Error::new(ErrorKind::NotFound, "No seeds to ask");
+        for seed in &self.seeds {
+            let members = match seed.resolve_blocking().and_then(|addr| topology::group_members(&self.channel, addr)) {
+                Ok(members) => members,
+                Err(e) => {
+                    last = e;
+                    continue;
+                },
+            };
+            // a member that has left the group, or is still joining it, sees no one online
+            if !members.iter().any(|m| m.is_online()) {
+                last = Error::new(ErrorKind::NotFound, format!("{} sees no online members of its group", seed));
+                continue;
+            }
+            let backends = members.iter()
+                .filter(|m| m.is_online())
+                .filter(|m| match self.role {
+                    MemberRole::Any => true,
+                    MemberRole::Primary => m.is_primary(),
+                    MemberRole::Secondary => !m.is_primary(),
+                })
+                .map(|m| BackendAddr::new(&m.host, m.port))
+                .collect();
+            return Ok(sorted(backends).into_iter().map(|b| (b, 1)).collect());
+        }
+        Err(last)
+    }
+}
+
+fn sorted(mut backends: Vec<BackendAddr>) -> Vec<BackendAddr> {
+    backends.sort_by_key(|b| b.to_string());
+    backends.dedup();
+    backends
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ConsulConfig {
+    /// the Consul agent's HTTP API
+    #[serde(default = "ConsulConfig::default_addr")]
+    pub addr: String,
+    pub service: String,
+    /// only instances with this tag, e.g. `primary`
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    /// ACL token, otherwise `$CONSUL_HTTP_TOKEN` if set
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl ConsulConfig {
+
+    fn default_addr() -> String {
+        "http://127.0.0.1:8500".to_string()
+    }
+}
+
+/// A Consul service's instances that pass their health checks
+#[cfg(feature = "consul")]
+pub struct ConsulDiscovery {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+#[cfg(feature = "consul")]
+impl ConsulDiscovery {
+
+    pub fn new(config: &ConsulConfig) -> Self {
+        let mut url = format!("{}/v1/health/service/{}?passing=true", config.addr.trim_end_matches('/'), config.service);
+        if let Some(ref tag) = config.tag {
+            url.push_str(&format!("&tag={}", tag));
+        }
+        if let Some(ref datacenter) = config.datacenter {
+            url.push_str(&format!("&dc={}", datacenter));
+        }
+        let token = config.token.clone().or_else(|| ::std::env::var("CONSUL_HTTP_TOKEN").ok());
+        ConsulDiscovery { url, token }
+    }
+}
+
+#[cfg(feature = "consul")]
+impl ServiceDiscovery for ConsulDiscovery {
+
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        let headers: Vec<(&str, String)> = self.token.iter().map(|t| ("X-Consul-Token", t.clone())).collect();
+        let entries = http_json("GET", &self.url, &headers, "", REGISTRY_TIMEOUT)
+            .map_err(|e| Error::new(e.kind(), format!("Consul: {}", e)))?;
+        let entries = entries.as_array().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Consul: expected a list of instances"))?;
+        let mut found = vec![];
+        for entry in entries {
+            // instances without an address of their own are reached at their node's
+            let service = &entry["Service"];
+            let host = match service["Address"].as_str() {
+                Some(address) if !address.is_empty() => address,
+                _ => entry["Node"]["Address"].as_str().unwrap_or_default(),
+            };
+            let port = service["Port"].as_u64().unwrap_or(0);
+            if host.is_empty() || port == 0 || port > u16::MAX as u64 {
+                continue;
+            }
+            let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1) as u32;
+            found.push((BackendAddr::new(host, port as u16), weight));
+        }
+        found.sort_by_key(|(b, _)| b.to_string());
+        found.dedup_by(|a, b| a.0 == b.0);
+        Ok(found)
+    }
+}
+
+#[cfg(feature = "consul")]
+fn consul_discovery(config: &ConsulConfig) -> io::Result<Box<dyn ServiceDiscovery>> {
+    Ok(Box::new(ConsulDiscovery::new(config)))
+}
+
+#[cfg(not(feature = "consul"))]
+fn consul_discovery(_: &ConsulConfig) -> io::Result<Box<dyn ServiceDiscovery>> {
+    Err(Error::new(ErrorKind::InvalidInput, "Consul discovery requires the 'consul' feature"))
+}
+
+/// Backends registered in etcd, one key each under `prefix`, with a value of either
+/// `host:port` or `{"address": "host:port", "weight": 2, "healthy": true}`. Registering
+/// backends with a lease that their health checks keep alive removes them when they fail;
+/// backends with `"healthy": false` are left out.
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct EtcdConfig {
+    /// the etcd v3 HTTP API
+    #[serde(default = "EtcdConfig::default_addr")]
+    pub addr: String,
+    /// e.g. `/services/mysql/`
+    pub prefix: String,
+}
+
+impl EtcdConfig {
+
+    fn default_addr() -> String {
+        "http://127.0.0.1:2379".to_string()
+    }
+}
+
+/// The backends registered under a key prefix in etcd
+#[cfg(feature = "etcd")]
+pub struct EtcdDiscovery {
+    pub url: String,
+    pub prefix: String,
+}
+
+#[cfg(feature = "etcd")]
+impl ServiceDiscovery for EtcdDiscovery {
+
+    fn discover(&self) -> io::Result<Vec<(BackendAddr, u32)>> {
+        use super::upstream::base64;
+
+        // every key from the prefix up to the prefix with its last byte incremented
+        let mut range_end = self.prefix.as_bytes().to_vec();
+        while range_end.last() == Some(&0xff) {
+            range_end.pop();
+        }
+        match range_end.last_mut() {
+            Some(last) => *last += 1,
+            None => range_end.push(0),
+        }
+        let body = json!({"key": base64(self.prefix.as_bytes()), "range_end": base64(&range_end)}).to_string();
+        let response = http_json("POST", &self.url, &[("Content-Type", "application/json".to_string())], &body, REGISTRY_TIMEOUT)
+            .map_err(|e| Error::new(e.kind(), format!("etcd: {}", e)))?;
+        let mut found = vec![];
+        for kv in response["kvs"].as_array().map(|kvs| &kvs[..]).unwrap_or_default() {
+            let key = kv["key"].as_str().and_then(base64_decode).map(|k| String::from_utf8_lossy(&k).into_owned()).unwrap_or_default();
+            let value = kv["value"].as_str().and_then(base64_decode).unwrap_or_default();
+            match etcd_backend(&value) {
+                Ok(Some(backend)) => found.push(backend),
+                Ok(None) => {},
+                Err(e) => warn!("Ignoring etcd key '{}': {}", key, e),
+            }
+        }
+        found.sort_by_key(|(b, _)| b.to_string());
+        found.dedup_by(|a, b| a.0 == b.0);
+        Ok(found)
+    }
+}
+
+/// The backend in a registration, or `None` if it's unhealthy
+#[cfg(feature = "etcd")]
+fn etcd_backend(value: &[u8]) -> io::Result<Option<(BackendAddr, u32)>> {
+    let value = String::from_utf8_lossy(value);
+    let value = value.trim();
+    if !value.starts_with('{') {
+        return Ok(Some((value.parse()?, 1)));
+    }
+    let registration: serde_json::Value = serde_json::from_str(value).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    if registration["healthy"].as_bool() == Some(false) {
+        return Ok(None);
+    }
+    let address = registration["address"].as_str().ok_or_else(|| Error::new(ErrorKind::InvalidData, "no address"))?;
+    let weight = registration["weight"].as_u64().unwrap_or(1) as u32;
+    Ok(Some((address.parse()?, weight)))
+}
+
+#[cfg(feature = "etcd")]
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let (mut bits, mut n) = (0_u32, 0);
+    for c in s.bytes().filter(|&c| c != b'=') {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | v as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(feature = "etcd")]
+fn etcd_discovery(config: &EtcdConfig) -> io::Result<Box<dyn ServiceDiscovery>> {
+    Ok(Box::new(EtcdDiscovery {
+        url: format!("{}/v3/kv/range", config.addr.trim_end_matches('/')),
+        prefix: config.prefix.clone(),
+    }))
+}
+
+#[cfg(not(feature = "etcd"))]
+fn etcd_discovery(_: &EtcdConfig) -> io::Result<Box<dyn ServiceDiscovery>> {
+    Err(Error::new(ErrorKind::InvalidInput, "etcd discovery requires the 'etcd' feature"))
+}
+
+/// An SRV record's target
+#[derive(Clone,Debug,PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Ask the first name server in `/etc/resolv.conf` for the SRV records of `name`
+pub fn lookup_srv(name: &str) -> io::Result<Vec<SrvRecord>> {
+    let server = nameserver();
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(DNS_TIMEOUT))?;
+    socket.connect(server)?;
+    let id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0);
+    socket.send(&srv_query(id, name)?)?;
+    let mut buf = [0_u8; 4096];
+    loop {
+        let n = socket.recv(&mut buf)?;
+        // ignore late answers to earlier queries
+        if n >= 2 && BigEndian::read_u16(&buf) == id {
+            return parse_srv_response(id, &buf[..n]);
+        }
+    }
+}
+
+fn nameserver() -> SocketAddr {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let ip = conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => words.next().and_then(|ip| ip.parse::<IpAddr>().ok()),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or_else(|| IpAddr::from([127, 0, 0, 1]));
+    SocketAddr::new(ip, 53)
+}
+
+/// A recursive query for the SRV records of `name`
+pub fn srv_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = vec![0_u8; 12];
+    BigEndian::write_u16(&mut query[0..], id);
+    BigEndian::write_u16(&mut query[2..], 0x0100); // recursion desired
+    BigEndian::write_u16(&mut query[4..], 1);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid DNS name '{}'", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&[0, TYPE_SRV as u8, 0, CLASS_IN as u8]);
+    Ok(query)
+}
+
+/// The SRV records in the answer to query `id`, ordered by priority, then by weight, heaviest
+/// first
+pub fn parse_srv_response(id: u16, msg: &[u8]) -> io::Result<Vec<SrvRecord>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid DNS response");
+    if msg.len() < 12 || BigEndian::read_u16(msg) != id {
+        return Err(invalid());
+    }
+    let flags = BigEndian::read_u16(&msg[2..]);
+    if flags & 0x8000 == 0 {
+        return Err(invalid());
+    }
+    if flags & 0x0200 != 0 {
+        return Err(Error::other("DNS response truncated"));
+    }
+    match flags & 0x000f {
+        0 => {},
+        3 => return Err(Error::new(ErrorKind::NotFound, "No such DNS name")),
+        rcode => return Err(Error::other(format!("DNS server failed with response code {}", rcode))),
+    }
+    let (questions, answers) = (BigEndian::read_u16(&msg[4..]), BigEndian::read_u16(&msg[6..]));
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let header = msg.get(pos..pos + 10).ok_or_else(invalid)?;
+        let (record_type, class, len) = (BigEndian::read_u16(header), BigEndian::read_u16(&header[2..]), BigEndian::read_u16(&header[8..]) as usize);
+        let data_pos = pos + 10;
+        let data = msg.get(data_pos..data_pos + len).ok_or_else(invalid)?;
+        if record_type == TYPE_SRV && class == CLASS_IN && len > 6 {
+            records.push(SrvRecord {
+                priority: BigEndian::read_u16(data),
+                weight: BigEndian::read_u16(&data[2..]),
+                port: BigEndian::read_u16(&data[4..]),
+                target: read_name(msg, data_pos + 6)?.0,
+            });
+        }
+        pos = data_pos + len;
+    }
+    records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)).then_with(|| a.target.cmp(&b.target)));
+    Ok(records)
+}
+
+/// The name at `pos`, following compression pointers, and the position after it
+fn read_name(msg: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid name in DNS response");
+    let mut labels = vec![];
+    let mut end = None;
+    // a name can't take more steps than the message has bytes, unless pointers loop
+    for _ in 0..msg.len() {
+        let len = *msg.get(pos).ok_or_else(invalid)? as usize;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+            },
+            len if len & 0xc0 == 0xc0 => {
+                let offset = BigEndian::read_u16(msg.get(pos..pos + 2).ok_or_else(invalid)?) as usize & 0x3fff;
+                end = end.or(Some(pos + 2));
+                pos = offset;
+            },
+            len if len <= 63 => {
+                let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(invalid)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Err(invalid())
+}
@@ -0,0 +1,236 @@
+//! Structured packet dumps for debugging.
+//!
+//! Wrap a connection's handler in a `DumpHandler` and every packet passing through it is
+//! described as a line of JSON, with its direction, sequence id, inferred type, a decoded
+//! summary and the start of its payload in hex. Dumping can be switched on and off at
+//! runtime with the shared `PacketDumper`.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::{ErrPacket, HandshakeResponse, HandshakeV10, OkPacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+
+/// How many payload bytes are included in a dump
+pub const HEX_EXCERPT_LEN: usize = 64;
+
+/// How many characters of a statement are included in a summary
+const SUMMARY_LEN: usize = 256;
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// client to server
+    Request,
+    /// server to client
+    Response,
+}
+
+/// A single dumped packet
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct PacketDump {
+    pub timestamp_ms: u64,
+    pub connection: String,
+    pub direction: Direction,
+    pub seq: u8,
+    pub length: usize,
+    #[serde(rename = "type")]
+    pub packet_type: String,
+    pub summary: Option<String>,
+    pub hex: String,
+    /// whether `hex` only covers the start of the payload
+    pub truncated: bool,
+}
+
+impl PacketDump {
+
+    /// Describe a packet, given the phase of the connection it was seen in
+    pub fn new(connection: &str, direction: Direction, phase: ConnectionPhase, p: &Packet) -> Self {
+        let payload = p.payload();
+        let (packet_type, summary) = describe(direction, phase, p);
+        let excerpt = &payload[..payload.len().min(HEX_EXCERPT_LEN)];
+        PacketDump {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            connection: connection.to_string(),
+            direction,
+            seq: p.sequence_id(),
+            length: payload.len(),
+            packet_type,
+            summary,
+            hex: excerpt.iter().map(|b| format!("{:02x}", b)).collect(),
+            truncated: excerpt.len() < payload.len(),
+        }
+    }
+
+    /// The packet that was dumped, if `hex` holds its whole payload
+    pub fn packet(&self) -> Option<Packet> {
+        if self.truncated || self.hex.len() != self.length * 2 {
+            return None;
+        }
+        let payload = (0..self.length)
+            .map(|i| u8::from_str_radix(self.hex.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Packet::new(self.seq, &payload))
+    }
+}
+
+fn describe(direction: Direction, phase: ConnectionPhase, p: &Packet) -> (String, Option<String>) {
+    let payload = p.payload();
+    match (direction, phase, payload.first()) {
+        (_, _, None) => ("empty".to_string(), None),
+        (Direction::Request, ConnectionPhase::Handshake, _) => match HandshakeResponse::parse(p) {
+            Ok(r) => ("handshake_response".to_string(), Some(format!("user={}", r.username))),
+            Err(_) => ("auth_data".to_string(), None),
+        },
+        (Direction::Request, ConnectionPhase::Command, Some(_)) => {
+            let arg = || Some(excerpt(payload.get(1..).unwrap_or(&[])));
+            match p.packet_type() {
+                Ok(t @ PacketType::ComQuery) | Ok(t @ PacketType::ComInitDb)
+                | Ok(t @ PacketType::ComStmtPrepare) => (command_name(t), arg()),
+                Ok(PacketType::Unknown(command)) => (format!("unknown(0x{:02x})", command), None),
+                Ok(t) => (command_name(t), None),
+                Err(_) => ("empty".to_string(), None),
+            }
+        },
+        (Direction::Response, _, Some(&0x0a)) if p.sequence_id() == 0 => match HandshakeV10::parse(p) {
+            Ok(g) => ("handshake".to_string(), Some(format!("server_version={}", g.server_version))),
+            Err(_) => ("unknown".to_string(), None),
+        },
+        (Direction::Response, _, Some(&0xff)) => match ErrPacket::parse(p) {
+            Ok(e) => {
+                let state = e.state.map(|s| format!(" ({})", String::from_utf8_lossy(&s))).unwrap_or_default();
+                ("err".to_string(), Some(format!("{}{}: {}", e.code, state, e.message)))
+            },
+            Err(_) => ("err".to_string(), None),
+        },
+        (Direction::Response, _, Some(&0x00)) if payload.len() >= 7 => match OkPacket::parse(p, CLIENT_PROTOCOL_41) {
+            Ok(ok) => {
+                ("ok".to_string(), Some(format!("affected_rows={} last_insert_id={}", ok.affected_rows, ok.last_insert_id)))
+            },
+            Err(_) => ("row".to_string(), None),
+        },
+        (Direction::Response, _, Some(&0xfe)) if payload.len() < 9 => ("eof".to_string(), None),
+        (Direction::Response, ConnectionPhase::Handshake, Some(&0xfe)) => ("auth_switch".to_string(), None),
+        (Direction::Response, ConnectionPhase::Handshake, Some(&0x01)) => ("auth_more_data".to_string(), None),
+        (Direction::Response, ConnectionPhase::Command, Some(_)) => ("row".to_string(), None),
+        (Direction::Response, ConnectionPhase::Handshake, Some(_)) => ("unknown".to_string(), None),
+    }
+}
+
+/// `ComStmtPrepare` becomes `com_stmt_prepare`, to match the other type names
+fn command_name(t: PacketType) -> String {
+    t.as_str().to_ascii_lowercase()
+}
+
+fn excerpt(bytes: &[u8]) -> String {
+    let s = String::from_utf8_lossy(bytes);
+    match s.char_indices().nth(SUMMARY_LEN) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s.into_owned(),
+    }
+}
+
+enum Sink {
+    Writer(Mutex<Box<dyn Write + Send>>),
+    Channel(Mutex<Sender<PacketDump>>),
+}
+
+/// Shared destination for packet dumps, with a switch to turn dumping on and off at runtime.
+/// Dumping starts disabled.
+#[derive(Clone)]
+pub struct PacketDumper {
+    enabled: Arc<AtomicBool>,
+    sink: Arc<Sink>,
+}
+
+impl PacketDumper {
+
+    /// Write dumps as lines of JSON, e.g. to a file or stdout
+    pub fn to_writer(writer: Box<dyn Write + Send>) -> Self {
+        PacketDumper::with_sink(Sink::Writer(Mutex::new(writer)))
+    }
+
+    /// Send dumps to a channel, for tools that want to process them further
+    pub fn to_channel(sender: Sender<PacketDump>) -> Self {
+        PacketDumper::with_sink(Sink::Channel(Mutex::new(sender)))
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        PacketDumper { enabled: Arc::new(AtomicBool::new(false)), sink: Arc::new(sink) }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Dump a packet, if dumping is enabled
+    pub fn dump(&self, connection: &str, direction: Direction, phase: ConnectionPhase, p: &Packet) {
+        if !self.is_enabled() {
+            return;
+        }
+        let dump = PacketDump::new(connection, direction, phase, p);
+        match *self.sink {
+            Sink::Writer(ref writer) => {
+                let line = serde_json::to_string(&dump).expect("packet dump serializes");
+                if let Err(e) = writeln!(writer.lock().unwrap(), "{}", line) {
+                    warn!("Failed to write packet dump: {}", e);
+                }
+            },
+            Sink::Channel(ref sender) => {
+                // nobody listening any more is not an error for the connection
+                let _ = sender.lock().unwrap().send(dump);
+            },
+        }
+    }
+}
+
+/// Wraps another handler and dumps every packet it sees
+pub struct DumpHandler<H: PacketHandler> {
+    dumper: PacketDumper,
+    connection: String,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> DumpHandler<H> where H: PacketHandler {
+
+    /// `connection` labels this connection's packets in the dump, e.g. the client address
+    pub fn new(dumper: PacketDumper, connection: &str, inner: H) -> Self {
+        DumpHandler { dumper, connection: connection.to_string(), phase: PhaseTracker::new(), inner }
+    }
+}
+
+impl<H> PacketHandler for DumpHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        self.dumper.dump(&self.connection, Direction::Request, self.phase.phase(), p);
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        // describe the packet in the phase it was sent in, before it can end the handshake
+        self.dumper.dump(&self.connection, Direction::Response, self.phase.phase(), p);
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
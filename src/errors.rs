@@ -0,0 +1,234 @@
+//! Rewriting the errors backends send before clients see them.
+//!
+//! Backend errors can give away more than clients should know, such as the host names of
+//! internal servers, or mean little to them, such as a login failure on a backend account
+//! the client never heard of. Each of the `[[error_rules]]` matches errors by code, by a
+//! piece of their message or both, and changes their code, SQL state or message, or hides
+//! parts of the message. The first rule matching an error applies. `ErrorRulesHandler`
+//! rewrites the errors answering commands, and `ProxyAuth` those of failed backend logins.
+
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::pipeline::{Correlator, ResponseKind};
+
+/// What replaces the parts of messages a rule hides
+const REDACTED: &str = "***";
+
+/// A change to the backend errors it matches, e.g. `code = 1045` and `message = "Access
+/// denied for {user}"`
+#[derive(Clone,Debug,Deserialize,PartialEq,Serialize)]
+pub struct ErrorRule {
+    /// only errors with this code, otherwise any
+    #[serde(default)]
+    pub code: Option<u16>,
+    /// only errors whose message contains this
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// the code to send instead
+    #[serde(default)]
+    pub new_code: Option<u16>,
+    /// the SQL state to send instead
+    #[serde(default)]
+    pub sql_state: Option<String>,
+    /// the message to send instead, where `{message}` is the backend's, `{code}` its code
+    /// and `{user}` the proxy user
+    #[serde(default)]
+    pub message: Option<String>,
+    /// parts of the backend's message to hide, e.g. host names
+    #[serde(default)]
+    pub redact: Vec<String>,
+}
+
+impl ErrorRule {
+
+    /// Check the rule for mistakes that would keep it from ever matching or changing anything
+    pub fn validate(&self) -> Result<(), String> {
+        if self.contains.as_ref().map(|c| c.is_empty()).unwrap_or(false) {
+            return Err("contains is empty".to_string());
+        }
+        if let Some(ref state) = self.sql_state {
+            if state.len() != 5 || !state.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                return Err(format!("invalid SQL state '{}', expected 5 letters or digits", state));
+            }
+        }
+        if self.redact.iter().any(|r| r.is_empty()) {
+            return Err("empty text to redact".to_string());
+        }
+        if self.new_code.is_none() && self.sql_state.is_none() && self.message.is_none() && self.redact.is_empty() {
+            return Err("the rule changes nothing, expected new_code, sql_state, message or redact".to_string());
+        }
+        Ok(())
+    }
+
+    fn matches(&self, error: &BackendError) -> bool {
+        self.code.map(|code| code == error.code).unwrap_or(true)
+            && self.contains.as_ref().map(|c| error.msg.contains(&c[..])).unwrap_or(true)
+    }
+
+    fn apply(&self, error: &BackendError, user: &str) -> BackendError {
+        let mut msg = error.msg.clone();
+        for hidden in &self.redact {
+            msg = msg.replace(&hidden[..], REDACTED);
+        }
+        if let Some(ref template) = self.message {
+            msg = template.replace("{message}", &msg)
+                .replace("{code}", &error.code.to_string())
+                .replace("{user}", user);
+        }
+        let mut state = error.state;
+        if let Some(ref new_state) = self.sql_state {
+            let mut bytes = [0; 5];
+            bytes.copy_from_slice(new_state.as_bytes());
+            state = Some(bytes);
+        }
+        BackendError { code: self.new_code.unwrap_or(error.code), state, msg }
+    }
+}
+
+/// The fields of an ERR packet
+#[derive(Clone,Debug,PartialEq)]
+struct BackendError {
+    code: u16,
+    /// absent in errors sent before the client's capabilities are known
+    state: Option<[u8; 5]>,
+    msg: String,
+}
+
+impl BackendError {
+
+    fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < 3 || payload[0] != 0xff {
+            return None;
+        }
+        let code = LittleEndian::read_u16(&payload[1..3]);
+        let (state, msg) = if payload.len() >= 9 && payload[3] == b'#' {
+            let mut state = [0; 5];
+            state.copy_from_slice(&payload[4..9]);
+            (Some(state), &payload[9..])
+        } else {
+            (None, &payload[3..])
+        };
+        Some(BackendError { code, state, msg: String::from_utf8_lossy(msg).into_owned() })
+    }
+
+    fn packet(&self, sequence_id: u8) -> Packet {
+        let mut payload = Vec::with_capacity(9 + self.msg.len());
+        payload.push(0xff);
+        payload.extend_from_slice(&self.code.to_le_bytes());
+        if let Some(ref state) = self.state {
+            payload.push(b'#');
+            payload.extend_from_slice(state);
+        }
+        payload.extend_from_slice(self.msg.as_bytes());
+        Packet::new(sequence_id, &payload)
+    }
+}
+
+/// The error rules of a listener, shared by its sessions
+#[derive(Clone,Debug,Default)]
+pub struct ErrorRules {
+    rules: Arc<Vec<ErrorRule>>,
+}
+
+impl ErrorRules {
+
+    pub fn new(rules: Vec<ErrorRule>) -> Self {
+        ErrorRules { rules: Arc::new(rules) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The error packet to send `user` in place of `p`, if a rule matches it
+    pub fn rewrite(&self, p: &Packet, user: &str) -> Option<Packet> {
+        let error = BackendError::parse(p.payload())?;
+        let rule = self.rules.iter().find(|rule| rule.matches(&error))?;
+        Some(rule.apply(&error, user).packet(p.sequence_id()))
+    }
+
+    /// The code, SQL state and message to send `user` in place of an error, the SQL state
+    /// staying as it is unless a rule changes it
+    pub fn rewrite_fields(&self, code: u16, state: [u8; 5], msg: String, user: &str) -> (u16, [u8; 5], String) {
+        let error = BackendError { code, state: Some(state), msg };
+        match self.rules.iter().find(|rule| rule.matches(&error)) {
+            Some(rule) => {
+                let rewritten = rule.apply(&error, user);
+                (rewritten.code, rewritten.state.unwrap_or(state), rewritten.msg)
+            },
+            None => (error.code, state, error.msg),
+        }
+    }
+}
+
+/// Wraps another handler and rewrites the errors answering the commands of a session
+pub struct ErrorRulesHandler<H: PacketHandler> {
+    rules: ErrorRules,
+    user: String,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    inner: H,
+}
+
+impl<H> ErrorRulesHandler<H> where H: PacketHandler {
+
+    pub fn for_session(rules: ErrorRules, session: &Session, inner: H) -> Self {
+        ErrorRulesHandler {
+            rules,
+            user: session.user.clone(),
+            phase: PhaseTracker::new(),
+            correlator: Correlator::new(session.backend_capabilities),
+            inner,
+        }
+    }
+}
+
+impl<H> PacketHandler for ErrorRulesHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() == ConnectionPhase::Command {
+            match action {
+                Action::Forward => self.correlator.request(p),
+                Action::Mutate(ref p2) => self.correlator.request(p2),
+                _ => {},
+            }
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        let action = self.inner.handle_response(p);
+        if phase != ConnectionPhase::Command {
+            return action;
+        }
+        let error = match self.correlator.response(p) {
+            Some(answered) => answered.kind == ResponseKind::Err,
+            None => false,
+        };
+        if !error {
+            return action;
+        }
+        let rewritten = match action {
+            Action::Forward => self.rules.rewrite(p, &self.user),
+            Action::Mutate(ref p2) => self.rules.rewrite(p2, &self.user),
+            _ => None,
+        };
+        match rewritten {
+            Some(rewritten) => Action::Mutate(rewritten),
+            None => action,
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,150 @@
+//! Security events as lines of JSON, for fail2ban or a SIEM.
+//!
+//! An `[event_log]` section writes the events named in `events`, by default failed logins
+//! and protocol violations, to a file, to a UDP socket, or both, one JSON object per line or
+//! datagram. Each has the event's fields, a `timestamp_ms`, and for events about a client,
+//! its bare address as `ip`, so a fail2ban filter can match the host without the port:
+//!
+//! ```text
+//! {"client":"203.0.113.9:51234","event":"auth_failed","ip":"203.0.113.9","reason":"wrong password","timestamp_ms":1760000000000,"user":"root"}
+//! ```
+//!
+//! Fields are in alphabetical order, so a fail2ban filter can be as simple as
+//! `failregex = "event":"auth_failed","ip":"<HOST>"`.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use super::events::{Event, EventBus, SubscriptionId};
+
+/// The events that can be logged, by the names they're written with
+pub const EVENT_NAMES: &[&str] = &[
+    "connection_opened",
+    "connection_closed",
+    "query_executed",
+    "backend_marked_down",
+    "backend_marked_up",
+    "auth_failed",
+    "protocol_violation",
+    "rule_matched",
+    "honeypot_login",
+    "honeypot_command",
+];
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct EventLogConfig {
+    /// a file to append events to
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// where to send events as UDP datagrams
+    #[serde(default)]
+    pub udp: Option<SocketAddr>,
+    #[serde(default = "EventLogConfig::default_events")]
+    pub events: Vec<String>,
+}
+
+impl EventLogConfig {
+
+    fn default_events() -> Vec<String> {
+        vec!["auth_failed".to_string(), "protocol_violation".to_string()]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.is_none() && self.udp.is_none() {
+            return Err("needs a path or a udp address".to_string());
+        }
+        for name in &self.events {
+            if !EVENT_NAMES.contains(&&name[..]) {
+                return Err(format!("unknown event '{}', expected one of {}", name, EVENT_NAMES.join(", ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Destinations {
+    writer: Option<Box<dyn Write + Send>>,
+    socket: Option<(UdpSocket, SocketAddr)>,
+}
+
+/// Writes events to a file or socket
+#[derive(Clone)]
+pub struct EventLog {
+    events: Arc<Vec<String>>,
+    destinations: Arc<Mutex<Destinations>>,
+}
+
+impl EventLog {
+
+    /// Open the file and socket of `config`
+    pub fn open(config: &EventLogConfig) -> io::Result<Self> {
+        let writer = match config.path {
+            Some(ref path) => Some(Box::new(OpenOptions::new().create(true).append(true).open(path)?) as Box<dyn Write + Send>),
+            None => None,
+        };
+        let socket = match config.udp {
+            Some(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+                Some((UdpSocket::bind(local)?, addr))
+            },
+            None => None,
+        };
+        Ok(EventLog::with_destinations(config, writer, socket))
+    }
+
+    /// Write the events of `config` to an arbitrary writer instead
+    pub fn new(config: &EventLogConfig, writer: Box<dyn Write + Send>) -> Self {
+        EventLog::with_destinations(config, Some(writer), None)
+    }
+
+    fn with_destinations(config: &EventLogConfig, writer: Option<Box<dyn Write + Send>>, socket: Option<(UdpSocket, SocketAddr)>) -> Self {
+        EventLog {
+            events: Arc::new(config.events.clone()),
+            destinations: Arc::new(Mutex::new(Destinations { writer, socket })),
+        }
+    }
+
+    /// Log the events published on `bus` from now on
+    pub fn subscribe(&self, bus: &EventBus) -> SubscriptionId {
+        let log = self.clone();
+        bus.subscribe(move |event| {
+            if let Err(e) = log.write(event) {
+                warn!("Could not write to the event log: {}", e);
+            }
+        })
+    }
+
+    /// Write an event, if it's one of those logged
+    pub fn write(&self, event: &Event) -> io::Result<()> {
+        let mut record = serde_json::to_value(event)?;
+        match record.get("event").and_then(Value::as_str) {
+            Some(name) if self.events.iter().any(|e| e == name) => {},
+            _ => return Ok(()),
+        }
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let ip = record.get("client").and_then(Value::as_str).and_then(|c| c.parse::<SocketAddr>().ok()).map(|c| c.ip());
+        if let Some(fields) = record.as_object_mut() {
+            fields.insert("timestamp_ms".to_string(), timestamp_ms.into());
+            if let Some(ip) = ip {
+                fields.insert("ip".to_string(), ip.to_string().into());
+            }
+        }
+        let line = record.to_string();
+
+        let mut destinations = self.destinations.lock().unwrap();
+        if let Some((ref socket, addr)) = destinations.socket {
+            socket.send_to(line.as_bytes(), addr)?;
+        }
+        if let Some(ref mut writer) = destinations.writer {
+            writeln!(writer, "{}", line)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Events about proxy activity, for applications embedding the proxy.
+//!
+//! Parts of the proxy given an `EventBus` publish what they do on it: sessions opening and
+//! closing, failed logins, queries completing, backends going down and coming back, table
+//! rules matching statements, and what clients of honeypot listeners attempt. Subscribers
+//! either register a callback, which runs on the thread that publishes the event and so
+//! should be quick, or take events from a channel on a thread of their own. Nothing is published while there are no subscribers.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use super::rules::RuleAction;
+
+/// Something that happened in the proxy
+#[derive(Clone,Debug,PartialEq,Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// a client logged in and was connected to a backend
+    ConnectionOpened {
+        user: String,
+        client: SocketAddr,
+        backend: String,
+        /// the session's labels, see `labels`
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+    },
+    /// a session ended, `error` says why, e.g. `connection closed` when either side hung up
+    ConnectionClosed {
+        user: String,
+        client: SocketAddr,
+        duration_ms: u64,
+        error: Option<String>,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+    },
+    /// the backend's response to a query is complete
+    QueryExecuted {
+        user: String,
+        fingerprint: String,
+        elapsed_us: u64,
+        error: Option<u16>,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        labels: BTreeMap<String, String>,
+    },
+    /// a health check of a backend failed, when it was up or hadn't been checked before
+    BackendMarkedDown { backend: String, error: Option<String> },
+    /// a health check of a backend that was down succeeded
+    BackendMarkedUp { backend: String },
+    /// a client failed to log in, as `user` if it got as far as naming one
+    AuthFailed { client: SocketAddr, user: Option<String>, reason: String },
+    /// a client sent something other than the MySQL protocol expects during the handshake
+    ProtocolViolation { client: SocketAddr, reason: String },
+    /// a client logged in to a honeypot listener, which accepts any credentials
+    HoneypotLogin {
+        client: SocketAddr,
+        user: String,
+        database: Option<String>,
+        auth_plugin: Option<String>,
+        /// the connection attributes the client sent, such as `_client_name` and `program_name`
+        attrs: BTreeMap<String, String>,
+    },
+    /// a client logged in to a honeypot listener sent a command, e.g. `COM_QUERY` and its SQL
+    HoneypotCommand { client: SocketAddr, user: String, command: String, statement: Option<String> },
+    /// a table rule blocked or audited a statement
+    RuleMatched {
+        user: String,
+        action: RuleAction,
+        schema: String,
+        table: String,
+        column: Option<String>,
+        statement: String,
+    },
+}
+
+/// Identifies a subscription, to end it
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct SubscriptionId(usize);
+
+enum Subscriber {
+    Callback(Box<dyn Fn(&Event) + Send + Sync>),
+    Channel(Mutex<Sender<Event>>),
+}
+
+/// Publishes events to subscribers. Clones share the subscribers.
+#[derive(Clone,Default)]
+pub struct EventBus {
+    subscribers: Arc<RwLock<Vec<(SubscriptionId, Subscriber)>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventBus {{ subscribers: {} }}", self.subscribers.read().unwrap().len())
+    }
+}
+
+impl EventBus {
+
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Call `callback` with every event from now on
+    pub fn subscribe<F>(&self, callback: F) -> SubscriptionId where F: Fn(&Event) + Send + Sync + 'static {
+        self.add(Subscriber::Callback(Box::new(callback)))
+    }
+
+    /// Send every event from now on to the returned receiver, until it's dropped
+    pub fn subscribe_channel(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.add(Subscriber::Channel(Mutex::new(sender)));
+        receiver
+    }
+
+    /// End a subscription, returning whether it was still active
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.write().unwrap();
+        let before = subscribers.len();
+        subscribers.retain(|&(s, _)| s != id);
+        subscribers.len() < before
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.read().unwrap().is_empty()
+    }
+
+    /// Hand an event to every subscriber
+    pub fn publish(&self, event: Event) {
+        let mut disconnected = vec![];
+        for &(id, ref subscriber) in self.subscribers.read().unwrap().iter() {
+            match *subscriber {
+                Subscriber::Callback(ref callback) => callback(&event),
+                Subscriber::Channel(ref sender) => {
+                    if sender.lock().unwrap().send(event.clone()).is_err() {
+                        disconnected.push(id);
+                    }
+                },
+            }
+        }
+        for id in disconnected {
+            self.unsubscribe(id);
+        }
+    }
+
+    /// Publish the event `make` creates, only creating it if there are subscribers
+    pub fn publish_with<F>(&self, make: F) where F: FnOnce() -> Event {
+        if self.has_subscribers() {
+            self.publish(make());
+        }
+    }
+
+    fn add(&self, subscriber: Subscriber) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers.write().unwrap().push((id, subscriber));
+        id
+    }
+}
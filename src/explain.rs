@@ -0,0 +1,350 @@
+//! Vetting of ad-hoc queries with `EXPLAIN` before they run.
+//!
+//! For the configured users, each `SELECT` on the configured tables is first explained on a
+//! side connection to the session's backend, logged in as a separate account. Plans that
+//! read every row of a table, or that estimate examining more rows than allowed, are
+//! rejected with the error MySQL sends for `max_join_size`, or forwarded and logged when the
+//! vetting only flags them. Queries that can't be explained are forwarded as they are, and
+//! so are all queries while the side connection is down: this is a safety net for analytics
+//! users, not access control.
+//!
+//! The side connection is blocking, so the thread relaying the session, and every other
+//! session on it, waits for the `EXPLAIN`. `timeout_ms` bounds how long.
+
+use std::io::{Result, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::{QueryResponse, QueryResponseDecoder, MAX_PAYLOAD_LEN};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sidechannel::{login, read_packet};
+use super::sql::Statement;
+
+/// MySQL error ER_TOO_BIG_SELECT
+pub const ER_TOO_BIG_SELECT: u16 = 1104;
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VetAction {
+    /// refuse the query
+    Reject,
+    /// forward the query, logging why it was flagged
+    Flag,
+}
+
+/// Which queries to explain first and which plans are too expensive
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ExplainConfig {
+    /// users whose queries are vetted
+    pub users: Vec<String>,
+    /// only queries on these tables, as `schema.table` where either part may be `*`,
+    /// otherwise every `SELECT`
+    #[serde(default)]
+    pub tables: Vec<String>,
+    /// the backend account that explains queries, which needs `SELECT` on the tables
+    pub backend_user: String,
+    pub backend_password: String,
+    /// the most rows a plan may estimate examining
+    #[serde(default)]
+    pub max_rows: Option<u64>,
+    /// whether plans may read every row of a table
+    #[serde(default)]
+    pub allow_full_scans: bool,
+    #[serde(default = "ExplainConfig::default_action")]
+    pub action: VetAction,
+    /// how long connecting to the backend, and each read and write, may take
+    #[serde(default = "ExplainConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl ExplainConfig {
+
+    fn default_action() -> VetAction {
+        VetAction::Reject
+    }
+
+    fn default_timeout_ms() -> u64 {
+        500
+    }
+
+    /// Whether a query by `user` in `schema` is vetted
+    pub fn covers(&self, user: &str, schema: Option<&str>, statement: &Statement) -> bool {
+        if statement.command != "SELECT" || !self.users.iter().any(|u| u == user) {
+            return false;
+        }
+        self.tables.is_empty() || statement.tables.iter().any(|t| {
+            let table_schema = t.schema.as_ref().map(|s| &s[..]).or(schema).unwrap_or("");
+            self.tables.iter().any(|pattern| {
+                let (s, name) = pattern.split_once('.').unwrap_or(("*", &pattern[..]));
+                (s == "*" || s == table_schema) && (name == "*" || name.eq_ignore_ascii_case(&t.table))
+            })
+        })
+    }
+
+    /// Why a plan is too expensive, if it is
+    pub fn assess(&self, plan: &Plan) -> Option<String> {
+        if !self.allow_full_scans {
+            if let Some(table) = plan.full_scans().first() {
+                return Some(format!("The SELECT would read every row of table '{}'", table));
+            }
+        }
+        match self.max_rows {
+            Some(max) if plan.estimated_rows() > max => {
+                Some(format!("The SELECT would examine about {} rows, more than the {} allowed",
+                             plan.estimated_rows(), max))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// A step of a query plan, from a row of `EXPLAIN`
+#[derive(Clone,Debug,PartialEq)]
+pub struct PlanStep {
+    /// the `SELECT` the step belongs to
+    pub id: Option<u64>,
+    pub table: Option<String>,
+    /// the join type, `ALL` for a full scan
+    pub access: Option<String>,
+    pub rows: Option<u64>,
+}
+
+/// The plan `EXPLAIN` reported for a query
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+
+    /// Tables read in full, other than derived tables
+    pub fn full_scans(&self) -> Vec<&str> {
+        self.steps.iter()
+            .filter(|s| s.access.as_ref().map(|a| a == "ALL").unwrap_or(false))
+            .filter_map(|s| s.table.as_ref().map(|t| &t[..]))
+            .filter(|t| !t.starts_with('<'))
+            .collect()
+    }
+
+    /// The rows examined, as the product of the rows of the steps of each `SELECT`, which are
+    /// joined, summed over the `SELECT`s
+    pub fn estimated_rows(&self) -> u64 {
+        let mut ids: Vec<Option<u64>> = self.steps.iter().map(|s| s.id).collect();
+        ids.dedup();
+        ids.iter().fold(0_u64, |total, id| {
+            let joined = self.steps.iter()
+                .filter(|s| s.id == *id)
+                .fold(1_u64, |product, s| product.saturating_mul(s.rows.unwrap_or(1).max(1)));
+            total.saturating_add(joined)
+        })
+    }
+}
+
+/// A blocking connection to the backend that explains queries
+pub struct ExplainClient {
+    backend: SocketAddr,
+    user: String,
+    password: String,
+    timeout: Duration,
+    stream: Option<TcpStream>,
+    /// the schema selected on the connection
+    schema: Option<String>,
+}
+
+impl ExplainClient {
+
+    pub fn new(backend: SocketAddr, user: &str, password: &str, timeout: Duration) -> Self {
+        ExplainClient {
+            backend,
+            user: user.to_string(),
+            password: password.to_string(),
+            timeout,
+            stream: None,
+            schema: None,
+        }
+    }
+
+    /// Explain `sql` in `schema`, or return `None` if the backend can't explain it. The
+    /// connection is dropped on errors and made again for the next query.
+    pub fn explain(&mut self, schema: Option<&str>, sql: &str) -> Result<Option<Plan>> {
+        let result = self.try_explain(schema, sql);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn try_explain(&mut self, schema: Option<&str>, sql: &str) -> Result<Option<Plan>> {
+        if self.stream.is_none() {
+            self.stream = Some(self.login()?);
+            self.schema = None;
+        }
+        if let Some(schema) = schema {
+            if self.schema.as_ref().map(|s| &s[..]) != Some(schema) {
+                let p = self.command(Packet::com_init_db(schema))?;
+                if p.payload().first() != Some(&0x00) {
+                    // the backend will refuse the query too
+                    return Ok(None);
+                }
+                self.schema = Some(schema.to_string());
+            }
+        }
+        let explain = Packet::com_query(&format!("EXPLAIN {}", sql));
+        if explain.payload().len() >= MAX_PAYLOAD_LEN {
+            return Ok(None);
+        }
+        let first = self.command(explain)?;
+        let stream = self.stream.as_mut().unwrap();
+        let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+        let mut columns: Vec<String> = vec![];
+        let mut plan = Some(Plan::default());
+        let mut p = first;
+        loop {
+            match decoder.decode(&p)? {
+                QueryResponse::Column(column) => columns.push(column.name.to_lowercase()),
+                QueryResponse::Row(row) => {
+                    let value = |name: &str| columns.iter().position(|c| c == name)
+                        .and_then(|i| row.get(i).cloned())
+                        .and_then(|v| v)
+                        .map(|v| String::from_utf8_lossy(&v).into_owned());
+                    if let Some(ref mut plan) = plan {
+                        plan.steps.push(PlanStep {
+                            id: value("id").and_then(|v| v.parse().ok()),
+                            table: value("table"),
+                            access: value("type"),
+                            rows: value("rows").and_then(|v| v.parse().ok()),
+                        });
+                    }
+                },
+                // statements EXPLAIN doesn't support
+                QueryResponse::Err(_) | QueryResponse::Ok(_) => plan = None,
+                _ => {},
+            }
+            if decoder.is_done() {
+                break;
+            }
+            p = read_packet(stream)?;
+        }
+        if !columns.iter().any(|c| c == "type") {
+            // not a plan in the traditional format
+            plan = None;
+        }
+        Ok(plan)
+    }
+
+    /// Send a command and read the first packet of the response
+    fn command(&mut self, command: Packet) -> Result<Packet> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(&command.bytes)?;
+        read_packet(stream)
+    }
+
+    fn login(&self) -> Result<TcpStream> {
+        login(self.backend, &self.user, &self.password, self.timeout)
+    }
+}
+
+/// Wraps another handler and vets the user's queries with `EXPLAIN` before forwarding them
+pub struct ExplainHandler<H: PacketHandler> {
+    config: ExplainConfig,
+    user: String,
+    schema: Option<String>,
+    client: ExplainClient,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> ExplainHandler<H> where H: PacketHandler {
+
+    /// Vet queries by `user`, explaining them on `backend`
+    pub fn new(config: ExplainConfig, user: &str, backend: SocketAddr, inner: H) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let client = ExplainClient::new(backend, &config.backend_user, &config.backend_password, timeout);
+        ExplainHandler {
+            config,
+            user: user.to_string(),
+            schema: None,
+            client,
+            phase: PhaseTracker::new(),
+            inner,
+        }
+    }
+
+    /// Vet the session user's queries on its backend, starting in the schema it logged in
+    /// with. Inside a `TenantHandler`, schema names are the backend's.
+    pub fn for_session(config: &ExplainConfig, session: &Session, inner: H) -> Self {
+        let mut handler = ExplainHandler::new(config.clone(), &session.user, session.backend, inner);
+        handler.schema = match session.tenant {
+            Some(ref tenant) => session.database.as_ref().map(|db| tenant.to_backend(db)),
+            None => session.database.clone(),
+        };
+        handler
+    }
+
+    fn vet(&mut self, sql: &str) -> Option<Action> {
+        let statement = Statement::parse(sql);
+        if statement.command == "USE" {
+            // assume it succeeds, a failed USE leaves the backend's schema where it was
+            if let Some(schema) = statement.identifiers.get(1) {
+                self.schema = Some(schema.clone());
+            }
+        }
+        if !self.config.covers(&self.user, self.schema.as_ref().map(|s| &s[..]), &statement) {
+            return None;
+        }
+        let plan = match self.client.explain(self.schema.as_ref().map(|s| &s[..]), sql) {
+            Ok(Some(plan)) => plan,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to explain query by '{}', forwarding it unvetted: {}", self.user, e);
+                return None;
+            },
+        };
+        let reason = self.config.assess(&plan)?;
+        match self.config.action {
+            VetAction::Reject => {
+                info!("Rejected query by '{}': {}: {}", self.user, reason, sql);
+                Some(Action::Error { code: ER_TOO_BIG_SELECT, state: *b"42000", msg: reason })
+            },
+            VetAction::Flag => {
+                warn!("Flagged query by '{}': {}: {}", self.user, reason, sql);
+                None
+            },
+        }
+    }
+}
+
+impl<H> PacketHandler for ExplainHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0 {
+            let arg = String::from_utf8_lossy(p.payload().get(1..).unwrap_or(&[]));
+            let action = match p.packet_type() {
+                Ok(PacketType::ComInitDb) => {
+                    self.schema = Some(arg.into_owned());
+                    None
+                },
+                // statements split over several packets aren't vetted
+                Ok(PacketType::ComQuery) if p.payload().len() < MAX_PAYLOAD_LEN => self.vet(&arg),
+                _ => None,
+            };
+            if let Some(action) = action {
+                return action;
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
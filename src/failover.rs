@@ -0,0 +1,199 @@
+//! Transparent failover support.
+//!
+//! While a `FailoverWindow` is open, statements from authenticated clients are held in the
+//! proxy instead of being sent to a primary that is in the middle of being replaced, and new
+//! connections wait before connecting to the backend. Once the new primary has been promoted
+//! (or the window expires) held statements are flushed and waiting connections go to the
+//! current primary.
+//!
+//! Held statements are only flushed to the new primary: once the window closes with one, a
+//! pipe moves its session there with its session resume before releasing them, and closes
+//! the session if it can't be resumed, e.g. mid-transaction (see the `resume` module).
+//! Sessions whose backend connection is lost during the window see the disconnect, unless
+//! they're idle outside a transaction and `[session_resume]` logs them in again.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::retry::ConnectRetryPolicy;
+
+#[derive(Debug)]
+struct WindowState {
+    primary: SocketAddr,
+    hold_until: Option<Instant>,
+    generation: u64,
+    waiting: Vec<Task>,
+}
+
+/// Shared handle used to open and close a failover window
+#[derive(Clone)]
+pub struct FailoverWindow {
+    state: Arc<Mutex<WindowState>>,
+}
+
+impl FailoverWindow {
+
+    pub fn new(primary: SocketAddr) -> Self {
+        FailoverWindow {
+            state: Arc::new(Mutex::new(WindowState {
+                primary,
+                hold_until: None,
+                generation: 0,
+                waiting: vec![],
+            }))
+        }
+    }
+
+    /// Start holding statements for up to `max_hold` while a new primary is promoted, with a
+    /// timer on `handle`'s reactor to release them
+    pub fn begin(&self, max_hold: Duration, handle: &Handle) -> io::Result<()> {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            state.hold_until = Some(Instant::now() + max_hold);
+            state.generation
+        };
+        info!("Failover window opened, holding statements for up to {:?}", max_hold);
+
+        // release everything if the promotion doesn't complete in time
+        let window = self.clone();
+        let timer = Timeout::new(max_hold, handle)?;
+        handle.spawn(timer.then(move |_| {
+            window.release(generation, None);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// The new primary is available, flush held statements to it
+    pub fn promote(&self, primary: SocketAddr) {
+        let generation = self.state.lock().unwrap().generation;
+        self.release(generation, Some(primary));
+    }
+
+    /// The address of the current primary
+    pub fn primary(&self) -> SocketAddr {
+        self.state.lock().unwrap().primary
+    }
+
+    pub fn is_holding(&self) -> bool {
+        match self.state.lock().unwrap().hold_until {
+            Some(t) => Instant::now() < t,
+            None => false,
+        }
+    }
+
+    /// Returns `NotReady` while the window is open, arranging for the current task to be
+    /// notified when it closes
+    pub fn poll_release(&self) -> Async<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.hold_until {
+            Some(t) if Instant::now() < t => {
+                if !state.waiting.iter().any(|t| t.will_notify_current()) {
+                    state.waiting.push(task::current());
+                }
+                Async::NotReady
+            },
+            _ => Async::Ready(()),
+        }
+    }
+
+    fn release(&self, generation: u64, primary: Option<SocketAddr>) {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            if state.generation != generation || state.hold_until.is_none() {
+                return;
+            }
+            if let Some(addr) = primary {
+                state.primary = addr;
+            }
+            state.hold_until = None;
+            mem::take(&mut state.waiting)
+        };
+        info!("Failover window closed, releasing {} waiting connections", waiting.len());
+        for t in waiting {
+            t.notify();
+        }
+    }
+}
+
+enum ConnectState {
+    Waiting,
+    Connecting(TcpStreamNew),
+    /// before trying again
+    Backoff(Timeout),
+}
+
+/// Future returned by `connect()`
+pub struct ConnectPrimary {
+    window: FailoverWindow,
+    handle: Handle,
+    state: ConnectState,
+    retry: Option<ConnectRetryPolicy>,
+    attempt: u32,
+}
+
+/// Connect to the current primary, waiting for any open failover window to close first
+pub fn connect(window: &FailoverWindow, handle: &Handle) -> ConnectPrimary {
+    ConnectPrimary {
+        window: window.clone(),
+        handle: handle.clone(),
+        state: ConnectState::Waiting,
+        retry: None,
+        attempt: 1,
+    }
+}
+
+impl ConnectPrimary {
+
+    /// Try again after the failures `policy` retries, connecting to whichever backend is the
+    /// primary by then
+    pub fn with_retry(mut self, policy: ConnectRetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+}
+
+impl Future for ConnectPrimary {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            let next = match self.state {
+                ConnectState::Waiting => {
+                    if let Async::NotReady = self.window.poll_release() {
+                        return Ok(Async::NotReady);
+                    }
+                    ConnectState::Connecting(TcpStream::connect(&self.window.primary(), &self.handle))
+                },
+                ConnectState::Connecting(ref mut f) => {
+                    let e = match f.poll() {
+                        Err(e) => e,
+                        result => return result,
+                    };
+                    let delay = match self.retry.as_ref().and_then(|r| r.retry_after(self.attempt, &e)) {
+                        Some(delay) => delay,
+                        None => return Err(e),
+                    };
+                    debug!("Connecting to the primary {} failed ({}), trying again in {:?}", self.window.primary(), e, delay);
+                    self.attempt += 1;
+                    ConnectState::Backoff(Timeout::new(delay, &self.handle)?)
+                },
+                ConnectState::Backoff(ref mut timer) => {
+                    try_ready!(timer.poll());
+                    ConnectState::Waiting
+                },
+            };
+            self.state = next;
+        }
+    }
+}
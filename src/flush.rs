@@ -0,0 +1,131 @@
+//! When a `Pipe` writes what it has queued for a socket.
+//!
+//! By default a pipe writes whatever it has queued for either socket as soon as it has
+//! processed what it read, which on a busy connection running small queries means a system
+//! call for each response, or for each part of one that arrived on its own. A `FlushPolicy`
+//! trades a little latency for fewer writes: `per_response` only writes to the client once a
+//! response is complete, so a result set goes out in one write however its packets arrived,
+//! and `batch` holds what's queued for either socket for up to `delay_us`, so the responses
+//! to pipelined commands go out together. Under either, once `max_bytes` are queued they are
+//! written at once, and everything queued is written before a connection closes.
+//!
+//! Commands are complete when they are queued, so `per_response` doesn't hold them back.
+//! The reactor's timers only fire on millisecond ticks, so a batch waits at least a
+//! millisecond however short `delay_us` is: it suits workloads that care about throughput
+//! far more than latency. The `flush_bench` example compares the policies on a loopback
+//! connection.
+
+use std::io::Result;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// When a pipe writes what it has queued
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Default)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum FlushPolicy {
+    /// write as soon as anything is queued
+    #[default]
+    Immediate,
+    /// write to the client once a response is complete
+    PerResponse {
+        #[serde(default = "FlushPolicy::default_max_bytes")]
+        max_bytes: usize,
+    },
+    /// write once what's queued has waited `delay_us`
+    Batch {
+        #[serde(default = "FlushPolicy::default_delay_us")]
+        delay_us: u64,
+        #[serde(default = "FlushPolicy::default_max_bytes")]
+        max_bytes: usize,
+    },
+}
+
+impl FlushPolicy {
+
+    fn default_max_bytes() -> usize {
+        64 * 1024
+    }
+
+    fn default_delay_us() -> u64 {
+        200
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match *self {
+            FlushPolicy::PerResponse { max_bytes: 0 } | FlushPolicy::Batch { max_bytes: 0, .. } => {
+                Err("max_bytes must be at least 1".to_string())
+            },
+            FlushPolicy::Batch { delay_us, .. } if delay_us == 0 || delay_us > 1_000_000 => {
+                Err("delay_us must be between 1 and 1000000".to_string())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decides, for one socket of a pipe, when what's queued for it is written
+#[derive(Default)]
+pub struct FlushControl {
+    policy: FlushPolicy,
+    handle: Option<Handle>,
+    /// a complete response has been queued
+    complete: bool,
+    /// what's queued is being written, and keeps being written until none is left
+    due: bool,
+    timer: Option<Timeout>,
+}
+
+impl FlushControl {
+
+    pub fn new(policy: FlushPolicy, handle: &Handle) -> Self {
+        FlushControl { policy, handle: Some(handle.clone()), ..FlushControl::default() }
+    }
+
+    /// Note that the last packet of a response has been queued
+    pub fn complete(&mut self) {
+        self.complete = true;
+    }
+
+    /// Write everything queued at once, such as before the connection closes
+    pub fn force(&mut self) {
+        self.due = true;
+    }
+
+    /// Whether `queued` bytes should be written now. A batch that isn't due yet has its
+    /// timer polled, so the task is woken when it is.
+    pub fn due(&mut self, queued: usize) -> Result<bool> {
+        if queued == 0 || self.due {
+            return Ok(true);
+        }
+        self.due = match self.policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::PerResponse { max_bytes } => self.complete || queued >= max_bytes,
+            FlushPolicy::Batch { delay_us, max_bytes } => queued >= max_bytes || self.waited(delay_us)?,
+        };
+        Ok(self.due)
+    }
+
+    /// Note that everything queued has been written
+    pub fn written(&mut self) {
+        self.complete = false;
+        self.due = false;
+        self.timer = None;
+    }
+
+    /// Whether `delay_us` have passed since the batch started, starting it if need be
+    fn waited(&mut self, delay_us: u64) -> Result<bool> {
+        let handle = match self.handle {
+            Some(ref handle) => handle,
+            None => return Ok(true),
+        };
+        if self.timer.is_none() {
+            self.timer = Some(Timeout::new(Duration::from_micros(delay_us), handle)?);
+        }
+        match self.timer {
+            Some(ref mut timer) => Ok(timer.poll()?.is_ready()),
+            None => Ok(true),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! `MySqlPacketCodec` frames a byte stream into MySQL packets.
+//!
+//! It implements the `Decoder` and `Encoder` traits from tokio-io, so a connection can be
+//! wrapped in `Framed<T, MySqlPacketCodec>` to get a `Stream` and `Sink` of packets. With the
+//! `tokio-util` feature it also implements the tokio-util traits of the same name, for
+//! applications on current versions of tokio.
+
+use std::io;
+
+use bytes::BytesMut;
+use tokio_io::codec::{Decoder, Encoder};
+
+use super::Packet;
+use super::codec::frame_len;
+use super::pool::BufferPool;
+
+/// Codec for MySQL packets. Packets are decoded one at a time, so a payload larger than
+/// 16MB arrives as several packets.
+#[derive(Clone,Debug,Default)]
+pub struct MySqlPacketCodec {
+    pool: Option<BufferPool>,
+}
+
+impl MySqlPacketCodec {
+
+    pub fn new() -> Self {
+        MySqlPacketCodec::default()
+    }
+
+    /// Take the buffers for decoded packets from a pool, and return encoded packets' buffers
+    /// to it
+    pub fn with_pool(pool: BufferPool) -> Self {
+        MySqlPacketCodec { pool: Some(pool) }
+    }
+
+    fn take_packet(&self, frame: &[u8]) -> Packet {
+        let mut bytes = match self.pool {
+            Some(ref pool) => pool.acquire(frame.len()),
+            None => Vec::with_capacity(frame.len()),
+        };
+        bytes.extend_from_slice(frame);
+        Packet { bytes }
+    }
+
+    fn recycle(&self, p: Packet) {
+        if let Some(ref pool) = self.pool {
+            pool.release(p.bytes);
+        }
+    }
+}
+
+impl Decoder for MySqlPacketCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+        Ok(frame_len(buf).map(|n| self.take_packet(&buf.split_to(n))))
+    }
+}
+
+impl Encoder for MySqlPacketCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn encode(&mut self, p: Packet, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&p.bytes);
+        self.recycle(p);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio-util")]
+mod tokio_util_codec {
+    use std::io;
+
+    use bytes1::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::MySqlPacketCodec;
+    use super::super::Packet;
+    use super::super::codec::frame_len;
+
+    impl Decoder for MySqlPacketCodec {
+        type Item = Packet;
+        type Error = io::Error;
+
+        fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+            Ok(frame_len(buf).map(|n| self.take_packet(&buf.split_to(n))))
+        }
+    }
+
+    impl Encoder<Packet> for MySqlPacketCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, p: Packet, buf: &mut BytesMut) -> io::Result<()> {
+            buf.extend_from_slice(&p.bytes);
+            self.recycle(p);
+            Ok(())
+        }
+    }
+}
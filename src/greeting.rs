@@ -0,0 +1,88 @@
+//! Rewriting the server greeting presented to clients.
+//!
+//! Clients pick features and workarounds from the server version and capabilities in the
+//! greeting. Presenting the same greeting whatever backend a client lands on keeps clients
+//! behaving consistently across mixed backend versions, and keeps backend versions from being
+//! advertised to untrusted networks.
+
+use super::{Action, Packet, PacketHandler};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::HandshakeV10;
+
+/// Changes made to the greeting, anything not set is passed through from the backend
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct GreetingConfig {
+    /// server version reported to clients, e.g. "8.0.36-proxy"
+    #[serde(default)]
+    pub server_version: Option<String>,
+    /// capability flags removed from the greeting
+    #[serde(default)]
+    pub clear_capabilities: u32,
+    /// capability flags added to the greeting, which every backend must support, since
+    /// packets are relayed verbatim
+    #[serde(default)]
+    pub set_capabilities: u32,
+}
+
+impl GreetingConfig {
+
+    pub fn apply(&self, greeting: &mut HandshakeV10) {
+        if let Some(ref version) = self.server_version {
+            greeting.server_version = version.clone();
+        }
+        greeting.capability_flags = (greeting.capability_flags | self.set_capabilities) & !self.clear_capabilities;
+    }
+
+    /// Rewrite a greeting packet, or `None` if the packet isn't a greeting
+    pub fn rewrite(&self, p: &Packet) -> Option<Packet> {
+        if p.sequence_id() != 0 || p.payload().first() != Some(&10) {
+            return None;
+        }
+        let mut greeting = HandshakeV10::parse(p).ok()?;
+        self.apply(&mut greeting);
+        Some(greeting.to_packet(0))
+    }
+}
+
+/// Wraps another handler and rewrites the backend's greeting before it reaches the client
+pub struct GreetingHandler<H: PacketHandler> {
+    config: GreetingConfig,
+    greeted: bool,
+    inner: H,
+}
+
+impl<H> GreetingHandler<H> where H: PacketHandler {
+
+    pub fn new(config: GreetingConfig, inner: H) -> Self {
+        GreetingHandler { config, greeted: false, inner }
+    }
+}
+
+impl<H> PacketHandler for GreetingHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        // the greeting is the first packet the server sends
+        if self.greeted {
+            return self.inner.handle_response(p);
+        }
+        self.greeted = true;
+        match self.config.rewrite(p) {
+            Some(greeting) => match self.inner.handle_response(&greeting) {
+                Action::Forward => Action::Mutate(greeting),
+                action => action,
+            },
+            None => {
+                warn!("First packet from the server is not a greeting, passing it through");
+                self.inner.handle_response(p)
+            },
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
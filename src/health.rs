@@ -0,0 +1,444 @@
+//! Health checking, for load balancers in front of the proxy.
+//!
+//! A `HealthMonitor` periodically checks that each backend accepts a connection and greets
+//! it, and `serve` answers health checks over HTTP or plain TCP from the results, so a load
+//! balancer can check the proxy without a MySQL login. For Kubernetes probes, `/healthz`
+//! answers while the proxy runs and `/readyz` while a backend is up. The endpoint also
+//! serves query statistics at `/stats`, and their histograms for Prometheus at `/metrics`.
+//! `PingHandler` answers `COM_PING` in the proxy, so client-side pings don't cost a backend
+//! round trip. With `slow_start_secs`, a backend that comes back up is warmed up over that
+//! long rather than getting its whole share of new sessions at once. With `replication`, the
+//! monitor also logs in to each backend and marks replicas whose replication has stopped, or
+//! lags more than `max_lag_secs`, as down.
+
+use std::collections::BTreeMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::{self, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use tokio_io::io::{read, write_all};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::read_packet;
+use super::balance::{BackendPool, BackendWeights};
+use super::codec::{ok_packet, HandshakeV10};
+use super::connect::BackendAddr;
+use super::events::{Event, EventBus};
+use super::retry::{retry_connect, ConnectRetryPolicy};
+use super::sidechannel::{SideChannel, SideChannelConfig};
+use super::stats::Stats;
+use super::topology;
+use super::upstream::{connect_through, UpstreamProxy};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct HealthConfig {
+    /// address of the health endpoint
+    pub listen: SocketAddr,
+    /// time between backend checks
+    #[serde(default = "HealthConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// how long a backend has to greet a check before it's considered down
+    #[serde(default = "HealthConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// how long a backend that comes back up takes to get its full weight, 0 for at once
+    #[serde(default)]
+    pub slow_start_secs: u64,
+    /// also log in to backends and check that replicas are replicating
+    #[serde(default)]
+    pub replication: Option<ReplicationCheckConfig>,
+}
+
+/// Logins for checking replication, and how far behind a replica may fall
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ReplicationCheckConfig {
+    pub user: String,
+    pub password: String,
+    /// replicas further behind their source are down, by default only stopped ones are
+    #[serde(default)]
+    pub max_lag_secs: Option<u64>,
+}
+
+impl HealthConfig {
+
+    fn default_interval_secs() -> u64 {
+        5
+    }
+
+    fn default_timeout_ms() -> u64 {
+        2000
+    }
+}
+
+/// The result of the latest check of a backend
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct BackendHealth {
+    pub up: bool,
+    pub error: Option<String>,
+    pub checked_ms: u64,
+}
+
+/// What the health endpoint reports
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct HealthReport {
+    /// "ok" while at least one backend is up, or none are monitored, otherwise "down"
+    pub status: &'static str,
+    pub backends: BTreeMap<String, BackendHealth>,
+}
+
+/// Shared record of backend health
+#[derive(Clone,Debug,Default)]
+pub struct HealthMonitor {
+    backends: Arc<Mutex<BTreeMap<String, BackendHealth>>>,
+    upstream: Option<UpstreamProxy>,
+    stats: Option<Stats>,
+    events: Option<EventBus>,
+    pool: Option<BackendPool>,
+    warm_up: Option<(BackendWeights, Duration)>,
+    replication: Option<ReplicationCheck>,
+    connect_retry: Option<ConnectRetryPolicy>,
+}
+
+impl HealthMonitor {
+
+    pub fn new() -> Self {
+        HealthMonitor::default()
+    }
+
+    /// Check backends through an upstream proxy, as clients reach them
+    pub fn with_upstream(mut self, upstream: Option<UpstreamProxy>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Serve these query statistics at `/stats`
+    pub fn with_stats(mut self, stats: Option<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Publish backends going down and coming back up on `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Check the pool's backends as they are at each check, rather than a fixed list, and
+    /// forget backends that leave it
+    pub fn with_backend_pool(mut self, pool: BackendPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Warm up backends that come back up over `window`
+    pub fn with_warm_up(mut self, weights: BackendWeights, window: Duration) -> Self {
+        self.warm_up = Some((weights, window));
+        self
+    }
+
+    /// Mark replicas whose replication has stopped, or fallen too far behind, as down
+    pub fn with_replication(mut self, config: &ReplicationCheckConfig, timeout: Duration) -> Self {
+        self.replication = Some(ReplicationCheck::new(config, timeout));
+        self
+    }
+
+    /// Try connecting to a backend again after the failures `policy` retries before it's
+    /// marked down, within the check's timeout
+    pub fn with_connect_retry(mut self, policy: Option<ConnectRetryPolicy>) -> Self {
+        self.connect_retry = policy;
+        self
+    }
+
+    /// Record the result of checking a backend
+    pub fn record(&self, backend: &BackendAddr, result: &io::Result<()>) {
+        let health = BackendHealth {
+            up: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            checked_ms: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        };
+        let mut backends = self.backends.lock().unwrap();
+        let was_up = backends.get(&backend.to_string()).map(|previous| previous.up);
+        if was_up.is_some() && was_up != Some(health.up) {
+            info!("Backend {} is {}", backend, if health.up { "up" } else { "down" });
+        }
+        let event = match (was_up, health.up) {
+            (Some(false), true) => {
+                if let Some((ref weights, window)) = self.warm_up {
+                    weights.warm_up(backend, window);
+                }
+                Some(Event::BackendMarkedUp { backend: backend.to_string() })
+            },
+            (None, false) | (Some(true), false) => {
+                Some(Event::BackendMarkedDown { backend: backend.to_string(), error: health.error.clone() })
+            },
+            _ => None,
+        };
+        backends.insert(backend.to_string(), health);
+        // subscribers may ask for the report, so they're called without the lock
+        drop(backends);
+        if let (Some(events), Some(event)) = (self.events.as_ref(), event) {
+            events.publish(event);
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        let backends = self.backends.lock().unwrap();
+        backends.is_empty() || backends.values().any(|b| b.up)
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let backends = self.backends.lock().unwrap().clone();
+        let healthy = backends.is_empty() || backends.values().any(|b| b.up);
+        HealthReport { status: if healthy { "ok" } else { "down" }, backends }
+    }
+
+    /// Check `backends` every `interval` until the reactor shuts down
+    pub fn monitor(&self,
+                   backends: Vec<BackendAddr>,
+                   interval: Duration,
+                   timeout: Duration,
+                   handle: &Handle) -> io::Result<Box<dyn Future<Item = (), Error = io::Error>>> {
+        let monitor = self.clone();
+        let handle = handle.clone();
+        // check straight away rather than reporting nothing for the first interval
+        let ticks = futures::stream::once(Ok(())).chain(Interval::new(interval, &handle)?);
+        Ok(Box::new(ticks.for_each(move |_| {
+            let backends = match monitor.pool {
+                Some(ref pool) => {
+                    let current = pool.all();
+                    let names: Vec<String> = current.iter().map(|b| b.to_string()).collect();
+                    monitor.backends.lock().unwrap().retain(|name, _| names.contains(name));
+                    current
+                },
+                None => backends.clone(),
+            };
+            for backend in &backends {
+                let monitor = monitor.clone();
+                let backend = backend.clone();
+                let check = check_backend(&backend, monitor.upstream.as_ref(), monitor.connect_retry.as_ref(), timeout, &handle);
+                let check: Box<dyn Future<Item = (), Error = io::Error>> = match monitor.replication.clone() {
+                    Some(replication) => {
+                        let replica = backend.clone();
+                        Box::new(check.and_then(move |_| replication.check_in_thread(replica)))
+                    },
+                    None => check,
+                };
+                handle.spawn(check.then(move |result| {
+                    monitor.record(&backend, &result);
+                    Ok(())
+                }));
+            }
+            Ok(())
+        })))
+    }
+}
+
+/// Check `backends` and answer health checks on `config.listen`, on a thread of its own so
+/// that busy proxy reactors don't delay the answers
+pub fn run_in_thread(config: &HealthConfig, backends: Vec<BackendAddr>, monitor: HealthMonitor) -> io::Result<()> {
+    let std_listener = net::TcpListener::bind(config.listen)?;
+    let interval = Duration::from_secs(config.interval_secs);
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let addr = config.listen;
+    thread::Builder::new().name("mysql-proxy-health".to_string()).spawn(move || {
+        let result = Core::new().and_then(|mut core| {
+            let handle = core.handle();
+            let listener = TcpListener::from_listener(std_listener, &addr, &handle)?;
+            handle.spawn(monitor.monitor(backends, interval, timeout, &handle)?.map_err(|e| {
+                warn!("Backend health monitoring stopped: {}", e);
+            }));
+            core.run(serve(listener, monitor, &handle))
+        });
+        if let Err(e) = result {
+            warn!("Health endpoint failed: {}", e);
+        }
+    })?;
+    Ok(())
+}
+
+/// Connect to a backend, as often as `retry` allows, and wait for it to greet us. Servers
+/// that refuse the connection with an error packet, e.g. because they're blocking the proxy's
+/// host, are down.
+pub fn check_backend(backend: &BackendAddr,
+                     upstream: Option<&UpstreamProxy>,
+                     retry: Option<&ConnectRetryPolicy>,
+                     timeout: Duration,
+                     handle: &Handle) -> Box<dyn Future<Item = (), Error = io::Error>> {
+    let (upstream_proxy, target, connect_handle) = (upstream.cloned(), backend.clone(), handle.clone());
+    let attempt = move || connect_through(upstream_proxy.as_ref(), &target, &connect_handle);
+    let check = retry_connect(retry, &backend.to_string(), handle, attempt)
+        .and_then(read_packet)
+        .and_then(|(_, greeting)| match greeting.payload().first() {
+            Some(&0xff) => Err(Error::new(ErrorKind::ConnectionRefused, "Server refused the connection")),
+            _ => HandshakeV10::parse(&greeting).map(|_| ()),
+        });
+    with_timeout(check, timeout, handle)
+}
+
+/// Checks that replicas are replicating, over side channel logins of its own, which reach
+/// backends directly even when clients go through an upstream proxy
+#[derive(Clone,Debug)]
+struct ReplicationCheck {
+    channel: SideChannel,
+    max_lag_secs: Option<u64>,
+}
+
+impl ReplicationCheck {
+
+    fn new(config: &ReplicationCheckConfig, timeout: Duration) -> Self {
+        let channel = SideChannel::new(&SideChannelConfig {
+            backend_user: config.user.clone(),
+            backend_password: config.password.clone(),
+            timeout_ms: timeout.as_millis().max(1) as u64,
+            max_concurrent: 64,
+            max_idle: 1,
+            max_rows: 64,
+            max_lifetime_secs: None,
+            max_idle_secs: None,
+        });
+        ReplicationCheck { channel, max_lag_secs: config.max_lag_secs }
+    }
+
+    fn check_in_thread(self, backend: BackendAddr) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(self.check(&backend));
+        });
+        Box::new(rx
+            .map_err(|_| Error::other("Replication check thread failed"))
+            .and_then(|r| r))
+    }
+
+    /// Backends that aren't replicas pass
+    fn check(&self, backend: &BackendAddr) -> io::Result<()> {
+        for status in topology::replica_status(&self.channel, backend.resolve_blocking()?)? {
+            let channel = if status.channel.is_empty() { String::new() } else { format!(" on channel '{}'", status.channel) };
+            if !status.is_replicating() {
+                let reason = status.last_io_error.or(status.last_sql_error).unwrap_or_else(|| "not running".to_string());
+                return Err(Error::other(format!("Replication{} is stopped: {}", channel, reason)));
+            }
+            if let (Some(max), Some(lag)) = (self.max_lag_secs, status.seconds_behind_source) {
+                if lag > max {
+                    return Err(Error::other(format!("Replica{} is {}s behind its source, more than {}s", channel, lag, max)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Answer health checks on `listener`. HTTP requests for `/live` or `/healthz` succeed while
+/// the proxy is running, `/readyz` succeeds while a backend is up, `/stats` gets the query
+/// statistics, if any, `/metrics` their histograms for Prometheus, and any other path gets
+/// the health report as JSON, with status 503 when no backend is up. Anything else, such as a
+/// bare newline, gets a one line `OK` or `DOWN`.
+pub fn serve(listener: TcpListener,
+             monitor: HealthMonitor,
+             handle: &Handle) -> Box<dyn Future<Item = (), Error = io::Error>> {
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(socket, _)| {
+        let monitor = monitor.clone();
+        let request = read(socket, vec![0; 4096]);
+        let answer = with_timeout(request, Duration::from_secs(5), &handle)
+            .and_then(move |(socket, buf, n)| {
+                let response = respond(&monitor, &String::from_utf8_lossy(&buf[..n]));
+                write_all(socket, response)
+            })
+            .then(|result| {
+                if let Err(e) = result {
+                    debug!("Health check failed: {}", e);
+                }
+                Ok(())
+            });
+        handle.spawn(answer);
+        Ok(())
+    }))
+}
+
+fn respond(monitor: &HealthMonitor, request: &str) -> Vec<u8> {
+    let mut words = request.split_whitespace();
+    let (method, path) = (words.next(), words.next());
+    let http = words.next().map(|v| v.starts_with("HTTP/")).unwrap_or(false);
+    if !http {
+        return if monitor.is_healthy() { b"OK\n".to_vec() } else { b"DOWN\n".to_vec() };
+    }
+    let json = "application/json";
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/live")) | (Some("GET"), Some("/healthz")) => ("200 OK", json, "{\"status\":\"ok\"}".to_string()),
+        (Some("GET"), Some("/readyz")) if monitor.is_healthy() => ("200 OK", json, "{\"status\":\"ready\"}".to_string()),
+        (Some("GET"), Some("/readyz")) => ("503 Service Unavailable", json, "{\"status\":\"not ready\"}".to_string()),
+        (Some("GET"), Some("/stats")) if monitor.stats.is_some() => {
+            let snapshot = monitor.stats.as_ref().map(|stats| stats.snapshot());
+            ("200 OK", json, serde_json::to_string(&snapshot).expect("statistics serialize"))
+        },
+        (Some("GET"), Some("/metrics")) if monitor.stats.is_some() => {
+            let metrics = monitor.stats.as_ref().map(|stats| stats.snapshot().prometheus()).unwrap_or_default();
+            ("200 OK", "text/plain; version=0.0.4", metrics)
+        },
+        _ => {
+            let report = monitor.report();
+            let status = if report.status == "ok" { "200 OK" } else { "503 Service Unavailable" };
+            (status, json, serde_json::to_string(&report).expect("health report serializes"))
+        },
+    };
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body).into_bytes()
+}
+
+fn with_timeout<F>(f: F, timeout: Duration, handle: &Handle) -> Box<dyn Future<Item = F::Item, Error = io::Error>>
+    where F: Future<Error = io::Error> + 'static
+{
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    Box::new(f.select2(timer).then(|result| match result {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err(Error::new(ErrorKind::TimedOut, "Timed out")),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+    }))
+}
+
+/// Wraps another handler and answers `COM_PING` without involving the backend
+pub struct PingHandler<H: PacketHandler> {
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> PingHandler<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        PingHandler { phase: PhaseTracker::new(), inner }
+    }
+}
+
+impl<H> PacketHandler for PingHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() == ConnectionPhase::Command {
+            if let Ok(PacketType::ComPing) = p.packet_type() {
+                return Action::Respond(vec![ok_packet(1)]);
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
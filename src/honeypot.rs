@@ -0,0 +1,285 @@
+//! Listeners that only pretend to be a MySQL server, to catch scanners and intruders.
+//!
+//! A listener with a `[honeypot]` section never connects to a backend. It greets clients as
+//! the server version it's given, after an optional delay, lets them log in with any
+//! credentials, and answers what they send as a plausible, empty server would: the queries
+//! clients and tools run as they connect get the answers they expect, other reads get empty
+//! results and writes succeed, or with `answer = "denied"` every statement fails for lack of
+//! privileges. Each login and command is published as a `honeypot_login` or
+//! `honeypot_command` event, which an `[event_log]` writes out for a SIEM.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::future::{Either, Loop};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::io::write_all;
+
+use super::{Packet, PacketType};
+use super::auth::{read_packet, write_packet, AuthFuture};
+use super::codec::{eof_packet, ok_packet, text_result_set, ColumnDefinition, HandshakeResponse, HandshakeV10};
+use super::events::{Event, EventBus};
+use super::protocol::*;
+use super::rules::ER_TABLEACCESS_DENIED_ERROR;
+use super::unknown::ER_UNKNOWN_COM_ERROR;
+
+/// MySQL error ER_DBACCESS_DENIED_ERROR
+pub const ER_DBACCESS_DENIED_ERROR: u16 = 1044;
+
+/// What a honeypot presents itself as, by default a stock MySQL 8.0
+pub const DEFAULT_HONEYPOT_VERSION: &str = "8.0.36";
+
+/// The capabilities a honeypot greets clients with: no TLS or compression, and classic EOF
+/// packets, so every client can log in and its commands can be answered simply
+const HONEYPOT_CAPABILITIES: u32 = CLIENT_LONG_PASSWORD | CLIENT_FOUND_ROWS | CLIENT_LONG_FLAG
+    | CLIENT_CONNECT_WITH_DB | CLIENT_PROTOCOL_41 | CLIENT_TRANSACTIONS | CLIENT_SECURE_CONNECTION
+    | CLIENT_MULTI_RESULTS | CLIENT_PLUGIN_AUTH | CLIENT_CONNECT_ATTRS | CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA;
+
+/// The databases a honeypot lists, those of a fresh server
+const DATABASES: &[&str] = &["information_schema", "mysql", "performance_schema", "sys"];
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(8);
+
+/// How a honeypot answers statements
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HoneypotAnswer {
+    /// empty results for reads, success for everything else
+    #[default]
+    Empty,
+    /// a permission error for everything but the queries clients run as they connect
+    Denied,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct HoneypotConfig {
+    /// the server version in the greeting, e.g. "5.7.44-log"
+    #[serde(default = "HoneypotConfig::default_server_version")]
+    pub server_version: String,
+    /// how long to wait before greeting a client, as a slow or distant server would
+    #[serde(default)]
+    pub greeting_delay_ms: u64,
+    /// how long a client may stay silent before it's disconnected
+    #[serde(default = "HoneypotConfig::default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    #[serde(default)]
+    pub answer: HoneypotAnswer,
+}
+
+impl HoneypotConfig {
+
+    fn default_server_version() -> String {
+        DEFAULT_HONEYPOT_VERSION.to_string()
+    }
+
+    fn default_idle_timeout_secs() -> u64 {
+        300
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server_version.is_empty() || self.server_version.contains('\0') {
+            return Err("server_version must be a non-empty string without NUL bytes".to_string());
+        }
+        if self.idle_timeout_secs == 0 {
+            return Err("idle_timeout_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a honeypot knows of a client's session
+struct Session {
+    connection_id: u32,
+    user: String,
+    host: String,
+    database: Option<String>,
+}
+
+/// Greet a client, accept its login and answer its commands until it leaves or stays silent
+/// for too long, publishing what it does on `events`
+pub fn serve_client(client: TcpStream, peer: SocketAddr, config: &HoneypotConfig, events: EventBus, handle: &Handle) -> AuthFuture<()> {
+    let delay = match Timeout::new(Duration::from_millis(config.greeting_delay_ms), handle) {
+        Ok(delay) => delay,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let scramble = match generate_scramble() {
+        Ok(scramble) => scramble,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u32;
+    let greeting = HandshakeV10 {
+        server_version: config.server_version.clone(),
+        connection_id,
+        capability_flags: HONEYPOT_CAPABILITIES,
+        character_set: 0xff, // utf8mb4_0900_ai_ci
+        status_flags: 0x0002, // SERVER_STATUS_AUTOCOMMIT
+        auth_plugin_data: scramble,
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    let idle = Duration::from_secs(config.idle_timeout_secs);
+    let (config, login_handle, handle) = (config.clone(), handle.clone(), handle.clone());
+    let login_events = events.clone();
+
+    Box::new(delay.and_then(move |_| write_packet(client, greeting.to_packet(0)))
+        .and_then(move |client| read_within(client, idle, &login_handle))
+        .and_then(move |(client, p)| -> AuthFuture<_> {
+            let response = match HandshakeResponse::parse(&p) {
+                Ok(response) => response,
+                Err(e) => {
+                    login_events.publish_with(|| Event::ProtocolViolation {
+                        client: peer,
+                        reason: format!("Malformed handshake response: {}", e),
+                    });
+                    return Box::new(future::err(e));
+                },
+            };
+            let session = Session {
+                connection_id,
+                user: response.username.clone(),
+                host: peer.ip().to_string(),
+                database: response.database.clone(),
+            };
+            info!("Honeypot login by '{}' from {}", session.user, peer);
+            login_events.publish_with(|| Event::HoneypotLogin {
+                client: peer,
+                user: response.username.clone(),
+                database: response.database.clone(),
+                auth_plugin: response.auth_plugin_name.clone(),
+                attrs: response.parse_connect_attrs().unwrap_or_default().into_iter().collect(),
+            });
+            // whatever the credentials, they're accepted
+            Box::new(write_packet(client, ok_packet(p.sequence_id().wrapping_add(1))).map(move |client| (client, session)))
+        })
+        .and_then(move |(client, session)| future::loop_fn((client, session), move |(client, mut session)| {
+            let (config, events) = (config.clone(), events.clone());
+            read_within(client, idle, &handle).and_then(move |(client, p)| -> AuthFuture<_> {
+                let command = match p.packet_type() {
+                    Ok(command) => command,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+                let statement = match command {
+                    PacketType::ComQuery | PacketType::ComInitDb | PacketType::ComFieldList
+                    | PacketType::ComCreateDb | PacketType::ComDropDb | PacketType::ComStmtPrepare => {
+                        Some(String::from_utf8_lossy(&p.payload()[1..]).into_owned())
+                    },
+                    _ => None,
+                };
+                debug!("Honeypot command from {}: {:?} {:?}", peer, command, statement);
+                events.publish_with(|| Event::HoneypotCommand {
+                    client: peer,
+                    user: session.user.clone(),
+                    command: command_name(command),
+                    statement: statement.clone(),
+                });
+                if command == PacketType::ComQuit {
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                let bytes: Vec<u8> = answer(&config, &mut session, command, statement.as_ref().map(|s| &s[..]))
+                    .into_iter().flat_map(|p| p.bytes).collect();
+                Box::new(write_all(client, bytes).map(move |(client, _)| Loop::Continue((client, session))))
+            })
+        })))
+}
+
+/// Read a packet, failing if none comes within `idle`
+fn read_within(client: TcpStream, idle: Duration, handle: &Handle) -> AuthFuture<(TcpStream, Packet)> {
+    let timer = match Timeout::new(idle, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    Box::new(read_packet(client).select2(timer).then(|result| match result {
+        Ok(Either::A((read, _))) => Ok(read),
+        Ok(Either::B(_)) => Err(::std::io::Error::new(::std::io::ErrorKind::TimedOut, "Client was idle for too long")),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+    }))
+}
+
+/// The name MySQL documents a command by, e.g. `COM_QUERY`
+fn command_name(command: PacketType) -> String {
+    if let PacketType::Unknown(byte) = command {
+        return format!("0x{:02x}", byte);
+    }
+    command.as_str().to_string()
+}
+
+/// The response to a command, as a server with nothing in it would send
+fn answer(config: &HoneypotConfig, session: &mut Session, command: PacketType, statement: Option<&str>) -> Vec<Packet> {
+    match (command, statement) {
+        (PacketType::ComQuery, Some(sql)) => answer_query(config, session, sql),
+        (PacketType::ComInitDb, Some(database)) => use_database(config, session, database),
+        (PacketType::ComPing, _) | (PacketType::ComResetConnection, _) | (PacketType::ComRefresh, _) => vec![ok_packet(1)],
+        (PacketType::ComSetOption, _) | (PacketType::ComDebug, _) => vec![eof_packet(1, 0x0002)],
+        (PacketType::ComStatistics, _) => {
+            vec![Packet::new(1, b"Uptime: 2419200  Threads: 2  Questions: 1843  Slow queries: 0  Opens: 143  Flush tables: 3  Open tables: 62  Queries per second avg: 0.000")]
+        },
+        // these are never answered
+        (PacketType::ComStmtClose, _) | (PacketType::ComStmtSendLongData, _) => vec![],
+        _ => vec![Packet::error_packet(ER_UNKNOWN_COM_ERROR, *b"08S01", "Unknown command".to_string())],
+    }
+}
+
+fn use_database(config: &HoneypotConfig, session: &mut Session, database: &str) -> Vec<Packet> {
+    if config.answer == HoneypotAnswer::Denied {
+        let msg = format!("Access denied for user '{}'@'{}' to database '{}'", session.user, session.host, database);
+        return vec![Packet::error_packet(ER_DBACCESS_DENIED_ERROR, *b"42000", msg)];
+    }
+    session.database = Some(database.to_string());
+    vec![ok_packet(1)]
+}
+
+fn answer_query(config: &HoneypotConfig, session: &mut Session, sql: &str) -> Vec<Packet> {
+    let sql = sql.trim().trim_end_matches(';').trim();
+    let lower = sql.to_ascii_lowercase();
+    let verb = lower.split_whitespace().next().unwrap_or("");
+
+    // what clients and tools ask as they connect is answered as any server would
+    if verb == "select" {
+        let start = 6 + lower[6..].len() - lower[6..].trim_start().len();
+        let expr = lower[start..].trim_end_matches(" limit 1").trim_end();
+        let value = match expr {
+            "@@version_comment" => Some(Some("MySQL Community Server - GPL".to_string())),
+            "@@version" | "version()" => Some(Some(config.server_version.clone())),
+            "database()" | "schema()" => Some(session.database.clone()),
+            "user()" | "current_user()" | "session_user()" | "system_user()" => Some(Some(format!("{}@{}", session.user, session.host))),
+            "connection_id()" => Some(Some(session.connection_id.to_string())),
+            _ => None,
+        };
+        if let Some(value) = value {
+            let name = &sql[start..start + expr.len()];
+            return text_result_set(&[ColumnDefinition::varchar(name, 255)], &[vec![value.map(String::into_bytes)]], 0);
+        }
+    }
+    match verb {
+        "set" | "begin" | "start" | "commit" | "rollback" => return vec![ok_packet(1)],
+        "use" => return use_database(config, session, sql[3..].trim().trim_matches('`')),
+        _ => {},
+    }
+
+    if config.answer == HoneypotAnswer::Denied {
+        let msg = format!("{} command denied to user '{}'@'{}'", verb.to_ascii_uppercase(), session.user, session.host);
+        return vec![Packet::error_packet(ER_TABLEACCESS_DENIED_ERROR, *b"42000", msg)];
+    }
+    match verb {
+        "show" if lower.starts_with("show databases") || lower.starts_with("show schemas") => {
+            let rows: Vec<_> = DATABASES.iter().map(|db| vec![Some(db.as_bytes().to_vec())]).collect();
+            text_result_set(&[ColumnDefinition::varchar("Database", 256)], &rows, 0)
+        },
+        "show" if lower.starts_with("show tables") => {
+            let column = format!("Tables_in_{}", session.database.as_ref().map(|db| &db[..]).unwrap_or("mysql"));
+            text_result_set(&[ColumnDefinition::varchar(&column, 256)], &[], 0)
+        },
+        "select" => {
+            // an empty result named after what was selected
+            let end = lower.find(" from ").unwrap_or(sql.len());
+            let name = sql[6..end].trim();
+            text_result_set(&[ColumnDefinition::varchar(if name.is_empty() { "?" } else { name }, 255)], &[], 0)
+        },
+        "show" | "describe" | "desc" | "explain" | "with" | "table" => {
+            text_result_set(&[ColumnDefinition::varchar("Value", 255)], &[], 0)
+        },
+        _ => vec![ok_packet(1)],
+    }
+}
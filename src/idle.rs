@@ -0,0 +1,163 @@
+//! Ending transactions that a client leaves open while idle.
+//!
+//! A client that opens a transaction and then goes quiet keeps its locks and holds back
+//! purge, a common cause of lock pileups and replication lag. `IdleTransactionGuard` follows
+//! the transaction state the server reports in OK and EOF packets and, once a transaction has
+//! been open with no command running for longer than `idle_secs`, either closes the session,
+//! which makes the backend roll the transaction back, or rolls the transaction back itself
+//! and keeps the session. After a rollback the client's next command is answered with an
+//! error, so it doesn't go on as if the transaction were still open. Either way the event is
+//! logged and recorded in the audit log, if there is one.
+
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{Packet, PacketType};
+use super::audit::AuditLog;
+use super::codec::{EofPacket, OkPacket};
+use super::pipeline::{ResponseKind, ResponsePacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::state::SERVER_STATUS_IN_TRANS;
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    /// close the client and backend connections
+    Kill,
+    /// roll the transaction back on the backend and keep the session
+    Rollback,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct IdleTransactionConfig {
+    /// how long a transaction may be open with no command running
+    #[serde(default = "IdleTransactionConfig::default_idle_secs")]
+    pub idle_secs: u64,
+    #[serde(default = "IdleTransactionConfig::default_action")]
+    pub action: IdleAction,
+}
+
+impl IdleTransactionConfig {
+
+    fn default_idle_secs() -> u64 {
+        60
+    }
+
+    fn default_action() -> IdleAction {
+        IdleAction::Kill
+    }
+}
+
+/// Follows the transaction state of a session and tells when it has been idle for too long
+pub struct IdleTransactionGuard {
+    config: IdleTransactionConfig,
+    user: String,
+    handle: Handle,
+    audit_log: Option<AuditLog>,
+    in_transaction: bool,
+    timer: Option<Timeout>,
+    /// the proxy's ROLLBACK is waiting for its response, which the client mustn't see
+    rolling_back: bool,
+    /// the client hasn't yet been told its transaction was rolled back
+    rolled_back: bool,
+}
+
+impl IdleTransactionGuard {
+
+    pub fn new(config: IdleTransactionConfig, user: &str, handle: &Handle) -> Self {
+        IdleTransactionGuard {
+            config,
+            user: user.to_string(),
+            handle: handle.clone(),
+            audit_log: None,
+            in_transaction: false,
+            timer: None,
+            rolling_back: false,
+            rolled_back: false,
+        }
+    }
+
+    /// Record the sessions killed and transactions rolled back in `log`
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit_log = Some(log);
+        self
+    }
+
+    /// Whether the proxy's ROLLBACK is running, so client commands must wait
+    pub fn rolling_back(&self) -> bool {
+        self.rolling_back
+    }
+
+    /// Observe a packet from the client, returning the error to answer it with if it is a
+    /// command and the transaction was rolled back while the client was idle
+    pub fn request(&mut self, p: &Packet) -> Option<Packet> {
+        self.timer = None;
+        if !self.rolled_back || p.sequence_id() != 0 || p.packet_type().ok() == Some(PacketType::ComQuit) {
+            return None;
+        }
+        self.rolled_back = false;
+        let msg = format!("The transaction was rolled back by the proxy after being idle for over {}s", self.config.idle_secs);
+        Some(Packet::error_packet(1105, *b"HY000", msg)) // ER_UNKNOWN_ERROR
+    }
+
+    /// Observe a response packet, returning true if it answers the proxy's ROLLBACK
+    pub fn response(&mut self, p: &Packet, answered: Option<ResponsePacket>) -> bool {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return false,
+        };
+        if answered.last {
+            let status_flags = match answered.kind {
+                ResponseKind::Ok => OkPacket::parse(p, CLIENT_PROTOCOL_41).ok().map(|ok| ok.status_flags),
+                ResponseKind::Eof => EofPacket::parse(p).ok().map(|eof| eof.status_flags),
+                _ => None,
+            };
+            if let Some(status_flags) = status_flags {
+                self.in_transaction = status_flags & SERVER_STATUS_IN_TRANS != 0;
+            }
+        }
+        if !self.rolling_back {
+            return false;
+        }
+        if answered.last {
+            self.rolling_back = false;
+            self.rolled_back = true;
+        }
+        true
+    }
+
+    /// Check whether the open transaction has been idle for too long, with `in_flight`
+    /// commands awaiting a response, returning what to do about it once it has
+    pub fn poll_idle(&mut self, in_flight: usize) -> Option<IdleAction> {
+        if !self.in_transaction || in_flight > 0 || self.rolling_back {
+            self.timer = None;
+            return None;
+        }
+        if self.timer.is_none() {
+            self.timer = Timeout::new(Duration::from_secs(self.config.idle_secs), &self.handle).ok();
+        }
+        let expired = self.timer.as_mut()?.poll().map(|a| a.is_ready()).unwrap_or(true);
+        if !expired {
+            return None;
+        }
+        self.timer = None;
+        let event = match self.config.action {
+            IdleAction::Kill => "session killed",
+            IdleAction::Rollback => {
+                self.in_transaction = false;
+                self.rolling_back = true;
+                "transaction rolled back"
+            },
+        };
+        warn!("Transaction of '{}' idle for over {}s, {}", self.user, self.config.idle_secs, event);
+        if let Some(ref log) = self.audit_log {
+            let record = format!("-- transaction idle for over {}s, {} by the proxy", self.config.idle_secs, event);
+            if let Err(e) = log.record(&self.user, &record) {
+                warn!("Failed to write audit record: {}", e);
+            }
+        }
+        Some(self.config.action)
+    }
+}
@@ -0,0 +1,125 @@
+//! Labels on connections, for slicing what the proxy reports by application or team.
+//!
+//! A session's labels are key/value pairs taken from its listener's `[labels]`: `fixed` ones
+//! every session gets, ones copied from the connection attributes its client sent, such as
+//! `program_name`, and ones copied from the attributes of its user's mapping. Handlers given
+//! the session can set more while it runs, since every copy of a session's `Labels` shares
+//! them. They appear in the log lines and events of sessions opening and closing, in
+//! `QueryExecuted` events, in audit records and in the sessions the management API and the
+//! admin interface list. The statistics can also be kept by some of them, see `StatsConfig`.
+//!
+//! ```toml
+//! [labels]
+//! fixed = { team = "payments" }
+//! # label = "connection attribute"
+//! connect_attrs = { app = "program_name" }
+//! # label = "attribute of the user's mapping"
+//! user_attributes = { tenant = "tenant_id" }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Longest label value taken from a client, longer ones are cut short
+pub const MAX_VALUE_LEN: usize = 64;
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct LabelsConfig {
+    /// labels every session gets
+    #[serde(default)]
+    pub fixed: BTreeMap<String, String>,
+    /// labels taken from the client's connection attributes, by attribute name
+    #[serde(default)]
+    pub connect_attrs: BTreeMap<String, String>,
+    /// labels taken from the attributes of the user's mapping, by attribute name
+    #[serde(default)]
+    pub user_attributes: BTreeMap<String, String>,
+}
+
+impl LabelsConfig {
+
+    pub fn validate(&self) -> Result<(), String> {
+        let names = self.fixed.keys().chain(self.connect_attrs.keys()).chain(self.user_attributes.keys());
+        match names.into_iter().find(|name| !is_label_name(name)) {
+            Some(name) => Err(format!("'{}' isn't a label name, which starts with a letter or _ and has only letters, digits and _", name)),
+            None => Ok(()),
+        }
+    }
+
+    /// The labels of a session whose client sent `connect_attrs` and whose user's mapping
+    /// has `attributes`. Labels set here override those the client chose.
+    pub fn labels_for(&self, connect_attrs: &[(String, String)], attributes: &HashMap<String, String>) -> Labels {
+        let mut labels = BTreeMap::new();
+        for (label, attr) in &self.connect_attrs {
+            if let Some((_, value)) = connect_attrs.iter().find(|(key, _)| key == attr) {
+                labels.insert(label.clone(), value.chars().take(MAX_VALUE_LEN).collect());
+            }
+        }
+        for (label, attribute) in &self.user_attributes {
+            if let Some(value) = attributes.get(attribute) {
+                labels.insert(label.clone(), value.clone());
+            }
+        }
+        labels.extend(self.fixed.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Labels::new(labels)
+    }
+}
+
+/// Whether a name can be a label, which Prometheus also requires of its label names
+pub fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+/// A session's labels, shared by every copy
+#[derive(Clone,Debug,Default)]
+pub struct Labels {
+    labels: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Labels {
+
+    pub fn new(labels: BTreeMap<String, String>) -> Self {
+        Labels { labels: Arc::new(Mutex::new(labels)) }
+    }
+
+    pub fn set(&self, name: &str, value: &str) {
+        self.labels.lock().unwrap().insert(name.to_string(), value.to_string());
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.labels.lock().unwrap().remove(name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.labels.lock().unwrap().get(name).cloned()
+    }
+
+    /// The labels as they are now
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        self.labels.lock().unwrap().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.lock().unwrap().is_empty()
+    }
+}
+
+impl PartialEq for Labels {
+    fn eq(&self, other: &Labels) -> bool {
+        Arc::ptr_eq(&self.labels, &other.labels) || self.snapshot() == other.snapshot()
+    }
+}
+
+/// The labels as `name=value` pairs separated by spaces, as log lines show them
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self.snapshot();
+        let pairs: Vec<String> = labels.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        write!(f, "{}", pairs.join(" "))
+    }
+}
@@ -0,0 +1,172 @@
+//! Favouring the backends of a routing group that answer fastest.
+//!
+//! Replicas of the same data rarely answer equally fast: one is busier, further away or
+//! catching up on replication. With a `latency` section, a routing group measures how long
+//! its backends take to start answering the commands sessions send them, as an
+//! exponentially weighted moving average, and scales each backend's weight by how much
+//! slower than the fastest it is, so new sessions lean towards the fast ones. Backends
+//! slower than `outlier_factor` times the median are left out altogether until they
+//! recover, which only fresh measurements from sessions still on them can show. Backends
+//! that haven't been measured yet keep their whole weight.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::connect::BackendAddr;
+use super::pipeline::{self, Correlator};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct LatencyConfig {
+    /// how much each measurement moves the average, from 0 to 1
+    #[serde(default = "LatencyConfig::default_decay")]
+    pub decay: f64,
+    /// backends this many times slower than the median get no sessions
+    #[serde(default = "LatencyConfig::default_outlier_factor")]
+    pub outlier_factor: f64,
+}
+
+impl LatencyConfig {
+
+    fn default_decay() -> f64 {
+        0.1
+    }
+
+    fn default_outlier_factor() -> f64 {
+        3.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.decay > 0.0 && self.decay <= 1.0) {
+            return Err(format!("decay must be above 0 and at most 1, not {}", self.decay));
+        }
+        if self.outlier_factor <= 1.0 {
+            return Err(format!("outlier_factor must be above 1, not {}", self.outlier_factor));
+        }
+        Ok(())
+    }
+}
+
+/// The average time backends take to answer, shared by every listener
+#[derive(Clone,Debug,Default)]
+pub struct BackendLatency {
+    /// in microseconds
+    averages: Arc<Mutex<HashMap<BackendAddr, f64>>>,
+}
+
+impl BackendLatency {
+
+    pub fn new() -> Self {
+        BackendLatency::default()
+    }
+
+    /// Add a measurement of how long `backend` took to answer, moving its average by `decay`
+    pub fn record(&self, backend: &BackendAddr, rtt: Duration, decay: f64) {
+        let rtt = rtt.as_secs_f64() * 1e6;
+        let mut averages = self.averages.lock().unwrap();
+        let average = averages.entry(backend.clone()).or_insert(rtt);
+        *average += decay * (rtt - *average);
+    }
+
+    /// The average time `backend` takes to answer, if it's been measured
+    pub fn average(&self, backend: &BackendAddr) -> Option<Duration> {
+        self.averages.lock().unwrap().get(backend).map(|&us| Duration::from_secs_f64(us / 1e6))
+    }
+
+    /// How much of its weight each of `backends` keeps: the fastest all of it, slower ones
+    /// in proportion to their speed, and outliers none
+    pub fn scales(&self, backends: &[BackendAddr], config: &LatencyConfig) -> Vec<f64> {
+        let averages = self.averages.lock().unwrap();
+        let measured: Vec<Option<f64>> = backends.iter().map(|b| averages.get(b).cloned()).collect();
+        let mut known: Vec<f64> = measured.iter().flatten().cloned().collect();
+        if known.is_empty() {
+            return vec![1.0; backends.len()];
+        }
+        known.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let (fastest, median) = (known[0].max(1.0), known[known.len() / 2].max(1.0));
+        measured.into_iter().map(|average| match average {
+            Some(average) if average > config.outlier_factor * median => 0.0,
+            Some(average) => fastest / average.max(1.0),
+            None => 1.0,
+        }).collect()
+    }
+}
+
+/// Wraps another handler and measures how long the session's backend takes to start
+/// answering the commands forwarded to it
+pub struct LatencyHandler<H: PacketHandler> {
+    latency: BackendLatency,
+    backend: BackendAddr,
+    decay: f64,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// when each command waiting for a response was forwarded, until its first packet
+    sent: VecDeque<Option<Instant>>,
+    inner: H,
+}
+
+impl<H> LatencyHandler<H> where H: PacketHandler {
+
+    pub fn new(latency: BackendLatency, backend: BackendAddr, config: &LatencyConfig, inner: H) -> Self {
+        LatencyHandler {
+            latency,
+            backend,
+            decay: config.decay,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            sent: VecDeque::new(),
+            inner,
+        }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+}
+
+impl<H> PacketHandler for LatencyHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        if pipeline::is_answered(forwarded) {
+            // a command sent behind others waits for them as well as for the backend
+            let sent = if self.sent.is_empty() { Some(Instant::now()) } else { None };
+            self.sent.push_back(sent);
+        }
+        self.correlator.request(forwarded);
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                if let Some(sent) = self.sent.front_mut().and_then(|sent| sent.take()) {
+                    self.latency.record(&self.backend, sent.elapsed(), self.decay);
+                }
+                if answered.last {
+                    self.sent.pop_front();
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
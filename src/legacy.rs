@@ -0,0 +1,243 @@
+//! Compatibility with legacy clients.
+//!
+//! Old client libraries predate protocol changes that newer backends expect. When the proxy
+//! logs in to a backend with CLIENT_DEPRECATE_EOF, which the client never gets from the
+//! proxy, the backend leaves out the EOF packets after column and parameter definitions and
+//! ends rows with an OK packet instead of an EOF. `LegacyEofHandler` puts the responses back
+//! into the form the client expects. The columns of a result set read with a cursor already
+//! end with an EOF, sent as an OK with an EOF header, which only needs turning back into one. Clients of accounts with pre-4.1 password hashes are
+//! authenticated by the proxy itself, see `UserMapping::old_password`.
+
+use std::collections::VecDeque;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::{OkPacket, PayloadReader, MAX_PAYLOAD_LEN, SERVER_MORE_RESULTS_EXISTS};
+use super::pipeline::{opens_cursor, SERVER_STATUS_CURSOR_EXISTS, SERVER_STATUS_LAST_ROW_SENT};
+use super::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+
+/// The part of a response still to come
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Expect {
+    /// OK, ERR, a LOCAL INFILE request or the start of a result set
+    Results,
+    /// the results of a COM_STMT_EXECUTE asking for a cursor
+    CursorResults,
+    /// column definitions, followed by rows, or by the end of the columns with a cursor
+    Columns { remaining: u64, cursor: bool },
+    /// the OK that ends the columns of a result set read with a cursor, or the first row if
+    /// the server didn't open one
+    CursorEnd,
+    Rows,
+    /// column definitions ended by OK, for COM_FIELD_LIST
+    Fields,
+    /// PREPARE_OK, or ERR
+    Prepared,
+    /// parameter definitions, followed by `columns` column definitions
+    Params { remaining: u64, columns: u64 },
+    /// column definitions of a prepared statement
+    Definitions { remaining: u64 },
+    /// a single packet
+    One,
+    /// the EOF answering COM_SET_OPTION or COM_DEBUG, sent as an OK
+    Status,
+}
+
+/// How a response packet is translated
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Translate {
+    Keep,
+    /// send an EOF after the packet
+    EofAfter,
+    /// send an EOF before the packet
+    EofBefore,
+    /// replace the OK packet by an EOF
+    OkToEof,
+}
+
+/// Wraps another handler and translates responses from a backend logged in to with
+/// CLIENT_DEPRECATE_EOF for a client without it
+pub struct LegacyEofHandler<H: PacketHandler> {
+    in_flight: VecDeque<Expect>,
+    /// EOF packets added to the current response, by which later packets are renumbered
+    added: u8,
+    /// the server status from the latest OK, for the EOF packets that are added
+    status_flags: u16,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> LegacyEofHandler<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        LegacyEofHandler {
+            in_flight: VecDeque::new(),
+            added: 0,
+            status_flags: 0x0002, // SERVER_STATUS_AUTOCOMMIT
+            phase: PhaseTracker::new(),
+            inner,
+        }
+    }
+
+    /// Follow a response packet, returning how to translate it
+    fn follow(&mut self, p: &Packet) -> Translate {
+        let expect = match self.in_flight.front() {
+            Some(&expect) => expect,
+            None => return Translate::Keep,
+        };
+        let payload = p.payload();
+        let header = payload.first().cloned();
+        // the OK that takes the place of an EOF has an EOF header
+        let ok_as_eof = header == Some(0xfe) && payload.len() < MAX_PAYLOAD_LEN;
+        let (translate, next) = match (expect, header) {
+            (_, Some(0xff)) => (Translate::Keep, None),
+            (Expect::Results, Some(0x00)) | (Expect::CursorResults, Some(0x00)) => (Translate::Keep, self.after_ok(p, Expect::Results)),
+            (Expect::Results, Some(0xfb)) => (Translate::Keep, Some(Expect::Results)),
+            (Expect::Results, _) | (Expect::CursorResults, _) => {
+                let columns = PayloadReader::new(payload).lenenc_int().unwrap_or(0);
+                (Translate::Keep, Some(Expect::Columns { remaining: columns, cursor: expect == Expect::CursorResults }))
+            },
+            (Expect::Columns { remaining, cursor }, _) if remaining > 1 => {
+                (Translate::Keep, Some(Expect::Columns { remaining: remaining - 1, cursor }))
+            },
+            (Expect::Columns { cursor: true, .. }, _) => (Translate::Keep, Some(Expect::CursorEnd)),
+            (Expect::Columns { .. }, _) => (Translate::EofAfter, Some(Expect::Rows)),
+            (Expect::CursorEnd, _) if ok_as_eof => (Translate::OkToEof, self.after_ok(p, Expect::Results)),
+            (Expect::CursorEnd, _) => (Translate::EofBefore, Some(Expect::Rows)),
+            (Expect::Rows, _) if ok_as_eof => (Translate::OkToEof, self.after_ok(p, Expect::Results)),
+            (Expect::Rows, _) => (Translate::Keep, Some(Expect::Rows)),
+            (Expect::Fields, _) if ok_as_eof => (Translate::OkToEof, None),
+            (Expect::Fields, _) => (Translate::Keep, Some(Expect::Fields)),
+            (Expect::Prepared, _) if payload.len() >= 9 => {
+                let columns = payload[5] as u64 | (payload[6] as u64) << 8;
+                let params = payload[7] as u64 | (payload[8] as u64) << 8;
+                let next = match (params, columns) {
+                    (0, 0) => None,
+                    (0, columns) => Some(Expect::Definitions { remaining: columns }),
+                    (params, columns) => Some(Expect::Params { remaining: params, columns }),
+                };
+                (Translate::Keep, next)
+            },
+            (Expect::Prepared, _) => (Translate::Keep, None),
+            (Expect::Params { remaining, columns }, _) if remaining > 1 => {
+                (Translate::Keep, Some(Expect::Params { remaining: remaining - 1, columns }))
+            },
+            (Expect::Params { columns, .. }, _) if columns > 0 => (Translate::EofAfter, Some(Expect::Definitions { remaining: columns })),
+            (Expect::Params { .. }, _) => (Translate::EofAfter, None),
+            (Expect::Definitions { remaining }, _) if remaining > 1 => (Translate::Keep, Some(Expect::Definitions { remaining: remaining - 1 })),
+            (Expect::Definitions { .. }, _) => (Translate::EofAfter, None),
+            (Expect::One, _) => (Translate::Keep, None),
+            (Expect::Status, _) if ok_as_eof => (Translate::OkToEof, None),
+            (Expect::Status, _) => (Translate::Keep, None),
+        };
+        match next {
+            Some(next) => self.in_flight[0] = next,
+            None => {
+                self.in_flight.pop_front();
+            },
+        }
+        translate
+    }
+
+    /// What follows an OK packet that ends a result, noting the server status it reports
+    fn after_ok(&mut self, p: &Packet, more: Expect) -> Option<Expect> {
+        let status_flags = OkPacket::parse(p, CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF).ok()?.status_flags;
+        // a cursor's flags only describe the response they came with
+        self.status_flags = status_flags & !(SERVER_STATUS_CURSOR_EXISTS | SERVER_STATUS_LAST_ROW_SENT);
+        if status_flags & SERVER_MORE_RESULTS_EXISTS != 0 {
+            Some(more)
+        } else {
+            None
+        }
+    }
+}
+
+/// A classic EOF packet
+fn eof(sequence_id: u8, warnings: u16, status_flags: u16) -> Vec<u8> {
+    let mut payload = vec![0xfe];
+    payload.write_u16::<LittleEndian>(warnings).unwrap();
+    payload.write_u16::<LittleEndian>(status_flags).unwrap();
+    Packet::new(sequence_id, &payload).bytes
+}
+
+impl<H> PacketHandler for LegacyEofHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        let forwarded = match action {
+            Action::Forward => Some(p),
+            Action::Mutate(ref p2) => Some(p2),
+            _ => None,
+        };
+        // follow the commands the backend will answer
+        if let Some(p) = forwarded {
+            if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0 && !p.payload().is_empty() {
+                let expect = match p.packet_type() {
+                    Ok(PacketType::ComStmtExecute) if opens_cursor(p) => Some(Expect::CursorResults),
+                    Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtExecute) => Some(Expect::Results),
+                    Ok(PacketType::ComStmtFetch) => Some(Expect::Rows),
+                    Ok(PacketType::ComFieldList) => Some(Expect::Fields),
+                    Ok(PacketType::ComStmtPrepare) => Some(Expect::Prepared),
+                    Ok(PacketType::ComSetOption) | Ok(PacketType::ComDebug) => Some(Expect::Status),
+                    Ok(PacketType::ComQuit) | Ok(PacketType::ComStmtSendLongData) | Ok(PacketType::ComStmtClose) => None,
+                    _ => Some(Expect::One),
+                };
+                self.in_flight.extend(expect);
+            }
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase != ConnectionPhase::Command {
+            return self.inner.handle_response(p);
+        }
+        let responses = self.in_flight.len();
+        let translate = self.follow(p);
+        let added = self.added;
+        // the numbering starts over with the next response
+        self.added = match translate {
+            _ if self.in_flight.len() < responses => 0,
+            Translate::EofAfter | Translate::EofBefore => added.wrapping_add(1),
+            _ => added,
+        };
+        let action = self.inner.handle_response(p);
+        if translate == Translate::Keep && added == 0 {
+            return action;
+        }
+        let p = match action {
+            Action::Forward => Packet { bytes: p.bytes.clone() },
+            Action::Mutate(p) => p,
+            action => return action,
+        };
+        let sequence_id = p.sequence_id().wrapping_add(added);
+        match translate {
+            Translate::Keep => Action::Mutate(p.with_sequence_id(sequence_id)),
+            Translate::EofAfter => {
+                // the EOF goes out with the packet before it, as handlers send one packet
+                let mut bytes = p.with_sequence_id(sequence_id).bytes;
+                bytes.extend_from_slice(&eof(sequence_id.wrapping_add(1), 0, self.status_flags));
+                Action::Mutate(Packet { bytes })
+            },
+            Translate::EofBefore => {
+                let mut bytes = eof(sequence_id, 0, self.status_flags);
+                bytes.extend_from_slice(&p.with_sequence_id(sequence_id.wrapping_add(1)).bytes);
+                Action::Mutate(Packet { bytes })
+            },
+            Translate::OkToEof => {
+                let ok = OkPacket::parse(&p, CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+                let (warnings, status_flags) = ok.map(|ok| (ok.warnings, ok.status_flags)).unwrap_or((0, self.status_flags));
+                Action::Mutate(Packet { bytes: eof(sequence_id, warnings, status_flags) })
+            },
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
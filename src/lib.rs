@@ -7,16 +7,150 @@ extern crate env_logger;
 extern crate futures;
 #[macro_use]
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate bytes;
+#[cfg(feature = "tokio-util")]
+extern crate tokio_util;
+#[cfg(feature = "tokio-util")]
+extern crate bytes1;
 extern crate byteorder;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate toml;
+extern crate net2;
+extern crate libc;
+extern crate flate2;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
+#[cfg(any(feature = "vault", feature = "aws-secrets"))]
+extern crate webpki_roots;
+#[cfg(feature = "tls")]
+extern crate x509_parser;
+
+pub mod acl;
+pub mod admin;
+pub mod annotate;
+pub mod anomaly;
+pub mod attrs;
+pub mod audit;
+pub mod auth;
+pub mod authenticator;
+pub mod balance;
+pub mod breaker;
+pub mod budget;
+pub mod capabilities;
+pub mod capture;
+pub mod charset;
+pub mod chargeback;
+pub mod coalesce;
+pub mod codec;
+pub mod compress;
+pub mod config;
+pub mod connect;
+pub mod credentials;
+pub mod discovery;
+pub mod dump;
+pub mod errors;
+pub mod eventlog;
+pub mod events;
+pub mod explain;
+pub mod failover;
+pub mod flush;
+pub mod framed;
+pub mod greeting;
+pub mod health;
+pub mod honeypot;
+pub mod idle;
+pub mod labels;
+pub mod latency;
+pub mod legacy;
+pub mod lifetime;
+pub mod listener;
+pub mod maintenance;
+pub mod management;
+pub mod metacache;
+pub mod pipeline;
+pub mod pool;
+pub mod priming;
+pub mod probe;
+pub mod profile;
+pub mod protocol;
+pub mod querylog;
+pub mod quota;
+pub mod replay;
+pub mod resume;
+pub mod retry;
+pub mod rowfilter;
+pub mod rules;
+pub mod schemas;
+pub mod server;
+pub mod sessionreplay;
+pub mod sidechannel;
+pub mod sink;
+pub mod sockopt;
+pub mod sql;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod state;
+pub mod statements;
+pub mod stats;
+pub mod tap;
+pub mod tarpit;
+pub mod tenant;
+pub mod testing;
+pub mod timing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod topology;
+pub mod trace;
+pub mod unknown;
+pub mod upstream;
+pub mod users;
+pub mod variables;
+pub mod warmpool;
+pub mod xprotocol;
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
 use std::rc::Rc;
-use std::io::{self, Read, Write, Error, ErrorKind};
-use std::net::Shutdown;
+use std::io::{self, Read, Write, Error};
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
 
+use bytes::BytesMut;
 use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
 use tokio_core::net::{TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder};
 use byteorder::*;
 
+use anomaly::{Anomaly, ProtocolChecks, Verdict};
+use budget::{PollBudget, Usage, Work};
+use coalesce::SessionCoalescing;
+use compress::{CompressedSequence, CompressionConfig, Compressor, Decompressor};
+use flush::{FlushControl, FlushPolicy};
+use framed::MySqlPacketCodec;
+use idle::{IdleAction, IdleTransactionGuard};
+use lifetime::LifetimeGuard;
+use timing::ExchangeTimer;
+use metacache::SessionMetadataCache;
+use pipeline::{Correlator, HeldResponses, ResponsePacket};
+use priming::{Primed, SessionPriming};
+use profile::Stage;
+use resume::SessionResume;
+use retry::{DeadlockRetry, RetryPolicy};
+
 /// Handlers return a variant of this enum to indicate how the proxy should handle the packet.
 #[derive(Debug,PartialEq)]
 pub enum Action {
@@ -26,7 +160,7 @@ pub enum Action {
     Forward,
     /// forward a mutated packet
     Mutate(Packet),
-    /// respond to the packet without forwarding
+    /// respond to the packet without forwarding, splitting payloads too long for one packet
     Respond(Vec<Packet>),
     /// respond with an error packet
     Error { code: u16, state: [u8; 5], msg: String },
@@ -36,6 +170,29 @@ pub enum Action {
 pub trait PacketHandler {
     fn handle_request(&mut self, p: &Packet) -> Action;
     fn handle_response(&mut self, p: &Packet) -> Action;
+
+    /// Decide whether a connection is killed when a packet read from either side breaks the
+    /// protocol, see `anomaly::ProtocolChecks`. Wrappers pass the anomaly on to the handler
+    /// they wrap.
+    fn handle_anomaly(&mut self, _anomaly: &Anomaly) -> Verdict {
+        Verdict::Tolerate
+    }
+}
+
+/// Boxed handlers let the chain of wrappers be chosen at runtime
+impl<H> PacketHandler for Box<H> where H: PacketHandler + ?Sized {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        (**self).handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        (**self).handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        (**self).handle_anomaly(anomaly)
+    }
 }
 
 /// A packet is just a wrapper for a Vec<u8>
@@ -46,6 +203,51 @@ pub struct Packet {
 
 impl Packet {
 
+    /// Create a packet from a payload, adding the 4 byte header. A payload longer than
+    /// `codec::MAX_PAYLOAD_LEN` doesn't fit in one packet, but a handler can respond with one
+    /// anyway, and the pipe splits it, see `codec::chunk_response`.
+    pub fn new(sequence_id: u8, payload: &[u8]) -> Self {
+        let mut bytes: Vec<u8> = Vec::with_capacity(4 + payload.len());
+        bytes.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+        bytes.pop(); // we need 3 byte length, so discard last byte
+        bytes.push(sequence_id);
+        bytes.extend_from_slice(payload);
+        Packet { bytes }
+    }
+
+    /// Create a command packet, starting a new sequence, of the command byte followed by `arg`
+    pub fn command(command: PacketType, arg: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(1 + arg.len());
+        payload.push(command.byte());
+        payload.extend_from_slice(arg);
+        Packet::new(0, &payload)
+    }
+
+    /// Create a `COM_QUERY` running `sql`
+    pub fn com_query(sql: &str) -> Self {
+        Packet::command(PacketType::ComQuery, sql.as_bytes())
+    }
+
+    /// Create a `COM_INIT_DB` changing the default schema to `schema`
+    pub fn com_init_db(schema: &str) -> Self {
+        Packet::command(PacketType::ComInitDb, schema.as_bytes())
+    }
+
+    /// Create a `COM_PING`
+    pub fn com_ping() -> Self {
+        Packet::command(PacketType::ComPing, &[])
+    }
+
+    /// Create a `COM_QUIT`
+    pub fn com_quit() -> Self {
+        Packet::command(PacketType::ComQuit, &[])
+    }
+
+    /// Create a `COM_RESET_CONNECTION`
+    pub fn com_reset_connection() -> Self {
+        Packet::command(PacketType::ComResetConnection, &[])
+    }
+
     /// Create an error packet
     pub fn error_packet(code: u16, state: [u8; 5], msg: String) -> Self {
 
@@ -57,211 +259,1150 @@ impl Packet {
         payload.extend_from_slice(&state); // SQL STATE
         payload.extend_from_slice(msg.as_bytes());
 
-        // create header with length and sequence id
-        let mut header: Vec<u8> = Vec::with_capacity(4 + 9 + msg.len());
-        header.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
-        header.pop(); // we need 3 byte length, so discard last byte
-        header.push(1); // sequence_id
-
-        // combine the vectors
-        header.extend_from_slice(&payload);
-
-        // now move the vector into the packet
-        Packet { bytes: header }
+        Packet::new(1, &payload)
     }
 
     pub fn sequence_id(&self) -> u8 {
         self.bytes[3]
     }
 
-    /// Determine the type of packet
+    /// Return a copy of this packet with a different sequence id
+    pub fn with_sequence_id(mut self, sequence_id: u8) -> Self {
+        self.bytes[3] = sequence_id;
+        self
+    }
+
+    /// The packet payload, without the 4 byte header
+    pub fn payload(&self) -> &[u8] {
+        &self.bytes[4..]
+    }
+
+    /// Determine the type of packet, which fails only if it has no payload
     pub fn packet_type(&self) -> Result<PacketType, Error> {
-        match self.bytes[4] {
-            0x00 => Ok(PacketType::ComSleep),
-            0x01 => Ok(PacketType::ComQuit),
-            0x02 => Ok(PacketType::ComInitDb),
-            0x03 => Ok(PacketType::ComQuery),
-            0x04 => Ok(PacketType::ComFieldList),
-            0x05 => Ok(PacketType::ComCreateDb),
-            0x06 => Ok(PacketType::ComDropDb),
-            0x07 => Ok(PacketType::ComRefresh),
-            0x08 => Ok(PacketType::ComShutdown),
-            0x09 => Ok(PacketType::ComStatistics),
-            0x0a => Ok(PacketType::ComProcessInfo),
-            0x0b => Ok(PacketType::ComConnect),
-            0x0c => Ok(PacketType::ComProcessKill),
-            0x0d => Ok(PacketType::ComDebug),
-            0x0e => Ok(PacketType::ComPing),
-            0x0f => Ok(PacketType::ComTime),
-            0x10 => Ok(PacketType::ComDelayedInsert),
-            0x11 => Ok(PacketType::ComChangeUser),
-            0x12 => Ok(PacketType::ComBinlogDump),
-            0x13 => Ok(PacketType::ComTableDump),
-            0x14 => Ok(PacketType::ComConnectOut),
-            0x15 => Ok(PacketType::ComRegisterSlave),
-            0x16 => Ok(PacketType::ComStmtPrepare),
-            0x17 => Ok(PacketType::ComStmtExecute),
-            0x18 => Ok(PacketType::ComStmtSendLongData),
-            0x19 => Ok(PacketType::ComStmtClose),
-            0x1a => Ok(PacketType::ComStmtReset),
-            0x1d => Ok(PacketType::ComDaemon),
-            0x1e => Ok(PacketType::ComBinlogDumpGtid),
-            0x1f => Ok(PacketType::ComResetConnection),
-            _ => Err(Error::new(ErrorKind::Other, "Invalid packet type"))
-        }
-    }
-
-}
-
-#[derive(Copy,Clone)]
+        match self.bytes.get(4) {
+            Some(&command) => Ok(PacketType::from_u8(command)),
+            None => Err(Error::other("Empty packet")),
+        }
+    }
+
+}
+
+/// The phase of the MySQL protocol that a connection is in
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ConnectionPhase {
+    /// server greeting, handshake response and any auth switch exchanges
+    Handshake,
+    /// the client is authenticated and is sending commands
+    Command,
+}
+
+/// Follows the packets flowing through a connection to determine the current phase
+#[derive(Debug)]
+pub struct PhaseTracker {
+    phase: ConnectionPhase,
+}
+
+impl Default for PhaseTracker {
+    fn default() -> Self {
+        PhaseTracker::new()
+    }
+}
+
+impl PhaseTracker {
+
+    pub fn new() -> Self {
+        PhaseTracker { phase: ConnectionPhase::Handshake }
+    }
+
+    pub fn phase(&self) -> ConnectionPhase {
+        self.phase
+    }
+
+    /// Observe a packet sent by the client. Commands always start a new sequence, which never
+    /// happens during the handshake.
+    pub fn observe_request(&mut self, p: &Packet) {
+        if p.sequence_id() == 0 {
+            self.phase = ConnectionPhase::Command;
+        }
+    }
+
+    /// Observe a packet sent by the server. An OK packet that isn't the server greeting
+    /// completes authentication.
+    pub fn observe_response(&mut self, p: &Packet) {
+        if self.phase == ConnectionPhase::Handshake && p.sequence_id() > 0
+            && p.bytes.len() > 4 && p.bytes[4] == 0x00 {
+            self.phase = ConnectionPhase::Command;
+        }
+    }
+}
+
+/// The command a client packet starts. New servers add commands, so matches need a wildcard.
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+#[non_exhaustive]
 pub enum PacketType {
-    ComSleep = 0x00,
-    ComQuit = 0x01,
-    ComInitDb = 0x02,
-    ComQuery = 0x03,
-    ComFieldList = 0x04,
-    ComCreateDb = 0x05,
-    ComDropDb = 0x06,
-    ComRefresh = 0x07,
-    ComShutdown = 0x08,
-    ComStatistics = 0x09,
-    ComProcessInfo = 0x0a,
-    ComConnect = 0x0b,
-    ComProcessKill= 0x0c,
-    ComDebug = 0x0d,
-    ComPing = 0x0e,
-    ComTime = 0x0f,
-    ComDelayedInsert = 0x10,
-    ComChangeUser = 0x11,
-    ComBinlogDump = 0x12,
-    ComTableDump = 0x13,
-    ComConnectOut = 0x14,
-    ComRegisterSlave = 0x15,
-    ComStmtPrepare = 0x16,
-    ComStmtExecute = 0x17,
-    ComStmtSendLongData = 0x18,
-    ComStmtClose = 0x19,
-    ComStmtReset = 0x1a,
-    ComDaemon= 0x1d,
-    ComBinlogDumpGtid = 0x1e,
-    ComResetConnection = 0x1f,
-}
-
-
-/// Wrapper for TcpStream with some built-in buffering
+    ComSleep,
+    ComQuit,
+    ComInitDb,
+    ComQuery,
+    ComFieldList,
+    ComCreateDb,
+    ComDropDb,
+    ComRefresh,
+    ComShutdown,
+    ComStatistics,
+    ComProcessInfo,
+    ComConnect,
+    ComProcessKill,
+    ComDebug,
+    ComPing,
+    ComTime,
+    ComDelayedInsert,
+    ComChangeUser,
+    ComBinlogDump,
+    ComTableDump,
+    ComConnectOut,
+    ComRegisterSlave,
+    ComStmtPrepare,
+    ComStmtExecute,
+    ComStmtSendLongData,
+    ComStmtClose,
+    ComStmtReset,
+    ComSetOption,
+    ComStmtFetch,
+    ComDaemon,
+    ComBinlogDumpGtid,
+    ComResetConnection,
+    /// MySQL 8.0's clone plugin taking over the connection
+    ComClone,
+    /// MySQL 8.0's group replication streaming changes to a subscriber
+    ComSubscribeGroupReplicationStream,
+    /// MariaDB's execution of a prepared statement for many rows of parameters
+    ComStmtBulkExecute,
+    /// a command byte the proxy doesn't know, such as one added by a newer server
+    Unknown(u8),
+}
+
+impl PacketType {
+
+    /// The command a packet's first payload byte stands for
+    pub fn from_u8(command: u8) -> Self {
+        match command {
+            0x00 => PacketType::ComSleep,
+            0x01 => PacketType::ComQuit,
+            0x02 => PacketType::ComInitDb,
+            0x03 => PacketType::ComQuery,
+            0x04 => PacketType::ComFieldList,
+            0x05 => PacketType::ComCreateDb,
+            0x06 => PacketType::ComDropDb,
+            0x07 => PacketType::ComRefresh,
+            0x08 => PacketType::ComShutdown,
+            0x09 => PacketType::ComStatistics,
+            0x0a => PacketType::ComProcessInfo,
+            0x0b => PacketType::ComConnect,
+            0x0c => PacketType::ComProcessKill,
+            0x0d => PacketType::ComDebug,
+            0x0e => PacketType::ComPing,
+            0x0f => PacketType::ComTime,
+            0x10 => PacketType::ComDelayedInsert,
+            0x11 => PacketType::ComChangeUser,
+            0x12 => PacketType::ComBinlogDump,
+            0x13 => PacketType::ComTableDump,
+            0x14 => PacketType::ComConnectOut,
+            0x15 => PacketType::ComRegisterSlave,
+            0x16 => PacketType::ComStmtPrepare,
+            0x17 => PacketType::ComStmtExecute,
+            0x18 => PacketType::ComStmtSendLongData,
+            0x19 => PacketType::ComStmtClose,
+            0x1a => PacketType::ComStmtReset,
+            0x1b => PacketType::ComSetOption,
+            0x1c => PacketType::ComStmtFetch,
+            0x1d => PacketType::ComDaemon,
+            0x1e => PacketType::ComBinlogDumpGtid,
+            0x1f => PacketType::ComResetConnection,
+            0x20 => PacketType::ComClone,
+            0x21 => PacketType::ComSubscribeGroupReplicationStream,
+            0xfa => PacketType::ComStmtBulkExecute,
+            other => PacketType::Unknown(other),
+        }
+    }
+
+    /// The first payload byte of a packet of this type
+    pub fn byte(self) -> u8 {
+        match self {
+            PacketType::ComSleep => 0x00,
+            PacketType::ComQuit => 0x01,
+            PacketType::ComInitDb => 0x02,
+            PacketType::ComQuery => 0x03,
+            PacketType::ComFieldList => 0x04,
+            PacketType::ComCreateDb => 0x05,
+            PacketType::ComDropDb => 0x06,
+            PacketType::ComRefresh => 0x07,
+            PacketType::ComShutdown => 0x08,
+            PacketType::ComStatistics => 0x09,
+            PacketType::ComProcessInfo => 0x0a,
+            PacketType::ComConnect => 0x0b,
+            PacketType::ComProcessKill => 0x0c,
+            PacketType::ComDebug => 0x0d,
+            PacketType::ComPing => 0x0e,
+            PacketType::ComTime => 0x0f,
+            PacketType::ComDelayedInsert => 0x10,
+            PacketType::ComChangeUser => 0x11,
+            PacketType::ComBinlogDump => 0x12,
+            PacketType::ComTableDump => 0x13,
+            PacketType::ComConnectOut => 0x14,
+            PacketType::ComRegisterSlave => 0x15,
+            PacketType::ComStmtPrepare => 0x16,
+            PacketType::ComStmtExecute => 0x17,
+            PacketType::ComStmtSendLongData => 0x18,
+            PacketType::ComStmtClose => 0x19,
+            PacketType::ComStmtReset => 0x1a,
+            PacketType::ComSetOption => 0x1b,
+            PacketType::ComStmtFetch => 0x1c,
+            PacketType::ComDaemon => 0x1d,
+            PacketType::ComBinlogDumpGtid => 0x1e,
+            PacketType::ComResetConnection => 0x1f,
+            PacketType::ComClone => 0x20,
+            PacketType::ComSubscribeGroupReplicationStream => 0x21,
+            PacketType::ComStmtBulkExecute => 0xfa,
+            PacketType::Unknown(command) => command,
+        }
+    }
+
+    /// The name MySQL gives the command, e.g. `COM_QUERY`, or `COM_UNKNOWN` for one it doesn't
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PacketType::ComSleep => "COM_SLEEP",
+            PacketType::ComQuit => "COM_QUIT",
+            PacketType::ComInitDb => "COM_INIT_DB",
+            PacketType::ComQuery => "COM_QUERY",
+            PacketType::ComFieldList => "COM_FIELD_LIST",
+            PacketType::ComCreateDb => "COM_CREATE_DB",
+            PacketType::ComDropDb => "COM_DROP_DB",
+            PacketType::ComRefresh => "COM_REFRESH",
+            PacketType::ComShutdown => "COM_SHUTDOWN",
+            PacketType::ComStatistics => "COM_STATISTICS",
+            PacketType::ComProcessInfo => "COM_PROCESS_INFO",
+            PacketType::ComConnect => "COM_CONNECT",
+            PacketType::ComProcessKill => "COM_PROCESS_KILL",
+            PacketType::ComDebug => "COM_DEBUG",
+            PacketType::ComPing => "COM_PING",
+            PacketType::ComTime => "COM_TIME",
+            PacketType::ComDelayedInsert => "COM_DELAYED_INSERT",
+            PacketType::ComChangeUser => "COM_CHANGE_USER",
+            PacketType::ComBinlogDump => "COM_BINLOG_DUMP",
+            PacketType::ComTableDump => "COM_TABLE_DUMP",
+            PacketType::ComConnectOut => "COM_CONNECT_OUT",
+            PacketType::ComRegisterSlave => "COM_REGISTER_SLAVE",
+            PacketType::ComStmtPrepare => "COM_STMT_PREPARE",
+            PacketType::ComStmtExecute => "COM_STMT_EXECUTE",
+            PacketType::ComStmtSendLongData => "COM_STMT_SEND_LONG_DATA",
+            PacketType::ComStmtClose => "COM_STMT_CLOSE",
+            PacketType::ComStmtReset => "COM_STMT_RESET",
+            PacketType::ComSetOption => "COM_SET_OPTION",
+            PacketType::ComStmtFetch => "COM_STMT_FETCH",
+            PacketType::ComDaemon => "COM_DAEMON",
+            PacketType::ComBinlogDumpGtid => "COM_BINLOG_DUMP_GTID",
+            PacketType::ComResetConnection => "COM_RESET_CONNECTION",
+            PacketType::ComClone => "COM_CLONE",
+            PacketType::ComSubscribeGroupReplicationStream => "COM_SUBSCRIBE_GROUP_REPLICATION_STREAM",
+            PacketType::ComStmtBulkExecute => "COM_STMT_BULK_EXECUTE",
+            PacketType::Unknown(_) => "COM_UNKNOWN",
+        }
+    }
+}
+
+/// The command's name, with the byte of a command the proxy doesn't know, e.g. `COM_UNKNOWN(0x40)`
+impl fmt::Display for PacketType {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketType::Unknown(command) => write!(f, "COM_UNKNOWN(0x{:02x})", command),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// A connection that a `Pipe` can relay packets over
+pub trait Transport {
+    fn poll_read(&self) -> Async<()>;
+    fn poll_write(&self) -> Async<()>;
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    /// Write out anything the transport has buffered internally
+    fn flush(&self) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+
+    fn poll_read(&self) -> Async<()> {
+        TcpStream::poll_read(self)
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        TcpStream::poll_write(self)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// Any `AsyncRead + AsyncWrite` stream as a `Transport`, for relaying over a wrapper such as a
+/// metered or throttled connection, or an in-memory stream in tests. The stream registers
+/// the task for wakeup when it has nothing to read or can't be written to, as tokio's
+/// streams do.
+pub struct AsyncTransport<T> {
+    stream: RefCell<T>,
+}
+
+impl<T> AsyncTransport<T> where T: AsyncRead + AsyncWrite {
+
+    pub fn new(stream: T) -> Self {
+        AsyncTransport { stream: RefCell::new(stream) }
+    }
+}
+
+impl<T> Transport for AsyncTransport<T> where T: AsyncRead + AsyncWrite {
+
+    // readiness is only known by trying, so reads and writes may return WouldBlock
+    fn poll_read(&self) -> Async<()> {
+        Async::Ready(())
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        Async::Ready(())
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.borrow_mut().read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.borrow_mut().write(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.stream.borrow_mut().flush()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how == Shutdown::Read {
+            return Ok(());
+        }
+        match AsyncWrite::shutdown(&mut *self.stream.borrow_mut())? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// Wrapper for a Transport with some built-in buffering
 struct ConnReader {
-    stream: Rc<TcpStream>,
-    packet_buf: Vec<u8>,
+    stream: Rc<dyn Transport>,
+    codec: MySqlPacketCodec,
+    packet_buf: BytesMut,
     read_buf: Vec<u8>,
+    pool: Option<pool::BufferPool>,
+    decompressor: Option<Decompressor>,
 }
 
-/// Wrapper for TcpStream with some built-in buffering
+/// Bytes a `ConnWriter` buffers before further packets wait as they are, so a large response
+/// is copied into the buffer as the socket takes it rather than all at once
+const MAX_WRITE_BUFFER: usize = 1 << 20;
+
+/// Wrapper for a Transport with some built-in buffering
 struct ConnWriter {
-    stream: Rc<TcpStream>,
-    write_buf: Vec<u8>,
+    stream: Rc<dyn Transport>,
+    codec: MySqlPacketCodec,
+    write_buf: BytesMut,
+    /// packets waiting for `write_buf` to drain below `MAX_WRITE_BUFFER`
+    queued: VecDeque<Packet>,
+    compressor: Option<Compressor>,
+    /// the contents of `write_buf` once compressed
+    compressed_buf: BytesMut,
+    flush: FlushControl,
 }
 
 impl ConnReader {
 
-    fn new(stream: Rc<TcpStream>) -> Self {
+    fn new(stream: Rc<dyn Transport>) -> Self {
         ConnReader {
-            stream: stream,
-            packet_buf: Vec::with_capacity(4096),
-            read_buf: vec![0_u8; 4096]
+            stream,
+            codec: MySqlPacketCodec::new(),
+            packet_buf: BytesMut::with_capacity(4096),
+            read_buf: vec![0_u8; 4096],
+            pool: None,
+            decompressor: None,
         }
     }
 
-    /// Read from the socket until the status is NotReady
-    fn read(&mut self) -> Poll<(), io::Error> {
+    fn use_pool(&mut self, pool: &pool::BufferPool) {
+        let mut read_buf = pool.acquire(pool.buffer_size());
+        read_buf.resize(read_buf.capacity(), 0);
+        self.read_buf = read_buf;
+        self.codec = MySqlPacketCodec::with_pool(pool.clone());
+        self.pool = Some(pool.clone());
+    }
+
+    /// Read from the socket until the status is NotReady, or the poll's byte budget is used up
+    fn read(&mut self, work: &mut Work) -> Poll<(), io::Error> {
         debug!("read()");
         loop {
+            let limit = work.remaining_bytes().min(self.read_buf.len());
+            if limit == 0 {
+                return Ok(Async::Ready(()));
+            }
             match self.stream.poll_read() {
                 Async::Ready(_) => {
-                    let n = try_nb!((&*self.stream).read(&mut self.read_buf[..]));
+                    let n = try_nb!(self.stream.read(&mut self.read_buf[..limit]));
                     if n == 0 {
-                        return Err(Error::new(ErrorKind::Other, "connection closed"));
+                        return Err(Error::other("connection closed"));
+                    }
+                    work.add_bytes(n);
+                    match self.decompressor {
+                        Some(ref mut decompressor) => decompressor.decompress(&self.read_buf[0..n], &mut self.packet_buf)?,
+                        None => self.packet_buf.extend_from_slice(&self.read_buf[0..n]),
                     }
-                    self.packet_buf.extend_from_slice(&self.read_buf[0..n]);
                 },
                 _ => return Ok(Async::NotReady),
             }
         }
     }
 
+    /// Read from a new stream, dropping anything left over from the old one
+    fn reconnect(&mut self, stream: Rc<dyn Transport>) {
+        self.stream = stream;
+        self.packet_buf.clear();
+        self.codec = match self.pool {
+            Some(ref pool) => MySqlPacketCodec::with_pool(pool.clone()),
+            None => MySqlPacketCodec::new(),
+        };
+    }
+
     fn next(&mut self) -> Option<Packet> {
         debug!("next()");
-        // do we have a header
-        if self.packet_buf.len() > 3 {
-            let l = parse_packet_length(&self.packet_buf);
-            // do we have the whole packet?
-            let s = 4 + l;
-            if self.packet_buf.len() >= s {
-                let p = Packet { bytes: self.packet_buf.drain(0..s).collect() };
-                Some(p)
-            } else {
-                None
-            }
-        } else {
-            None
+        // decoding a packet can't fail, it can only be incomplete
+        self.codec.decode(&mut self.packet_buf).unwrap_or(None)
+    }
+}
+
+impl Drop for ConnReader {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.release(mem::take(&mut self.read_buf));
         }
     }
 }
 
 impl ConnWriter {
 
-    fn new(stream: Rc<TcpStream>) -> Self {
+    fn new(stream: Rc<dyn Transport>) -> Self {
         ConnWriter{
-            stream: stream,
-            write_buf: Vec::with_capacity(4096),
+            stream,
+            codec: MySqlPacketCodec::new(),
+            write_buf: BytesMut::with_capacity(4096),
+            queued: VecDeque::new(),
+            compressor: None,
+            compressed_buf: BytesMut::new(),
+            flush: FlushControl::default(),
         }
     }
 
-    /// Write a packet to the write buffer
-    fn push(&mut self, p: &Packet) {
-        //        debug!("push() capacity: {} position: {} packet_size: {}",
-        //               self.write_buf.capacity(), self.write_pos, p.bytes.len());
-
-        self.write_buf.extend_from_slice(&p.bytes);
+    /// Write a packet to the write buffer, or queue it behind others while the buffer is full
+    fn push(&mut self, p: Packet) {
+        if !self.queued.is_empty() || self.write_buf.len() >= MAX_WRITE_BUFFER {
+            self.queued.push_back(p);
+            return;
+        }
+        // encoding only copies the packet into the buffer, so it can't fail
+        let _ = self.codec.encode(p, &mut self.write_buf);
         debug!("end push()");
     }
 
-    /// Writes the contents of the write buffer to the socket
+    /// Write to a new stream, dropping anything not yet written to the old one
+    fn reconnect(&mut self, stream: Rc<dyn Transport>) {
+        self.stream = stream;
+        self.write_buf.clear();
+        self.queued.clear();
+        self.compressed_buf.clear();
+        self.flush.written();
+    }
+
+    /// Drop everything buffered and queued, for a peer that has gone
+    fn discard(&mut self) {
+        self.write_buf.clear();
+        self.queued.clear();
+        self.compressed_buf.clear();
+        self.flush.written();
+    }
+
+    /// Whether nothing is buffered or queued
+    fn is_empty(&self) -> bool {
+        self.write_buf.is_empty() && self.queued.is_empty() && self.compressed_buf.is_empty()
+    }
+
+    /// Writes the buffered and queued packets to the socket, refilling the buffer from the
+    /// queue as it drains
     fn write(&mut self) -> Poll<(), io::Error> {
+        loop {
+            while self.write_buf.len() < MAX_WRITE_BUFFER {
+                match self.queued.pop_front() {
+                    Some(p) => {
+                        let _ = self.codec.encode(p, &mut self.write_buf);
+                    },
+                    None => break,
+                }
+            }
+            try_ready!(self.write_buffered());
+            // what was due stays due until the queue behind it is written too
+            if self.queued.is_empty() {
+                self.flush.written();
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+
+    /// Writes the contents of the write buffer to the socket
+    fn write_buffered(&mut self) -> Poll<(), io::Error> {
         debug!("write()");
-        while self.write_buf.len() > 0 {
+        if !self.flush.due(self.write_buf.len() + self.compressed_buf.len())? {
+            return Ok(Async::NotReady);
+        }
+        let pending = match self.compressor {
+            Some(ref compressor) => {
+                if !self.write_buf.is_empty() {
+                    compressor.compress(&self.write_buf.take(), &mut self.compressed_buf);
+                }
+                &mut self.compressed_buf
+            },
+            None => &mut self.write_buf,
+        };
+        while !pending.is_empty() {
             match self.stream.poll_write() {
                 Async::Ready(_) => {
-                    let s = try!((&*self.stream).write(&self.write_buf[..]));
-                    let _ : Vec<u8> = self.write_buf.drain(0..s).collect();
+                    let s = try_nb!(self.stream.write(&pending[..]));
+                    pending.split_to(s);
                 },
                 _ => return Ok(Async::NotReady)
             }
         }
-        return Ok(Async::Ready(()));
+        try_nb!(self.stream.flush());
+        Ok(Async::Ready(()))
+    }
+}
+
+/// How much a `HalfPipe` has relayed
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct HalfPipeStats {
+    /// packets read from the source and handed on, with their headers
+    pub packets_read: u64,
+    pub bytes_read: u64,
+    /// packets written to the destination, with their headers
+    pub packets_written: u64,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug,Default)]
+struct HalfPipeState {
+    paused: bool,
+    /// the task to wake when the half pipe is resumed
+    task: Option<Task>,
+    stats: HalfPipeStats,
+}
+
+/// Pauses, resumes and reads the counters of a `HalfPipe` while it runs. Clones control the
+/// same half pipe.
+#[derive(Clone,Debug,Default)]
+pub struct HalfPipeControl {
+    state: Rc<RefCell<HalfPipeState>>,
+}
+
+impl HalfPipeControl {
+
+    /// Stop reading from the source, and handing on packets already read, until resumed
+    pub fn pause(&self) {
+        self.state.borrow_mut().paused = true;
+    }
+
+    pub fn resume(&self) {
+        let task = {
+            let mut state = self.state.borrow_mut();
+            state.paused = false;
+            state.task.take()
+        };
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.borrow().paused
+    }
+
+    pub fn stats(&self) -> HalfPipeStats {
+        self.state.borrow().stats
     }
 }
 
+/// One direction of a `Pipe`: packets read from one transport and written to another.
+///
+/// A pipe relays requests from the client to the server with one half pipe, and responses
+/// from the server to the client with the other, and holds what the two share: the handler,
+/// the connection's phase and the commands awaiting responses. A half pipe can also be
+/// driven on its own with `relay`, e.g. to test a transport or codec in one direction.
+/// Paused, it neither reads from its source nor hands on what it has read, while packets
+/// pushed to it are still written to the destination.
+pub struct HalfPipe {
+    reader: ConnReader,
+    writer: ConnWriter,
+    control: HalfPipeControl,
+}
+
+impl HalfPipe {
+
+    pub fn new(from: Rc<dyn Transport>, to: Rc<dyn Transport>) -> Self {
+        HalfPipe { reader: ConnReader::new(from), writer: ConnWriter::new(to), control: HalfPipeControl::default() }
+    }
+
+    /// A handle to pause and resume this half pipe and read its counters
+    pub fn control(&self) -> HalfPipeControl {
+        self.control.clone()
+    }
+
+    /// Read from the source until it would block or the poll's byte budget is used up
+    pub fn read(&mut self, work: &mut Work) -> Poll<(), Error> {
+        {
+            let mut state = self.control.state.borrow_mut();
+            if state.paused {
+                state.task = Some(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+        let _stage = profile::enter(Stage::Reader);
+        self.reader.read(work)
+    }
+
+    /// The next complete packet read from the source, unless paused
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        if self.control.is_paused() {
+            return None;
+        }
+        let p = {
+            let _stage = profile::enter(Stage::Codec);
+            self.reader.next()?
+        };
+        let mut state = self.control.state.borrow_mut();
+        state.stats.packets_read += 1;
+        state.stats.bytes_read += p.bytes.len() as u64;
+        Some(p)
+    }
+
+    /// Whether nothing is half read from the source or waiting to be written
+    pub fn is_idle(&self) -> bool {
+        self.reader.packet_buf.is_empty() && self.writer.is_empty()
+    }
+
+    /// Queue a packet to be written to the destination
+    pub fn push(&mut self, p: Packet) {
+        {
+            let mut state = self.control.state.borrow_mut();
+            state.stats.packets_written += 1;
+            state.stats.bytes_written += p.bytes.len() as u64;
+        }
+        self.writer.push(p);
+    }
+
+    /// Write what has been queued to the destination, until it would block
+    pub fn write(&mut self) -> Poll<(), Error> {
+        let _stage = profile::enter(Stage::Writer);
+        self.writer.write()
+    }
+
+    /// Pass every packet read from the source on to the destination unchanged, until either
+    /// would block or the budget is used up
+    pub fn relay(&mut self, work: &mut Work) -> Poll<(), Error> {
+        let read = self.read(work);
+        while !work.exhausted() {
+            match self.next_packet() {
+                Some(p) => {
+                    work.add_packet();
+                    self.push(p);
+                },
+                None => break,
+            }
+        }
+        let write = self.write();
+        if work.exhausted() {
+            read?;
+            write?;
+            return work.yield_now();
+        }
+        try_ready!(read);
+        write
+    }
+}
+
+/// Relays a session between a client and a server through a `PacketHandler`
 pub struct Pipe<H: PacketHandler + 'static> {
-    client_reader: ConnReader,
-    client_writer: ConnWriter,
-    server_reader: ConnReader,
-    server_writer: ConnWriter,
+    /// from the client to the server
+    requests: HalfPipe,
+    /// from the server to the client
+    responses: HalfPipe,
     handler: H,
+    phase: PhaseTracker,
+    /// the failover window, and the primary the session's server connection is to
+    failover: Option<(failover::FailoverWindow, SocketAddr)>,
+    budget: PollBudget,
+    usage: Usage,
+    correlator: Correlator,
+    /// responses from the handler, waiting for the responses to earlier commands
+    held: HeldResponses,
+    retry: Option<DeadlockRetry>,
+    idle: Option<IdleTransactionGuard>,
+    lifetime: Option<LifetimeGuard>,
+    timing: Option<ExchangeTimer>,
+    coalescing: Option<SessionCoalescing>,
+    metadata_cache: Option<SessionMetadataCache>,
+    priming: Option<SessionPriming>,
+    resume: Option<SessionResume>,
+    checks: Option<ProtocolChecks>,
+    /// how long to keep reading the responses in flight once the client has gone
+    drain: Option<(Duration, Handle)>,
+    /// the error the client's connection ended with, while its responses in flight are read
+    draining: Option<(Timeout, Error)>,
 }
 
 impl<H> Pipe<H> where H: PacketHandler + 'static {
-    pub fn new(client: Rc<TcpStream>,
-               server: Rc<TcpStream>,
-               handler: H
-    ) -> Pipe<H> {
+    pub fn new<C, S>(client: Rc<C>,
+                     server: Rc<S>,
+                     handler: H
+    ) -> Pipe<H> where C: Transport + 'static, S: Transport + 'static {
 
+        let client: Rc<dyn Transport> = client;
+        let server: Rc<dyn Transport> = server;
         Pipe {
-            client_reader: ConnReader::new(client.clone()),
-            client_writer: ConnWriter::new(client),
-            server_reader: ConnReader::new(server.clone()),
-            server_writer: ConnWriter::new(server),
-            handler: handler,
+            requests: HalfPipe::new(client.clone(), server.clone()),
+            responses: HalfPipe::new(server, client),
+            handler,
+            phase: PhaseTracker::new(),
+            failover: None,
+            budget: PollBudget::default(),
+            usage: Usage::default(),
+            correlator: Correlator::default(),
+            held: HeldResponses::default(),
+            retry: None,
+            idle: None,
+            lifetime: None,
+            timing: None,
+            coalescing: None,
+            metadata_cache: None,
+            priming: None,
+            resume: None,
+            checks: None,
+            drain: None,
+            draining: None,
+        }
+    }
+
+    /// Relay between any two streams, e.g. wrappers around the sockets the proxy accepted and
+    /// connected, rather than transports the crate knows about
+    pub fn from_streams<C, S>(client: C, server: S, handler: H) -> Pipe<H>
+        where C: AsyncRead + AsyncWrite + 'static, S: AsyncRead + AsyncWrite + 'static {
+        Pipe::new(Rc::new(AsyncTransport::new(client)), Rc::new(AsyncTransport::new(server)), handler)
+    }
+
+    /// Recycle packet and read buffers through a pool shared with other connections
+    pub fn with_buffer_pool(mut self, pool: &pool::BufferPool) -> Self {
+        self.requests.reader.use_pool(pool);
+        self.responses.reader.use_pool(pool);
+        self.responses.writer.codec = MySqlPacketCodec::with_pool(pool.clone());
+        self.requests.writer.codec = MySqlPacketCodec::with_pool(pool.clone());
+        self
+    }
+
+    /// Use the compressed protocol with the client, which asked for it during the handshake
+    pub fn with_client_compression(mut self, config: &CompressionConfig) -> Self {
+        let sequence = CompressedSequence::new();
+        self.requests.reader.decompressor = Some(Decompressor::new(sequence.clone()));
+        self.responses.writer.compressor = Some(Compressor::new(config, sequence));
+        self
+    }
+
+    /// Use the compressed protocol with the server, which the proxy logged in to with it
+    pub fn with_server_compression(mut self, config: &CompressionConfig) -> Self {
+        let sequence = CompressedSequence::new();
+        self.responses.reader.decompressor = Some(Decompressor::new(sequence.clone()));
+        self.requests.writer.compressor = Some(Compressor::new(config, sequence));
+        self
+    }
+
+    /// Hold what's queued for either socket as `policy` says, so fewer writes carry it
+    pub fn with_flush_policy(mut self, policy: FlushPolicy, handle: &Handle) -> Self {
+        let requests = match policy {
+            FlushPolicy::PerResponse { .. } => FlushPolicy::Immediate,
+            policy => policy,
+        };
+        self.requests.writer.flush = FlushControl::new(requests, handle);
+        self.responses.writer.flush = FlushControl::new(policy, handle);
+        self
+    }
+
+    /// Limit the work done in each poll, so other connections on the reactor get a turn
+    pub fn with_budget(mut self, budget: PollBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// The work this pipe has done, which can be read while it runs
+    pub fn usage(&self) -> Usage {
+        self.usage.clone()
+    }
+
+    /// Pauses and counts the requests from the client to the server
+    pub fn requests(&self) -> HalfPipeControl {
+        self.requests.control()
+    }
+
+    /// Pauses and counts the responses from the server to the client
+    pub fn responses(&self) -> HalfPipeControl {
+        self.responses.control()
+    }
+
+    /// Relax assumptions about the server's responses, for servers such as ClickHouse, Doris,
+    /// TiDB or Vitess that only approximate the MySQL protocol
+    pub fn with_tolerant_backend(mut self, tolerant: bool) -> Self {
+        self.correlator.set_tolerant(tolerant);
+        self
+    }
+
+    /// Follow responses as sent to a server logged in to with `capability_flags`, for
+    /// connections whose handshake the proxy completed before the pipe started
+    pub fn with_backend_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    /// Retry autocommit statements that fail with a deadlock or lock wait timeout
+    pub fn with_deadlock_retry(mut self, policy: RetryPolicy, handle: &Handle) -> Self {
+        self.retry = Some(DeadlockRetry::new(policy, handle));
+        self
+    }
+
+    /// Kill the session, or roll back its transaction, when it leaves a transaction idle
+    pub fn with_idle_transaction_guard(mut self, guard: IdleTransactionGuard) -> Self {
+        self.idle = Some(guard);
+        self
+    }
+
+    /// Close the session between statements once it has lived, or idled, for too long
+    pub fn with_lifetime_guard(mut self, guard: LifetimeGuard) -> Self {
+        self.lifetime = Some(guard);
+        self
+    }
+
+    /// Time how long each exchange spends in the proxy, at the backend and on the way back
+    pub fn with_exchange_timing(mut self, timer: ExchangeTimer) -> Self {
+        self.timing = Some(timer);
+        self
+    }
+
+    /// Share the results of reads with other sessions sending the same query at the same time
+    pub fn with_coalescing(mut self, coalescing: SessionCoalescing) -> Self {
+        self.coalescing = Some(coalescing);
+        self
+    }
+
+    /// Answer frequent metadata queries with results kept from earlier sessions
+    pub fn with_metadata_cache(mut self, cache: SessionMetadataCache) -> Self {
+        self.metadata_cache = Some(cache);
+        self
+    }
+
+    /// Send the statements the user's sessions start with as soon as the session starts, and
+    /// answer the client's with their responses
+    pub fn with_session_priming(mut self, priming: SessionPriming) -> Self {
+        self.priming = Some(priming);
+        self
+    }
+
+    /// Log in to another backend and restore the session's state if the server connection
+    /// is lost while the session is idle outside a transaction
+    pub fn with_session_resume(mut self, resume: SessionResume) -> Self {
+        self.resume = Some(resume);
+        self
+    }
+
+    /// Check the packets read from either side for protocol anomalies, and ask the handler
+    /// what to do about them
+    pub fn with_protocol_checks(mut self, checks: ProtocolChecks) -> Self {
+        self.checks = Some(checks);
+        self
+    }
+
+    /// Once the client disconnects, keep reading the responses to its commands in flight for
+    /// up to `timeout`, so the handler sees them complete, before closing the server connection
+    pub fn with_drain_on_close(mut self, timeout: Duration, handle: &Handle) -> Self {
+        self.drain = Some((timeout, handle.clone()));
+        self
+    }
+
+    /// Hold client statements while the failover window is open. Once it closes with a new
+    /// primary, the session is moved there with its session resume, whose reconnect must log
+    /// in to the window's primary, before the statements are sent. Sessions that can't be
+    /// resumed are closed rather than have their statements sent to the old primary.
+    pub fn with_failover(mut self, window: failover::FailoverWindow) -> Self {
+        let primary = window.primary();
+        self.failover = Some((window, primary));
+        self
+    }
+
+    /// Send the handler's response to a command once the commands before it have been
+    /// answered, so pipelined commands get their responses in order
+    fn respond(&mut self, packets: Vec<Packet>) {
+        for p in self.held.respond(&self.correlator, codec::chunk_response(packets)) {
+            self.responses.push(p);
+        }
+        self.responses.writer.flush.complete();
+    }
+
+    /// Send a command on to the server, unless the session follows another's flight for it
+    fn forward(&mut self, request: Packet) {
+        let issued = self.correlator.issued();
+        match self.priming.as_mut().map(|p| p.request(&request, issued)) {
+            Some(Primed::Claimed(responses)) => {
+                for (response, answered, command) in responses {
+                    self.pass_response(response, Some(answered), command);
+                }
+                return;
+            },
+            Some(Primed::Undo(commands)) => {
+                for command in commands {
+                    self.send_own(command);
+                }
+            },
+            Some(Primed::Send) | None => {},
+        }
+        let request = match self.resume {
+            Some(ref mut resume) => resume.request(request),
+            None => request,
+        };
+        let (depth, issued) = (self.correlator.depth(), self.correlator.issued());
+        // answer metadata queries whose results are kept as if the server had
+        if let Some(packets) = self.metadata_cache.as_mut().and_then(|c| c.request(&request, depth, issued)) {
+            self.correlator.request(&request);
+            for response in packets {
+                self.process_response(response);
+            }
+            return;
+        }
+        if let Some(ref mut coalescing) = self.coalescing {
+            if coalescing.request(&request, self.correlator.depth(), self.correlator.issued()) {
+                self.correlator.request(&request);
+                return;
+            }
+        }
+        if let Some(ref mut retry) = self.retry {
+            retry.request(&request, self.correlator.depth());
+        }
+        self.correlator.request(&request);
+        self.requests.push(request);
+        if let Some(ref mut timing) = self.timing {
+            if self.correlator.issued() > issued {
+                timing.request(self.correlator.issued());
+            }
+        }
+    }
+
+    /// Send a command of the proxy's own to the server
+    fn send_own(&mut self, command: Packet) {
+        let command = match self.resume {
+            Some(ref mut resume) => resume.request(command),
+            None => command,
+        };
+        self.correlator.request(&command);
+        self.requests.push(command);
+    }
+
+    /// Pass a packet from the server, or shared from another session's, to the handler
+    fn process_response(&mut self, response: Packet) {
+        self.phase.observe_response(&response);
+        let command = self.correlator.completed() + 1;
+        let answered = self.correlator.response(&response);
+        if let Some(ref mut timing) = self.timing {
+            if answered.map(|r| r.last).unwrap_or(false) {
+                timing.answered(command);
+            }
+        }
+        // the responses to the commands restoring a resumed session are the proxy's own
+        let response = match self.resume {
+            Some(ref mut resume) => match resume.response(response, answered) {
+                Some(response) => response,
+                None => return,
+            },
+            None => response,
+        };
+        // as are those to primed statements, until the client sends them
+        let response = match self.priming {
+            Some(ref mut priming) => match priming.response(response, answered, command) {
+                Some(response) => response,
+                None => {
+                    if answered.map(|r| r.last).unwrap_or(false) {
+                        self.release_held();
+                    }
+                    return;
+                },
+            },
+            None => response,
+        };
+        self.pass_response(response, answered, command);
+    }
+
+    /// Pass a response packet, answering command number `command`, on to the handler
+    fn pass_response(&mut self, response: Packet, answered: Option<ResponsePacket>, command: u64) {
+        if let Some(ref mut coalescing) = self.coalescing {
+            coalescing.response(&response, answered, command);
         }
+        if let Some(ref mut cache) = self.metadata_cache {
+            cache.response(&response, answered, command);
+        }
+        // the client only sees the error once the retries run out
+        if let Some(ref mut retry) = self.retry {
+            if retry.response(&response, answered) {
+                return;
+            }
+        }
+        if let Some(ref mut lifetime) = self.lifetime {
+            lifetime.response(&response, answered);
+        }
+        // nor the response to the proxy's own ROLLBACK
+        if let Some(ref mut idle) = self.idle {
+            if idle.response(&response, answered) {
+                return;
+            }
+        }
+        let action = {
+            let _stage = profile::enter(Stage::Handler);
+            self.handler.handle_response(&response)
+        };
+        match action {
+            Action::Drop => {},
+            Action::Forward => self.responses.push(response),
+            Action::Mutate(p2) => self.responses.push(p2),
+            Action::Respond(v) => {
+                for p in v {
+                    self.requests.push(p);
+                }
+            },
+            Action::Error { code, state, msg } => {
+                let error_packet = Packet::error_packet(code, state, msg);
+                self.responses.push(error_packet);
+            }
+        };
+        // a response the correlator doesn't follow may well be complete, so isn't held
+        if answered.map(|r| r.last).unwrap_or(true) {
+            self.responses.writer.flush.complete();
+        }
+        if answered.map(|r| r.last).unwrap_or(false) {
+            self.release_held();
+        }
+    }
+
+    /// Let the handler decide what happens to the connection after each anomaly, ending it
+    /// with an error if the handler or the checks' configuration kill it
+    fn judge(&mut self, anomalies: Vec<Anomaly>) -> Result<(), Error> {
+        for anomaly in anomalies {
+            let verdict = self.handler.handle_anomaly(&anomaly);
+            if verdict == Verdict::Kill || self.checks.as_ref().map(|c| c.kills(&anomaly)).unwrap_or(false) {
+                warn!("Killing connection after a protocol violation: {}", anomaly);
+                let _ = self.responses.writer.stream.shutdown(Shutdown::Both);
+                let _ = self.requests.writer.stream.shutdown(Shutdown::Both);
+                return Err(Error::new(io::ErrorKind::InvalidData, format!("Protocol violation: {}", anomaly)));
+            }
+            debug!("Tolerating a protocol violation: {}", anomaly);
+        }
+        Ok(())
+    }
+
+    /// Whether the session is between statements: logged in, with no command awaiting a
+    /// response or held, and nothing half read or unwritten in either direction
+    fn between_statements(&self) -> bool {
+        self.phase.phase() == ConnectionPhase::Command
+            && self.correlator.depth() == 0
+            && self.held.is_empty()
+            && !self.holding()
+            && self.requests.is_idle()
+            && self.responses.is_idle()
+    }
+
+    /// Send held responses whose preceding commands have now been answered
+    fn release_held(&mut self) {
+        for p in self.held.release(&self.correlator) {
+            self.responses.push(p);
+            self.responses.writer.flush.complete();
+        }
+    }
+
+    /// Whether client statements must stay buffered because a failover is in progress, a
+    /// statement is waiting to be retried, the proxy is rolling back an idle transaction, the
+    /// session is waiting for another's result or moving to another backend
+    fn holding(&self) -> bool {
+        if self.phase.phase() != ConnectionPhase::Command {
+            return false;
+        }
+        if self.retry.as_ref().map(|r| r.waiting()).unwrap_or(false)
+            || self.idle.as_ref().map(|i| i.rolling_back()).unwrap_or(false)
+            || self.coalescing.as_ref().map(|c| c.following()).unwrap_or(false)
+            || self.resume.as_ref().map(|r| r.resuming()).unwrap_or(false) {
+            return true;
+        }
+        match self.failover {
+            // until the session has moved to the primary a failover promoted
+            Some((ref window, primary)) => !window.poll_release().is_ready() || window.primary() != primary,
+            None => false,
+        }
+    }
+
+    /// Move the session to the primary a closed failover window promoted, once the commands
+    /// sent to the old one have been answered
+    fn follow_failover(&mut self) -> Result<(), Error> {
+        let promoted = match self.failover {
+            Some((ref window, primary)) if self.phase.phase() == ConnectionPhase::Command
+                && window.poll_release().is_ready() && window.primary() != primary => window.primary(),
+            _ => return Ok(()),
+        };
+        if self.correlator.depth() > 0 || self.resume.as_ref().map(|r| r.resuming()).unwrap_or(false) {
+            return Ok(());
+        }
+        let compressed = self.requests.writer.compressor.is_some();
+        match self.resume {
+            Some(ref mut resume) if resume.resumable(0) && !compressed => {
+                info!("Moving the session to the new primary {}", promoted);
+                resume.begin()?;
+            },
+            _ => {
+                warn!("Closing a session that can't be moved to the new primary {}", promoted);
+                let _ = self.responses.writer.stream.shutdown(Shutdown::Both);
+                let _ = self.requests.writer.stream.shutdown(Shutdown::Both);
+                return Err(Error::other(format!("The session can't follow the failover to the new primary {}", promoted)));
+            },
+        }
+        if let Some((_, ref mut primary)) = self.failover {
+            *primary = promoted;
+        }
+        Ok(())
     }
 }
 
@@ -270,79 +1411,251 @@ impl<H> Future for Pipe<H> where H: PacketHandler + 'static {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<(), Error> {
+        let mut work = self.budget.start();
+        let result = self.relay(&mut work);
+        self.usage.record(&work);
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        debug!("Pipe finished after {:?}", self.usage.stats());
+        result
+    }
+
+}
+
+impl<H> Pipe<H> where H: PacketHandler + 'static {
+
+    /// Relay packets until the sockets would block or the budget for this poll is used up
+    fn relay(&mut self, work: &mut Work) -> Poll<(), Error> {
         loop {
-            let client_read = self.client_reader.read();
+            let client_read = match self.draining {
+                Some(_) => Ok(Async::NotReady),
+                None => self.requests.read(work),
+            };
+
+            // send the statements the session is primed with before any of the client's
+            for statement in self.priming.as_mut().map(|p| p.start()).unwrap_or_default() {
+                self.send_own(statement);
+            }
+            if let Some(e) = self.priming.as_mut().and_then(|p| p.failed()) {
+                let _ = self.responses.writer.stream.shutdown(Shutdown::Both);
+                return Err(e);
+            }
 
-            // process buffered requests
-            while let Some(request) = self.client_reader.next() {
-                match self.handler.handle_request(&request) {
+            // answer a command that followed another session's with its result, or send it
+            // after all if the result isn't shared
+            match self.coalescing.as_mut().and_then(|c| c.poll_result()) {
+                Some(Ok(packets)) => {
+                    for response in packets {
+                        self.process_response(response);
+                    }
+                },
+                Some(Err(request)) => self.requests.push(request),
+                None => {},
+            }
+
+            // process buffered requests, unless they are being held during a failover
+            self.follow_failover()?;
+            while !self.holding() && !work.exhausted() {
+                let request = match self.requests.next_packet() {
+                    Some(request) => request,
+                    None => break,
+                };
+                work.add_packet();
+                if let Some(ref mut timing) = self.timing {
+                    timing.take();
+                }
+                self.phase.observe_request(&request);
+                // the handshake response starts with the client's capabilities
+                if self.phase.phase() == ConnectionPhase::Handshake
+                    && request.sequence_id() == 1 && request.payload().len() >= 32 {
+                    self.correlator.set_capabilities(LittleEndian::read_u32(request.payload()));
+                }
+                let (phase, in_flight) = (self.phase.phase(), self.correlator.depth());
+                if let Some(anomalies) = self.checks.as_mut().map(|c| c.request(&request, phase, in_flight)) {
+                    self.judge(anomalies)?;
+                }
+                if let Some(ref mut lifetime) = self.lifetime {
+                    lifetime.request(&request);
+                }
+                if let Some(error) = self.idle.as_mut().and_then(|i| i.request(&request)) {
+                    self.respond(vec![error]);
+                    continue;
+                }
+                let action = {
+                    let _stage = profile::enter(Stage::Handler);
+                    self.handler.handle_request(&request)
+                };
+                match action {
                     Action::Drop => {},
-                    Action::Forward => self.server_writer.push(&request),
-                    Action::Mutate(ref p2) => self.server_writer.push(p2),
-                    Action::Respond(ref v) => {
-                        for p in v {
-                            self.client_writer.push(&p);
-                        }
-                    },
+                    Action::Forward => self.forward(request),
+                    Action::Mutate(p2) => self.forward(p2),
+                    Action::Respond(v) => self.respond(v),
                     Action::Error { code, state, msg } => {
                         let error_packet = Packet::error_packet(code, state, msg);
-                        self.client_writer.push(&error_packet);
+                        self.respond(vec![error_packet]);
                     }
                 };
             }
 
-            // try reading from server
-            let server_read = self.server_reader.read();
+            // once the client has gone, read the responses in flight before closing the server
+            // connection, if the pipe drains them
+            let client_read = match (client_read, &self.drain) {
+                (Err(e), &Some((timeout, ref handle))) if self.correlator.depth() > 0 => {
+                    debug!("Client closed connection ({}), reading {} responses in flight", e, self.correlator.depth());
+                    self.draining = Some((Timeout::new(timeout, handle)?, e));
+                    Ok(Async::NotReady)
+                },
+                (result, _) => result,
+            };
+
+            // try reading from server, unless the session is logging in to another
+            let resuming = self.resume.as_ref().map(|r| r.resuming()).unwrap_or(false);
+            let server_read = match self.resume {
+                Some(ref resume) if resume.connecting() => Ok(Async::NotReady),
+                _ => self.responses.read(work),
+            };
 
             // process buffered responses
-            while let Some(response) = self.server_reader.next() {
-                match self.handler.handle_response(&response) {
-                    Action::Drop => {},
-                    Action::Forward => self.client_writer.push(&response),
-                    Action::Mutate(ref p2) => self.client_writer.push(p2),
-                    Action::Respond(ref v) => {
-                        for p in v {
-                            self.server_writer.push(&p);
-                        }
-                    },
-                    Action::Error { code, state, msg } => {
-                        let error_packet = Packet::error_packet(code, state, msg);
-                        self.client_writer.push(&error_packet);
-                    }
+            while !work.exhausted() {
+                let response = match self.responses.next_packet() {
+                    Some(response) => response,
+                    None => break,
                 };
+                work.add_packet();
+                let (phase, in_flight) = (self.phase.phase(), self.correlator.depth());
+                if let Some(anomalies) = self.checks.as_mut().map(|c| c.response(&response, phase, in_flight)) {
+                    self.judge(anomalies)?;
+                }
+                self.process_response(response);
+            }
+            self.usage.record_queue_depth(self.correlator.depth());
+
+            // send the commands held while the session's state was restored
+            if resuming && !self.resume.as_ref().map(|r| r.resuming()).unwrap_or(false) {
+                continue;
             }
 
             // perform all of the writes at the end, since the request handlers may have
             // queued packets in either, or both directions
 
+            // write everything held back once either side has closed
+            if client_read.is_err() || server_read.is_err() {
+                self.responses.writer.flush.force();
+                self.requests.writer.flush.force();
+            }
+
+            // close the server connection once the responses in flight have been read, or the
+            // client has been gone for too long
+            if let Some((ref mut timer, _)) = self.draining {
+                let drained = self.correlator.depth() == 0;
+                if !drained && !timer.poll()?.is_ready() {
+                    self.responses.writer.discard();
+                } else {
+                    if !drained {
+                        warn!("Gave up on {} responses in flight after the client closed its connection", self.correlator.depth());
+                    }
+                    let _ = self.requests.writer.stream.shutdown(Shutdown::Write);
+                    return Err(self.draining.take().map(|(_, e)| e).unwrap());
+                }
+            }
+
             // try writing to client
-            let client_write = self.client_writer.write();
+            let client_write = self.responses.write();
+            if let (Ok(Async::Ready(())), Some(timing)) = (&client_write, self.timing.as_mut()) {
+                timing.written();
+            }
+
+            // move an idle session to another backend if it lost its own
+            let server_read = match server_read {
+                Err(e) => match self.resume {
+                    Some(ref mut resume) if resume.resumable(self.correlator.depth()) && self.requests.writer.compressor.is_none() => {
+                        info!("Server closed connection ({}), resuming the session on another backend", e);
+                        resume.begin()?;
+                        Ok(Async::NotReady)
+                    },
+                    _ => Err(e),
+                },
+                result => result,
+            };
 
             // if the server connection has closed, close the client connection too
-            match &server_read {
-                &Err(ref e) => {
-                    debug!("Server closed connection: {}", e);
-                    match self.client_writer.stream.shutdown(Shutdown::Write) {
-                        Ok(_) => {},
-                        Err(_) => {}
-                    }
+            if let Err(ref e) = server_read {
+                debug!("Server closed connection: {}", e);
+                let _ = self.responses.writer.stream.shutdown(Shutdown::Write);
+            }
+
+            // end transactions that have been left idle for too long
+            let in_flight = self.correlator.depth();
+            match self.idle.as_mut().and_then(|i| i.poll_idle(in_flight)) {
+                Some(IdleAction::Kill) => {
+                    let _ = self.responses.writer.stream.shutdown(Shutdown::Both);
+                    let _ = self.requests.writer.stream.shutdown(Shutdown::Both);
+                    return Ok(Async::Ready(()));
                 },
-                _ => {}
+                Some(IdleAction::Rollback) => self.send_own(Packet::com_query("ROLLBACK")),
+                None => {},
+            }
+
+            // send a statement again once its backoff is over
+            if let Some(statement) = self.retry.as_mut().and_then(|r| r.poll_resend()) {
+                if let Some(ref mut resume) = self.resume {
+                    resume.resend(&statement);
+                }
+                self.correlator.request(&statement);
+                self.requests.push(statement);
+            }
+
+            // restore the session's state on its new backend, then carry on with its commands
+            if let Some(ref mut resume) = self.resume {
+                match resume.poll_reconnected() {
+                    Ok(Async::Ready(Some(resumed))) => {
+                        self.responses.reader.reconnect(resumed.server.clone());
+                        self.requests.writer.reconnect(resumed.server);
+                        for command in resumed.replay {
+                            self.correlator.request(&command);
+                            self.requests.push(command);
+                        }
+                        continue;
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        warn!("Could not resume the session on another backend: {}", e);
+                        let _ = self.responses.writer.stream.shutdown(Shutdown::Write);
+                        return Err(e);
+                    },
+                }
             }
 
             // try writing to server
-            let server_write = self.server_writer.write();
+            let server_write = self.requests.write();
+            if let (Ok(Async::Ready(())), Some(timing)) = (&server_write, self.timing.as_mut()) {
+                timing.sent();
+            }
+
+            // close sessions that have lived, or idled, for too long, between statements
+            let between_statements = self.between_statements();
+            if self.lifetime.as_mut().and_then(|l| l.poll_expired(between_statements)).is_some() {
+                self.requests.push(Packet::com_quit());
+                let _ = self.requests.write();
+                let _ = self.responses.writer.stream.shutdown(Shutdown::Both);
+                let _ = self.requests.writer.stream.shutdown(Shutdown::Both);
+                return Ok(Async::Ready(()));
+            }
 
             // if the client connection has closed, close the server connection too
-            match &client_read {
-                &Err(ref e) => {
-                    debug!("Client closed connection: {}", e);
-                    match self.server_writer.stream.shutdown(Shutdown::Write) {
-                        Ok(_) => {},
-                        Err(_) => {}
-                    }
-                },
-                _ => {}
+            if let Err(ref e) = client_read {
+                debug!("Client closed connection: {}", e);
+                let _ = self.requests.writer.stream.shutdown(Shutdown::Write);
+            }
+
+            // let other connections run once the budget is used up, even if there is more to do
+            if work.exhausted() {
+                client_read?;
+                client_write?;
+                server_read?;
+                server_write?;
+                return work.yield_now();
             }
 
             try_ready!(client_read);
@@ -350,14 +1663,6 @@ impl<H> Future for Pipe<H> where H: PacketHandler + 'static {
             try_ready!(server_read);
             try_ready!(server_write);
         }
-
     }
 
 }
-
-/// Parse the MySQL packet length (3 byte little-endian)
-fn parse_packet_length(header: &[u8]) -> usize {
-    (((header[2] as u32) << 16) |
-        ((header[1] as u32) << 8) |
-        header[0] as u32) as usize
-}
@@ -0,0 +1,151 @@
+//! Closing client sessions once they have lived, or idled, for too long.
+//!
+//! Long-lived connections go stale in ways neither end notices until a statement fails: a NAT
+//! or firewall along the way forgets them, or the backend closes them after `wait_timeout`.
+//! `LifetimeGuard` closes a session once it has been open for `max_lifetime_secs`, or has run
+//! nothing for `max_idle_secs`, but only between statements: with nothing in flight, nothing
+//! half read or unwritten in either direction and no transaction open, so no client loses a
+//! response or a transaction to it. A session that outlives its lifetime mid-transaction is
+//! closed as soon as the transaction ends. The backend is sent `COM_QUIT` before both
+//! connections are closed, and the client's pool opens a new one when it next needs it.
+//!
+//! Pooled backend connections are recycled in the same way by their pools, see
+//! `WarmPoolConfig` and `SideChannelConfig`.
+//!
+//! ```toml
+//! [connection_lifetime]
+//! max_lifetime_secs = 3600
+//! max_idle_secs = 600
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::Packet;
+use super::codec::{EofPacket, OkPacket};
+use super::pipeline::{ResponseKind, ResponsePacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::state::SERVER_STATUS_IN_TRANS;
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct ConnectionLifetimeConfig {
+    /// how long a session may stay open
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// how long a session may run nothing
+    #[serde(default)]
+    pub max_idle_secs: Option<u64>,
+}
+
+impl ConnectionLifetimeConfig {
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_lifetime_secs.is_none() && self.max_idle_secs.is_none() {
+            return Err("needs max_lifetime_secs, max_idle_secs or both".to_string());
+        }
+        if self.max_lifetime_secs == Some(0) || self.max_idle_secs == Some(0) {
+            return Err("max_lifetime_secs and max_idle_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Why a session was closed
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Expiry {
+    /// it was open for `max_lifetime_secs`
+    Lifetime,
+    /// it ran nothing for `max_idle_secs`
+    Idle,
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expiry::Lifetime => write!(f, "reached its maximum lifetime"),
+            Expiry::Idle => write!(f, "was idle for too long"),
+        }
+    }
+}
+
+/// Follows a session's age, idleness and transaction state, and tells when to close it
+pub struct LifetimeGuard {
+    config: ConnectionLifetimeConfig,
+    user: String,
+    handle: Handle,
+    in_transaction: bool,
+    /// runs out once the session has lived for `max_lifetime_secs`
+    lifetime: Option<Timeout>,
+    /// the session has lived for `max_lifetime_secs`, and closes at the next boundary
+    expired: bool,
+    idle: Option<Timeout>,
+}
+
+impl LifetimeGuard {
+
+    pub fn new(config: ConnectionLifetimeConfig, user: &str, handle: &Handle) -> Self {
+        let lifetime = config.max_lifetime_secs.and_then(|secs| Timeout::new(Duration::from_secs(secs), handle).ok());
+        LifetimeGuard {
+            config,
+            user: user.to_string(),
+            handle: handle.clone(),
+            in_transaction: false,
+            lifetime,
+            expired: false,
+            idle: None,
+        }
+    }
+
+    /// Observe a packet from the client, which makes the session busy again
+    pub fn request(&mut self, _: &Packet) {
+        self.idle = None;
+    }
+
+    /// Observe a response packet, to follow whether a transaction is open
+    pub fn response(&mut self, p: &Packet, answered: Option<ResponsePacket>) {
+        let answered = match answered {
+            Some(ref answered) if answered.last => answered,
+            _ => return,
+        };
+        let status_flags = match answered.kind {
+            ResponseKind::Ok => OkPacket::parse(p, CLIENT_PROTOCOL_41).ok().map(|ok| ok.status_flags),
+            ResponseKind::Eof => EofPacket::parse(p).ok().map(|eof| eof.status_flags),
+            _ => None,
+        };
+        if let Some(status_flags) = status_flags {
+            self.in_transaction = status_flags & SERVER_STATUS_IN_TRANS != 0;
+        }
+    }
+
+    /// Check whether the session must be closed, which it only is `between_statements` and
+    /// outside a transaction
+    pub fn poll_expired(&mut self, between_statements: bool) -> Option<Expiry> {
+        let lived = self.lifetime.as_mut().map(|t| t.poll().map(|a| a.is_ready()).unwrap_or(true)).unwrap_or(false);
+        if lived {
+            self.lifetime = None;
+            self.expired = true;
+        }
+        if !between_statements || self.in_transaction {
+            self.idle = None;
+            return None;
+        }
+        let expiry = if self.expired {
+            Expiry::Lifetime
+        } else {
+            let secs = self.config.max_idle_secs?;
+            if self.idle.is_none() {
+                self.idle = Timeout::new(Duration::from_secs(secs), &self.handle).ok();
+            }
+            let idled = self.idle.as_mut()?.poll().map(|a| a.is_ready()).unwrap_or(true);
+            if !idled {
+                return None;
+            }
+            Expiry::Idle
+        };
+        info!("Closing the session of '{}', which {}", self.user, expiry);
+        Some(expiry)
+    }
+}
@@ -0,0 +1,225 @@
+//! Listening for client connections on one or more reactor threads.
+//!
+//! With more than one worker, every worker thread runs its own reactor and accepts on its
+//! own socket bound to the same address with `SO_REUSEPORT`. The kernel spreads incoming
+//! connections across the sockets, so workers never share a listener or a connection.
+//!
+//! A `ListenerControl` pauses a listener or limits its connections at runtime. Like
+//! `MaintenanceMode` it is a cheap, cloneable switch, shared by the listener's workers and
+//! whatever admin facility manages it.
+
+use std::io::{self, Error, ErrorKind};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use futures::{Future, Stream};
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+
+use super::Packet;
+use super::maintenance::ER_SERVER_SHUTDOWN;
+
+/// MySQL error ER_CON_COUNT_ERROR
+pub const ER_CON_COUNT_ERROR: u16 = 1040;
+
+/// Connections accepted on any of a worker's listeners, with the address of each client
+pub type Connections = Box<dyn Stream<Item = (TcpStream, SocketAddr), Error = io::Error>>;
+
+/// Bind a listening socket that other sockets may bind to the same address
+pub fn bind_reuseport(addr: &SocketAddr, backlog: i32) -> io::Result<net::TcpListener> {
+    bind(addr, true, false, backlog)
+}
+
+/// `v6_only` keeps an IPv6 socket from also accepting IPv4 connections, so that it can
+/// share a port with an IPv4 socket
+fn bind(addr: &SocketAddr, share: bool, v6_only: bool, backlog: i32) -> io::Result<net::TcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(v6_only)?;
+            builder
+        },
+    };
+    builder.reuse_address(true)?;
+    if share {
+        reuse_port(&builder)?;
+    }
+    builder.bind(addr)?;
+    builder.listen(backlog)
+}
+
+#[cfg(unix)]
+fn reuse_port(builder: &TcpBuilder) -> io::Result<()> {
+    builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn reuse_port(_builder: &TcpBuilder) -> io::Result<()> {
+    Err(Error::other("SO_REUSEPORT is not supported on this platform"))
+}
+
+/// Accept connections on every address in `addrs` with `workers` reactor threads, allowing
+/// up to `backlog` connections to wait to be accepted on each listener.
+///
+/// `serve` is called once per worker, on that worker's thread, with the connections accepted
+/// by its listeners and the handle of its reactor, and the worker runs until the returned
+/// future completes. With a single worker everything runs on the calling thread. Blocks until
+/// all workers are done.
+///
+/// IPv6 addresses only accept IPv6 connections when `addrs` also has an IPv4 address, so
+/// `0.0.0.0:3307` and `[::]:3307` can be used together. On its own, `[::]:3307` accepts both.
+pub fn run<F, S>(addrs: &[SocketAddr], workers: usize, backlog: i32, serve: F) -> io::Result<()>
+    where F: Fn(Connections, Handle) -> S + Send + Sync + 'static,
+          S: Future<Item = (), Error = io::Error>,
+{
+    if addrs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No addresses to listen on"));
+    }
+    let v6_only = addrs.iter().any(|a| a.is_ipv4());
+
+    if workers <= 1 {
+        let mut core = Core::new()?;
+        let listeners = addrs.iter()
+            .map(|addr| bind(addr, false, v6_only, backlog))
+            .collect::<io::Result<Vec<_>>>()?;
+        let connections = incoming(listeners, &core.handle())?;
+        return core.run(serve(connections, core.handle()));
+    }
+
+    // bind every socket up front, so that errors are reported before any worker starts
+    let mut per_worker: Vec<Vec<net::TcpListener>> = (0..workers).map(|_| vec![]).collect();
+    for addr in addrs {
+        let first = bind(addr, true, v6_only, backlog)?;
+        let addr = first.local_addr()?;
+        per_worker[0].push(first);
+        for listeners in per_worker.iter_mut().skip(1) {
+            listeners.push(bind(&addr, true, v6_only, backlog)?);
+        }
+    }
+
+    let serve = Arc::new(serve);
+    let threads: Vec<_> = per_worker.into_iter().enumerate().map(|(i, listeners)| {
+        let serve = serve.clone();
+        thread::Builder::new().name(format!("mysql-proxy-worker-{}", i)).spawn(move || {
+            let mut core = Core::new()?;
+            let connections = incoming(listeners, &core.handle())?;
+            core.run(serve(connections, core.handle()))
+        })
+    }).collect::<io::Result<_>>()?;
+
+    let mut result = Ok(());
+    for t in threads {
+        let r = t.join().unwrap_or_else(|_| Err(Error::other("worker thread panicked")));
+        if let Err(e) = r {
+            warn!("Worker failed: {}", e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Register listeners with a reactor and merge the connections they accept
+fn incoming(listeners: Vec<net::TcpListener>, handle: &Handle) -> io::Result<Connections> {
+    let mut connections: Option<Connections> = None;
+    for std_listener in listeners {
+        let addr = std_listener.local_addr()?;
+        let accepted = TcpListener::from_listener(std_listener, &addr, handle)?.incoming();
+        connections = Some(match connections {
+            Some(c) => Box::new(c.select(accepted)),
+            None => Box::new(accepted),
+        });
+    }
+    connections.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No addresses to listen on"))
+}
+
+#[derive(Debug,Default)]
+struct ControlState {
+    paused: AtomicBool,
+    /// 0 for no limit
+    max_connections: AtomicUsize,
+    active: AtomicUsize,
+}
+
+/// Shared switch for pausing a listener and limiting its connections at runtime
+#[derive(Clone,Debug,Default)]
+pub struct ListenerControl {
+    name: Arc<String>,
+    state: Arc<ControlState>,
+}
+
+impl ListenerControl {
+
+    pub fn new(name: &str) -> Self {
+        ListenerControl { name: Arc::new(name.to_string()), state: Arc::default() }
+    }
+
+    pub fn with_max_connections(self, max_connections: Option<usize>) -> Self {
+        self.set_max_connections(max_connections);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Refuse new connections, leaving those already open alone
+    pub fn pause(&self) {
+        info!("Pausing listener '{}'", self.name);
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        info!("Resuming listener '{}'", self.name);
+        self.state.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+
+    /// Limit the connections open at once, which doesn't close any already open
+    pub fn set_max_connections(&self, max_connections: Option<usize>) {
+        self.state.max_connections.store(max_connections.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// How many of the listener's connections are open
+    pub fn active(&self) -> usize {
+        self.state.active.load(Ordering::SeqCst)
+    }
+
+    /// Admit a new connection, counting it until the returned guard is dropped, or return the
+    /// error packet to refuse it with in place of the greeting
+    pub fn admit(&self) -> Result<Admitted, Packet> {
+        if self.is_paused() {
+            let msg = format!("Listener '{}' is not accepting connections", self.name);
+            return Err(Packet::error_packet(ER_SERVER_SHUTDOWN, *b"08S01", msg).with_sequence_id(0));
+        }
+        let max = self.state.max_connections.load(Ordering::SeqCst);
+        let active = self.state.active.fetch_add(1, Ordering::SeqCst);
+        if max > 0 && active >= max {
+            self.state.active.fetch_sub(1, Ordering::SeqCst);
+            return Err(Packet::error_packet(ER_CON_COUNT_ERROR, *b"08004", "Too many connections".to_string()).with_sequence_id(0));
+        }
+        Ok(Admitted { state: self.state.clone() })
+    }
+}
+
+/// A connection counted against its listener's limit while it is open
+#[derive(Debug)]
+pub struct Admitted {
+    state: Arc<ControlState>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
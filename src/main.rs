@@ -0,0 +1,247 @@
+//! The `mysql-proxy` command line: runs the proxy from a configuration file, checks or prints
+//! configurations, replays captured traffic and prints protocol traces.
+
+extern crate env_logger;
+extern crate mysql_proxy;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::process;
+use std::time::{Duration, Instant};
+
+use mysql_proxy::PacketType;
+use mysql_proxy::config::{ProxyConfig, DEFAULT_CONFIG};
+use mysql_proxy::probe;
+use mysql_proxy::replay::{Capture, Outcome, ReplayClient};
+use mysql_proxy::server;
+use mysql_proxy::trace::Trace;
+
+const USAGE: &str = "Usage: mysql-proxy <command> [options]
+
+Commands:
+  run [--config FILE] [--listen ADDRS] [--dry-run]
+      Run the proxy. Without [listeners] in the configuration, it listens on ADDRS,
+      e.g. \"0.0.0.0:3307,[::]:3307\", 127.0.0.1:3307 by default. With --dry-run, check
+      everything the proxy needs to start, such as that backends resolve and TLS
+      certificates load, and exit without listening.
+  check-config [--config FILE] [--dry-run]
+      Check a configuration for mistakes and exit, with --dry-run as above.
+  probe [--config FILE] --user USER [--password PASSWORD] [--timeout MS]
+      Log in to every backend in the configuration and run SELECT 1, reporting how each
+      answered, and fail unless all did, e.g. before moving traffic to a new proxy. The
+      password defaults to $MYSQL_PWD and each step may take up to 2000 ms by default.
+  print-default-config
+      Print a minimal configuration to start from.
+  replay <capture> --target ADDR --user USER [--password PASSWORD] [--database DB]
+      Replay the text commands in a packet dump against a server, one connection per
+      captured connection. The password defaults to $MYSQL_PWD.
+  print-trace <trace>
+      Print a connection's protocol trace as a timeline, one packet per line.
+
+The configuration file defaults to proxy.toml.
+";
+
+/// How long each step of a probe may take, by default
+const DEFAULT_PROBE_TIMEOUT_MS: u64 = 2000;
+
+/// How much of a statement is shown when replaying it
+const STATEMENT_EXCERPT_LEN: usize = 80;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|s| &s[..]) {
+        Some("run") => run(&args[1..]),
+        Some("check-config") => parse_options(&args[1..], &["config"], &["dry-run"]).and_then(|(options, _)| check_config(&options)),
+        Some("probe") => probe(&args[1..]),
+        Some("print-default-config") => {
+            print!("{}", DEFAULT_CONFIG);
+            Ok(())
+        },
+        Some("replay") => replay(&args[1..]),
+        Some("print-trace") => print_trace(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        _ => {
+            eprint!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("mysql-proxy: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (options, _) = parse_options(args, &["config", "listen"], &["dry-run"])?;
+    if options.contains_key("dry-run") {
+        return check_config(&options);
+    }
+    if env::var_os("RUST_LOG").is_none() {
+        env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init().map_err(Error::other)?;
+    let config = load_config(&options)?;
+    let listen = options.get("listen").map(|s| &s[..]).unwrap_or("127.0.0.1:3307").split(',')
+        .map(|addr| addr.trim().parse::<SocketAddr>()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid listen address '{}': {}", addr, e))))
+        .collect::<Result<Vec<_>>>()?;
+    server::run(&config, listen)
+}
+
+fn check_config(options: &HashMap<String, String>) -> Result<()> {
+    let config = load_config(options)?;
+    println!("Configuration OK: {} listener(s), {} routing group(s), {} user(s)",
+             config.listeners.len(), config.groups.len(), config.users.len());
+    Ok(())
+}
+
+fn load_config(options: &HashMap<String, String>) -> Result<ProxyConfig> {
+    let path = options.get("config").map(|s| &s[..]).unwrap_or("proxy.toml");
+    let config = ProxyConfig::from_file(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    let problems = if options.contains_key("dry-run") {
+        config.dry_run()
+    } else {
+        config.validate()
+    };
+    if !problems.is_empty() {
+        let msg = format!("{}: {} problem(s)\n  {}", path, problems.len(), problems.join("\n  "));
+        return Err(Error::new(ErrorKind::InvalidData, msg));
+    }
+    Ok(config)
+}
+
+fn probe(args: &[String]) -> Result<()> {
+    let (options, _) = parse_options(args, &["config", "user", "password", "timeout"], &[])?;
+    let user = options.get("user").ok_or_else(|| Error::new(ErrorKind::InvalidInput, "probe requires --user"))?;
+    let password = options.get("password").cloned().or_else(|| env::var("MYSQL_PWD").ok()).unwrap_or_default();
+    let timeout = match options.get("timeout") {
+        Some(ms) => ms.parse().map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid timeout '{}': {}", ms, e)))?,
+        None => DEFAULT_PROBE_TIMEOUT_MS,
+    };
+    let config = load_config(&options)?;
+    let results = probe::probe_all(&config.backends(), user, &password, Duration::from_millis(timeout));
+    for r in &results {
+        let addr = r.addr.map(|a| format!(" ({})", a)).unwrap_or_default();
+        match r.result {
+            Ok(()) => println!("{}{}: OK ({} ms)", r.backend, addr, r.elapsed.as_millis()),
+            Err(ref e) => println!("{}{}: FAILED: {} ({} ms)", r.backend, addr, e, r.elapsed.as_millis()),
+        }
+    }
+    let failed = results.iter().filter(|r| !r.is_ok()).count();
+    if failed > 0 {
+        return Err(Error::other(format!("{} of {} backend(s) failed the probe", failed, results.len())));
+    }
+    println!("All {} backend(s) answered", results.len());
+    Ok(())
+}
+
+fn replay(args: &[String]) -> Result<()> {
+    let (options, positional) = parse_options(args, &["target", "user", "password", "database"], &[])?;
+    let capture_path = match positional.first() {
+        Some(path) if positional.len() == 1 => path,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "replay takes a single capture file")),
+    };
+    let required = |name: &str| options.get(name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("replay requires --{}", name)));
+    let target = required("target")?;
+    let user = required("user")?;
+    let password = options.get("password").cloned().or_else(|| env::var("MYSQL_PWD").ok()).unwrap_or_default();
+    let database = options.get("database").map(|s| &s[..]);
+
+    let capture = Capture::read(BufReader::new(File::open(capture_path)?))?;
+    let mut clients: HashMap<String, ReplayClient> = HashMap::new();
+    let mut connections = 0;
+    let mut errors = 0;
+    let started = Instant::now();
+    for command in &capture.commands {
+        if !clients.contains_key(&command.connection) {
+            clients.insert(command.connection.clone(), ReplayClient::connect(target, user, password.as_str(), database)?);
+            connections += 1;
+        }
+        let quit = command.packet.packet_type().ok() == Some(PacketType::ComQuit);
+        let sent = Instant::now();
+        let outcome = clients.get_mut(&command.connection).unwrap().command(&command.packet)?;
+        let outcome = match outcome {
+            Outcome::Ok { affected_rows } => format!("OK, {} row(s) affected", affected_rows),
+            Outcome::Rows(rows) => format!("{} row(s)", rows),
+            Outcome::Err { code, message } => {
+                errors += 1;
+                format!("ERROR {}: {}", code, message)
+            },
+        };
+        println!("[{}] {} -> {} ({} ms)", command.connection, describe(&command.packet), outcome, sent.elapsed().as_millis());
+        if quit {
+            clients.remove(&command.connection);
+        }
+    }
+    println!("Replayed {} command(s) on {} connection(s) in {} ms: {} error(s), {} command(s) skipped",
+             capture.commands.len(), connections, started.elapsed().as_millis(), errors, capture.skipped);
+    Ok(())
+}
+
+fn print_trace(args: &[String]) -> Result<()> {
+    let (_, positional) = parse_options(args, &[], &[])?;
+    let path = match positional.first() {
+        Some(path) if positional.len() == 1 => path,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "print-trace takes a single trace file")),
+    };
+    let trace = Trace::read(BufReader::new(File::open(path)?))?;
+    println!("Trace of {} started at {} ms since the epoch, {} record(s)", trace.label, trace.started_ms, trace.records.len());
+    for record in &trace.records {
+        println!("{}", record);
+    }
+    Ok(())
+}
+
+/// A command as shown when replaying it, e.g. `ComQuery SELECT 1`
+fn describe(p: &mysql_proxy::Packet) -> String {
+    let payload = p.payload();
+    let name = match p.packet_type() {
+        Ok(PacketType::Unknown(command)) => format!("0x{:02x}", command),
+        Ok(t) => format!("{:?}", t),
+        Err(_) => "0x00".to_string(),
+    };
+    let arg = String::from_utf8_lossy(payload.get(1..).unwrap_or(&[]));
+    match arg.char_indices().nth(STATEMENT_EXCERPT_LEN) {
+        Some((i, _)) => format!("{} {}...", name, &arg[..i]),
+        None if arg.is_empty() => name,
+        None => format!("{} {}", name, arg),
+    }
+}
+
+/// Split `--name value` and `--name=value` options, for the given names, and `--flag`s from
+/// positional arguments. Flags that are present map to an empty value.
+fn parse_options(args: &[String], names: &[&str], flags: &[&str]) -> Result<(HashMap<String, String>, Vec<String>)> {
+    let mut options = HashMap::new();
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let (name, value) = match arg[2..].find('=') {
+            Some(i) => (arg[2..2 + i].to_string(), Some(arg[3 + i..].to_string())),
+            None => (arg[2..].to_string(), None),
+        };
+        if flags.contains(&&name[..]) && value.is_none() {
+            options.insert(name, String::new());
+            continue;
+        }
+        if !names.contains(&&name[..]) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Unknown option --{}", name)));
+        }
+        let value = match value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("--{} requires a value", name))),
+        };
+        options.insert(name, value);
+    }
+    Ok((options, positional))
+}
@@ -0,0 +1,123 @@
+//! Maintenance mode, for draining traffic from a MySQL server during upgrades.
+//!
+//! A `MaintenanceMode` is a cheap, cloneable switch that can be shared between every
+//! connection and whatever admin facility toggles it. Wrap a connection's handler in a
+//! `MaintenanceHandler` and it will start answering on behalf of the server as soon as
+//! maintenance is enabled.
+
+use std::sync::{Arc, RwLock};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+
+/// MySQL error ER_SERVER_SHUTDOWN
+pub const ER_SERVER_SHUTDOWN: u16 = 1053;
+
+/// How the proxy should treat clients while maintenance mode is enabled
+#[derive(Clone,Debug,PartialEq)]
+pub enum MaintenancePolicy {
+    /// accept connections, but answer every query with an error
+    RejectQueries { code: u16, state: [u8; 5], msg: String },
+    /// refuse the connection with an error once the client has sent its handshake response
+    RefuseConnections { code: u16, state: [u8; 5], msg: String },
+}
+
+impl MaintenancePolicy {
+
+    /// Reject queries with ER_SERVER_SHUTDOWN and a custom message
+    pub fn reject_queries(msg: &str) -> Self {
+        MaintenancePolicy::RejectQueries {
+            code: ER_SERVER_SHUTDOWN,
+            state: *b"08S01",
+            msg: msg.to_string(),
+        }
+    }
+
+    /// Refuse connections with ER_SERVER_SHUTDOWN and a custom message
+    pub fn refuse_connections(msg: &str) -> Self {
+        MaintenancePolicy::RefuseConnections {
+            code: ER_SERVER_SHUTDOWN,
+            state: *b"08S01",
+            msg: msg.to_string(),
+        }
+    }
+}
+
+/// Shared switch for enabling and disabling maintenance mode at runtime
+#[derive(Clone,Debug,Default)]
+pub struct MaintenanceMode {
+    policy: Arc<RwLock<Option<MaintenancePolicy>>>,
+}
+
+impl MaintenanceMode {
+
+    pub fn new() -> Self {
+        MaintenanceMode::default()
+    }
+
+    /// Put the proxy into maintenance mode
+    pub fn enable(&self, policy: MaintenancePolicy) {
+        info!("Entering maintenance mode: {:?}", policy);
+        *self.policy.write().unwrap() = Some(policy);
+    }
+
+    /// Resume normal operation
+    pub fn disable(&self) {
+        info!("Leaving maintenance mode");
+        *self.policy.write().unwrap() = None;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.policy.read().unwrap().is_some()
+    }
+
+    /// The policy currently in effect, if maintenance mode is enabled
+    pub fn policy(&self) -> Option<MaintenancePolicy> {
+        self.policy.read().unwrap().clone()
+    }
+}
+
+/// Wraps another handler and answers on behalf of the server while maintenance mode is
+/// enabled. `COM_QUIT` is always forwarded so that clients can disconnect cleanly.
+pub struct MaintenanceHandler<H: PacketHandler> {
+    mode: MaintenanceMode,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> MaintenanceHandler<H> where H: PacketHandler {
+
+    pub fn new(mode: MaintenanceMode, inner: H) -> Self {
+        MaintenanceHandler { mode, phase: PhaseTracker::new(), inner }
+    }
+}
+
+impl<H> PacketHandler for MaintenanceHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        match (self.mode.policy(), self.phase.phase()) {
+            (Some(MaintenancePolicy::RefuseConnections { code, state, msg }), ConnectionPhase::Handshake) => {
+                let error = Packet::error_packet(code, state, msg)
+                    .with_sequence_id(p.sequence_id().wrapping_add(1));
+                Action::Respond(vec![error])
+            },
+            (Some(MaintenancePolicy::RejectQueries { code, state, msg }), ConnectionPhase::Command) => {
+                match p.packet_type() {
+                    Ok(PacketType::ComQuit) => self.inner.handle_request(p),
+                    _ => Action::Error { code, state, msg },
+                }
+            },
+            _ => self.inner.handle_request(p),
+        }
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
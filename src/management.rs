@@ -0,0 +1,712 @@
+//! Management API, for changing the proxy while it runs.
+//!
+//! With a `[management]` section, the proxy answers HTTP requests with JSON bodies on a
+//! listener of its own:
+//!
+//! - `GET /connections` lists the sessions running, with their labels, and
+//!   `DELETE /connections/{id}` kills one
+//! - `GET /users` lists each listener's users, `PUT /users/{user}` adds or replaces a user
+//!   mapping, in the same form as `[[users]]`, and `DELETE /users/{user}` removes one
+//! - `GET /rules` gets each listener's table rules and row filters, and `PUT /rules` replaces
+//!   them with `{"table_rules": [...], "row_filters": [...]}`
+//! - `GET /backends` gets the backend weights by routing group, and `PUT /backends/{host:port}`
+//!   sets one with `{"weight": 2}`, see `balance`
+//! - `GET /maintenance` gets maintenance mode, `PUT /maintenance` enables it with
+//!   `{"mode": "reject_queries"}` or `"refuse_connections"`, and an optional `message`, and
+//!   `DELETE /maintenance` disables it
+//! - `POST /tls/reload` loads every listener's TLS certificate, key and client CA again, for
+//!   new connections, with the `tls` feature
+//! - `GET /stats` gets the query statistics, if they're kept, and `GET /chargeback` each
+//!   user's usage in the current chargeback period
+//! - `GET /profile` gets the samples and total time of each stage of the pipes, with
+//!   `[profiling]`, see `profile`
+//!
+//! Changes apply to every listener. Sessions take their user mapping and rules when they
+//! start, so the ones already running carry on as they were. With a `token`, requests need an
+//! `Authorization: Bearer <token>` header. With a `[management.admin]` section, users, backends
+//! and table rules can also be changed over MySQL, see `admin`.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::admin::AdminConfig;
+use super::balance::{BackendPool, BackendWeights};
+use super::chargeback::Chargeback;
+use super::connect::BackendAddr;
+use super::labels::Labels;
+use super::maintenance::{MaintenanceMode, MaintenancePolicy};
+use super::profile::StageTotals;
+use super::rowfilter::RowFilter;
+use super::rules::TableRule;
+use super::stats::Stats;
+#[cfg(feature = "tls")]
+use super::tls::ServerCertificates;
+use super::users::{UserMap, UserMapping};
+
+/// Largest request body accepted
+pub const MAX_BODY_LEN: usize = 1 << 20;
+
+/// Longest request line or header accepted
+const MAX_LINE_LEN: u64 = 8192;
+
+/// Most headers accepted in a request
+const MAX_HEADERS: usize = 32;
+
+/// How long a client may take to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ManagementConfig {
+    pub listen: SocketAddr,
+    /// the bearer token requests must carry, required unless listening on a loopback address
+    #[serde(default)]
+    pub token: Option<String>,
+    /// a MySQL admin interface to the same configuration, see `admin`
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+}
+
+/// A running session
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub listener: String,
+    pub user: String,
+    pub client: SocketAddr,
+    pub backend: String,
+    /// seconds since the epoch
+    pub connected_at: u64,
+    /// the session's labels when it was listed
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A running session's entry, with its labels as they change
+#[derive(Debug)]
+struct Registered {
+    info: ConnectionInfo,
+    labels: Labels,
+    kill: oneshot::Sender<()>,
+}
+
+#[derive(Debug,Default)]
+struct Registry {
+    next_id: u64,
+    connections: BTreeMap<u64, Registered>,
+}
+
+/// The sessions running on every listener
+#[derive(Clone,Debug,Default)]
+pub struct ConnectionRegistry {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl ConnectionRegistry {
+
+    pub fn new() -> Self {
+        ConnectionRegistry::default()
+    }
+
+    /// Track a session until the returned entry is dropped
+    pub fn register(&self, listener: &str, user: &str, client: SocketAddr, backend: &str, labels: Labels) -> RegisteredConnection {
+        let (sender, killed) = oneshot::channel();
+        let mut registry = self.registry.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let info = ConnectionInfo {
+            id,
+            listener: listener.to_string(),
+            user: user.to_string(),
+            client,
+            backend: backend.to_string(),
+            connected_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            labels: BTreeMap::new(),
+        };
+        registry.connections.insert(id, Registered { info, labels, kill: sender });
+        RegisteredConnection { id, registry: self.clone(), killed }
+    }
+
+    /// The sessions running, oldest first
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.registry.lock().unwrap().connections.values().map(|registered| {
+            ConnectionInfo { labels: registered.labels.snapshot(), ..registered.info.clone() }
+        }).collect()
+    }
+
+    /// Kill a session, returning whether it was running
+    pub fn kill(&self, id: u64) -> bool {
+        match self.registry.lock().unwrap().connections.remove(&id) {
+            Some(Registered { info, kill, .. }) => {
+                info!("Killing session {} of user '{}' from {}", id, info.user, info.client);
+                let _ = kill.send(());
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// A session in the registry. As a future it fails when the session is killed, so a session
+/// run alongside it ends then.
+#[derive(Debug)]
+pub struct RegisteredConnection {
+    id: u64,
+    registry: ConnectionRegistry,
+    killed: oneshot::Receiver<()>,
+}
+
+impl RegisteredConnection {
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Future for RegisteredConnection {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        match self.killed.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // the sender is only dropped once the session is killed
+            _ => Err(Error::new(ErrorKind::ConnectionAborted, "Killed through the management API")),
+        }
+    }
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        self.registry.registry.lock().unwrap().connections.remove(&self.id);
+    }
+}
+
+/// Table rules and row filters
+#[derive(Clone,Debug,Default,Deserialize,PartialEq,Serialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub table_rules: Vec<TableRule>,
+    #[serde(default)]
+    pub row_filters: Vec<RowFilter>,
+}
+
+impl Rules {
+
+    /// Problems with the rules, as the configuration check reports them
+    pub fn validate(&self) -> Vec<String> {
+        let table_rules = self.table_rules.iter().filter_map(|rule| rule.validate().err()).map(|e| format!("Table rule: {}", e));
+        let row_filters = self.row_filters.iter().filter_map(|filter| filter.validate().err()).map(|e| format!("Row filter: {}", e));
+        table_rules.chain(row_filters).collect()
+    }
+}
+
+#[derive(Debug,Default)]
+struct CurrentRules {
+    table_rules: Arc<Vec<TableRule>>,
+    row_filters: Arc<Vec<RowFilter>>,
+}
+
+impl CurrentRules {
+
+    fn new(rules: Rules) -> Self {
+        CurrentRules { table_rules: Arc::new(rules.table_rules), row_filters: Arc::new(rules.row_filters) }
+    }
+}
+
+/// A listener's rules, which can be replaced without affecting the sessions that already
+/// took them
+#[derive(Clone,Debug,Default)]
+pub struct SharedRules {
+    current: Arc<RwLock<CurrentRules>>,
+}
+
+impl SharedRules {
+
+    pub fn new(rules: Rules) -> Self {
+        SharedRules { current: Arc::new(RwLock::new(CurrentRules::new(rules))) }
+    }
+
+    pub fn table_rules(&self) -> Arc<Vec<TableRule>> {
+        self.current.read().unwrap().table_rules.clone()
+    }
+
+    pub fn row_filters(&self) -> Arc<Vec<RowFilter>> {
+        self.current.read().unwrap().row_filters.clone()
+    }
+
+    pub fn get(&self) -> Rules {
+        let current = self.current.read().unwrap();
+        Rules { table_rules: (*current.table_rules).clone(), row_filters: (*current.row_filters).clone() }
+    }
+
+    pub fn replace(&self, rules: Rules) {
+        *self.current.write().unwrap() = CurrentRules::new(rules);
+    }
+}
+
+#[derive(Debug)]
+struct ManagedListener {
+    name: String,
+    users: Arc<UserMap>,
+    rules: SharedRules,
+}
+
+/// Everything the management API changes, shared with the listeners
+#[derive(Clone,Debug,Default)]
+pub struct Management {
+    pub connections: ConnectionRegistry,
+    pub weights: BackendWeights,
+    pub maintenance: MaintenanceMode,
+    backends: BackendPool,
+    stats: Option<Stats>,
+    chargeback: Option<Chargeback>,
+    profile: Option<StageTotals>,
+    listeners: Arc<Mutex<Vec<ManagedListener>>>,
+    /// each listener's certificates, by listener name
+    #[cfg(feature = "tls")]
+    certificates: Arc<Mutex<Vec<(String, ServerCertificates)>>>,
+}
+
+/// A response status and JSON body
+type Response = (&'static str, String);
+
+/// A user mapping as listed, without its passwords
+#[derive(Serialize)]
+struct UserInfo {
+    user: String,
+    backend_user: String,
+    default_group: String,
+}
+
+#[derive(Serialize)]
+struct BackendWeight {
+    backend: String,
+    weight: u32,
+}
+
+#[derive(Deserialize)]
+struct WeightChange {
+    weight: u32,
+}
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MaintenanceKind {
+    RejectQueries,
+    RefuseConnections,
+}
+
+#[derive(Deserialize,Serialize)]
+struct MaintenanceState {
+    #[serde(default)]
+    mode: Option<MaintenanceKind>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+impl Management {
+
+    /// Manage the weights of the backends in `backends`
+    pub fn new(backends: BackendPool, weights: BackendWeights) -> Self {
+        Management { backends, weights, ..Management::default() }
+    }
+
+    pub fn with_stats(mut self, stats: Option<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn with_chargeback(mut self, chargeback: Option<Chargeback>) -> Self {
+        self.chargeback = chargeback;
+        self
+    }
+
+    pub fn with_profile(mut self, profile: Option<StageTotals>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Let the API change a listener's users and rules
+    pub fn add_listener(&self, name: &str, users: Arc<UserMap>, rules: SharedRules) {
+        self.listeners.lock().unwrap().push(ManagedListener { name: name.to_string(), users, rules });
+    }
+
+    /// Let the API reload a listener's TLS certificate
+    #[cfg(feature = "tls")]
+    pub fn add_certificates(&self, name: &str, certificates: ServerCertificates) {
+        self.certificates.lock().unwrap().push((name.to_string(), certificates));
+    }
+
+    /// The query statistics, if they're kept
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    /// The backends the weights are managed for
+    pub fn backend_pool(&self) -> &BackendPool {
+        &self.backends
+    }
+
+    /// Every listener's user mappings, once for each user, sorted by user
+    pub fn user_mappings(&self) -> Vec<UserMapping> {
+        let mut users = BTreeMap::new();
+        for listener in self.listeners.lock().unwrap().iter() {
+            for mapping in listener.users.users() {
+                users.entry(mapping.user.clone()).or_insert(mapping);
+            }
+        }
+        users.into_values().collect()
+    }
+
+    /// Replace every listener's user mappings, unless one would let clients in without
+    /// credentials on one of them
+    pub fn replace_users(&self, users: Vec<UserMapping>) -> Result<(), String> {
+        let listeners = self.listeners.lock().unwrap();
+        check_users(&listeners, &users)?;
+        for listener in listeners.iter() {
+            listener.users.replace(users.clone());
+        }
+        Ok(())
+    }
+
+    /// The first listener's table rules, which every listener has unless they were configured
+    /// otherwise
+    pub fn table_rules(&self) -> Vec<TableRule> {
+        let listeners = self.listeners.lock().unwrap();
+        listeners.first().map(|l| (*l.rules.table_rules()).clone()).unwrap_or_default()
+    }
+
+    /// Replace every listener's table rules, keeping their row filters
+    pub fn replace_table_rules(&self, table_rules: Vec<TableRule>) {
+        for listener in self.listeners.lock().unwrap().iter() {
+            let rules = Rules { table_rules: table_rules.clone(), row_filters: (*listener.rules.row_filters()).clone() };
+            listener.rules.replace(rules);
+        }
+    }
+
+    /// Answer a request with a JSON `body`
+    pub fn handle(&self, method: &str, path: &str, body: &[u8]) -> Response {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let result = match (method, &segments[..]) {
+            ("GET", ["connections"]) => Ok(json_response(&self.connections.list())),
+            ("DELETE", ["connections", id]) => match id.parse() {
+                Ok(id) if self.connections.kill(id) => Ok(json_response(&json!({"killed": id}))),
+                _ => Err(not_found(format!("No session {}", id))),
+            },
+            ("GET", ["users"]) => Ok(self.users()),
+            ("PUT", ["users", user]) => self.put_user(user, body),
+            ("DELETE", ["users", user]) => self.delete_user(user),
+            ("GET", ["rules"]) => Ok(self.rules()),
+            ("PUT", ["rules"]) => self.put_rules(body),
+            ("GET", ["backends"]) => Ok(self.backends()),
+            ("PUT", ["backends", backend]) => self.put_weight(backend, body),
+            ("GET", ["maintenance"]) => Ok(self.maintenance()),
+            ("PUT", ["maintenance"]) => self.put_maintenance(body),
+            ("DELETE", ["maintenance"]) => {
+                self.maintenance.disable();
+                Ok(self.maintenance())
+            },
+            ("POST", ["tls", "reload"]) => self.reload_tls(),
+            ("GET", ["stats"]) => match self.stats {
+                Some(ref stats) => Ok(json_response(&stats.snapshot())),
+                None => Err(not_found("Statistics aren't kept".to_string())),
+            },
+            ("GET", ["chargeback"]) => match self.chargeback {
+                Some(ref chargeback) => Ok(json_response(&chargeback.current())),
+                None => Err(not_found("Chargeback reports aren't written".to_string())),
+            },
+            ("GET", ["profile"]) => match self.profile {
+                Some(ref profile) => Ok(json_response(&profile.snapshot())),
+                None => Err(not_found("Stages aren't profiled".to_string())),
+            },
+            (_, ["connections"]) | (_, ["connections", _]) | (_, ["users"]) | (_, ["users", _]) | (_, ["rules"])
+                | (_, ["backends"]) | (_, ["backends", _]) | (_, ["maintenance"]) | (_, ["tls", "reload"]) | (_, ["stats"])
+                | (_, ["chargeback"]) | (_, ["profile"]) =>
+                Err(error_response("405 Method Not Allowed", format!("{} isn't supported for {}", method, path))),
+            _ => Err(not_found(format!("No such resource {}", path))),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+
+    fn users(&self) -> Response {
+        let listeners = self.listeners.lock().unwrap();
+        let users: BTreeMap<&str, Vec<UserInfo>> = listeners.iter().map(|listener| {
+            let mut users: Vec<UserInfo> = listener.users.users().into_iter()
+                .map(|u| UserInfo { user: u.user, backend_user: u.backend_user, default_group: u.default_group })
+                .collect();
+            users.sort_by(|a, b| a.user.cmp(&b.user));
+            (&listener.name[..], users)
+        }).collect();
+        json_response(&users)
+    }
+
+    fn put_user(&self, user: &str, body: &[u8]) -> Result<Response, Response> {
+        let mut value: Value = parse_body(body)?;
+        match value.as_object_mut() {
+            Some(fields) => fields.insert("user".to_string(), Value::String(user.to_string())),
+            None => return Err(bad_request("Expected a user mapping".to_string())),
+        };
+        let mapping: UserMapping = serde_json::from_value(value).map_err(|e| bad_request(e.to_string()))?;
+        if !self.backends.has_group(&mapping.default_group) {
+            return Err(bad_request(format!("Unknown routing group '{}'", mapping.default_group)));
+        }
+        let listeners = self.listeners.lock().unwrap();
+        check_users(&listeners, std::slice::from_ref(&mapping)).map_err(bad_request)?;
+        info!("User '{}' mapped through the management API", user);
+        for listener in listeners.iter() {
+            listener.users.insert(mapping.clone());
+        }
+        drop(listeners);
+        Ok(self.users())
+    }
+
+    fn delete_user(&self, user: &str) -> Result<Response, Response> {
+        let mut removed = false;
+        for listener in self.listeners.lock().unwrap().iter() {
+            removed |= listener.users.remove(user).is_some();
+        }
+        if !removed {
+            return Err(not_found(format!("No user '{}'", user)));
+        }
+        info!("User '{}' removed through the management API", user);
+        Ok(self.users())
+    }
+
+    fn rules(&self) -> Response {
+        let listeners = self.listeners.lock().unwrap();
+        let rules: BTreeMap<&str, Rules> = listeners.iter().map(|l| (&l.name[..], l.rules.get())).collect();
+        json_response(&rules)
+    }
+
+    fn put_rules(&self, body: &[u8]) -> Result<Response, Response> {
+        let rules: Rules = parse_body(body)?;
+        let problems = rules.validate();
+        if !problems.is_empty() {
+            return Err(bad_request(problems.join("; ")));
+        }
+        info!("{} table rule(s) and {} row filter(s) set through the management API",
+              rules.table_rules.len(), rules.row_filters.len());
+        for listener in self.listeners.lock().unwrap().iter() {
+            listener.rules.replace(rules.clone());
+        }
+        Ok(self.rules())
+    }
+
+    fn backends(&self) -> Response {
+        let weights: BTreeMap<String, Vec<BackendWeight>> = self.backends.groups().into_iter().map(|(name, backends)| {
+            let backends = backends.iter().enumerate()
+                .map(|(i, b)| BackendWeight { backend: b.to_string(), weight: self.weights.weight(b, i) })
+                .collect();
+            (name, backends)
+        }).collect();
+        json_response(&weights)
+    }
+
+    fn put_weight(&self, backend: &str, body: &[u8]) -> Result<Response, Response> {
+        let change: WeightChange = parse_body(body)?;
+        let backend: BackendAddr = backend.parse().map_err(|e: Error| bad_request(e.to_string()))?;
+        if !self.backends.all().contains(&backend) {
+            return Err(not_found(format!("No backend {} in any routing group", backend)));
+        }
+        self.weights.set(&backend, change.weight);
+        Ok(self.backends())
+    }
+
+    fn maintenance(&self) -> Response {
+        let state = match self.maintenance.policy() {
+            Some(MaintenancePolicy::RejectQueries { msg, .. }) =>
+                MaintenanceState { mode: Some(MaintenanceKind::RejectQueries), message: Some(msg) },
+            Some(MaintenancePolicy::RefuseConnections { msg, .. }) =>
+                MaintenanceState { mode: Some(MaintenanceKind::RefuseConnections), message: Some(msg) },
+            None => MaintenanceState { mode: None, message: None },
+        };
+        json_response(&state)
+    }
+
+    fn put_maintenance(&self, body: &[u8]) -> Result<Response, Response> {
+        let change: MaintenanceState = parse_body(body)?;
+        let msg = change.message.unwrap_or_else(|| "The server is down for maintenance".to_string());
+        let policy = match change.mode {
+            Some(MaintenanceKind::RejectQueries) => MaintenancePolicy::reject_queries(&msg),
+            Some(MaintenanceKind::RefuseConnections) => MaintenancePolicy::refuse_connections(&msg),
+            None => return Err(bad_request("Expected a mode, reject_queries or refuse_connections".to_string())),
+        };
+        self.maintenance.enable(policy);
+        Ok(self.maintenance())
+    }
+
+    #[cfg(feature = "tls")]
+    fn reload_tls(&self) -> Result<Response, Response> {
+        let certificates = self.certificates.lock().unwrap();
+        if certificates.is_empty() {
+            return Err(not_found("No listener accepts TLS".to_string()));
+        }
+        // a listener that fails keeps its certificate, the others still reload
+        let failures: Vec<String> = certificates.iter()
+            .filter_map(|(name, certificates)| certificates.reload().err().map(|e| format!("Listener '{}': {}", name, e)))
+            .collect();
+        if !failures.is_empty() {
+            return Err(error_response("500 Internal Server Error", failures.join("; ")));
+        }
+        let reloaded: Vec<&str> = certificates.iter().map(|(name, _)| &name[..]).collect();
+        Ok(json_response(&json!({"reloaded": reloaded})))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn reload_tls(&self) -> Result<Response, Response> {
+        Err(not_found("TLS support requires the 'tls' feature".to_string()))
+    }
+}
+
+/// Check user mappings against every listener they'd be given to
+fn check_users(listeners: &[ManagedListener], users: &[UserMapping]) -> Result<(), String> {
+    for listener in listeners {
+        for user in users {
+            listener.users.check(user).map_err(|e| format!("User '{}' on listener '{}': {}", user.user, listener.name, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_body<T>(body: &[u8]) -> Result<T, Response> where T: for<'de> serde::Deserialize<'de> {
+    serde_json::from_slice(body).map_err(|e| bad_request(format!("Invalid request body: {}", e)))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response {
+    ("200 OK", serde_json::to_string(value).expect("management responses serialize"))
+}
+
+fn error_response(status: &'static str, msg: String) -> Response {
+    (status, json!({"error": msg}).to_string())
+}
+
+fn bad_request(msg: String) -> Response {
+    error_response("400 Bad Request", msg)
+}
+
+fn not_found(msg: String) -> Response {
+    error_response("404 Not Found", msg)
+}
+
+/// Answer management requests on a thread of their own, one at a time, returning the
+/// address listened on
+pub fn run_in_thread(config: &ManagementConfig, management: Management) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(config.listen)?;
+    let addr = listener.local_addr()?;
+    let token = config.token.clone();
+    thread::Builder::new().name("mysql-proxy-management".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| answer(&management, token.as_ref().map(|t| &t[..]), stream)) {
+                debug!("Management request failed: {}", e);
+            }
+        }
+    })?;
+    Ok(addr)
+}
+
+fn answer(management: &Management, token: Option<&str>, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    // requests are answered one at a time, so one sent slowly mustn't hold up the others
+    let request = read_request(Deadline { stream: &stream, deadline: Instant::now() + REQUEST_TIMEOUT });
+    let (status, body) = match request {
+        Ok(ref request) if !authorized(token, request.authorization.as_ref().map(|a| &a[..])) =>
+            error_response("401 Unauthorized", "Missing or wrong bearer token".to_string()),
+        Ok(request) => management.handle(&request.method, &request.path, &request.body),
+        Err(ref e) if e.kind() == ErrorKind::InvalidData => bad_request(e.to_string()),
+        Err(e) => return Err(e),
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                           status, body.len(), body);
+    stream.write_all(response.as_bytes())
+}
+
+fn authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let presented = authorization.and_then(|a| a.strip_prefix("Bearer ")).unwrap_or_default();
+    // compare every byte, so the time taken doesn't tell how much of the token was right
+    presented.len() == token.len() && presented.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+fn read_request<R: Read>(stream: R) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let request_line = read_line(&mut reader)?;
+    let mut words = request_line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Not an HTTP request")),
+    };
+    let mut length = 0;
+    let mut authorization = None;
+    for headers in 0.. {
+        let header = read_line(&mut reader)?;
+        if header.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(Error::new(ErrorKind::InvalidData, format!("More than {} headers", MAX_HEADERS)));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    if length > MAX_BODY_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Request body over {} bytes", MAX_BODY_LEN)));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, authorization, body })
+}
+
+/// Reads from a stream until `deadline`, however slowly what's read arrives
+pub struct Deadline<'a> {
+    pub stream: &'a TcpStream,
+    pub deadline: Instant,
+}
+
+impl<'a> Read for Deadline<'a> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "The client took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// A line without its line ending
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during the request"));
+    }
+    if !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "Request line or header too long"));
+    }
+    Ok(line.trim_end().to_string())
+}
@@ -0,0 +1,348 @@
+//! Answering frequent metadata queries from a cache.
+//!
+//! ORMs and drivers ask the same questions every time they connect: `SELECT
+//! @@version_comment`, `SHOW VARIABLES LIKE 'sql_mode'`, `SELECT DATABASE()` and the like.
+//! With a `[metadata_cache]` section, the results of queries starting with one of `queries`
+//! are kept for `ttl_ms`, and sessions sending the same query to the same backend in the same
+//! state get the kept result instead of a round trip. It passes through the session's handlers
+//! as if the backend had sent it.
+//!
+//! A result is only shared between sessions of the same user, logged in the same way, with
+//! the same default schema and the same `SET` statements run, in the same order. Sessions
+//! that may have changed their state otherwise, with `COM_CHANGE_USER`, `CALL` or a query of
+//! several statements, stop using the cache. Errors and results larger than
+//! `max_result_bytes` aren't kept. The TTL bounds how stale a variable that changes on its
+//! own, such as `timestamp`, can get, so keep it short and leave such queries out.
+//!
+//! Dashboards polling the same query tend to get the same result every time. When a result
+//! is kept again and is identical to the one it replaces, the kept one is reused, only its
+//! expiry moving, and the repeat is counted. Sending clients only what changed since their
+//! previous result would need a protocol extension no MySQL client speaks, so every hit is
+//! still sent in full.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::{Packet, PacketType};
+use super::auth::Session;
+use super::connect::BackendAddr;
+use super::pipeline::{is_answered, ResponseKind, ResponsePacket};
+use super::sql::{self, Token};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct MetadataCacheConfig {
+    /// how long a result is kept
+    #[serde(default = "MetadataCacheConfig::default_ttl_ms")]
+    pub ttl_ms: u64,
+    /// results kept at a time, across backends
+    #[serde(default = "MetadataCacheConfig::default_max_entries")]
+    pub max_entries: usize,
+    /// the largest result kept
+    #[serde(default = "MetadataCacheConfig::default_max_result_bytes")]
+    pub max_result_bytes: usize,
+    /// the beginnings of the queries whose results are kept, ignoring case and extra spaces
+    #[serde(default = "MetadataCacheConfig::default_queries")]
+    pub queries: Vec<String>,
+}
+
+impl MetadataCacheConfig {
+
+    fn default_ttl_ms() -> u64 {
+        5000
+    }
+
+    fn default_max_entries() -> usize {
+        1024
+    }
+
+    fn default_max_result_bytes() -> usize {
+        64 * 1024
+    }
+
+    fn default_queries() -> Vec<String> {
+        ["SELECT @@version_comment", "SELECT @@version", "SELECT VERSION()", "SELECT DATABASE()",
+         "SHOW VARIABLES LIKE", "SHOW SESSION VARIABLES LIKE", "SHOW CHARACTER SET", "SHOW COLLATION", "SHOW ENGINES"]
+            .iter().map(|q| q.to_string()).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl_ms == 0 || self.max_entries == 0 {
+            return Err("ttl_ms and max_entries must be at least 1".to_string());
+        }
+        if self.queries.iter().any(|q| normalize(q).is_empty()) {
+            return Err("queries can't be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the result of `sql` may be kept
+    pub fn is_cached(&self, sql: &str) -> bool {
+        let sql = normalize(sql);
+        // a query of several statements could do anything
+        if sql::tokenize(&sql).iter().any(|t| t.is_punct(b';')) {
+            return false;
+        }
+        let sql = sql.to_lowercase();
+        self.queries.iter().any(|q| sql.starts_with(&normalize(q).to_lowercase()))
+    }
+}
+
+impl Default for MetadataCacheConfig {
+    fn default() -> Self {
+        MetadataCacheConfig {
+            ttl_ms: MetadataCacheConfig::default_ttl_ms(),
+            max_entries: MetadataCacheConfig::default_max_entries(),
+            max_result_bytes: MetadataCacheConfig::default_max_result_bytes(),
+            queries: MetadataCacheConfig::default_queries(),
+        }
+    }
+}
+
+/// A query without leading comments, a trailing semicolon or runs of whitespace
+fn normalize(sql: &str) -> String {
+    let mut sql = sql.trim();
+    while sql.starts_with("/*") {
+        sql = match sql.find("*/") {
+            Some(end) => sql[end + 2..].trim_start(),
+            None => "",
+        };
+    }
+    sql.trim_end_matches(';').split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// What makes two sessions get the same result for a query
+#[derive(Clone,Debug,Hash,PartialEq,Eq)]
+pub struct CacheKey {
+    pub backend: BackendAddr,
+    pub user: String,
+    pub character_set: u8,
+    pub backend_capabilities: u32,
+    pub database: Option<String>,
+    /// the `SET` statements the session ran, in order
+    pub settings: Vec<String>,
+    /// the query as sent to the backend
+    pub query: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    /// shared with the sessions being answered with it, so the lock isn't held while they
+    /// copy it
+    packets: Arc<Vec<Vec<u8>>>,
+    expires: Instant,
+}
+
+/// The kept results, shared by a listener's connections
+#[derive(Clone,Debug)]
+pub struct MetadataCache {
+    config: Arc<MetadataCacheConfig>,
+    results: Arc<Mutex<HashMap<CacheKey, CachedResult>>>,
+    repeats: Arc<AtomicU64>,
+}
+
+impl MetadataCache {
+
+    pub fn new(config: &MetadataCacheConfig) -> Self {
+        MetadataCache {
+            config: Arc::new(config.clone()),
+            results: Arc::new(Mutex::new(HashMap::new())),
+            repeats: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The kept result for `key`, unless it has expired
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<Packet>> {
+        let packets = match self.results.lock().unwrap().get(key) {
+            Some(result) if result.expires > Instant::now() => result.packets.clone(),
+            _ => return None,
+        };
+        Some(packets.iter().map(|bytes| Packet { bytes: bytes.clone() }).collect())
+    }
+
+    /// Keep the result of a query, unless the cache is full of results that haven't expired.
+    /// A result identical to the one kept for the same query only extends its expiry.
+    pub fn insert(&self, key: CacheKey, packets: Vec<Vec<u8>>) {
+        let now = Instant::now();
+        let expires = now + Duration::from_millis(self.config.ttl_ms);
+        let mut results = self.results.lock().unwrap();
+        if let Some(result) = results.get_mut(&key) {
+            if *result.packets == packets {
+                result.expires = expires;
+                self.repeats.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        if results.len() >= self.config.max_entries && !results.contains_key(&key) {
+            results.retain(|_, result| result.expires > now);
+            if results.len() >= self.config.max_entries {
+                return;
+            }
+        }
+        results.insert(key, CachedResult { packets: Arc::new(packets), expires });
+    }
+
+    /// How many results kept were identical to the one they replaced
+    pub fn repeats(&self) -> u64 {
+        self.repeats.load(Ordering::Relaxed)
+    }
+
+    /// How many results are kept, including expired ones not yet dropped
+    pub fn len(&self) -> usize {
+        self.results.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What a command does to the state the cache key follows, once it succeeds
+#[derive(Debug,PartialEq)]
+enum Change {
+    Schema(String),
+    Set(String),
+    Reset,
+}
+
+/// A session's use of the metadata cache: answering the queries it sends from the cache, or
+/// keeping their results
+pub struct SessionMetadataCache {
+    cache: MetadataCache,
+    key: CacheKey,
+    /// the session's state is known, so cached results apply to it
+    eligible: bool,
+    /// the changes of the commands awaiting responses, by command number
+    pending: VecDeque<(u64, Change)>,
+    /// the result being kept, with the number of the command it answers
+    recording: Option<(u64, CacheKey, Vec<Vec<u8>>, usize)>,
+}
+
+impl SessionMetadataCache {
+
+    /// Use the cache for a session in the state of `key`, whose query is ignored
+    pub fn new(cache: MetadataCache, key: CacheKey) -> Self {
+        SessionMetadataCache { cache, key, eligible: true, pending: VecDeque::new(), recording: None }
+    }
+
+    pub fn for_session(cache: MetadataCache, session: &Session) -> Self {
+        SessionMetadataCache::new(cache, CacheKey {
+            backend: session.backend_name.clone(),
+            user: session.user.clone(),
+            character_set: session.character_set,
+            backend_capabilities: session.backend_capabilities,
+            database: session.database.clone(),
+            settings: vec![],
+            query: vec![],
+        })
+    }
+
+    /// Observe a command about to be sent to the backend as command number `issued + 1`,
+    /// with `in_flight` commands before it waiting for their response. Returns the kept
+    /// result to answer it with instead, if there is one.
+    pub fn request(&mut self, p: &Packet, in_flight: usize, issued: u64) -> Option<Vec<Packet>> {
+        if !is_answered(p) {
+            return None;
+        }
+        let command = issued + 1;
+        let sql = match p.packet_type() {
+            Ok(PacketType::ComInitDb) => {
+                let schema = String::from_utf8_lossy(&p.payload()[1..]).into_owned();
+                self.pending.push_back((command, Change::Schema(schema)));
+                return None;
+            },
+            Ok(PacketType::ComResetConnection) => {
+                self.pending.push_back((command, Change::Reset));
+                return None;
+            },
+            Ok(PacketType::ComChangeUser) => {
+                self.eligible = false;
+                return None;
+            },
+            Ok(PacketType::ComQuery) => String::from_utf8_lossy(&p.payload()[1..]).into_owned(),
+            _ => return None,
+        };
+        let tokens = sql::tokenize(&sql);
+        let statements = tokens.split(|t| t.is_punct(b';')).filter(|s| !s.is_empty()).count();
+        if statements > 1 || tokens.first().map(|t| t.is_keyword("CALL")).unwrap_or(false) {
+            self.eligible = false;
+            return None;
+        }
+        if let Some(change) = classify(&sql, &tokens) {
+            self.pending.push_back((command, change));
+            return None;
+        }
+        if !self.eligible || in_flight > 0 || self.recording.is_some() || !self.cache.config.is_cached(&sql) {
+            return None;
+        }
+        let key = CacheKey { query: p.payload()[1..].to_vec(), ..self.key.clone() };
+        match self.cache.get(&key) {
+            Some(packets) => Some(packets),
+            None => {
+                self.recording = Some((command, key, vec![], 0));
+                None
+            },
+        }
+    }
+
+    /// Observe a packet from the backend answering command number `command`, before the
+    /// handlers see it
+    pub fn response(&mut self, p: &Packet, answered: Option<ResponsePacket>, command: u64) {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return,
+        };
+        if answered.last {
+            while self.pending.front().map(|&(c, _)| c < command).unwrap_or(false) {
+                self.pending.pop_front();
+            }
+            if self.pending.front().map(|&(c, _)| c == command).unwrap_or(false) {
+                if let Some((_, change)) = self.pending.pop_front() {
+                    if answered.kind != ResponseKind::Err {
+                        self.apply(change);
+                    }
+                }
+            }
+        }
+        let too_large = match self.recording {
+            Some((recorded, _, ref mut packets, ref mut bytes)) if recorded == command => {
+                *bytes += p.bytes.len();
+                packets.push(p.bytes.clone());
+                *bytes > self.cache.config.max_result_bytes
+            },
+            _ => return,
+        };
+        if too_large || answered.kind == ResponseKind::Err {
+            self.recording = None;
+        } else if answered.last {
+            if let Some((_, key, packets, _)) = self.recording.take() {
+                self.cache.insert(key, packets);
+            }
+        }
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Schema(schema) => self.key.database = Some(schema),
+            Change::Set(statement) => {
+                // the latest run of a statement is the one that counts
+                self.key.settings.retain(|s| *s != statement);
+                self.key.settings.push(statement);
+            },
+            Change::Reset => self.key.settings.clear(),
+        }
+    }
+}
+
+/// What a query of a single statement does to the state the cache key follows
+fn classify(sql: &str, tokens: &[Token]) -> Option<Change> {
+    let keyword = |i: usize, k: &str| tokens.get(i).map(|t| t.is_keyword(k)).unwrap_or(false);
+    if keyword(0, "USE") {
+        return tokens.get(1).and_then(|t| t.name.clone()).map(Change::Schema);
+    }
+    if keyword(0, "SET") {
+        return Some(Change::Set(sql.trim().trim_end_matches(';').trim_end().to_string()));
+    }
+    None
+}
@@ -0,0 +1,339 @@
+//! Correlating responses with the commands they answer.
+//!
+//! Clients may pipeline commands, sending several before the first has been answered, and
+//! a MariaDB `COM_STMT_BULK_EXECUTE` sends many rows of parameters in one command. The server
+//! answers commands strictly in order, so a `Correlator` keeps the commands that are waiting
+//! for a response in a queue and follows each response to its end, telling which command
+//! every response packet belongs to and what kind of packet it is.
+//!
+//! A `COM_STMT_EXECUTE` asking for a cursor is answered with the columns only, ending with an
+//! EOF whose status has `SERVER_STATUS_CURSOR_EXISTS`, even under `CLIENT_DEPRECATE_EOF`,
+//! where it's an OK with an EOF header. The rows are then fetched with `COM_STMT_FETCH`, each
+//! answered with some rows and an EOF, so they're followed and counted like any others.
+
+use std::collections::VecDeque;
+use std::io;
+
+use super::{Packet, PacketType};
+use super::codec::{EofPacket, OkPacket, PayloadReader, MAX_PAYLOAD_LEN, SERVER_MORE_RESULTS_EXISTS};
+use super::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+
+/// Status flag set on the EOF after the columns of a result set that is read with a cursor
+pub const SERVER_STATUS_CURSOR_EXISTS: u16 = 0x0040;
+
+/// Status flag set on the EOF after the last row a cursor had
+pub const SERVER_STATUS_LAST_ROW_SENT: u16 = 0x0080;
+
+/// The bits of COM_STMT_EXECUTE's flags that ask for a cursor
+pub const CURSOR_TYPE_MASK: u8 = 0x07;
+
+/// What a response packet is, as far as the structure of the response goes
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum ResponseKind {
+    /// an OK packet, including the OK that ends a result set under CLIENT_DEPRECATE_EOF
+    Ok,
+    Err,
+    Eof,
+    /// anything else, such as column definitions and rows
+    Data,
+}
+
+/// Where a response packet belongs
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ResponsePacket {
+    /// the first byte of the command being answered
+    pub command: u8,
+    pub kind: ResponseKind,
+    /// whether this packet completes the response to the command
+    pub last: bool,
+}
+
+/// The part of a response still to come
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Expect {
+    /// OK, ERR, a LOCAL INFILE request or the start of a result set
+    Results,
+    Columns { remaining: u64 },
+    /// the EOF after the column definitions
+    ColumnsEof,
+    Rows,
+    /// under CLIENT_DEPRECATE_EOF, the OK with an EOF header after the columns of a result set
+    /// read with a cursor, or the first row if the server didn't open one
+    CursorEnd,
+    /// column definitions until EOF, for COM_FIELD_LIST
+    Fields,
+    /// PREPARE_OK, or ERR
+    Prepared,
+    /// parameter and column definitions after PREPARE_OK
+    Definitions { remaining: u64 },
+    /// a single packet, e.g. OK or ERR
+    One,
+    /// EOF, OK or ERR, for COM_SET_OPTION and COM_DEBUG. Servers answer them with EOF, which
+    /// under CLIENT_DEPRECATE_EOF is an OK with an EOF header, some versions with OK.
+    Status,
+    /// an authentication exchange ending with OK or ERR
+    Auth,
+    /// a replication stream, which only ends with the connection
+    Stream,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    command: u8,
+    expect: Expect,
+    /// a COM_STMT_EXECUTE asking for a cursor
+    cursor: bool,
+}
+
+/// Queue of the commands waiting for a response on a connection
+#[derive(Debug)]
+pub struct Correlator {
+    capability_flags: u32,
+    in_flight: VecDeque<InFlight>,
+    /// the last packet filled its payload, so the next continues it
+    continuation: bool,
+    issued: u64,
+    completed: u64,
+    tolerant: bool,
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Correlator::new(CLIENT_PROTOCOL_41)
+    }
+}
+
+impl Correlator {
+
+    /// `capability_flags` are those agreed for the connection during the handshake
+    pub fn new(capability_flags: u32) -> Self {
+        Correlator {
+            capability_flags,
+            in_flight: VecDeque::new(),
+            continuation: false,
+            issued: 0,
+            completed: 0,
+            tolerant: false,
+        }
+    }
+
+    pub fn set_capabilities(&mut self, capability_flags: u32) {
+        self.capability_flags = capability_flags;
+    }
+
+    /// Follow responses from servers that leave out the EOF after column definitions even
+    /// without CLIENT_DEPRECATE_EOF
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.tolerant = tolerant;
+    }
+
+    /// Commands sent to the server that haven't been answered completely
+    pub fn depth(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// How many commands expecting a response have been sent
+    pub fn issued(&self) -> u64 {
+        self.issued
+    }
+
+    /// Whether the next packet from the server is a row, unless it's the EOF, OK or ERR that
+    /// ends the rows
+    pub fn expects_row(&self) -> bool {
+        !self.continuation && self.in_flight.front().map(|f| f.expect == Expect::Rows || f.expect == Expect::CursorEnd).unwrap_or(false)
+    }
+
+    /// How many commands have been answered completely
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// Observe a packet sent to the server after the handshake. Only packets starting a new
+    /// sequence are commands, others continue one, e.g. LOCAL INFILE contents.
+    pub fn request(&mut self, p: &Packet) {
+        if !is_answered(p) {
+            return;
+        }
+        let expect = match p.packet_type() {
+            Ok(PacketType::ComQuery)
+            | Ok(PacketType::ComStmtExecute)
+            | Ok(PacketType::ComStmtBulkExecute)
+            | Ok(PacketType::ComProcessInfo) => Expect::Results,
+            Ok(PacketType::ComStmtFetch) => Expect::Rows,
+            Ok(PacketType::ComFieldList) => Expect::Fields,
+            Ok(PacketType::ComStmtPrepare) => Expect::Prepared,
+            Ok(PacketType::ComChangeUser) => Expect::Auth,
+            Ok(PacketType::ComSetOption) | Ok(PacketType::ComDebug) => Expect::Status,
+            Ok(PacketType::ComBinlogDump) | Ok(PacketType::ComBinlogDumpGtid) => Expect::Stream,
+            _ => Expect::One,
+        };
+        self.issued += 1;
+        self.in_flight.push_back(InFlight { command: p.payload()[0], expect, cursor: opens_cursor(p) });
+    }
+
+    /// Observe a packet received from the server, returning which command it answers, or
+    /// `None` if no command is waiting for a response
+    pub fn response(&mut self, p: &Packet) -> Option<ResponsePacket> {
+        let continued = self.continuation;
+        self.continuation = p.payload().len() == MAX_PAYLOAD_LEN;
+        let deprecate_eof = self.capability_flags & CLIENT_DEPRECATE_EOF != 0;
+        let flags = self.capability_flags;
+        let tolerant = self.tolerant;
+        let front = self.in_flight.front_mut()?;
+        let command = front.command;
+        let cursor = front.cursor;
+        if continued {
+            return Some(ResponsePacket { command, kind: ResponseKind::Data, last: false });
+        }
+
+        let payload = p.payload();
+        let header = payload.first().cloned();
+        let is_eof = header == Some(0xfe) && payload.len() < 9;
+        let (kind, next) = match (front.expect, header) {
+            (Expect::Stream, _) => (ResponseKind::Data, Some(Expect::Stream)),
+            (_, Some(0xff)) => (ResponseKind::Err, None),
+            (Expect::Results, Some(0x00)) => (ResponseKind::Ok, more_results(OkPacket::parse(p, flags).map(|ok| ok.status_flags))),
+            // the client sends the file and the server answers with OK or ERR
+            (Expect::Results, Some(0xfb)) => (ResponseKind::Data, Some(Expect::Results)),
+            (Expect::Results, _) => {
+                let remaining = PayloadReader::new(payload).lenenc_int().unwrap_or(0);
+                (ResponseKind::Data, Some(Expect::Columns { remaining }))
+            },
+            (Expect::Columns { remaining }, _) => {
+                let next = match (remaining.saturating_sub(1), deprecate_eof) {
+                    (0, true) if cursor => Expect::CursorEnd,
+                    (0, true) => Expect::Rows,
+                    (0, false) => Expect::ColumnsEof,
+                    (n, _) => Expect::Columns { remaining: n },
+                };
+                (ResponseKind::Data, Some(next))
+            },
+            // the server went straight to the rows
+            (Expect::ColumnsEof, _) if tolerant && !is_eof => (ResponseKind::Data, Some(Expect::Rows)),
+            (Expect::ColumnsEof, _) => {
+                let status_flags = EofPacket::parse(p).map(|eof| eof.status_flags).unwrap_or(0);
+                // rows of a cursor are fetched with COM_STMT_FETCH
+                if status_flags & SERVER_STATUS_CURSOR_EXISTS != 0 {
+                    (ResponseKind::Eof, None)
+                } else {
+                    (ResponseKind::Eof, Some(Expect::Rows))
+                }
+            },
+            (Expect::CursorEnd, Some(0xfe)) if payload.len() < MAX_PAYLOAD_LEN => {
+                match OkPacket::parse(p, flags).map(|ok| ok.status_flags) {
+                    Ok(status_flags) if status_flags & SERVER_STATUS_CURSOR_EXISTS != 0 => (ResponseKind::Ok, None),
+                    status_flags => (ResponseKind::Ok, more_results(status_flags)),
+                }
+            },
+            (Expect::CursorEnd, _) => (ResponseKind::Data, Some(Expect::Rows)),
+            (Expect::Rows, Some(0xfe)) if deprecate_eof && payload.len() < MAX_PAYLOAD_LEN => {
+                (ResponseKind::Ok, more_results(OkPacket::parse(p, flags).map(|ok| ok.status_flags)))
+            },
+            (Expect::Rows, Some(0xfe)) if is_eof => {
+                (ResponseKind::Eof, more_results(EofPacket::parse(p).map(|eof| eof.status_flags)))
+            },
+            (Expect::Rows, _) => (ResponseKind::Data, Some(Expect::Rows)),
+            (Expect::Fields, Some(0xfe)) if is_eof || deprecate_eof => (ResponseKind::Eof, None),
+            (Expect::Fields, _) => (ResponseKind::Data, Some(Expect::Fields)),
+            (Expect::Prepared, _) => {
+                // PREPARE_OK: status, statement id, columns, parameters
+                let counts = if payload.len() >= 9 {
+                    let columns = payload[5] as u64 | (payload[6] as u64) << 8;
+                    let params = payload[7] as u64 | (payload[8] as u64) << 8;
+                    let eofs = if deprecate_eof { 0 } else { (columns > 0) as u64 + (params > 0) as u64 };
+                    columns + params + eofs
+                } else {
+                    0
+                };
+                (ResponseKind::Data, if counts > 0 { Some(Expect::Definitions { remaining: counts }) } else { None })
+            },
+            (Expect::Definitions { remaining }, _) => {
+                let kind = if is_eof { ResponseKind::Eof } else { ResponseKind::Data };
+                (kind, if remaining > 1 { Some(Expect::Definitions { remaining: remaining - 1 }) } else { None })
+            },
+            (Expect::Auth, Some(0x00)) => (ResponseKind::Ok, None),
+            // auth switch requests and more data for the authentication plugin
+            (Expect::Auth, _) => (ResponseKind::Data, Some(Expect::Auth)),
+            (Expect::One, Some(0x00)) => (ResponseKind::Ok, None),
+            (Expect::One, Some(0xfe)) if is_eof => (ResponseKind::Eof, None),
+            (Expect::One, _) => (ResponseKind::Data, None),
+            (Expect::Status, Some(0x00)) => (ResponseKind::Ok, None),
+            (Expect::Status, Some(0xfe)) if deprecate_eof => (ResponseKind::Ok, None),
+            (Expect::Status, Some(0xfe)) => (ResponseKind::Eof, None),
+            (Expect::Status, _) => (ResponseKind::Data, None),
+        };
+
+        match next {
+            Some(expect) => {
+                front.expect = expect;
+                Some(ResponsePacket { command, kind, last: false })
+            },
+            None => {
+                self.in_flight.pop_front();
+                self.completed += 1;
+                Some(ResponsePacket { command, kind, last: true })
+            },
+        }
+    }
+}
+
+/// Responses the proxy makes to commands itself, held until the commands sent before them
+/// have been answered, so pipelined commands get their responses in order
+#[derive(Debug,Default)]
+pub struct HeldResponses {
+    /// each response with the number of commands that must be answered before it
+    held: VecDeque<(u64, Vec<Packet>)>,
+}
+
+impl HeldResponses {
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// The packets to send the client now: the response itself if nothing is waiting for an
+    /// answer, otherwise nothing until it's released
+    pub fn respond(&mut self, correlator: &Correlator, packets: Vec<Packet>) -> Vec<Packet> {
+        if self.held.is_empty() && correlator.depth() == 0 {
+            return packets;
+        }
+        self.held.push_back((correlator.issued(), packets));
+        vec![]
+    }
+
+    /// The held responses whose preceding commands have now been answered
+    pub fn release(&mut self, correlator: &Correlator) -> Vec<Packet> {
+        let mut released = vec![];
+        while let Some(&(after, _)) = self.held.front() {
+            if correlator.completed() < after {
+                break;
+            }
+            released.extend(self.held.pop_front().unwrap().1);
+        }
+        released
+    }
+}
+
+/// Whether a command is a COM_STMT_EXECUTE asking for a cursor, whose rows are fetched with
+/// COM_STMT_FETCH. The server may still not open one, e.g. for a statement without results.
+pub fn opens_cursor(p: &Packet) -> bool {
+    p.packet_type().ok() == Some(PacketType::ComStmtExecute)
+        && p.payload().get(5).map(|&flags| flags & CURSOR_TYPE_MASK != 0).unwrap_or(false)
+}
+
+/// Whether a packet sent to the server after the handshake is a command the server answers
+pub fn is_answered(p: &Packet) -> bool {
+    if p.sequence_id() != 0 || p.payload().is_empty() {
+        return false;
+    }
+    // these are never answered
+    !matches!(p.packet_type(), Ok(PacketType::ComQuit) | Ok(PacketType::ComStmtSendLongData) | Ok(PacketType::ComStmtClose))
+}
+
+/// Another result set follows if the server says so, otherwise the response is complete
+fn more_results(status_flags: io::Result<u16>) -> Option<Expect> {
+    match status_flags {
+        Ok(flags) if flags & SERVER_MORE_RESULTS_EXISTS != 0 => Some(Expect::Results),
+        _ => None,
+    }
+}
@@ -0,0 +1,138 @@
+//! Pooling of packet and socket buffers.
+//!
+//! Every packet the proxy relays needs a buffer for its bytes, and every connection needs a
+//! buffer to read into. A `BufferPool` keeps released buffers for reuse, so that busy proxies
+//! don't spend their time in the allocator. Pools are cheap to clone and clones share their
+//! buffers, so one pool can serve every connection, on any thread.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Sizing of a `BufferPool`
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct PoolConfig {
+    /// initial capacity of newly allocated buffers
+    #[serde(default = "PoolConfig::default_buffer_size")]
+    pub buffer_size: usize,
+    /// how many released buffers are kept for reuse
+    #[serde(default = "PoolConfig::default_max_buffers")]
+    pub max_buffers: usize,
+    /// released buffers that have grown larger than this are freed rather than kept
+    #[serde(default = "PoolConfig::default_max_buffer_size")]
+    pub max_buffer_size: usize,
+}
+
+impl PoolConfig {
+
+    fn default_buffer_size() -> usize {
+        4096
+    }
+
+    fn default_max_buffers() -> usize {
+        1024
+    }
+
+    fn default_max_buffer_size() -> usize {
+        64 * 1024
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            buffer_size: PoolConfig::default_buffer_size(),
+            max_buffers: PoolConfig::default_max_buffers(),
+            max_buffer_size: PoolConfig::default_max_buffer_size(),
+        }
+    }
+}
+
+/// A snapshot of a pool's counters
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct PoolStats {
+    /// buffers handed out from the pool
+    pub hits: usize,
+    /// buffers that had to be allocated because the pool was empty
+    pub misses: usize,
+    /// buffers that were freed on release, because the pool was full or they were too large
+    pub discarded: usize,
+    /// buffers currently available for reuse
+    pub pooled: usize,
+}
+
+#[derive(Debug,Default)]
+struct Counters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    discarded: AtomicUsize,
+}
+
+/// Shared pool of reusable byte buffers
+#[derive(Clone,Debug)]
+pub struct BufferPool {
+    config: Arc<PoolConfig>,
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    counters: Arc<Counters>,
+}
+
+impl BufferPool {
+
+    pub fn new(config: PoolConfig) -> Self {
+        BufferPool {
+            config: Arc::new(config),
+            buffers: Arc::new(Mutex::new(vec![])),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes
+    pub fn acquire(&self, capacity: usize) -> Vec<u8> {
+        let pooled = self.buffers.lock().unwrap().pop();
+        match pooled {
+            Some(mut buf) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                buf.reserve(capacity);
+                buf
+            },
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(capacity.max(self.config.buffer_size))
+            },
+        }
+    }
+
+    /// Return a buffer to the pool once it's no longer needed
+    pub fn release(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > self.config.max_buffer_size || buf.capacity() == 0 {
+            self.counters.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.config.max_buffers {
+            buf.clear();
+            buffers.push(buf);
+        } else {
+            self.counters.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The configured size of new buffers
+    pub fn buffer_size(&self) -> usize {
+        self.config.buffer_size
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            discarded: self.counters.discarded.load(Ordering::Relaxed),
+            pooled: self.buffers.lock().unwrap().len(),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(PoolConfig::default())
+    }
+}
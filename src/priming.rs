@@ -0,0 +1,269 @@
+//! Priming new sessions with the setup statements their application always sends.
+//!
+//! Drivers and ORMs follow every login with the same handful of statements, such as `SET
+//! NAMES`, `SET autocommit` and `SET sql_mode`, each a round trip between the client and the
+//! backend. With a `[session_priming]` section, the proxy learns the `SET` statements each
+//! user's sessions start with and, once `sessions` sessions in a row have started with the
+//! same ones, sends them to the backend itself as soon as a new session of the user is logged
+//! in. When the client then sends them, each is answered with the response the backend gave
+//! to the primed statement, which is usually already there, so the setup takes no round trips
+//! to the backend at all.
+//!
+//! A session that starts differently, leaving out a primed statement or sending another
+//! command first, has its backend connection reset with `COM_RESET_CONNECTION` and the
+//! statements it did send run again, with the responses kept from the client, before its
+//! command goes on, and the user's statements are learnt again. The proxy has no pool of
+//! backend connections to prime ahead of logins, so the statements are sent right after the
+//! login instead.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::{Packet, PacketType};
+use super::codec::ErrPacket;
+use super::pipeline::{is_answered, ResponseKind, ResponsePacket};
+use super::sql::{self, Token};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct SessionPrimingConfig {
+    /// sessions in a row starting with the same statements before they are primed
+    #[serde(default = "SessionPrimingConfig::default_sessions")]
+    pub sessions: u32,
+    /// the most setup statements learnt for a user
+    #[serde(default = "SessionPrimingConfig::default_max_statements")]
+    pub max_statements: usize,
+}
+
+impl SessionPrimingConfig {
+
+    fn default_sessions() -> u32 {
+        3
+    }
+
+    fn default_max_statements() -> usize {
+        16
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sessions == 0 || self.max_statements == 0 {
+            return Err("sessions and max_statements must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SessionPrimingConfig {
+    fn default() -> Self {
+        SessionPrimingConfig {
+            sessions: SessionPrimingConfig::default_sessions(),
+            max_statements: SessionPrimingConfig::default_max_statements(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Learnt {
+    statements: Vec<String>,
+    /// sessions in a row that started with them
+    sessions: u32,
+}
+
+/// The setup statements learnt for each user, shared by a listener's connections
+#[derive(Clone,Debug)]
+pub struct Priming {
+    config: SessionPrimingConfig,
+    users: Arc<Mutex<HashMap<String, Learnt>>>,
+}
+
+impl Priming {
+
+    pub fn new(config: SessionPrimingConfig) -> Self {
+        Priming { config, users: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// The statements to prime `user`'s sessions with, once enough sessions started with them
+    pub fn statements(&self, user: &str) -> Vec<String> {
+        match self.users.lock().unwrap().get(user) {
+            Some(learnt) if learnt.sessions >= self.config.sessions => learnt.statements.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// A session of `user` started with `statements`
+    pub fn learn(&self, user: &str, statements: Vec<String>) {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(user) {
+            Some(learnt) if learnt.statements == statements => {
+                learnt.sessions = learnt.sessions.saturating_add(1);
+                return;
+            },
+            _ => {},
+        }
+        if statements.is_empty() {
+            users.remove(user);
+        } else {
+            users.insert(user.to_string(), Learnt { statements, sessions: 1 });
+        }
+    }
+}
+
+/// What to do with a client command
+#[derive(Debug,PartialEq)]
+pub enum Primed {
+    /// send it to the backend as usual
+    Send,
+    /// don't, it was primed: the responses are those the backend already gave, with the
+    /// number of the command they answer, and any still to come pass on to the client as
+    /// they arrive
+    Claimed(Vec<(Packet, ResponsePacket, u64)>),
+    /// send these commands first, whose responses the client mustn't see, then the command
+    Undo(Vec<Packet>),
+}
+
+#[derive(Debug)]
+struct Prime {
+    statement: String,
+    claimed: bool,
+    /// the response, if it came before the client sent the statement
+    response: Option<(Packet, ResponsePacket, u64)>,
+}
+
+/// A session's priming: the statements sent for it at the start, and those it sends itself
+pub struct SessionPriming {
+    priming: Priming,
+    user: String,
+    started: bool,
+    /// the primed statements, sent as commands 1 to n
+    primes: Vec<Prime>,
+    /// more of the primes may still be claimed
+    priming_done: bool,
+    /// the setup statements the session sent, while it hasn't sent anything else
+    setup: Option<Vec<String>>,
+    /// the numbers of the proxy's own commands, whose responses are kept from the client
+    hidden: HashSet<u64>,
+    failed: Option<io::Error>,
+}
+
+impl SessionPriming {
+
+    pub fn new(priming: Priming, user: &str) -> Self {
+        SessionPriming {
+            priming,
+            user: user.to_string(),
+            started: false,
+            primes: vec![],
+            priming_done: false,
+            setup: Some(vec![]),
+            hidden: HashSet::new(),
+            failed: None,
+        }
+    }
+
+    /// The statements to send before any of the client's commands, the first time it's called
+    pub fn start(&mut self) -> Vec<Packet> {
+        if self.started {
+            return vec![];
+        }
+        self.started = true;
+        self.primes = self.priming.statements(&self.user).into_iter()
+            .map(|statement| Prime { statement, claimed: false, response: None })
+            .collect();
+        self.priming_done = self.primes.is_empty();
+        self.primes.iter().map(|prime| Packet::com_query(&prime.statement)).collect()
+    }
+
+    /// Observe a client command about to be sent to the backend as command number `issued + 1`
+    pub fn request(&mut self, p: &Packet, issued: u64) -> Primed {
+        if !is_answered(p) {
+            return Primed::Send;
+        }
+        let statement = match p.packet_type() {
+            Ok(PacketType::ComQuery) => setup_statement(&String::from_utf8_lossy(&p.payload()[1..])),
+            _ => None,
+        };
+        let max_statements = self.priming.config.max_statements;
+        let setup = match (self.setup.take(), statement.clone()) {
+            (Some(mut setup), Some(statement)) if setup.len() < max_statements => {
+                setup.push(statement);
+                Some(setup)
+            },
+            (Some(setup), _) => {
+                self.priming.learn(&self.user, setup);
+                None
+            },
+            (None, _) => None,
+        };
+        self.setup = setup;
+        if self.priming_done {
+            return Primed::Send;
+        }
+        if let Some(prime) = self.primes.iter_mut().find(|prime| !prime.claimed) {
+            if statement.as_ref() == Some(&prime.statement) {
+                prime.claimed = true;
+                let claimed = prime.response.take().into_iter().collect();
+                self.priming_done = self.primes.iter().all(|prime| prime.claimed);
+                return Primed::Claimed(claimed);
+            }
+        }
+        // the session starts differently, so the backend must forget the statements the
+        // client didn't send
+        self.priming_done = true;
+        debug!("Session of '{}' didn't start with its primed statements, resetting its connection", self.user);
+        let mut undo = vec![Packet::com_reset_connection()];
+        undo.extend(self.primes.iter().filter(|prime| prime.claimed).map(|prime| Packet::com_query(&prime.statement)));
+        self.hidden.extend((1..=undo.len() as u64).map(|n| issued + n));
+        Primed::Undo(undo)
+    }
+
+    /// Observe a response packet answering command number `command`. Returns the packet to
+    /// pass on, or `None` if it answers a primed statement the client hasn't sent yet or one
+    /// of the proxy's own commands.
+    pub fn response(&mut self, p: Packet, answered: Option<ResponsePacket>, command: u64) -> Option<Packet> {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return Some(p),
+        };
+        if self.hidden.contains(&command) {
+            if answered.kind == ResponseKind::Err && self.failed.is_none() {
+                let msg = ErrPacket::parse(&p).map(|e| e.message).unwrap_or_default();
+                self.failed = Some(io::Error::other(format!("Could not reset a primed session: {}", msg)));
+            }
+            if answered.last {
+                self.hidden.remove(&command);
+            }
+            return None;
+        }
+        match self.primes.get_mut((command as usize).wrapping_sub(1)) {
+            Some(prime) if !prime.claimed => {
+                // a statement the client gave up on is forgotten by the reset
+                if !self.priming_done {
+                    prime.response = Some((p, answered, command));
+                }
+                None
+            },
+            _ => Some(p),
+        }
+    }
+
+    /// The error that ends the session, if the reset or a statement run again failed
+    pub fn failed(&mut self) -> Option<io::Error> {
+        self.failed.take()
+    }
+}
+
+/// The statement, if `sql` is a single `SET` of session state
+fn setup_statement(sql: &str) -> Option<String> {
+    let tokens = sql::tokenize(sql);
+    let statements: Vec<&[Token]> = tokens.split(|t| t.is_punct(b';')).filter(|s| !s.is_empty()).collect();
+    let tokens = match statements.as_slice() {
+        [tokens] => tokens,
+        _ => return None,
+    };
+    let keyword = |i: usize, k: &str| tokens.get(i).map(|t| t.is_keyword(k)).unwrap_or(false);
+    if !keyword(0, "SET") || keyword(1, "TRANSACTION") || keyword(1, "PASSWORD")
+        || tokens.iter().any(|t| t.is_keyword("GLOBAL") || t.is_keyword("PERSIST") || t.is_keyword("PERSIST_ONLY")) {
+        return None;
+    }
+    Some(sql.trim().trim_end_matches(';').trim_end().to_string())
+}
@@ -0,0 +1,85 @@
+//! End-to-end checks of backends, before a proxy takes traffic.
+//!
+//! Where `ProxyConfig::dry_run` only checks that backends resolve, a probe connects to each,
+//! logs in with the credentials it's given and runs `SELECT 1`, so a deployment pipeline can
+//! tell that a new proxy instance will be able to serve its clients before moving them over.
+//! Backends are probed directly, each on a thread of its own, even when the proxy reaches them
+//! through an upstream proxy, and logins use mysql_native_password without TLS, as side
+//! queries do.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::connect::BackendAddr;
+use super::sidechannel::{SideChannel, SideChannelConfig};
+
+/// The query a probe runs once logged in
+pub const PROBE_QUERY: &str = "SELECT 1";
+
+/// How one backend answered a probe
+#[derive(Debug)]
+pub struct ProbeResult {
+    pub backend: BackendAddr,
+    /// the address probed last, if the backend resolved
+    pub addr: Option<SocketAddr>,
+    /// how long resolving, logging in and querying took
+    pub elapsed: Duration,
+    pub result: Result<()>,
+}
+
+impl ProbeResult {
+
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Log in to each of `backends` as `user`, run `SELECT 1` and check its answer, waiting up to
+/// `timeout` at each step. The results are in the order of `backends`.
+pub fn probe_all(backends: &[BackendAddr], user: &str, password: &str, timeout: Duration) -> Vec<ProbeResult> {
+    let threads: Vec<_> = backends.iter().cloned().map(|backend| {
+        let (user, password) = (user.to_string(), password.to_string());
+        thread::spawn(move || probe(&backend, &user, &password, timeout))
+    }).collect();
+    threads.into_iter().zip(backends).map(|(t, backend)| t.join().unwrap_or_else(|_| ProbeResult {
+        backend: backend.clone(),
+        addr: None,
+        elapsed: Duration::default(),
+        result: Err(Error::other("Probe thread failed")),
+    })).collect()
+}
+
+/// Probe a single backend, trying each address it resolves to until one answers
+pub fn probe(backend: &BackendAddr, user: &str, password: &str, timeout: Duration) -> ProbeResult {
+    let started = Instant::now();
+    let channel = SideChannel::new(&SideChannelConfig {
+        backend_user: user.to_string(),
+        backend_password: password.to_string(),
+        timeout_ms: timeout.as_millis().max(1) as u64,
+        max_concurrent: 1,
+        max_idle: 0,
+        max_rows: 1,
+        max_lifetime_secs: None,
+        max_idle_secs: None,
+    });
+    let mut addr = None;
+    let result = (backend.host(), backend.port()).to_socket_addrs().and_then(|addrs| {
+        let mut result = Err(Error::new(ErrorKind::NotFound, "Resolves to no addresses"));
+        for a in addrs {
+            addr = Some(a);
+            result = channel.query_blocking(a, PROBE_QUERY).and_then(|answer| {
+                match answer.rows.first().and_then(|row| row.first()) {
+                    Some(Some(value)) if value == b"1" => Ok(()),
+                    _ => Err(Error::new(ErrorKind::InvalidData, format!("Unexpected answer to {}", PROBE_QUERY))),
+                }
+            });
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    });
+    ProbeResult { backend: backend.clone(), addr, elapsed: started.elapsed(), result }
+}
@@ -0,0 +1,194 @@
+//! Hooks marking the stages of a pipe's work, for profilers.
+//!
+//! With the `profiling` feature, pipes mark the time they spend reading from sockets, which
+//! includes decompressing, splitting what they read into packets, running handlers and
+//! writing. A `StageHook` installed with `set_hook` is told, on the thread doing the work,
+//! when a stage is entered and left, so it can enter a `tracing` span for `tracing-flame`, or
+//! tag the samples of pprof-rs, and attribute CPU to the stages in production. Only one in
+//! `sample_every` stages is reported, to keep the hook's cost low enough to leave it on.
+//!
+//! `[profiling]` installs `StageTotals`, which counts the sampled stages and their time,
+//! served by the management API at `/profile`. Without the feature, `enter` does nothing
+//! and costs nothing.
+
+#[cfg(feature = "profiling")]
+use std::cell::Cell;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "profiling")]
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+#[cfg(feature = "profiling")]
+use std::time::Instant;
+
+/// Part of the work of relaying packets
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum Stage {
+    /// reading from a socket
+    Reader,
+    /// splitting what was read into packets
+    Codec,
+    /// running the session's handlers on a packet
+    Handler,
+    /// writing to a socket
+    Writer,
+}
+
+impl Stage {
+
+    pub const ALL: [Stage; 4] = [Stage::Reader, Stage::Codec, Stage::Handler, Stage::Writer];
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Stage::Reader => "reader",
+            Stage::Codec => "codec",
+            Stage::Handler => "handler",
+            Stage::Writer => "writer",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Told about sampled stages, on the thread running them
+pub trait StageHook: Send + Sync {
+
+    fn enter(&self, _stage: Stage) {}
+
+    fn exit(&self, stage: Stage, elapsed: Duration);
+}
+
+/// Stage sampling, e.g. `sample_every = 100`
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ProfilingConfig {
+    /// report one in this many stages, 1 for all of them
+    #[serde(default = "ProfilingConfig::default_sample_every")]
+    pub sample_every: u32,
+}
+
+impl ProfilingConfig {
+
+    fn default_sample_every() -> u32 {
+        100
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sample_every == 0 {
+            return Err("sample_every must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+type Installed = (Arc<dyn StageHook>, u32);
+
+static HOOK: RwLock<Option<Installed>> = RwLock::new(None);
+
+#[cfg(feature = "profiling")]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "profiling")]
+thread_local! {
+    /// stages entered on this thread since the last one sampled
+    static SKIPPED: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Report one in `sample_every` stages to `hook`, in place of any hook installed before
+pub fn set_hook(hook: Arc<dyn StageHook>, sample_every: u32) {
+    *HOOK.write().unwrap() = Some((hook, sample_every.max(1)));
+    #[cfg(feature = "profiling")]
+    INSTALLED.store(true, Ordering::Release);
+}
+
+/// Stop reporting stages
+pub fn clear_hook() {
+    #[cfg(feature = "profiling")]
+    INSTALLED.store(false, Ordering::Release);
+    *HOOK.write().unwrap() = None;
+}
+
+/// Marks a stage until it is dropped
+#[must_use]
+pub struct StageGuard {
+    #[cfg(feature = "profiling")]
+    sampled: Option<(Arc<dyn StageHook>, Stage, Instant)>,
+}
+
+impl Drop for StageGuard {
+
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(feature = "profiling")]
+        if let Some((ref hook, stage, entered)) = self.sampled {
+            hook.exit(stage, entered.elapsed());
+        }
+    }
+}
+
+/// Mark the start of a stage, which lasts until the guard is dropped
+#[cfg(feature = "profiling")]
+#[inline]
+pub fn enter(stage: Stage) -> StageGuard {
+    if !INSTALLED.load(Ordering::Acquire) {
+        return StageGuard { sampled: None };
+    }
+    let sampled = match *HOOK.read().unwrap() {
+        Some((ref hook, sample_every)) => {
+            let due = SKIPPED.with(|skipped| {
+                let n = skipped.get() + 1;
+                skipped.set(if n >= sample_every { 0 } else { n });
+                n >= sample_every
+            });
+            if due { Some(hook.clone()) } else { None }
+        },
+        None => None,
+    };
+    StageGuard {
+        sampled: sampled.map(|hook| {
+            hook.enter(stage);
+            (hook, stage, Instant::now())
+        }),
+    }
+}
+
+/// Mark the start of a stage, which lasts until the guard is dropped
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+pub fn enter(_stage: Stage) -> StageGuard {
+    StageGuard {}
+}
+
+/// The number of sampled stages and their time, by stage
+#[derive(Clone,Debug,Default)]
+pub struct StageTotals {
+    counts: Arc<[AtomicU64; 4]>,
+    nanos: Arc<[AtomicU64; 4]>,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct StageTotal {
+    pub stage: &'static str,
+    pub samples: u64,
+    pub total_us: u64,
+}
+
+impl StageTotals {
+
+    pub fn snapshot(&self) -> Vec<StageTotal> {
+        Stage::ALL.iter().map(|stage| StageTotal {
+            stage: stage.as_str(),
+            samples: self.counts[stage.index()].load(Ordering::Relaxed),
+            total_us: self.nanos[stage.index()].load(Ordering::Relaxed) / 1000,
+        }).collect()
+    }
+}
+
+impl StageHook for StageTotals {
+
+    fn exit(&self, stage: Stage, elapsed: Duration) {
+        self.counts[stage.index()].fetch_add(1, Ordering::Relaxed);
+        self.nanos[stage.index()].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,183 @@
+//! MySQL protocol constants and the mysql_native_password and mysql_old_password schemes.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use sha1::Sha1;
+
+pub const CLIENT_LONG_PASSWORD: u32 = 0x0000_0001;
+pub const CLIENT_FOUND_ROWS: u32 = 0x0000_0002;
+pub const CLIENT_LONG_FLAG: u32 = 0x0000_0004;
+pub const CLIENT_CONNECT_WITH_DB: u32 = 0x0000_0008;
+pub const CLIENT_NO_SCHEMA: u32 = 0x0000_0010;
+pub const CLIENT_COMPRESS: u32 = 0x0000_0020;
+pub const CLIENT_ODBC: u32 = 0x0000_0040;
+pub const CLIENT_LOCAL_FILES: u32 = 0x0000_0080;
+pub const CLIENT_IGNORE_SPACE: u32 = 0x0000_0100;
+pub const CLIENT_PROTOCOL_41: u32 = 0x0000_0200;
+pub const CLIENT_INTERACTIVE: u32 = 0x0000_0400;
+pub const CLIENT_SSL: u32 = 0x0000_0800;
+pub const CLIENT_IGNORE_SIGPIPE: u32 = 0x0000_1000;
+pub const CLIENT_TRANSACTIONS: u32 = 0x0000_2000;
+pub const CLIENT_SECURE_CONNECTION: u32 = 0x0000_8000;
+pub const CLIENT_MULTI_STATEMENTS: u32 = 0x0001_0000;
+pub const CLIENT_MULTI_RESULTS: u32 = 0x0002_0000;
+pub const CLIENT_PS_MULTI_RESULTS: u32 = 0x0004_0000;
+pub const CLIENT_PLUGIN_AUTH: u32 = 0x0008_0000;
+pub const CLIENT_CONNECT_ATTRS: u32 = 0x0010_0000;
+pub const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 0x0020_0000;
+pub const CLIENT_SESSION_TRACK: u32 = 0x0080_0000;
+pub const CLIENT_DEPRECATE_EOF: u32 = 0x0100_0000;
+
+pub const NATIVE_PASSWORD_PLUGIN: &str = "mysql_native_password";
+pub const CLEAR_PASSWORD_PLUGIN: &str = "mysql_clear_password";
+pub const OLD_PASSWORD_PLUGIN: &str = "mysql_old_password";
+
+/// MySQL error ER_ACCESS_DENIED_ERROR
+pub const ER_ACCESS_DENIED_ERROR: u16 = 1045;
+
+/// MySQL error ER_NET_PACKETS_OUT_OF_ORDER, sent to clients whose sequence ids don't follow
+/// the exchange
+pub const ER_NET_PACKETS_OUT_OF_ORDER: u16 = 1156;
+
+/// MySQL error ER_NET_READ_INTERRUPTED, sent to clients that are too slow to log in
+pub const ER_NET_READ_INTERRUPTED: u16 = 1159;
+
+/// Generate a random 20 byte scramble for mysql_native_password, from the operating system's
+/// random number generator, since a predictable scramble would let responses be replayed
+pub fn generate_scramble() -> io::Result<Vec<u8>> {
+    let mut random = File::open("/dev/urandom")?;
+    let mut scramble = Vec::with_capacity(20);
+    let mut bytes = [0_u8; 32];
+    while scramble.len() < 20 {
+        random.read_exact(&mut bytes)?;
+        for &b in bytes.iter() {
+            // printable ASCII, excluding '$' like the MySQL server does, from the bytes
+            // that map onto it evenly
+            if b >= 2 * 0x5e {
+                continue;
+            }
+            let b = 0x21 + b % 0x5e;
+            if b != b'$' && scramble.len() < 20 {
+                scramble.push(b);
+            }
+        }
+    }
+    Ok(scramble)
+}
+
+fn sha1(data: &[&[u8]]) -> [u8; 20] {
+    let mut m = Sha1::new();
+    for d in data {
+        m.update(d);
+    }
+    m.digest().bytes()
+}
+
+/// Compute the mysql_native_password auth response for a password and server scramble:
+/// SHA1(password) XOR SHA1(scramble + SHA1(SHA1(password)))
+pub fn native_password_auth(password: &str, scramble: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return vec![];
+    }
+    let stage1 = sha1(&[password.as_bytes()]);
+    let stage2 = sha1(&[&stage1]);
+    let mask = sha1(&[scramble, &stage2]);
+    stage1.iter().zip(mask.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+/// The mysql_clear_password auth response: the password itself, null terminated
+pub fn clear_password_auth(password: &str) -> Vec<u8> {
+    let mut auth = password.as_bytes().to_vec();
+    auth.push(0);
+    auth
+}
+
+/// The double SHA1 hash of a password that MySQL stores for mysql_native_password accounts,
+/// formatted the same way as `PASSWORD()`, e.g. `*2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19`
+pub fn native_password_hash(password: &str) -> String {
+    let stage2 = sha1(&[&sha1(&[password.as_bytes()])]);
+    let mut s = String::with_capacity(41);
+    s.push('*');
+    for b in stage2.iter() {
+        s.push_str(&format!("{:02X}", b));
+    }
+    s
+}
+
+/// Check a mysql_native_password auth response against a stored `*HEX` password hash
+pub fn verify_native_password(hash: &str, scramble: &[u8], auth_response: &[u8]) -> bool {
+    let stage2 = match parse_password_hash(hash) {
+        Some(h) => h,
+        None => return false,
+    };
+    if auth_response.len() != 20 {
+        return false;
+    }
+    let mask = sha1(&[scramble, &stage2]);
+    let stage1: Vec<u8> = auth_response.iter().zip(mask.iter()).map(|(a, b)| a ^ b).collect();
+    sha1(&[&stage1]) == stage2
+}
+
+fn parse_password_hash(hash: &str) -> Option<[u8; 20]> {
+    if hash.len() != 41 || !hash.starts_with('*') {
+        return None;
+    }
+    let mut out = [0_u8; 20];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hash[1 + i * 2..3 + i * 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+/// The pre-4.1 hash of a password that MySQL stores for mysql_old_password accounts, as two
+/// 31 bit numbers. Spaces and tabs in the password are ignored.
+pub fn old_password_hash(password: &[u8]) -> [u32; 2] {
+    let (mut nr, mut add, mut nr2) = (1_345_345_333_u32, 7_u32, 0x1234_5671_u32);
+    for &c in password.iter().filter(|&&c| c != b' ' && c != b'\t') {
+        let c = c as u32;
+        nr ^= ((nr & 63).wrapping_add(add)).wrapping_mul(c).wrapping_add(nr << 8);
+        nr2 = nr2.wrapping_add((nr2 << 8) ^ nr);
+        add = add.wrapping_add(c);
+    }
+    [nr & 0x7fff_ffff, nr2 & 0x7fff_ffff]
+}
+
+/// Format an old password hash the way `OLD_PASSWORD()` does, e.g. `5d2e19393cc5ef67`
+pub fn format_old_password_hash(hash: [u32; 2]) -> String {
+    format!("{:08x}{:08x}", hash[0], hash[1])
+}
+
+/// Compute the mysql_old_password auth response for a password hash and the first 8 bytes
+/// of the server scramble
+pub fn old_password_auth(hash: [u32; 2], scramble: &[u8]) -> Vec<u8> {
+    let message = old_password_hash(&scramble[..scramble.len().min(8)]);
+    let max = 0x3fff_ffff_u64;
+    let mut seed1 = (hash[0] ^ message[0]) as u64 % max;
+    let mut seed2 = (hash[1] ^ message[1]) as u64 % max;
+    let mut rnd = || {
+        seed1 = (seed1 * 3 + seed2) % max;
+        seed2 = (seed1 + seed2 + 33) % max;
+        seed1 as f64 / max as f64
+    };
+    let mut auth: Vec<u8> = (0..8).map(|_| (rnd() * 31.0) as u8 + 64).collect();
+    let extra = (rnd() * 31.0) as u8;
+    for b in auth.iter_mut() {
+        *b ^= extra;
+    }
+    auth
+}
+
+/// Check a mysql_old_password auth response against a 16 hex digit `OLD_PASSWORD()` hash
+pub fn verify_old_password(hash: &str, scramble: &[u8], auth_response: &[u8]) -> bool {
+    if hash.len() != 16 {
+        return false;
+    }
+    let (high, low) = match (u32::from_str_radix(&hash[..8], 16), u32::from_str_radix(&hash[8..], 16)) {
+        (Ok(high), Ok(low)) => (high, low),
+        _ => return false,
+    };
+    // the client may terminate the response with a null byte
+    let auth_response = auth_response.strip_suffix(&[0]).unwrap_or(auth_response);
+    old_password_auth([high, low], scramble) == auth_response
+}
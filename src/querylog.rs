@@ -0,0 +1,231 @@
+//! Sampling of full query text, slow log style.
+//!
+//! With a `[query_log]` section, a fraction of the queries taking at least `min_time_ms` is
+//! written to a file, one line of JSON per query with its user, fingerprint, full text, time
+//! and any error. Queries are sampled per fingerprint: the first of each is always logged,
+//! then `sample_rate` of the rest, so rare queries show up alongside frequent ones. Unless
+//! `scrub` is turned off, string and number literals in the text are replaced by `?` first,
+//! so the log shows the shape of the workload without the values in it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::ErrPacket;
+use super::pipeline::{Correlator, ResponseKind};
+use super::sql;
+use super::stats::MAX_DIGESTS;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct QueryLogConfig {
+    pub path: PathBuf,
+    /// the fraction of each fingerprint's queries logged after its first, from 0 to 1
+    #[serde(default = "QueryLogConfig::default_sample_rate")]
+    pub sample_rate: f64,
+    /// only queries that take at least this long are sampled
+    #[serde(default)]
+    pub min_time_ms: u64,
+    /// replace literals in the logged text by `?`
+    #[serde(default = "QueryLogConfig::default_scrub")]
+    pub scrub: bool,
+}
+
+impl QueryLogConfig {
+
+    fn default_sample_rate() -> f64 {
+        0.01
+    }
+
+    fn default_scrub() -> bool {
+        true
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            return Err(format!("sample_rate must be between 0 and 1, not {}", self.sample_rate));
+        }
+        Ok(())
+    }
+}
+
+/// A logged query
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct QuerySample {
+    pub timestamp_ms: u64,
+    pub user: String,
+    pub fingerprint: String,
+    pub query: String,
+    pub elapsed_us: u64,
+    /// the error code, if the query failed
+    pub error: Option<u16>,
+}
+
+struct QueryLogState {
+    writer: Box<dyn Write + Send>,
+    /// how much of a sample each fingerprint has built up, a whole one being due
+    credit: HashMap<String, f64>,
+}
+
+/// The file samples are written to, and the sampling across sessions, shared between
+/// connections
+#[derive(Clone)]
+pub struct QueryLog {
+    min_time: Duration,
+    sample_rate: f64,
+    scrub: bool,
+    state: Arc<Mutex<QueryLogState>>,
+}
+
+impl QueryLog {
+
+    /// Open `config.path` for appending
+    pub fn open(config: &QueryLogConfig) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        Ok(QueryLog::new(config, Box::new(file)))
+    }
+
+    /// Sample to an arbitrary writer
+    pub fn new(config: &QueryLogConfig, writer: Box<dyn Write + Send>) -> Self {
+        QueryLog {
+            min_time: Duration::from_millis(config.min_time_ms),
+            sample_rate: config.sample_rate,
+            scrub: config.scrub,
+            state: Arc::new(Mutex::new(QueryLogState { writer, credit: HashMap::new() })),
+        }
+    }
+
+    /// Log a query by `user` that took `elapsed`, if it's sampled
+    pub fn offer(&self, user: &str, query: &str, elapsed: Duration, error: Option<u16>) -> Result<()> {
+        if elapsed < self.min_time {
+            return Ok(());
+        }
+        let fingerprint = sql::fingerprint(query);
+        let mut state = self.state.lock().unwrap();
+        // fingerprints beyond the most tracked are sampled together
+        let key = if state.credit.contains_key(&fingerprint) || state.credit.len() < MAX_DIGESTS {
+            fingerprint.clone()
+        } else {
+            String::new()
+        };
+        let credit = state.credit.entry(key).or_insert(1.0 - self.sample_rate);
+        *credit += self.sample_rate;
+        if *credit < 1.0 {
+            return Ok(());
+        }
+        *credit -= 1.0;
+        let sample = QuerySample {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            user: user.to_string(),
+            fingerprint,
+            query: if self.scrub { sql::scrub(query) } else { query.to_string() },
+            elapsed_us: elapsed.as_micros() as u64,
+            error,
+        };
+        let line = serde_json::to_string(&sample).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        writeln!(state.writer, "{}", line)?;
+        state.writer.flush()
+    }
+}
+
+/// A forwarded query waiting for its response
+struct PendingQuery {
+    text: String,
+    sent: Instant,
+}
+
+/// Wraps another handler and offers a session's queries to a `QueryLog` once they're
+/// answered
+pub struct QueryLogHandler<H: PacketHandler> {
+    log: QueryLog,
+    user: String,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// queries for forwarded commands, in order, and the error code of the current response
+    pending: VecDeque<Option<PendingQuery>>,
+    error: Option<u16>,
+    inner: H,
+}
+
+impl<H> QueryLogHandler<H> where H: PacketHandler {
+
+    pub fn new(log: QueryLog, user: &str, inner: H) -> Self {
+        QueryLogHandler {
+            log,
+            user: user.to_string(),
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: VecDeque::new(),
+            error: None,
+            inner,
+        }
+    }
+
+    /// Follow the session's queries with the capabilities the proxy logged in to the
+    /// backend with
+    pub fn for_session(log: QueryLog, session: &Session, inner: H) -> Self {
+        let mut handler = QueryLogHandler::new(log, &session.user, inner);
+        handler.correlator.set_capabilities(session.backend_capabilities);
+        handler
+    }
+}
+
+impl<H> PacketHandler for QueryLogHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        // the query as the client sent it, timed from when it's forwarded
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        let issued = self.correlator.issued();
+        self.correlator.request(forwarded);
+        if self.correlator.issued() > issued {
+            let query = match p.packet_type() {
+                Ok(PacketType::ComQuery) => Some(PendingQuery {
+                    text: String::from_utf8_lossy(&p.payload()[1..]).into_owned(),
+                    sent: Instant::now(),
+                }),
+                _ => None,
+            };
+            self.pending.push_back(query);
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                if answered.kind == ResponseKind::Err && self.error.is_none() {
+                    self.error = ErrPacket::parse(p).map(|e| e.code).ok();
+                }
+                if answered.last {
+                    if let Some(Some(query)) = self.pending.pop_front() {
+                        if let Err(e) = self.log.offer(&self.user, &query.text, query.sent.elapsed(), self.error) {
+                            warn!("Failed to write to the query log: {}", e);
+                        }
+                    }
+                    self.error = None;
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
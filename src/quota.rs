@@ -0,0 +1,250 @@
+//! Per-user connection and query quotas.
+//!
+//! A user mapping's `quota` limits how many connections the user may have open at once, how
+//! many of its queries may be in flight at once across those connections, and how many
+//! queries it may send an hour. Like MySQL's own account limits, a connection over the limit
+//! is refused with ER_TOO_MANY_USER_CONNECTIONS, and a query over either query limit fails
+//! with ER_USER_LIMIT_REACHED, leaving the connection open. A user's hourly count starts over
+//! an hour after the first query counted in it.
+//!
+//! `Quotas` keeps every user's usage and is shared by all listeners, so the limits apply
+//! across them. `QuotaHandler` counts a session's queries against it. With statistics kept,
+//! the usage is served along with them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::pipeline::{self, Correlator};
+
+/// MySQL error ER_TOO_MANY_USER_CONNECTIONS
+pub const ER_TOO_MANY_USER_CONNECTIONS: u16 = 1203;
+
+/// MySQL error ER_USER_LIMIT_REACHED
+pub const ER_USER_LIMIT_REACHED: u16 = 1226;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[derive(Clone,Debug,Default,Deserialize,PartialEq,Serialize)]
+pub struct QuotaConfig {
+    /// connections open at once
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+    /// queries waiting for their response at once, across all of the user's connections
+    #[serde(default)]
+    pub max_concurrent_queries: Option<u32>,
+    #[serde(default)]
+    pub max_queries_per_hour: Option<u64>,
+}
+
+/// A user's usage of its quota, as reported with the statistics
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct QuotaUsage {
+    pub connections: u32,
+    pub queries_in_flight: u32,
+    pub queries_this_hour: u64,
+    pub limits: QuotaConfig,
+}
+
+#[derive(Debug,Default)]
+struct UserUsage {
+    /// as of the user's latest connection
+    limits: QuotaConfig,
+    connections: u32,
+    in_flight: u32,
+    hour_started: Option<Instant>,
+    queries_this_hour: u64,
+}
+
+impl UserUsage {
+
+    fn queries_this_hour(&self, now: Instant) -> u64 {
+        match self.hour_started {
+            Some(started) if now.duration_since(started) < HOUR => self.queries_this_hour,
+            _ => 0,
+        }
+    }
+}
+
+/// Every user's usage, shared between connections
+#[derive(Clone,Debug,Default)]
+pub struct Quotas {
+    users: Arc<Mutex<HashMap<String, UserUsage>>>,
+}
+
+impl Quotas {
+
+    pub fn new() -> Self {
+        Quotas::default()
+    }
+
+    /// Count a connection for `user`, unless it already has as many as `limits` allow. The
+    /// connection counts until the lease is dropped.
+    pub fn connect(&self, user: &str, limits: &QuotaConfig) -> Result<QuotaLease, String> {
+        let mut users = self.users.lock().unwrap();
+        let usage = users.entry(user.to_string()).or_default();
+        usage.limits = limits.clone();
+        if let Some(max) = limits.max_connections {
+            if usage.connections >= max {
+                return Err(format!("User {} already has more than 'max_user_connections' active connections", user));
+            }
+        }
+        usage.connections += 1;
+        Ok(QuotaLease { quotas: self.clone(), user: user.to_string() })
+    }
+
+    /// Count a query for `user`, unless it would exceed one of its limits
+    pub fn start_query(&self, user: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        let usage = match users.get_mut(user) {
+            Some(usage) => usage,
+            None => return Ok(()),
+        };
+        if usage.queries_this_hour(now) == 0 {
+            usage.hour_started = Some(now);
+            usage.queries_this_hour = 0;
+        }
+        let exceeded = |resource: &str, current: u64| {
+            format!("User '{}' has exceeded the '{}' resource (current value: {})", user, resource, current)
+        };
+        if let Some(max) = usage.limits.max_queries_per_hour {
+            if usage.queries_this_hour >= max {
+                return Err(exceeded("max_questions", usage.queries_this_hour));
+            }
+        }
+        if let Some(max) = usage.limits.max_concurrent_queries {
+            if usage.in_flight >= max {
+                return Err(exceeded("max_concurrent_queries", usage.in_flight as u64));
+            }
+        }
+        usage.queries_this_hour += 1;
+        usage.in_flight += 1;
+        Ok(())
+    }
+
+    /// A query counted by `start_query` has been answered, or will never be
+    pub fn finish_query(&self, user: &str) {
+        if let Some(usage) = self.users.lock().unwrap().get_mut(user) {
+            usage.in_flight = usage.in_flight.saturating_sub(1);
+        }
+    }
+
+    pub fn usage(&self) -> BTreeMap<String, QuotaUsage> {
+        let now = Instant::now();
+        self.users.lock().unwrap().iter().map(|(user, usage)| {
+            (user.clone(), QuotaUsage {
+                connections: usage.connections,
+                queries_in_flight: usage.in_flight,
+                queries_this_hour: usage.queries_this_hour(now),
+                limits: usage.limits.clone(),
+            })
+        }).collect()
+    }
+}
+
+impl PartialEq for Quotas {
+    fn eq(&self, other: &Quotas) -> bool {
+        Arc::ptr_eq(&self.users, &other.users)
+    }
+}
+
+/// A connection counted against its user's quota
+#[derive(Debug,PartialEq)]
+pub struct QuotaLease {
+    quotas: Quotas,
+    user: String,
+}
+
+impl Drop for QuotaLease {
+    fn drop(&mut self) {
+        if let Some(usage) = self.quotas.users.lock().unwrap().get_mut(&self.user) {
+            usage.connections = usage.connections.saturating_sub(1);
+        }
+    }
+}
+
+/// Wraps another handler and counts the commands it forwards against the session's quota,
+/// failing those over it with ER_USER_LIMIT_REACHED
+pub struct QuotaHandler<H: PacketHandler> {
+    lease: Arc<QuotaLease>,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// commands counted that haven't been answered yet
+    in_flight: u32,
+    inner: H,
+}
+
+impl<H> QuotaHandler<H> where H: PacketHandler {
+
+    /// Count the queries of a connection whose lease holds it against its user's quota
+    pub fn new(lease: Arc<QuotaLease>, inner: H) -> Self {
+        QuotaHandler {
+            lease,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            in_flight: 0,
+            inner,
+        }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+}
+
+impl<H> PacketHandler for QuotaHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        if !pipeline::is_answered(forwarded) {
+            return action;
+        }
+        if let Err(msg) = self.lease.quotas.start_query(&self.lease.user) {
+            return Action::Error { code: ER_USER_LIMIT_REACHED, state: *b"42000", msg };
+        }
+        self.correlator.request(forwarded);
+        self.in_flight += 1;
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                if answered.last && self.in_flight > 0 {
+                    self.in_flight -= 1;
+                    self.lease.quotas.finish_query(&self.lease.user);
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+impl<H> Drop for QuotaHandler<H> where H: PacketHandler {
+    fn drop(&mut self) {
+        // queries still waiting when the session ends no longer count
+        for _ in 0..self.in_flight {
+            self.lease.quotas.finish_query(&self.lease.user);
+        }
+    }
+}
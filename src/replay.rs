@@ -0,0 +1,205 @@
+//! Replaying captured traffic against a server.
+//!
+//! A capture is a packet dump as written by `dump::DumpHandler`, one JSON `PacketDump` per
+//! line. `Capture` picks out the text commands clients sent, and a `ReplayClient` per captured
+//! connection sends them again, e.g. to try a workload on a new server version. A command can
+//! only be replayed if its dump holds the whole payload, either in `hex` or, for statements,
+//! in a summary that wasn't cut short.
+
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+
+use super::{Packet, PacketType};
+use super::codec::{is_eof_packet, is_err_packet, AuthSwitchRequest, EofPacket, ErrPacket, HandshakeResponse, HandshakeV10,
+                   OkPacket, PacketDecoder, SERVER_MORE_RESULTS_EXISTS};
+use super::dump::{Direction, PacketDump};
+use super::protocol::*;
+
+/// A command read from a capture
+#[derive(Debug,PartialEq)]
+pub struct CapturedCommand {
+    /// the connection the command was captured on
+    pub connection: String,
+    pub packet: Packet,
+}
+
+/// The commands in a capture that can be replayed
+#[derive(Debug,Default,PartialEq)]
+pub struct Capture {
+    pub commands: Vec<CapturedCommand>,
+    /// commands that can't be replayed, as their payload was cut short or isn't text
+    pub skipped: usize,
+}
+
+impl Capture {
+
+    /// Read a capture, ignoring responses and the login exchange
+    pub fn read<R: BufRead>(reader: R) -> Result<Self> {
+        let mut capture = Capture::default();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let dump: PacketDump = serde_json::from_str(&line)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", n + 1, e)))?;
+            if dump.direction != Direction::Request || !dump.packet_type.starts_with("com_") {
+                continue;
+            }
+            match command_payload(&dump) {
+                Some(payload) => capture.commands.push(CapturedCommand {
+                    connection: dump.connection,
+                    packet: Packet::new(0, &payload),
+                }),
+                None => capture.skipped += 1,
+            }
+        }
+        Ok(capture)
+    }
+}
+
+/// The whole payload of a dumped text command, if the dump has it
+fn command_payload(dump: &PacketDump) -> Option<Vec<u8>> {
+    let command = match &dump.packet_type[..] {
+        "com_query" => PacketType::ComQuery,
+        "com_init_db" => PacketType::ComInitDb,
+        "com_ping" => PacketType::ComPing,
+        "com_quit" => PacketType::ComQuit,
+        _ => return None,
+    };
+    let payload = if !dump.truncated {
+        dump.packet()?.payload().to_vec()
+    } else {
+        // the summary is the rest of the payload, unless it was shortened or wasn't UTF-8
+        let mut payload = vec![command.byte()];
+        payload.extend_from_slice(dump.summary.as_ref()?.as_bytes());
+        payload
+    };
+    if payload.len() == dump.length && payload.first() == Some(&command.byte()) {
+        Some(payload)
+    } else {
+        None
+    }
+}
+
+/// How the server answered a replayed command
+#[derive(Clone,Debug,PartialEq)]
+pub enum Outcome {
+    Ok { affected_rows: u64 },
+    Err { code: u16, message: String },
+    /// a result set, or several for a multi-statement query
+    Rows(u64),
+}
+
+/// A blocking client connection replaying commands
+pub struct ReplayClient {
+    stream: TcpStream,
+    decoder: PacketDecoder,
+}
+
+impl ReplayClient {
+
+    /// Log in with mysql_native_password
+    pub fn connect(addr: &str, user: &str, password: &str, database: Option<&str>) -> Result<Self> {
+        let mut client = ReplayClient { stream: TcpStream::connect(addr)?, decoder: PacketDecoder::new() };
+        let greeting = HandshakeV10::parse(&client.read_packet()?)?;
+        let mut capability_flags = CLIENT_LONG_PASSWORD | CLIENT_PROTOCOL_41 | CLIENT_TRANSACTIONS
+            | CLIENT_SECURE_CONNECTION | CLIENT_MULTI_STATEMENTS | CLIENT_MULTI_RESULTS | CLIENT_PLUGIN_AUTH;
+        if database.is_some() {
+            capability_flags |= CLIENT_CONNECT_WITH_DB;
+        }
+        let scramble = &greeting.auth_plugin_data[..greeting.auth_plugin_data.len().min(20)];
+        let response = HandshakeResponse {
+            capability_flags,
+            max_packet_size: 1 << 24,
+            character_set: 45, // utf8mb4_general_ci
+            username: user.to_string(),
+            auth_response: native_password_auth(password, scramble),
+            database: database.map(|db| db.to_string()),
+            auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+            connect_attrs: None,
+        };
+        client.write_packet(&response.to_packet(1))?;
+        loop {
+            let p = client.read_packet()?;
+            match p.payload().first() {
+                Some(&0x00) => return Ok(client),
+                Some(&0xfe) => {
+                    let switch = AuthSwitchRequest::parse(&p)?;
+                    if switch.plugin_name != NATIVE_PASSWORD_PLUGIN {
+                        let msg = format!("Unsupported auth plugin '{}'", switch.plugin_name);
+                        return Err(Error::new(ErrorKind::PermissionDenied, msg));
+                    }
+                    let auth = native_password_auth(password, &switch.plugin_data);
+                    client.write_packet(&Packet::new(p.sequence_id().wrapping_add(1), &auth))?;
+                },
+                Some(&0xff) => return Err(Error::new(ErrorKind::PermissionDenied, ErrPacket::parse(&p)?.message)),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected packet during login")),
+            }
+        }
+    }
+
+    /// Send a command and read the whole response to it
+    pub fn command(&mut self, p: &Packet) -> Result<Outcome> {
+        self.write_packet(p)?;
+        if p.packet_type().ok() == Some(PacketType::ComQuit) {
+            return Ok(Outcome::Ok { affected_rows: 0 });
+        }
+        let mut rows = 0;
+        let mut result_sets = 0;
+        loop {
+            let first = self.read_packet()?;
+            let status_flags = match first.payload().first() {
+                Some(&0x00) => {
+                    let ok = OkPacket::parse(&first, CLIENT_PROTOCOL_41)?;
+                    if result_sets == 0 && ok.status_flags & SERVER_MORE_RESULTS_EXISTS == 0 {
+                        return Ok(Outcome::Ok { affected_rows: ok.affected_rows });
+                    }
+                    ok.status_flags
+                },
+                Some(&0xff) => {
+                    let err = ErrPacket::parse(&first)?;
+                    return Ok(Outcome::Err { code: err.code, message: err.message });
+                },
+                Some(&0xfb) => return Err(Error::new(ErrorKind::InvalidData, "LOAD DATA LOCAL INFILE can't be replayed")),
+                _ => {
+                    // column definitions up to an EOF, then rows up to another
+                    result_sets += 1;
+                    while !is_eof_packet(&self.read_packet()?) {}
+                    loop {
+                        let p = self.read_packet()?;
+                        if is_err_packet(&p) {
+                            let err = ErrPacket::parse(&p)?;
+                            return Ok(Outcome::Err { code: err.code, message: err.message });
+                        }
+                        if is_eof_packet(&p) {
+                            break EofPacket::parse(&p)?.status_flags;
+                        }
+                        rows += 1;
+                    }
+                },
+            };
+            if status_flags & SERVER_MORE_RESULTS_EXISTS == 0 {
+                return Ok(Outcome::Rows(rows));
+            }
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Packet> {
+        let mut buf = [0_u8; 4096];
+        loop {
+            if let Some(p) = self.decoder.next_packet() {
+                return Ok(p);
+            }
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by server"));
+            }
+            self.decoder.extend(&buf[..n]);
+        }
+    }
+
+    fn write_packet(&mut self, p: &Packet) -> Result<()> {
+        self.stream.write_all(&p.bytes)
+    }
+}
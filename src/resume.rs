@@ -0,0 +1,184 @@
+//! Moving idle sessions to another backend when theirs is lost.
+//!
+//! With a `[session_resume]` section, a session whose backend connection drops while it has
+//! no command in flight and no transaction open is logged in to another backend of its
+//! routing group instead of being disconnected. A `SessionReplay` rebuilds the state the
+//! session had built up there: its schema, its `SET` statements and its prepared statements.
+//! Their responses are kept from the client, which carries on as if nothing had happened.
+//!
+//! Sessions mid-transaction, or with a command in flight, still see the disconnect, since
+//! their work can't be redone behind their back. So do sessions with state that can't be
+//! replayed, such as temporary tables or locks, see the `sessionreplay` module. Sessions
+//! whose authentication is passed through can't be resumed either, nor those using the
+//! compressed protocol with their backend.
+
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{Packet, PacketType, Transport};
+use super::pipeline::ResponsePacket;
+use super::sessionreplay::{SessionReplay, DEFAULT_MAX_STATEMENTS};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct SessionResumeConfig {
+    /// `SET` statements remembered for a session before it stops being resumable
+    #[serde(default = "SessionResumeConfig::default_max_statements")]
+    pub max_statements: usize,
+    /// how long connecting and logging in to the new backend may take
+    #[serde(default = "SessionResumeConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl SessionResumeConfig {
+
+    fn default_max_statements() -> usize {
+        DEFAULT_MAX_STATEMENTS
+    }
+
+    fn default_timeout_ms() -> u64 {
+        5000
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_ms == 0 {
+            return Err("timeout_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SessionResumeConfig {
+    fn default() -> Self {
+        SessionResumeConfig {
+            max_statements: SessionResumeConfig::default_max_statements(),
+            timeout_ms: SessionResumeConfig::default_timeout_ms(),
+        }
+    }
+}
+
+/// Resolves to a stream logged in to a backend
+pub type Login = Box<dyn Future<Item = Rc<dyn Transport>, Error = io::Error>>;
+
+/// Connects and logs in to a new backend for the session, with the capabilities of the old
+pub type Reconnect = Box<dyn FnMut() -> Login>;
+
+/// A session's new backend
+pub struct Resumed {
+    pub server: Rc<dyn Transport>,
+    /// the commands that restore the session's state, to send before any other
+    pub replay: Vec<Packet>,
+}
+
+/// Follows a session's state, and logs it in to another backend if it loses its own
+pub struct SessionResume {
+    config: SessionResumeConfig,
+    handle: Handle,
+    reconnect: Reconnect,
+    replay: SessionReplay,
+    connecting: Option<(Timeout, Login)>,
+    /// the client has sent COM_QUIT, so the server closing the connection is expected
+    quit: bool,
+    /// the backend sent a packet nothing asked for, such as the error before it kills a session
+    unsolicited: bool,
+    failed: Option<io::Error>,
+}
+
+impl SessionResume {
+
+    pub fn new(config: SessionResumeConfig, reconnect: Reconnect, handle: &Handle) -> Self {
+        SessionResume {
+            replay: SessionReplay::new(config.max_statements),
+            config,
+            handle: handle.clone(),
+            reconnect,
+            connecting: None,
+            quit: false,
+            unsolicited: false,
+            failed: None,
+        }
+    }
+
+    /// Start from the schema the session logged in to the backend with
+    pub fn with_schema(mut self, schema: Option<String>) -> Self {
+        self.replay = self.replay.with_schema(schema);
+        self
+    }
+
+    /// Whether the session is moving to a new backend, so client commands must wait
+    pub fn resuming(&self) -> bool {
+        self.connecting.is_some() || self.replay.replaying()
+    }
+
+    /// Whether the session is logging in to a new backend, so there's no server to read from
+    pub fn connecting(&self) -> bool {
+        self.connecting.is_some()
+    }
+
+    /// Whether the session could be moved to another backend now that `in_flight` commands
+    /// await a response
+    pub fn resumable(&self, in_flight: usize) -> bool {
+        in_flight == 0 && !self.resuming() && self.replay.replayable() && !self.quit && !self.unsolicited
+    }
+
+    /// Observe a command sent to the server, returning it as the backend must get it
+    pub fn request(&mut self, p: Packet) -> Packet {
+        if p.sequence_id() == 0 && p.packet_type().ok() == Some(PacketType::ComQuit) {
+            self.quit = true;
+        }
+        self.replay.request(p)
+    }
+
+    /// Observe a command sent to the server again as the backend got it before
+    pub fn resend(&mut self, p: &Packet) {
+        self.replay.resend(p);
+    }
+
+    /// Observe a response packet, returning it as the client must get it, or `None` if it
+    /// answers a replayed command
+    pub fn response(&mut self, p: Packet, answered: Option<ResponsePacket>) -> Option<Packet> {
+        if answered.is_none() {
+            self.unsolicited = true;
+        }
+        match self.replay.response(p, answered) {
+            Ok(p) => p,
+            Err(msg) => {
+                if self.failed.is_none() {
+                    self.failed = Some(io::Error::other(format!("Could not restore the session's state: {}", msg)));
+                }
+                None
+            },
+        }
+    }
+
+    /// Start logging in to a new backend
+    pub fn begin(&mut self) -> io::Result<()> {
+        let timer = Timeout::new(Duration::from_millis(self.config.timeout_ms), &self.handle)?;
+        self.connecting = Some((timer, (self.reconnect)()));
+        Ok(())
+    }
+
+    /// The new backend, once logged in. Fails if the login or a replayed command did.
+    pub fn poll_reconnected(&mut self) -> Poll<Option<Resumed>, io::Error> {
+        if let Some(e) = self.failed.take() {
+            return Err(e);
+        }
+        let server = match self.connecting {
+            Some((ref mut timer, ref mut login)) => match login.poll()? {
+                Async::Ready(server) => server,
+                Async::NotReady => {
+                    if timer.poll()?.is_ready() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out logging in to a new backend"));
+                    }
+                    return Ok(Async::NotReady);
+                },
+            },
+            None => return Ok(Async::Ready(None)),
+        };
+        self.connecting = None;
+        Ok(Async::Ready(Some(Resumed { server, replay: self.replay.replay() })))
+    }
+}
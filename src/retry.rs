@@ -0,0 +1,384 @@
+//! Retrying statements that lost a deadlock or timed out waiting for a lock.
+//!
+//! A statement run in autocommit mode is its own transaction, so when the backend rolls it
+//! back with a deadlock (1213) or lock wait timeout (1205) error it can safely be sent again.
+//! The error is kept from the client and the statement retried after a jittered backoff, up
+//! to `max_retries` times, before the error is passed on. Only statements that were the sole
+//! command awaiting a response are retried, while the server reports autocommit on and no
+//! transaction open; statements in explicit transactions fail as usual, since the rest of the
+//! transaction was rolled back with them.
+//!
+//! Connections to backends are retried the same way with a `ConnectRetryPolicy`, up to
+//! `max_attempts` attempts in all, but only after the failures listed in `retry_on`: a backend
+//! that refused the connection or reset it may well be restarting, while one whose name
+//! doesn't resolve won't be any different 100ms later. Sessions and warm pool logins retry
+//! through `ProxyAuth::with_connect_retry`, health checks through
+//! `HealthMonitor::with_connect_retry`, side channels through `SideChannel::with_connect_retry`
+//! and connections to a new primary through `ConnectPrimary::with_retry`, so none of them has
+//! its own idea of how long to wait. A circuit breaker only counts the connection as failed
+//! once the retries have run out.
+//!
+//! ```toml
+//! [connect_retry]
+//! max_attempts = 3
+//! base_delay_ms = 100
+//! max_delay_ms = 2000
+//! retry_on = ["refused", "reset", "timed_out", "unreachable"]
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{Packet, PacketType};
+use super::codec::{EofPacket, ErrPacket, OkPacket, MAX_PAYLOAD_LEN, SERVER_STATUS_AUTOCOMMIT};
+use super::pipeline::{ResponseKind, ResponsePacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::state::SERVER_STATUS_IN_TRANS;
+
+/// MySQL error ER_LOCK_WAIT_TIMEOUT
+pub const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+
+/// MySQL error ER_LOCK_DEADLOCK
+pub const ER_LOCK_DEADLOCK: u16 = 1213;
+
+/// How often, and how soon, to retry a statement
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct RetryPolicy {
+    #[serde(default = "RetryPolicy::default_max_retries")]
+    pub max_retries: u32,
+    /// the backoff before the first retry, doubled for each one after it
+    #[serde(default = "RetryPolicy::default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "RetryPolicy::default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: RetryPolicy::default_max_retries(),
+            base_delay_ms: RetryPolicy::default_base_delay_ms(),
+            max_delay_ms: RetryPolicy::default_max_delay_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    fn default_max_retries() -> u32 {
+        3
+    }
+
+    fn default_base_delay_ms() -> u64 {
+        20
+    }
+
+    fn default_max_delay_ms() -> u64 {
+        1000
+    }
+
+    /// The delay before retry number `retry`, counting from 0: a random time between half
+    /// the backoff and all of it, so clients that deadlocked on each other don't collide again
+    pub fn delay(&self, retry: u32) -> Duration {
+        backoff(self.base_delay_ms, self.max_delay_ms, retry)
+    }
+}
+
+/// A random time between half of `base_delay_ms` doubled `retry` times, at most `max_delay_ms`,
+/// and all of it
+fn backoff(base_delay_ms: u64, max_delay_ms: u64, retry: u32) -> Duration {
+    let backoff = base_delay_ms.saturating_mul(1_u64 << retry.min(32)).min(max_delay_ms);
+    let jitter = RandomState::new().build_hasher().finish() % (backoff / 2 + 1);
+    Duration::from_millis(backoff - backoff / 2 + jitter)
+}
+
+/// How a connection to a backend failed
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectFailure {
+    /// nothing listens on the port, e.g. while the backend restarts
+    Refused,
+    /// the backend or something on the way closed the connection
+    Reset,
+    TimedOut,
+    /// no route to the backend's host or network
+    Unreachable,
+    /// anything else, e.g. a name that doesn't resolve or an upstream proxy's refusal
+    Other,
+}
+
+impl ConnectFailure {
+
+    pub fn of(e: &io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionRefused => ConnectFailure::Refused,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof => ConnectFailure::Reset,
+            ErrorKind::TimedOut => ConnectFailure::TimedOut,
+            ErrorKind::AddrNotAvailable => ConnectFailure::Unreachable,
+            _ => match e.raw_os_error() {
+                Some(libc::ENETUNREACH) | Some(libc::EHOSTUNREACH) => ConnectFailure::Unreachable,
+                _ => ConnectFailure::Other,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ConnectFailure::Refused => "refused",
+            ConnectFailure::Reset => "reset",
+            ConnectFailure::TimedOut => "timed_out",
+            ConnectFailure::Unreachable => "unreachable",
+            ConnectFailure::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How often, and how soon, to try connecting to a backend again
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct ConnectRetryPolicy {
+    /// attempts in all, the first included
+    #[serde(default = "ConnectRetryPolicy::default_max_attempts")]
+    pub max_attempts: u32,
+    /// the backoff before the second attempt, doubled for each one after it
+    #[serde(default = "ConnectRetryPolicy::default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "ConnectRetryPolicy::default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// the failures worth trying again after
+    #[serde(default = "ConnectRetryPolicy::default_retry_on")]
+    pub retry_on: Vec<ConnectFailure>,
+}
+
+impl Default for ConnectRetryPolicy {
+    fn default() -> Self {
+        ConnectRetryPolicy {
+            max_attempts: ConnectRetryPolicy::default_max_attempts(),
+            base_delay_ms: ConnectRetryPolicy::default_base_delay_ms(),
+            max_delay_ms: ConnectRetryPolicy::default_max_delay_ms(),
+            retry_on: ConnectRetryPolicy::default_retry_on(),
+        }
+    }
+}
+
+impl ConnectRetryPolicy {
+
+    fn default_max_attempts() -> u32 {
+        3
+    }
+
+    fn default_base_delay_ms() -> u64 {
+        100
+    }
+
+    fn default_max_delay_ms() -> u64 {
+        2000
+    }
+
+    fn default_retry_on() -> Vec<ConnectFailure> {
+        vec![ConnectFailure::Refused, ConnectFailure::Reset, ConnectFailure::TimedOut, ConnectFailure::Unreachable]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 || self.max_attempts > 10 {
+            return Err("max_attempts must be between 1 and 10".to_string());
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            return Err("base_delay_ms can't be more than max_delay_ms".to_string());
+        }
+        Ok(())
+    }
+
+    /// How long to wait before trying again after attempt number `attempt`, counting from 1,
+    /// failed with `e`, or `None` to give up
+    pub fn retry_after(&self, attempt: u32, e: &io::Error) -> Option<Duration> {
+        let failure = ConnectFailure::of(e);
+        if attempt >= self.max_attempts || !self.retry_on.contains(&failure) {
+            return None;
+        }
+        Some(backoff(self.base_delay_ms, self.max_delay_ms, attempt.saturating_sub(1)))
+    }
+}
+
+type Attempt<T> = Box<dyn Future<Item = T, Error = io::Error>>;
+
+/// Future of a connection tried again as its `ConnectRetryPolicy` allows
+pub struct RetryConnect<T, F> {
+    policy: Option<ConnectRetryPolicy>,
+    /// what's connected to, for the logs
+    what: String,
+    handle: Handle,
+    connect: F,
+    attempt: u32,
+    connecting: Option<Attempt<T>>,
+    backoff: Option<Timeout>,
+}
+
+/// Connect to `what` with `connect`, calling it again after the failures `policy` retries,
+/// or just once without a policy
+pub fn retry_connect<T, F>(policy: Option<&ConnectRetryPolicy>, what: &str, handle: &Handle, mut connect: F) -> RetryConnect<T, F>
+    where F: FnMut() -> Attempt<T> {
+    let connecting = Some(connect());
+    RetryConnect {
+        policy: policy.cloned(),
+        what: what.to_string(),
+        handle: handle.clone(),
+        connect,
+        attempt: 1,
+        connecting,
+        backoff: None,
+    }
+}
+
+impl<T, F> Future for RetryConnect<T, F> where F: FnMut() -> Attempt<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<T, io::Error> {
+        loop {
+            if let Some(ref mut backoff) = self.backoff {
+                try_ready!(backoff.poll());
+            }
+            if self.backoff.take().is_some() {
+                self.attempt += 1;
+                self.connecting = Some((self.connect)());
+            }
+            let e = match self.connecting.as_mut().expect("polled after completion").poll() {
+                Err(e) => e,
+                result => return result,
+            };
+            self.connecting = None;
+            let delay = match self.policy.as_ref().and_then(|p| p.retry_after(self.attempt, &e)) {
+                Some(delay) => delay,
+                None => return Err(e),
+            };
+            debug!("Connecting to {} failed ({}: {}), trying again in {:?}", self.what, ConnectFailure::of(&e), e, delay);
+            self.backoff = Some(Timeout::new(delay, &self.handle)?);
+        }
+    }
+}
+
+/// Connect to `what` with `connect`, on the calling thread, sleeping between the attempts
+/// `policy` allows
+pub fn retry_blocking<T, F>(policy: Option<&ConnectRetryPolicy>, what: &str, mut connect: F) -> io::Result<T>
+    where F: FnMut() -> io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        let e = match connect() {
+            Err(e) => e,
+            result => return result,
+        };
+        let delay = match policy.and_then(|p| p.retry_after(attempt, &e)) {
+            Some(delay) => delay,
+            None => return Err(e),
+        };
+        debug!("Connecting to {} failed ({}: {}), trying again in {:?}", what, ConnectFailure::of(&e), e, delay);
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Follows the statements on a connection and sends those that lost a deadlock again
+pub struct DeadlockRetry {
+    policy: RetryPolicy,
+    handle: Handle,
+    /// the server's status after the latest complete response
+    status_flags: Option<u16>,
+    /// the only command awaiting a response, if it may be retried, and its retries so far
+    candidate: Option<(Packet, u32)>,
+    /// a statement waiting to be sent again
+    pending: Option<(Timeout, Packet)>,
+}
+
+impl DeadlockRetry {
+
+    pub fn new(policy: RetryPolicy, handle: &Handle) -> Self {
+        DeadlockRetry {
+            policy,
+            handle: handle.clone(),
+            status_flags: None,
+            candidate: None,
+            pending: None,
+        }
+    }
+
+    /// Whether a statement is waiting to be sent again, so later commands must wait too
+    pub fn waiting(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Observe a command sent to the server while `in_flight` others await a response
+    pub fn request(&mut self, p: &Packet, in_flight: usize) {
+        if p.sequence_id() != 0 {
+            return;
+        }
+        let statement = matches!(p.packet_type(), Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtExecute));
+        let autocommit = self.status_flags
+            .map(|s| s & SERVER_STATUS_AUTOCOMMIT != 0 && s & SERVER_STATUS_IN_TRANS == 0)
+            .unwrap_or(false);
+        self.candidate = if statement && autocommit && in_flight == 0 && p.payload().len() < MAX_PAYLOAD_LEN {
+            Some((Packet { bytes: p.bytes.clone() }, 0))
+        } else {
+            None
+        };
+    }
+
+    /// Observe a response packet, returning true if it is a lock error that will be retried
+    /// rather than passed on to the client
+    pub fn response(&mut self, p: &Packet, answered: Option<ResponsePacket>) -> bool {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return false,
+        };
+        if answered.last {
+            match answered.kind {
+                ResponseKind::Ok => self.status_flags = OkPacket::parse(p, CLIENT_PROTOCOL_41).ok().map(|ok| ok.status_flags),
+                ResponseKind::Eof => self.status_flags = EofPacket::parse(p).ok().map(|eof| eof.status_flags),
+                _ => {},
+            }
+        }
+        // only the first packet of the response can be the error
+        let (statement, retries) = match self.candidate.take() {
+            Some(candidate) => candidate,
+            None => return false,
+        };
+        if answered.kind != ResponseKind::Err || retries >= self.policy.max_retries {
+            return false;
+        }
+        let code = ErrPacket::parse(p).map(|e| e.code).unwrap_or(0);
+        if code != ER_LOCK_DEADLOCK && code != ER_LOCK_WAIT_TIMEOUT {
+            return false;
+        }
+        let delay = self.policy.delay(retries);
+        let timer = match Timeout::new(delay, &self.handle) {
+            Ok(timer) => timer,
+            Err(_) => return false,
+        };
+        debug!("Retrying statement after error {} in {:?}", code, delay);
+        self.candidate = Some((Packet { bytes: statement.bytes.clone() }, retries + 1));
+        self.pending = Some((timer, statement));
+        true
+    }
+
+    /// The statement to send again, once its backoff is over
+    pub fn poll_resend(&mut self) -> Option<Packet> {
+        let ready = match self.pending {
+            Some((ref mut timer, _)) => timer.poll().map(|a| a.is_ready()).unwrap_or(true),
+            None => false,
+        };
+        if !ready {
+            return None;
+        }
+        self.pending.take().map(|(_, statement)| statement)
+    }
+}
@@ -0,0 +1,550 @@
+//! Row-level security: each user only sees and changes the rows of a table that belong to it.
+//!
+//! A `RowFilter` limits a table to the rows whose column has a value from the user's
+//! mapping, e.g. `tenant_id = {tenant_id}`. `RowFilterHandler` rewrites statements so the
+//! backend applies the filter itself: in queries every use of the table in `FROM` or `JOIN`
+//! becomes a derived table of its filtered rows, and single-table `UPDATE` and `DELETE`
+//! statements get the filter added to their `WHERE` clause. Queries that can't be rewritten,
+//! e.g. because of index hints, are forwarded as they are and the rows that don't match are
+//! dropped from the result instead, or all of them if the result leaves out the column.
+//! Other statements that would touch rows of a filtered table in ways the proxy can't
+//! follow, such as `TRUNCATE`, multi-table updates, `REPLACE` and `INSERT ... ON DUPLICATE
+//! KEY UPDATE`, are refused, as is a `PREPARE` of a statement naming a filtered table or of
+//! one held in a variable, so an `EXECUTE` only runs statements that don't need a filter.
+//! Multi-statement queries aren't rewritten: the rows of a single filtered table can be
+//! dropped from their results if every statement is a query, and otherwise any naming a
+//! filtered table is refused. Plain `INSERT`s aren't checked.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::{QueryResponse, QueryResponseDecoder, MAX_PAYLOAD_LEN};
+use super::pipeline::Correlator;
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sql::{self, table_spans, tokenize, PreparedFrom, TableSpan, Token};
+
+/// MySQL error ER_NOT_SUPPORTED_YET
+pub const ER_NOT_SUPPORTED_YET: u16 = 1235;
+
+/// Rows of a table that a user may see, e.g. `table = "shop.orders"`, `column = "tenant_id"`
+/// and `value = "{tenant_id}"`
+#[derive(Clone,Debug,Deserialize,PartialEq,Serialize)]
+pub struct RowFilter {
+    /// `schema.table`, or `table` in any schema
+    pub table: String,
+    pub column: String,
+    /// the value rows must have, where `{user}` is the proxy user and `{name}` is the `name`
+    /// attribute of the user's mapping
+    pub value: String,
+    /// users that see every row
+    #[serde(default)]
+    pub exempt_users: Vec<String>,
+}
+
+impl RowFilter {
+
+    /// Check the filter for mistakes that would otherwise only show when it's applied
+    pub fn validate(&self) -> Result<(), String> {
+        if self.table.is_empty() || self.table.split('.').any(|part| part.is_empty()) || self.table.matches('.').count() > 1 {
+            return Err(format!("invalid table '{}', expected `schema.table` or `table`", self.table));
+        }
+        if self.column.is_empty() {
+            return Err(format!("no column for table '{}'", self.table));
+        }
+        let mut rest = &self.value[..];
+        while let Some(open) = rest.find('{') {
+            match rest[open..].find('}') {
+                Some(close) if close > 1 => rest = &rest[open + close + 1..],
+                _ => return Err(format!("unclosed or empty placeholder in value '{}'", self.value)),
+            }
+        }
+        Ok(())
+    }
+
+    fn covers(&self, schema: Option<&str>, table: &str) -> bool {
+        match self.table.split_once('.') {
+            Some((s, t)) => schema == Some(s) && t.eq_ignore_ascii_case(table),
+            None => self.table.eq_ignore_ascii_case(table),
+        }
+    }
+
+    /// The value for a user, or `None` if one of the attributes it needs is missing
+    fn value_for(&self, user: &str, attributes: &HashMap<String, String>) -> Option<String> {
+        let mut value = String::new();
+        let mut rest = &self.value[..];
+        while let Some(open) = rest.find('{') {
+            let close = open + rest[open..].find('}')?;
+            value.push_str(&rest[..open]);
+            let name = &rest[open + 1..close];
+            value.push_str(if name == "user" { user } else { attributes.get(name)? });
+            rest = &rest[close + 1..];
+        }
+        value.push_str(rest);
+        Some(value)
+    }
+}
+
+/// A filter with its value for one session
+#[derive(Clone,Debug,PartialEq)]
+struct SessionFilter {
+    filter: RowFilter,
+    /// `None` lets no rows through
+    value: Option<String>,
+}
+
+impl SessionFilter {
+
+    fn predicate(&self, qualifier: Option<&str>) -> String {
+        let column = format!("`{}`", self.filter.column.replace('`', "``"));
+        let column = match qualifier {
+            Some(q) => format!("`{}`.{}", q.replace('`', "``"), column),
+            None => column,
+        };
+        match self.value {
+            Some(ref value) => format!("{} = '{}'", column, value.replace('\\', "\\\\").replace('\'', "''")),
+            None => "FALSE".to_string(),
+        }
+    }
+}
+
+/// How a statement is sent on
+#[derive(Debug,PartialEq)]
+enum Rewrite {
+    /// it doesn't use a filtered table
+    Unchanged,
+    Rewritten(String),
+    /// forward it and filter the rows of its result by the filter's column
+    FilterRows(SessionFilter),
+    /// refuse it, naming the filtered table
+    Refuse(String),
+}
+
+/// Whether a statement only queries, by its first token
+fn is_query(tokens: &[Token]) -> bool {
+    tokens.first().map(|t| ["select", "with", "table"].iter().any(|k| t.is_keyword(k)) || t.is_punct(b'(')).unwrap_or(false)
+}
+
+/// The schema a `USE` switches to, `Some(None)` if it can't be read, `None` for other statements
+fn use_schema(tokens: &[Token]) -> Option<Option<String>> {
+    if tokens.first().map(|t| t.is_keyword("use")).unwrap_or(false) {
+        Some(tokens.get(1).and_then(|t| t.name.clone()))
+    } else {
+        None
+    }
+}
+
+/// Rewrite a statement to apply the filters, with unqualified tables in `schema`
+fn rewrite(sql: &str, filters: &[SessionFilter], schema: Option<&str>) -> Rewrite {
+    let statements = sql::split(sql);
+    if statements.len() > 1 {
+        return rewrite_multi(&statements, filters, schema);
+    }
+    if let Some(from) = sql::prepared_from(sql) {
+        return rewrite_prepare(from, filters, schema);
+    }
+    let tokens = tokenize(sql);
+    let spans = table_spans(&tokens);
+    let filtered: Vec<(&TableSpan, &SessionFilter)> = spans.iter()
+        .filter_map(|span| {
+            let table_schema = span.table.schema.as_ref().map(|s| &s[..]).or(schema);
+            filters.iter().find(|f| f.filter.covers(table_schema, &span.table.table)).map(|f| (span, f))
+        })
+        .collect();
+    let (first_span, first_filter) = match filtered.first() {
+        Some(&(span, filter)) => (span, filter),
+        None => return Rewrite::Unchanged,
+    };
+    let command = tokens.first().and_then(|t| t.name.as_ref()).map(|n| n.to_lowercase()).unwrap_or_default();
+    let query = is_query(&tokens);
+    let upsert = tokens.windows(2).any(|w| w[0].is_keyword("on") && w[1].is_keyword("duplicate"));
+    // rows of one table can be filtered from a result
+    let fallback = || if query && filtered.len() == 1 {
+        Rewrite::FilterRows(first_filter.clone())
+    } else {
+        Rewrite::Refuse(first_span.table.table.clone())
+    };
+
+    let mut replace: Vec<(usize, usize, String)> = vec![];
+    for &(span, filter) in &filtered {
+        let keyword = &tokens[span.keyword];
+        let target = (command == "update" && span.keyword == 0)
+            || (command == "delete" && keyword.is_keyword("from") && tokens[..span.keyword].iter().all(|t| t.name.is_some()));
+        if target {
+            // a single table, changed only where the filter matches
+            let single = spans.iter().filter(|s| s.keyword == span.keyword).count() == 1
+                && !tokens.iter().any(|t| t.is_keyword("join"));
+            match (single, add_where(sql, &tokens, span, filter)) {
+                (true, Some(edit)) => replace.push(edit),
+                _ => return Rewrite::Refuse(span.table.table.clone()),
+            }
+        } else if keyword.is_keyword("from") || keyword.is_keyword("join") {
+            // index hints and partitions can't follow a derived table
+            let after = span.alias.as_ref().map(|&(_, next)| next).unwrap_or(span.end);
+            if tokens.get(after).map(|t| ["use", "ignore", "force", "partition"].iter().any(|k| t.is_keyword(k))).unwrap_or(false) {
+                return fallback();
+            }
+            let name = &sql[tokens[span.start].start..tokens[span.end - 1].end];
+            let alias = span.alias.as_ref().map(|(a, _)| &a[..]).unwrap_or(&span.table.table);
+            let end = tokens[after - 1].end;
+            replace.push((tokens[span.start].start, end, format!("(SELECT * FROM {} WHERE {}) AS `{}`",
+                                                               name, filter.predicate(None), alias.replace('`', "``"))));
+        } else if command == "truncate" {
+            return Rewrite::Refuse(span.table.table.clone());
+        } else if (command == "replace" || (command == "insert" && upsert)) && (span.keyword == 0 || keyword.is_keyword("into")) {
+            // a replaced or updated row may belong to another user
+            return Rewrite::Refuse(span.table.table.clone());
+        } else if keyword.is_keyword("table") && span.keyword == 0 {
+            // TABLE orders
+            return fallback();
+        }
+        // inserts, DDL, DESCRIBE and LOCK TABLES don't read or change rows
+    }
+    replace.sort_by_key(|&(start, _, _)| start);
+    let mut rewritten = String::with_capacity(sql.len() + 64 * replace.len());
+    let mut from = 0;
+    for (start, end, text) in replace {
+        if start < from {
+            return fallback();
+        }
+        rewritten.push_str(&sql[from..start]);
+        rewritten.push_str(&text);
+        from = end;
+    }
+    rewritten.push_str(&sql[from..]);
+    Rewrite::Rewritten(rewritten)
+}
+
+/// Multi-statement queries aren't rewritten: those naming one filtered table, in statements
+/// that all only query, are forwarded with the rows of other tenants dropped from their
+/// results, and any other naming a filtered table is refused
+fn rewrite_multi(statements: &[&str], filters: &[SessionFilter], schema: Option<&str>) -> Rewrite {
+    let mut schema = schema.map(|s| s.to_string());
+    let mut filtered: Vec<(String, &SessionFilter)> = vec![];
+    let mut queries = true;
+    for text in statements {
+        let tokens = tokenize(text);
+        match use_schema(&tokens) {
+            Some(Some(name)) => schema = Some(name),
+            // the tables after it can't be resolved
+            Some(None) => return Rewrite::Refuse("*".to_string()),
+            None => queries &= is_query(&tokens),
+        }
+        if let Some(from) = sql::prepared_from(text) {
+            if let refuse @ Rewrite::Refuse(_) = rewrite_prepare(from, filters, schema.as_ref().map(|s| &s[..])) {
+                return refuse;
+            }
+        }
+        for span in table_spans(&tokens) {
+            let table_schema = span.table.schema.as_ref().or(schema.as_ref()).map(|s| &s[..]);
+            if let Some(filter) = filters.iter().find(|f| f.filter.covers(table_schema, &span.table.table)) {
+                filtered.push((span.table.table.clone(), filter));
+            }
+        }
+    }
+    match filtered.first() {
+        None => Rewrite::Unchanged,
+        Some(&(_, filter)) if queries && filtered.len() == 1 => Rewrite::FilterRows(filter.clone()),
+        Some((table, _)) => Rewrite::Refuse(table.clone()),
+    }
+}
+
+/// A `PREPARE` is refused if the statement it prepares names a filtered table, or if that
+/// statement can't be read
+fn rewrite_prepare(from: PreparedFrom, filters: &[SessionFilter], schema: Option<&str>) -> Rewrite {
+    let text = match from {
+        PreparedFrom::Literal(text) => text,
+        PreparedFrom::Expression if filters.is_empty() => return Rewrite::Unchanged,
+        PreparedFrom::Expression => return Rewrite::Refuse("*".to_string()),
+    };
+    let tokens = tokenize(&text);
+    let filtered = table_spans(&tokens).into_iter().find(|span| {
+        let table_schema = span.table.schema.as_ref().map(|s| &s[..]).or(schema);
+        filters.iter().any(|f| f.filter.covers(table_schema, &span.table.table))
+    });
+    match filtered {
+        Some(span) => Rewrite::Refuse(span.table.table),
+        None => Rewrite::Unchanged,
+    }
+}
+
+/// Add the filter to the `WHERE` clause of an `UPDATE` or `DELETE` of one table, returning
+/// the byte range to replace and its replacement
+fn add_where(sql: &str, tokens: &[Token], span: &TableSpan, filter: &SessionFilter) -> Option<(usize, usize, String)> {
+    let qualifier = span.alias.as_ref().map(|(a, _)| &a[..]).unwrap_or(&span.table.table);
+    let predicate = filter.predicate(Some(qualifier));
+    let mut depth = 0_i32;
+    let mut condition: Option<usize> = None;
+    for (i, t) in tokens.iter().enumerate().skip(span.end) {
+        if t.is_punct(b'(') {
+            depth += 1;
+        } else if t.is_punct(b')') {
+            depth -= 1;
+        }
+        if depth != 0 {
+            continue;
+        }
+        if t.is_keyword("where") && condition.is_none() {
+            condition = Some(i + 1);
+        } else if t.is_keyword("order") || t.is_keyword("limit") || t.is_punct(b';') {
+            return Some(match condition {
+                Some(start) if start < i => {
+                    let (from, to) = (tokens[start].start, tokens[i - 1].end);
+                    (from, to, format!("({}) AND ({})", predicate, &sql[from..to]))
+                },
+                Some(_) => return None,
+                None => (t.start, t.start, format!("WHERE {} ", predicate)),
+            });
+        }
+    }
+    let last = tokens.last()?;
+    Some(match condition {
+        Some(start) if start < tokens.len() => {
+            let from = tokens[start].start;
+            (from, last.end, format!("({}) AND ({})", predicate, &sql[from..last.end]))
+        },
+        Some(_) => return None,
+        None => (last.end, last.end, format!(" WHERE {}", predicate)),
+    })
+}
+
+/// The rows of the current result that are being filtered
+struct RowCheck {
+    filter: SessionFilter,
+    decoder: QueryResponseDecoder,
+    /// the filtered column in the current result set, once its columns are known
+    column: Option<usize>,
+    columns_seen: usize,
+}
+
+/// Wraps another handler and applies row filters for a session's user
+pub struct RowFilterHandler<H: PacketHandler> {
+    filters: Vec<SessionFilter>,
+    schema: Option<String>,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// row checks for forwarded queries that will need them, in order, and whether the
+    /// query's response is being checked
+    pending: Vec<Option<SessionFilter>>,
+    check: Option<RowCheck>,
+    /// packets dropped from the current response, so later ones are renumbered
+    dropped: u8,
+    inner: H,
+}
+
+impl<H> RowFilterHandler<H> where H: PacketHandler {
+
+    /// Apply the filters to `user`, taking values from its mapping's `attributes`
+    pub fn new(filters: &[RowFilter], user: &str, attributes: &HashMap<String, String>, inner: H) -> Self {
+        let filters = filters.iter()
+            .filter(|f| !f.exempt_users.iter().any(|u| u == user))
+            .map(|f| SessionFilter { filter: f.clone(), value: f.value_for(user, attributes) })
+            .collect();
+        RowFilterHandler {
+            filters,
+            schema: None,
+            capability_flags: CLIENT_PROTOCOL_41,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: vec![],
+            check: None,
+            dropped: 0,
+            inner,
+        }
+    }
+
+    /// Apply the filters to the session's user, starting in the schema it logged in with
+    pub fn for_session(filters: Arc<Vec<RowFilter>>, session: &Session, inner: H) -> Self {
+        let mut handler = RowFilterHandler::new(&filters, &session.user, &session.attributes, inner)
+            .with_capabilities(session.backend_capabilities);
+        handler.schema = session.database.clone();
+        handler
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    /// What to do with a command, and the row check its response needs
+    fn command(&mut self, p: &Packet) -> (Option<Action>, Option<SessionFilter>) {
+        let payload = p.payload();
+        let arg = String::from_utf8_lossy(payload.get(1..).unwrap_or(&[]));
+        let prepare = match p.packet_type() {
+            Ok(PacketType::ComInitDb) => {
+                self.schema = Some(arg.into_owned());
+                return (None, None);
+            },
+            Ok(PacketType::ComQuery) => false,
+            Ok(PacketType::ComStmtPrepare) => true,
+            _ => return (None, None),
+        };
+        let refuse = |table: &str| Action::Error {
+            code: ER_NOT_SUPPORTED_YET,
+            state: *b"42000",
+            msg: format!("The proxy can't apply the row filter for table '{}' to this statement", table),
+        };
+        if payload.len() >= MAX_PAYLOAD_LEN && !self.filters.is_empty() {
+            return (Some(refuse("*")), None);
+        }
+        let rewrite = rewrite(&arg, &self.filters, self.schema.as_ref().map(|s| &s[..]));
+        if !matches!(rewrite, Rewrite::Refuse(_)) {
+            // assume the query's USEs succeed, a failed USE leaves the backend's schema where it was
+            for text in sql::split(&arg) {
+                if let Some(Some(name)) = use_schema(&tokenize(text)) {
+                    self.schema = Some(name);
+                }
+            }
+        }
+        match rewrite {
+            Rewrite::Unchanged => (None, None),
+            Rewrite::Rewritten(sql) => {
+                debug!("Row filters rewrote query to: {}", sql);
+                let mut rewritten = Vec::with_capacity(1 + sql.len());
+                rewritten.push(payload[0]);
+                rewritten.extend_from_slice(sql.as_bytes());
+                if rewritten.len() >= MAX_PAYLOAD_LEN {
+                    return (Some(refuse("*")), None);
+                }
+                (Some(Action::Mutate(Packet::new(0, &rewritten))), None)
+            },
+            // binary rows of prepared statements aren't decoded
+            Rewrite::FilterRows(filter) if prepare => (Some(refuse(&filter.filter.table)), None),
+            Rewrite::FilterRows(filter) => (None, Some(filter)),
+            Rewrite::Refuse(table) => (Some(refuse(&table)), None),
+        }
+    }
+
+    /// Whether a response packet is a row the filter rejects
+    fn rejects(&mut self, p: &Packet) -> bool {
+        let check = match self.check {
+            Some(ref mut check) => check,
+            None => return false,
+        };
+        match check.decoder.decode(p) {
+            Ok(QueryResponse::ColumnCount(_)) => {
+                check.column = None;
+                check.columns_seen = 0;
+                false
+            },
+            Ok(QueryResponse::Column(column)) => {
+                let table = check.filter.filter.table.rsplit('.').next().unwrap_or("");
+                if column.org_name.eq_ignore_ascii_case(&check.filter.filter.column) && column.org_table.eq_ignore_ascii_case(table) {
+                    check.column = Some(check.columns_seen);
+                }
+                check.columns_seen += 1;
+                false
+            },
+            Ok(QueryResponse::Row(row)) => {
+                let value = check.column.and_then(|i| row.get(i)).and_then(|v| v.as_ref());
+                match (value, check.filter.value.as_ref()) {
+                    (Some(value), Some(expected)) => value != expected.as_bytes(),
+                    // without the column, or a value for the user, no row can be shown
+                    _ => true,
+                }
+            },
+            Ok(_) => false,
+            // rows the decoder can't follow can't be checked
+            Err(_) => true,
+        }
+    }
+}
+
+impl<H> PacketHandler for RowFilterHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        match self.phase.phase() {
+            ConnectionPhase::Handshake => {
+                // the handshake response starts with the client's capabilities
+                if p.sequence_id() == 1 && p.payload().len() >= 32 {
+                    self.capability_flags = LittleEndian::read_u32(p.payload());
+                    self.correlator.set_capabilities(self.capability_flags);
+                }
+                self.inner.handle_request(p)
+            },
+            ConnectionPhase::Command => {
+                let (action, check) = if p.sequence_id() == 0 && !self.filters.is_empty() {
+                    self.command(p)
+                } else {
+                    (None, None)
+                };
+                let action = match action {
+                    Some(Action::Mutate(rewritten)) => match self.inner.handle_request(&rewritten) {
+                        Action::Forward => Action::Mutate(rewritten),
+                        action => action,
+                    },
+                    Some(action) => return action,
+                    None => self.inner.handle_request(p),
+                };
+                // only commands the backend will answer
+                let forwarded = match action {
+                    Action::Forward => Some(p),
+                    Action::Mutate(ref p2) => Some(p2),
+                    _ => None,
+                };
+                if let Some(forwarded) = forwarded {
+                    let issued = self.correlator.issued();
+                    self.correlator.request(forwarded);
+                    if self.correlator.issued() > issued {
+                        self.pending.push(check);
+                    }
+                }
+                action
+            },
+        }
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase != ConnectionPhase::Command {
+            return self.inner.handle_response(p);
+        }
+        if self.check.is_none() {
+            // the response to the oldest command may need checking
+            if let Some(filter) = self.pending.first_mut().and_then(|check| check.take()) {
+                self.check = Some(RowCheck {
+                    filter,
+                    decoder: QueryResponseDecoder::new(self.capability_flags),
+                    column: None,
+                    columns_seen: 0,
+                });
+            }
+        }
+        let last = match self.correlator.response(p) {
+            Some(answered) => answered.last,
+            None => false,
+        };
+        let rejected = self.rejects(p) && !last;
+        let dropped = self.dropped;
+        if last {
+            self.pending.remove(0);
+            self.check = None;
+            self.dropped = 0;
+        }
+        if rejected {
+            self.dropped = self.dropped.wrapping_add(1);
+            return Action::Drop;
+        }
+        if dropped == 0 {
+            return self.inner.handle_response(p);
+        }
+        let renumbered = Packet { bytes: p.bytes.clone() }.with_sequence_id(p.sequence_id().wrapping_sub(dropped));
+        match self.inner.handle_response(&renumbered) {
+            Action::Forward => Action::Mutate(renumbered),
+            action => action,
+        }
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,301 @@
+//! Rules restricting which users may touch which schemas, tables and columns.
+//!
+//! A rule such as "nobody except `billing_svc` may use `payments.cards`" is checked against
+//! the tables each statement names, as found by the `sql` module, before it is forwarded.
+//! Blocked statements get the same access denied error MySQL itself would send; audited
+//! ones are forwarded and recorded. Unqualified table names are taken to be in the session's
+//! current schema, as set at login, with `COM_INIT_DB` or with `USE`, including a `USE`
+//! earlier in the same multi-statement query. A restricted user's multi-statement query with
+//! a `USE` whose schema can't be read is refused, since the tables after it can't be resolved.
+//! `PREPARE ... FROM '<statement>'` is checked as the statement it prepares, and a restricted
+//! user's `PREPARE ... FROM @variable` is refused, since the proxy can't see what it holds.
+//! `HANDLER` statements are checked like any other that names a table.
+//!
+//! Table names are found without fully parsing the statement, so a rule may also match a
+//! statement that only looks like it uses the table, e.g. through an alias of the same name.
+//! Column rules match any statement that mentions the column name or selects `*` from the
+//! table.
+
+use std::sync::Arc;
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::audit::AuditLog;
+use super::auth::Session;
+use super::events::{Event, EventBus};
+use super::codec::MAX_PAYLOAD_LEN;
+use super::rowfilter::ER_NOT_SUPPORTED_YET;
+use super::sql::{self, PreparedFrom, Statement, TableRef};
+
+/// MySQL error ER_TABLEACCESS_DENIED_ERROR
+pub const ER_TABLEACCESS_DENIED_ERROR: u16 = 1142;
+
+/// MySQL error ER_COLUMNACCESS_DENIED_ERROR
+pub const ER_COLUMNACCESS_DENIED_ERROR: u16 = 1143;
+
+/// MySQL error ER_NET_PACKET_TOO_LARGE
+pub const ER_NET_PACKET_TOO_LARGE: u16 = 1153;
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq,Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// reject the statement with an access denied error
+    Block,
+    /// forward the statement, recording it in the audit log
+    Audit,
+}
+
+/// Access to a table, or some of its columns, allowed only for some users
+#[derive(Clone,Debug,Deserialize,PartialEq,Serialize)]
+pub struct TableRule {
+    /// `schema.table`, where either part may be `*`
+    pub table: String,
+    /// only these columns, otherwise the whole table
+    #[serde(default)]
+    pub columns: Vec<String>,
+    /// users the rule doesn't apply to
+    #[serde(default)]
+    pub allow_users: Vec<String>,
+    #[serde(default = "TableRule::default_action")]
+    pub action: RuleAction,
+}
+
+impl TableRule {
+
+    fn default_action() -> RuleAction {
+        RuleAction::Block
+    }
+
+    /// Check the rule for mistakes that would keep it from ever matching
+    pub fn validate(&self) -> Result<(), String> {
+        if self.table.is_empty() || self.table.split('.').any(|part| part.is_empty()) || self.table.matches('.').count() > 1 {
+            return Err(format!("invalid table '{}', expected `schema.table`", self.table));
+        }
+        if self.columns.iter().any(|column| column.is_empty()) {
+            return Err(format!("empty column name for table '{}'", self.table));
+        }
+        Ok(())
+    }
+
+    /// Whether the rule covers `table` in `schema`. Schema names are compared exactly, as on
+    /// Linux, table names without regard to case.
+    fn covers(&self, schema: &str, table: &str) -> bool {
+        let (rule_schema, rule_table) = self.table.split_once('.').unwrap_or(("*", &self.table[..]));
+        (rule_schema == "*" || rule_schema == schema) && (rule_table == "*" || rule_table.eq_ignore_ascii_case(table))
+    }
+}
+
+/// A statement that a rule applies to
+#[derive(Clone,Debug,PartialEq)]
+pub struct Violation {
+    pub action: RuleAction,
+    pub schema: String,
+    pub table: String,
+    /// the column the rule protects, for column rules
+    pub column: Option<String>,
+}
+
+/// Check a statement run by `user` in `schema` against the rules, returning the first rule
+/// that blocks it, or else the first that audits it
+pub fn check(rules: &[TableRule], user: &str, schema: Option<&str>, statement: &Statement) -> Option<Violation> {
+    let mut audit = None;
+    for rule in rules.iter().filter(|r| !r.allow_users.iter().any(|u| u == user)) {
+        for TableRef { schema: table_schema, table } in &statement.tables {
+            let table_schema = match table_schema.as_ref().map(|s| &s[..]).or(schema) {
+                Some(s) => s,
+                None => continue,
+            };
+            if !rule.covers(table_schema, table) {
+                continue;
+            }
+            let column = if rule.columns.is_empty() {
+                None
+            } else {
+                match rule.columns.iter().find(|c| statement.uses_column(c)) {
+                    Some(column) => Some(column.clone()),
+                    None => continue,
+                }
+            };
+            let violation = Violation { action: rule.action, schema: table_schema.to_string(), table: table.clone(), column };
+            match rule.action {
+                RuleAction::Block => return Some(violation),
+                RuleAction::Audit => if audit.is_none() { audit = Some(violation) },
+            }
+        }
+    }
+    audit
+}
+
+/// Wraps another handler and enforces table rules for a session's user
+pub struct TableRulesHandler<H: PacketHandler> {
+    rules: Arc<Vec<TableRule>>,
+    user: String,
+    host: String,
+    schema: Option<String>,
+    audit_log: Option<AuditLog>,
+    events: Option<EventBus>,
+    /// the rest of a rejected statement that was split over several packets
+    discarding: bool,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> TableRulesHandler<H> where H: PacketHandler {
+
+    pub fn new(rules: Arc<Vec<TableRule>>, user: &str, host: &str, inner: H) -> Self {
+        TableRulesHandler {
+            rules,
+            user: user.to_string(),
+            host: host.to_string(),
+            schema: None,
+            audit_log: None,
+            events: None,
+            discarding: false,
+            phase: PhaseTracker::new(),
+            inner,
+        }
+    }
+
+    /// Enforce the rules for the session's user, starting in the schema it logged in with
+    pub fn for_session(rules: Arc<Vec<TableRule>>, session: &Session, inner: H) -> Self {
+        let mut handler = TableRulesHandler::new(rules, &session.user, &session.client.ip().to_string(), inner);
+        handler.schema = session.database.clone();
+        handler
+    }
+
+    /// Record audited statements in `log`, rather than only logging them
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit_log = Some(log);
+        self
+    }
+
+    /// Publish the statements rules match on `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Whether any rule applies to the user
+    fn restricted(&self) -> bool {
+        self.rules.iter().any(|r| !r.allow_users.contains(&self.user))
+    }
+
+    fn check(&mut self, sql: &str) -> Option<Action> {
+        // each statement of a multi-statement query runs in the schema left by the USEs before it
+        let statements = sql::split(sql);
+        let mut schema = self.schema.clone();
+        let mut audited = None;
+        for text in &statements {
+            let statement = Statement::parse(text);
+            if statement.command == "USE" {
+                // assume it succeeds, a failed USE also stops the statements after it
+                match statement.identifiers.get(1) {
+                    Some(name) => schema = Some(name.clone()),
+                    None if statements.len() > 1 && self.restricted() => return Some(Action::Error {
+                        code: ER_NOT_SUPPORTED_YET,
+                        state: *b"42000",
+                        msg: "Statement rejected: the proxy's table rules can't follow its USE".to_string(),
+                    }),
+                    None => {},
+                }
+            }
+            // a prepared statement is checked as it will run
+            let statement = match sql::prepared_from(text) {
+                Some(PreparedFrom::Literal(prepared)) => Statement::parse(&prepared),
+                Some(PreparedFrom::Expression) if self.restricted() => return Some(Action::Error {
+                    code: ER_NOT_SUPPORTED_YET,
+                    state: *b"42000",
+                    msg: "Statement rejected: the proxy's table rules can't check a statement prepared from an expression".to_string(),
+                }),
+                _ => statement,
+            };
+            let violation = match check(&self.rules, &self.user, schema.as_ref().map(|s| &s[..]), &statement) {
+                Some(violation) => violation,
+                None => continue,
+            };
+            if let Some(ref events) = self.events {
+                events.publish_with(|| Event::RuleMatched {
+                    user: self.user.clone(),
+                    action: violation.action,
+                    schema: violation.schema.clone(),
+                    table: violation.table.clone(),
+                    column: violation.column.clone(),
+                    statement: sql.to_string(),
+                });
+            }
+            match violation.action {
+                RuleAction::Block => {
+                    info!("Blocked {} by '{}' on {}.{}", statement.command, self.user, violation.schema, violation.table);
+                    let msg = match violation.column {
+                        Some(ref column) => format!("{} command denied to user '{}'@'{}' for column '{}' in table '{}'",
+                                                    statement.command, self.user, self.host, column, violation.table),
+                        None => format!("{} command denied to user '{}'@'{}' for table '{}'",
+                                        statement.command, self.user, self.host, violation.table),
+                    };
+                    let code = if violation.column.is_some() { ER_COLUMNACCESS_DENIED_ERROR } else { ER_TABLEACCESS_DENIED_ERROR };
+                    return Some(Action::Error { code, state: *b"42000", msg });
+                },
+                RuleAction::Audit => if audited.is_none() { audited = Some((statement.command, violation)) },
+            }
+        }
+        if let Some((command, violation)) = audited {
+            info!("Audited {} by '{}' on {}.{}: {}", command, self.user, violation.schema, violation.table, sql);
+            if let Some(ref log) = self.audit_log {
+                if let Err(e) = log.record(&self.user, sql) {
+                    // refuse statements that can't be audited rather than run them unrecorded
+                    warn!("Failed to write audit record: {}", e);
+                    return Some(Action::Error {
+                        code: 1105, // ER_UNKNOWN_ERROR
+                        state: *b"HY000",
+                        msg: "Statement rejected: audit log unavailable".to_string(),
+                    });
+                }
+            }
+        }
+        self.schema = schema;
+        None
+    }
+}
+
+impl<H> PacketHandler for TableRulesHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.discarding {
+            self.discarding = p.payload().len() == MAX_PAYLOAD_LEN;
+            return Action::Drop;
+        }
+        if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0 {
+            let arg = String::from_utf8_lossy(p.payload().get(1..).unwrap_or(&[]));
+            let action = match p.packet_type() {
+                Ok(PacketType::ComInitDb) => {
+                    self.schema = Some(arg.into_owned());
+                    None
+                },
+                // only the first packet could be checked, so the statement can't be allowed
+                Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtPrepare)
+                    if p.payload().len() == MAX_PAYLOAD_LEN && self.restricted() => Some(Action::Error {
+                        code: ER_NET_PACKET_TOO_LARGE,
+                        state: *b"08S01",
+                        msg: "Statement too large to check against the proxy's table rules".to_string(),
+                    }),
+                Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtPrepare) => self.check(&arg),
+                _ => None,
+            };
+            if let Some(action) = action {
+                self.discarding = p.payload().len() == MAX_PAYLOAD_LEN;
+                return action;
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
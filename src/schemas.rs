@@ -0,0 +1,163 @@
+//! Pinning proxy users to a set of schemas.
+//!
+//! A user's `schemas` policy names the schema its sessions start in and the schemas it may
+//! switch to. A login naming no schema is given the default one, while a login naming one
+//! outside the set is refused with the access denied error MySQL sends for schemas the user
+//! has no grants on. `SchemaHandler` answers `COM_INIT_DB` and `USE` to other schemas with
+//! the same error, without forwarding them, checking every statement of a multi-statement
+//! query. A `USE` whose schema it can't read, and `COM_CHANGE_USER`, which names a schema of
+//! its own, are refused as well.
+//!
+//! This is coarse tenancy for backends whose grants can't tell the users apart, e.g. when
+//! they share one backend user. Only the current schema is checked: statements naming
+//! tables of other schemas, such as `SELECT * FROM other.t`, are forwarded as they are.
+//! Schema names are the ones clients use, before any tenant prefix is applied.
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::MAX_PAYLOAD_LEN;
+use super::honeypot::ER_DBACCESS_DENIED_ERROR;
+use super::rowfilter::ER_NOT_SUPPORTED_YET;
+use super::sql::{self, Statement};
+
+/// The schemas a user may use, e.g. `default = "app"` and `allowed = ["app_reports"]`
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct SchemaPolicy {
+    /// the schema sessions start in when the client names none
+    #[serde(default)]
+    pub default: Option<String>,
+    /// schemas the user may use besides the default
+    #[serde(default)]
+    pub allowed: Vec<String>,
+}
+
+impl SchemaPolicy {
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.default.is_none() && self.allowed.is_empty() {
+            return Err("no default or allowed schemas".to_string());
+        }
+        if self.default.iter().chain(self.allowed.iter()).any(|s| s.is_empty()) {
+            return Err("empty schema name".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the user may use `schema`
+    pub fn allows(&self, schema: &str) -> bool {
+        self.default.as_ref().map(|s| s == schema).unwrap_or(false) || self.allowed.iter().any(|s| s == schema)
+    }
+
+    /// The schema a login starts in, or the schema it named that the user may not use
+    pub fn login_schema(&self, requested: Option<&str>) -> Result<Option<String>, String> {
+        match requested {
+            Some(schema) if self.allows(schema) => Ok(Some(schema.to_string())),
+            Some(schema) => Err(schema.to_string()),
+            None => Ok(self.default.clone()),
+        }
+    }
+}
+
+/// The error for a user switching to a schema it may not use
+pub fn access_denied(user: &str, host: &str, schema: &str) -> String {
+    format!("Access denied for user '{}'@'{}' to database '{}'", user, host, schema)
+}
+
+/// Wraps another handler and refuses switches to schemas the user's policy doesn't allow
+pub struct SchemaHandler<H: PacketHandler> {
+    policy: SchemaPolicy,
+    user: String,
+    host: String,
+    phase: PhaseTracker,
+    /// dropping the rest of a refused statement split over several packets
+    discarding: bool,
+    inner: H,
+}
+
+impl<H> SchemaHandler<H> where H: PacketHandler {
+
+    pub fn new(policy: SchemaPolicy, user: &str, host: &str, inner: H) -> Self {
+        SchemaHandler {
+            policy,
+            user: user.to_string(),
+            host: host.to_string(),
+            phase: PhaseTracker::new(),
+            discarding: false,
+            inner,
+        }
+    }
+
+    /// Enforce the policy for the session's user, whose login has already been checked
+    pub fn for_session(policy: SchemaPolicy, session: &Session, inner: H) -> Self {
+        SchemaHandler::new(policy, &session.user, &session.client.ip().to_string(), inner)
+    }
+
+    /// The schemas a command switches to, with `None` for a `USE` whose schema can't be read
+    fn switches_to(p: &Packet) -> Vec<Option<String>> {
+        let payload = p.payload();
+        match p.packet_type() {
+            Ok(PacketType::ComInitDb) => vec![Some(String::from_utf8_lossy(&payload[1..]).into_owned())],
+            Ok(PacketType::ComQuery) => {
+                let sql = String::from_utf8_lossy(&payload[1..]);
+                sql::split(&sql).into_iter()
+                    .map(Statement::parse)
+                    .filter(|statement| statement.command == "USE")
+                    .map(|statement| statement.identifiers.get(1).cloned())
+                    .collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    /// The error to refuse a command with, if the policy doesn't allow it
+    fn refusal(&self, p: &Packet) -> Option<Action> {
+        let not_supported = |msg: &str| Action::Error { code: ER_NOT_SUPPORTED_YET, state: *b"42000", msg: msg.to_string() };
+        if p.packet_type().ok() == Some(PacketType::ComChangeUser) {
+            info!("Refused COM_CHANGE_USER by '{}', whose schemas are pinned", self.user);
+            return Some(not_supported("Statement rejected: users pinned to schemas can't change user"));
+        }
+        for schema in Self::switches_to(p) {
+            match schema {
+                Some(ref schema) if self.policy.allows(schema) => {},
+                Some(schema) => {
+                    info!("Refused switch by '{}' to schema '{}'", self.user, schema);
+                    return Some(Action::Error {
+                        code: ER_DBACCESS_DENIED_ERROR,
+                        state: *b"42000",
+                        msg: access_denied(&self.user, &self.host, &schema),
+                    });
+                },
+                None => return Some(not_supported("Statement rejected: the proxy's schema policy can't follow its USE")),
+            }
+        }
+        None
+    }
+}
+
+impl<H> PacketHandler for SchemaHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.discarding {
+            self.discarding = p.payload().len() == MAX_PAYLOAD_LEN;
+            return Action::Drop;
+        }
+        if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0 {
+            if let Some(refusal) = self.refusal(p) {
+                self.discarding = p.payload().len() == MAX_PAYLOAD_LEN;
+                return refusal;
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
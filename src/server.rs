@@ -0,0 +1,744 @@
+//! The complete proxy server, as run by the `mysql-proxy` binary.
+//!
+//! `run` starts everything a `ProxyConfig` describes: the health endpoint, the X Protocol
+//! relay, and a thread per listener profile accepting connections. Each client is
+//! authenticated with its user mapping and connected to a backend in its routing group,
+//! through the handlers the configuration enables, and to the backend the group's weights
+//! pick. `run_with_events` also publishes what the proxy does on an `EventBus`.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::stream::Stream;
+
+use super::{Action, Packet, PacketHandler, Pipe, Transport};
+use super::acl::AccessControl;
+use super::admin::{self, Admin};
+use super::annotate::AnnotateHandler;
+use super::anomaly::ProtocolChecks;
+use super::audit::{AuditHandler, AuditLog};
+use super::auth::{ChangeUserHandler, ProxyAuth};
+use super::balance::{BackendPool, BackendWeights};
+use super::breaker::{BreakerHandler, CircuitBreaker};
+use super::capture::{CaptureHandler, SessionCapture};
+use super::charset::CharsetHandler;
+use super::chargeback::Chargeback;
+use super::coalesce::{Coalescer, SessionCoalescing};
+use super::connect::BackendAddr;
+use super::config::{ListenerProfile, ProxyConfig, RoutingGroup, TlsConfig};
+use super::discovery;
+use super::errors::{ErrorRules, ErrorRulesHandler};
+use super::eventlog::EventLog;
+use super::events::{Event, EventBus};
+use super::explain::ExplainHandler;
+use super::health::{self, HealthMonitor, PingHandler};
+use super::honeypot;
+use super::idle::IdleTransactionGuard;
+use super::lifetime::LifetimeGuard;
+use super::latency::{BackendLatency, LatencyHandler};
+use super::legacy::LegacyEofHandler;
+use super::listener::{self, ListenerControl};
+use super::maintenance::{MaintenanceHandler, MaintenancePolicy};
+use super::management::{self, Management, Rules, SharedRules};
+use super::metacache::{MetadataCache, SessionMetadataCache};
+use super::pool::BufferPool;
+use super::priming::{Priming, SessionPriming};
+use super::profile::{self, StageTotals};
+use super::protocol::{CLIENT_COMPRESS, CLIENT_DEPRECATE_EOF};
+use super::querylog::{QueryLog, QueryLogHandler};
+use super::quota::{QuotaHandler, Quotas};
+use super::resume::{Reconnect, SessionResume};
+use super::rowfilter::RowFilterHandler;
+use super::rules::TableRulesHandler;
+use super::schemas::SchemaHandler;
+use super::sink::EventSink;
+use super::sockopt;
+use super::stats::{Stats, StatsHandler};
+use super::tap::{Tap, TapHandler, TapWriter};
+use super::tarpit::Tarpit;
+use super::tenant::TenantHandler;
+use super::timing::ExchangeTimer;
+use super::trace::TraceHandler;
+use super::unknown::{UnknownCommandHandler, UnknownCommandPolicy};
+use super::users::UserMap;
+use super::warmpool::WarmPool;
+use super::variables::VariablesHandler;
+use super::xprotocol;
+
+/// Run the proxy until its listeners fail. Without listener profiles in the configuration,
+/// it listens on `default_listen`.
+pub fn run(config: &ProxyConfig, default_listen: Vec<SocketAddr>) -> io::Result<()> {
+    run_with_events(config, default_listen, EventBus::new())
+}
+
+/// Run the proxy like `run`, publishing its events on `events`
+pub fn run_with_events(config: &ProxyConfig, default_listen: Vec<SocketAddr>, events: EventBus) -> io::Result<()> {
+
+    // users' quotas apply across every listener
+    let quotas = Quotas::new();
+
+    // write security events for fail2ban or a SIEM
+    if let Some(ref event_log_config) = config.event_log {
+        EventLog::open(event_log_config)?.subscribe(&events);
+    }
+
+    // publish events and audit records to a data pipeline
+    let sink = match config.event_sink {
+        Some(ref sink_config) => {
+            let sink = EventSink::open(sink_config)?;
+            sink.subscribe(&events);
+            Some(sink)
+        },
+        None => None,
+    };
+
+    // carry on counting from the statistics saved by the last run
+    let stats = match config.stats {
+        Some(ref stats_config) => {
+            let stats = Stats::load(&stats_config.path)?.with_quotas(quotas.clone())
+                .with_labels(&stats_config.labels, stats_config.max_label_values);
+            stats.save_in_thread(stats_config)?;
+            Some(stats)
+        },
+        None => None,
+    };
+
+    // report each user's usage period by period
+    let chargeback = match (&config.chargeback, &stats) {
+        (Some(chargeback_config), Some(stats)) => {
+            let chargeback = Chargeback::new(stats.clone());
+            chargeback.run_in_thread(chargeback_config)?;
+            Some(chargeback)
+        },
+        _ => None,
+    };
+
+    // time a sample of each pipe's stages
+    let profile = config.profiling.as_ref().map(|profiling| {
+        let totals = StageTotals::default();
+        profile::set_hook(Arc::new(totals.clone()), profiling.sample_every);
+        totals
+    });
+
+    // look up backends of groups with discovery before anything needs them
+    let weights = BackendWeights::new();
+    let backends = backend_pool(&config.groups, &weights)?;
+    let latency = BackendLatency::new();
+    let breaker = config.circuit_breaker.clone().map(CircuitBreaker::new);
+
+    // answer load balancer health checks on a thread of their own
+    if let Some(ref health_config) = config.health {
+        let mut monitor = HealthMonitor::new().with_upstream(config.upstream.clone()).with_stats(stats.clone())
+            .with_events(events.clone())
+            .with_backend_pool(backends.clone())
+            .with_connect_retry(config.connect_retry.clone());
+        if health_config.slow_start_secs > 0 {
+            monitor = monitor.with_warm_up(weights.clone(), Duration::from_secs(health_config.slow_start_secs));
+        }
+        if let Some(ref replication) = health_config.replication {
+            monitor = monitor.with_replication(replication, Duration::from_millis(health_config.timeout_ms));
+        }
+        health::run_in_thread(health_config, backends.all(), monitor)?;
+        info!("Health checks on: {}", health_config.listen);
+    }
+
+    // take changes through the management API on a thread of its own
+    let management = match config.management {
+        Some(ref management_config) => {
+            let management = Management::new(backends.clone(), weights.clone()).with_stats(stats.clone())
+                .with_chargeback(chargeback.clone()).with_profile(profile.clone());
+            management::run_in_thread(management_config, management.clone())?;
+            info!("Management API on: {}", management_config.listen);
+            if let Some(ref admin_config) = management_config.admin {
+                let admin = Admin::new(management.clone()).with_path(admin_config.path.clone());
+                admin::run_in_thread(admin_config, admin)?;
+                info!("Admin interface on: {}", admin_config.listen);
+            }
+            Some(management)
+        },
+        None => None,
+    };
+
+    // relay X Protocol clients untouched, if configured
+    if let Some(ref x_config) = config.x_protocol {
+        xprotocol::run_in_thread(x_config)?;
+        info!("X Protocol on: {}", x_config.listen);
+    }
+
+    let profiles = if config.listeners.is_empty() {
+        vec![ListenerProfile {
+            name: "default".to_string(),
+            listen: default_listen,
+            group: None,
+            max_connections: None,
+            config: config.clone(),
+        }]
+    } else {
+        config.listeners.clone()
+    };
+
+    // listeners that record to the same audit log share it, to keep a single hash chain,
+    // those sampling to the same query log share the sampling, and taps writing to the same
+    // file share its writer
+    let mut audit_logs: HashMap<PathBuf, AuditLog> = HashMap::new();
+    let mut query_logs: HashMap<PathBuf, QueryLog> = HashMap::new();
+    let mut tap_writers: HashMap<PathBuf, TapWriter> = HashMap::new();
+    let mut threads = vec![];
+    for profile in profiles {
+        let audit_log = match profile.config.audit_log {
+            Some(ref path) if !audit_logs.contains_key(path) => {
+                let mut log = AuditLog::open_files(path, profile.config.audit_files.clone().unwrap_or_default())?;
+                if let Some(ref sink) = sink {
+                    log = log.with_sink(sink.clone());
+                }
+                audit_logs.insert(path.clone(), log.clone());
+                Some(log)
+            },
+            Some(ref path) => audit_logs.get(path).cloned(),
+            None => None,
+        };
+        let query_log = match profile.config.query_log {
+            Some(ref query_log_config) if !query_logs.contains_key(&query_log_config.path) => {
+                let log = QueryLog::open(query_log_config)?;
+                query_logs.insert(query_log_config.path.clone(), log.clone());
+                Some(log)
+            },
+            Some(ref query_log_config) => query_logs.get(&query_log_config.path).cloned(),
+            None => None,
+        };
+        let mut taps = vec![];
+        for tap_config in &profile.config.taps {
+            let writer = match tap_writers.get(&tap_config.path) {
+                Some(writer) => writer.clone(),
+                None => {
+                    let writer = TapWriter::open(tap_config)?;
+                    tap_writers.insert(tap_config.path.clone(), writer.clone());
+                    writer
+                },
+            };
+            taps.push(Tap::new(tap_config, writer));
+        }
+        // listeners with routing groups of their own look up their backends themselves
+        let backends = if profile.config.groups == config.groups {
+            backends.clone()
+        } else {
+            backend_pool(&profile.config.groups, &weights)?
+        };
+        let services = Services {
+            audit_log,
+            query_log,
+            taps,
+            stats: stats.clone(),
+            quotas: quotas.clone(),
+            events: events.clone(),
+            backends,
+            weights: weights.clone(),
+            latency: latency.clone(),
+            breaker: breaker.clone(),
+            management: management.clone(),
+        };
+        let control = ListenerControl::new(&profile.name).with_max_connections(profile.max_connections);
+        threads.push(thread::Builder::new().name(format!("listener-{}", profile.name)).spawn(move || {
+            serve(profile, services, control)
+        })?);
+    }
+    for t in threads {
+        t.join().map_err(|_| io::Error::other("Listener thread panicked"))??;
+    }
+    Ok(())
+}
+
+/// The backends of `groups`, looking up those of groups with discovery now and as the proxy
+/// runs
+fn backend_pool(groups: &HashMap<String, RoutingGroup>, weights: &BackendWeights) -> io::Result<BackendPool> {
+    let pool = BackendPool::new(groups);
+    for (name, group) in groups {
+        if let Some(ref discovery_config) = group.discovery {
+            discovery::run_in_thread(name, discovery_config, pool.clone(), weights.clone())?;
+        }
+    }
+    Ok(pool)
+}
+
+/// What a listener shares with the rest of the proxy
+#[derive(Clone,Default)]
+pub struct Services {
+    /// shared by the listeners recording to the same file
+    pub audit_log: Option<AuditLog>,
+    /// shared by the listeners sampling to the same file
+    pub query_log: Option<QueryLog>,
+    /// writing to files shared by the listeners' taps writing to the same one
+    pub taps: Vec<Tap>,
+    pub stats: Option<Stats>,
+    /// shared by every listener
+    pub quotas: Quotas,
+    pub events: EventBus,
+    /// the backends of the listener's routing groups
+    pub backends: BackendPool,
+    pub weights: BackendWeights,
+    /// how fast backends answer, shared by every listener
+    pub latency: BackendLatency,
+    /// the circuits of every backend, shared by every listener
+    pub breaker: Option<CircuitBreaker>,
+    /// lets the management API change the listener's users and rules, and kill its sessions
+    pub management: Option<Management>,
+}
+
+/// Accept connections for a listener profile, on as many reactor threads as configured
+pub fn serve(profile: ListenerProfile, services: Services, control: ListenerControl) -> io::Result<()> {
+    let Services { audit_log, query_log, taps, stats, quotas, events, backends, weights, latency, breaker, management } = services;
+    let bind_addrs = profile.listen.clone();
+    let profile = Arc::new(profile);
+    let config = Arc::new(profile.config.clone());
+    let pool = BufferPool::new(config.buffer_pool.clone());
+    let coalescer = config.coalesce.as_ref().map(Coalescer::new);
+    let metadata_cache = config.metadata_cache.as_ref().map(MetadataCache::new);
+    let priming = config.session_priming.clone().map(Priming::new);
+    let access = AccessControl::new(config.access.clone());
+    let error_rules = ErrorRules::new(config.error_rules.clone());
+    let users = Arc::new(UserMap::new(config.users.clone()).with_authenticator(config.auth.is_some()));
+    let rules = SharedRules::new(Rules { table_rules: config.table_rules.clone(), row_filters: config.row_filters.clone() });
+    if let Some(ref management) = management {
+        management.add_listener(&profile.name, users.clone(), rules.clone());
+    }
+    let mut proxy_auth = ProxyAuth::new(users)
+        .with_access_control(access.clone())
+        .with_client_socket(config.client_socket)
+        .with_backend_socket(config.backend_socket)
+        .with_greeting(config.greeting.clone())
+        .with_capability_policy(config.capabilities.clone())
+        .with_connect_attrs(config.connect_attrs.clone())
+        .with_tolerant_backends(config.tolerant_backends)
+        .with_backend_deprecate_eof(config.backend_deprecate_eof)
+        .with_backend_collation(config.backend_collation)
+        .with_compression(config.compression.clone())
+        .with_upstream(config.upstream.clone())
+        .with_connect_retry(config.connect_retry.clone())
+        .with_quotas(quotas)
+        .with_error_rules(error_rules.clone())
+        .with_handshake_timeout(config.handshake_timeout())
+        .with_events(events.clone());
+    if let Some(ref tarpit) = config.tarpit {
+        proxy_auth = proxy_auth.with_tarpit(Tarpit::new(tarpit.clone()));
+    }
+    if let Some(ref warm_pool) = config.warm_pool {
+        proxy_auth = proxy_auth.with_warm_pool(WarmPool::new(warm_pool));
+    }
+    if let Some(ref breaker) = breaker {
+        proxy_auth = proxy_auth.with_circuit_breaker(breaker.clone());
+    }
+    if let Some(ref auth_config) = config.auth {
+        proxy_auth = proxy_auth.with_authenticator(auth_config.pool()?);
+    }
+    if let Some(ref credentials) = config.credentials {
+        proxy_auth = proxy_auth.with_credential_provider(credentials.provider()?);
+    }
+    if let Some(ref tls_config) = config.tls {
+        proxy_auth = enable_tls(proxy_auth, tls_config, &profile.name, management.as_ref())?;
+    }
+
+    let workers = config.workers;
+    for addr in &bind_addrs {
+        info!("Listening on: {} ({})", addr, profile.name);
+    }
+    let backlog = config.backlog.unwrap_or(sockopt::DEFAULT_BACKLOG);
+    listener::run(&bind_addrs, workers, backlog, move |connections, handle| {
+        let profile = profile.clone();
+        let config = config.clone();
+        let control = control.clone();
+        let audit_log = audit_log.clone();
+        let query_log = query_log.clone();
+        let taps = taps.clone();
+        let stats = stats.clone();
+        let events = events.clone();
+        let pool = pool.clone();
+        let coalescer = coalescer.clone();
+        let metadata_cache = metadata_cache.clone();
+        let priming = priming.clone();
+        let error_rules = error_rules.clone();
+        let access = access.clone();
+        let proxy_auth = proxy_auth.clone();
+        let rules = rules.clone();
+        let backends = backends.clone();
+        let weights = weights.clone();
+        let latency = latency.clone();
+        let breaker = breaker.clone();
+        let management = management.clone();
+
+        connections.for_each(move |(socket, addr)| {
+
+            // drop connections from networks that aren't allowed before greeting them
+            if !access.check_connection(&addr) {
+                return Ok(());
+            }
+
+            // refuse connections while the listener is paused or full, or the proxy is in
+            // maintenance mode, in place of the greeting
+            let maintenance = management.as_ref().and_then(|m| m.maintenance.policy());
+            let admitted = match (control.admit(), maintenance) {
+                (Ok(_), Some(MaintenancePolicy::RefuseConnections { code, state, msg })) => {
+                    let refusal = Packet::error_packet(code, state, msg).with_sequence_id(0);
+                    handle.spawn(::tokio_io::io::write_all(socket, refusal.bytes).then(|_| Ok(())));
+                    return Ok(());
+                },
+                (Ok(admitted), _) => admitted,
+                (Err(refusal), _) => {
+                    handle.spawn(::tokio_io::io::write_all(socket, refusal.bytes).then(|_| Ok(())));
+                    return Ok(());
+                }
+            };
+
+            // honeypots answer clients themselves, whoever they claim to be
+            if let Some(ref honeypot) = config.honeypot {
+                let trapped = honeypot::serve_client(socket, addr, honeypot, events.clone(), &handle);
+                handle.spawn(trapped.then(move |result| {
+                    drop(admitted);
+                    result.map_err(|err| {
+                        info!("Honeypot session from {} ended: {}", addr, err);
+                    })
+                }));
+                return Ok(());
+            }
+
+            // authenticate the client and connect it to the backend for its routing group
+            let profile = profile.clone();
+            let config = config.clone();
+            let audit_log = audit_log.clone();
+            let query_log = query_log.clone();
+            let taps = taps.clone();
+            let stats = stats.clone();
+            let events = events.clone();
+            let closed_events = events.clone();
+            let pool = pool.clone();
+            let coalescer = coalescer.clone();
+            let metadata_cache = metadata_cache.clone();
+            let priming = priming.clone();
+            let error_rules = error_rules.clone();
+            let reactor = handle.clone();
+            let table_rules = rules.table_rules();
+            let row_filters = rules.row_filters();
+            let management = management.clone();
+            let group = profile.group.clone();
+            let backends = backends.clone();
+            let weights = weights.clone();
+            let latency = latency.clone();
+            let route_latency = latency.clone();
+            let route_config = config.clone();
+            let (route_breaker, resume_breaker, session_breaker) = (breaker.clone(), breaker.clone(), breaker.clone());
+            let (resume_backends, resume_weights) = (backends.clone(), weights.clone());
+            let future = proxy_auth.establish_with_relogin(socket,
+                                              move |user| {
+                                                  let group = group.as_ref().unwrap_or(&user.default_group);
+                                                  let mut backends = backends.backends(group);
+                                                  // backends whose circuits are open get no sessions
+                                                  if let Some(ref breaker) = route_breaker {
+                                                      backends = breaker.available(&backends);
+                                                  }
+                                                  let backend = match route_config.groups.get(group).and_then(|g| g.latency.as_ref()) {
+                                                      Some(latency_config) => {
+                                                          weights.choose_scaled(&backends, &route_latency.scales(&backends, latency_config))
+                                                      },
+                                                      None => weights.choose(&backends),
+                                                  };
+                                                  match (backend, route_breaker) {
+                                                      (Some(backend), Some(breaker)) => Some(backend).filter(|b| breaker.admit(b)),
+                                                      (backend, _) => backend,
+                                                  }
+                                              },
+                                              &handle)
+                .and_then(move |(client, server, mut session, relogin)| {
+                    if let Some(ref labels) = config.labels {
+                        session.labels = labels.labels_for(&session.connect_attrs, &session.attributes);
+                    }
+                    let labelled = if session.labels.is_empty() { String::new() } else { format!(" [{}]", session.labels) };
+                    match session.tls_identity {
+                        Some(ref identity) => info!("User '{}' ({}) connected to {}{}", session.user, identity, session.backend, labelled),
+                        None => info!("User '{}' connected to {}{}", session.user, session.backend, labelled),
+                    }
+                    events.publish_with(|| Event::ConnectionOpened {
+                        user: session.user.clone(),
+                        client: addr,
+                        backend: session.backend.to_string(),
+                        labels: session.labels.snapshot(),
+                    });
+                    let idle_guard = config.idle_transaction.clone().map(|idle| {
+                        let guard = IdleTransactionGuard::new(idle, &session.user, &reactor);
+                        match audit_log {
+                            Some(ref log) => guard.with_audit_log(log.clone()),
+                            None => guard,
+                        }
+                    });
+                    let capture = match (&config.capture, &audit_log) {
+                        (Some(capture), Some(log)) => Some((SessionCapture::new(capture), log.clone())),
+                        _ => None,
+                    };
+                    let mut handler: Box<dyn PacketHandler> = Box::new(PassthroughHandler);
+                    // queries other handlers answer themselves don't count against the quota
+                    if let Some(ref lease) = session.quota {
+                        handler = Box::new(QuotaHandler::new(lease.clone(), handler).with_capabilities(session.backend_capabilities));
+                    }
+                    let group = profile.group.as_ref().unwrap_or(&session.group);
+                    if let Some(latency_config) = config.groups.get(group).and_then(|g| g.latency.as_ref()) {
+                        handler = Box::new(LatencyHandler::new(latency, session.backend_name.clone(), latency_config, handler)
+                            .with_capabilities(session.backend_capabilities));
+                    }
+                    if let Some(breaker) = session_breaker {
+                        handler = Box::new(BreakerHandler::new(breaker, session.backend_name.clone(), handler)
+                            .with_capabilities(session.backend_capabilities));
+                    }
+                    if let Some(ref explain) = config.explain {
+                        handler = Box::new(ExplainHandler::for_session(explain, &session, handler));
+                    }
+                    if let Some(ref tenant) = session.tenant {
+                        handler = Box::new(TenantHandler::new(tenant.clone(), handler).with_capabilities(session.backend_capabilities));
+                    }
+                    if !row_filters.is_empty() {
+                        handler = Box::new(RowFilterHandler::for_session(row_filters, &session, handler));
+                    }
+                    if !table_rules.is_empty() {
+                        let mut rules = TableRulesHandler::for_session(table_rules, &session, handler);
+                        if let Some(ref log) = audit_log {
+                            rules = rules.with_audit_log(log.clone());
+                        }
+                        rules = rules.with_events(events.clone());
+                        handler = Box::new(rules);
+                    }
+                    // outside the table rules, which follow the schema a USE switches to
+                    if let Some(ref policy) = session.schemas {
+                        handler = Box::new(SchemaHandler::for_session(policy.clone(), &session, handler));
+                    }
+                    if let Some(ref annotate) = config.annotate {
+                        handler = Box::new(AnnotateHandler::for_session(annotate, &session, handler));
+                    }
+                    handler = Box::new(VariablesHandler::for_session(&session, handler));
+                    if config.answer_ping {
+                        handler = Box::new(PingHandler::new(handler));
+                    }
+                    if config.unknown_commands == UnknownCommandPolicy::Reject {
+                        handler = Box::new(UnknownCommandHandler::new(handler));
+                    }
+                    if let Some(ref management) = management {
+                        handler = Box::new(MaintenanceHandler::new(management.maintenance.clone(), handler));
+                    }
+                    if let Some(log) = audit_log {
+                        handler = Box::new(AuditHandler::new(log, &session.user, handler).with_labels(session.labels.clone()));
+                    }
+                    if let Some(log) = query_log {
+                        handler = Box::new(QueryLogHandler::for_session(log, &session, handler));
+                    }
+                    if !taps.is_empty() {
+                        handler = Box::new(TapHandler::for_session(taps, &session, handler));
+                    }
+                    let checks = config.protocol_checks.as_ref().map(|checks_config| {
+                        let checks = ProtocolChecks::new(checks_config);
+                        match stats.clone() {
+                            Some(stats) => checks.with_stats(stats),
+                            None => checks,
+                        }
+                    });
+                    let timer = stats.clone().map(ExchangeTimer::new);
+                    if stats.is_some() || events.has_subscribers() {
+                        let mut recorder = StatsHandler::for_session(&session, handler).with_events(events.clone());
+                        if let Some(stats) = stats {
+                            recorder = recorder.with_stats(stats);
+                        }
+                        handler = Box::new(recorder);
+                    }
+                    // statistics count the errors backends sent
+                    if !error_rules.is_empty() {
+                        handler = Box::new(ErrorRulesHandler::for_session(error_rules, &session, handler));
+                    }
+                    if let Some((ref capture, _)) = capture {
+                        handler = Box::new(CaptureHandler::new(capture.clone(), handler).with_capabilities(session.backend_capabilities));
+                    }
+                    if let Some(collation) = config.backend_collation {
+                        handler = Box::new(CharsetHandler::for_session(collation, &session, handler));
+                    }
+                    if session.backend_capabilities & CLIENT_DEPRECATE_EOF != 0 {
+                        handler = Box::new(LegacyEofHandler::new(handler));
+                    }
+                    if !session.auth_passthrough {
+                        handler = Box::new(ChangeUserHandler::new(handler));
+                    }
+                    // traced as the client and backend sent them
+                    if let Some(trace) = config.trace.as_ref().filter(|t| t.traces(&session.user)) {
+                        match trace.create(&session.user, addr) {
+                            Ok(writer) => {
+                                handler = Box::new(TraceHandler::new(writer, handler).with_capabilities(session.backend_capabilities));
+                            },
+                            Err(e) => warn!("Failed to start a protocol trace in {}: {}", trace.dir.display(), e),
+                        }
+                    }
+                    let mut pipe = Pipe::new(Rc::new(client), Rc::new(server), handler)
+                        .with_buffer_pool(&pool)
+                        .with_budget(config.poll_budget)
+                        .with_flush_policy(config.flush, &reactor)
+                        .with_tolerant_backend(config.tolerant_backends)
+                        .with_backend_capabilities(session.backend_capabilities);
+                    if let Some(ref compression) = config.compression {
+                        if session.client_compressed {
+                            pipe = pipe.with_client_compression(compression);
+                        }
+                        if session.backend_capabilities & CLIENT_COMPRESS != 0 {
+                            pipe = pipe.with_server_compression(compression);
+                        }
+                    }
+                    let pipe = match config.deadlock_retry.clone() {
+                        Some(policy) => pipe.with_deadlock_retry(policy, &reactor),
+                        None => pipe,
+                    };
+                    let pipe = match idle_guard {
+                        Some(guard) => pipe.with_idle_transaction_guard(guard),
+                        None => pipe,
+                    };
+                    let pipe = match config.drain_on_close_ms {
+                        Some(ms) => pipe.with_drain_on_close(Duration::from_millis(ms), &reactor),
+                        None => pipe,
+                    };
+                    let pipe = match config.connection_lifetime.clone() {
+                        Some(lifetime) => pipe.with_lifetime_guard(LifetimeGuard::new(lifetime, &session.user, &reactor)),
+                        None => pipe,
+                    };
+                    let pipe = match timer {
+                        Some(timer) => pipe.with_exchange_timing(timer),
+                        None => pipe,
+                    };
+                    let pipe = match coalescer {
+                        Some(coalescer) => pipe.with_coalescing(SessionCoalescing::for_session(coalescer, &session)),
+                        None => pipe,
+                    };
+                    let pipe = match metadata_cache {
+                        Some(cache) => pipe.with_metadata_cache(SessionMetadataCache::for_session(cache, &session)),
+                        None => pipe,
+                    };
+                    let pipe = match priming {
+                        Some(priming) => pipe.with_session_priming(SessionPriming::new(priming, &session.user)),
+                        None => pipe,
+                    };
+                    let pipe = match checks {
+                        Some(checks) => pipe.with_protocol_checks(checks),
+                        None => pipe,
+                    };
+                    let pipe = match (config.session_resume.clone(), relogin) {
+                        (Some(resume_config), Some(relogin)) => {
+                            let resume_group = profile.group.clone().unwrap_or_else(|| session.group.clone());
+                            let (capabilities, mut current) = (session.backend_capabilities, session.backend_name.clone());
+                            // another of the group's backends, or the same one if it's the only one
+                            let reconnect: Reconnect = Box::new(move || {
+                                let mut candidates = resume_backends.backends(&resume_group);
+                                if let Some(ref breaker) = resume_breaker {
+                                    candidates = breaker.available(&candidates);
+                                }
+                                let others: Vec<BackendAddr> = candidates.iter().filter(|b| **b != current).cloned().collect();
+                                let backend = match resume_weights.choose(&others).or_else(|| resume_weights.choose(&candidates)) {
+                                    Some(backend) => backend,
+                                    None => {
+                                        let msg = format!("No backend available for routing group '{}'", resume_group);
+                                        return Box::new(future::err(io::Error::other(msg)));
+                                    },
+                                };
+                                if let Some(ref breaker) = resume_breaker {
+                                    if !breaker.admit(&backend) {
+                                        let msg = format!("The circuit breaker is open for {}", backend);
+                                        return Box::new(future::err(io::Error::other(msg)));
+                                    }
+                                }
+                                info!("Resuming a session of '{}' on {}", resume_group, backend);
+                                current = backend.clone();
+                                Box::new(relogin.login(backend).and_then(move |(server, _, login_capabilities)| {
+                                    // the session's handlers follow the protocol of the first login
+                                    if login_capabilities != capabilities {
+                                        return Err(io::Error::other("The new backend doesn't support the session's capabilities"));
+                                    }
+                                    Ok(Rc::new(server) as Rc<dyn Transport>)
+                                }))
+                            });
+                            // the backend knows a tenant's schema by its prefixed name
+                            let schema = match session.tenant {
+                                Some(ref tenant) => session.database.as_ref().map(|db| tenant.to_backend(db)),
+                                None => session.database.clone(),
+                            };
+                            pipe.with_session_resume(SessionResume::new(resume_config, reconnect, &reactor).with_schema(schema))
+                        },
+                        _ => pipe,
+                    };
+                    // end the session early if it's killed
+                    let registered = management.map(|m| {
+                        m.connections.register(&profile.name, &session.user, addr, &session.backend.to_string(), session.labels.clone())
+                    });
+                    let pipe: Box<dyn Future<Item = (), Error = io::Error>> = match registered {
+                        Some(registered) => Box::new(pipe.select(registered).map(|_| ()).map_err(|(e, _)| e)),
+                        None => Box::new(pipe),
+                    };
+                    let (user, labels, started) = (session.user.clone(), session.labels.clone(), Instant::now());
+                    pipe.then(move |result| {
+                        if let (Some((capture, log)), Err(e)) = (capture, result.as_ref()) {
+                            if let Err(e) = capture.dump(&log, &user, &addr.to_string(), &e.to_string()) {
+                                warn!("Failed to write the commands of the session from {} to the audit log: {}", addr, e);
+                            }
+                        }
+                        closed_events.publish_with(|| Event::ConnectionClosed {
+                            user,
+                            client: addr,
+                            duration_ms: started.elapsed().as_millis() as u64,
+                            error: result.as_ref().err().map(|e| e.to_string()),
+                            labels: labels.snapshot(),
+                        });
+                        result
+                    })
+                });
+
+            // run the session, counting the connection until it ends
+            handle.spawn(future.then(move |result| {
+                drop(admitted);
+                result.map_err(|err| {
+                    warn!("Session from {} ended: {}", addr, err);
+                })
+            }));
+
+            Ok(())
+        })
+    })
+}
+
+/// Offer TLS to the listener's clients, reloading the certificate when it changes or the
+/// management API asks to
+#[cfg(feature = "tls")]
+fn enable_tls(proxy_auth: ProxyAuth, tls_config: &TlsConfig, name: &str, management: Option<&Management>) -> io::Result<ProxyAuth> {
+    let proxy_auth = proxy_auth.with_tls(tls_config)?;
+    if let Some(certificates) = proxy_auth.tls_certificates() {
+        if tls_config.reload_secs > 0 {
+            certificates.watch(Duration::from_secs(tls_config.reload_secs))?;
+        }
+        if let Some(management) = management {
+            management.add_certificates(name, certificates.clone());
+        }
+    }
+    Ok(proxy_auth)
+}
+
+#[cfg(not(feature = "tls"))]
+fn enable_tls(_: ProxyAuth, _: &TlsConfig, _: &str, _: Option<&Management>) -> io::Result<ProxyAuth> {
+    Err(io::Error::other("TLS support requires the 'tls' feature"))
+}
+
+/// The innermost handler, which forwards everything the others let through
+struct PassthroughHandler;
+
+impl PacketHandler for PassthroughHandler {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+}
@@ -0,0 +1,389 @@
+//! Recording the state a session builds up, to rebuild it on a fresh backend connection.
+//!
+//! A `SessionReplay` follows the commands a session sends and the responses they get, and
+//! remembers those that succeeded in changing the session: its default schema, from
+//! `COM_INIT_DB` and `USE`, its `SET` statements, such as `SET NAMES`, `sql_mode` or user
+//! variables, and its prepared statements. `replay` returns the commands that restore that
+//! state on another connection logged in the same way: the `SET` statements in the order
+//! they last ran, each prepared statement in the schema it was prepared in, then the current
+//! schema.
+//!
+//! Prepared statements get new ids on the new connection. The replay translates the ids in
+//! the session's statement commands to those the backend knows, and gives statements the
+//! session prepares afterwards ids of their own, so the client keeps the ids it was given.
+//!
+//! Some state can't be rebuilt: temporary tables, table and named locks, XA transactions,
+//! `PREPARE` in SQL, user variables assigned outside `SET`, open cursors, parameters sent
+//! as long data, a `COM_CHANGE_USER`, or more `SET` statements than the replay keeps.
+//! `replayable` is false while a session has any, or a transaction open.
+//! `COM_RESET_CONNECTION` clears it all, apart from a changed user.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::mem;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Packet, PacketType};
+use super::codec::{EofPacket, ErrPacket, OkPacket};
+use super::pipeline::{is_answered, opens_cursor, ResponseKind, ResponsePacket};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sql::{self, Token};
+use super::state::SERVER_STATUS_IN_TRANS;
+
+/// `SET` statements kept by default
+pub const DEFAULT_MAX_STATEMENTS: usize = 64;
+
+/// What a command does to the session's state, once it succeeds
+#[derive(Debug,PartialEq)]
+enum Change {
+    None,
+    Schema(String),
+    Set(String),
+    /// a statement prepared in the schema of the time
+    Prepare(String, Option<String>),
+    Lock,
+    Unlock,
+    Reset,
+    /// state that can't be replayed
+    Lost,
+    /// a command of a replay
+    Replay,
+    /// a prepared statement prepared again by a replay, by the id the client knows it by
+    Reprepare(u32),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+struct Prepared {
+    sql: String,
+    schema: Option<String>,
+    /// the id the backend knows the statement by
+    backend_id: u32,
+}
+
+/// Follows the state of a session, and the commands that would rebuild it
+#[derive(Debug)]
+pub struct SessionReplay {
+    max_statements: usize,
+    /// the schema the session logged in with
+    initial_schema: Option<String>,
+    schema: Option<String>,
+    statements: Vec<String>,
+    /// the session's prepared statements, by the ids the client knows them by
+    prepared: BTreeMap<u32, Prepared>,
+    /// since a replay, statement ids are given out by the replay rather than the backend
+    remapped: bool,
+    next_id: u32,
+    /// statements with an open cursor or parameters sent as long data
+    busy: HashSet<u32>,
+    locked: bool,
+    lost: bool,
+    changed_user: bool,
+    in_transaction: bool,
+    /// the changes of the commands awaiting responses, oldest first
+    pending: VecDeque<Change>,
+    /// whether the next response packet starts the response to a command
+    first: bool,
+}
+
+impl Default for SessionReplay {
+    fn default() -> Self {
+        SessionReplay::new(DEFAULT_MAX_STATEMENTS)
+    }
+}
+
+impl SessionReplay {
+
+    /// Keep up to `max_statements` `SET` statements before the session can't be replayed
+    pub fn new(max_statements: usize) -> Self {
+        SessionReplay {
+            max_statements,
+            initial_schema: None,
+            schema: None,
+            statements: vec![],
+            prepared: BTreeMap::new(),
+            remapped: false,
+            next_id: 1,
+            busy: HashSet::new(),
+            locked: false,
+            lost: false,
+            changed_user: false,
+            in_transaction: false,
+            pending: VecDeque::new(),
+            first: true,
+        }
+    }
+
+    /// Start from the schema the session logged in to the backend with, which a fresh
+    /// connection logged in the same way starts from too
+    pub fn with_schema(mut self, schema: Option<String>) -> Self {
+        self.initial_schema = schema.clone();
+        self.schema = schema;
+        self
+    }
+
+    /// Whether the session's state could be rebuilt on another connection now
+    pub fn replayable(&self) -> bool {
+        !self.in_transaction && !self.lost && !self.changed_user && !self.locked && self.busy.is_empty()
+    }
+
+    /// Whether a replay's commands are still to be answered
+    pub fn replaying(&self) -> bool {
+        self.pending.iter().any(|c| matches!(*c, Change::Replay | Change::Reprepare(_)))
+    }
+
+    /// The session's current default schema, if it has one
+    pub fn schema(&self) -> Option<&str> {
+        self.schema.as_ref().map(|s| &s[..])
+    }
+
+    /// The `SET` statements a replay runs, in order
+    pub fn statements(&self) -> &[String] {
+        &self.statements
+    }
+
+    /// Observe a command sent to the server, returning it with the statement id the backend
+    /// knows, if that differs from the client's
+    pub fn request(&mut self, p: Packet) -> Packet {
+        if p.sequence_id() != 0 || p.payload().is_empty() {
+            return p;
+        }
+        let change = match p.packet_type() {
+            Ok(PacketType::ComStmtExecute)
+            | Ok(PacketType::ComStmtClose)
+            | Ok(PacketType::ComStmtReset)
+            | Ok(PacketType::ComStmtSendLongData)
+            | Ok(PacketType::ComStmtFetch) if p.payload().len() >= 5 => return self.statement_command(p),
+            _ if !is_answered(&p) => return p,
+            Ok(PacketType::ComInitDb) => Change::Schema(String::from_utf8_lossy(&p.payload()[1..]).into_owned()),
+            Ok(PacketType::ComQuery) => classify_query(&String::from_utf8_lossy(&p.payload()[1..])),
+            Ok(PacketType::ComStmtPrepare) => {
+                Change::Prepare(String::from_utf8_lossy(&p.payload()[1..]).into_owned(), self.schema.clone())
+            },
+            Ok(PacketType::ComResetConnection) => Change::Reset,
+            Ok(PacketType::ComChangeUser) => {
+                self.changed_user = true;
+                Change::None
+            },
+            _ => Change::None,
+        };
+        self.pending.push_back(change);
+        p
+    }
+
+    /// Observe a command sent to the server again as it was sent before, with the ids the
+    /// backend knows, such as a statement retried after a deadlock
+    pub fn resend(&mut self, p: &Packet) {
+        if !is_answered(p) {
+            return;
+        }
+        let change = match p.packet_type() {
+            Ok(PacketType::ComQuery) => classify_query(&String::from_utf8_lossy(&p.payload()[1..])),
+            _ => Change::None,
+        };
+        self.pending.push_back(change);
+    }
+
+    /// Follow a command on a prepared statement, and translate its id
+    fn statement_command(&mut self, p: Packet) -> Packet {
+        let id = LittleEndian::read_u32(&p.payload()[1..5]);
+        match p.packet_type() {
+            // a cursor is opened unless the cursor type in the flags is none
+            Ok(PacketType::ComStmtExecute) if opens_cursor(&p) => {
+                self.busy.insert(id);
+            },
+            Ok(PacketType::ComStmtExecute) | Ok(PacketType::ComStmtReset) => {
+                self.busy.remove(&id);
+            },
+            Ok(PacketType::ComStmtSendLongData) => {
+                self.busy.insert(id);
+            },
+            _ => {},
+        }
+        let backend_id = self.prepared.get(&id).map(|s| s.backend_id).unwrap_or(id);
+        if p.packet_type().ok() == Some(PacketType::ComStmtClose) {
+            self.prepared.remove(&id);
+            self.busy.remove(&id);
+        }
+        if is_answered(&p) {
+            self.pending.push_back(Change::None);
+        }
+        if backend_id == id {
+            return p;
+        }
+        let mut payload = p.payload().to_vec();
+        LittleEndian::write_u32(&mut payload[1..5], backend_id);
+        Packet::new(p.sequence_id(), &payload)
+    }
+
+    /// Observe a response packet. Returns the packet to pass on to the client, with the
+    /// statement id the client knows, or `None` if it answers a replayed command. Fails
+    /// with the error a replayed command got.
+    pub fn response(&mut self, p: Packet, answered: Option<ResponsePacket>) -> Result<Option<Packet>, String> {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return Ok(Some(p)),
+        };
+        let first = self.first;
+        self.first = answered.last;
+        let replayed = matches!(self.pending.front(), Some(&Change::Replay) | Some(&Change::Reprepare(_)));
+        let mut p = p;
+        // PREPARE_OK starts with a 0 status and the statement id
+        if first && answered.kind != ResponseKind::Err && p.payload().len() >= 5 && p.payload()[0] == 0x00 {
+            let backend_id = LittleEndian::read_u32(&p.payload()[1..5]);
+            match self.pending.front_mut() {
+                Some(change @ &mut Change::Prepare(..)) => {
+                    if let Change::Prepare(sql, schema) = mem::replace(change, Change::None) {
+                        p = self.prepared(p, backend_id, sql, schema);
+                    }
+                },
+                Some(&mut Change::Reprepare(id)) => {
+                    if let Some(statement) = self.prepared.get_mut(&id) {
+                        statement.backend_id = backend_id;
+                    }
+                },
+                _ => {},
+            }
+        }
+        let mut failed = None;
+        if replayed && first && answered.kind == ResponseKind::Err {
+            failed = Some(ErrPacket::parse(&p).map(|e| e.message).unwrap_or_default());
+        }
+        if answered.last {
+            let change = self.pending.pop_front().unwrap_or(Change::None);
+            if answered.kind != ResponseKind::Err {
+                self.apply(change);
+            }
+            let status_flags = match answered.kind {
+                ResponseKind::Ok => OkPacket::parse(&p, CLIENT_PROTOCOL_41).ok().map(|ok| ok.status_flags),
+                ResponseKind::Eof => EofPacket::parse(&p).ok().map(|eof| eof.status_flags),
+                _ => None,
+            };
+            if let Some(status_flags) = status_flags {
+                self.in_transaction = status_flags & SERVER_STATUS_IN_TRANS != 0;
+            }
+        }
+        match failed {
+            Some(msg) => Err(msg),
+            None if replayed => Ok(None),
+            None => Ok(Some(p)),
+        }
+    }
+
+    /// Remember a statement the backend prepared, returning its response with the id the
+    /// client will know it by
+    fn prepared(&mut self, p: Packet, backend_id: u32, sql: String, schema: Option<String>) -> Packet {
+        let id = if self.remapped { self.next_id } else { backend_id };
+        self.next_id = self.next_id.max(id.wrapping_add(1));
+        self.prepared.insert(id, Prepared { sql, schema, backend_id });
+        if id == backend_id {
+            return p;
+        }
+        let mut payload = p.payload().to_vec();
+        LittleEndian::write_u32(&mut payload[1..5], id);
+        Packet::new(p.sequence_id(), &payload)
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::None | Change::Prepare(..) | Change::Replay | Change::Reprepare(_) => {},
+            Change::Schema(schema) => self.schema = Some(schema),
+            Change::Set(statement) => {
+                // the latest run of a statement is the one that counts
+                self.statements.retain(|s| *s != statement);
+                self.statements.push(statement);
+                if self.statements.len() > self.max_statements {
+                    self.lost = true;
+                }
+            },
+            Change::Lock => self.locked = true,
+            Change::Unlock => self.locked = false,
+            Change::Reset => {
+                self.statements.clear();
+                self.prepared.clear();
+                self.busy.clear();
+                self.locked = false;
+                self.lost = false;
+            },
+            Change::Lost => self.lost = true,
+        }
+    }
+
+    /// The commands that rebuild the session's state on a fresh connection, which must be
+    /// sent before any other and their responses passed to `response`
+    pub fn replay(&mut self) -> Vec<Packet> {
+        self.pending.clear();
+        self.first = true;
+        self.in_transaction = false;
+        self.remapped = true;
+        let mut commands = vec![];
+        for statement in &self.statements {
+            commands.push((Packet::com_query(statement), Change::Replay));
+        }
+        let mut schema = self.initial_schema.clone();
+        for (&id, statement) in &self.prepared {
+            if let Some(ref prepared_in) = statement.schema {
+                if schema.as_ref() != Some(prepared_in) {
+                    commands.push((Packet::com_init_db(prepared_in), Change::Replay));
+                    schema = Some(prepared_in.clone());
+                }
+            }
+            commands.push((Packet::command(PacketType::ComStmtPrepare, statement.sql.as_bytes()), Change::Reprepare(id)));
+        }
+        if let Some(ref current) = self.schema {
+            if schema.as_ref() != Some(current) {
+                commands.push((Packet::com_init_db(current), Change::Replay));
+            }
+        }
+        commands.into_iter().map(|(command, change)| {
+            self.pending.push_back(change);
+            command
+        }).collect()
+    }
+}
+
+/// What a query does to the session's state
+fn classify_query(sql: &str) -> Change {
+    let tokens = sql::tokenize(sql);
+    let statements: Vec<&[Token]> = tokens.split(|t| t.is_punct(b';')).filter(|s| !s.is_empty()).collect();
+    match statements.len() {
+        0 => Change::None,
+        1 => classify(sql, statements[0]),
+        // the statements of a multi-statement query can fail part way through
+        _ => match statements.iter().all(|s| classify(sql, s) == Change::None) {
+            true => Change::None,
+            false => Change::Lost,
+        },
+    }
+}
+
+fn classify(sql: &str, tokens: &[Token]) -> Change {
+    let keyword = |i: usize, k: &str| tokens.get(i).map(|t| t.is_keyword(k)).unwrap_or(false);
+    let assigns_variable = tokens.windows(2).any(|w| w[0].is_punct(b':') && w[1].is_punct(b'='))
+        || tokens.windows(2).any(|w| w[0].is_keyword("INTO") && w[1].is_punct(b'@'));
+    if keyword(0, "USE") {
+        return match tokens.get(1).and_then(|t| t.name.clone()) {
+            Some(schema) => Change::Schema(schema),
+            None => Change::None,
+        };
+    }
+    if keyword(0, "SET") {
+        let global = tokens.iter().any(|t| t.is_keyword("GLOBAL") || t.is_keyword("PERSIST") || t.is_keyword("PERSIST_ONLY"));
+        // without SESSION, the characteristics only apply to the next transaction
+        if global || keyword(1, "TRANSACTION") || keyword(1, "PASSWORD") {
+            return Change::None;
+        }
+        // only a query of a single statement gets here, so it's the statement to replay
+        return Change::Set(sql.trim().trim_end_matches(';').trim_end().to_string());
+    }
+    if keyword(0, "LOCK") {
+        return Change::Lock;
+    }
+    if keyword(0, "UNLOCK") {
+        return Change::Unlock;
+    }
+    if (keyword(0, "CREATE") && keyword(1, "TEMPORARY")) || keyword(0, "PREPARE") || keyword(0, "XA")
+        || assigns_variable || tokens.iter().any(|t| t.is_keyword("GET_LOCK")) {
+        return Change::Lost;
+    }
+    Change::None
+}
@@ -0,0 +1,369 @@
+//! Out-of-band queries on backends, for handlers.
+//!
+//! A `SideChannel` runs queries on backends over connections of its own, logged in as a
+//! separate account, so a handler can look things up, such as a routing table or `SHOW
+//! STATUS`, without disturbing the session it relays. Each query runs on a thread of its own
+//! and its result comes back as a future, so the reactor isn't stalled; a handler that must
+//! have the answer before it decides what to do with a packet can wait with
+//! `query_blocking` instead, which stalls every session on its thread like `explain` does.
+//!
+//! Connections are kept after a query, up to `max_idle` per backend, and used again by the
+//! next, unless one has been open for `max_lifetime_secs` or kept unused for `max_idle_secs`,
+//! in which case it's closed with `COM_QUIT` and the query logs in afresh. At most `max_concurrent` queries run at a time across backends, and further ones fail
+//! at once rather than queueing. Results of more than `max_rows` rows fail too. Queries run
+//! without a default schema, so tables must be qualified with theirs.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+
+use super::Packet;
+use super::auth::Session;
+use super::codec::{parse_packet_length, AuthSwitchRequest, ColumnDefinition, HandshakeResponse, HandshakeV10,
+                   QueryResponse, QueryResponseDecoder, TextRow, MAX_PAYLOAD_LEN};
+use super::protocol::{native_password_auth, CLIENT_PLUGIN_AUTH, CLIENT_PROTOCOL_41,
+                      CLIENT_SECURE_CONNECTION, NATIVE_PASSWORD_PLUGIN};
+use super::retry::{retry_blocking, ConnectRetryPolicy};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct SideChannelConfig {
+    /// the backend account side queries run as
+    pub backend_user: String,
+    pub backend_password: String,
+    /// how long connecting to a backend, and each read and write, may take
+    #[serde(default = "SideChannelConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// queries running at a time, across backends
+    #[serde(default = "SideChannelConfig::default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// connections kept per backend between queries
+    #[serde(default = "SideChannelConfig::default_max_idle")]
+    pub max_idle: usize,
+    /// the most rows a result may have
+    #[serde(default = "SideChannelConfig::default_max_rows")]
+    pub max_rows: usize,
+    /// how long a connection is used before it's closed
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    /// how long a connection is kept unused before it's closed
+    #[serde(default)]
+    pub max_idle_secs: Option<u64>,
+}
+
+impl SideChannelConfig {
+
+    fn default_timeout_ms() -> u64 {
+        1000
+    }
+
+    fn default_max_concurrent() -> usize {
+        8
+    }
+
+    fn default_max_idle() -> usize {
+        2
+    }
+
+    fn default_max_rows() -> usize {
+        10000
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.timeout_ms == 0 || self.max_concurrent == 0 {
+            return Err("timeout_ms and max_concurrent must be at least 1".to_string());
+        }
+        if self.max_lifetime_secs == Some(0) || self.max_idle_secs == Some(0) {
+            return Err("max_lifetime_secs and max_idle_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether a connection opened at `opened` and unused since `used` must be closed
+    fn expired(&self, opened: Instant, used: Instant) -> bool {
+        let over = |limit: Option<u64>, since: Instant| limit.map(|secs| since.elapsed() >= Duration::from_secs(secs)).unwrap_or(false);
+        over(self.max_lifetime_secs, opened) || over(self.max_idle_secs, used)
+    }
+}
+
+/// A connection kept between queries
+#[derive(Debug)]
+struct Kept {
+    stream: TcpStream,
+    opened: Instant,
+    used: Instant,
+}
+
+/// The rows a side query returned, empty for statements without a result set
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<ColumnDefinition>,
+    pub rows: Vec<TextRow>,
+}
+
+impl ResultSet {
+
+    /// The value of the named column in row `row`, ignoring the case of the name, or `None`
+    /// if either doesn't exist or the value is `NULL`
+    pub fn value(&self, row: usize, column: &str) -> Option<String> {
+        let i = self.columns.iter().position(|c| c.name.eq_ignore_ascii_case(column))?;
+        let value = self.rows.get(row)?.get(i)?.as_ref()?;
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+}
+
+/// Runs queries on backends over connections of its own. Clones share the connections and
+/// the limit on running queries.
+#[derive(Clone,Debug)]
+pub struct SideChannel {
+    config: Arc<SideChannelConfig>,
+    idle: Arc<Mutex<HashMap<SocketAddr, Vec<Kept>>>>,
+    running: Arc<AtomicUsize>,
+    connect_retry: Option<ConnectRetryPolicy>,
+}
+
+impl SideChannel {
+
+    pub fn new(config: &SideChannelConfig) -> Self {
+        SideChannel {
+            config: Arc::new(config.clone()),
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(AtomicUsize::new(0)),
+            connect_retry: None,
+        }
+    }
+
+    /// Try logging in to a backend again after the connection failures `policy` retries
+    pub fn with_connect_retry(mut self, policy: Option<ConnectRetryPolicy>) -> Self {
+        self.connect_retry = policy;
+        self
+    }
+
+    /// Run `sql` on `backend` on a thread of its own
+    pub fn query(&self, backend: SocketAddr, sql: &str) -> Box<dyn Future<Item = ResultSet, Error = Error> + Send> {
+        if let Err(e) = self.start() {
+            return Box::new(future::err(e));
+        }
+        let (tx, rx) = oneshot::channel();
+        let channel = self.clone();
+        let sql = sql.to_string();
+        thread::spawn(move || {
+            let result = channel.run(backend, &sql);
+            channel.running.fetch_sub(1, Ordering::SeqCst);
+            let _ = tx.send(result);
+        });
+        Box::new(rx
+            .map_err(|_| Error::other("Side query thread failed"))
+            .and_then(|r| r))
+    }
+
+    /// Run `sql` on `backend` on the calling thread
+    pub fn query_blocking(&self, backend: SocketAddr, sql: &str) -> Result<ResultSet> {
+        self.start()?;
+        let result = self.run(backend, sql);
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// The side channel to a session's backend
+    pub fn for_session(&self, session: &Session) -> SessionSideChannel {
+        SessionSideChannel { channel: self.clone(), backend: session.backend }
+    }
+
+    /// How many connections are kept between queries, across backends
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().values().map(|streams| streams.len()).sum()
+    }
+
+    /// Count a query as running, unless too many already are
+    fn start(&self) -> Result<()> {
+        let max = self.config.max_concurrent;
+        self.running.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < max { Some(n + 1) } else { None })
+            .map(|_| ())
+            .map_err(|_| Error::new(ErrorKind::WouldBlock, format!("{} side queries are already running", max)))
+    }
+
+    fn run(&self, backend: SocketAddr, sql: &str) -> Result<ResultSet> {
+        let command = Packet::com_query(sql);
+        if command.payload().len() >= MAX_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "Side query too long"));
+        }
+        let (stream, opened, result) = match self.take(backend) {
+            Some(Kept { mut stream, opened, .. }) => match self.run_on(&mut stream, &command) {
+                // the backend closed the connection while it was kept, so the query never ran
+                Err(ref e) if is_closed(e) => {
+                    let mut stream = self.login(backend)?;
+                    let result = self.run_on(&mut stream, &command);
+                    (stream, Instant::now(), result)
+                },
+                result => (stream, opened, result),
+            },
+            None => {
+                let mut stream = self.login(backend)?;
+                let result = self.run_on(&mut stream, &command);
+                (stream, Instant::now(), result)
+            },
+        };
+        match result {
+            Ok((result_set, true)) => {
+                self.keep(backend, stream, opened);
+                Ok(result_set)
+            },
+            Ok((_, false)) => {
+                Err(Error::new(ErrorKind::InvalidData, format!("Side query returned more than {} rows", self.config.max_rows)))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send a query and read its response, returning whether it was read to the end
+    fn run_on(&self, stream: &mut TcpStream, command: &Packet) -> Result<(ResultSet, bool)> {
+        stream.write_all(&command.bytes)?;
+        let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+        let mut result_set = ResultSet::default();
+        let mut first = true;
+        loop {
+            let p = read_packet(stream)?;
+            match decoder.decode(&p)? {
+                QueryResponse::Column(column) if first => result_set.columns.push(column),
+                QueryResponse::Row(row) if first => {
+                    if result_set.rows.len() >= self.config.max_rows {
+                        return Ok((result_set, false));
+                    }
+                    result_set.rows.push(row);
+                },
+                QueryResponse::Err(e) => {
+                    let state = e.state.map(|s| format!(" ({})", String::from_utf8_lossy(&s))).unwrap_or_default();
+                    return Err(Error::other(format!("ERROR {}{}: {}", e.code, state, e.message)));
+                },
+                QueryResponse::LocalInfile(_) => {
+                    return Err(Error::new(ErrorKind::InvalidData, "Side queries can't load local files"));
+                },
+                QueryResponse::End { .. } => first = false,
+                _ => {},
+            }
+            if decoder.is_done() {
+                return Ok((result_set, true));
+            }
+        }
+    }
+
+    fn login(&self, backend: SocketAddr) -> Result<TcpStream> {
+        let config = &self.config;
+        retry_blocking(self.connect_retry.as_ref(), &backend.to_string(), || {
+            login(backend, &config.backend_user, &config.backend_password, Duration::from_millis(config.timeout_ms))
+        })
+    }
+
+    /// The most recently used connection kept for `backend`, closing any that have expired
+    fn take(&self, backend: SocketAddr) -> Option<Kept> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.get_mut(&backend)?;
+        while let Some(kept) = streams.pop() {
+            if !self.config.expired(kept.opened, kept.used) {
+                return Some(kept);
+            }
+            quit(kept.stream);
+        }
+        None
+    }
+
+    fn keep(&self, backend: SocketAddr, stream: TcpStream, opened: Instant) {
+        let now = Instant::now();
+        if self.config.expired(opened, now) {
+            return quit(stream);
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(backend).or_default();
+        if streams.len() < self.config.max_idle {
+            streams.push(Kept { stream, opened, used: now });
+        }
+    }
+}
+
+/// A `SideChannel` to the backend of a session
+#[derive(Clone)]
+pub struct SessionSideChannel {
+    channel: SideChannel,
+    backend: SocketAddr,
+}
+
+impl SessionSideChannel {
+
+    /// Run `sql` on the session's backend on a thread of its own
+    pub fn side_query(&self, sql: &str) -> Box<dyn Future<Item = ResultSet, Error = Error> + Send> {
+        self.channel.query(self.backend, sql)
+    }
+
+    /// Run `sql` on the session's backend on the calling thread
+    pub fn side_query_blocking(&self, sql: &str) -> Result<ResultSet> {
+        self.channel.query_blocking(self.backend, sql)
+    }
+}
+
+fn is_closed(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe)
+}
+
+/// Say goodbye to the backend before closing a connection, so it isn't counted as aborted
+fn quit(mut stream: TcpStream) {
+    let _ = stream.write_all(&Packet::com_quit().bytes);
+}
+
+/// Connect to `backend` and log in with mysql_native_password, blocking for up to `timeout`
+/// at each step
+pub fn login(backend: SocketAddr, user: &str, password: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&backend, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    let greeting = HandshakeV10::parse(&read_packet(&mut stream)?)?;
+    let response = HandshakeResponse {
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        max_packet_size: MAX_PAYLOAD_LEN as u32,
+        character_set: greeting.character_set,
+        username: user.to_string(),
+        auth_response: native_password_auth(password, &greeting.auth_plugin_data),
+        database: None,
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+        connect_attrs: None,
+    };
+    stream.write_all(&response.to_packet(1).bytes)?;
+    loop {
+        let p = read_packet(&mut stream)?;
+        match p.payload().first() {
+            Some(&0x00) => return Ok(stream),
+            Some(&0xfe) => {
+                let switch = AuthSwitchRequest::parse(&p)?;
+                if switch.plugin_name != NATIVE_PASSWORD_PLUGIN {
+                    let msg = format!("Unsupported backend auth plugin '{}'", switch.plugin_name);
+                    return Err(Error::new(ErrorKind::PermissionDenied, msg));
+                }
+                let auth = native_password_auth(password, &switch.plugin_data);
+                stream.write_all(&Packet::new(p.sequence_id().wrapping_add(1), &auth).bytes)?;
+            },
+            Some(&0xff) => {
+                let msg = String::from_utf8_lossy(&p.payload()[p.payload().len().min(9)..]).into_owned();
+                return Err(Error::new(ErrorKind::PermissionDenied, msg));
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Unexpected packet during backend login")),
+        }
+    }
+}
+
+/// Read a single packet from a blocking stream
+pub fn read_packet<R: Read>(stream: &mut R) -> Result<Packet> {
+    let mut bytes = vec![0_u8; 4];
+    stream.read_exact(&mut bytes)?;
+    let len = parse_packet_length(&bytes);
+    bytes.resize(4 + len, 0);
+    stream.read_exact(&mut bytes[4..])?;
+    Ok(Packet { bytes })
+}
@@ -0,0 +1,362 @@
+//! Proxy events and audit records published to NATS, for data pipelines.
+//!
+//! An `[event_sink]` section publishes the events named in `events`, by default all of
+//! them, as the JSON objects the event log writes, to the subject `<subject>.<event>`, e.g.
+//! `mysql_proxy.events.auth_failed`. With an `audit_subject`, the records of every audit log
+//! are also published there, exactly as they're written, hash chain included, so consumers
+//! can verify them.
+//!
+//! Messages queue for a thread that publishes up to `batch_size` of them at a time and waits
+//! for the server to acknowledge each batch. When the server can't be reached or the batch
+//! fails, it reconnects and retries the batch, backing off from `retry_ms` to
+//! `max_retry_ms`, unless the server refused it in a way retrying can't change, e.g. for a
+//! subject the proxy may not publish to: such a batch is dropped with a warning, as are
+//! messages larger than the server's `max_payload`. The queue holds at most `max_queued` messages: beyond it, messages are
+//! dropped and counted rather than holding up sessions, so an unreachable server never slows
+//! the proxy. Publishing needs the `nats` feature; Kafka isn't supported, as it would need a
+//! client library the proxy doesn't have.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use super::eventlog::EVENT_NAMES;
+use super::events::{Event, EventBus, SubscriptionId};
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct EventSinkConfig {
+    /// the NATS server, e.g. `nats.internal:4222`
+    pub nats: String,
+    /// the prefix of the subjects events are published to
+    #[serde(default = "EventSinkConfig::default_subject")]
+    pub subject: String,
+    /// the events published, all of them if left out
+    #[serde(default = "EventSinkConfig::default_events")]
+    pub events: Vec<String>,
+    /// the subject audit records are published to, if any
+    #[serde(default)]
+    pub audit_subject: Option<String>,
+    /// the most messages published at once
+    #[serde(default = "EventSinkConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// the most messages waiting to be published, beyond which messages are dropped
+    #[serde(default = "EventSinkConfig::default_max_queued")]
+    pub max_queued: usize,
+    /// the first wait before retrying a failed batch, doubled on each failure
+    #[serde(default = "EventSinkConfig::default_retry_ms")]
+    pub retry_ms: u64,
+    #[serde(default = "EventSinkConfig::default_max_retry_ms")]
+    pub max_retry_ms: u64,
+}
+
+impl EventSinkConfig {
+
+    fn default_subject() -> String {
+        "mysql_proxy.events".to_string()
+    }
+
+    fn default_events() -> Vec<String> {
+        EVENT_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn default_batch_size() -> usize {
+        100
+    }
+
+    fn default_max_queued() -> usize {
+        10_000
+    }
+
+    fn default_retry_ms() -> u64 {
+        100
+    }
+
+    fn default_max_retry_ms() -> u64 {
+        10_000
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.nats.is_empty() {
+            return Err("empty NATS server address".to_string());
+        }
+        for subject in Some(&self.subject).into_iter().chain(self.audit_subject.as_ref()) {
+            if !valid_subject(subject) {
+                return Err(format!("invalid subject '{}'", subject));
+            }
+        }
+        for name in &self.events {
+            if !EVENT_NAMES.contains(&&name[..]) {
+                return Err(format!("unknown event '{}', expected one of {}", name, EVENT_NAMES.join(", ")));
+            }
+        }
+        if self.batch_size == 0 || self.max_queued == 0 {
+            return Err("batch_size and max_queued must be at least 1".to_string());
+        }
+        if self.retry_ms == 0 || self.retry_ms > self.max_retry_ms {
+            return Err("retry_ms must be at least 1 and at most max_retry_ms".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Whether NATS accepts `subject` to publish to: dot-separated tokens without wildcards or
+/// whitespace
+fn valid_subject(subject: &str) -> bool {
+    subject.split('.').all(|token| !token.is_empty() && !token.contains(|c: char| c == '*' || c == '>' || c.is_whitespace()))
+}
+
+/// A message for a subject
+#[derive(Clone,Debug,PartialEq)]
+pub struct Message {
+    pub subject: String,
+    pub payload: Vec<u8>,
+}
+
+/// Delivers batches of messages, reconnecting as it needs to
+pub trait Publisher: Send {
+
+    /// Publish every message of `batch`, or fail so that the batch is retried. Failing with
+    /// `ErrorKind::InvalidInput` drops the batch instead, for failures retrying can't help.
+    fn publish(&mut self, batch: &[Message]) -> io::Result<()>;
+}
+
+/// The queue to the publishing thread, shared by everything publishing to the sink
+#[derive(Clone)]
+pub struct EventSink {
+    sender: SyncSender<Message>,
+    subject: Arc<String>,
+    events: Arc<Vec<String>>,
+    audit_subject: Option<Arc<String>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSink {
+
+    /// Publish to the NATS server of `config`
+    #[cfg(feature = "nats")]
+    pub fn open(config: &EventSinkConfig) -> io::Result<Self> {
+        EventSink::new(config, Box::new(NatsPublisher::new(&config.nats)))
+    }
+
+    /// Publish to the NATS server of `config`
+    #[cfg(not(feature = "nats"))]
+    pub fn open(_config: &EventSinkConfig) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Publishing to NATS requires the 'nats' feature"))
+    }
+
+    /// Publish with an arbitrary publisher on a thread of its own
+    pub fn new(config: &EventSinkConfig, mut publisher: Box<dyn Publisher>) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(config.max_queued);
+        let batch_size = config.batch_size;
+        let retry = (Duration::from_millis(config.retry_ms), Duration::from_millis(config.max_retry_ms));
+        thread::Builder::new().name("mysql-proxy-sink".to_string()).spawn(move || {
+            publish_batches(receiver, &mut *publisher, batch_size, retry);
+        })?;
+        Ok(EventSink {
+            sender,
+            subject: Arc::new(config.subject.clone()),
+            events: Arc::new(config.events.clone()),
+            audit_subject: config.audit_subject.clone().map(Arc::new),
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Publish the events published on `bus` from now on
+    pub fn subscribe(&self, bus: &EventBus) -> SubscriptionId {
+        let sink = self.clone();
+        bus.subscribe(move |event| sink.send_event(event))
+    }
+
+    /// Queue an event, if it's one of those published
+    pub fn send_event(&self, event: &Event) {
+        let mut record = match serde_json::to_value(event) {
+            Ok(record) => record,
+            Err(_) => return,
+        };
+        let name = match record.get("event").and_then(Value::as_str) {
+            Some(name) if self.events.iter().any(|e| e == name) => name.to_string(),
+            _ => return,
+        };
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        if let Some(fields) = record.as_object_mut() {
+            fields.insert("timestamp_ms".to_string(), timestamp_ms.into());
+        }
+        self.send(Message { subject: format!("{}.{}", self.subject, name), payload: record.to_string().into_bytes() });
+    }
+
+    /// Queue an audit record, if audit records are published
+    pub fn send_audit(&self, line: &str) {
+        if let Some(ref subject) = self.audit_subject {
+            self.send(Message { subject: subject.to_string(), payload: line.as_bytes().to_vec() });
+        }
+    }
+
+    /// The messages dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, message: Message) {
+        match self.sender.try_send(message) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Event sink queue full, dropping messages");
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+fn publish_batches(receiver: Receiver<Message>, publisher: &mut dyn Publisher, batch_size: usize, retry: (Duration, Duration)) {
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Ok(message) = receiver.recv() {
+        batch.push(message);
+        // take what has queued up, up to a batch
+        while batch.len() < batch_size {
+            match receiver.try_recv() {
+                Ok(message) => batch.push(message),
+                Err(_) => break,
+            }
+        }
+        let mut wait = retry.0;
+        while let Err(e) = publisher.publish(&batch) {
+            if e.kind() == io::ErrorKind::InvalidInput {
+                warn!("Could not publish {} messages, dropping them: {}", batch.len(), e);
+                break;
+            }
+            warn!("Could not publish {} messages, retrying in {}ms: {}", batch.len(), wait.as_millis(), e);
+            thread::sleep(wait);
+            wait = (wait * 2).min(retry.1);
+        }
+        batch.clear();
+    }
+}
+
+/// Publishes to a NATS server with its text protocol, waiting for a `PONG` after each batch
+/// to know the server has it
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    address: String,
+    connection: Option<nats::Connection>,
+}
+
+#[cfg(feature = "nats")]
+impl NatsPublisher {
+
+    pub fn new(address: &str) -> Self {
+        NatsPublisher { address: address.to_string(), connection: None }
+    }
+}
+
+#[cfg(feature = "nats")]
+impl Publisher for NatsPublisher {
+
+    fn publish(&mut self, batch: &[Message]) -> io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(nats::Connection::open(&self.address)?);
+        }
+        let result = self.connection.as_mut().unwrap().publish(batch);
+        if result.is_err() {
+            // reconnect for the retry
+            self.connection = None;
+        }
+        result
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+
+    use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::Message;
+    use super::super::authenticator::connect;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The largest payload servers accept unless they say otherwise
+    const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+    /// Server errors that a batch would get again if retried
+    const PERMANENT_ERRORS: &[&str] = &["permissions violation", "invalid subject", "maximum payload violation"];
+
+    pub struct Connection {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        /// the largest payload the server accepts
+        max_payload: usize,
+    }
+
+    impl Connection {
+
+        pub fn open(address: &str) -> io::Result<Self> {
+            let stream = connect(address, TIMEOUT)?;
+            let mut connection = Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: stream,
+                max_payload: DEFAULT_MAX_PAYLOAD,
+            };
+            let info = connection.read_line()?;
+            let info: Value = match info.strip_prefix("INFO ").map(serde_json::from_str) {
+                Some(Ok(info)) => info,
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("expected INFO from the NATS server, got '{}'", info))),
+            };
+            if let Some(max_payload) = info.get("max_payload").and_then(Value::as_u64) {
+                connection.max_payload = max_payload as usize;
+            }
+            connection.writer.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"mysql-proxy\",\"lang\":\"rust\"}\r\n")?;
+            Ok(connection)
+        }
+
+        pub fn publish(&mut self, batch: &[Message]) -> io::Result<()> {
+            let mut buf = vec![];
+            for message in batch {
+                // the server would close the connection, and refuse it again after reconnecting
+                if message.payload.len() > self.max_payload {
+                    warn!("Dropping a {} byte message to {}, the NATS server accepts at most {} bytes",
+                          message.payload.len(), message.subject, self.max_payload);
+                    continue;
+                }
+                write!(buf, "PUB {} {}\r\n", message.subject, message.payload.len())?;
+                buf.extend_from_slice(&message.payload);
+                buf.extend_from_slice(b"\r\n");
+            }
+            buf.extend_from_slice(b"PING\r\n");
+            self.writer.write_all(&buf)?;
+            loop {
+                let line = self.read_line()?;
+                match &line[..] {
+                    "PONG" => return Ok(()),
+                    "PING" => self.writer.write_all(b"PONG\r\n")?,
+                    _ if line.starts_with("-ERR") => {
+                        let message = line[4..].trim().trim_matches('\'');
+                        let permanent = PERMANENT_ERRORS.iter().any(|e| message.to_lowercase().starts_with(e));
+                        let kind = if permanent { ErrorKind::InvalidInput } else { ErrorKind::Other };
+                        return Err(Error::new(kind, format!("NATS server error: {}", message)));
+                    },
+                    // +OK, or INFO about the cluster
+                    _ => {},
+                }
+            }
+        }
+
+        fn read_line(&mut self) -> io::Result<String> {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "the NATS server closed the connection"));
+            }
+            Ok(line.trim_end().to_string())
+        }
+    }
+}
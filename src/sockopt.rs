@@ -0,0 +1,108 @@
+//! TCP socket options for client and backend connections.
+//!
+//! The operating system defaults suit short-lived connections. Database connections often
+//! sit idle for a long time, and NAT gateways and firewalls silently drop idle flows, so
+//! enabling keepalive with a probe time below their timeout keeps pooled connections usable.
+//! `linger_secs` sets SO_LINGER, so closing a connection waits up to that long for what's
+//! still queued to be sent, or resets the connection straight away if it's 0.
+
+use std::io::{self, Error};
+use std::time::Duration;
+
+use tokio_core::net::TcpStream;
+
+/// How many connections may be waiting to be accepted, unless configured otherwise
+pub const DEFAULT_BACKLOG: i32 = 1024;
+
+/// TCP keepalive probing of idle connections
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+pub struct Keepalive {
+    /// idle time before the first probe is sent
+    pub time_secs: u64,
+    /// time between unanswered probes, the system default if not set
+    #[serde(default)]
+    pub interval_secs: Option<u32>,
+    /// unanswered probes before the connection is dropped, the system default if not set
+    #[serde(default)]
+    pub probes: Option<u32>,
+}
+
+/// Options applied to each connection, anything not set keeps the system default
+#[derive(Clone,Copy,Debug,Default,Deserialize,PartialEq)]
+pub struct SocketOptions {
+    #[serde(default)]
+    pub keepalive: Option<Keepalive>,
+    /// disable Nagle's algorithm (TCP_NODELAY)
+    #[serde(default)]
+    pub nodelay: Option<bool>,
+    /// SO_SNDBUF
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    /// SO_LINGER, how long closing waits for queued data to be sent
+    #[serde(default)]
+    pub linger_secs: Option<u64>,
+}
+
+impl SocketOptions {
+
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(secs) = self.linger_secs {
+            stream.set_linger(Some(Duration::from_secs(secs)))?;
+        }
+        if let Some(ref keepalive) = self.keepalive {
+            stream.set_keepalive(Some(Duration::from_secs(keepalive.time_secs)))?;
+            if let Some(interval) = keepalive.interval_secs {
+                set_tcp_option(stream, TcpOption::KeepaliveInterval, interval)?;
+            }
+            if let Some(probes) = keepalive.probes {
+                set_tcp_option(stream, TcpOption::KeepaliveProbes, probes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum TcpOption {
+    KeepaliveInterval,
+    KeepaliveProbes,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_tcp_option(stream: &TcpStream, option: TcpOption, value: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let name = match option {
+        TcpOption::KeepaliveInterval => libc::TCP_KEEPINTVL,
+        TcpOption::KeepaliveProbes => libc::TCP_KEEPCNT,
+    };
+    let value = value as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(stream.as_raw_fd(),
+                         libc::IPPROTO_TCP,
+                         name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_tcp_option(_stream: &TcpStream, _option: TcpOption, _value: u32) -> io::Result<()> {
+    Err(Error::new(io::ErrorKind::Unsupported, "keepalive interval and probes are not supported on this platform"))
+}
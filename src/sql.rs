@@ -0,0 +1,682 @@
+//! Lightweight scanning of SQL statements.
+//!
+//! This isn't a parser: statements are split into tokens, skipping string literals and
+//! comments, and the tables a statement uses are picked out by the keywords in front of
+//! them, such as `FROM`, `JOIN`, `INTO` and `UPDATE`. Subqueries, joins and statements in a
+//! multi-statement query are all scanned, but derived names such as CTEs and table aliases
+//! can be mistaken for tables, so callers should err on the side of caution. The statement a
+//! `PREPARE ... FROM` prepares is in a string literal, which the scan skips, so callers check
+//! what `prepared_from` finds as a statement of its own.
+//!
+//! Statements are also classified by their leading keywords into a `StatementKind`, and
+//! `is_read_only` tells whether a query could run on a read-only replica without changing
+//! data or taking locks, for handlers that route, cache or check statements by what they do.
+
+/// A word, quoted identifier or punctuation in a statement
+#[derive(Clone,Debug,PartialEq)]
+pub struct Token {
+    /// the byte range of the token in the statement
+    pub start: usize,
+    pub end: usize,
+    /// the identifier, or keyword, this token could be
+    pub name: Option<String>,
+    /// a backquoted identifier, which is never a keyword
+    pub quoted: bool,
+    pub punct: Option<u8>,
+}
+
+impl Token {
+
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        !self.quoted && self.name.as_ref().map(|n| n.eq_ignore_ascii_case(keyword)).unwrap_or(false)
+    }
+
+    pub fn is_punct(&self, c: u8) -> bool {
+        self.punct == Some(c)
+    }
+}
+
+/// Split a statement into tokens, skipping string literals and comments. The contents of
+/// `/*!...*/` comments are tokenized, since MySQL runs them.
+pub fn tokenize(sql: &str) -> Vec<Token> {
+    let bytes = sql.as_bytes();
+    let mut tokens = vec![];
+    let mut executable = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        match c {
+            b'\'' | b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != c {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            },
+            b'`' => {
+                let mut name = String::new();
+                i += 1;
+                while i < bytes.len() {
+                    if bytes[i] == b'`' {
+                        if bytes.get(i + 1) == Some(&b'`') {
+                            name.push('`');
+                            i += 2;
+                            continue;
+                        }
+                        break;
+                    }
+                    let len = sql[i..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+                    name.push_str(&sql[i..i + len]);
+                    i += len;
+                }
+                i += 1;
+                tokens.push(Token { start, end: i.min(bytes.len()), name: Some(name), quoted: true, punct: None });
+            },
+            b'#' => i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len()),
+            b'-' if bytes.get(i + 1) == Some(&b'-') && bytes.get(i + 2).map(|b| b.is_ascii_whitespace()).unwrap_or(true) => {
+                i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len());
+            },
+            b'/' if bytes.get(i + 1) == Some(&b'*') && bytes.get(i + 2) == Some(&b'!') => {
+                i += 3;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                executable = true;
+            },
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = sql[i + 2..].find("*/").map(|n| i + n + 4).unwrap_or(bytes.len());
+            },
+            b'*' if executable && bytes.get(i + 1) == Some(&b'/') => {
+                i += 2;
+                executable = false;
+            },
+            _ if c.is_ascii_whitespace() => i += 1,
+            _ if is_word_byte(c) => {
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+                tokens.push(Token { start, end: i, name: Some(sql[start..i].to_string()), quoted: false, punct: None });
+            },
+            _ => {
+                i += 1;
+                tokens.push(Token { start, end: i, name: None, quoted: false, punct: Some(c) });
+            },
+        }
+    }
+    tokens
+}
+
+fn is_word_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+/// A statement's fingerprint: the statement with its literals replaced by `?`, lists of them
+/// such as `IN (1, 2, 3)` and the rows of multi-row `INSERT`s collapsed to `(...)`, comments
+/// removed and whitespace collapsed. Statements that only differ in their values share a
+/// fingerprint.
+pub fn fingerprint(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut executable = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        match c {
+            b'\'' | b'"' => {
+                // a doubled quote doesn't end the string
+                while i < bytes.len() && bytes[i] == c {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != c {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                }
+                out.push('?');
+            },
+            b'`' => {
+                i += 1;
+                while i < bytes.len() && (bytes[i] != b'`' || bytes.get(i + 1) == Some(&b'`')) {
+                    i += if bytes[i] == b'`' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                out.push_str(&sql[start..i]);
+            },
+            b'#' => i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len()),
+            b'-' if bytes.get(i + 1) == Some(&b'-') && bytes.get(i + 2).map(|b| b.is_ascii_whitespace()).unwrap_or(true) => {
+                i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len());
+            },
+            b'/' if bytes.get(i + 1) == Some(&b'*') && bytes.get(i + 2) == Some(&b'!') => {
+                i += 3;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                executable = true;
+            },
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = sql[i + 2..].find("*/").map(|n| i + n + 4).unwrap_or(bytes.len());
+            },
+            b'*' if executable && bytes.get(i + 1) == Some(&b'/') => {
+                i += 2;
+                executable = false;
+            },
+            _ if c.is_ascii_whitespace() => {
+                i += 1;
+                if !out.is_empty() && !out.ends_with(' ') {
+                    out.push(' ');
+                }
+            },
+            _ if c.is_ascii_digit() => {
+                // decimal, hex and binary numbers, with fractions and exponents
+                while i < bytes.len() && (is_word_byte(bytes[i]) || bytes[i] == b'.'
+                    || (matches!(bytes[i], b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E'))) {
+                    i += 1;
+                }
+                out.push('?');
+            },
+            _ if is_word_byte(c) => {
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+                out.push_str(&sql[start..i]);
+            },
+            _ => {
+                i += 1;
+                out.push(c as char);
+            },
+        }
+    }
+    collapse_lists(out.trim_end_matches([' ', ';']))
+}
+
+/// A statement with its string and number literals replaced by `?`, and everything else,
+/// comments and whitespace included, as it was
+pub fn scrub(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        match c {
+            b'\'' | b'"' => {
+                while i < bytes.len() && bytes[i] == c {
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != c {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    i += 1;
+                }
+                out.push('?');
+                continue;
+            },
+            b'`' => {
+                i += 1;
+                while i < bytes.len() && (bytes[i] != b'`' || bytes.get(i + 1) == Some(&b'`')) {
+                    i += if bytes[i] == b'`' { 2 } else { 1 };
+                }
+                i += 1;
+            },
+            b'#' => i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len()),
+            b'-' if bytes.get(i + 1) == Some(&b'-') && bytes.get(i + 2).map(|b| b.is_ascii_whitespace()).unwrap_or(true) => {
+                i = sql[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len());
+            },
+            // comments are kept, but not their executable contents
+            b'/' if bytes.get(i + 1) == Some(&b'*') && bytes.get(i + 2) != Some(&b'!') => {
+                i = sql[i + 2..].find("*/").map(|n| i + n + 4).unwrap_or(bytes.len());
+            },
+            _ if c.is_ascii_digit() => {
+                while i < bytes.len() && (is_word_byte(bytes[i]) || bytes[i] == b'.'
+                    || (matches!(bytes[i], b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E'))) {
+                    i += 1;
+                }
+                out.push('?');
+                continue;
+            },
+            _ if is_word_byte(c) => {
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+            },
+            _ => i += 1,
+        }
+        out.push_str(&sql[start..i.min(bytes.len())]);
+    }
+    out
+}
+
+/// Replace parenthesized lists of `?` by `(...)`, and lists of those by a single one
+fn collapse_lists(fingerprint: &str) -> String {
+    let mut out = String::with_capacity(fingerprint.len());
+    let mut rest = fingerprint;
+    while let Some(open) = rest.find('(') {
+        out.push_str(&rest[..open]);
+        let list = rest[open + 1..].find(')')
+            .map(|close| &rest[open + 1..open + 1 + close])
+            .filter(|list| list.contains('?') && list.bytes().all(|b| matches!(b, b'?' | b',' | b' ')));
+        match list {
+            Some(list) => {
+                if !(out.ends_with("(...), ") || out.ends_with("(...),")) {
+                    out.push_str("(...)");
+                } else {
+                    out.truncate(out.trim_end_matches([',', ' ']).len());
+                }
+                rest = &rest[open + list.len() + 2..];
+            },
+            None => {
+                out.push('(');
+                rest = &rest[open + 1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A table named in a statement, with its schema if it was qualified
+#[derive(Clone,Debug,PartialEq)]
+pub struct TableRef {
+    pub schema: Option<String>,
+    pub table: String,
+}
+
+/// What a statement does and what it touches
+#[derive(Clone,Debug,PartialEq)]
+pub struct Statement {
+    /// the first keyword, in upper case, e.g. `SELECT`
+    pub command: String,
+    pub tables: Vec<TableRef>,
+    /// every identifier in the statement, any of which could be a column
+    pub identifiers: Vec<String>,
+    /// whether the statement selects all columns with `*` or `t.*`
+    pub wildcard: bool,
+    /// what the first statement is
+    pub kind: StatementKind,
+    /// whether every statement only reads, see `is_read_only`
+    pub read_only: bool,
+}
+
+/// Keywords followed by a table name, or a list of them
+const TABLE_KEYWORDS: &[&str] = &["from", "join", "into", "update", "table", "tables", "truncate", "handler"];
+
+/// Keywords that can come between `INSERT` or `REPLACE` and the table
+const INSERT_MODIFIERS: &[&str] = &["low_priority", "delayed", "high_priority", "ignore"];
+
+/// Keywords that can follow a table name in place of an alias
+const CLAUSE_KEYWORDS: &[&str] = &["where", "on", "using", "set", "values", "value", "select", "group", "order",
+    "limit", "having", "window", "join", "inner", "left", "right", "cross", "natural", "straight_join",
+    "union", "for", "lock", "partition", "use", "ignore", "force", "to", "as", "like", "read", "write",
+    "default", "engine", "outfile", "dumpfile"];
+
+impl Statement {
+
+    pub fn parse(sql: &str) -> Self {
+        let tokens = tokenize(sql);
+        let command = tokens.iter()
+            .find(|t| t.name.is_some())
+            .and_then(|t| t.name.as_ref())
+            .map(|n| n.to_uppercase())
+            .unwrap_or_default();
+        let mut tables: Vec<TableRef> = vec![];
+        for span in table_spans(&tokens) {
+            if !tables.contains(&span.table) {
+                tables.push(span.table);
+            }
+        }
+        let identifiers = tokens.iter().filter_map(|t| t.name.clone()).collect();
+        let wildcard = tokens.iter().enumerate().any(|(i, t)| {
+            t.is_punct(b'*') && i > 0 && (tokens[i - 1].is_keyword("select") || tokens[i - 1].is_keyword("distinct")
+                                          || tokens[i - 1].is_punct(b',') || tokens[i - 1].is_punct(b'.'))
+        });
+        let (kind, read_only) = (StatementKind::of(&tokens), read_only(&tokens));
+        Statement { command, tables, identifiers, wildcard, kind, read_only }
+    }
+
+    /// Whether the statement mentions `column`, or selects every column
+    pub fn uses_column(&self, column: &str) -> bool {
+        self.wildcard || self.identifiers.iter().any(|i| i.eq_ignore_ascii_case(column))
+    }
+}
+
+/// What a statement is, by its leading keywords
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum StatementKind {
+    /// `SELECT`, `TABLE` and `VALUES`, and `WITH` followed by one of them
+    Select,
+    Insert,
+    Replace,
+    Update,
+    Delete,
+    /// `LOAD DATA` and `LOAD XML`
+    Load,
+    /// `CREATE`, `ALTER`, `DROP`, `RENAME` and `TRUNCATE`, but of accounts and roles
+    Ddl,
+    /// `GRANT`, `REVOKE`, `SET PASSWORD` and `CREATE`, `ALTER`, `DROP` and `RENAME` of
+    /// accounts and roles
+    Grant,
+    /// `SHOW`, and `DESCRIBE` of a table
+    Show,
+    /// `EXPLAIN`, and `DESCRIBE` of a statement
+    Explain,
+    /// `SET` of variables, names or the transaction's characteristics
+    Set,
+    Use,
+    /// `BEGIN`, `START TRANSACTION` and `XA START`
+    Begin,
+    /// `COMMIT` and `XA COMMIT`
+    Commit,
+    /// `ROLLBACK` and `XA ROLLBACK`, but to a savepoint
+    Rollback,
+    /// `SAVEPOINT`, `RELEASE SAVEPOINT` and `ROLLBACK TO SAVEPOINT`
+    Savepoint,
+    /// `LOCK TABLES` and `UNLOCK TABLES`
+    Lock,
+    Call,
+    /// `PREPARE`, `EXECUTE` and `DEALLOCATE PREPARE`
+    Prepare,
+    Do,
+    /// anything else, such as `FLUSH`, `KILL` or `ANALYZE TABLE`, or nothing at all
+    Other,
+}
+
+impl StatementKind {
+
+    /// The kind of the first statement in a tokenized query
+    pub fn of(tokens: &[Token]) -> Self {
+        let tokens = statements(tokens).into_iter().next().unwrap_or(&[]);
+        let words: Vec<&Token> = tokens.iter().filter(|t| t.name.is_some()).collect();
+        let word = |i: usize, keyword: &str| words.get(i).map(|t| t.is_keyword(keyword)).unwrap_or(false);
+        let account = word(1, "user") || word(1, "role");
+        let first = match words.first() {
+            Some(first) if !first.quoted => first.name.as_ref().map(|n| n.to_lowercase()).unwrap_or_default(),
+            _ => return StatementKind::Other,
+        };
+        match &first[..] {
+            "select" | "table" | "values" => StatementKind::Select,
+            "with" => with_kind(tokens),
+            "insert" => StatementKind::Insert,
+            "replace" => StatementKind::Replace,
+            "update" => StatementKind::Update,
+            "delete" => StatementKind::Delete,
+            "load" if word(1, "data") || word(1, "xml") => StatementKind::Load,
+            "create" | "alter" | "drop" | "rename" if account => StatementKind::Grant,
+            "create" | "alter" | "drop" | "rename" | "truncate" => StatementKind::Ddl,
+            "grant" | "revoke" => StatementKind::Grant,
+            "show" => StatementKind::Show,
+            "explain" => StatementKind::Explain,
+            "describe" | "desc" => match words.get(1) {
+                Some(t) if EXPLAINABLE.iter().chain(EXPLAIN_OPTIONS).any(|k| t.is_keyword(k)) => StatementKind::Explain,
+                _ => StatementKind::Show,
+            },
+            "set" if word(1, "password") => StatementKind::Grant,
+            "set" => StatementKind::Set,
+            "use" => StatementKind::Use,
+            "begin" => StatementKind::Begin,
+            "start" if word(1, "transaction") => StatementKind::Begin,
+            "xa" if word(1, "start") || word(1, "begin") => StatementKind::Begin,
+            "xa" if word(1, "commit") => StatementKind::Commit,
+            "xa" if word(1, "rollback") => StatementKind::Rollback,
+            "commit" => StatementKind::Commit,
+            "rollback" if word(1, "to") || word(2, "to") => StatementKind::Savepoint,
+            "rollback" => StatementKind::Rollback,
+            "savepoint" => StatementKind::Savepoint,
+            "release" if word(1, "savepoint") => StatementKind::Savepoint,
+            "lock" | "unlock" if word(1, "tables") || word(1, "table") => StatementKind::Lock,
+            "call" => StatementKind::Call,
+            "prepare" | "execute" | "deallocate" => StatementKind::Prepare,
+            "do" => StatementKind::Do,
+            _ => StatementKind::Other,
+        }
+    }
+
+    /// Whether statements of this kind insert, change or delete rows
+    pub fn modifies_data(self) -> bool {
+        matches!(self, StatementKind::Insert | StatementKind::Replace | StatementKind::Update
+                 | StatementKind::Delete | StatementKind::Load)
+    }
+}
+
+/// Keywords a statement `DESCRIBE` or `EXPLAIN` can be of starts with
+const EXPLAINABLE: &[&str] = &["select", "table", "values", "with", "insert", "replace", "update", "delete"];
+
+/// Options of `DESCRIBE` and `EXPLAIN` that mean a statement, rather than a table, follows
+const EXPLAIN_OPTIONS: &[&str] = &["format", "analyze", "extended", "partitions", "for"];
+
+/// Functions that take or release locks, so don't only read
+const LOCK_FUNCTIONS: &[&str] = &["get_lock", "release_lock", "release_all_locks"];
+
+/// What a `WITH` statement turns out to be, by the first statement keyword outside its CTEs
+fn with_kind(tokens: &[Token]) -> StatementKind {
+    let mut depth = 0_i32;
+    for token in tokens {
+        if token.is_punct(b'(') {
+            depth += 1;
+        } else if token.is_punct(b')') {
+            depth -= 1;
+        } else if depth == 0 {
+            if token.is_keyword("update") {
+                return StatementKind::Update;
+            } else if token.is_keyword("delete") {
+                return StatementKind::Delete;
+            } else if token.is_keyword("select") || token.is_keyword("table") || token.is_keyword("values") {
+                return StatementKind::Select;
+            }
+        }
+    }
+    StatementKind::Select
+}
+
+/// The tokens of each statement of a multi-statement query, without the `;` between them
+fn statements(tokens: &[Token]) -> Vec<&[Token]> {
+    tokens.split(|t| t.is_punct(b';')).filter(|s| !s.is_empty()).collect()
+}
+
+/// The text of each statement of a multi-statement query, split at the `;` between them
+/// outside string literals, quoted identifiers and comments, leaving out empty statements
+pub fn split(sql: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    for token in tokenize(sql) {
+        if token.is_punct(b';') {
+            parts.push(&sql[start..token.start]);
+            start = token.end;
+        }
+    }
+    parts.push(&sql[start..]);
+    parts.into_iter().filter(|part| !tokenize(part).is_empty()).collect()
+}
+
+/// Where a `PREPARE name FROM ...` statement takes the statement it prepares from
+#[derive(Clone,Debug,PartialEq)]
+pub enum PreparedFrom {
+    /// a single string literal, unescaped
+    Literal(String),
+    /// a user variable, or any other expression, which can't be read without running it
+    Expression,
+}
+
+/// Where the statement `sql` prepares comes from, if `sql` is a `PREPARE`
+pub fn prepared_from(sql: &str) -> Option<PreparedFrom> {
+    let tokens = tokenize(sql);
+    if !tokens.first()?.is_keyword("prepare") || !tokens.get(2)?.is_keyword("from") {
+        return None;
+    }
+    let rest = sql[tokens[2].end..].trim_start();
+    let (text, after) = match string_literal(rest) {
+        Some(literal) => literal,
+        None => return Some(PreparedFrom::Expression),
+    };
+    // adjacent literals are concatenated, so only a literal on its own is taken as it is
+    if after.trim().is_empty() {
+        Some(PreparedFrom::Literal(text))
+    } else {
+        Some(PreparedFrom::Expression)
+    }
+}
+
+/// The unescaped string literal `s` starts with, and the text after it
+fn string_literal(s: &str) -> Option<(String, &str)> {
+    let quote = match s.chars().next()? {
+        c @ '\'' | c @ '"' => c,
+        _ => return None,
+    };
+    let mut text = String::new();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+                match escaped {
+                    '0' => text.push('\0'),
+                    'b' => text.push('\x08'),
+                    'n' => text.push('\n'),
+                    'r' => text.push('\r'),
+                    't' => text.push('\t'),
+                    'Z' => text.push('\x1a'),
+                    // kept for LIKE patterns
+                    '%' | '_' => {
+                        text.push('\\');
+                        text.push(escaped);
+                    },
+                    other => text.push(other),
+                }
+            },
+            _ if c == quote => {
+                // a doubled quote stands for one
+                if s[i + 1..].starts_with(quote) {
+                    text.push(quote);
+                    chars.next();
+                    continue;
+                }
+                return Some((text, &s[i + 1..]));
+            },
+            _ => text.push(c),
+        }
+    }
+    None
+}
+
+/// The kind of the first statement in `sql`
+pub fn classify(sql: &str) -> StatementKind {
+    StatementKind::of(&tokenize(sql))
+}
+
+/// Whether every statement in `sql` only reads: selects, `SHOW`, `DESCRIBE` and `EXPLAIN`,
+/// without locking reads such as `FOR UPDATE`, `INTO OUTFILE` or lock functions, and with
+/// `EXPLAIN ANALYZE` only of a select, since it runs the statement
+pub fn is_read_only(sql: &str) -> bool {
+    read_only(&tokenize(sql))
+}
+
+fn read_only(tokens: &[Token]) -> bool {
+    let statements = statements(tokens);
+    !statements.is_empty() && statements.into_iter().all(|tokens| {
+        let reads = match StatementKind::of(tokens) {
+            StatementKind::Select | StatementKind::Show => true,
+            // EXPLAIN ANALYZE runs the statement it explains
+            StatementKind::Explain if tokens.iter().any(|t| t.is_keyword("analyze")) => {
+                let explained = tokens.iter().skip(1).find(|t| EXPLAINABLE.iter().any(|k| t.is_keyword(k)));
+                explained.map(|t| ["select", "table", "values", "with"].iter().any(|k| t.is_keyword(k))).unwrap_or(false)
+            },
+            StatementKind::Explain => true,
+            _ => false,
+        };
+        reads && !locks(tokens)
+    })
+}
+
+/// Whether a reading statement locks rows, writes a file or takes a named lock
+fn locks(tokens: &[Token]) -> bool {
+    tokens.iter().enumerate().any(|(i, t)| {
+        let next = |keyword: &str| tokens.get(i + 1).map(|n| n.is_keyword(keyword)).unwrap_or(false);
+        (t.is_keyword("for") && (next("update") || next("share")))
+            || (t.is_keyword("lock") && next("in"))
+            || (t.is_keyword("into") && (next("outfile") || next("dumpfile")))
+            || (LOCK_FUNCTIONS.iter().any(|f| t.is_keyword(f)) && tokens.get(i + 1).map(|n| n.is_punct(b'(')).unwrap_or(false))
+    })
+}
+
+/// Where a table is named in a tokenized statement
+#[derive(Clone,Debug,PartialEq)]
+pub struct TableSpan {
+    pub table: TableRef,
+    /// the token of the keyword the table follows, e.g. `FROM`, even if the table is further
+    /// along a list
+    pub keyword: usize,
+    /// the first token of the name
+    pub start: usize,
+    /// the token after the name
+    pub end: usize,
+    /// the alias, and the token after it
+    pub alias: Option<(String, usize)>,
+}
+
+/// Every table named in a tokenized statement, in order
+pub fn table_spans(tokens: &[Token]) -> Vec<TableSpan> {
+    let mut spans = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let before = |keyword: &str| i > 0 && tokens[i - 1].is_keyword(keyword);
+        let names_table = if i == 0 {
+            ["describe", "desc", "explain"].iter().any(|k| token.is_keyword(k))
+                || TABLE_KEYWORDS.iter().any(|k| token.is_keyword(k))
+                // INSERT and REPLACE can leave out INTO
+                || ((token.is_keyword("insert") || token.is_keyword("replace"))
+                    && !tokens[1..].iter().find(|t| !INSERT_MODIFIERS.iter().any(|k| t.is_keyword(k))).is_some_and(|t| t.is_keyword("into")))
+        } else {
+            TABLE_KEYWORDS.iter().any(|k| token.is_keyword(k))
+                // SHOW TABLES, SELECT .. FOR UPDATE and ON DUPLICATE KEY UPDATE name no table
+                && (!token.is_keyword("tables") || before("lock"))
+                && !(token.is_keyword("update") && (before("for") || before("key")))
+        };
+        if !names_table {
+            continue;
+        }
+        let mut at = i + 1;
+        // DROP TABLE IF EXISTS, CREATE TABLE IF NOT EXISTS, TRUNCATE TABLE
+        while tokens.get(at).map(|t| t.is_keyword("if") || t.is_keyword("not") || t.is_keyword("exists")
+                                    || t.is_keyword("table") || t.is_keyword("temporary")
+                                    || INSERT_MODIFIERS.iter().any(|k| t.is_keyword(k))).unwrap_or(false) {
+            at += 1;
+        }
+        while let Some((table, end)) = table_at(tokens, at) {
+            // another table follows a comma, after any alias
+            let mut next = end;
+            if tokens.get(next).map(|t| t.is_keyword("as")).unwrap_or(false) {
+                next += 1;
+            }
+            let alias = match tokens.get(next) {
+                Some(t) if t.name.is_some() && !is_clause(t) => {
+                    next += 1;
+                    t.name.clone().map(|name| (name, next))
+                },
+                _ => None,
+            };
+            spans.push(TableSpan { table, keyword: i, start: at, end, alias });
+            match tokens.get(next) {
+                Some(t) if t.is_punct(b',') => at = next + 1,
+                _ => break,
+            }
+        }
+    }
+    spans
+}
+
+fn is_clause(token: &Token) -> bool {
+    CLAUSE_KEYWORDS.iter().any(|k| token.is_keyword(k))
+}
+
+/// The table named at `at`, as `table` or `schema.table`, and the position after it
+fn table_at(tokens: &[Token], at: usize) -> Option<(TableRef, usize)> {
+    let first = tokens.get(at)?;
+    let first_name = first.name.as_ref()?;
+    if is_clause(first) || first.is_keyword("dual") {
+        return None;
+    }
+    match (tokens.get(at + 1), tokens.get(at + 2).and_then(|t| t.name.as_ref())) {
+        (Some(dot), Some(table)) if dot.is_punct(b'.') => {
+            Some((TableRef { schema: Some(first_name.clone()), table: table.clone() }, at + 3))
+        },
+        _ => Some((TableRef { schema: None, table: first_name.clone() }, at + 1)),
+    }
+}
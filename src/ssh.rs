@@ -0,0 +1,81 @@
+//! Backend connections tunnelled through an SSH jump host.
+//!
+//! The tunnel is opened by the system's OpenSSH client, run as `ssh -W host:port` with one
+//! end of a loopback connection as its standard input and output, so the proxy sees an
+//! ordinary socket to the backend. Authentication is by key only, with `BatchMode` so ssh
+//! never prompts, and the jump host's key is checked against `known_hosts`.
+
+use std::io::{self, Error, ErrorKind, Read};
+use std::net::{self, Ipv4Addr, SocketAddr};
+use std::os::fd::OwnedFd;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use futures::Future;
+use futures::future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+use super::connect::BackendAddr;
+use super::upstream::UpstreamProxy;
+
+/// Open a tunnel to `backend` through the jump host `upstream.addr`
+pub fn connect(upstream: &UpstreamProxy,
+               backend: &BackendAddr,
+               handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    Box::new(future::result(spawn(upstream, backend).and_then(|stream| TcpStream::from_stream(stream, handle))))
+}
+
+fn spawn(upstream: &UpstreamProxy, backend: &BackendAddr) -> io::Result<net::TcpStream> {
+    let (ours, theirs) = loopback_pair()?;
+    let stdin: OwnedFd = theirs.try_clone()?.into();
+    let stdout: OwnedFd = theirs.into();
+
+    let mut command = Command::new(upstream.ssh_command.as_ref().map(|c| &c[..]).unwrap_or("ssh"));
+    command.arg("-W").arg(format!("{}:{}", backend.host(), backend.port()))
+        .arg("-p").arg(upstream.addr.port().to_string())
+        .args(["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes", "-o", "StrictHostKeyChecking=yes"])
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::from(stdout))
+        .stderr(Stdio::piped());
+    if let Some(ref identity) = upstream.identity_file {
+        command.arg("-i").arg(identity).args(["-o", "IdentitiesOnly=yes"]);
+    }
+    if let Some(ref known_hosts) = upstream.known_hosts {
+        command.arg("-o").arg(format!("UserKnownHostsFile={}", known_hosts.display()));
+    }
+    if let Some(ref user) = upstream.username {
+        command.arg("-l").arg(user);
+    }
+    command.arg(upstream.addr.host());
+
+    debug!("Tunnelling to {} through SSH jump host {}", backend, upstream.addr);
+    let mut child = command.spawn()
+        .map_err(|e| Error::new(e.kind(), format!("Failed to run ssh: {}", e)))?;
+    let jump_host = upstream.addr.clone();
+    // reap ssh when the tunnel closes, logging why it failed
+    thread::Builder::new().name("mysql-proxy-ssh".to_string()).spawn(move || {
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        match child.wait() {
+            Ok(status) if status.success() => {},
+            Ok(status) => warn!("SSH tunnel through {} exited with {}: {}", jump_host, status, stderr.trim()),
+            Err(e) => warn!("SSH tunnel through {} failed: {}", jump_host, e),
+        }
+    })?;
+    Ok(ours)
+}
+
+/// Both ends of a connection over the loopback interface
+fn loopback_pair() -> io::Result<(net::TcpStream, net::TcpStream)> {
+    let listener = net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+    let ours = net::TcpStream::connect(listener.local_addr()?)?;
+    let (theirs, peer) = listener.accept()?;
+    // anything else on the host could have connected first
+    if peer != ours.local_addr()? {
+        return Err(Error::new(ErrorKind::ConnectionRefused, "Unexpected connection to the SSH tunnel"));
+    }
+    Ok((ours, theirs))
+}
@@ -0,0 +1,167 @@
+//! Session state, followed from the server's session state trackers.
+//!
+//! When CLIENT_SESSION_TRACK is negotiated the server reports changes to the session in its
+//! OK packets: the default schema, tracked system variables, GTIDs and the transaction state.
+//! `SessionTracker` follows the responses on a connection and keeps a `SessionState` up to
+//! date from them, so nothing needs to guess at the effect of statements by parsing SQL.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::{EofPacket, OkPacket, StateChange};
+use super::pipeline::{Correlator, ResponseKind};
+use super::protocol::CLIENT_PROTOCOL_41;
+
+/// Status flag set on OK and EOF packets while a transaction is open
+pub const SERVER_STATUS_IN_TRANS: u16 = 0x0001;
+
+/// What is known about a session's state
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct SessionState {
+    pub schema: Option<String>,
+    /// tracked system variables that have been set, by name
+    pub system_variables: BTreeMap<String, String>,
+    /// the GTIDs committed by the latest statement that reported any
+    pub last_gtids: Option<String>,
+    pub in_transaction: bool,
+    /// the tracked transaction state, such as `T_______`
+    pub transaction_state: Option<String>,
+    pub transaction_characteristics: Option<String>,
+    /// state the trackers don't describe, such as user variables, temporary tables or
+    /// prepared statements, has been created
+    pub state_changed: bool,
+}
+
+impl SessionState {
+
+    /// Update the state from an OK packet
+    pub fn apply(&mut self, ok: &OkPacket) {
+        self.in_transaction = ok.status_flags & SERVER_STATUS_IN_TRANS != 0;
+        for change in &ok.state_changes {
+            match *change {
+                StateChange::SystemVariable { ref name, ref value } => {
+                    self.system_variables.insert(name.clone(), value.clone());
+                },
+                StateChange::Schema(ref schema) => self.schema = Some(schema.clone()),
+                StateChange::StateChanged(changed) => self.state_changed |= changed,
+                StateChange::Gtids(ref gtids) => self.last_gtids = Some(gtids.clone()),
+                StateChange::TransactionCharacteristics(ref c) => self.transaction_characteristics = Some(c.clone()),
+                StateChange::TransactionState(ref s) => self.transaction_state = Some(s.clone()),
+                StateChange::Unknown { kind, .. } => debug!("Ignoring unknown session state tracker {}", kind),
+            }
+        }
+    }
+}
+
+/// Shared view of a connection's `SessionState`, which stays readable while the `Pipe` runs
+#[derive(Clone,Debug,Default)]
+pub struct SharedSessionState {
+    state: Rc<RefCell<SessionState>>,
+}
+
+impl SharedSessionState {
+
+    pub fn get(&self) -> SessionState {
+        self.state.borrow().clone()
+    }
+
+    fn update<F: FnOnce(&mut SessionState)>(&self, f: F) {
+        f(&mut self.state.borrow_mut())
+    }
+}
+
+/// Wraps another handler and follows the session state reported in OK packets
+pub struct SessionTracker<H: PacketHandler> {
+    state: SharedSessionState,
+    capability_flags: u32,
+    correlator: Correlator,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> SessionTracker<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        SessionTracker {
+            state: SharedSessionState::default(),
+            capability_flags: CLIENT_PROTOCOL_41,
+            correlator: Correlator::default(),
+            phase: PhaseTracker::new(),
+            inner,
+        }
+    }
+
+    /// Start from a known schema, such as the one the client logged in with
+    pub fn with_schema(self, schema: Option<String>) -> Self {
+        self.state.update(|s| s.schema = schema);
+        self
+    }
+
+    /// Capabilities negotiated with the client, for relayed handshakes these are taken from
+    /// the client's handshake response
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    /// The state of the session, kept up to date as responses are relayed
+    pub fn state(&self) -> SharedSessionState {
+        self.state.clone()
+    }
+
+    fn observe_ok(&self, ok: &OkPacket, command: u8) {
+        self.state.update(|s| {
+            if command == PacketType::ComResetConnection.byte() {
+                *s = SessionState { schema: s.schema.take(), ..SessionState::default() };
+            } else if command == PacketType::ComChangeUser.byte() {
+                *s = SessionState::default();
+            }
+            s.apply(ok);
+        });
+    }
+}
+
+impl<H> PacketHandler for SessionTracker<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        match self.phase.phase() {
+            ConnectionPhase::Handshake => {
+                // the handshake response starts with the client's capabilities
+                if p.sequence_id() == 1 && p.payload().len() >= 32 {
+                    self.capability_flags = LittleEndian::read_u32(p.payload());
+                    self.correlator.set_capabilities(self.capability_flags);
+                }
+            },
+            ConnectionPhase::Command => self.correlator.request(p),
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        if let Some(r) = self.correlator.response(p) {
+            match r.kind {
+                ResponseKind::Ok => match OkPacket::parse(p, self.capability_flags) {
+                    Ok(ok) => self.observe_ok(&ok, r.command),
+                    Err(e) => debug!("Ignoring malformed OK packet: {}", e),
+                },
+                ResponseKind::Eof => if let Ok(eof) = EofPacket::parse(p) {
+                    self.state.update(|s| s.in_transaction = eof.status_flags & SERVER_STATUS_IN_TRANS != 0);
+                },
+                ResponseKind::Err | ResponseKind::Data => {},
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,248 @@
+//! Prepared statements for sessions that move between backend connections.
+//!
+//! A server-side prepared statement only exists on the connection that prepared it, under
+//! an id that connection picked. A pool that multiplexes client sessions over backend
+//! connections moves a session from one connection to another between its commands, so
+//! the statements it prepared, and their ids, stay behind. `StatementCache` hides that:
+//! clients are given ids of the proxy's own, which it translates to the ids on whichever
+//! connection the session is on, and a statement used on a connection that hasn't prepared
+//! it yet is prepared there first, from the text it was prepared with, before the command
+//! using it is sent. Statements the client closes are closed on each connection the next
+//! time the session is on it.
+//!
+//! The pool must only move a session when `idle` says it's between commands, and not
+//! between the `COM_STMT_SEND_LONG_DATA` packets for a statement and its execution, since
+//! the long data stays on the connection it was sent to.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::{Packet, PacketType};
+use super::pipeline::{self, ResponseKind, ResponsePacket};
+
+/// MySQL error ER_UNKNOWN_STMT_HANDLER
+pub const ER_UNKNOWN_STMT_HANDLER: u16 = 1243;
+
+/// Identifies a backend connection to the cache, e.g. by its index in the pool
+pub type BackendId = u64;
+
+/// What an answered command sent to the backend was, for its response
+#[derive(Debug,PartialEq)]
+enum Sent {
+    /// the client prepared a statement with this text
+    Prepare(Vec<u8>),
+    /// the proxy prepared the client's statement on this connection
+    Reprepare(u32),
+    Other,
+}
+
+/// Where a response packet from the backend goes
+#[derive(Debug,PartialEq)]
+pub enum Reply {
+    /// to the client
+    Client(Packet),
+    /// to the backend: a command held while its statement was prepared
+    Server(Packet),
+    /// nowhere, it answers the proxy
+    Drop,
+}
+
+/// The statements of one client session, and their ids on each backend connection it has
+/// been on
+#[derive(Debug,Default)]
+pub struct StatementCache {
+    next_id: u32,
+    /// the text of each statement, by the id the client knows it by
+    statements: HashMap<u32, Vec<u8>>,
+    /// the ids of statements prepared on each connection, by client id
+    prepared: HashMap<BackendId, HashMap<u32, u32>>,
+    current: BackendId,
+    /// answered commands sent to the current connection, in order
+    sent: VecDeque<Sent>,
+    /// the command waiting for its statement to be prepared on the current connection
+    held: Option<(u32, Packet)>,
+}
+
+impl StatementCache {
+
+    /// Start with the session on connection `backend`
+    pub fn new(backend: BackendId) -> Self {
+        StatementCache { next_id: 1, current: backend, ..StatementCache::default() }
+    }
+
+    /// Whether no command is waiting for a response, so the session may move
+    pub fn idle(&self) -> bool {
+        self.sent.is_empty() && self.held.is_none()
+    }
+
+    /// Whether a command is held while its statement is prepared, so client commands must wait
+    pub fn holding(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// How many statements the client has open
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// The session is now on connection `backend`. Returns the `COM_STMT_CLOSE`s to send
+    /// there first, for statements the client closed while it was elsewhere.
+    pub fn switch(&mut self, backend: BackendId) -> Vec<Packet> {
+        self.current = backend;
+        self.close_stale()
+    }
+
+    /// Connection `backend` has been closed, along with its statements
+    pub fn forget(&mut self, backend: BackendId) {
+        self.prepared.remove(&backend);
+    }
+
+    /// Observe a command from the client, returning the packets to send to the current
+    /// connection in its place: the command with the connection's statement id, or the
+    /// `COM_STMT_PREPARE` preparing its statement there while the command is held. Commands
+    /// for statements the client never prepared are answered with the error to send back.
+    pub fn request(&mut self, p: Packet) -> Result<Vec<Packet>, Packet> {
+        if p.sequence_id() != 0 || p.payload().is_empty() {
+            return Ok(vec![p]);
+        }
+        let packet_type = p.packet_type().ok();
+        let statement_id = match packet_type {
+            Some(PacketType::ComStmtExecute)
+            | Some(PacketType::ComStmtBulkExecute)
+            | Some(PacketType::ComStmtSendLongData)
+            | Some(PacketType::ComStmtReset)
+            | Some(PacketType::ComStmtFetch)
+            | Some(PacketType::ComStmtClose) if p.payload().len() >= 5 => Some(read_id(p.payload())),
+            _ => None,
+        };
+        let client_id = match statement_id {
+            Some(client_id) => client_id,
+            None => {
+                match packet_type {
+                    Some(PacketType::ComStmtPrepare) => self.sent.push_back(Sent::Prepare(p.payload()[1..].to_vec())),
+                    // both end every statement on the connection
+                    Some(PacketType::ComResetConnection) | Some(PacketType::ComChangeUser) => {
+                        self.statements.clear();
+                        self.prepared.entry(self.current).or_default().clear();
+                        self.sent.push_back(Sent::Other);
+                    },
+                    _ if pipeline::is_answered(&p) => self.sent.push_back(Sent::Other),
+                    _ => {},
+                }
+                return Ok(vec![p]);
+            },
+        };
+        if packet_type == Some(PacketType::ComStmtClose) {
+            self.statements.remove(&client_id);
+            return Ok(self.close_stale());
+        }
+        if !self.statements.contains_key(&client_id) {
+            // the client gets no response to long data, the server ignores it as well
+            if packet_type == Some(PacketType::ComStmtSendLongData) {
+                return Ok(vec![]);
+            }
+            let msg = format!("Unknown prepared statement handler ({}) given to {}", client_id, command_name(&p));
+            return Err(Packet::error_packet(ER_UNKNOWN_STMT_HANDLER, *b"HY000", msg));
+        }
+        let server_id = self.prepared.get(&self.current).and_then(|ids| ids.get(&client_id)).cloned();
+        match server_id {
+            Some(server_id) => {
+                if pipeline::is_answered(&p) {
+                    self.sent.push_back(Sent::Other);
+                }
+                Ok(vec![with_id(&p, server_id)])
+            },
+            None => {
+                let prepare = Packet::command(PacketType::ComStmtPrepare, &self.statements[&client_id]);
+                self.sent.push_back(Sent::Reprepare(client_id));
+                self.held = Some((client_id, p));
+                Ok(vec![prepare])
+            },
+        }
+    }
+
+    /// Observe a packet from the current connection answering the command it belongs to,
+    /// as told by the connection's `Correlator`
+    pub fn response(&mut self, p: Packet, answered: Option<ResponsePacket>) -> Reply {
+        let answered = match answered {
+            Some(answered) => answered,
+            None => return Reply::Client(p),
+        };
+        let first = p.sequence_id() == 1;
+        let reply = match self.sent.front() {
+            Some(Sent::Prepare(sql)) if first && answered.kind != ResponseKind::Err && p.payload().len() >= 5 => {
+                let client_id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1).max(1);
+                self.statements.insert(client_id, sql.clone());
+                self.prepared.entry(self.current).or_default().insert(client_id, read_id(p.payload()));
+                Reply::Client(with_id(&p, client_id))
+            },
+            Some(&Sent::Reprepare(client_id)) => {
+                if first && answered.kind != ResponseKind::Err && p.payload().len() >= 5 {
+                    self.prepared.entry(self.current).or_default().insert(client_id, read_id(p.payload()));
+                }
+                if !answered.last {
+                    return Reply::Drop;
+                }
+                self.sent.pop_front();
+                return match self.held.take() {
+                    // the statement can't be prepared here, e.g. a table it uses was dropped
+                    Some((_, ref held)) if answered.kind == ResponseKind::Err => {
+                        if !pipeline::is_answered(held) {
+                            return Reply::Drop;
+                        }
+                        Reply::Client(p)
+                    },
+                    Some((_, held)) => {
+                        let server_id = self.prepared[&self.current][&client_id];
+                        if pipeline::is_answered(&held) {
+                            self.sent.push_back(Sent::Other);
+                        }
+                        Reply::Server(with_id(&held, server_id))
+                    },
+                    None => Reply::Drop,
+                };
+            },
+            _ => Reply::Client(p),
+        };
+        if answered.last {
+            self.sent.pop_front();
+        }
+        reply
+    }
+
+    /// Close statements prepared on the current connection that the client has closed
+    fn close_stale(&mut self) -> Vec<Packet> {
+        let statements = &self.statements;
+        let ids = self.prepared.entry(self.current).or_default();
+        let stale: Vec<u32> = ids.keys().filter(|id| !statements.contains_key(id)).cloned().collect();
+        stale.into_iter().map(|client_id| {
+            let server_id = ids.remove(&client_id).unwrap();
+            Packet::command(PacketType::ComStmtClose, &server_id.to_le_bytes())
+        }).collect()
+    }
+}
+
+/// The statement id following the command byte of a statement command, or the status byte
+/// of PREPARE_OK
+fn read_id(payload: &[u8]) -> u32 {
+    u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]])
+}
+
+fn with_id(p: &Packet, id: u32) -> Packet {
+    let mut bytes = p.bytes.clone();
+    bytes[5..9].copy_from_slice(&id.to_le_bytes());
+    Packet { bytes }
+}
+
+/// The name MySQL gives a statement command in its errors
+fn command_name(p: &Packet) -> &'static str {
+    match p.packet_type() {
+        Ok(PacketType::ComStmtExecute) | Ok(PacketType::ComStmtBulkExecute) => "mysqld_stmt_execute",
+        Ok(PacketType::ComStmtFetch) => "mysqld_stmt_fetch",
+        _ => "mysqld_stmt_reset",
+    }
+}
@@ -0,0 +1,689 @@
+//! Aggregate query statistics, kept across restarts.
+//!
+//! `Stats` counts queries by fingerprint, with their errors, timings and histograms of the
+//! rows and bytes they return, and connections,
+//! queries, errors, response bytes and backend time by user. `StatsHandler` records a session's queries, timed from when
+//! they're forwarded until the backend's response is complete, and can publish them as
+//! events too. With a `StatsConfig`, the totals are saved to a JSON file periodically and
+//! loaded from it on start, so they carry on from where the previous run left off. The
+//! health endpoint serves them at `/stats`, along with each user's current quota usage and
+//! the protocol anomalies `anomaly::ProtocolChecks` found, by kind, and the histograms at
+//! `/metrics` for Prometheus, so queries whose results keep growing stand out. The pipe
+//! adds how long each exchange spent in the proxy, at the backend and being written back to
+//! the client, see `timing`.
+//!
+//! With `labels` in the `StatsConfig`, connections, queries, errors, bytes and backend time
+//! are also counted by the values sessions have for those labels, see `labels`. Each label
+//! takes at most `max_label_values` values, and sessions with others count as `other`, so
+//! clients choosing their own labels can't make the metrics grow without bound.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, AnomalyKind, Verdict};
+use super::auth::Session;
+use super::codec::ErrPacket;
+use super::events::{Event, EventBus};
+use super::labels::{self, Labels};
+use super::pipeline::{Correlator, ResponseKind};
+use super::quota::{QuotaUsage, Quotas};
+use super::sql;
+use super::timing::ExchangeTiming;
+
+/// How many fingerprints are tracked. Queries with others still count towards their user's
+/// totals.
+pub const MAX_DIGESTS: usize = 10000;
+
+/// The value counted in place of a label's values beyond `max_label_values`
+pub const OTHER_LABEL_VALUE: &str = "other";
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct StatsConfig {
+    /// where the totals are saved and loaded from
+    pub path: PathBuf,
+    /// time between saves
+    #[serde(default = "StatsConfig::default_interval_secs")]
+    pub interval_secs: u64,
+    /// connection labels the totals are also kept by
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// how many values of each label are counted apart
+    #[serde(default = "StatsConfig::default_max_label_values")]
+    pub max_label_values: usize,
+}
+
+impl StatsConfig {
+
+    fn default_interval_secs() -> u64 {
+        60
+    }
+
+    fn default_max_label_values() -> usize {
+        100
+    }
+
+    pub fn validate(&self) -> ::std::result::Result<(), String> {
+        if let Some(name) = self.labels.iter().find(|name| !labels::is_label_name(name) || *name == "le") {
+            return Err(format!("'{}' can't be a label of the statistics", name));
+        }
+        if self.max_label_values == 0 {
+            return Err("max_label_values must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Upper bounds of the buckets of a `Histogram`, each 4 times the one before
+pub const HISTOGRAM_BOUNDS: [u64; 13] = [0, 1, 4, 16, 64, 256, 1024, 4096, 16384, 65536, 262144, 1048576, 4194304];
+
+/// How many values fell in each of a set of ranges
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct Histogram {
+    /// the values up to each of `HISTOGRAM_BOUNDS` and over the last of them, each counted
+    /// once, in the lowest bucket it fits
+    pub buckets: Vec<u64>,
+    pub sum: u64,
+    pub max: u64,
+}
+
+impl Histogram {
+
+    pub fn record(&mut self, value: u64) {
+        self.buckets.resize(HISTOGRAM_BOUNDS.len() + 1, 0);
+        let bucket = HISTOGRAM_BOUNDS.iter().position(|&bound| value <= bound).unwrap_or(HISTOGRAM_BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Add the values counted in `other`
+    pub fn merge(&mut self, other: &Histogram) {
+        self.buckets.resize(HISTOGRAM_BOUNDS.len() + 1, 0);
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// At most how large the smallest `q` of the values are, e.g. 0.99, as the upper bound of
+    /// its bucket, or `None` if there are none
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let rank = ((q * self.count() as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(HISTOGRAM_BOUNDS.get(i).map(|&bound| bound.min(self.max)).unwrap_or(self.max));
+            }
+        }
+        None
+    }
+}
+
+/// Totals for the queries sharing a fingerprint
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct DigestStats {
+    pub fingerprint: String,
+    pub count: u64,
+    pub errors: u64,
+    pub total_time_us: u64,
+    pub max_time_us: u64,
+    /// seconds since the epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// rows returned by each query, across its result sets
+    #[serde(default)]
+    pub rows: Histogram,
+    /// bytes of each query's response
+    #[serde(default)]
+    pub bytes: Histogram,
+}
+
+/// Totals for a proxy user
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct UserStats {
+    pub connections: u64,
+    pub queries: u64,
+    pub errors: u64,
+    /// the size of the backend's responses to every command
+    #[serde(default)]
+    pub bytes_returned: u64,
+    /// how long the backend took to answer every command
+    #[serde(default)]
+    pub backend_time_us: u64,
+}
+
+impl UserStats {
+
+    fn add(&mut self, other: &UserStats) {
+        self.connections += other.connections;
+        self.queries += other.queries;
+        self.errors += other.errors;
+        self.bytes_returned += other.bytes_returned;
+        self.backend_time_us += other.backend_time_us;
+    }
+}
+
+/// How long exchanges spent in each stage, in microseconds
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct ExchangeStats {
+    /// until the command was written to the backend
+    pub queued_us: Histogram,
+    /// until the backend's response had been read
+    pub backend_us: Histogram,
+    /// until the response was written to the client
+    pub client_write_us: Histogram,
+}
+
+impl ExchangeStats {
+
+    fn merge(&mut self, other: &ExchangeStats) {
+        self.queued_us.merge(&other.queued_us);
+        self.backend_us.merge(&other.backend_us);
+        self.client_write_us.merge(&other.client_write_us);
+    }
+}
+
+/// Totals for the sessions with the same values of the labels statistics are kept by
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct LabelledStats {
+    pub labels: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub totals: UserStats,
+}
+
+/// The totals at a point in time, as saved to disk
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct StatsSnapshot {
+    /// seconds since the epoch
+    pub taken_at: u64,
+    /// the busiest fingerprints first
+    pub digests: Vec<DigestStats>,
+    pub users: BTreeMap<String, UserStats>,
+    /// the usage of users with a quota when the snapshot was taken, which isn't restored
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quotas: BTreeMap<String, QuotaUsage>,
+    /// packets that broke the protocol, by kind of anomaly
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub anomalies: BTreeMap<String, u64>,
+    /// the totals by connection labels, if they're kept
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labelled: Vec<LabelledStats>,
+    /// where the time of the exchanges went, see `timing`
+    #[serde(default)]
+    pub exchanges: ExchangeStats,
+}
+
+impl StatsSnapshot {
+
+    /// The histograms of rows and bytes returned by fingerprint, in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let metrics = [
+            ("mysql_proxy_digest_result_rows", "Rows returned by each query, by fingerprint", true),
+            ("mysql_proxy_digest_result_bytes", "Bytes of each query's response, by fingerprint", false),
+        ];
+        for &(name, help, rows) in metrics.iter() {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+            for digest in &self.digests {
+                let histogram = if rows { &digest.rows } else { &digest.bytes };
+                let label = prometheus_label(&digest.fingerprint);
+                let mut cumulative = 0;
+                for (bound, count) in HISTOGRAM_BOUNDS.iter().zip(&histogram.buckets) {
+                    cumulative += count;
+                    out.push_str(&format!("{}_bucket{{digest=\"{}\",le=\"{}\"}} {}\n", name, label, bound, cumulative));
+                }
+                out.push_str(&format!("{}_bucket{{digest=\"{}\",le=\"+Inf\"}} {}\n", name, label, histogram.count()));
+                out.push_str(&format!("{}_sum{{digest=\"{}\"}} {}\n", name, label, histogram.sum));
+                out.push_str(&format!("{}_count{{digest=\"{}\"}} {}\n", name, label, histogram.count()));
+            }
+        }
+        let name = "mysql_proxy_exchange_time_us";
+        out.push_str(&format!("# HELP {} Microseconds each exchange spent in each stage\n# TYPE {} histogram\n", name, name));
+        let stages = [
+            ("queued", &self.exchanges.queued_us),
+            ("backend", &self.exchanges.backend_us),
+            ("client_write", &self.exchanges.client_write_us),
+        ];
+        for &(stage, histogram) in stages.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in HISTOGRAM_BOUNDS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                out.push_str(&format!("{}_bucket{{stage=\"{}\",le=\"{}\"}} {}\n", name, stage, bound, cumulative));
+            }
+            out.push_str(&format!("{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}\n", name, stage, histogram.count()));
+            out.push_str(&format!("{}_sum{{stage=\"{}\"}} {}\n", name, stage, histogram.sum));
+            out.push_str(&format!("{}_count{{stage=\"{}\"}} {}\n", name, stage, histogram.count()));
+        }
+        if self.labelled.is_empty() {
+            return out;
+        }
+        let counters: [(&str, &str, Counter); 5] = [
+            ("mysql_proxy_labelled_connections_total", "Connections, by connection labels", |t| t.connections),
+            ("mysql_proxy_labelled_queries_total", "Queries, by connection labels", |t| t.queries),
+            ("mysql_proxy_labelled_errors_total", "Queries that failed, by connection labels", |t| t.errors),
+            ("mysql_proxy_labelled_bytes_returned_total", "Bytes of the responses to every command, by connection labels", |t| t.bytes_returned),
+            ("mysql_proxy_labelled_backend_time_us_total", "Microseconds backends took to answer, by connection labels", |t| t.backend_time_us),
+        ];
+        for &(name, help, value) in counters.iter() {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+            for labelled in &self.labelled {
+                let labels: Vec<String> = labelled.labels.iter()
+                    .map(|(label, v)| format!("{}=\"{}\"", label, prometheus_label(v)))
+                    .collect();
+                out.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value(&labelled.totals)));
+            }
+        }
+        out
+    }
+}
+
+/// Takes one of the totals from a `UserStats`
+type Counter = fn(&UserStats) -> u64;
+
+/// A label value with backslashes, quotes and newlines escaped
+fn prometheus_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Debug,Default)]
+struct Totals {
+    digests: HashMap<String, DigestStats>,
+    users: BTreeMap<String, UserStats>,
+    anomalies: BTreeMap<String, u64>,
+    labelled: BTreeMap<BTreeMap<String, String>, UserStats>,
+    /// the values of each label counted apart so far
+    label_values: BTreeMap<String, BTreeSet<String>>,
+    exchanges: ExchangeStats,
+}
+
+/// Statistics shared between connections
+#[derive(Clone,Debug,Default)]
+pub struct Stats {
+    totals: Arc<Mutex<Totals>>,
+    quotas: Option<Quotas>,
+    labels: Vec<String>,
+    max_label_values: usize,
+}
+
+impl Stats {
+
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    /// Start from the totals saved in `path`, or from nothing if it doesn't exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stats = Stats::new();
+        match File::open(path) {
+            Ok(f) => stats.restore(serde_json::from_reader(BufReader::new(f))?),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(stats)
+    }
+
+    /// Include the users' quota usage in snapshots
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Also keep the totals by the values of `labels`, counting at most `max_values` of each
+    /// apart, including those of totals loaded already
+    pub fn with_labels(mut self, labels: &[String], max_values: usize) -> Self {
+        {
+            let mut totals = self.totals.lock().unwrap();
+            let totals = &mut *totals;
+            for set in totals.labelled.keys() {
+                for (label, value) in set.iter().filter(|&(label, value)| labels.contains(label) && value != OTHER_LABEL_VALUE) {
+                    totals.label_values.entry(label.clone()).or_default().insert(value.clone());
+                }
+            }
+        }
+        self.labels = labels.to_vec();
+        self.max_label_values = max_values;
+        self
+    }
+
+    /// Write the totals to `path`, replacing it only once they're all written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &self.snapshot())?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Save the totals to `config.path` every `config.interval_secs`, on a thread of its own
+    pub fn save_in_thread(&self, config: &StatsConfig) -> Result<()> {
+        let stats = self.clone();
+        let path = config.path.clone();
+        let interval = Duration::from_secs(config.interval_secs.max(1));
+        thread::Builder::new().name("mysql-proxy-stats".to_string()).spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = stats.save(&path) {
+                warn!("Failed to save statistics to {}: {}", path.display(), e);
+            }
+        })?;
+        Ok(())
+    }
+
+    pub fn record_connection(&self, user: &str) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.users.entry(user.to_string()).or_default().connections += 1;
+    }
+
+    /// Count a query that took `elapsed` until its response, of `rows` rows and `bytes`
+    /// bytes, was complete
+    pub fn record_query(&self, user: &str, fingerprint: &str, elapsed: Duration, error: bool, rows: u64, bytes: u64) {
+        let now = now_secs();
+        let time_us = elapsed.as_micros() as u64;
+        let mut totals = self.totals.lock().unwrap();
+        let user = totals.users.entry(user.to_string()).or_default();
+        user.queries += 1;
+        user.errors += error as u64;
+        if !totals.digests.contains_key(fingerprint) {
+            if totals.digests.len() >= MAX_DIGESTS {
+                return;
+            }
+            totals.digests.insert(fingerprint.to_string(), DigestStats {
+                fingerprint: fingerprint.to_string(),
+                first_seen: now,
+                ..DigestStats::default()
+            });
+        }
+        let digest = totals.digests.get_mut(fingerprint).unwrap();
+        digest.count += 1;
+        digest.errors += error as u64;
+        digest.total_time_us += time_us;
+        digest.max_time_us = digest.max_time_us.max(time_us);
+        digest.last_seen = now;
+        digest.rows.record(rows);
+        digest.bytes.record(bytes);
+    }
+
+    /// Count the response to any command, `bytes` long and complete after `elapsed`
+    pub fn record_response(&self, user: &str, bytes: u64, elapsed: Duration) {
+        let mut totals = self.totals.lock().unwrap();
+        let user = totals.users.entry(user.to_string()).or_default();
+        user.bytes_returned += bytes;
+        user.backend_time_us += elapsed.as_micros() as u64;
+    }
+
+    /// Add `usage` to the totals of the values `labels` has for the labels statistics are
+    /// kept by, if any
+    pub fn record_labelled(&self, labels: &Labels, usage: &UserStats) {
+        if self.labels.is_empty() {
+            return;
+        }
+        let mut totals = self.totals.lock().unwrap();
+        let mut set = BTreeMap::new();
+        for label in &self.labels {
+            let value = labels.get(label).unwrap_or_default();
+            let values = totals.label_values.entry(label.clone()).or_default();
+            let value = if values.contains(&value) {
+                value
+            } else if values.len() < self.max_label_values {
+                values.insert(value.clone());
+                value
+            } else {
+                OTHER_LABEL_VALUE.to_string()
+            };
+            set.insert(label.clone(), value);
+        }
+        let labelled = totals.labelled.entry(set).or_default();
+        labelled.add(usage);
+    }
+
+    /// Count an exchange that spent `timing` in each stage
+    pub fn record_exchange(&self, timing: &ExchangeTiming) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.exchanges.queued_us.record(timing.queued.as_micros() as u64);
+        totals.exchanges.backend_us.record(timing.backend.as_micros() as u64);
+        totals.exchanges.client_write_us.record(timing.client_write.as_micros() as u64);
+    }
+
+    /// Count a packet that broke the protocol
+    pub fn record_anomaly(&self, kind: AnomalyKind) {
+        let mut totals = self.totals.lock().unwrap();
+        *totals.anomalies.entry(kind.name().to_string()).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let totals = self.totals.lock().unwrap();
+        let mut digests: Vec<DigestStats> = totals.digests.values().cloned().collect();
+        digests.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.fingerprint.cmp(&b.fingerprint)));
+        let quotas = self.quotas.as_ref().map(|q| q.usage()).unwrap_or_default();
+        let labelled = totals.labelled.iter()
+            .map(|(labels, totals)| LabelledStats { labels: labels.clone(), totals: totals.clone() })
+            .collect();
+        StatsSnapshot {
+            taken_at: now_secs(),
+            digests,
+            users: totals.users.clone(),
+            quotas,
+            anomalies: totals.anomalies.clone(),
+            labelled,
+            exchanges: totals.exchanges.clone(),
+        }
+    }
+
+    /// Add the totals from a snapshot, e.g. one saved by an earlier run
+    pub fn restore(&self, snapshot: StatsSnapshot) {
+        let mut totals = self.totals.lock().unwrap();
+        for saved in snapshot.digests {
+            if !totals.digests.contains_key(&saved.fingerprint) && totals.digests.len() >= MAX_DIGESTS {
+                continue;
+            }
+            let digest = totals.digests.entry(saved.fingerprint.clone()).or_insert_with(|| DigestStats {
+                fingerprint: saved.fingerprint.clone(),
+                first_seen: saved.first_seen,
+                ..DigestStats::default()
+            });
+            digest.count += saved.count;
+            digest.errors += saved.errors;
+            digest.total_time_us += saved.total_time_us;
+            digest.max_time_us = digest.max_time_us.max(saved.max_time_us);
+            digest.first_seen = digest.first_seen.min(saved.first_seen);
+            digest.last_seen = digest.last_seen.max(saved.last_seen);
+            digest.rows.merge(&saved.rows);
+            digest.bytes.merge(&saved.bytes);
+        }
+        for (name, saved) in snapshot.users {
+            let user = totals.users.entry(name).or_default();
+            user.connections += saved.connections;
+            user.queries += saved.queries;
+            user.errors += saved.errors;
+            user.bytes_returned += saved.bytes_returned;
+            user.backend_time_us += saved.backend_time_us;
+        }
+        for (kind, count) in snapshot.anomalies {
+            *totals.anomalies.entry(kind).or_default() += count;
+        }
+        for saved in snapshot.labelled {
+            totals.labelled.entry(saved.labels).or_default().add(&saved.totals);
+        }
+        totals.exchanges.merge(&snapshot.exchanges);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A forwarded command waiting for its response
+struct PendingCommand {
+    /// the fingerprint of a query
+    fingerprint: Option<String>,
+    sent: Instant,
+}
+
+/// Wraps another handler and records a session's queries in `Stats`, publishes them as
+/// `QueryExecuted` events, or both
+pub struct StatsHandler<H: PacketHandler> {
+    stats: Option<Stats>,
+    events: Option<EventBus>,
+    user: String,
+    labels: Labels,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// forwarded commands, in order, and the error code, size and rows of the current response
+    pending: VecDeque<PendingCommand>,
+    error: Option<u16>,
+    bytes: u64,
+    rows: u64,
+    inner: H,
+}
+
+impl<H> StatsHandler<H> where H: PacketHandler {
+
+    pub fn new(user: &str, inner: H) -> Self {
+        StatsHandler {
+            stats: None,
+            events: None,
+            user: user.to_string(),
+            labels: Labels::default(),
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: VecDeque::new(),
+            error: None,
+            bytes: 0,
+            rows: 0,
+            inner,
+        }
+    }
+
+    /// Follow the session's queries with the capabilities the proxy logged in to the
+    /// backend with
+    pub fn for_session(session: &Session, inner: H) -> Self {
+        StatsHandler::new(&session.user, inner).with_capabilities(session.backend_capabilities)
+            .with_labels(session.labels.clone())
+    }
+
+    /// Count the session by its labels too, and publish them with its queries
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Record the connection and its queries in `stats`
+    pub fn with_stats(mut self, stats: Stats) -> Self {
+        stats.record_connection(&self.user);
+        stats.record_labelled(&self.labels, &UserStats { connections: 1, ..UserStats::default() });
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Publish the queries on `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    fn record(&self, command: PendingCommand) {
+        let elapsed = command.sent.elapsed();
+        if let Some(ref stats) = self.stats {
+            stats.record_response(&self.user, self.bytes, elapsed);
+            let query = command.fingerprint.is_some();
+            stats.record_labelled(&self.labels, &UserStats {
+                queries: query as u64,
+                errors: (query && self.error.is_some()) as u64,
+                bytes_returned: self.bytes,
+                backend_time_us: elapsed.as_micros() as u64,
+                ..UserStats::default()
+            });
+        }
+        let fingerprint = match command.fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return,
+        };
+        if let Some(ref stats) = self.stats {
+            stats.record_query(&self.user, &fingerprint, elapsed, self.error.is_some(), self.rows, self.bytes);
+        }
+        if let Some(ref events) = self.events {
+            events.publish_with(|| Event::QueryExecuted {
+                user: self.user.clone(),
+                fingerprint,
+                elapsed_us: elapsed.as_micros() as u64,
+                error: self.error,
+                labels: self.labels.snapshot(),
+            });
+        }
+    }
+}
+
+impl<H> PacketHandler for StatsHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        // only commands the backend will answer, timed as the client sent them
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        let issued = self.correlator.issued();
+        self.correlator.request(forwarded);
+        if self.correlator.issued() > issued {
+            let fingerprint = match p.packet_type() {
+                Ok(PacketType::ComQuery) => Some(sql::fingerprint(&String::from_utf8_lossy(&p.payload()[1..]))),
+                _ => None,
+            };
+            self.pending.push_back(PendingCommand { fingerprint, sent: Instant::now() });
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            let row = self.correlator.expects_row();
+            if let Some(answered) = self.correlator.response(p) {
+                if answered.kind == ResponseKind::Err && self.error.is_none() {
+                    self.error = ErrPacket::parse(p).map(|e| e.code).ok();
+                }
+                self.bytes += p.bytes.len() as u64;
+                self.rows += (row && answered.kind == ResponseKind::Data) as u64;
+                if answered.last {
+                    if let Some(command) = self.pending.pop_front() {
+                        self.record(command);
+                    }
+                    self.error = None;
+                    self.bytes = 0;
+                    self.rows = 0;
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,324 @@
+//! Copies of the results of selected queries, written to CSV files.
+//!
+//! A `[[taps]]` rule names a query by an example of it, whose literals and case don't
+//! matter since queries are matched by fingerprint, and optionally the users whose queries
+//! it taps. The rows of matching queries' results are decoded as they pass through and
+//! handed to a thread that appends them to the tap's file as CSV: the time, the user and the
+//! row's values, under a header from the first result's columns if the file is new. NULL is
+//! written as an empty field and empty strings as `""`.
+//!
+//! The queue to the writing thread holds at most `max_queued_rows`. Rows beyond it are
+//! dropped and counted rather than holding up the session, so a slow disk never slows the
+//! queries being tapped. Taps writing to the same file share its queue and thread. Only the
+//! text results of `COM_QUERY` are tapped, not those of prepared statements. CSV is the only
+//! format: Parquet would need a dependency the proxy doesn't have.
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::{QueryResponse, QueryResponseDecoder, TextRow};
+use super::pipeline::Correlator;
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sql;
+
+/// A query whose results are copied to a file
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct TapConfig {
+    /// an example of the query, e.g. `SELECT * FROM shop.orders WHERE id = 1`
+    pub query: String,
+    /// only these users' queries, otherwise everyone's
+    #[serde(default)]
+    pub users: Vec<String>,
+    pub path: PathBuf,
+    /// the most rows waiting to be written, beyond which rows are dropped
+    #[serde(default = "TapConfig::default_max_queued_rows")]
+    pub max_queued_rows: usize,
+}
+
+impl TapConfig {
+
+    fn default_max_queued_rows() -> usize {
+        10_000
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.query.trim().is_empty() {
+            return Err("empty query".to_string());
+        }
+        if self.max_queued_rows == 0 {
+            return Err("max_queued_rows must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+enum TapMessage {
+    /// the column names of the result set the following rows belong to
+    Columns(Vec<String>),
+    Row { timestamp_ms: u64, user: String, values: TextRow },
+}
+
+/// The queue to a tap file's writing thread, shared by the taps writing to it
+#[derive(Clone)]
+pub struct TapWriter {
+    sender: SyncSender<TapMessage>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TapWriter {
+
+    /// Append to `config.path`, writing a header first if the file is empty
+    pub fn open(config: &TapConfig) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let header = file.metadata()?.len() == 0;
+        let path = config.path.clone();
+        let (writer, receiver) = TapWriter::channel(config.max_queued_rows);
+        thread::Builder::new().name("mysql-proxy-tap".to_string()).spawn(move || {
+            if let Err(e) = write_rows(receiver, BufWriter::new(file), header) {
+                warn!("Stopped writing tapped rows to {}: {}", path.display(), e);
+            }
+        })?;
+        Ok(writer)
+    }
+
+    /// Write to an arbitrary writer on a thread of its own
+    pub fn new(writer: Box<dyn Write + Send>, max_queued_rows: usize, header: bool) -> Result<Self> {
+        let (tap_writer, receiver) = TapWriter::channel(max_queued_rows);
+        thread::Builder::new().name("mysql-proxy-tap".to_string()).spawn(move || {
+            if let Err(e) = write_rows(receiver, writer, header) {
+                warn!("Stopped writing tapped rows: {}", e);
+            }
+        })?;
+        Ok(tap_writer)
+    }
+
+    fn channel(max_queued_rows: usize) -> (Self, Receiver<TapMessage>) {
+        let (sender, receiver) = mpsc::sync_channel(max_queued_rows);
+        (TapWriter { sender, dropped: Arc::new(AtomicU64::new(0)) }, receiver)
+    }
+
+    /// The rows dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, message: TapMessage) {
+        match self.sender.try_send(message) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Tap queue full, dropping rows");
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+fn write_rows<W: Write>(receiver: Receiver<TapMessage>, mut writer: W, mut header: bool) -> Result<()> {
+    while let Ok(message) = receiver.recv() {
+        let mut next = Some(message);
+        // write what has queued up, then flush while waiting for more
+        while let Some(message) = next {
+            match message {
+                TapMessage::Columns(columns) => {
+                    if header {
+                        let names = ["timestamp_ms", "user"].iter().map(|s| s.to_string()).chain(columns);
+                        let fields: Vec<String> = names.map(|name| csv_field(Some(name.as_bytes()))).collect();
+                        writeln!(writer, "{}", fields.join(","))?;
+                        header = false;
+                    }
+                },
+                TapMessage::Row { timestamp_ms, user, values } => {
+                    let mut fields = vec![timestamp_ms.to_string(), csv_field(Some(user.as_bytes()))];
+                    fields.extend(values.iter().map(|value| csv_field(value.as_ref().map(|v| &v[..]))));
+                    writeln!(writer, "{}", fields.join(","))?;
+                },
+            }
+            next = receiver.try_recv().ok();
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// A CSV field, quoted if it needs to be, empty for NULL
+pub fn csv_field(value: Option<&[u8]>) -> String {
+    let value = match value {
+        Some(value) => String::from_utf8_lossy(value),
+        None => return String::new(),
+    };
+    if value.is_empty() || value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into_owned()
+    }
+}
+
+/// A rule choosing the queries whose results go to a `TapWriter`
+#[derive(Clone)]
+pub struct Tap {
+    fingerprint: String,
+    users: Vec<String>,
+    writer: TapWriter,
+}
+
+impl Tap {
+
+    pub fn new(config: &TapConfig, writer: TapWriter) -> Self {
+        Tap { fingerprint: sql::fingerprint(&config.query).to_lowercase(), users: config.users.clone(), writer }
+    }
+
+    /// Whether a query by `user` is tapped
+    pub fn matches(&self, user: &str, query: &str) -> bool {
+        (self.users.is_empty() || self.users.iter().any(|u| u == user)) && sql::fingerprint(query).to_lowercase() == self.fingerprint
+    }
+}
+
+/// The result being tapped
+struct Tapped {
+    taps: Vec<Tap>,
+    decoder: QueryResponseDecoder,
+    columns: Vec<String>,
+    /// whether the taps have been sent the columns of the current result set
+    columns_sent: bool,
+}
+
+/// Wraps another handler and copies the rows of tapped queries' results to their taps
+pub struct TapHandler<H: PacketHandler> {
+    taps: Vec<Tap>,
+    user: String,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// the taps of each forwarded command, in order
+    pending: VecDeque<Option<Tapped>>,
+    inner: H,
+}
+
+impl<H> TapHandler<H> where H: PacketHandler {
+
+    pub fn new(taps: Vec<Tap>, user: &str, inner: H) -> Self {
+        TapHandler {
+            taps,
+            user: user.to_string(),
+            capability_flags: CLIENT_PROTOCOL_41,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            pending: VecDeque::new(),
+            inner,
+        }
+    }
+
+    /// Tap the session's queries, decoding results with the capabilities the proxy logged
+    /// in to the backend with
+    pub fn for_session(taps: Vec<Tap>, session: &Session, inner: H) -> Self {
+        TapHandler::new(taps, &session.user, inner).with_capabilities(session.backend_capabilities)
+    }
+
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    fn tap(&mut self, p: &Packet) {
+        let tapped = match self.pending.front_mut() {
+            Some(&mut Some(ref mut tapped)) => tapped,
+            _ => return,
+        };
+        match tapped.decoder.decode(p) {
+            Ok(QueryResponse::ColumnCount(_)) => {
+                tapped.columns.clear();
+                tapped.columns_sent = false;
+            },
+            Ok(QueryResponse::Column(column)) => tapped.columns.push(column.name),
+            Ok(QueryResponse::Row(values)) => {
+                if !tapped.columns_sent {
+                    for tap in &tapped.taps {
+                        tap.writer.send(TapMessage::Columns(tapped.columns.clone()));
+                    }
+                    tapped.columns_sent = true;
+                }
+                let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+                for tap in &tapped.taps {
+                    tap.writer.send(TapMessage::Row { timestamp_ms, user: self.user.clone(), values: values.clone() });
+                }
+            },
+            Ok(_) => {},
+            // give up on responses the decoder can't follow, e.g. split packets
+            Err(_) => {
+                if let Some(front) = self.pending.front_mut() {
+                    *front = None;
+                }
+            },
+        }
+    }
+}
+
+impl<H> PacketHandler for TapHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let action = self.inner.handle_request(p);
+        if self.phase.phase() != ConnectionPhase::Command {
+            return action;
+        }
+        let forwarded = match action {
+            Action::Forward => p,
+            Action::Mutate(ref p2) => p2,
+            _ => return action,
+        };
+        let issued = self.correlator.issued();
+        self.correlator.request(forwarded);
+        if self.correlator.issued() > issued {
+            let tapped = match forwarded.packet_type() {
+                Ok(PacketType::ComQuery) => {
+                    let query = String::from_utf8_lossy(&forwarded.payload()[1..]);
+                    let taps: Vec<Tap> = self.taps.iter().filter(|t| t.matches(&self.user, &query)).cloned().collect();
+                    if taps.is_empty() {
+                        None
+                    } else {
+                        Some(Tapped {
+                            taps,
+                            decoder: QueryResponseDecoder::new(self.capability_flags),
+                            columns: vec![],
+                            columns_sent: false,
+                        })
+                    }
+                },
+                _ => None,
+            };
+            self.pending.push_back(tapped);
+        }
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(answered) = self.correlator.response(p) {
+                self.tap(p);
+                if answered.last {
+                    self.pending.pop_front();
+                }
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,186 @@
+//! Slowing down and blocking hosts that keep failing to log in.
+//!
+//! With a `[tarpit]` section, a listener counts the failed logins of each client address. A
+//! failure is a password the proxy rejected, or for users whose authentication is passed
+//! through, one the backend rejected. Each failure doubles how long the next connection from
+//! the address waits for its greeting, from `delay_ms` up to `max_delay_ms`, which slows
+//! down credential stuffing without turning away a user who mistyped a password. After
+//! `max_failures` the address is blocked for `ban_secs`: like a MySQL server with
+//! `max_connect_errors`, the proxy answers its connections with ER_HOST_IS_BLOCKED in place
+//! of the greeting. Failures are forgotten `window_secs` after the last one, and a
+//! successful login clears them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::acl::Cidr;
+
+/// MySQL error ER_HOST_IS_BLOCKED
+pub const ER_HOST_IS_BLOCKED: u16 = 1129;
+
+/// Addresses tracked before those whose failures were forgotten are cleaned up
+const CLEANUP_THRESHOLD: usize = 4096;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct TarpitConfig {
+    /// failed logins after which an address is blocked, or 0 never to block addresses
+    #[serde(default = "TarpitConfig::default_max_failures")]
+    pub max_failures: u32,
+    /// the greeting delay after the first failure, doubled with each failure after it
+    #[serde(default = "TarpitConfig::default_delay_ms")]
+    pub delay_ms: u64,
+    #[serde(default = "TarpitConfig::default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// how long an address stays blocked
+    #[serde(default = "TarpitConfig::default_ban_secs")]
+    pub ban_secs: u64,
+    /// how long after an address's last failure its failures are forgotten
+    #[serde(default = "TarpitConfig::default_window_secs")]
+    pub window_secs: u64,
+    /// networks that are never slowed down or blocked, such as those of health checks
+    #[serde(default)]
+    pub exempt: Vec<Cidr>,
+}
+
+impl TarpitConfig {
+
+    fn default_max_failures() -> u32 {
+        10
+    }
+
+    fn default_delay_ms() -> u64 {
+        200
+    }
+
+    fn default_max_delay_ms() -> u64 {
+        10_000
+    }
+
+    fn default_ban_secs() -> u64 {
+        300
+    }
+
+    fn default_window_secs() -> u64 {
+        600
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_delay_ms < self.delay_ms {
+            return Err(format!("max_delay_ms {} is less than delay_ms {}", self.max_delay_ms, self.delay_ms));
+        }
+        if self.max_failures > 0 && self.ban_secs == 0 {
+            return Err("ban_secs must be at least 1 when max_failures is set".to_string());
+        }
+        if self.window_secs == 0 {
+            return Err("window_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        TarpitConfig {
+            max_failures: TarpitConfig::default_max_failures(),
+            delay_ms: TarpitConfig::default_delay_ms(),
+            max_delay_ms: TarpitConfig::default_max_delay_ms(),
+            ban_secs: TarpitConfig::default_ban_secs(),
+            window_secs: TarpitConfig::default_window_secs(),
+            exempt: vec![],
+        }
+    }
+}
+
+/// How a new connection from an address is treated
+#[derive(Clone,Debug,PartialEq)]
+pub enum Admission {
+    Now,
+    /// greet the client only after a delay
+    After(Duration),
+    /// refuse the connection with this message
+    Blocked(String),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// The failed logins of every address, shared by a listener's connections
+#[derive(Clone,Debug)]
+pub struct Tarpit {
+    config: Arc<TarpitConfig>,
+    hosts: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+}
+
+impl Tarpit {
+
+    pub fn new(config: TarpitConfig) -> Self {
+        Tarpit { config: Arc::new(config), hosts: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// How to treat a connection from `ip`
+    pub fn admit(&self, ip: IpAddr) -> Admission {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        let (count, blocked_until) = match hosts.get(&ip) {
+            Some(failures) if self.remembered(failures, now) => (failures.count, failures.blocked_until),
+            Some(_) => {
+                hosts.remove(&ip);
+                return Admission::Now;
+            },
+            None => return Admission::Now,
+        };
+        match blocked_until {
+            Some(until) if now < until => {
+                Admission::Blocked(format!("Host '{}' is blocked because of many connection errors", ip))
+            },
+            _ => {
+                let doublings = count.saturating_sub(1).min(63);
+                let delay_ms = self.config.delay_ms.saturating_mul(1 << doublings).min(self.config.max_delay_ms);
+                Admission::After(Duration::from_millis(delay_ms))
+            },
+        }
+    }
+
+    /// Count a failed login from `ip`
+    pub fn failed(&self, ip: IpAddr) {
+        if self.config.exempt.iter().any(|net| net.contains(ip)) {
+            return;
+        }
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= CLEANUP_THRESHOLD && !hosts.contains_key(&ip) {
+            hosts.retain(|_, failures| self.remembered(failures, now));
+        }
+        let failures = hosts.entry(ip).or_insert(Failures { count: 0, last: now, blocked_until: None });
+        if !self.remembered(failures, now) {
+            *failures = Failures { count: 0, last: now, blocked_until: None };
+        }
+        failures.count += 1;
+        failures.last = now;
+        if self.config.max_failures > 0 && failures.count >= self.config.max_failures && failures.blocked_until.is_none() {
+            warn!("Blocking {} for {} seconds after {} failed logins", ip, self.config.ban_secs, failures.count);
+            failures.blocked_until = Some(now + Duration::from_secs(self.config.ban_secs));
+        }
+    }
+
+    /// Forget the failures of `ip` after a successful login
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.hosts.lock().unwrap().remove(&ip);
+    }
+
+    /// Whether an address's failures still count: until the window after the last one has
+    /// passed, and for a blocked address until the block has ended too
+    fn remembered(&self, failures: &Failures, now: Instant) -> bool {
+        let window_end = failures.last + Duration::from_secs(self.config.window_secs);
+        match failures.blocked_until {
+            Some(until) => now < until,
+            None => now < window_end,
+        }
+    }
+}
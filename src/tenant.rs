@@ -0,0 +1,280 @@
+//! Per-tenant virtual databases on a shared backend.
+//!
+//! Each tenant's schemas live on the backend under a prefix, e.g. `tenant_42_app`, while the
+//! tenant's clients see and use plain `app`. `TenantHandler` rewrites the schema names in
+//! what clients send, such as `USE app`, `COM_INIT_DB` and qualified names like `app.orders`
+//! in queries and prepared statements, and strips the prefix again from result metadata,
+//! result values that name one of the tenant's schemas and error messages.
+//!
+//! Only the schemas listed for the tenant are rewritten, so `information_schema` and other
+//! shared schemas keep their names. String literals aren't rewritten either, e.g. in
+//! `WHERE table_schema = 'app'`. Isolation between tenants relies on the backend user's
+//! grants, which should cover only the tenant's own prefixed schemas.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::codec::{text_row_packet, ColumnDefinition, ErrPacket, QueryResponse,
+                   QueryResponseDecoder, MAX_PAYLOAD_LEN};
+use super::pipeline::{Correlator, ResponseKind};
+use super::protocol::CLIENT_PROTOCOL_41;
+use super::sql::{tokenize, Token};
+
+/// The backend schemas of a tenant, e.g. `prefix = "tenant_{user}_"` and `schemas = ["app"]`
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct TenantSchemas {
+    /// prepended to schema names on the backend, `{user}` is replaced by the proxy user
+    pub prefix: String,
+    /// the schema names clients use
+    pub schemas: Vec<String>,
+}
+
+impl TenantSchemas {
+
+    /// These schemas with `{user}` in the prefix replaced by `user`
+    pub fn for_user(&self, user: &str) -> TenantSchemas {
+        TenantSchemas {
+            prefix: self.prefix.replace("{user}", user),
+            schemas: self.schemas.clone(),
+        }
+    }
+
+    /// The backend name of a schema the client named, unchanged unless it is the tenant's
+    pub fn to_backend(&self, name: &str) -> String {
+        if self.schemas.iter().any(|s| s == name) {
+            format!("{}{}", self.prefix, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// The name the client knows a backend schema by, unchanged unless it is the tenant's
+    pub fn to_client<'a>(&self, name: &'a str) -> &'a str {
+        match name.strip_prefix(&self.prefix[..]) {
+            Some(stripped) if self.schemas.iter().any(|s| s == stripped) => stripped,
+            _ => name,
+        }
+    }
+
+    /// Rewrite the tenant's schema names in a statement, or `None` if it names none of them
+    pub fn rewrite_query(&self, sql: &str) -> Option<String> {
+        let tokens = tokenize(sql);
+        let mut replace: Vec<(usize, usize, String)> = vec![];
+        let is_show = tokens.first().map(|t| t.is_keyword("show")).unwrap_or(false);
+        for (i, token) in tokens.iter().enumerate() {
+            let name = match token.name {
+                Some(ref name) if self.schemas.contains(name) => name,
+                _ => continue,
+            };
+            let keyword = |n: usize, keywords: &[&str]| i >= n && keywords.iter().any(|k| tokens[i - n].is_keyword(k));
+            let punct = |at: Option<&Token>, c: u8| at.map(|t| t.is_punct(c)).unwrap_or(false);
+            // app.orders, but not the column of o.app.id
+            let qualifies = punct(tokens.get(i + 1), b'.') && !(i > 0 && punct(tokens.get(i - 1), b'.'));
+            // USE app, CREATE DATABASE IF NOT EXISTS app
+            let named = keyword(1, &["use", "database", "schema"])
+                || (keyword(1, &["exists"]) && (keyword(3, &["database", "schema"]) || keyword(4, &["database", "schema"])));
+            // SHOW TABLES FROM app, SHOW COLUMNS FROM orders IN app
+            let shown = is_show && keyword(1, &["from", "in"])
+                && (keyword(2, &["tables", "status", "triggers", "events"])
+                    || (i >= 3 && tokens[i - 2].name.is_some() && keyword(3, &["from", "in"])));
+            if qualifies || named || shown {
+                let backend = self.to_backend(name).replace('`', "``");
+                replace.push((token.start, token.end, format!("`{}`", backend)));
+            }
+        }
+        if replace.is_empty() {
+            return None;
+        }
+        let mut rewritten = String::with_capacity(sql.len() + replace.len() * (self.prefix.len() + 2));
+        let mut from = 0;
+        for (start, end, name) in replace {
+            rewritten.push_str(&sql[from..start]);
+            rewritten.push_str(&name);
+            from = end;
+        }
+        rewritten.push_str(&sql[from..]);
+        Some(rewritten)
+    }
+
+    /// Replace backend schema names in a message with the client's
+    fn strip_message(&self, message: &str) -> String {
+        let mut message = message.to_string();
+        for schema in &self.schemas {
+            message = message.replace(&format!("{}{}", self.prefix, schema), schema);
+        }
+        message
+    }
+}
+
+/// Wraps another handler and maps the tenant's schema names between clients and the backend
+pub struct TenantHandler<H: PacketHandler> {
+    tenant: TenantSchemas,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    /// follows the response to a COM_QUERY, to tell column definitions from rows
+    query: Option<QueryResponseDecoder>,
+    inner: H,
+}
+
+impl<H> TenantHandler<H> where H: PacketHandler {
+
+    pub fn new(tenant: TenantSchemas, inner: H) -> Self {
+        TenantHandler {
+            tenant,
+            capability_flags: CLIENT_PROTOCOL_41,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            query: None,
+            inner,
+        }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    fn rewrite_request(&self, p: &Packet) -> Option<Packet> {
+        let payload = p.payload();
+        if p.sequence_id() != 0 || payload.is_empty() {
+            return None;
+        }
+        let rewritten = match p.packet_type() {
+            Ok(PacketType::ComInitDb) | Ok(PacketType::ComCreateDb) | Ok(PacketType::ComDropDb) => {
+                let name = String::from_utf8_lossy(&payload[1..]);
+                let backend = self.tenant.to_backend(&name);
+                if backend == name {
+                    return None;
+                }
+                backend
+            },
+            // statements split over several packets are left alone rather than re-split
+            Ok(PacketType::ComQuery) | Ok(PacketType::ComStmtPrepare) if payload.len() < MAX_PAYLOAD_LEN => {
+                self.tenant.rewrite_query(&String::from_utf8_lossy(&payload[1..]))?
+            },
+            _ => return None,
+        };
+        let mut mutated = Vec::with_capacity(1 + rewritten.len());
+        mutated.push(payload[0]);
+        mutated.extend_from_slice(rewritten.as_bytes());
+        if mutated.len() >= MAX_PAYLOAD_LEN {
+            return None;
+        }
+        Some(Packet::new(0, &mutated))
+    }
+
+    fn rewrite_response(&mut self, p: &Packet) -> Option<Packet> {
+        let answered = self.correlator.response(p)?;
+        let query = if answered.command == PacketType::ComQuery.byte() {
+            let capability_flags = self.capability_flags;
+            let decoded = self.query.get_or_insert_with(|| QueryResponseDecoder::new(capability_flags)).decode(p);
+            if answered.last {
+                self.query = None;
+            }
+            // give up on responses the decoder can't follow, e.g. split packets
+            match decoded {
+                Ok(decoded) => Some(decoded),
+                Err(_) => return None,
+            }
+        } else {
+            None
+        };
+
+        match (answered.kind, query) {
+            (ResponseKind::Err, _) => {
+                let err = ErrPacket::parse(p).ok()?;
+                let message = self.tenant.strip_message(&err.message);
+                if message == err.message {
+                    return None;
+                }
+                Some(Packet::error_packet(err.code, err.state.unwrap_or(*b"HY000"), message).with_sequence_id(p.sequence_id()))
+            },
+            (ResponseKind::Data, Some(QueryResponse::Column(column))) => self.rewrite_column(column, p),
+            (ResponseKind::Data, Some(QueryResponse::Row(row))) => {
+                let mut changed = false;
+                let row = row.into_iter().map(|value| value.map(|value| {
+                    match String::from_utf8(value) {
+                        Ok(s) => {
+                            let client = self.tenant.to_client(&s);
+                            changed |= client.len() != s.len();
+                            client.as_bytes().to_vec()
+                        },
+                        Err(e) => e.into_bytes(),
+                    }
+                })).collect::<Vec<_>>();
+                if changed { Some(text_row_packet(p.sequence_id(), &row)) } else { None }
+            },
+            // column definitions of prepared statements and COM_FIELD_LIST, binary rows start
+            // with 0x00 instead
+            (ResponseKind::Data, None) if p.payload().starts_with(b"\x03def") => {
+                let column = ColumnDefinition::parse(p).ok()?;
+                self.rewrite_column(column, p)
+            },
+            _ => None,
+        }
+    }
+
+    fn rewrite_column(&self, mut column: ColumnDefinition, p: &Packet) -> Option<Packet> {
+        let schema = self.tenant.to_client(&column.schema);
+        if schema.len() == column.schema.len() {
+            return None;
+        }
+        column.schema = schema.to_string();
+        Some(column.to_packet(p.sequence_id()))
+    }
+}
+
+impl<H> PacketHandler for TenantHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        match self.phase.phase() {
+            ConnectionPhase::Handshake => {
+                // the handshake response starts with the client's capabilities
+                if p.sequence_id() == 1 && p.payload().len() >= 32 {
+                    self.capability_flags = LittleEndian::read_u32(p.payload());
+                    self.correlator.set_capabilities(self.capability_flags);
+                }
+            },
+            ConnectionPhase::Command => {
+                let action = match self.rewrite_request(p) {
+                    Some(rewritten) => match self.inner.handle_request(&rewritten) {
+                        Action::Forward => Action::Mutate(rewritten),
+                        action => action,
+                    },
+                    None => self.inner.handle_request(p),
+                };
+                // only commands the backend will answer
+                match action {
+                    Action::Forward => self.correlator.request(p),
+                    Action::Mutate(ref p2) => self.correlator.request(p2),
+                    _ => {},
+                }
+                return action;
+            },
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        if phase == ConnectionPhase::Command {
+            if let Some(rewritten) = self.rewrite_response(p) {
+                return match self.inner.handle_response(&rewritten) {
+                    Action::Forward => Action::Mutate(rewritten),
+                    action => action,
+                };
+            }
+        }
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,483 @@
+//! Testing packet handlers without sockets.
+//!
+//! `HandlerTester` plays the part of a `Pipe` around a handler: it feeds it the packets of
+//! a script, in the order the client and the server sent them, applies the actions it
+//! returns the way the pipe would, including holding its own responses to pipelined
+//! commands until the commands before them are answered, and collects the packets that
+//! would have been written to each side. A script can be written out by hand as `Step`s or
+//! read from a packet dump of a real connection with `read_script`. `Forward` is the handler
+//! to wrap when only the handler around it is under test, and `SessionBuilder` makes the
+//! `Session` handlers built for a logged-in client are given.
+//!
+//! To test a whole session instead, client, `Pipe` and server, `duplex` makes in-memory
+//! connections to relay between, which run the same way every time, so partial writes, slow
+//! clients and timeouts can be tested without sockets:
+//!
+//! ```ignore
+//! let (client, proxy_client) = duplex(64 * 1024);
+//! let (proxy_server, server) = duplex(64 * 1024);
+//! let pipe = Pipe::new(Rc::new(proxy_client), Rc::new(proxy_server), handler);
+//! client.send(&Packet::com_query("SELECT 1"));
+//! // poll the pipe, e.g. on a Core, then answer as the server
+//! server.send(&ok);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Error, ErrorKind, Result};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use futures::Async;
+use futures::task::{self, Task};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PhaseTracker, Transport};
+use super::auth::Session;
+use super::codec;
+use super::connect::BackendAddr;
+use super::dump::{Direction, PacketDump};
+use super::labels::Labels;
+use super::pipeline::{Correlator, HeldResponses};
+
+/// A packet sent by the client or by the server
+#[derive(Debug,PartialEq)]
+pub enum Step {
+    Request(Packet),
+    Response(Packet),
+}
+
+/// Read the packets dumped for `connection`, or for the only connection in the dump, as a
+/// script. Fails if a packet's payload was cut short.
+pub fn read_script<R: BufRead>(reader: R, connection: Option<&str>) -> Result<Vec<Step>> {
+    let mut script = vec![];
+    let mut first: Option<String> = None;
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("Line {}: {}", n + 1, msg));
+        let dump: PacketDump = serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+        match connection {
+            Some(connection) if dump.connection != connection => continue,
+            Some(_) => {},
+            None => match first {
+                Some(ref first) if *first != dump.connection => {
+                    return Err(invalid(format!("more than one connection, {} and {}", first, dump.connection)));
+                },
+                Some(_) => {},
+                None => first = Some(dump.connection.clone()),
+            },
+        }
+        let p = dump.packet().ok_or_else(|| invalid(format!("the payload of the {} packet was cut short", dump.packet_type)))?;
+        script.push(match dump.direction {
+            Direction::Request => Step::Request(p),
+            Direction::Response => Step::Response(p),
+        });
+    }
+    Ok(script)
+}
+
+/// Drives a handler through a script, collecting what it sends each way
+pub struct HandlerTester<H: PacketHandler> {
+    handler: H,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    held: HeldResponses,
+    to_client: Vec<Packet>,
+    to_server: Vec<Packet>,
+}
+
+impl<H> HandlerTester<H> where H: PacketHandler {
+
+    pub fn new(handler: H) -> Self {
+        HandlerTester {
+            handler,
+            phase: PhaseTracker::new(),
+            correlator: Correlator::default(),
+            held: HeldResponses::default(),
+            to_client: vec![],
+            to_server: vec![],
+        }
+    }
+
+    /// Follow responses as sent to a server logged in to with `capability_flags`, for
+    /// scripts that start after the handshake
+    pub fn with_backend_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    /// Pass a packet from the client to the handler, returning its action
+    pub fn request(&mut self, p: Packet) -> Action {
+        self.phase.observe_request(&p);
+        // the handshake response starts with the client's capabilities
+        if self.phase.phase() == ConnectionPhase::Handshake && p.sequence_id() == 1 && p.payload().len() >= 32 {
+            self.correlator.set_capabilities(LittleEndian::read_u32(p.payload()));
+        }
+        let action = self.handler.handle_request(&p);
+        match action {
+            Action::Drop => {},
+            Action::Forward => self.forward(copy(&p)),
+            Action::Mutate(ref p2) => self.forward(copy(p2)),
+            Action::Respond(ref v) => self.respond(codec::chunk_response(v.iter().map(copy).collect())),
+            Action::Error { code, state, ref msg } => self.respond(vec![Packet::error_packet(code, state, msg.clone())]),
+        }
+        action
+    }
+
+    /// Pass a packet from the server to the handler, returning its action
+    pub fn response(&mut self, p: Packet) -> Action {
+        self.phase.observe_response(&p);
+        let answered = self.correlator.response(&p);
+        let action = self.handler.handle_response(&p);
+        match action {
+            Action::Drop => {},
+            Action::Forward => self.to_client.push(copy(&p)),
+            Action::Mutate(ref p2) => self.to_client.push(copy(p2)),
+            Action::Respond(ref v) => self.to_server.extend(v.iter().map(copy)),
+            Action::Error { code, state, ref msg } => self.to_client.push(Packet::error_packet(code, state, msg.clone())),
+        }
+        if answered.map(|r| r.last).unwrap_or(false) {
+            let released = self.held.release(&self.correlator);
+            self.to_client.extend(released);
+        }
+        action
+    }
+
+    /// Play a script, returning the handler's action for each step
+    pub fn run<I>(&mut self, script: I) -> Vec<Action> where I: IntoIterator<Item = Step> {
+        script.into_iter().map(|step| match step {
+            Step::Request(p) => self.request(p),
+            Step::Response(p) => self.response(p),
+        }).collect()
+    }
+
+    /// The packets written to the client since last asked
+    pub fn to_client(&mut self) -> Vec<Packet> {
+        self.to_client.drain(..).collect()
+    }
+
+    /// The packets written to the server since last asked
+    pub fn to_server(&mut self) -> Vec<Packet> {
+        self.to_server.drain(..).collect()
+    }
+
+    /// Commands sent to the server that haven't been answered completely
+    pub fn in_flight(&self) -> usize {
+        self.correlator.depth()
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    fn forward(&mut self, p: Packet) {
+        self.correlator.request(&p);
+        self.to_server.push(p);
+    }
+
+    fn respond(&mut self, packets: Vec<Packet>) {
+        let now = self.held.respond(&self.correlator, packets);
+        self.to_client.extend(now);
+    }
+}
+
+fn copy(p: &Packet) -> Packet {
+    Packet { bytes: p.bytes.clone() }
+}
+
+/// A handler that forwards every packet, for wrapping in the handler under test
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Forward;
+
+impl PacketHandler for Forward {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+}
+
+/// Makes a `Session` as if user `app` had logged in from 127.0.0.1 and been sent to the
+/// `primary` group's 127.0.0.1:3306, changing only what a test sets
+pub struct SessionBuilder {
+    session: Session,
+}
+
+impl SessionBuilder {
+
+    pub fn new() -> Self {
+        SessionBuilder {
+            session: Session {
+                connection_id: 1,
+                user: "app".to_string(),
+                backend_user: "app".to_string(),
+                group: "primary".to_string(),
+                backend: "127.0.0.1:3306".parse().unwrap(),
+                backend_name: BackendAddr::new("127.0.0.1", 3306),
+                backend_capabilities: 0,
+                character_set: 0x21,
+                database: None,
+                tls_identity: None,
+                client: "127.0.0.1:40000".parse().unwrap(),
+                connect_attrs: vec![],
+                tenant: None,
+                schemas: None,
+                attributes: HashMap::new(),
+                quota: None,
+                client_compressed: false,
+                labels: Labels::default(),
+                auth_passthrough: false,
+            },
+        }
+    }
+
+    pub fn connection_id(mut self, connection_id: u32) -> Self {
+        self.session.connection_id = connection_id;
+        self
+    }
+
+    /// The user the client logged in to the proxy as, and to the backend as unless set
+    /// with `backend_user`
+    pub fn user(mut self, user: &str) -> Self {
+        self.session.user = user.to_string();
+        self.session.backend_user = user.to_string();
+        self
+    }
+
+    pub fn backend_user(mut self, backend_user: &str) -> Self {
+        self.session.backend_user = backend_user.to_string();
+        self
+    }
+
+    pub fn group(mut self, group: &str) -> Self {
+        self.session.group = group.to_string();
+        self
+    }
+
+    pub fn backend(mut self, host: &str, port: u16) -> Self {
+        self.session.backend = format!("{}:{}", host, port).parse().expect("an IP address");
+        self.session.backend_name = BackendAddr::new(host, port);
+        self
+    }
+
+    pub fn client(mut self, client: SocketAddr) -> Self {
+        self.session.client = client;
+        self
+    }
+
+    pub fn database(mut self, database: &str) -> Self {
+        self.session.database = Some(database.to_string());
+        self
+    }
+
+    pub fn connect_attrs(mut self, connect_attrs: &[(&str, &str)]) -> Self {
+        self.session.connect_attrs = connect_attrs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        self
+    }
+
+    pub fn attribute(mut self, name: &str, value: &str) -> Self {
+        self.session.attributes.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn build(self) -> Session {
+        self.session
+    }
+}
+
+impl Default for SessionBuilder {
+    fn default() -> Self {
+        SessionBuilder::new()
+    }
+}
+
+/// Bytes written one way over a `duplex` connection, waiting to be read
+struct Channel {
+    data: VecDeque<u8>,
+    /// the most bytes waiting at once, beyond which writes are partial or would block
+    capacity: usize,
+    /// the writing end has shut down, or the reading end won't read any more
+    closed: bool,
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+impl Channel {
+
+    fn new(capacity: usize) -> Rc<RefCell<Channel>> {
+        Rc::new(RefCell::new(Channel { data: VecDeque::new(), capacity, closed: false, reader: None, writer: None }))
+    }
+
+    fn room(&self) -> usize {
+        self.capacity.saturating_sub(self.data.len())
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Open an in-memory connection whose ends each buffer at most `capacity` bytes the other has
+/// yet to read
+pub fn duplex(capacity: usize) -> (DuplexEnd, DuplexEnd) {
+    let (a, b) = (Channel::new(capacity), Channel::new(capacity));
+    (DuplexEnd { incoming: a.clone(), outgoing: b.clone() }, DuplexEnd { incoming: b, outgoing: a })
+}
+
+/// One end of an in-memory connection made by `duplex`.
+///
+/// As a `Transport` it behaves like a socket on a reactor: reads and writes that would block
+/// return `WouldBlock` and wake the task once the other end has written or read, writes only
+/// take as much as there is room for, and shutting down writes ends the other end's reads.
+/// Its inherent methods play the other part of a scenario from the test itself, outside any
+/// task, so they never block: `send` writes whole packets however full the connection is, and
+/// `recv` takes the complete packets waiting. Nothing depends on the network or another
+/// thread, so a client, a `Pipe` and a server run the same way every time.
+#[derive(Clone)]
+pub struct DuplexEnd {
+    incoming: Rc<RefCell<Channel>>,
+    outgoing: Rc<RefCell<Channel>>,
+}
+
+impl DuplexEnd {
+
+    /// Write `p` to the other end
+    pub fn send(&self, p: &Packet) {
+        self.send_bytes(&p.bytes);
+    }
+
+    /// Write `bytes` to the other end, e.g. part of a packet
+    pub fn send_bytes(&self, bytes: &[u8]) {
+        let mut outgoing = self.outgoing.borrow_mut();
+        outgoing.data.extend(bytes);
+        outgoing.wake_reader();
+    }
+
+    /// Take the complete packets the other end has written, leaving any incomplete one
+    pub fn recv(&self) -> Vec<Packet> {
+        let mut incoming = self.incoming.borrow_mut();
+        let mut packets = vec![];
+        while incoming.data.len() >= 4 {
+            let header: Vec<u8> = incoming.data.iter().take(4).cloned().collect();
+            let len = 4 + LittleEndian::read_uint(&header, 3) as usize;
+            if incoming.data.len() < len {
+                break;
+            }
+            packets.push(Packet { bytes: incoming.data.drain(..len).collect() });
+        }
+        incoming.wake_writer();
+        packets
+    }
+
+    /// How many bytes the other end has written that haven't been read
+    pub fn pending(&self) -> usize {
+        self.incoming.borrow().data.len()
+    }
+
+    /// Buffer at most `capacity` unread bytes from the other end from now on, e.g. 0 for a
+    /// consumer that has stopped reading
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut incoming = self.incoming.borrow_mut();
+        incoming.capacity = capacity;
+        incoming.wake_writer();
+    }
+
+    /// Close the connection from this end, as a peer closing its socket would
+    pub fn close(&self) {
+        for channel in &[&self.incoming, &self.outgoing] {
+            let mut channel = channel.borrow_mut();
+            channel.closed = true;
+            channel.wake_reader();
+            channel.wake_writer();
+        }
+    }
+
+    /// Whether the other end has shut down its writes
+    pub fn is_closed(&self) -> bool {
+        self.incoming.borrow().closed
+    }
+}
+
+impl Transport for DuplexEnd {
+
+    fn poll_read(&self) -> Async<()> {
+        let mut incoming = self.incoming.borrow_mut();
+        if !incoming.data.is_empty() || incoming.closed {
+            return Async::Ready(());
+        }
+        incoming.reader = Some(task::current());
+        Async::NotReady
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        if outgoing.room() > 0 || outgoing.closed {
+            return Async::Ready(());
+        }
+        outgoing.writer = Some(task::current());
+        Async::NotReady
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.data.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            incoming.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.data.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.data.drain(..n)) {
+            *b = byte;
+        }
+        incoming.wake_writer();
+        Ok(n)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.borrow_mut();
+        if outgoing.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let n = buf.len().min(outgoing.room());
+        if n == 0 && !buf.is_empty() {
+            outgoing.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        outgoing.data.extend(&buf[..n]);
+        outgoing.wake_reader();
+        Ok(n)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut outgoing = self.outgoing.borrow_mut();
+            outgoing.closed = true;
+            outgoing.wake_reader();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Where the time of each exchange goes: in the proxy, at the backend or on the way to the
+//! client.
+//!
+//! `ExchangeTimer` follows every command a session sends on to its backend, from when the pipe
+//! takes it off the client's connection until the last packet of its response has been
+//! written back, and splits that time in three:
+//!
+//! * `queued`, until the command has been written to the backend, which is the proxy's own
+//!   overhead on the way there, including any time the backend's socket wasn't writable;
+//! * `backend`, from then until the last packet of the response has been read, which the
+//!   proxy can do nothing about;
+//! * `client_write`, from then until the response has been written to the client, which is
+//!   the proxy's overhead on the way back, including time the flush policy held it for and
+//!   time the client was slow to read.
+//!
+//! The times are added to `Stats` as histograms, in microseconds, and served along with the
+//! other metrics at `/metrics`, so it takes a glance to tell whether the proxy adds anything
+//! worth mentioning to a slow query. Commands answered from the metadata cache or by the
+//! handler, and the proxy's own, aren't timed.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::stats::Stats;
+
+/// Most commands timed at once, beyond which a pipelining client's aren't
+pub const MAX_TIMED: usize = 1024;
+
+/// How long an exchange spent in each stage
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct ExchangeTiming {
+    pub queued: Duration,
+    pub backend: Duration,
+    pub client_write: Duration,
+}
+
+/// When a command reached each stage
+#[derive(Debug)]
+struct Stamps {
+    /// the number of the command, as counted by the `Correlator`
+    command: u64,
+    taken: Instant,
+    sent: Option<Instant>,
+    answered: Option<Instant>,
+}
+
+/// Times the exchanges of a session, and records them in `Stats`
+pub struct ExchangeTimer {
+    stats: Stats,
+    /// when the command being handled was taken off the client's connection
+    taken: Option<Instant>,
+    /// the oldest command first
+    exchanges: VecDeque<Stamps>,
+}
+
+impl ExchangeTimer {
+
+    pub fn new(stats: Stats) -> Self {
+        ExchangeTimer { stats, taken: None, exchanges: VecDeque::new() }
+    }
+
+    /// Note a packet was taken off the client's connection just now
+    pub fn take(&mut self) {
+        self.taken = Some(Instant::now());
+    }
+
+    /// Note the packet taken last was queued for the backend as command number `command`
+    pub fn request(&mut self, command: u64) {
+        if self.exchanges.len() >= MAX_TIMED {
+            return;
+        }
+        let taken = self.taken.take().unwrap_or_else(Instant::now);
+        self.exchanges.push_back(Stamps { command, taken, sent: None, answered: None });
+    }
+
+    /// Note everything queued for the backend has been written
+    pub fn sent(&mut self) {
+        let now = Instant::now();
+        for stamps in self.exchanges.iter_mut().rev().take_while(|s| s.sent.is_none()) {
+            stamps.sent = Some(now);
+        }
+    }
+
+    /// Note the last packet of the response to command number `command` was read
+    pub fn answered(&mut self, command: u64) {
+        if let Some(stamps) = self.exchanges.iter_mut().find(|s| s.command == command) {
+            stamps.answered = Some(Instant::now());
+        }
+    }
+
+    /// Note everything queued for the client has been written, which ends the exchanges
+    /// whose responses were read
+    pub fn written(&mut self) {
+        let now = Instant::now();
+        while self.exchanges.front().map(|s| s.answered.is_some()).unwrap_or(false) {
+            let stamps = self.exchanges.pop_front().unwrap();
+            let (sent, answered) = match (stamps.sent, stamps.answered) {
+                (Some(sent), Some(answered)) => (sent, answered),
+                _ => continue,
+            };
+            self.stats.record_exchange(&ExchangeTiming {
+                queued: sent.duration_since(stamps.taken),
+                backend: answered.duration_since(sent),
+                client_write: now.duration_since(answered),
+            });
+        }
+    }
+}
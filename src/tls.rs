@@ -0,0 +1,350 @@
+//! TLS for client connections, enabled with the `tls` feature.
+//!
+//! MySQL clients ask for TLS by sending an SSLRequest packet in place of their handshake
+//! response, after which the rest of the conversation happens over TLS. `accept` performs
+//! the TLS handshake on such a connection and resolves to a `TlsStream`, which can then be
+//! used anywhere a `TcpStream` would be.
+//!
+//! Connections to backends don't use TLS yet. Settings for them, such as a CA bundle and
+//! client certificate per backend, allowed versions and ciphers, hostname verification and
+//! SNI, are deferred until they do.
+//!
+//! `ServerCertificates` holds a listener's server configuration and rebuilds it from the
+//! certificate, key and client CA files when asked to, or when `watch` sees them change, so
+//! short-lived certificates can be renewed without a restart. Connections already upgraded
+//! keep the certificate they were accepted with, and a reload that fails to load keeps the
+//! configuration in use.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use futures::{Async, Future, Poll};
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use super::Transport;
+use super::config::{CertIdentity, TlsConfig};
+
+/// Build the rustls server configuration described by `config`
+pub fn server_config(config: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let verifier = match config.client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+            let builder = if config.require_client_cert {
+                builder
+            } else {
+                builder.allow_unauthenticated()
+            };
+            builder.build().map_err(invalid_data)?
+        },
+        None if config.require_client_cert => {
+            return Err(Error::new(ErrorKind::InvalidInput, "require_client_cert needs a client_ca"));
+        },
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(server_config))
+}
+
+/// A listener's server configuration, rebuilt when its files change. Clones share it.
+#[derive(Clone)]
+pub struct ServerCertificates {
+    config: TlsConfig,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    /// when the files were last modified, as of the last load
+    modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+}
+
+impl ServerCertificates {
+
+    /// Load the server configuration described by `config`
+    pub fn new(config: &TlsConfig) -> io::Result<Self> {
+        let modified = modified_times(config);
+        Ok(ServerCertificates {
+            config: config.clone(),
+            current: Arc::new(RwLock::new(server_config(config)?)),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    /// The configuration new connections are accepted with
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load the files again, keeping the configuration in use if they can't be loaded
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = modified_times(&self.config);
+        let server_config = server_config(&self.config)?;
+        *self.current.write().unwrap() = server_config;
+        *self.modified.lock().unwrap() = modified;
+        info!("Reloaded the TLS certificate {}", self.config.cert.display());
+        Ok(())
+    }
+
+    /// Load the files again if any was modified since the last load, returning whether they were
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        if modified_times(&self.config) == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+
+    /// Check the files for changes every `interval` on a thread of its own
+    pub fn watch(&self, interval: Duration) -> io::Result<()> {
+        let certificates = self.clone();
+        thread::Builder::new().name("mysql-proxy-tls-reload".to_string()).spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = certificates.reload_if_changed() {
+                warn!("Failed to reload the TLS certificate {}: {}", certificates.config.cert.display(), e);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ServerCertificates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerCertificates").field("config", &self.config).finish()
+    }
+}
+
+/// When the certificate, key and client CA were last modified, `None` for any that can't be read
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    Some(&config.cert).into_iter()
+        .chain(Some(&config.key))
+        .chain(config.client_ca.as_ref())
+        .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+pub(crate) fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("no certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("no private key in {}", path.display())))
+}
+
+fn invalid_data<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Perform the server side of a TLS handshake on a client connection
+pub fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<TlsAccept> {
+    let conn = ServerConnection::new(config).map_err(invalid_data)?;
+    Ok(TlsAccept { stream: Some(TlsStream { tcp: stream, conn: RefCell::new(conn) }) })
+}
+
+/// Future returned by `accept`, resolving once the TLS handshake has completed
+pub struct TlsAccept {
+    stream: Option<TlsStream>,
+}
+
+impl Future for TlsAccept {
+    type Item = TlsStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<TlsStream, Error> {
+        {
+            let stream = self.stream.as_ref().expect("polled TlsAccept after completion");
+            let mut conn = stream.conn.borrow_mut();
+            while conn.is_handshaking() {
+                if conn.wants_write() {
+                    try_nb!(conn.write_tls(&mut &stream.tcp));
+                } else if conn.wants_read() {
+                    let n = try_nb!(conn.read_tls(&mut &stream.tcp));
+                    if n == 0 {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during TLS handshake"));
+                    }
+                    if let Err(e) = conn.process_new_packets() {
+                        // let the client know why, if the socket will take the alert
+                        let _ = conn.write_tls(&mut &stream.tcp);
+                        return Err(invalid_data(e));
+                    }
+                } else {
+                    break;
+                }
+            }
+            try_nb!(flush_tls(&mut conn, &stream.tcp));
+        }
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
+
+/// A client connection secured with TLS
+pub struct TlsStream {
+    tcp: TcpStream,
+    conn: RefCell<ServerConnection>,
+}
+
+impl TlsStream {
+
+    /// The identity named by the client's certificate, if it presented one
+    pub fn peer_identity(&self, source: CertIdentity) -> Option<String> {
+        let conn = self.conn.borrow();
+        let cert = conn.peer_certificates()?.first()?;
+        certificate_identity(cert.as_ref(), source)
+    }
+}
+
+/// The identity `source` names in a DER encoded certificate
+pub fn certificate_identity(der: &[u8], source: CertIdentity) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    match source {
+        CertIdentity::Cn => {
+            let cn = cert.subject().iter_common_name().next()?;
+            cn.as_str().ok().map(|s| s.to_string())
+        },
+        _ => {
+            let san = cert.subject_alternative_name().ok()??;
+            san.value.general_names.iter().filter_map(|name| match (source, name) {
+                (CertIdentity::SanDns, &GeneralName::DNSName(s)) |
+                (CertIdentity::SanEmail, &GeneralName::RFC822Name(s)) |
+                (CertIdentity::SanUri, &GeneralName::URI(s)) => Some(s.to_string()),
+                _ => None,
+            }).next()
+        },
+    }
+}
+
+/// Write out any TLS records rustls has queued, returning WouldBlock if the socket is full
+fn flush_tls(conn: &mut ServerConnection, tcp: &TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(&mut &*tcp)?;
+    }
+    Ok(())
+}
+
+impl Read for &TlsStream {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.conn.borrow_mut();
+        loop {
+            match conn.reader().read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                result => return result,
+            }
+            // no plaintext buffered, so decrypt more from the socket
+            if conn.read_tls(&mut &self.tcp)? == 0 {
+                return Ok(0);
+            }
+            conn.process_new_packets().map_err(invalid_data)?;
+            // processing may have queued alerts or key updates for the peer
+            match flush_tls(&mut conn, &self.tcp) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                result => result?,
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.borrow_mut();
+        // don't accept more plaintext until earlier records have reached the socket
+        flush_tls(&mut conn, &self.tcp)?;
+        let n = conn.writer().write(buf)?;
+        match flush_tls(&mut conn, &self.tcp) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(n),
+            result => result.map(|_| n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        flush_tls(&mut self.conn.borrow_mut(), &self.tcp)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+}
+
+impl Write for TlsStream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(&mut &*self)
+    }
+}
+
+impl AsyncRead for TlsStream {}
+
+impl AsyncWrite for TlsStream {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.conn.borrow_mut().send_close_notify();
+        try_nb!(Write::flush(&mut &*self));
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Transport for TlsStream {
+
+    fn poll_read(&self) -> Async<()> {
+        // plaintext is only ever left buffered in rustls until the socket blocks, so the
+        // socket's readiness is all that matters
+        self.tcp.poll_read()
+    }
+
+    fn poll_write(&self) -> Async<()> {
+        self.tcp.poll_write()
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Write::flush(&mut &*self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut conn = self.conn.borrow_mut();
+            conn.send_close_notify();
+            let _ = flush_tls(&mut conn, &self.tcp);
+        }
+        self.tcp.shutdown(how)
+    }
+}
@@ -0,0 +1,171 @@
+//! Typed answers to the statements that describe a backend's place in a topology.
+//!
+//! Health checks and discovery need to know whether a backend replicates, how far behind it
+//! is, how it's configured and which members its replication group has. The helpers here run
+//! `SHOW REPLICA STATUS`, `SHOW GLOBAL VARIABLES` and queries of `performance_schema` over a
+//! `SideChannel` and decode their results into structs, by column name, so the callers don't
+//! depend on column order or on which server version renamed what. They block, so run them
+//! on a thread of their own rather than on a reactor.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+
+use super::admin::ER_PARSE_ERROR;
+use super::sidechannel::{ResultSet, SideChannel};
+
+/// The members of a Group Replication or InnoDB Cluster group, as any member sees them
+pub const GROUP_MEMBERS_QUERY: &str = "SELECT MEMBER_ID, MEMBER_HOST, MEMBER_PORT, MEMBER_STATE, MEMBER_ROLE \
+                                       FROM performance_schema.replication_group_members";
+
+/// A replica's view of one of its replication channels, from `SHOW REPLICA STATUS`
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ReplicaStatus {
+    /// empty for the default channel
+    pub channel: String,
+    pub source_host: String,
+    pub source_port: u16,
+    pub io_running: bool,
+    pub sql_running: bool,
+    /// `None` while the SQL thread isn't running, or the lag can't be told
+    pub seconds_behind_source: Option<u64>,
+    pub last_io_error: Option<String>,
+    pub last_sql_error: Option<String>,
+    pub executed_gtid_set: Option<String>,
+}
+
+impl ReplicaStatus {
+
+    /// One status per channel, none if the backend isn't a replica. Both the column names of
+    /// MySQL 8.0.22 on and the older `Master`/`Slave` ones, which MariaDB keeps, are read.
+    pub fn from_result_set(result: &ResultSet) -> Result<Vec<Self>> {
+        (0..result.rows.len()).map(|row| {
+            let value = |names: &[&str]| names.iter().find_map(|name| result.value(row, name));
+            let text = |names: &[&str]| value(names).filter(|v| !v.is_empty());
+            let source_host = value(&["Source_Host", "Master_Host"])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Replica status has no source host"))?;
+            Ok(ReplicaStatus {
+                channel: value(&["Channel_Name", "Connection_name"]).unwrap_or_default(),
+                source_host,
+                source_port: value(&["Source_Port", "Master_Port"]).and_then(|p| p.parse().ok()).unwrap_or(0),
+                io_running: value(&["Replica_IO_Running", "Slave_IO_Running"]).map(|v| v == "Yes").unwrap_or(false),
+                sql_running: value(&["Replica_SQL_Running", "Slave_SQL_Running"]).map(|v| v == "Yes").unwrap_or(false),
+                seconds_behind_source: value(&["Seconds_Behind_Source", "Seconds_Behind_Master"]).and_then(|s| s.parse().ok()),
+                last_io_error: text(&["Last_IO_Error"]),
+                last_sql_error: text(&["Last_SQL_Error"]),
+                executed_gtid_set: text(&["Executed_Gtid_Set", "Gtid_Slave_Pos"]),
+            })
+        }).collect()
+    }
+
+    /// Whether both replication threads are running
+    pub fn is_replicating(&self) -> bool {
+        self.io_running && self.sql_running
+    }
+}
+
+/// Server variables by name, from `SHOW VARIABLES`
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Variables {
+    values: BTreeMap<String, String>,
+}
+
+impl Variables {
+
+    pub fn from_result_set(result: &ResultSet) -> Result<Self> {
+        let mut values = BTreeMap::new();
+        for row in 0..result.rows.len() {
+            let name = result.value(row, "Variable_name")
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Variables have no Variable_name column"))?;
+            values.insert(name.to_ascii_lowercase(), result.value(row, "Value").unwrap_or_default());
+        }
+        Ok(Variables { values })
+    }
+
+    /// The value of a variable, whatever the case of its name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(&name.to_ascii_lowercase()).map(|v| &v[..])
+    }
+
+    /// A boolean variable, such as `read_only`, which servers show as `ON` or `OFF`
+    pub fn flag(&self, name: &str) -> Option<bool> {
+        match self.get(name)? {
+            v if v.eq_ignore_ascii_case("ON") || v == "1" => Some(true),
+            v if v.eq_ignore_ascii_case("OFF") || v == "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// A numeric variable, such as `server_id`
+    pub fn number(&self, name: &str) -> Option<u64> {
+        self.get(name)?.parse().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// A member of a replication group, from `performance_schema.replication_group_members`
+#[derive(Clone,Debug,PartialEq)]
+pub struct GroupMember {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    /// e.g. `ONLINE`, `RECOVERING`, `UNREACHABLE`
+    pub state: String,
+    /// `PRIMARY` or `SECONDARY`, empty before MySQL 8.0.2
+    pub role: String,
+}
+
+impl GroupMember {
+
+    pub fn from_result_set(result: &ResultSet) -> Result<Vec<Self>> {
+        (0..result.rows.len()).map(|row| {
+            let host = result.value(row, "MEMBER_HOST")
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Group member has no host"))?;
+            Ok(GroupMember {
+                id: result.value(row, "MEMBER_ID").unwrap_or_default(),
+                host,
+                port: result.value(row, "MEMBER_PORT").and_then(|p| p.parse().ok()).unwrap_or(3306),
+                state: result.value(row, "MEMBER_STATE").unwrap_or_default(),
+                role: result.value(row, "MEMBER_ROLE").unwrap_or_default(),
+            })
+        }).collect()
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state == "ONLINE"
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.role == "PRIMARY"
+    }
+}
+
+/// The status of each of a backend's replication channels, none if it isn't a replica. Servers
+/// older than MySQL 8.0.22 are asked with `SHOW SLAVE STATUS` instead.
+pub fn replica_status(channel: &SideChannel, backend: SocketAddr) -> Result<Vec<ReplicaStatus>> {
+    let result = match channel.query_blocking(backend, "SHOW REPLICA STATUS") {
+        Err(ref e) if e.to_string().starts_with(&format!("ERROR {} ", ER_PARSE_ERROR)) => {
+            channel.query_blocking(backend, "SHOW SLAVE STATUS")?
+        },
+        result => result?,
+    };
+    ReplicaStatus::from_result_set(&result)
+}
+
+/// The global variables whose names match a `LIKE` pattern, e.g. `%read_only`
+pub fn global_variables(channel: &SideChannel, backend: SocketAddr, like: &str) -> Result<Variables> {
+    let sql = format!("SHOW GLOBAL VARIABLES LIKE '{}'", like.replace('\\', "\\\\").replace('\'', "\\'"));
+    Variables::from_result_set(&channel.query_blocking(backend, &sql)?)
+}
+
+/// The members of the replication group a backend belongs to, none if it's in no group
+pub fn group_members(channel: &SideChannel, backend: SocketAddr) -> Result<Vec<GroupMember>> {
+    GroupMember::from_result_set(&channel.query_blocking(backend, GROUP_MEMBERS_QUERY)?)
+}
@@ -0,0 +1,512 @@
+//! Protocol traces of single connections, for bug reports.
+//!
+//! A packet dump shows what was said, which is often more than can be shared. A trace only
+//! keeps the shape of a connection: for each packet, when it passed through the proxy, its
+//! direction, the connection's phase, its sequence id and length, what kind of packet it was
+//! and which command it answers, what the proxy did with it, and the first 8 bytes of a
+//! SHA-256 of its payload, so repeated packets can be told apart without their contents.
+//! Anomalies the protocol checks reported are kept in line with the packets. That's usually
+//! enough to work out offline how a client, the proxy and a backend fell out of step.
+//!
+//! With a `[trace]` section, `TraceHandler` writes a trace per traced connection to `dir`,
+//! for the `users` listed or every user if there are none. `Trace::read` reads one back, and
+//! `mysql-proxy print-trace` prints it as a timeline.
+//!
+//! A trace is a compact binary file, with little endian integers: the magic `MPTRACE`, a
+//! format version byte, the time the trace started in milliseconds since the epoch as a u64,
+//! and the connection's label as a u16 length and UTF-8 bytes. Records follow, each starting
+//! with a tag byte: `1` for a packet, followed by the microseconds since the start as a u64,
+//! the direction, phase and sequence id bytes, the payload length as a u32, the kind byte, a
+//! flag byte and command byte for the command, the action byte and the 8 hash bytes; `2` for
+//! an anomaly, followed by the microseconds since the start, the anomaly kind byte and the
+//! verdict byte.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, AnomalyKind, Verdict};
+use super::dump::Direction;
+use super::pipeline::{Correlator, ResponseKind};
+
+/// The start of every trace file
+pub const MAGIC: &[u8; 7] = b"MPTRACE";
+
+/// The version of the format written
+pub const VERSION: u8 = 1;
+
+const TAG_PACKET: u8 = 1;
+const TAG_ANOMALY: u8 = 2;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct TraceConfig {
+    /// the directory trace files are written to
+    pub dir: PathBuf,
+    /// the users whose connections are traced, every user's if empty
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+impl TraceConfig {
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.dir.as_os_str().is_empty() {
+            return Err("dir must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether `user`'s connections are traced
+    pub fn traces(&self, user: &str) -> bool {
+        self.users.is_empty() || self.users.iter().any(|u| u == user)
+    }
+
+    /// Start a trace file in `dir` for a connection of `user` from `client`
+    pub fn create(&self, user: &str, client: SocketAddr) -> io::Result<TraceWriter> {
+        fs::create_dir_all(&self.dir)?;
+        let label = format!("{}@{}", user, client);
+        let started_ms = now_ms();
+        let name: String = format!("{}-{}.trace", started_ms, label).chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '@' { c } else { '_' })
+            .collect();
+        TraceWriter::create(&self.dir.join(name), &label)
+    }
+}
+
+/// What kind of packet a trace record is for
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum PacketKind {
+    /// a client packet starting a new sequence
+    Command,
+    /// a client packet continuing a command, e.g. LOCAL INFILE contents
+    Continuation,
+    Ok,
+    Err,
+    Eof,
+    /// any other part of a response, such as column definitions and rows
+    Data,
+    /// a packet of the login exchange
+    Handshake,
+    /// a server packet while no command awaited a response
+    Unsolicited,
+}
+
+impl PacketKind {
+
+    fn byte(self) -> u8 {
+        match self {
+            PacketKind::Command => 0,
+            PacketKind::Continuation => 1,
+            PacketKind::Ok => 2,
+            PacketKind::Err => 3,
+            PacketKind::Eof => 4,
+            PacketKind::Data => 5,
+            PacketKind::Handshake => 6,
+            PacketKind::Unsolicited => 7,
+        }
+    }
+
+    fn from_byte(b: u8) -> io::Result<Self> {
+        Ok(match b {
+            0 => PacketKind::Command,
+            1 => PacketKind::Continuation,
+            2 => PacketKind::Ok,
+            3 => PacketKind::Err,
+            4 => PacketKind::Eof,
+            5 => PacketKind::Data,
+            6 => PacketKind::Handshake,
+            7 => PacketKind::Unsolicited,
+            _ => return Err(invalid(format!("Unknown packet kind {}", b))),
+        })
+    }
+}
+
+/// What the proxy did with a packet
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TracedAction {
+    Drop,
+    Forward,
+    Mutate,
+    Respond,
+    Error,
+}
+
+impl TracedAction {
+
+    fn of(action: &Action) -> Self {
+        match *action {
+            Action::Drop => TracedAction::Drop,
+            Action::Forward => TracedAction::Forward,
+            Action::Mutate(_) => TracedAction::Mutate,
+            Action::Respond(_) => TracedAction::Respond,
+            Action::Error { .. } => TracedAction::Error,
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            TracedAction::Drop => 0,
+            TracedAction::Forward => 1,
+            TracedAction::Mutate => 2,
+            TracedAction::Respond => 3,
+            TracedAction::Error => 4,
+        }
+    }
+
+    fn from_byte(b: u8) -> io::Result<Self> {
+        Ok(match b {
+            0 => TracedAction::Drop,
+            1 => TracedAction::Forward,
+            2 => TracedAction::Mutate,
+            3 => TracedAction::Respond,
+            4 => TracedAction::Error,
+            _ => return Err(invalid(format!("Unknown action {}", b))),
+        })
+    }
+}
+
+/// A packet as traced
+#[derive(Clone,Debug,PartialEq)]
+pub struct PacketRecord {
+    /// microseconds since the trace started
+    pub elapsed_us: u64,
+    pub direction: Direction,
+    pub phase: ConnectionPhase,
+    pub seq: u8,
+    pub length: u32,
+    pub kind: PacketKind,
+    /// the command a client packet starts, or the one a server packet answers
+    pub command: Option<u8>,
+    pub action: TracedAction,
+    /// the first 8 bytes of the SHA-256 of the payload
+    pub hash: [u8; 8],
+}
+
+/// One entry of a trace's timeline
+#[derive(Clone,Debug,PartialEq)]
+pub enum TraceRecord {
+    Packet(PacketRecord),
+    Anomaly { elapsed_us: u64, kind: AnomalyKind, verdict: Verdict },
+}
+
+impl fmt::Display for TraceRecord {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceRecord::Packet(ref p) => {
+                let arrow = match p.direction {
+                    Direction::Request => "->",
+                    Direction::Response => "<-",
+                };
+                write!(f, "{:>12} us {} {:?} seq={} len={} {:?}", p.elapsed_us, arrow, p.phase, p.seq, p.length, p.kind)?;
+                if let Some(command) = p.command {
+                    write!(f, " {}", PacketType::from_u8(command))?;
+                }
+                let hash: String = p.hash.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, " {:?} {}", p.action, hash)
+            },
+            TraceRecord::Anomaly { elapsed_us, kind, verdict } =>
+                write!(f, "{:>12} us !! {} {:?}", elapsed_us, kind.name(), verdict),
+        }
+    }
+}
+
+/// A trace read back from a file
+#[derive(Clone,Debug,PartialEq)]
+pub struct Trace {
+    /// the connection traced, e.g. `app@10.0.0.7:51234`
+    pub label: String,
+    /// when the trace started, in milliseconds since the epoch
+    pub started_ms: u64,
+    pub records: Vec<TraceRecord>,
+}
+
+impl Trace {
+
+    /// Read a whole trace. A trace cut short in its last record, as when the proxy stopped
+    /// mid-write, ends at the last whole record.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 7];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a protocol trace".to_string()));
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(invalid(format!("Unsupported trace version {}", version)));
+        }
+        let started_ms = reader.read_u64::<LittleEndian>()?;
+        let mut label = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+        reader.read_exact(&mut label)?;
+        let label = String::from_utf8(label).map_err(|e| invalid(e.to_string()))?;
+        let mut records = vec![];
+        loop {
+            let tag = match reader.read_u8() {
+                Ok(tag) => tag,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            match read_record(tag, &mut reader) {
+                Ok(record) => records.push(record),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Trace { label, started_ms, records })
+    }
+}
+
+fn read_record<R: Read>(tag: u8, reader: &mut R) -> io::Result<TraceRecord> {
+    let elapsed_us = reader.read_u64::<LittleEndian>()?;
+    match tag {
+        TAG_PACKET => {
+            let direction = match reader.read_u8()? {
+                0 => Direction::Request,
+                1 => Direction::Response,
+                b => return Err(invalid(format!("Unknown direction {}", b))),
+            };
+            let phase = match reader.read_u8()? {
+                0 => ConnectionPhase::Handshake,
+                1 => ConnectionPhase::Command,
+                b => return Err(invalid(format!("Unknown phase {}", b))),
+            };
+            let seq = reader.read_u8()?;
+            let length = reader.read_u32::<LittleEndian>()?;
+            let kind = PacketKind::from_byte(reader.read_u8()?)?;
+            let has_command = reader.read_u8()? != 0;
+            let command = reader.read_u8()?;
+            let action = TracedAction::from_byte(reader.read_u8()?)?;
+            let mut hash = [0; 8];
+            reader.read_exact(&mut hash)?;
+            Ok(TraceRecord::Packet(PacketRecord {
+                elapsed_us,
+                direction,
+                phase,
+                seq,
+                length,
+                kind,
+                command: if has_command { Some(command) } else { None },
+                action,
+                hash,
+            }))
+        },
+        TAG_ANOMALY => {
+            let kind = match reader.read_u8()? {
+                0 => AnomalyKind::BadSequenceId,
+                1 => AnomalyKind::UnexpectedPacket,
+                2 => AnomalyKind::OversizedFrame,
+                3 => AnomalyKind::UnknownCommand,
+                b => return Err(invalid(format!("Unknown anomaly {}", b))),
+            };
+            let verdict = match reader.read_u8()? {
+                0 => Verdict::Tolerate,
+                1 => Verdict::Kill,
+                b => return Err(invalid(format!("Unknown verdict {}", b))),
+            };
+            Ok(TraceRecord::Anomaly { elapsed_us, kind, verdict })
+        },
+        _ => Err(invalid(format!("Unknown record tag {}", tag))),
+    }
+}
+
+/// Writes one connection's trace
+pub struct TraceWriter {
+    writer: Box<dyn Write>,
+    started: Instant,
+    /// set once a write fails, after which nothing more is written
+    failed: bool,
+}
+
+impl TraceWriter {
+
+    /// Start a trace file at `path`
+    pub fn create(path: &Path, label: &str) -> io::Result<Self> {
+        TraceWriter::new(Box::new(BufWriter::new(File::create(path)?)), label)
+    }
+
+    /// Start a trace on any writer
+    pub fn new(mut writer: Box<dyn Write>, label: &str) -> io::Result<Self> {
+        let label = &label.as_bytes()[..label.len().min(u16::MAX as usize)];
+        writer.write_all(MAGIC)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u64::<LittleEndian>(now_ms())?;
+        writer.write_u16::<LittleEndian>(label.len() as u16)?;
+        writer.write_all(label)?;
+        Ok(TraceWriter { writer, started: Instant::now(), failed: false })
+    }
+
+    fn elapsed_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    pub fn packet(&mut self, record: &PacketRecord) {
+        let mut buf = Vec::with_capacity(30);
+        buf.push(TAG_PACKET);
+        let _ = buf.write_u64::<LittleEndian>(record.elapsed_us);
+        buf.push(match record.direction {
+            Direction::Request => 0,
+            Direction::Response => 1,
+        });
+        buf.push(match record.phase {
+            ConnectionPhase::Handshake => 0,
+            ConnectionPhase::Command => 1,
+        });
+        buf.push(record.seq);
+        let _ = buf.write_u32::<LittleEndian>(record.length);
+        buf.push(record.kind.byte());
+        buf.push(record.command.is_some() as u8);
+        buf.push(record.command.unwrap_or(0));
+        buf.push(record.action.byte());
+        buf.extend_from_slice(&record.hash);
+        self.write(&buf);
+    }
+
+    pub fn anomaly(&mut self, kind: AnomalyKind, verdict: Verdict) {
+        let mut buf = Vec::with_capacity(11);
+        buf.push(TAG_ANOMALY);
+        let _ = buf.write_u64::<LittleEndian>(self.elapsed_us());
+        buf.push(match kind {
+            AnomalyKind::BadSequenceId => 0,
+            AnomalyKind::UnexpectedPacket => 1,
+            AnomalyKind::OversizedFrame => 2,
+            AnomalyKind::UnknownCommand => 3,
+        });
+        buf.push(match verdict {
+            Verdict::Tolerate => 0,
+            Verdict::Kill => 1,
+        });
+        self.write(&buf);
+    }
+
+    /// Write out buffered records
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.writer.write_all(buf) {
+            warn!("Failed to write protocol trace, no longer tracing the connection: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        if !self.failed {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+/// Wraps another handler and traces every packet it sees, with what the handler did with it
+pub struct TraceHandler<H: PacketHandler> {
+    writer: TraceWriter,
+    phase: PhaseTracker,
+    correlator: Correlator,
+    inner: H,
+}
+
+impl<H> TraceHandler<H> where H: PacketHandler {
+
+    pub fn new(writer: TraceWriter, inner: H) -> Self {
+        TraceHandler { writer, phase: PhaseTracker::new(), correlator: Correlator::default(), inner }
+    }
+
+    /// Capabilities the backend's responses follow, for handshakes the proxy completed itself
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.correlator.set_capabilities(capability_flags);
+        self
+    }
+
+    fn record(&self, direction: Direction, phase: ConnectionPhase, p: &Packet, kind: PacketKind, command: Option<u8>,
+              action: &Action) -> PacketRecord {
+        let payload = p.payload();
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&Sha256::digest(payload)[..8]);
+        PacketRecord {
+            elapsed_us: self.writer.elapsed_us(),
+            direction,
+            phase,
+            seq: p.sequence_id(),
+            length: payload.len() as u32,
+            kind,
+            command,
+            action: TracedAction::of(action),
+            hash,
+        }
+    }
+}
+
+impl<H> PacketHandler for TraceHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        let phase = self.phase.phase();
+        let (kind, command) = match phase {
+            ConnectionPhase::Handshake => (PacketKind::Handshake, None),
+            ConnectionPhase::Command if p.sequence_id() == 0 => (PacketKind::Command, p.payload().first().cloned()),
+            ConnectionPhase::Command => (PacketKind::Continuation, None),
+        };
+        let action = self.inner.handle_request(p);
+        if phase == ConnectionPhase::Command {
+            // the backend answers what it was sent
+            match action {
+                Action::Forward => self.correlator.request(p),
+                Action::Mutate(ref p2) => self.correlator.request(p2),
+                _ => {},
+            }
+        }
+        let record = self.record(Direction::Request, phase, p, kind, command, &action);
+        self.writer.packet(&record);
+        action
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        let phase = self.phase.phase();
+        self.phase.observe_response(p);
+        let (kind, command) = match phase {
+            ConnectionPhase::Handshake => (PacketKind::Handshake, None),
+            ConnectionPhase::Command => match self.correlator.response(p) {
+                Some(answered) => {
+                    let kind = match answered.kind {
+                        ResponseKind::Ok => PacketKind::Ok,
+                        ResponseKind::Err => PacketKind::Err,
+                        ResponseKind::Eof => PacketKind::Eof,
+                        ResponseKind::Data => PacketKind::Data,
+                    };
+                    (kind, Some(answered.command))
+                },
+                None => (PacketKind::Unsolicited, None),
+            },
+        };
+        let action = self.inner.handle_response(p);
+        let record = self.record(Direction::Response, phase, p, kind, command, &action);
+        self.writer.packet(&record);
+        action
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        let verdict = self.inner.handle_anomaly(anomaly);
+        self.writer.anomaly(anomaly.kind(), verdict);
+        verdict
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
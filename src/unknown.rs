@@ -0,0 +1,64 @@
+//! Commands the proxy doesn't know.
+//!
+//! Servers add commands from release to release and forks have commands of their own, so a
+//! command starting with a byte the proxy doesn't know is a `PacketType::Unknown` and, by
+//! default, is forwarded to the backend untouched, its response expected to be a single
+//! packet. With `unknown_commands = "reject"`, an `UnknownCommandHandler` answers such
+//! commands with `ER_UNKNOWN_COM_ERROR` instead, as a server that doesn't know them would.
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+
+/// MySQL error ER_UNKNOWN_COM_ERROR
+pub const ER_UNKNOWN_COM_ERROR: u16 = 1047;
+
+/// What the proxy does with commands it doesn't know
+#[derive(Copy,Clone,Debug,Deserialize,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownCommandPolicy {
+    /// pass them on to the backend untouched
+    #[default]
+    Forward,
+    /// answer them with an error without involving the backend
+    Reject,
+}
+
+/// Wraps another handler and answers commands the proxy doesn't know with an error
+pub struct UnknownCommandHandler<H: PacketHandler> {
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> UnknownCommandHandler<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        UnknownCommandHandler { phase: PhaseTracker::new(), inner }
+    }
+}
+
+impl<H> PacketHandler for UnknownCommandHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        if self.phase.phase() == ConnectionPhase::Command && p.sequence_id() == 0 {
+            if let Ok(PacketType::Unknown(command)) = p.packet_type() {
+                debug!("Rejecting unknown command 0x{:02x}", command);
+                return Action::Error {
+                    code: ER_UNKNOWN_COM_ERROR,
+                    state: *b"08S01",
+                    msg: "Unknown command".to_string(),
+                };
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
@@ -0,0 +1,244 @@
+//! Reaching backends through an upstream proxy.
+//!
+//! Databases behind a bastion host or a corporate egress proxy can't be connected to
+//! directly. With an `UpstreamProxy` configured, the proxy connects to it instead and asks it
+//! to open a tunnel to the backend, with SOCKS5 (RFC 1928, with RFC 1929 username and
+//! password authentication) or an HTTP `CONNECT` request (with basic authentication). The
+//! backend's host name is resolved by the upstream proxy. With the `ssh` feature, the
+//! upstream can also be an SSH jump host, see the `ssh` module.
+
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use futures::Future;
+use futures::future::{self, Loop};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+use super::connect::{connect_backend, BackendAddr};
+#[cfg(feature = "ssh")]
+use super::ssh;
+
+#[derive(Clone,Copy,Debug,Deserialize,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+    Socks5,
+    /// HTTP `CONNECT`
+    Http,
+    /// an SSH jump host, requires the `ssh` feature
+    Ssh,
+}
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct UpstreamProxy {
+    pub kind: UpstreamKind,
+    /// address of the upstream proxy
+    pub addr: BackendAddr,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// not used for SSH, which only authenticates with keys
+    #[serde(default)]
+    pub password: Option<String>,
+    /// SSH private key, otherwise ssh's default keys and agent are used
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+    /// SSH known hosts file holding the jump host's key, otherwise ssh's default
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+    /// the OpenSSH client to run, `ssh` if not set
+    #[serde(default)]
+    pub ssh_command: Option<String>,
+}
+
+type ConnectFuture = Box<dyn Future<Item = TcpStream, Error = io::Error>>;
+
+/// Connect to `backend`, through `upstream` if there is one
+pub fn connect_through(upstream: Option<&UpstreamProxy>, backend: &BackendAddr, handle: &Handle) -> ConnectFuture {
+    match upstream {
+        Some(upstream) => upstream.connect(backend, handle),
+        None => connect_backend(backend, handle),
+    }
+}
+
+impl UpstreamProxy {
+
+    /// Connect to the upstream proxy and open a tunnel through it to `backend`
+    pub fn connect(&self, backend: &BackendAddr, handle: &Handle) -> ConnectFuture {
+        if self.kind == UpstreamKind::Ssh {
+            return self.connect_ssh(backend, handle);
+        }
+        let upstream = self.clone();
+        let backend = backend.clone();
+        debug!("Connecting to {} through {:?} proxy {}", backend, self.kind, self.addr);
+        Box::new(connect_backend(&self.addr, handle).and_then(move |stream| match upstream.kind {
+            UpstreamKind::Socks5 => socks5_connect(stream, &upstream, &backend),
+            UpstreamKind::Http => http_connect(stream, &upstream, &backend),
+            UpstreamKind::Ssh => unreachable!(),
+        }))
+    }
+
+    #[cfg(feature = "ssh")]
+    fn connect_ssh(&self, backend: &BackendAddr, handle: &Handle) -> ConnectFuture {
+        ssh::connect(self, backend, handle)
+    }
+
+    #[cfg(not(feature = "ssh"))]
+    fn connect_ssh(&self, _backend: &BackendAddr, _handle: &Handle) -> ConnectFuture {
+        Box::new(future::err(Error::new(ErrorKind::Unsupported, "SSH upstreams need the proxy built with the ssh feature")))
+    }
+
+    fn credentials(&self) -> Option<(&str, &str)> {
+        self.username.as_ref().map(|user| (&user[..], self.password.as_ref().map(|p| &p[..]).unwrap_or("")))
+    }
+}
+
+fn socks5_connect(stream: TcpStream, upstream: &UpstreamProxy, backend: &BackendAddr) -> ConnectFuture {
+    const NO_AUTH: u8 = 0x00;
+    const USER_PASS: u8 = 0x02;
+
+    let credentials = upstream.credentials().map(|(u, p)| (u.to_string(), p.to_string()));
+    let methods = match credentials {
+        Some(_) => vec![0x05, 0x02, NO_AUTH, USER_PASS],
+        None => vec![0x05, 0x01, NO_AUTH],
+    };
+    let request = match socks5_request(backend) {
+        Ok(request) => request,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(write_all(stream, methods)
+        .and_then(|(stream, _)| read_exact(stream, [0_u8; 2]))
+        .and_then(move |(stream, reply)| -> ConnectFuture {
+            match (reply, credentials) {
+                ([0x05, NO_AUTH], _) => Box::new(future::ok(stream)),
+                ([0x05, USER_PASS], Some((user, password))) => {
+                    let mut auth = vec![0x01, user.len() as u8];
+                    auth.extend_from_slice(user.as_bytes());
+                    auth.push(password.len() as u8);
+                    auth.extend_from_slice(password.as_bytes());
+                    Box::new(write_all(stream, auth)
+                        .and_then(|(stream, _)| read_exact(stream, [0_u8; 2]))
+                        .and_then(|(stream, status)| match status[1] {
+                            0x00 => Ok(stream),
+                            _ => Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy rejected the credentials")),
+                        }))
+                },
+                _ => Box::new(future::err(Error::new(ErrorKind::PermissionDenied,
+                                                     "SOCKS5 proxy offered no acceptable authentication method"))),
+            }
+        })
+        .and_then(move |stream| write_all(stream, request))
+        .and_then(|(stream, _)| read_exact(stream, [0_u8; 4]))
+        .and_then(|(stream, reply)| -> ConnectFuture {
+            if reply[1] != 0x00 {
+                return Box::new(future::err(Error::new(ErrorKind::ConnectionRefused,
+                    format!("SOCKS5 proxy failed to connect: {}", socks5_error(reply[1])))));
+            }
+            // skip the address the proxy bound to, and its port
+            let skip = match reply[3] {
+                0x01 => future::Either::A(future::ok((stream, 4 + 2))),
+                0x04 => future::Either::A(future::ok((stream, 16 + 2))),
+                _ => future::Either::B(read_exact(stream, [0_u8; 1]).map(|(stream, len)| (stream, len[0] as usize + 2))),
+            };
+            Box::new(skip
+                .and_then(|(stream, n)| read_exact(stream, vec![0_u8; n]))
+                .map(|(stream, _)| stream))
+        }))
+}
+
+/// A CONNECT request for `backend`, passing host names on for the proxy to resolve
+fn socks5_request(backend: &BackendAddr) -> io::Result<Vec<u8>> {
+    let mut request = vec![0x05, 0x01, 0x00];
+    match backend.host().parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) if backend.host().len() <= 255 => {
+            request.push(0x03);
+            request.push(backend.host().len() as u8);
+            request.extend_from_slice(backend.host().as_bytes());
+        },
+        Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Host name too long for SOCKS5")),
+    }
+    request.push((backend.port() >> 8) as u8);
+    request.push(backend.port() as u8);
+    Ok(request)
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn http_connect(stream: TcpStream, upstream: &UpstreamProxy, backend: &BackendAddr) -> ConnectFuture {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", backend);
+    if let Some((user, password)) = upstream.credentials() {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n",
+                                  base64(format!("{}:{}", user, password).as_bytes())));
+    }
+    request.push_str("\r\n");
+
+    Box::new(write_all(stream, request.into_bytes())
+        .and_then(|(stream, _)| read_headers(stream))
+        .and_then(|(stream, headers)| {
+            let status = headers.split_whitespace().nth(1).unwrap_or("");
+            if status == "200" {
+                Ok(stream)
+            } else {
+                let line = headers.lines().next().unwrap_or("").to_string();
+                Err(Error::new(ErrorKind::ConnectionRefused, format!("HTTP proxy refused to connect: {}", line)))
+            }
+        }))
+}
+
+/// Read the response headers a byte at a time, so nothing the backend sends after them is
+/// consumed
+fn read_headers(stream: TcpStream) -> Box<dyn Future<Item = (TcpStream, String), Error = io::Error>> {
+    Box::new(future::loop_fn((stream, Vec::new()), |(stream, mut headers)| {
+        read_exact(stream, [0_u8; 1]).and_then(move |(stream, b)| {
+            headers.push(b[0]);
+            if headers.ends_with(b"\r\n\r\n") {
+                Ok(Loop::Break((stream, String::from_utf8_lossy(&headers).into_owned())))
+            } else if headers.len() > 8192 {
+                Err(Error::new(ErrorKind::InvalidData, "HTTP proxy response headers too long"))
+            } else {
+                Ok(Loop::Continue((stream, headers)))
+            }
+        })
+    }))
+}
+
+/// Standard base64 with padding
+pub fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
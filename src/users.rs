@@ -0,0 +1,160 @@
+//! Mapping of users authenticated at the proxy to backend credentials and routing groups.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::acl::AccessList;
+use super::protocol;
+use super::quota::QuotaConfig;
+use super::schemas::SchemaPolicy;
+use super::tenant::TenantSchemas;
+
+/// Credentials and routing for a single proxy user
+#[derive(Clone,Debug,Default,Deserialize,PartialEq)]
+pub struct UserMapping {
+    /// the user name clients authenticate to the proxy with, or `*` for a mapping that applies
+    /// to any user without one of their own
+    pub user: String,
+    /// the proxy password, either in plain text or as a `*HEX` mysql_native_password hash, or
+    /// a 16 hex digit `OLD_PASSWORD()` hash for `old_password` users. Not used when an
+    /// external authenticator is configured, and required when one isn't.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// the user name the proxy logs in to the backend with
+    #[serde(default)]
+    pub backend_user: String,
+    /// the password the proxy logs in to the backend with
+    #[serde(default)]
+    pub backend_password: String,
+    /// the routing group that sessions for this user are sent to by default
+    pub default_group: String,
+    /// networks this user may connect from, checked after authentication
+    #[serde(default)]
+    pub access: AccessList,
+    /// relay the client's own authentication exchange, such as Kerberos or GSSAPI, to the
+    /// backend instead of checking a proxy password. The client logs in to the backend as
+    /// itself, so the proxy password and backend credentials aren't used.
+    #[serde(default)]
+    pub auth_passthrough: bool,
+    /// authenticate this user's clients with the pre-4.1 mysql_old_password scheme, for legacy
+    /// applications whose accounts newer backends no longer accept. The proxy still logs in
+    /// to the backend with the backend credentials.
+    #[serde(default)]
+    pub old_password: bool,
+    /// give this user virtual databases, kept on the backend under a prefix
+    #[serde(default)]
+    pub tenant: Option<TenantSchemas>,
+    /// pin the user's sessions to a default schema and the schemas it may switch to
+    #[serde(default)]
+    pub schemas: Option<SchemaPolicy>,
+    /// values for `{name}` placeholders in row filters, e.g. `tenant_id = "42"`
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// limits on the user's connections and queries
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+}
+
+impl UserMapping {
+
+    /// The mysql_native_password hash of the proxy password
+    pub fn password_hash(&self) -> String {
+        let password = self.password.as_deref().unwrap_or_default();
+        if password.len() == 41 && password.starts_with('*') {
+            password.to_string()
+        } else {
+            protocol::native_password_hash(password)
+        }
+    }
+
+    /// The mysql_old_password hash of the proxy password
+    pub fn old_password_hash(&self) -> String {
+        let password = self.password.as_deref().unwrap_or_default();
+        if password.len() == 16 && password.chars().all(|c| c.is_ascii_hexdigit()) {
+            password.to_string()
+        } else {
+            protocol::format_old_password_hash(protocol::old_password_hash(password.as_bytes()))
+        }
+    }
+
+    /// Check that clients can't log in as the user without credentials: unless an external
+    /// `authenticator` checks them, or the backend does for passthrough users, the mapping
+    /// needs a password, and a `*` mapping would let anyone in under any name
+    pub fn check_password(&self, authenticator: bool) -> Result<(), String> {
+        if authenticator || self.auth_passthrough {
+            return Ok(());
+        }
+        if self.user == "*" {
+            return Err("a `*` mapping needs an [auth] authenticator".to_string());
+        }
+        match self.password {
+            Some(ref password) if !password.is_empty() => Ok(()),
+            _ => Err("no password, and no [auth] authenticator to check clients with".to_string()),
+        }
+    }
+}
+
+/// Source of user mappings, implemented by the built-in table and by external stores
+pub trait UserStore: Send + Sync {
+    fn lookup(&self, user: &str) -> Option<UserMapping>;
+}
+
+/// In-memory user mapping table, which can be updated at runtime
+#[derive(Debug,Default)]
+pub struct UserMap {
+    users: RwLock<HashMap<String, UserMapping>>,
+    /// whether an external authenticator checks the users' credentials
+    authenticator: bool,
+}
+
+impl UserMap {
+
+    pub fn new(users: Vec<UserMapping>) -> Self {
+        let map = UserMap::default();
+        for u in users {
+            map.insert(u);
+        }
+        map
+    }
+
+    /// The mappings of a listener whose clients are checked by an external authenticator
+    pub fn with_authenticator(mut self, authenticator: bool) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Check a mapping before it's added at runtime, see `UserMapping::check_password`
+    pub fn check(&self, mapping: &UserMapping) -> Result<(), String> {
+        mapping.check_password(self.authenticator)
+    }
+
+    /// Add a mapping, replacing any existing mapping for the same user
+    pub fn insert(&self, mapping: UserMapping) {
+        self.users.write().unwrap().insert(mapping.user.clone(), mapping);
+    }
+
+    pub fn remove(&self, user: &str) -> Option<UserMapping> {
+        self.users.write().unwrap().remove(user)
+    }
+
+    /// Replace every mapping at once
+    pub fn replace(&self, users: Vec<UserMapping>) {
+        *self.users.write().unwrap() = users.into_iter().map(|u| (u.user.clone(), u)).collect();
+    }
+
+    pub fn users(&self) -> Vec<UserMapping> {
+        self.users.read().unwrap().values().cloned().collect()
+    }
+}
+
+impl UserStore for UserMap {
+
+    /// Look up a user, falling back to the `*` mapping if there is one
+    fn lookup(&self, user: &str) -> Option<UserMapping> {
+        let users = self.users.read().unwrap();
+        users.get(user).or_else(|| users.get("*")).cloned().map(|mut m| {
+            m.user = user.to_string();
+            m
+        })
+    }
+}
@@ -0,0 +1,144 @@
+//! `@@proxy_*` variables, answered by the proxy itself.
+//!
+//! `SELECT @@proxy_version, @@proxy_backend` works from any MySQL client and tells operators
+//! and applications which proxy, and which backend behind it, their connection goes through.
+//! Only queries that select nothing but proxy variables are answered by the proxy; anything
+//! else goes to the backend as usual.
+
+use std::collections::BTreeMap;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{Action, ConnectionPhase, Packet, PacketHandler, PacketType, PhaseTracker};
+use super::anomaly::{Anomaly, Verdict};
+use super::auth::Session;
+use super::codec::{text_result_set, ColumnDefinition};
+
+/// MySQL error ER_UNKNOWN_SYSTEM_VARIABLE
+pub const ER_UNKNOWN_SYSTEM_VARIABLE: u16 = 1193;
+
+/// The version of the proxy, reported as `@@proxy_version`
+pub const PROXY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Wraps another handler and answers queries for `@@proxy_*` variables
+pub struct VariablesHandler<H: PacketHandler> {
+    variables: BTreeMap<String, String>,
+    capability_flags: u32,
+    phase: PhaseTracker,
+    inner: H,
+}
+
+impl<H> VariablesHandler<H> where H: PacketHandler {
+
+    /// Answer `@@proxy_version`, and any variables added with `with_variable`
+    pub fn new(inner: H) -> Self {
+        let mut variables = BTreeMap::new();
+        variables.insert("proxy_version".to_string(), PROXY_VERSION.to_string());
+        VariablesHandler { variables, capability_flags: 0, phase: PhaseTracker::new(), inner }
+    }
+
+    /// Also answer `@@proxy_backend`, `@@proxy_connection_id` and `@@proxy_user` for a session
+    pub fn for_session(session: &Session, inner: H) -> Self {
+        VariablesHandler::new(inner)
+            .with_variable("proxy_backend", &session.backend.to_string())
+            .with_variable("proxy_connection_id", &session.connection_id.to_string())
+            .with_variable("proxy_user", &session.user)
+    }
+
+    /// Answer `@@name`, which must start with `proxy_`
+    pub fn with_variable(mut self, name: &str, value: &str) -> Self {
+        self.variables.insert(name.to_lowercase(), value.to_string());
+        self
+    }
+
+    /// Capabilities negotiated with the client, for relayed handshakes these are taken from
+    /// the client's handshake response
+    pub fn with_capabilities(mut self, capability_flags: u32) -> Self {
+        self.capability_flags = capability_flags;
+        self
+    }
+
+    fn answer(&self, selected: Vec<(String, String)>) -> Action {
+        let mut columns = Vec::with_capacity(selected.len());
+        let mut row = Vec::with_capacity(selected.len());
+        for (variable, column) in selected {
+            let value = match self.variables.get(&variable) {
+                Some(value) => value,
+                None => return Action::Error {
+                    code: ER_UNKNOWN_SYSTEM_VARIABLE,
+                    state: *b"HY000",
+                    msg: format!("Unknown system variable '{}'", variable),
+                },
+            };
+            columns.push(ColumnDefinition::varchar(&column, value.len().max(1) as u32 * 4));
+            row.push(Some(value.as_bytes().to_vec()));
+        }
+        Action::Respond(text_result_set(&columns, &[row], self.capability_flags))
+    }
+}
+
+impl<H> PacketHandler for VariablesHandler<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        self.phase.observe_request(p);
+        match self.phase.phase() {
+            ConnectionPhase::Handshake => {
+                // the handshake response starts with the client's capabilities
+                if p.sequence_id() == 1 && p.payload().len() >= 32 {
+                    self.capability_flags = LittleEndian::read_u32(p.payload());
+                }
+            },
+            ConnectionPhase::Command => {
+                if let Ok(PacketType::ComQuery) = p.packet_type() {
+                    let sql = String::from_utf8_lossy(&p.payload()[1..]);
+                    if let Some(selected) = parse_select(&sql) {
+                        return self.answer(selected);
+                    }
+                }
+            },
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.phase.observe_response(p);
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+/// Parse `SELECT @@proxy_a, @@proxy_b AS b` into variable names and column names, or `None`
+/// if the query selects anything other than proxy variables
+fn parse_select(sql: &str) -> Option<Vec<(String, String)>> {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+    match sql.get(..6) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("select") && sql[6..].starts_with(char::is_whitespace) => {},
+        _ => return None,
+    }
+    let mut selected = vec![];
+    for item in sql[6..].split(',') {
+        let words: Vec<&str> = item.split_whitespace().collect();
+        let (expr, alias) = match words.len() {
+            1 => (words[0], None),
+            2 => (words[0], Some(words[1])),
+            3 if words[1].eq_ignore_ascii_case("as") => (words[0], Some(words[2])),
+            _ => return None,
+        };
+        if !expr.starts_with("@@") {
+            return None;
+        }
+        let variable = expr[2..].to_lowercase();
+        if !variable.starts_with("proxy_") {
+            return None;
+        }
+        let column = match alias {
+            Some(alias) => alias.trim_matches(|c| c == '`' || c == '\'' || c == '"').to_string(),
+            None => expr.to_string(),
+        };
+        selected.push((variable, column));
+    }
+    Some(selected)
+}
@@ -0,0 +1,217 @@
+//! Backend connections logged in ahead of the sessions that need them.
+//!
+//! A new session normally connects to its backend and logs in there before its client gets
+//! an OK, which costs several round trips, more with an upstream proxy in the way. With a
+//! `[warm_pool]`, the proxy keeps `size` connections logged in ahead for every backend,
+//! backend user and shape of login it has seen, i.e. the client's capabilities and character
+//! set, and hands one to the next session that would log in the same way, switching it to the
+//! session's default schema with `COM_INIT_DB` if there is one. Each connection taken is
+//! replaced in the background, and connections left idle for `max_age_secs` are closed and
+//! replaced too, so none outlives the backend's `wait_timeout`. The first session of each
+//! shape logs in as before.
+//!
+//! Warm connections are logged in without connection attributes, since they aren't made for
+//! any one client. Users whose authentication is passed through, and backends spoken to with
+//! the compressed protocol, always log in for themselves. Connections belong to the reactor
+//! that made them, so every worker thread keeps its own.
+//!
+//! ```toml
+//! [warm_pool]
+//! size = 4
+//! max_age_secs = 300
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::CoreId;
+
+use super::connect::BackendAddr;
+
+/// Most connections kept for one shape of login
+pub const MAX_SIZE: usize = 100;
+
+/// Most shapes of login kept warm, further ones log in as usual
+pub const MAX_SHAPES: usize = 256;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct WarmPoolConfig {
+    /// connections kept logged in for each backend, user and shape of login
+    #[serde(default = "WarmPoolConfig::default_size")]
+    pub size: usize,
+    /// how long a connection is kept before it's replaced
+    #[serde(default = "WarmPoolConfig::default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl WarmPoolConfig {
+
+    fn default_size() -> usize {
+        2
+    }
+
+    fn default_max_age_secs() -> u64 {
+        300
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.size == 0 || self.size > MAX_SIZE {
+            return Err(format!("size must be between 1 and {}", MAX_SIZE));
+        }
+        if self.max_age_secs == 0 {
+            return Err("max_age_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+/// What a warm connection has in common with the logins it can stand in for
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct WarmKey {
+    /// the reactor the connection is registered with
+    pub reactor: CoreId,
+    pub backend: BackendAddr,
+    pub user: String,
+    pub password: String,
+    /// of the client's handshake response, which the backend's login keeps
+    pub capability_flags: u32,
+    pub character_set: u8,
+}
+
+/// A backend connection that has logged in
+#[derive(Debug)]
+pub struct WarmConnection {
+    pub id: u64,
+    pub server: TcpStream,
+    /// the address it's connected to
+    pub addr: SocketAddr,
+    /// the capabilities the login used
+    pub capabilities: u32,
+    pub created: Instant,
+}
+
+#[derive(Debug,Default)]
+struct Shape {
+    /// the oldest first
+    idle: VecDeque<WarmConnection>,
+    /// logins under way
+    pending: usize,
+}
+
+/// The warm connections of a listener, by shape of login
+#[derive(Clone,Debug)]
+pub struct WarmPool {
+    config: WarmPoolConfig,
+    shapes: Arc<Mutex<HashMap<WarmKey, Shape>>>,
+    next_id: Arc<AtomicU64>,
+    hits: Arc<AtomicUsize>,
+    misses: Arc<AtomicUsize>,
+}
+
+impl WarmPool {
+
+    pub fn new(config: &WarmPoolConfig) -> Self {
+        WarmPool {
+            config: config.clone(),
+            shapes: Arc::default(),
+            next_id: Arc::default(),
+            hits: Arc::default(),
+            misses: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &WarmPoolConfig {
+        &self.config
+    }
+
+    /// Take the newest connection for `key`, closing any the backend has closed or written
+    /// to meanwhile. Must run in a task of `key`'s reactor.
+    pub fn take(&self, key: &WarmKey) -> Option<WarmConnection> {
+        let mut shapes = self.shapes.lock().unwrap();
+        let shape = match shapes.get_mut(key) {
+            Some(shape) => shape,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            },
+        };
+        while let Some(connection) = shape.idle.pop_back() {
+            // an idle connection has nothing to read unless the backend gave up on it
+            match connection.server.peek(&mut [0]) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(connection);
+                },
+                _ => debug!("Closing a warm connection to {} that the backend closed", key.backend),
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// How many logins `key` needs to have `size` connections, counted as under way until
+    /// each is `added` or has `failed`
+    pub fn reserve(&self, key: &WarmKey) -> usize {
+        let mut shapes = self.shapes.lock().unwrap();
+        if !shapes.contains_key(key) && shapes.len() >= MAX_SHAPES {
+            return 0;
+        }
+        let shape = shapes.entry(key.clone()).or_default();
+        let wanted = self.config.size.saturating_sub(shape.idle.len() + shape.pending);
+        shape.pending += wanted;
+        wanted
+    }
+
+    /// Keep a connection that has logged in for `key`, returning its id
+    pub fn add(&self, key: &WarmKey, server: TcpStream, addr: SocketAddr, capabilities: u32) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut shapes = self.shapes.lock().unwrap();
+        let shape = shapes.entry(key.clone()).or_default();
+        shape.pending = shape.pending.saturating_sub(1);
+        shape.idle.push_back(WarmConnection { id, server, addr, capabilities, created: Instant::now() });
+        id
+    }
+
+    /// Give up on a login for `key`
+    pub fn failed(&self, key: &WarmKey) {
+        if let Some(shape) = self.shapes.lock().unwrap().get_mut(key) {
+            shape.pending = shape.pending.saturating_sub(1);
+        }
+    }
+
+    /// Close connection `id` if it's still idle, returning whether it was
+    pub fn retire(&self, key: &WarmKey, id: u64) -> bool {
+        let mut shapes = self.shapes.lock().unwrap();
+        let shape = match shapes.get_mut(key) {
+            Some(shape) => shape,
+            None => return false,
+        };
+        let before = shape.idle.len();
+        shape.idle.retain(|connection| connection.id != id);
+        shape.idle.len() < before
+    }
+
+    /// How many connections are idle for `key`
+    pub fn idle(&self, key: &WarmKey) -> usize {
+        self.shapes.lock().unwrap().get(key).map(|shape| shape.idle.len()).unwrap_or(0)
+    }
+
+    /// How many sessions were given a warm connection
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How many sessions logged in for themselves, since no connection was ready
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
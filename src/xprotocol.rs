@@ -0,0 +1,210 @@
+//! MySQL X Protocol clients.
+//!
+//! X Protocol clients such as MySQL Shell frame protobuf messages with a 4 byte length and a
+//! message type, and speak first, where classic clients wait for the server's greeting. An X
+//! client pointed at a classic port sends a message where the handshake response should be,
+//! which the proxy recognises and rejects with an X Protocol error the client can show,
+//! rather than misreading its messages. X Protocol traffic can instead be relayed to the
+//! backends' X Protocol port, unmodified, by a listener of its own.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::thread;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use futures::{Future, Poll, Stream};
+use futures::future;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::copy;
+
+use super::{Action, Packet, PacketHandler};
+use super::anomaly::{Anomaly, Verdict};
+use super::connect::{connect, BackendAddr};
+
+/// The port MySQL servers accept X Protocol connections on
+pub const X_PROTOCOL_PORT: u16 = 33060;
+
+/// MySQL error ER_HANDSHAKE_ERROR
+pub const ER_HANDSHAKE_ERROR: u16 = 1043;
+
+/// Client message types that can start an X Protocol session: CON_CAPABILITIES_GET,
+/// CON_CAPABILITIES_SET, CON_CLOSE and SESS_AUTHENTICATE_START
+const FIRST_CLIENT_MESSAGES: [u8; 4] = [1, 2, 3, 4];
+
+/// Server message type of `Mysqlx.Error`
+const SERVER_ERROR: u8 = 1;
+
+#[derive(Clone,Debug,Deserialize,PartialEq)]
+pub struct XProtocolConfig {
+    /// address to accept X Protocol clients on
+    pub listen: SocketAddr,
+    /// the backends' X Protocol addresses, tried in order
+    pub backends: Vec<BackendAddr>,
+}
+
+/// Whether the first packet a client sent is an X Protocol message. Read as a classic
+/// packet, a small X message has sequence id 0, which a handshake response never has.
+pub fn is_x_protocol(p: &Packet) -> bool {
+    p.sequence_id() == 0 && p.payload().len() < 0x1_0000
+        && p.payload().first().map(|t| FIRST_CLIENT_MESSAGES.contains(t)).unwrap_or(false)
+}
+
+/// A fatal `Mysqlx.Error` message, framed for sending as is
+pub fn error_message(code: u16, state: &str, msg: &str) -> Packet {
+    let mut error = vec![0x08, 0x01]; // severity: FATAL
+    error.push(0x10);
+    write_varint(&mut error, code as u64);
+    error.push(0x1a);
+    write_varint(&mut error, msg.len() as u64);
+    error.extend_from_slice(msg.as_bytes());
+    error.push(0x22);
+    write_varint(&mut error, state.len() as u64);
+    error.extend_from_slice(state.as_bytes());
+
+    let mut bytes = Vec::with_capacity(5 + error.len());
+    bytes.write_u32::<LittleEndian>(error.len() as u32 + 1).unwrap();
+    bytes.push(SERVER_ERROR);
+    bytes.extend_from_slice(&error);
+    Packet { bytes }
+}
+
+/// The error sent to X Protocol clients that connect to a classic protocol port
+pub fn wrong_port_error() -> Packet {
+    error_message(ER_HANDSHAKE_ERROR, "08S01",
+                  "This port only accepts the classic MySQL protocol, use the proxy's X Protocol port instead")
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Wraps another handler and rejects X Protocol clients on a relayed connection
+pub struct XProtocolGuard<H: PacketHandler> {
+    first: bool,
+    inner: H,
+}
+
+impl<H> XProtocolGuard<H> where H: PacketHandler {
+
+    pub fn new(inner: H) -> Self {
+        XProtocolGuard { first: true, inner }
+    }
+}
+
+impl<H> PacketHandler for XProtocolGuard<H> where H: PacketHandler {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        if self.first {
+            self.first = false;
+            if is_x_protocol(p) {
+                warn!("Rejecting an X Protocol client on the classic protocol port");
+                return Action::Respond(vec![wrong_port_error()]);
+            }
+        }
+        self.inner.handle_request(p)
+    }
+
+    fn handle_response(&mut self, p: &Packet) -> Action {
+        self.inner.handle_response(p)
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.inner.handle_anomaly(anomaly)
+    }
+}
+
+/// Relay X Protocol clients to the configured backends on a thread of its own
+pub fn run_in_thread(config: &XProtocolConfig) -> io::Result<()> {
+    let std_listener = std::net::TcpListener::bind(config.listen)?;
+    let config = config.clone();
+    thread::Builder::new().name("mysql-proxy-xprotocol".to_string()).spawn(move || {
+        let result = Core::new().and_then(|mut core| {
+            let handle = core.handle();
+            let listener = TcpListener::from_listener(std_listener, &config.listen, &handle)?;
+            core.run(serve(listener, config.backends, &handle))
+        });
+        if let Err(e) = result {
+            warn!("X Protocol listener failed: {}", e);
+        }
+    })?;
+    Ok(())
+}
+
+/// Relay every connection accepted on `listener` to the first of `backends` to accept it
+pub fn serve(listener: TcpListener,
+             backends: Vec<BackendAddr>,
+             handle: &Handle) -> Box<dyn Future<Item = (), Error = io::Error>> {
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(client, addr)| {
+        let relay = connect_any(backends.clone(), &handle)
+            .and_then(move |server| relay(client, server))
+            .then(move |result| {
+                match result {
+                    Ok((up, down)) => debug!("X Protocol client {} relayed {} bytes up, {} down", addr, up, down),
+                    Err(e) => debug!("X Protocol client {} disconnected: {}", addr, e),
+                }
+                Ok(())
+            });
+        handle.spawn(relay);
+        Ok(())
+    }))
+}
+
+/// Connect to the first backend that accepts, in order
+fn connect_any(backends: Vec<BackendAddr>, handle: &Handle) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    let handle = handle.clone();
+    backends.into_iter().fold(
+        Box::new(future::err(io::Error::new(io::ErrorKind::NotFound, "No X Protocol backends"))) as Box<dyn Future<Item = _, Error = _>>,
+        move |previous, backend| {
+            let handle = handle.clone();
+            Box::new(previous.or_else(move |_| {
+                backend.resolve().and_then(move |addrs| connect(addrs, &handle))
+            }))
+        })
+}
+
+/// Copy bytes both ways until both sides have finished, resolving to the bytes sent each way
+fn relay(client: TcpStream, server: TcpStream) -> Box<dyn Future<Item = (u64, u64), Error = io::Error>> {
+    let client = Shared(Rc::new(client));
+    let server = Shared(Rc::new(server));
+    let up = copy(client.clone(), server.clone())
+        .and_then(|(n, _, server)| server.0.shutdown(Shutdown::Write).map(|_| n));
+    let down = copy(server, client)
+        .and_then(|(n, _, client)| client.0.shutdown(Shutdown::Write).map(|_| n));
+    Box::new(up.join(down))
+}
+
+/// A socket shared by both directions of a relay
+#[derive(Clone)]
+struct Shared(Rc<TcpStream>);
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncRead for Shared {}
+
+impl AsyncWrite for Shared {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.shutdown(Shutdown::Write).map(|_| ().into())
+    }
+}
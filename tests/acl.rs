@@ -0,0 +1,114 @@
+extern crate mysql_proxy;
+
+use std::io::ErrorKind;
+use std::net::IpAddr;
+
+use mysql_proxy::acl::{AccessControl, AccessList, Cidr};
+use mysql_proxy::config::ProxyConfig;
+
+fn access(toml: &str) -> AccessList {
+    ProxyConfig::parse(&format!("access = {}", toml)).unwrap().access
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn users_may_require_certificate_identities() {
+    let control = AccessControl::default();
+    let peer = "10.0.0.1:40000".parse().unwrap();
+    let list = access(r#"{ allow = ["10.0.0.0/8"], identities = ["app@example.com", "app"] }"#);
+    assert!(control.check_user("app", &list, &peer, Some("app@example.com")));
+    assert!(control.check_user("app", &list, &peer, Some("app")));
+    assert!(!control.check_user("app", &list, &peer, Some("other@example.com")));
+    // a client without a certificate has no identity to match
+    assert!(!control.check_user("app", &list, &peer, None));
+    // and networks still apply
+    assert!(!control.check_user("app", &list, &"192.168.0.1:40000".parse().unwrap(), Some("app")));
+    assert_eq!(control.rejected_logins(), 3);
+
+    // without identities, any client is fine
+    let list = access(r#"{ allow = ["10.0.0.0/8"] }"#);
+    assert!(control.check_user("app", &list, &peer, None));
+    assert!(control.check_user("app", &list, &peer, Some("anyone")));
+}
+
+#[test]
+fn networks_are_parsed_from_cidr_notation() {
+    let net: Cidr = "10.0.0.0/8".parse().unwrap();
+    assert_eq!(net.to_string(), "10.0.0.0/8");
+    // a bare address is a network of one
+    assert_eq!("10.1.2.3".parse::<Cidr>().unwrap().to_string(), "10.1.2.3/32");
+    assert_eq!("fd00::1".parse::<Cidr>().unwrap().to_string(), "fd00::1/128");
+
+    for malformed in &["10.0.0.0/33", "fd00::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/8/8", "10.0.0/8", "example.com/8", ""] {
+        let e = malformed.parse::<Cidr>().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", malformed);
+    }
+    assert_eq!("10.0.0.0/33".parse::<Cidr>().unwrap_err().to_string(), "Invalid prefix length /33 for 10.0.0.0");
+    assert_eq!("10.0.0/8".parse::<Cidr>().unwrap_err().to_string(), "Invalid network '10.0.0/8'");
+
+    // and so are access lists in the configuration
+    assert!(ProxyConfig::parse(r#"access = { allow = ["10.0.0.0/33"] }"#).is_err());
+}
+
+#[test]
+fn networks_contain_the_addresses_under_their_prefix() {
+    let net: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(net.contains(ip("10.1.0.0")));
+    assert!(net.contains(ip("10.1.255.255")));
+    assert!(!net.contains(ip("10.2.0.0")));
+    assert!(!net.contains(ip("::1")));
+
+    // /0 is everything of its family
+    let any: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains(ip("203.0.113.7")));
+    assert!(!any.contains(ip("2001:db8::1")));
+    assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+
+    let net: Cidr = "2001:db8::/32".parse().unwrap();
+    assert!(net.contains(ip("2001:db8:ffff::1")));
+    assert!(!net.contains(ip("2001:db9::1")));
+
+    // IPv4-mapped IPv6 addresses are the IPv4 addresses they map
+    assert!("10.1.0.0/16".parse::<Cidr>().unwrap().contains(ip("::ffff:10.1.2.3")));
+    assert!(!"10.1.0.0/16".parse::<Cidr>().unwrap().contains(ip("::ffff:10.2.2.3")));
+}
+
+#[test]
+fn deny_rules_take_precedence() {
+    let list = access(r#"{ allow = ["10.0.0.0/8"], deny = ["10.6.0.0/16"] }"#);
+    assert!(list.permits(ip("10.1.2.3")));
+    assert!(!list.permits(ip("10.6.2.3")));
+    assert!(!list.permits(ip("192.168.0.1")));
+    assert!(!list.permits(ip("::ffff:10.6.2.3")));
+
+    // without allow rules, everything that isn't denied is allowed
+    let list = access(r#"{ deny = ["192.168.0.0/16"] }"#);
+    assert!(list.permits(ip("10.1.2.3")));
+    assert!(!list.permits(ip("192.168.1.1")));
+    assert!(AccessList::default().permits(ip("10.1.2.3")));
+    assert!(AccessList::default().is_empty());
+
+    // a deny /0 shuts everyone out, whatever's allowed
+    let list = access(r#"{ allow = ["10.0.0.0/8"], deny = ["0.0.0.0/0", "::/0"] }"#);
+    assert!(!list.permits(ip("10.1.2.3")));
+    assert!(!list.permits(ip("2001:db8::1")));
+}
+
+#[test]
+fn listeners_count_the_connections_they_refuse() {
+    let control = AccessControl::new(access(r#"{ allow = ["127.0.0.0/8"] }"#));
+    assert!(control.check_connection(&"127.0.0.1:40000".parse().unwrap()));
+    assert!(control.check_connection(&"[::ffff:127.0.0.1]:40000".parse().unwrap()));
+    assert!(!control.check_connection(&"10.0.0.1:40000".parse().unwrap()));
+    assert!(!control.check_connection(&"[::1]:40000".parse().unwrap()));
+    assert_eq!(control.rejected_connections(), 2);
+    assert_eq!(control.rejected_logins(), 0);
+
+    // clones share their counters
+    let clone = control.clone();
+    assert!(!clone.check_connection(&"10.0.0.1:40000".parse().unwrap()));
+    assert_eq!(control.rejected_connections(), 3);
+}
@@ -0,0 +1,192 @@
+extern crate mysql_proxy;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql_proxy::admin::{self, Admin, AdminConfig, Reply, ER_DUP_ENTRY, ER_NO_SUCH_TABLE, ER_PARSE_ERROR};
+use mysql_proxy::balance::{BackendPool, BackendWeights};
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::management::{Management, Rules, SharedRules};
+use mysql_proxy::sidechannel::{SideChannel, SideChannelConfig};
+use mysql_proxy::users::{UserMap, UserMapping};
+
+fn management() -> Management {
+    let pool = BackendPool::default();
+    pool.set("main", vec![BackendAddr::new("10.0.0.1", 3306), BackendAddr::new("10.0.0.2", 3306)]);
+    let management = Management::new(pool, BackendWeights::new());
+    let alice = UserMapping {
+        user: "alice".to_string(),
+        password: Some("alice-secret".to_string()),
+        default_group: "main".to_string(),
+        ..UserMapping::default()
+    };
+    management.add_listener("default", Arc::new(UserMap::new(vec![alice])), SharedRules::new(Rules::default()));
+    management
+}
+
+fn rows(reply: Reply) -> Vec<Vec<Option<String>>> {
+    match reply {
+        Reply::Rows { rows, .. } => rows,
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+fn row(values: &[&str]) -> Vec<Option<String>> {
+    values.iter().map(|v| Some(v.to_string())).collect()
+}
+
+#[test]
+fn memory_changes_apply_once_loaded_to_runtime() {
+    let management = management();
+    let admin = Admin::new(management.clone());
+    assert_eq!(admin.execute("INSERT INTO users (user, password, default_group) VALUES ('bob', 'secret', 'main')"),
+               Ok(Reply::Affected(1)));
+    assert_eq!(rows(admin.execute("SELECT user FROM users").unwrap()), vec![row(&["alice"]), row(&["bob"])]);
+    assert_eq!(rows(admin.execute("select user from runtime_users").unwrap()), vec![row(&["alice"])]);
+
+    admin.execute("LOAD USERS TO RUNTIME").unwrap();
+    let users: Vec<String> = management.user_mappings().into_iter().map(|u| u.user).collect();
+    assert_eq!(users, vec!["alice", "bob"]);
+
+    // without an authenticator, no one may log in without a password
+    for &(user, password) in &[("carol", "NULL"), ("*", "'secret'")] {
+        admin.execute(&format!("INSERT INTO users (user, password, default_group) VALUES ('{}', {}, 'main')", user, password)).unwrap();
+        let e = admin.execute("LOAD USERS TO RUNTIME").unwrap_err();
+        assert!(e.msg.contains("authenticator"), "{}", e);
+        admin.execute(&format!("DELETE FROM users WHERE user = '{}'", user)).unwrap();
+    }
+    assert_eq!(management.user_mappings().len(), 2);
+
+    assert_eq!(admin.execute("UPDATE users SET default_group = 'reports', old_password = 1 WHERE user = 'bob'"),
+               Ok(Reply::Affected(1)));
+    let e = admin.execute("LOAD USERS TO RUNTIME").unwrap_err();
+    assert!(e.msg.contains("Unknown routing group 'reports'"), "{}", e);
+    assert_eq!(admin.execute("DELETE FROM users WHERE user = 'bob' AND old_password = TRUE"), Ok(Reply::Affected(1)));
+    admin.execute("LOAD USERS FROM MEMORY").unwrap();
+    assert_eq!(management.user_mappings().len(), 1);
+
+    // the backends in use, weighted as the first of a group is by default
+    assert_eq!(rows(admin.execute("SELECT * FROM runtime_backends WHERE weight = 0").unwrap()),
+               vec![row(&["main", "10.0.0.2:3306", "0"])]);
+    admin.execute("UPDATE backends SET weight = 3 WHERE backend = '10.0.0.2:3306'").unwrap();
+    admin.execute("INSERT INTO backends (routing_group, backend) VALUES ('reports', '10.0.0.3:3306')").unwrap();
+    admin.execute("LOAD BACKENDS TO RUNTIME").unwrap();
+    assert_eq!(management.weights.weight(&BackendAddr::new("10.0.0.2", 3306), 1), 3);
+    assert_eq!(management.backend_pool().backends("reports"), vec![BackendAddr::new("10.0.0.3", 3306)]);
+
+    admin.execute("INSERT INTO table_rules (table_name, allow_users, action) VALUES ('hr.salaries', 'alice, bob', 'audit')").unwrap();
+    admin.execute("LOAD TABLE RULES TO RUNTIME").unwrap();
+    let rules = management.table_rules();
+    assert_eq!(rules[0].table, "hr.salaries");
+    assert_eq!(rules[0].allow_users, vec!["alice", "bob"]);
+}
+
+#[test]
+fn bad_statements_change_nothing() {
+    let admin = Admin::new(management());
+    let code = |sql: &str| admin.execute(sql).unwrap_err().code;
+    assert_eq!(code("INSERT INTO users (user, default_group) VALUES ('carol', 'main'), ('alice', 'main')"), ER_DUP_ENTRY);
+    assert_eq!(code("SELECT * FROM servers"), ER_NO_SUCH_TABLE);
+    assert_eq!(code("DELETE users"), ER_PARSE_ERROR);
+    assert!(admin.execute("SELECT nope FROM users").is_err());
+    assert!(admin.execute("INSERT INTO users (user) VALUES ('dave')").is_err());
+    assert!(admin.execute("UPDATE runtime_users SET password = 'x'").is_err());
+    assert!(admin.execute("INSERT INTO table_rules (table_name, action) VALUES ('hr.', 'block')").is_err());
+    assert!(admin.execute("SAVE USERS TO DISK").is_err());
+    assert_eq!(rows(admin.execute("SELECT user FROM users").unwrap()), vec![row(&["alice"])]);
+}
+
+#[test]
+fn tables_are_saved_to_disk_and_loaded_back() {
+    let path = env::temp_dir().join(format!("mysql-proxy-admin-{}.json", process::id()));
+    let _ = fs::remove_file(&path);
+    let admin = Admin::new(management()).with_path(Some(path.clone()));
+    let e = admin.execute("LOAD USERS FROM DISK").unwrap_err();
+    assert!(e.msg.starts_with("No users saved"), "{}", e);
+
+    admin.execute("UPDATE users SET password = 'secret'").unwrap();
+    admin.execute("SAVE USERS TO DISK").unwrap();
+    admin.execute("SAVE BACKENDS FROM MEMORY").unwrap();
+    admin.execute("DELETE FROM users").unwrap();
+    admin.execute("SAVE USERS FROM RUNTIME").unwrap();
+    assert_eq!(rows(admin.execute("SELECT password FROM users").unwrap()), vec![row(&["alice-secret"])]);
+
+    admin.execute("LOAD USERS FROM DISK").unwrap();
+    assert_eq!(rows(admin.execute("SELECT password FROM users").unwrap()), vec![row(&["secret"])]);
+    let other = Admin::new(management()).with_path(Some(path.clone()));
+    other.execute("DELETE FROM backends").unwrap();
+    other.execute("LOAD BACKENDS TO MEMORY").unwrap();
+    assert_eq!(rows(other.execute("SELECT backend FROM backends").unwrap()).len(), 2);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn admin_clients_log_in_over_mysql() {
+    let config = AdminConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        user: "admin".to_string(),
+        password: "change-me".to_string(),
+        path: None,
+        login_timeout_secs: 10,
+        max_sessions: 8,
+    };
+    assert!(config.validate().is_ok());
+    let addr = admin::run_in_thread(&config, Admin::new(management())).unwrap();
+    let client = |password: &str| SideChannel::new(&SideChannelConfig {
+        backend_user: "admin".to_string(),
+        backend_password: password.to_string(),
+        timeout_ms: 1000,
+        max_concurrent: 1,
+        max_idle: 1,
+        max_rows: 10,
+        max_lifetime_secs: None,
+        max_idle_secs: None,
+    });
+    let result = client("change-me").query_blocking(addr, "SELECT user, default_group FROM users").unwrap();
+    assert_eq!(result.value(0, "default_group"), Some("main".to_string()));
+
+    let e = client("wrong").query_blocking(addr, "SHOW TABLES").unwrap_err();
+    assert!(e.to_string().contains("Access denied"), "{}", e);
+}
+
+#[test]
+fn admin_clients_that_dont_log_in_are_let_go() {
+    let config = AdminConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        user: "admin".to_string(),
+        password: "change-me".to_string(),
+        path: None,
+        login_timeout_secs: 1,
+        max_sessions: 1,
+    };
+    let addr = admin::run_in_thread(&config, Admin::new(management())).unwrap();
+
+    // a client that doesn't log in holds its session until the login timeout, and clients
+    // beyond max_sessions are refused meanwhile
+    let started = Instant::now();
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let mut greeting = [0; 4];
+    idle.read_exact(&mut greeting).unwrap();
+    let mut refused = vec![];
+    TcpStream::connect(addr).unwrap().read_to_end(&mut refused).unwrap();
+    assert_eq!(&refused[4..7], &[0xff, 0x10, 0x04]);
+    idle.read_to_end(&mut vec![]).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(3));
+
+    // once it's gone, the next client is greeted
+    for _ in 0..100 {
+        let mut first = [0; 5];
+        TcpStream::connect(addr).unwrap().read_exact(&mut first).unwrap();
+        if first[4] == 0x0a {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the idle client's session was never let go");
+}
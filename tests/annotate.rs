@@ -0,0 +1,51 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::annotate::{AnnotateConfig, AnnotateHandler};
+use mysql_proxy::auth::Session;
+use mysql_proxy::codec::{ok_packet, MAX_PAYLOAD_LEN};
+use mysql_proxy::testing::{Forward, HandlerTester, SessionBuilder};
+
+fn session(connect_attrs: &[(&str, &str)]) -> Session {
+    SessionBuilder::new().connection_id(12).client("10.1.2.3:40000".parse().unwrap()).connect_attrs(connect_attrs).build()
+}
+
+
+#[test]
+fn queries_are_annotated_with_where_they_came_from() {
+    let handler = AnnotateHandler::for_session(&AnnotateConfig::default(), &session(&[("program_name", "billing batch")]), Forward);
+    let mut tester = HandlerTester::new(handler);
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")),
+               Action::Mutate(Packet::com_query("/* proxy_conn=12 client=10.1.2.3 app=billing_batch */ SELECT 1")));
+    tester.response(ok_packet(1));
+
+    // other commands are left alone
+    assert_eq!(tester.request(Packet::new(0, b"\x16SELECT ?")), Action::Forward);
+
+    // the configured application name wins over the client's
+    let config = AnnotateConfig { application: Some("shop".to_string()) };
+    let mut tester = HandlerTester::new(AnnotateHandler::for_session(&config, &session(&[("program_name", "billing")]), Forward));
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")),
+               Action::Mutate(Packet::com_query("/* proxy_conn=12 client=10.1.2.3 app=shop */ SELECT 1")));
+    let mut tester = HandlerTester::new(AnnotateHandler::for_session(&AnnotateConfig::default(), &session(&[]), Forward));
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")),
+               Action::Mutate(Packet::com_query("/* proxy_conn=12 client=10.1.2.3 */ SELECT 1")));
+}
+
+#[test]
+fn annotations_cant_end_their_comment() {
+    let mut tester = HandlerTester::new(AnnotateHandler::new("app=x */ DROP TABLE t; /*\n", Forward));
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")),
+               Action::Mutate(Packet::com_query("/* app=x *_/ DROP TABLE t; /* */ SELECT 1")));
+}
+
+#[test]
+fn queries_that_would_no_longer_fit_a_packet_are_left_alone() {
+    let mut tester = HandlerTester::new(AnnotateHandler::new("app=billing", Forward));
+    let sql = format!("SELECT '{}'", "x".repeat(MAX_PAYLOAD_LEN - 20));
+    assert_eq!(tester.request(Packet::com_query(&sql)), Action::Forward);
+
+    // nor is anything annotated before the handshake is done
+    let mut handler = AnnotateHandler::new("app=billing", Forward);
+    assert_eq!(handler.handle_request(&Packet::new(1, b"\x03SELECT 1")), Action::Forward);
+}
@@ -0,0 +1,217 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Action, ConnectionPhase, Packet, PacketHandler, Pipe};
+use mysql_proxy::anomaly::{Anomaly, AnomalyKind, ProtocolChecks, ProtocolChecksConfig, Verdict};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::dump::Direction;
+use mysql_proxy::stats::Stats;
+
+/// Forwards everything, keeping the anomalies it's told about and killing connections on
+/// unknown commands
+#[derive(Default)]
+struct Judge {
+    anomalies: Rc<RefCell<Vec<Anomaly>>>,
+}
+
+impl PacketHandler for Judge {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_anomaly(&mut self, anomaly: &Anomaly) -> Verdict {
+        self.anomalies.borrow_mut().push(anomaly.clone());
+        match *anomaly {
+            Anomaly::UnknownCommand(_) => Verdict::Kill,
+            _ => Verdict::Tolerate,
+        }
+    }
+}
+
+/// One end of an in-memory connection
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+struct Session {
+    client: Memory,
+    server: Memory,
+    anomalies: Rc<RefCell<Vec<Anomaly>>>,
+    pipe: Spawn<Pipe<Judge>>,
+}
+
+impl Session {
+
+    fn new(config: &ProtocolChecksConfig, stats: &Stats) -> Self {
+        let (client, server) = (Memory::default(), Memory::default());
+        let judge = Judge::default();
+        let anomalies = judge.anomalies.clone();
+        let pipe = Pipe::from_streams(client.clone(), server.clone(), judge)
+            .with_protocol_checks(ProtocolChecks::new(config).with_stats(stats.clone()));
+        Session { client, server, anomalies, pipe: executor::spawn(pipe) }
+    }
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.pipe.poll_future_notify(&Arc::new(Ignore), 0)
+    }
+}
+
+fn query(sql: &str) -> Packet {
+    Packet::new(0, &[&[0x03], sql.as_bytes()].concat())
+}
+
+#[test]
+fn packets_are_checked_against_their_exchange() {
+    let stats = Stats::new();
+    let mut checks = ProtocolChecks::new(&ProtocolChecksConfig::default()).with_stats(stats.clone());
+    let command = ConnectionPhase::Command;
+    // pipelined commands each get a response starting at 1
+    assert!(checks.request(&query("SELECT 1"), command, 0).is_empty());
+    assert!(checks.request(&query("SELECT 2"), command, 1).is_empty());
+    assert!(checks.response(&ok_packet(1), command, 2).is_empty());
+    assert!(checks.response(&ok_packet(1), command, 1).is_empty());
+
+    assert!(checks.request(&query("SELECT 3"), command, 0).is_empty());
+    assert!(checks.response(&Packet::new(1, &[1]), command, 1).is_empty());
+    assert_eq!(checks.response(&Packet::new(3, &[0xfe, 0, 0, 2, 0]), command, 1),
+               vec![Anomaly::BadSequenceId { from: Direction::Response, expected: 2, actual: 3 }]);
+    assert_eq!(checks.response(&ok_packet(0), command, 0),
+               vec![Anomaly::UnexpectedPacket { from: Direction::Response, phase: command }]);
+    assert_eq!(checks.request(&Packet::new(0, &[0x99]), command, 0), vec![Anomaly::UnknownCommand(0x99)]);
+    assert_eq!(checks.request(&Packet::new(5, &[0x03]), command, 1),
+               vec![Anomaly::BadSequenceId { from: Direction::Request, expected: 1, actual: 5 }]);
+    // with nothing in flight, only a command may come from the client
+    assert_eq!(checks.request(&Packet::new(6, &[0x03]), command, 0),
+               vec![Anomaly::BadSequenceId { from: Direction::Request, expected: 0, actual: 6 }]);
+    // during the handshake, the client's packets follow the server's
+    assert!(checks.response(&Packet::new(2, &[0xfe]), ConnectionPhase::Handshake, 0).is_empty());
+    assert!(checks.request(&Packet::new(3, &[0x99]), ConnectionPhase::Handshake, 0).is_empty());
+    assert_eq!(checks.request(&Packet::new(7, &[0x99]), ConnectionPhase::Handshake, 0),
+               vec![Anomaly::BadSequenceId { from: Direction::Request, expected: 4, actual: 7 }]);
+
+    let anomalies = stats.snapshot().anomalies;
+    assert_eq!(anomalies.get("bad_sequence_id"), Some(&4));
+    assert_eq!(anomalies.get("unexpected_packet"), Some(&1));
+    assert_eq!(anomalies.get("unknown_command"), Some(&1));
+    assert_eq!(anomalies.get("oversized_frame"), None);
+}
+
+#[test]
+fn the_handler_decides_whether_a_connection_is_killed() {
+    let stats = Stats::new();
+    let mut session = Session::new(&ProtocolChecksConfig::default(), &stats);
+    session.client.send(&query("SELECT 1"));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.server.written(), query("SELECT 1").bytes);
+    // tolerated
+    session.server.send(&ok_packet(2));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.client.written(), ok_packet(2).bytes);
+    assert_eq!(*session.anomalies.borrow(),
+               vec![Anomaly::BadSequenceId { from: Direction::Response, expected: 1, actual: 2 }]);
+
+    // killed
+    session.client.send(&Packet::new(0, &[0x42]));
+    assert!(session.poll().is_err());
+    assert!(session.server.written().is_empty());
+    assert_eq!(session.anomalies.borrow().len(), 2);
+    assert_eq!(stats.snapshot().anomalies.len(), 2);
+}
+
+#[test]
+fn clients_out_of_sequence_are_always_killed() {
+    let mut session = Session::new(&ProtocolChecksConfig::default(), &Stats::new());
+    session.client.send(&query("SELECT 1"));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    session.server.send(&ok_packet(1));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    session.server.written();
+    // a query passed off as the next packet of the finished exchange
+    session.client.send(&Packet::new(2, b"\x03DROP TABLE t"));
+    assert!(session.poll().is_err());
+    assert!(session.server.written().is_empty());
+    assert_eq!(*session.anomalies.borrow(),
+               vec![Anomaly::BadSequenceId { from: Direction::Request, expected: 0, actual: 2 }]);
+}
+
+#[test]
+fn configured_kinds_always_kill() {
+    let config = ProtocolChecksConfig { max_frame_bytes: 64, kill: vec![AnomalyKind::OversizedFrame] };
+    assert!(config.validate().is_ok());
+    let mut session = Session::new(&config, &Stats::new());
+    session.client.send(&query(&format!("SELECT '{}'", "x".repeat(100))));
+    assert!(session.poll().is_err());
+    assert!(session.server.written().is_empty());
+    assert_eq!(session.anomalies.borrow()[0].kind(), AnomalyKind::OversizedFrame);
+
+    assert!(ProtocolChecksConfig { max_frame_bytes: 0, kill: vec![] }.validate().is_err());
+}
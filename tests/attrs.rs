@@ -0,0 +1,97 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::attrs::{proxy_attrs, ConnectAttrsConfig, ConnectAttrsHandler};
+use mysql_proxy::codec::{HandshakeResponse, HandshakeV10};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::*;
+use mysql_proxy::testing::Forward;
+use mysql_proxy::variables::PROXY_VERSION;
+
+const FLAGS: u32 = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH;
+
+fn attrs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+fn greeting(capability_flags: u32) -> Packet {
+    HandshakeV10 {
+        server_version: "8.0.36".to_string(),
+        connection_id: 7,
+        capability_flags,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some("mysql_native_password".to_string()),
+    }.to_packet(0)
+}
+
+fn response(connect_attrs: &[(String, String)]) -> HandshakeResponse {
+    let mut response = HandshakeResponse {
+        capability_flags: FLAGS,
+        max_packet_size: 1 << 24,
+        character_set: 0x21,
+        username: "app".to_string(),
+        auth_response: vec![0x01; 20],
+        database: None,
+        auth_plugin_name: Some("mysql_native_password".to_string()),
+        connect_attrs: None,
+    };
+    response.set_connect_attrs(connect_attrs);
+    response
+}
+
+#[test]
+fn backends_get_the_clients_attributes_and_the_proxys() {
+    let client = "10.1.2.3:40000".parse().unwrap();
+    let sent = attrs(&[("program_name", "billing"), ("proxy_client_ip", "127.0.0.1")]);
+    let expected = attrs(&[("program_name", "billing"), ("proxy_version", PROXY_VERSION), ("proxy_client_ip", "10.1.2.3"), ("proxy_client_port", "40000")]);
+    // clients can't pass themselves off as the proxy
+    assert_eq!(ConnectAttrsConfig::default().backend_attrs(&sent, &client), expected);
+    assert_eq!(proxy_attrs(&client), expected[1..].to_vec());
+
+    let config = ProxyConfig::parse("connect_attrs = { forward = false }").unwrap().connect_attrs;
+    assert_eq!(config.backend_attrs(&sent, &client), expected[1..].to_vec());
+    let config = ConnectAttrsConfig { forward: true, add_proxy: false };
+    assert_eq!(config.backend_attrs(&sent, &client), sent[..1].to_vec());
+}
+
+#[test]
+fn relayed_handshake_responses_carry_the_backend_attributes() {
+    let client = "10.1.2.3:40000".parse().unwrap();
+    let mut handler = ConnectAttrsHandler::new(ConnectAttrsConfig::default(), client, Forward);
+    let attrs_seen = handler.attrs();
+    assert_eq!(handler.handle_response(&greeting(FLAGS | CLIENT_CONNECT_ATTRS)), Action::Forward);
+
+    let sent = attrs(&[("program_name", "billing")]);
+    let rewritten = match handler.handle_request(&response(&sent).to_packet(1)) {
+        Action::Mutate(p) => HandshakeResponse::parse(&p).unwrap(),
+        action => panic!("{:?}", action),
+    };
+    assert_eq!(rewritten.parse_connect_attrs().unwrap(), ConnectAttrsConfig::default().backend_attrs(&sent, &client));
+    assert_eq!(rewritten.username, "app");
+    assert_eq!(attrs_seen.get(), sent);
+    assert_eq!(attrs_seen.value("program_name"), Some("billing".to_string()));
+
+    // only the handshake response is rewritten
+    assert_eq!(handler.handle_request(&response(&sent).to_packet(1)), Action::Forward);
+}
+
+#[test]
+fn backends_without_attributes_get_the_response_as_it_was() {
+    let client = "10.1.2.3:40000".parse().unwrap();
+    let mut handler = ConnectAttrsHandler::new(ConnectAttrsConfig::default(), client, Forward);
+    let attrs_seen = handler.attrs();
+    handler.handle_response(&greeting(FLAGS));
+    let sent = attrs(&[("program_name", "billing")]);
+    assert_eq!(handler.handle_request(&response(&sent).to_packet(1)), Action::Forward);
+    // though what the client sent is still known
+    assert_eq!(attrs_seen.value("program_name"), Some("billing".to_string()));
+
+    // and SSL requests are left alone
+    let mut handler = ConnectAttrsHandler::new(ConnectAttrsConfig::default(), client, Forward);
+    handler.handle_response(&greeting(FLAGS | CLIENT_CONNECT_ATTRS | CLIENT_SSL));
+    let mut ssl_request = vec![0; 32];
+    ssl_request[..4].copy_from_slice(&(FLAGS | CLIENT_SSL).to_le_bytes());
+    assert_eq!(handler.handle_request(&Packet::new(1, &ssl_request)), Action::Forward);
+}
@@ -0,0 +1,199 @@
+extern crate mysql_proxy;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::audit::{self, AuditCompression, AuditFiles, AuditHandler, AuditLog, FsyncPolicy};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+#[derive(Clone,Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(str::to_string).collect()
+    }
+}
+
+impl io::Write for SharedBuf {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn log_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mysql-proxy-audit-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Wait for the background compression and pruning to leave `count` files in `dir`
+fn settled(dir: &Path, count: usize, gzipped: usize) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let names = file_names(dir);
+        if (names.len() == count && names.iter().filter(|n| n.ends_with(".gz")).count() == gzipped)
+            || Instant::now() > deadline {
+            return names;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn rotated_files_continue_the_chain() {
+    let dir = log_dir("rotate");
+    let path = dir.join("audit.log");
+    let files = AuditFiles { max_bytes: 1, fsync: FsyncPolicy::Always, ..AuditFiles::default() };
+    let log = AuditLog::open_files(&path, files.clone()).unwrap();
+    for i in 0..3 {
+        log.record("admin", &format!("DROP TABLE t{}", i)).unwrap();
+    }
+    drop(log);
+    // each record but the last went to a file of its own
+    assert_eq!(file_names(&dir).len(), 3);
+    assert_eq!(audit::verify_files(&path).unwrap(), 3);
+
+    // the current file on its own starts partway through the chain
+    let current = fs::read_to_string(&path).unwrap();
+    assert!(audit::verify(current.as_bytes()).is_err());
+
+    let log = AuditLog::open_files(&path, files).unwrap();
+    log.record("admin", "DROP TABLE t3").unwrap();
+    assert_eq!(audit::verify_files(&path).unwrap(), 4);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rotated_files_are_gzipped_and_pruned() {
+    let dir = log_dir("prune");
+    let path = dir.join("audit.log");
+    let files = AuditFiles { max_bytes: 1, compress: AuditCompression::Gzip, keep_files: 2, ..AuditFiles::default() };
+    let log = AuditLog::open_files(&path, files).unwrap();
+    for i in 0..5 {
+        log.record("admin", &format!("DROP TABLE t{}", i)).unwrap();
+        // one rotation at a time, so pruning sees every file compressed
+        settled(&dir, (i + 1).min(3), i.min(2));
+    }
+    let names = settled(&dir, 3, 2);
+    assert_eq!(names.iter().filter(|n| n.ends_with(".gz")).count(), 2, "{:?}", names);
+    // the oldest file kept starts after the removed ones
+    assert_eq!(audit::verify_files(&path).unwrap(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tampering_with_a_rotated_file_is_detected() {
+    let dir = log_dir("tamper");
+    let path = dir.join("audit.log");
+    let log = AuditLog::open_files(&path, AuditFiles { max_bytes: 1, ..AuditFiles::default() }).unwrap();
+    for i in 0..3 {
+        log.record("admin", &format!("DROP TABLE t{}", i)).unwrap();
+    }
+    // "audit.log" sorts first, then the file with t0
+    let middle = dir.join(&file_names(&dir)[2]);
+    let text = fs::read_to_string(&middle).unwrap().replace("DROP TABLE t1", "DROP TABLE t9");
+    fs::write(&middle, text).unwrap();
+    assert!(audit::verify_files(&path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn audit_files_need_an_audit_log_and_rotation() {
+    let config = ProxyConfig::parse(r#"
+        audit_files = { compress = "gzip" }
+    "#).unwrap();
+    assert_eq!(config.validate(), vec![
+        "Audit files: compress, keep_files and keep_secs need max_bytes or max_age_secs".to_string(),
+        "Audit files: needs an audit_log to rotate".to_string(),
+    ]);
+}
+
+#[test]
+fn edited_removed_or_reordered_records_break_the_chain() {
+    let buf = SharedBuf::default();
+    let log = AuditLog::new(Box::new(buf.clone()));
+    for i in 0..3 {
+        log.record("admin", &format!("DROP TABLE t{}", i)).unwrap();
+    }
+    let lines = buf.lines();
+    assert_eq!(audit::verify(lines.join("\n").as_bytes()).unwrap(), 3);
+
+    let edited = lines.join("\n").replace("DROP TABLE t1", "DROP TABLE t9");
+    let e = audit::verify(edited.as_bytes()).unwrap_err();
+    assert_eq!(e.to_string(), "Audit log line 2: record hash does not match its contents");
+
+    let removed = [&lines[0][..], &lines[2][..]].join("\n");
+    let e = audit::verify(removed.as_bytes()).unwrap_err();
+    assert_eq!(e.to_string(), "Audit log line 2: expected record 1 but found 2");
+
+    let reordered = [&lines[1][..], &lines[0][..], &lines[2][..]].join("\n");
+    assert!(audit::verify(reordered.as_bytes()).is_err());
+
+    // the first record can't be dropped either
+    assert!(audit::verify(lines[1..].join("\n").as_bytes()).is_err());
+}
+
+#[test]
+fn reopened_logs_continue_the_chain_unless_it_is_broken() {
+    let dir = log_dir("reopen");
+    let path = dir.join("audit.log");
+    AuditLog::open(&path).unwrap().record("admin", "DROP TABLE t0").unwrap();
+    AuditLog::open(&path).unwrap().record("admin", "DROP TABLE t1").unwrap();
+    assert_eq!(audit::verify(fs::read(&path).unwrap().as_slice()).unwrap(), 2);
+
+    let tampered = fs::read_to_string(&path).unwrap().replace("t0", "t9");
+    fs::write(&path, tampered).unwrap();
+    assert!(AuditLog::open(&path).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn audited_statements() {
+    for sql in &["DROP TABLE t", "/* x */ create user bob", "-- x\nGRANT ALL ON *.* TO bob", "SET GLOBAL max_connections = 10",
+                 "set @@global.read_only = 1", "SELECT 1; DROP TABLE t2", "SELECT 1;\nalter table t add c int;"] {
+        assert!(audit::is_audited(sql), "{}", sql);
+    }
+    for sql in &["SELECT 1", "SET SESSION sql_mode = ''", "SELECT 'x; DROP TABLE t'", "SELECT 1; /* ; DROP TABLE t */", "", ";"] {
+        assert!(!audit::is_audited(sql), "{}", sql);
+    }
+}
+
+#[test]
+fn queries_and_prepared_statements_with_audited_statements_are_recorded() {
+    let buf = SharedBuf::default();
+    let mut tester = HandlerTester::new(AuditHandler::new(AuditLog::new(Box::new(buf.clone())), "app", Forward));
+    for payload in &[&b"\x03SELECT 1; DROP TABLE t2"[..], b"\x03SELECT 1", b"\x16CREATE TABLE t3 (id INT)", b"\x16SELECT ?", b"\x06shop"] {
+        assert_eq!(tester.request(Packet::new(0, payload)), Action::Forward);
+    }
+    let statements: Vec<String> = buf.lines().iter().map(|line| {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        format!("{} {}", record["user"].as_str().unwrap(), record["statement"].as_str().unwrap())
+    }).collect();
+    assert_eq!(statements, vec!["app SELECT 1; DROP TABLE t2", "app CREATE TABLE t3 (id INT)", "app DROP DATABASE `shop`"]);
+}
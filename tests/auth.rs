@@ -0,0 +1,182 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::auth::{ChangeUserHandler, ProxyAuth};
+use mysql_proxy::codec::{ok_packet, HandshakeResponse, HandshakeV10};
+use mysql_proxy::protocol::{native_password_auth, ER_ACCESS_DENIED_ERROR, ER_NET_PACKETS_OUT_OF_ORDER, ER_NET_READ_INTERRUPTED, CLIENT_PLUGIN_AUTH, CLIENT_PROTOCOL_41, CLIENT_SECURE_CONNECTION};
+use mysql_proxy::rowfilter::ER_NOT_SUPPORTED_YET;
+use mysql_proxy::sidechannel::read_packet;
+use mysql_proxy::testing::{Forward, HandlerTester};
+use mysql_proxy::users::{UserMap, UserMapping};
+
+/// Accept a single client, running `client` against the proxy on a thread of its own, and
+/// return how authenticating it ended along with what the client saw
+fn handshake<F>(auth: &ProxyAuth, client: F) -> (String, Vec<Vec<u8>>)
+    where F: FnOnce(&mut TcpStream) + Send + 'static
+{
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client(&mut stream);
+        let mut seen = vec![];
+        while let Ok(p) = read_packet(&mut stream) {
+            seen.push(p.payload().to_vec());
+        }
+        // the proxy closed the connection rather than the read timing out
+        assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+        seen
+    });
+    let (socket, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e)).unwrap().0.unwrap();
+    let result = core.run(auth.establish(socket, |_| None, &handle));
+    let outcome = match result {
+        Ok(_) => "established".to_string(),
+        Err(e) => e.to_string(),
+    };
+    (outcome, seen.join().unwrap())
+}
+
+/// A handshake response from alice for `plugin`, answering `scramble` if there's one
+fn response(plugin: &str, scramble: Option<&[u8]>) -> Packet {
+    let response = HandshakeResponse {
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        max_packet_size: 1 << 24,
+        character_set: 0x21,
+        username: "alice".to_string(),
+        auth_response: scramble.map(|s| native_password_auth("secret", s)).unwrap_or_default(),
+        database: None,
+        auth_plugin_name: Some(plugin.to_string()),
+        connect_attrs: None,
+    };
+    response.to_packet(1)
+}
+
+fn auth() -> ProxyAuth {
+    let alice = UserMapping {
+        user: "alice".to_string(),
+        password: Some("secret".to_string()),
+        default_group: "main".to_string(),
+        ..UserMapping::default()
+    };
+    ProxyAuth::new(Arc::new(UserMap::new(vec![alice]))).with_handshake_timeout(Some(Duration::from_millis(100)))
+}
+
+#[test]
+fn silent_clients_are_told_they_timed_out() {
+    let auth = auth();
+    let (outcome, seen) = handshake(&auth, |_| {});
+    assert_eq!(outcome, "Client took too long to log in");
+    // the greeting, then the error
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[1][0], 0xff);
+    assert_eq!(&seen[1][1..3], &ER_NET_READ_INTERRUPTED.to_le_bytes());
+    assert_eq!(&seen[1][3..9], b"#08S01");
+    assert_eq!(auth.handshake_timeouts(), 1);
+}
+
+#[test]
+fn clients_stalling_mid_login_are_disconnected() {
+    let auth = auth();
+    let (outcome, seen) = handshake(&auth, |stream| {
+        read_packet(stream).unwrap();
+        // a plugin the proxy doesn't want, so it asks to switch, and the client never does
+        stream.write_all(&response("caching_sha2_password", None).bytes).unwrap();
+    });
+    assert_eq!(outcome, "Client took too long to log in");
+    // only the auth switch request
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0][0], 0xfe);
+    assert_eq!(auth.handshake_timeouts(), 1);
+
+    // and without a limit, the time a client takes doesn't matter
+    let auth = auth.with_handshake_timeout(None);
+    let (outcome, _) = handshake(&auth, |stream| {
+        let greeting = HandshakeV10::parse(&read_packet(stream).unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        stream.write_all(&response("mysql_native_password", Some(&greeting.auth_plugin_data)).bytes).unwrap();
+    });
+    assert_eq!(outcome, "No backend available for routing group 'main'");
+    assert_eq!(auth.handshake_timeouts(), 1);
+}
+
+#[test]
+fn handshake_packets_must_follow_the_proxys() {
+    let auth = auth();
+    let (outcome, seen) = handshake(&auth, |stream| {
+        read_packet(stream).unwrap();
+        stream.write_all(&response("mysql_native_password", None).with_sequence_id(3).bytes).unwrap();
+    });
+    assert_eq!(outcome, "Client sent sequence id 3 where 1 was expected");
+    assert_eq!(seen.len(), 1);
+    assert_eq!(&seen[0][1..9], &[&ER_NET_PACKETS_OUT_OF_ORDER.to_le_bytes()[..], b"#08S01"].concat()[..]);
+    assert_eq!(auth.handshake_timeouts(), 0);
+}
+
+#[test]
+fn clients_are_greeted_with_a_fresh_scramble() {
+    let auth = auth();
+    let (outcome, seen) = handshake(&auth, |stream| {
+        let greeting = HandshakeV10::parse(&read_packet(stream).unwrap()).unwrap();
+        assert_eq!(greeting.auth_plugin_data.len(), 20);
+        assert_eq!(greeting.auth_plugin_name.as_ref().map(|s| &s[..]), Some("mysql_native_password"));
+        stream.write_all(&response("mysql_native_password", Some(&greeting.auth_plugin_data)).bytes).unwrap();
+    });
+    // the password was right, so only routing is left to fail
+    assert_eq!(outcome, "No backend available for routing group 'main'");
+    assert_eq!(seen.len(), 1);
+}
+
+#[test]
+fn wrong_passwords_are_refused() {
+    let auth = auth();
+    let (outcome, seen) = handshake(&auth, |stream| {
+        let greeting = HandshakeV10::parse(&read_packet(stream).unwrap()).unwrap();
+        let mut response = HandshakeResponse::parse(&response("mysql_native_password", None)).unwrap();
+        response.auth_response = native_password_auth("wrong", &greeting.auth_plugin_data);
+        stream.write_all(&response.to_packet(1).bytes).unwrap();
+    });
+    assert_eq!(outcome, "Access denied for user 'alice'");
+    assert_eq!(seen.len(), 1);
+    assert_eq!(&seen[0][1..9], &[&ER_ACCESS_DENIED_ERROR.to_le_bytes()[..], b"#28000"].concat()[..]);
+
+    // nor is the answer to another scramble any good
+    let (outcome, _) = handshake(&auth, |stream| {
+        read_packet(stream).unwrap();
+        stream.write_all(&response("mysql_native_password", Some(&[0x41; 20])).bytes).unwrap();
+    });
+    assert_eq!(outcome, "Access denied for user 'alice'");
+}
+
+#[test]
+fn proxy_authenticated_clients_cant_change_user() {
+    let mut tester = HandlerTester::new(ChangeUserHandler::new(Forward));
+    // handshake packets that happen to look like it are left alone
+    assert_eq!(tester.request(Packet::new(1, b"\x11alice")), Action::Forward);
+
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")), Action::Forward);
+    tester.response(ok_packet(1));
+    match tester.request(Packet::new(0, b"\x11root\x00")) {
+        Action::Error { code, state, msg } => {
+            assert_eq!(code, ER_NOT_SUPPORTED_YET);
+            assert_eq!(&state, b"42000");
+            assert_eq!(msg, "COM_CHANGE_USER isn't supported for users the proxy authenticates");
+        },
+        action => panic!("{:?}", action),
+    }
+    assert_eq!(tester.request(Packet::new(0, b"\x0e")), Action::Forward);
+}
@@ -0,0 +1,262 @@
+extern crate mysql_proxy;
+
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use mysql_proxy::authenticator::{Authenticator, AuthenticatorPool, CachingAuthenticator, LdapAuthenticator, WebhookAuthenticator};
+use mysql_proxy::config::ProxyConfig;
+
+/// Accept one connection, answering whatever arrives first with `reply`, sent in two parts
+/// so that it has to be reassembled, and return what was received
+fn serve_once(reply: Vec<u8>) -> (String, thread::JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![0; 4096];
+        let n = stream.read(&mut request).unwrap();
+        request.truncate(n);
+        let (first, rest) = reply.split_at(reply.len() / 2);
+        stream.write_all(first).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(rest).unwrap();
+        request
+    });
+    (addr, server)
+}
+
+/// An LDAP BindResponse with `code`
+fn bind_response(code: u8) -> Vec<u8> {
+    vec![0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, code, 0x04, 0x00, 0x04, 0x00]
+}
+
+#[test]
+fn ldap_binds_as_the_user() {
+    let (addr, server) = serve_once(bind_response(0));
+    let ldap = LdapAuthenticator::new(&addr, "uid={user},ou=people");
+    assert!(ldap.authenticate("a,b", "secret").unwrap());
+
+    // a BindRequest with message id 1, version 3, the escaped DN and the password
+    let dn = b"uid=a\\,b,ou=people";
+    let mut bind = vec![0x02, 0x01, 0x03, 0x04, dn.len() as u8];
+    bind.extend_from_slice(dn);
+    bind.extend_from_slice(b"\x80\x06secret");
+    let mut expected = vec![0x30, (bind.len() + 5) as u8, 0x02, 0x01, 0x01, 0x60, bind.len() as u8];
+    expected.extend_from_slice(&bind);
+    assert_eq!(server.join().unwrap(), expected);
+}
+
+#[test]
+fn ldap_encodes_long_values_with_long_form_lengths() {
+    let (addr, server) = serve_once(bind_response(49));
+    let password = "p".repeat(300);
+    let ldap = LdapAuthenticator::new(&addr, "uid={user}");
+    assert!(!ldap.authenticate("alice", &password).unwrap());
+
+    let request = server.join().unwrap();
+    // lengths over 255 bytes take two more
+    assert_eq!(&request[0..2], &[0x30, 0x82]);
+    assert_eq!(((request[2] as usize) << 8 | request[3] as usize) + 4, request.len());
+    assert_eq!(&request[4..8], &[0x02, 0x01, 0x01, 0x60]);
+    let password_at = request.len() - 300 - 4;
+    assert_eq!(&request[password_at..password_at + 4], &[0x80, 0x82, 0x01, 0x2c]);
+    assert!(request.ends_with(password.as_bytes()));
+
+    // and over 65535 bytes three
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).unwrap();
+        let len = (header[2] as usize) << 16 | (header[3] as usize) << 8 | header[4] as usize;
+        let mut request = header.to_vec();
+        request.resize(5 + len, 0);
+        stream.read_exact(&mut request[5..]).unwrap();
+        stream.write_all(&bind_response(49)).unwrap();
+        request
+    });
+    let password = "p".repeat(70000);
+    assert!(!LdapAuthenticator::new(&addr, "uid={user}").authenticate("alice", &password).unwrap());
+    let request = server.join().unwrap();
+    assert_eq!(&request[0..2], &[0x30, 0x83]);
+    let password_at = request.len() - 70000 - 5;
+    assert_eq!(&request[password_at..password_at + 5], &[0x80, 0x83, 0x01, 0x11, 0x70]);
+}
+
+#[test]
+fn ldap_reports_other_results_and_bad_responses_as_errors() {
+    let (addr, server) = serve_once(bind_response(52));
+    let e = LdapAuthenticator::new(&addr, "uid={user}").authenticate("alice", "secret").unwrap_err();
+    assert_eq!(e.to_string(), "LDAP bind failed with result code 52");
+    server.join().unwrap();
+
+    // a SearchResultDone rather than a BindResponse
+    let mut response = bind_response(0);
+    response[5] = 0x65;
+    let (addr, server) = serve_once(response);
+    let e = LdapAuthenticator::new(&addr, "uid={user}").authenticate("alice", "secret").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    server.join().unwrap();
+
+    // lengths of more than 4 bytes, which no response needs
+    let (addr, server) = serve_once(vec![0x30, 0x89, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]);
+    let e = LdapAuthenticator::new(&addr, "uid={user}").authenticate("alice", "secret").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    server.join().unwrap();
+}
+
+#[test]
+fn ldap_refuses_empty_passwords_without_asking() {
+    // nothing listens here, so binding would fail
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    assert!(!LdapAuthenticator::new(&addr, "uid={user}").authenticate("alice", "").unwrap());
+}
+
+fn http(status: &str) -> Vec<u8> {
+    format!("HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status).into_bytes()
+}
+
+#[test]
+fn webhooks_are_posted_the_credentials() {
+    let (addr, server) = serve_once(http("204 No Content"));
+    let webhook = WebhookAuthenticator::new(&format!("http://{}/mysql-login", addr));
+    assert!(webhook.authenticate("alice", "se\"cret").unwrap());
+    let request = String::from_utf8(server.join().unwrap()).unwrap();
+    assert!(request.starts_with("POST /mysql-login HTTP/1.0\r\n"), "{}", request);
+    assert!(request.ends_with(r#"{"password":"se\"cret","user":"alice"}"#), "{}", request);
+
+    for &(status, valid) in &[("401 Unauthorized", Some(false)), ("403 Forbidden", Some(false)), ("500 Internal Server Error", None)] {
+        let (addr, server) = serve_once(http(status));
+        let result = WebhookAuthenticator::new(&format!("http://{}/", addr)).authenticate("alice", "secret");
+        assert_eq!(result.ok(), valid, "{}", status);
+        server.join().unwrap();
+    }
+
+    let e = WebhookAuthenticator::new("ftp://auth.internal/").authenticate("alice", "secret").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
+
+/// Accepts `password` and counts how often it's asked
+struct Counting {
+    password: &'static str,
+    calls: Arc<Mutex<usize>>,
+}
+
+impl Authenticator for Counting {
+
+    fn authenticate(&self, _: &str, password: &str) -> io::Result<bool> {
+        *self.calls.lock().unwrap() += 1;
+        Ok(password == self.password)
+    }
+}
+
+#[test]
+fn successful_authentications_are_cached_until_they_expire() {
+    let calls = Arc::new(Mutex::new(0));
+    let cache = CachingAuthenticator::new(Counting { password: "secret", calls: calls.clone() }, Duration::from_millis(100));
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert_eq!(*calls.lock().unwrap(), 1);
+
+    // other passwords are always checked, and failures aren't cached
+    assert!(!cache.authenticate("alice", "wrong").unwrap());
+    assert!(!cache.authenticate("alice", "wrong").unwrap());
+    assert_eq!(*calls.lock().unwrap(), 3);
+
+    // a failure forgets the cached success
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert_eq!(*calls.lock().unwrap(), 4);
+
+    thread::sleep(Duration::from_millis(150));
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert_eq!(*calls.lock().unwrap(), 5);
+
+    cache.clear();
+    assert!(cache.authenticate("alice", "secret").unwrap());
+    assert_eq!(*calls.lock().unwrap(), 6);
+}
+
+/// Blocks every check it starts until the test lets it through
+struct Gate {
+    started: Mutex<mpsc::Sender<()>>,
+    open: Mutex<mpsc::Receiver<()>>,
+}
+
+impl Authenticator for Gate {
+
+    fn authenticate(&self, _: &str, _: &str) -> io::Result<bool> {
+        self.started.lock().unwrap().send(()).unwrap();
+        self.open.lock().unwrap().recv().unwrap();
+        Ok(true)
+    }
+}
+
+#[test]
+fn logins_beyond_the_pools_queue_fail_at_once() {
+    let (started, checking) = mpsc::channel();
+    let (open, gate) = mpsc::channel();
+    let gate = Gate { started: Mutex::new(started), open: Mutex::new(gate) };
+    let pool = AuthenticatorPool::new(Arc::new(gate), 1, 1).unwrap();
+    let (done, results) = mpsc::channel();
+    let check = |user: &str| {
+        let done = done.clone();
+        pool.authenticate(user.to_string(), "secret".to_string(), move |r| done.send(r.unwrap()).unwrap())
+    };
+
+    // one check on the thread, then one queued
+    check("alice").unwrap();
+    checking.recv_timeout(Duration::from_secs(5)).unwrap();
+    check("bob").unwrap();
+    assert_eq!(check("carol").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    open.send(()).unwrap();
+    open.send(()).unwrap();
+    assert_eq!(results.recv_timeout(Duration::from_secs(5)), Ok(true));
+    assert_eq!(results.recv_timeout(Duration::from_secs(5)), Ok(true));
+}
+
+#[test]
+fn users_need_a_password_without_an_authenticator() {
+    let users = r#"
+        [groups.main]
+        backends = ["127.0.0.1:3306"]
+
+        [[users]]
+        user = "alice"
+        default_group = "main"
+
+        [[users]]
+        user = "*"
+        password = "secret"
+        default_group = "main"
+
+        [[users]]
+        user = "kerberos"
+        auth_passthrough = true
+        default_group = "main"
+    "#;
+    assert_eq!(ProxyConfig::parse(users).unwrap().validate(), vec![
+        "User 'alice': no password, and no [auth] authenticator to check clients with".to_string(),
+        "User '*': a `*` mapping needs an [auth] authenticator".to_string(),
+    ]);
+
+    let auth = "[auth]\nprovider = \"ldap\"\naddr = \"ldap:636\"\nbind_dn = \"uid={user}\"\ntls = \"ldaps\"\n";
+    let tls = "[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\nrequired = true\n";
+    let config = ProxyConfig::parse(&format!("{}\n{}\n{}", users, auth, tls)).unwrap();
+    assert_eq!(config.validate(), Vec::<String>::new());
+
+    // clients send an authenticator their passwords in clear text
+    let config = ProxyConfig::parse(&format!("{}\n{}\n{}", users, auth, tls.replace("required = true", ""))).unwrap();
+    assert_eq!(config.validate(), vec![
+        "Authenticator: needs [tls] with `required = true`, as clients send it their passwords in clear text".to_string(),
+    ]);
+}
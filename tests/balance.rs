@@ -0,0 +1,79 @@
+extern crate mysql_proxy;
+
+use std::io;
+use std::time::Duration;
+
+use mysql_proxy::balance::BackendWeights;
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::health::HealthMonitor;
+use mysql_proxy::latency::{BackendLatency, LatencyConfig};
+
+#[test]
+fn choose_backends_by_weight() {
+    let backends = vec![BackendAddr::new("db1", 3306), BackendAddr::new("db2", 3306), BackendAddr::new("db3", 3306)];
+    let weights = BackendWeights::new();
+    let choose = |n: usize| (0..n).map(|_| weights.choose(&backends).map(|b| b.host().to_string())).collect::<Vec<_>>();
+    let some = |hosts: &[&str]| hosts.iter().map(|h| Some(h.to_string())).collect::<Vec<_>>();
+
+    // only the first backend until others get a weight
+    assert_eq!(choose(2), some(&["db1", "db1"]));
+    weights.set(&backends[1], 2);
+    assert_eq!(choose(3), some(&["db2", "db1", "db2"]));
+
+    // draining every backend leaves none to choose
+    weights.set(&backends[0], 0);
+    assert_eq!(choose(2), some(&["db2", "db2"]));
+    weights.set(&backends[1], 0);
+    assert_eq!(choose(1), vec![None]);
+}
+
+#[test]
+fn favour_backends_that_answer_fastest() {
+    let backends = vec![BackendAddr::new("db1", 3306), BackendAddr::new("db2", 3306), BackendAddr::new("db3", 3306)];
+    let config = LatencyConfig { decay: 0.5, outlier_factor: 3.0 };
+    let latency = BackendLatency::new();
+    let weights = BackendWeights::new();
+    for b in &backends {
+        weights.set(b, 1);
+    }
+
+    // unmeasured backends keep their weight
+    assert_eq!(latency.scales(&backends, &config), vec![1.0, 1.0, 1.0]);
+    latency.record(&backends[0], Duration::from_millis(2), config.decay);
+    latency.record(&backends[1], Duration::from_millis(1), config.decay);
+    latency.record(&backends[1], Duration::from_millis(3), config.decay);
+    assert_eq!(latency.average(&backends[1]), Some(Duration::from_millis(2)));
+    latency.record(&backends[2], Duration::from_millis(50), config.decay);
+    assert_eq!(latency.scales(&backends, &config), vec![1.0, 1.0, 0.0]);
+
+    latency.record(&backends[1], Duration::from_millis(6), config.decay);
+    let scales = latency.scales(&backends, &config);
+    assert_eq!(scales[..2], [1.0, 0.5]);
+    let chosen: Vec<String> = (0..6).map(|_| weights.choose_scaled(&backends, &scales).unwrap().host().to_string()).collect();
+    assert_eq!(chosen.iter().filter(|h| *h == "db1").count(), 4);
+    assert!(!chosen.contains(&"db3".to_string()));
+}
+
+#[test]
+fn warm_up_backends_that_come_back_up() {
+    let backends = vec![BackendAddr::new("db1", 3306), BackendAddr::new("db2", 3306)];
+    let weights = BackendWeights::new();
+    for b in &backends {
+        weights.set(b, 1);
+    }
+    let monitor = HealthMonitor::new().with_warm_up(weights.clone(), Duration::from_secs(3600));
+    let choose = |n: usize| (0..n).map(|_| weights.choose(&backends).unwrap().host().to_string()).collect::<Vec<_>>();
+
+    // a backend that was up all along isn't warmed up
+    monitor.record(&backends[0], &Ok(()));
+    monitor.record(&backends[1], &Ok(()));
+    assert_eq!(choose(2), vec!["db1", "db2"]);
+
+    // one that comes back gets next to nothing at first
+    monitor.record(&backends[0], &Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+    monitor.record(&backends[0], &Ok(()));
+    assert!(choose(20).iter().all(|h| h == "db2"));
+
+    // unless it's all there is
+    assert_eq!(weights.choose(&backends[..1]), Some(backends[0].clone()));
+}
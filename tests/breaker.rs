@@ -0,0 +1,108 @@
+extern crate mysql_proxy;
+
+use std::thread;
+use std::time::Duration;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::breaker::{BreakerHandler, CircuitBreaker, CircuitBreakerConfig, CircuitState};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::Forward;
+
+fn backend(s: &str) -> BackendAddr {
+    s.parse().unwrap()
+}
+
+fn error(code: u16, msg: &str) -> Packet {
+    let mut payload = vec![0xff, code as u8, (code >> 8) as u8];
+    payload.extend_from_slice(b"#HY000");
+    payload.extend_from_slice(msg.as_bytes());
+    Packet::new(1, &payload)
+}
+
+#[test]
+fn failed_connections_open_the_circuit() {
+    let breaker = CircuitBreaker::new(CircuitBreakerConfig { connect_failures: 3, ..CircuitBreakerConfig::default() });
+    let (db1, db2) = (backend("db1:3306"), backend("db2:3306"));
+    breaker.connect_failed(&db1);
+    breaker.connect_failed(&db1);
+    // a success starts the count again
+    breaker.connected(&db1);
+    breaker.connect_failed(&db1);
+    breaker.connect_failed(&db1);
+    assert_eq!(breaker.state(&db1), CircuitState::Closed);
+
+    breaker.connect_failed(&db1);
+    assert_eq!(breaker.state(&db1), CircuitState::Open);
+    assert_eq!(breaker.open_circuits(), vec![db1.clone()]);
+    assert_eq!(breaker.available(&[db1.clone(), db2.clone()]), vec![db2.clone()]);
+    assert!(!breaker.admit(&db1));
+    assert!(breaker.admit(&db2));
+}
+
+#[test]
+fn a_half_open_circuit_lets_probes_through() {
+    let breaker = CircuitBreaker::new(CircuitBreakerConfig { connect_failures: 1, cooldown_secs: 1, ..CircuitBreakerConfig::default() });
+    let db = backend("db:3306");
+    breaker.connect_failed(&db);
+    assert_eq!(breaker.state(&db), CircuitState::Open);
+    thread::sleep(Duration::from_millis(1100));
+
+    // one probe at a time, and a failed one opens the circuit again
+    assert_eq!(breaker.state(&db), CircuitState::HalfOpen);
+    assert!(breaker.admit(&db));
+    assert_eq!(breaker.available(&[db.clone(), backend("other:3306")]), vec![backend("other:3306")]);
+    assert!(!breaker.admit(&db));
+    breaker.connect_failed(&db);
+    assert_eq!(breaker.state(&db), CircuitState::Open);
+    thread::sleep(Duration::from_millis(1100));
+
+    assert!(breaker.admit(&db));
+    breaker.connected(&db);
+    assert_eq!(breaker.state(&db), CircuitState::Closed);
+    assert!(breaker.admit(&db));
+    assert!(breaker.admit(&db));
+}
+
+#[test]
+fn overload_errors_open_the_circuit() {
+    let breaker = CircuitBreaker::new(CircuitBreakerConfig { min_commands: 4, error_rate: 0.5, ..CircuitBreakerConfig::default() });
+    let db = backend("db:3306");
+    let mut handler = BreakerHandler::new(breaker.clone(), db.clone(), Forward).with_capabilities(CLIENT_PROTOCOL_41);
+    let mut run = |response: Packet| {
+        assert_eq!(handler.handle_request(&Packet::new(0, b"\x03SELECT 1")), Action::Forward);
+        assert_eq!(handler.handle_response(&response), Action::Forward);
+    };
+    // the client's own mistakes don't count
+    run(error(1064, "You have an error in your SQL syntax"));
+    run(ok_packet(1));
+    run(error(1040, "Too many connections"));
+    assert_eq!(breaker.state(&db), CircuitState::Closed);
+    run(error(1205, "Lock wait timeout exceeded; try restarting transaction"));
+    assert_eq!(breaker.state(&db), CircuitState::Open);
+}
+
+#[test]
+fn backends_can_have_thresholds_of_their_own() {
+    let config = ProxyConfig::parse(r#"
+        [circuit_breaker]
+        connect_failures = 5
+
+        [circuit_breaker.backends."fragile:3306"]
+        connect_failures = 1
+    "#).unwrap();
+    let breaker = CircuitBreaker::new(config.circuit_breaker.unwrap());
+    let (fragile, sturdy) = (backend("fragile:3306"), backend("sturdy:3306"));
+    breaker.connect_failed(&fragile);
+    breaker.connect_failed(&sturdy);
+    assert_eq!(breaker.state(&fragile), CircuitState::Open);
+    assert_eq!(breaker.state(&sturdy), CircuitState::Closed);
+
+    let problems = ProxyConfig::parse(r#"
+        [circuit_breaker.backends."db:3306"]
+        error_rate = 2.0
+    "#).unwrap().validate();
+    assert!(problems.iter().any(|p| p.starts_with("Circuit breaker: backend 'db:3306': error_rate")), "{:?}", problems);
+}
@@ -0,0 +1,84 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::capabilities::{Capability, CapabilityHandler, CapabilityPolicy};
+use mysql_proxy::codec::{HandshakeResponse, HandshakeV10};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::*;
+use mysql_proxy::testing::Forward;
+
+const FLAGS: u32 = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH | CLIENT_LOCAL_FILES
+    | CLIENT_MULTI_STATEMENTS | CLIENT_DEPRECATE_EOF | CLIENT_CONNECT_WITH_DB;
+
+fn greeting(capability_flags: u32) -> Packet {
+    HandshakeV10 {
+        server_version: "8.0.36".to_string(),
+        connection_id: 7,
+        capability_flags,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some("mysql_native_password".to_string()),
+    }.to_packet(0)
+}
+
+fn response(capability_flags: u32) -> Packet {
+    HandshakeResponse {
+        capability_flags,
+        max_packet_size: 1 << 24,
+        character_set: 0x21,
+        username: "app".to_string(),
+        auth_response: vec![0x01; 20],
+        database: Some("shop".to_string()),
+        auth_plugin_name: Some("mysql_native_password".to_string()),
+        connect_attrs: None,
+    }.to_packet(1)
+}
+
+fn policy(disable: &[Capability]) -> CapabilityPolicy {
+    CapabilityPolicy { disable: disable.to_vec() }
+}
+
+#[test]
+fn capabilities_are_named_in_the_configuration() {
+    let config = ProxyConfig::parse("capabilities = { disable = [\"local_files\", \"multi_statements\", \"deprecate_eof\"] }").unwrap();
+    assert_eq!(config.capabilities.disabled(), CLIENT_LOCAL_FILES | CLIENT_MULTI_STATEMENTS | CLIENT_DEPRECATE_EOF);
+    assert!(ProxyConfig::parse("capabilities = { disable = [\"ssl\"] }").is_err());
+    assert_eq!(policy(&[Capability::Compress]).apply(CLIENT_COMPRESS | CLIENT_PROTOCOL_41), CLIENT_PROTOCOL_41);
+}
+
+#[test]
+fn disabled_capabilities_are_cleared_from_both_sides_of_the_handshake() {
+    let policy = policy(&[Capability::LocalFiles, Capability::DeprecateEof]);
+    let expected = FLAGS & !CLIENT_LOCAL_FILES & !CLIENT_DEPRECATE_EOF;
+
+    // in both halves of the greeting's flags, and nothing else changes
+    let greeting = policy.rewrite_greeting(&greeting(FLAGS)).unwrap();
+    assert_eq!(HandshakeV10::parse(&greeting).unwrap().capability_flags, expected);
+    assert_eq!(greeting.bytes.len(), self::greeting(FLAGS).bytes.len());
+
+    let response = policy.rewrite_response(&response(FLAGS)).unwrap();
+    let parsed = HandshakeResponse::parse(&response).unwrap();
+    assert_eq!(parsed.capability_flags, expected);
+    assert_eq!(parsed.database, Some("shop".to_string()));
+
+    // packets that already comply are left alone
+    assert_eq!(policy.rewrite_greeting(&self::greeting(expected)), None);
+    assert_eq!(policy.rewrite_response(&self::response(expected)), None);
+    // as is anything that isn't a greeting or a 4.1 response
+    assert_eq!(policy.rewrite_greeting(&Packet::new(0, &[0xff, 0x15, 0x04])), None);
+    assert_eq!(policy.rewrite_response(&Packet::new(1, &[0x85, 0xa6, 0x03, 0x00])), None);
+}
+
+#[test]
+fn the_handler_rewrites_only_the_handshake() {
+    let mut handler = CapabilityHandler::new(policy(&[Capability::MultiStatements]), Forward);
+    assert_eq!(handler.handle_response(&greeting(FLAGS)), Action::Mutate(policy(&[Capability::MultiStatements]).rewrite_greeting(&greeting(FLAGS)).unwrap()));
+    match handler.handle_request(&response(FLAGS)) {
+        Action::Mutate(p) => assert_eq!(HandshakeResponse::parse(&p).unwrap().capability_flags, FLAGS & !CLIENT_MULTI_STATEMENTS),
+        action => panic!("{:?}", action),
+    }
+    // later packets that happen to look like them are relayed untouched
+    assert_eq!(handler.handle_response(&greeting(FLAGS)), Action::Forward);
+    assert_eq!(handler.handle_request(&response(FLAGS)), Action::Forward);
+}
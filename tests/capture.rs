@@ -0,0 +1,72 @@
+extern crate mysql_proxy;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use mysql_proxy::{Packet, PacketHandler};
+use mysql_proxy::audit::AuditLog;
+use mysql_proxy::capture::{CaptureConfig, CaptureHandler, Outcome, SessionCapture};
+use mysql_proxy::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+use mysql_proxy::testing::Forward;
+
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn column(name: &str) -> Vec<u8> {
+    let mut payload = vec![];
+    for s in &["def", "shop", "orders", "orders", name, name] {
+        payload.push(s.len() as u8);
+        payload.extend_from_slice(s.as_bytes());
+    }
+    payload.extend_from_slice(&[0x0c, 0x21, 0, 11, 0, 0, 0, 3, 0, 0, 0, 0, 0]);
+    payload
+}
+
+#[test]
+fn capture_the_last_commands_of_a_session() {
+    let capture = SessionCapture::new(&CaptureConfig { exchanges: 2 });
+    let mut handler = CaptureHandler::new(capture.clone(), Forward).with_capabilities(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+    let mut exchange = |query: &[u8], responses: Vec<Vec<u8>>| {
+        handler.handle_request(&Packet::new(0, query));
+        for (i, payload) in responses.iter().enumerate() {
+            handler.handle_response(&Packet::new(i as u8 + 1, payload));
+        }
+    };
+
+    exchange(b"\x03SET NAMES utf8mb4", vec![vec![0x00, 0, 0, 2, 0, 0, 0]]);
+    exchange(b"\x03UPDATE orders SET paid = 1 WHERE id IN (1, 2, 3)", vec![vec![0x00, 3, 0, 2, 0, 0, 0]]);
+    exchange(b"\x03SELECT id FROM orders WHERE user_id = 7",
+             vec![vec![1], column("id"), vec![1, b'1'], vec![1, b'2'], vec![0xfe, 0, 0, 2, 0, 0, 0]]);
+    exchange(b"\x03SELECT * FROM missing", vec![b"\xff\x7a\x04#42S02Table 'shop.missing' doesn't exist".to_vec()]);
+    exchange(b"\x03SELECT SLEEP(600)", vec![]);
+
+    // the last two answered and the one still waiting, without their literals
+    let exchanges = capture.exchanges();
+    let summary: Vec<(&str, &Outcome)> = exchanges.iter().map(|e| (&e.command[..], &e.outcome)).collect();
+    assert_eq!(summary, vec![
+        ("SELECT id FROM orders WHERE user_id = ?", &Outcome::Rows(2)),
+        ("SELECT * FROM missing", &Outcome::Err { code: 1146, message: "Table 'shop.missing' doesn't exist".to_string() }),
+        ("SELECT SLEEP(...)", &Outcome::Pending),
+    ]);
+    assert!(exchanges[1].elapsed_ms.is_some());
+    assert_eq!(exchanges[2].elapsed_ms, None);
+
+    let written = Shared::default();
+    capture.dump(&AuditLog::new(Box::new(written.clone())), "app", "10.0.0.5:51234", "Connection reset by peer").unwrap();
+    let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains("connection from 10.0.0.5:51234 ended: Connection reset by peer; its last 3 commands follow"));
+    assert!(lines[3].contains("SELECT SLEEP(...) at ") && lines[3].contains(": no response"));
+}
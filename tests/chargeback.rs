@@ -0,0 +1,61 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::Packet;
+use mysql_proxy::chargeback::{Chargeback, CSV_HEADER};
+use mysql_proxy::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+use mysql_proxy::stats::{Stats, StatsHandler};
+use mysql_proxy::testing::{Forward, HandlerTester, Step};
+
+fn session(stats: &Stats, user: &str) -> HandlerTester<StatsHandler<Forward>> {
+    HandlerTester::new(StatsHandler::new(user, Forward).with_stats(stats.clone()))
+        .with_backend_capabilities(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF)
+}
+
+fn select(tester: &mut HandlerTester<StatsHandler<Forward>>) {
+    tester.run(vec![
+        Step::Request(Packet::new(0, b"\x03SELECT name FROM t")),
+        Step::Response(Packet::new(1, &[0x01])),
+        Step::Response(Packet::new(2, b"\x03def\x00\x01t\x01t\x04name\x04name\x0c\x21\x00\x10\x00\x00\x00\xfd\x00\x00\x00\x00\x00")),
+        Step::Response(Packet::new(3, b"\x05alice")),
+        Step::Response(Packet::new(4, &[0xfe, 0, 0, 2, 0, 0, 0])),
+    ]);
+}
+
+#[test]
+fn report_each_users_usage_by_period() {
+    let stats = Stats::new();
+    // usage before the first period isn't charged
+    select(&mut session(&stats, "billing"));
+    let chargeback = Chargeback::new(stats.clone());
+
+    let mut billing = session(&stats, "billing");
+    select(&mut billing);
+    select(&mut billing);
+    // a prepared statement's time and response count too, but it isn't a query
+    billing.run(vec![
+        Step::Request(Packet::new(0, b"\x16SELECT 1")),
+        Step::Response(Packet::new(1, &[0x00, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])),
+    ]);
+    let mut search = session(&stats, "search,eu");
+    search.run(vec![
+        Step::Request(Packet::new(0, b"\x03SELECT * FROM missing")),
+        Step::Response(Packet::new(1, b"\xff\x7a\x04#42S02Table 'missing' doesn't exist")),
+    ]);
+
+    let report = chargeback.current();
+    let billing = &report.users["billing"];
+    assert_eq!((billing.connections, billing.queries, billing.errors), (1, 2, 0));
+    assert_eq!(billing.bytes_returned, 2 * (5 + 36 + 10 + 11) + 16);
+    assert_eq!(report.users["search,eu"].errors, 1);
+
+    let mut csv = vec![];
+    chargeback.close_period().write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let rows: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+    assert_eq!(CSV_HEADER.split(',').nth(2), Some("user"));
+    assert_eq!(&rows[0][2..7], &["billing", "1", "2", "0", "140"]);
+    assert_eq!(&rows[1][2..4], &["\"search", "eu\""]);
+
+    // the next period starts from nothing
+    assert!(chargeback.current().users.is_empty());
+}
@@ -0,0 +1,107 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::charset::{Charset, CharsetHandler, Collation, ER_UNKNOWN_CHARACTER_SET};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+/// A latin1 client of a utf8mb4 backend
+fn tester() -> HandlerTester<CharsetHandler<Forward>> {
+    let handler = CharsetHandler::new("latin1_swedish_ci".parse().unwrap(), "utf8mb4_general_ci".parse().unwrap(), Forward)
+        .with_capabilities(CLIENT_PROTOCOL_41);
+    HandlerTester::new(handler).with_backend_capabilities(CLIENT_PROTOCOL_41)
+}
+
+/// What the handler sends the backend for a command
+fn sent(tester: &mut HandlerTester<CharsetHandler<Forward>>, payload: &[u8]) -> Vec<u8> {
+    match tester.request(Packet::new(0, payload)) {
+        Action::Forward => payload.to_vec(),
+        Action::Mutate(p) => p.payload().to_vec(),
+        action => panic!("{:?}", action),
+    }
+}
+
+fn column(seq: u8, character_set: u16, length: u32) -> Packet {
+    let mut payload = b"\x03def\x04shop\x01t\x01t\x01c\x01c\x0c".to_vec();
+    payload.extend_from_slice(&character_set.to_le_bytes());
+    payload.extend_from_slice(&length.to_le_bytes());
+    payload.extend_from_slice(&[0xfd, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    Packet::new(seq, &payload)
+}
+
+fn eof(seq: u8) -> Packet {
+    Packet::new(seq, &[0xfe, 0x00, 0x00, 0x02, 0x00])
+}
+
+#[test]
+fn collations_and_charsets_are_known_by_name() {
+    let collation: Collation = "UTF8MB4_GENERAL_CI".parse().unwrap();
+    assert_eq!((collation.id(), collation.name(), collation.charset()), (45, "utf8mb4_general_ci", Charset::Utf8mb4));
+    assert!("utf16_general_ci".parse::<Collation>().is_err());
+    assert_eq!(Collation::default_for(Charset::Latin1).name(), "latin1_swedish_ci");
+    assert_eq!(Charset::from_name("utf8"), Some(Charset::Utf8));
+
+    // Windows-1252 characters, and ones latin1 doesn't have
+    assert_eq!(&Charset::Latin1.convert(Charset::Utf8mb4, b"\x80\xe9")[..], "€é".as_bytes());
+    assert_eq!(&Charset::Utf8mb4.convert(Charset::Latin1, "é😀".as_bytes())[..], b"\xe9?");
+    assert_eq!(&Charset::Utf8mb4.convert(Charset::Utf8, "é😀".as_bytes())[..], "é?".as_bytes());
+}
+
+#[test]
+fn statements_are_converted_but_for_binary_literals() {
+    let mut tester = tester();
+    assert_eq!(sent(&mut tester, b"\x03SELECT 'caf\xe9'"), b"\x03SELECT 'caf\xc3\xa9'");
+
+    let sql = b"\x03INSERT INTO t VALUES (_binary'\xe9\xff', _BINARY \"\xe9\", X'E9', b'1', 0xE9, '\xe9')";
+    assert_eq!(sent(&mut tester, sql), &b"\x03INSERT INTO t VALUES (_binary'\xe9\xff', _BINARY \"\xe9\", X'E9', b'1', 0xE9, '\xc3\xa9')"[..]);
+
+    // escaped quotes don't end a binary literal early
+    assert_eq!(sent(&mut tester, b"\x03SELECT _binary'\\'\xe9', '\xe9'"), b"\x03SELECT _binary'\\'\xe9', '\xc3\xa9'");
+
+    // only binary literals: not strings, identifiers or comments that look like them
+    assert_eq!(sent(&mut tester, b"\x03SELECT '_binary', '\xe9' AS x, `_binary` /* _binary'\xe9' */"),
+               b"\x03SELECT '_binary', '\xc3\xa9' AS x, `_binary` /* _binary'\xc3\xa9' */");
+    assert_eq!(sent(&mut tester, b"\x03SELECT max'\xe9'"), b"\x03SELECT max'\xc3\xa9'");
+
+    // and prepared statements alike
+    assert_eq!(sent(&mut tester, b"\x16SELECT ? = _binary'\xe9' OR ? = '\xe9'"), b"\x16SELECT ? = _binary'\xe9' OR ? = '\xc3\xa9'");
+}
+
+#[test]
+fn set_names_changes_the_clients_character_set() {
+    let mut tester = tester();
+    assert_eq!(sent(&mut tester, b"\x03SET NAMES utf8mb4"), b"\x03SET NAMES utf8mb4 COLLATE utf8mb4_general_ci");
+    tester.response(ok_packet(1));
+    assert_eq!(sent(&mut tester, "\x03SELECT 'é'".as_bytes()), "\x03SELECT 'é'".as_bytes());
+
+    match tester.request(Packet::new(0, b"\x03SET NAMES klingon")) {
+        Action::Error { code, msg, .. } => {
+            assert_eq!(code, ER_UNKNOWN_CHARACTER_SET);
+            assert_eq!(msg, "Unknown character set: 'klingon'");
+        },
+        action => panic!("{:?}", action),
+    }
+}
+
+#[test]
+fn text_results_are_converted_for_the_client() {
+    let mut tester = tester();
+    sent(&mut tester, b"\x03SELECT c FROM t");
+    assert_eq!(tester.response(Packet::new(1, &[0x02])), Action::Forward);
+    // a text column takes the client's character set, with room for as many characters
+    assert_eq!(tester.response(column(2, 45, 40)), Action::Mutate(column(2, 8, 10)));
+    // a binary one is left alone
+    assert_eq!(tester.response(column(3, 63, 40)), Action::Mutate(column(3, 63, 40)));
+    assert_eq!(tester.response(eof(4)), Action::Forward);
+    assert_eq!(tester.response(Packet::new(5, b"\x02\xc3\xa9\x02\xc3\xa9")), Action::Mutate(Packet::new(5, b"\x01\xe9\x02\xc3\xa9")));
+    assert_eq!(tester.response(Packet::new(6, b"\xfb\x01a")), Action::Forward);
+    assert_eq!(tester.response(eof(7)), Action::Forward);
+    assert_eq!(tester.in_flight(), 0);
+
+    // and so are error messages
+    sent(&mut tester, b"\x03SELECT x");
+    let error = [&b"\xff\x48\x04#42S22Unknown column '"[..], "é".as_bytes(), b"'"].concat();
+    let converted = [&b"\xff\x48\x04#42S22Unknown column '"[..], b"\xe9", b"'"].concat();
+    assert_eq!(tester.response(Packet::new(1, &error)), Action::Mutate(Packet::new(1, &converted)));
+}
@@ -0,0 +1,70 @@
+extern crate futures;
+extern crate mysql_proxy;
+
+use futures::Async;
+use futures::future::{self, Future};
+
+use mysql_proxy::Packet;
+use mysql_proxy::coalesce::{is_coalescable, CoalesceConfig, Coalescer, FlightKey, Joined};
+
+fn key(query: &str) -> FlightKey {
+    FlightKey {
+        user: "app".to_string(),
+        group: "replicas".to_string(),
+        database: Some("shop".to_string()),
+        character_set: 33,
+        backend_capabilities: 0,
+        query: query.as_bytes().to_vec(),
+    }
+}
+
+#[test]
+fn followers_get_the_leaders_result() {
+    let coalescer = Coalescer::new(&CoalesceConfig { max_result_bytes: 64 });
+    let mut leader = match coalescer.join(key("SELECT * FROM products")) {
+        Joined::Leader(leader) => leader,
+        Joined::Follower(_) => panic!("nothing to follow yet"),
+    };
+    let follower = match coalescer.join(key("SELECT * FROM products")) {
+        Joined::Follower(follower) => follower,
+        Joined::Leader(_) => panic!("should follow the flight in progress"),
+    };
+    future::lazy(|| {
+        assert_eq!(follower.poll(), Async::NotReady);
+        Ok::<_, ()>(())
+    }).wait().unwrap();
+
+    leader.record(&Packet::new(1, b"\x01"));
+    leader.record(&Packet::new(2, b"\x00\x00\x00\x02\x00\x00\x00"));
+    leader.finish();
+    assert_eq!(follower.poll(), Async::Ready(Some(vec![Packet::new(1, b"\x01"), Packet::new(2, b"\x00\x00\x00\x02\x00\x00\x00")])));
+    assert_eq!(coalescer.in_flight(), 0);
+
+    // a result too large to share, or a leader that goes away, leaves followers on their own
+    let mut leader = match coalescer.join(key("SELECT * FROM orders")) {
+        Joined::Leader(leader) => leader,
+        Joined::Follower(_) => panic!("the flight has landed"),
+    };
+    let follower = match coalescer.join(key("SELECT * FROM orders")) {
+        Joined::Follower(follower) => follower,
+        Joined::Leader(_) => panic!("should follow the flight in progress"),
+    };
+    leader.record(&Packet::new(1, &[0_u8; 100]));
+    assert_eq!(follower.poll(), Async::Ready(None));
+    drop(leader);
+    match coalescer.join(key("SELECT * FROM orders")) {
+        Joined::Leader(leader) => drop(leader),
+        Joined::Follower(_) => panic!("the flight was abandoned"),
+    }
+    assert_eq!(coalescer.in_flight(), 0);
+}
+
+#[test]
+fn only_reads_with_the_same_result_are_coalesced() {
+    assert!(is_coalescable("SELECT id, name FROM products WHERE category = 'toys' LIMIT 10"));
+    for sql in &["SELECT NOW()", "SELECT * FROM t WHERE id = @id", "SELECT * FROM t FOR UPDATE",
+                 "SELECT * FROM t LOCK IN SHARE MODE", "SELECT 1; DELETE FROM t", "UPDATE t SET a = 1",
+                 "SELECT a INTO @a FROM t", "SELECT RAND()"] {
+        assert!(!is_coalescable(sql), "{}", sql);
+    }
+}
@@ -0,0 +1,393 @@
+extern crate bytes;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use bytes::BytesMut;
+use tokio_io::codec::{Decoder, Encoder};
+
+use mysql_proxy::{Packet, PacketType};
+use mysql_proxy::codec::*;
+use mysql_proxy::framed::MySqlPacketCodec;
+use mysql_proxy::protocol::*;
+
+fn column(name: &str) -> ColumnDefinition {
+    ColumnDefinition {
+        catalog: "def".to_string(),
+        schema: "test".to_string(),
+        table: "t".to_string(),
+        org_table: "t".to_string(),
+        name: name.to_string(),
+        org_name: name.to_string(),
+        character_set: 0x21,
+        column_length: 11,
+        column_type: 0x03,
+        flags: 0,
+        decimals: 0,
+    }
+}
+
+fn eof_packet(sequence_id: u8, status_flags: u16) -> Packet {
+    Packet::new(sequence_id, &[0xfe, 0x00, 0x00, status_flags as u8, (status_flags >> 8) as u8])
+}
+
+#[test]
+fn packet_length_is_little_endian() {
+    assert_eq!(parse_packet_length(&[0x01, 0x00, 0x00, 0x00]), 1);
+    assert_eq!(parse_packet_length(&[0x00, 0x01, 0x00, 0x07]), 256);
+    assert_eq!(parse_packet_length(&[0xff, 0xff, 0xff, 0x00]), MAX_PAYLOAD_LEN);
+}
+
+#[test]
+fn command_constructors() {
+    assert_eq!(Packet::com_query("SELECT 1"), Packet::new(0, b"\x03SELECT 1"));
+    assert_eq!(Packet::com_init_db("shop"), Packet::new(0, b"\x02shop"));
+    assert_eq!(Packet::com_ping(), Packet::new(0, &[0x0e]));
+    assert_eq!(Packet::com_quit(), Packet::new(0, &[0x01]));
+    assert_eq!(Packet::com_reset_connection(), Packet::new(0, &[0x1f]));
+    assert_eq!(Packet::command(PacketType::ComStmtClose, &[7, 0, 0, 0]), Packet::new(0, &[0x19, 7, 0, 0, 0]));
+}
+
+#[test]
+fn decoder_reassembles_split_packets() {
+    let a = Packet::new(0, b"\x03select 1");
+    let b = Packet::new(1, b"\x01");
+    let mut bytes = a.bytes.clone();
+    bytes.extend_from_slice(&b.bytes);
+
+    // feed the stream one byte at a time
+    let mut decoder = PacketDecoder::new();
+    let mut packets = vec![];
+    for byte in &bytes {
+        decoder.extend(&[*byte]);
+        while let Some(p) = decoder.next_packet() {
+            packets.push(p);
+        }
+    }
+    assert_eq!(packets, vec![a, b]);
+    assert_eq!(decoder.buffered(), 0);
+}
+
+#[test]
+fn decoder_waits_for_complete_packet() {
+    let mut decoder = PacketDecoder::new();
+    decoder.extend(&[0x05, 0x00, 0x00]);
+    assert_eq!(decoder.next_packet(), None);
+    decoder.extend(&[0x00, 0x03, b'a']);
+    assert_eq!(decoder.next_packet(), None);
+    assert_eq!(decoder.buffered(), 6);
+    decoder.extend(b"bcd");
+    assert_eq!(decoder.next_packet(), Some(Packet::new(0, b"\x03abcd")));
+}
+
+#[test]
+fn decoder_handles_empty_payload() {
+    let mut decoder = PacketDecoder::new();
+    decoder.extend(&[0x00, 0x00, 0x00, 0x04]);
+    assert_eq!(decoder.next_packet(), Some(Packet::new(4, &[])));
+}
+
+#[test]
+fn packet_codec_round_trip() {
+    let mut codec = MySqlPacketCodec::new();
+    let mut buf = BytesMut::new();
+    codec.encode(Packet::new(0, b"\x03select 1"), &mut buf).unwrap();
+    codec.encode(Packet::new(1, b"\x01"), &mut buf).unwrap();
+
+    let mut partial = buf.split_to(6);
+    assert_eq!(codec.decode(&mut partial).unwrap(), None);
+    partial.extend_from_slice(&buf);
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(Packet::new(0, b"\x03select 1")));
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(Packet::new(1, b"\x01")));
+    assert_eq!(codec.decode(&mut partial).unwrap(), None);
+    assert!(partial.is_empty());
+}
+
+#[test]
+fn lenenc_int_round_trip() {
+    for n in &[0_u64, 1, 250, 251, 0xffff, 0x10000, 0xff_ffff, 0x100_0000, u64::MAX] {
+        let mut buf = vec![];
+        write_lenenc_int(&mut buf, *n);
+        let mut r = PayloadReader::new(&buf);
+        assert_eq!(r.lenenc_int().unwrap(), *n);
+        assert!(r.is_empty(), "trailing bytes encoding {}", n);
+    }
+}
+
+#[test]
+fn lenenc_int_sizes() {
+    let size = |n| {
+        let mut buf = vec![];
+        write_lenenc_int(&mut buf, n);
+        buf.len()
+    };
+    assert_eq!(size(250), 1);
+    assert_eq!(size(251), 3);
+    assert_eq!(size(0x10000), 4);
+    assert_eq!(size(0x100_0000), 9);
+}
+
+#[test]
+fn payload_reader_rejects_short_input() {
+    let mut r = PayloadReader::new(&[0x01, 0x02]);
+    assert!(r.u32().is_err());
+    let mut r = PayloadReader::new(&[0xfc, 0x01]);
+    assert!(r.lenenc_int().is_err());
+    let mut r = PayloadReader::new(b"abc");
+    assert!(r.null_str().is_err());
+    assert_eq!(r.null_str_or_eof().unwrap(), "abc");
+}
+
+#[test]
+fn handshake_round_trip() {
+    let greeting = HandshakeV10 {
+        server_version: "5.7.0-test".to_string(),
+        connection_id: 42,
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    let p = greeting.to_packet(0);
+    assert_eq!(p.sequence_id(), 0);
+    assert_eq!(HandshakeV10::parse(&p).unwrap(), greeting);
+}
+
+#[test]
+fn handshake_rejects_other_protocol_versions() {
+    assert!(HandshakeV10::parse(&Packet::new(0, b"\x09old\x00")).is_err());
+}
+
+#[test]
+fn handshake_response_round_trip() {
+    let response = HandshakeResponse {
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH
+            | CLIENT_CONNECT_WITH_DB | CLIENT_CONNECT_ATTRS,
+        max_packet_size: 1 << 24,
+        character_set: 0x21,
+        username: "app".to_string(),
+        auth_response: native_password_auth("secret", b"abcdefghijklmnopqrst"),
+        database: Some("test".to_string()),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+        connect_attrs: Some(vec![0x03, b'f', b'o', b'o', 0x03, b'b', b'a', b'r']),
+    };
+    assert_eq!(HandshakeResponse::parse(&response.to_packet(1)).unwrap(), response);
+}
+
+#[test]
+fn handshake_response_with_lenenc_auth_data() {
+    let response = HandshakeResponse {
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_PLUGIN_AUTH | CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA,
+        max_packet_size: 1 << 24,
+        character_set: 0x21,
+        username: "app".to_string(),
+        auth_response: vec![7; 300],
+        database: None,
+        auth_plugin_name: Some(CLEAR_PASSWORD_PLUGIN.to_string()),
+        connect_attrs: None,
+    };
+    assert_eq!(HandshakeResponse::parse(&response.to_packet(1)).unwrap(), response);
+}
+
+#[test]
+fn auth_switch_round_trip() {
+    let switch = AuthSwitchRequest {
+        plugin_name: NATIVE_PASSWORD_PLUGIN.to_string(),
+        plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+    };
+    assert_eq!(AuthSwitchRequest::parse(&switch.to_packet(2)).unwrap(), switch);
+    assert!(AuthSwitchRequest::parse(&ok_packet(2)).is_err());
+}
+
+#[test]
+fn ssl_request_detection() {
+    let mut payload = vec![0_u8; 32];
+    payload[1] = (CLIENT_SSL >> 8) as u8;
+    assert!(is_ssl_request(&Packet::new(1, &payload)));
+    assert!(!is_ssl_request(&Packet::new(1, &[0_u8; 32])));
+    assert!(!is_ssl_request(&Packet::new(1, &payload[..31])));
+}
+
+#[test]
+fn ok_packet_parse() {
+    let p = Packet::new(1, &[0x00, 0xfc, 0x00, 0x01, 0x05, 0x02, 0x00, 0x01, 0x00, b'h', b'i']);
+    let ok = OkPacket::parse(&p, CLIENT_PROTOCOL_41).unwrap();
+    assert_eq!(ok, OkPacket {
+        affected_rows: 256,
+        last_insert_id: 5,
+        status_flags: 0x0002,
+        warnings: 1,
+        info: "hi".to_string(),
+        state_changes: vec![],
+    });
+    assert!(is_ok_packet(&p));
+    assert_eq!(OkPacket::parse(&ok_packet(2), CLIENT_PROTOCOL_41).unwrap().status_flags, 0x0002);
+}
+
+#[test]
+fn ok_packet_session_state_changes() {
+    let mut changes = vec![];
+    changes.extend_from_slice(&[0x00, 0x0f, 0x0a]);
+    changes.extend_from_slice(b"autocommit\x03OFF");
+    changes.extend_from_slice(&[0x01, 0x05, 0x04]);
+    changes.extend_from_slice(b"test");
+    changes.extend_from_slice(&[0x03, 0x08, 0x00, 0x06]);
+    changes.extend_from_slice(b"uuid:1");
+    changes.extend_from_slice(&[0x05, 0x09, 0x08]);
+    changes.extend_from_slice(b"T_______");
+    changes.extend_from_slice(&[0x2a, 0x01, 0x00]);
+    let mut payload = vec![0x00, 0x00, 0x00, 0x02, 0x40, 0x00, 0x00, 0x00];
+    write_lenenc_str(&mut payload, &changes);
+
+    let ok = OkPacket::parse(&Packet::new(1, &payload), CLIENT_PROTOCOL_41 | CLIENT_SESSION_TRACK).unwrap();
+    assert_eq!(ok.status_flags, SERVER_STATUS_AUTOCOMMIT | SERVER_SESSION_STATE_CHANGED);
+    assert_eq!(ok.info, "");
+    assert_eq!(ok.state_changes, vec![
+        StateChange::SystemVariable { name: "autocommit".to_string(), value: "OFF".to_string() },
+        StateChange::Schema("test".to_string()),
+        StateChange::Gtids("uuid:1".to_string()),
+        StateChange::TransactionState("T_______".to_string()),
+        StateChange::Unknown { kind: 0x2a, data: vec![0x00] },
+    ]);
+
+    // without CLIENT_SESSION_TRACK the rest of the packet is the info string
+    assert!(OkPacket::parse(&Packet::new(1, &payload), CLIENT_PROTOCOL_41).unwrap().state_changes.is_empty());
+}
+
+#[test]
+fn err_packet_parse() {
+    let p = Packet::error_packet(1045, *b"28000", "Access denied".to_string());
+    assert!(is_err_packet(&p));
+    let err = ErrPacket::parse(&p).unwrap();
+    assert_eq!(err.code, 1045);
+    assert_eq!(err.state, Some(*b"28000"));
+    assert_eq!(err.message, "Access denied");
+
+    let pre41 = Packet::new(1, b"\xff\x15\x04Access denied");
+    assert_eq!(ErrPacket::parse(&pre41).unwrap().state, None);
+    assert!(ErrPacket::parse(&ok_packet(1)).is_err());
+}
+
+#[test]
+fn eof_packet_parse() {
+    let p = eof_packet(5, 0x0022);
+    assert!(is_eof_packet(&p));
+    assert_eq!(EofPacket::parse(&p).unwrap(), EofPacket { warnings: 0, status_flags: 0x0022 });
+    // a row whose first value has an 8 byte length is not an EOF packet
+    assert!(!is_eof_packet(&Packet::new(5, &[0xfe, 0, 0, 0, 0, 0, 0, 0, 0])));
+}
+
+#[test]
+fn column_definition_round_trip() {
+    let c = column("id");
+    assert_eq!(ColumnDefinition::parse(&c.to_packet(2)).unwrap(), c);
+}
+
+#[test]
+fn text_row_round_trip() {
+    let row = vec![Some(b"1".to_vec()), None, Some(vec![]), Some(vec![b'x'; 300])];
+    let p = text_row_packet(3, &row);
+    assert_eq!(parse_text_row(&p, row.len()).unwrap(), row);
+    assert!(parse_text_row(&p, row.len() + 1).is_err());
+}
+
+#[test]
+fn query_response_ok() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    match decoder.decode(&ok_packet(1)).unwrap() {
+        QueryResponse::Ok(ok) => assert_eq!(ok.affected_rows, 0),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(decoder.is_done());
+    assert!(decoder.decode(&ok_packet(2)).is_err());
+}
+
+#[test]
+fn query_response_err() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    let err = Packet::error_packet(1064, *b"42000", "syntax".to_string());
+    assert!(matches!(decoder.decode(&err).unwrap(), QueryResponse::Err(_)));
+    assert!(decoder.is_done());
+}
+
+#[test]
+fn query_response_result_set() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    let packets = [
+        Packet::new(1, &[0x02]),
+        column("a").to_packet(2),
+        column("b").to_packet(3),
+        eof_packet(4, 0x0002),
+        text_row_packet(5, &[Some(b"1".to_vec()), None]),
+        text_row_packet(6, &[Some(b"2".to_vec()), Some(b"x".to_vec())]),
+        eof_packet(7, 0x0002),
+    ];
+    let decoded: Vec<QueryResponse> = packets.iter().map(|p| decoder.decode(p).unwrap()).collect();
+    assert_eq!(decoded, vec![
+        QueryResponse::ColumnCount(2),
+        QueryResponse::Column(column("a")),
+        QueryResponse::Column(column("b")),
+        QueryResponse::ColumnsEnd,
+        QueryResponse::Row(vec![Some(b"1".to_vec()), None]),
+        QueryResponse::Row(vec![Some(b"2".to_vec()), Some(b"x".to_vec())]),
+        QueryResponse::End { status_flags: 0x0002, more_results: false },
+    ]);
+    assert!(decoder.is_done());
+}
+
+#[test]
+fn query_response_result_set_without_eof() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+    assert_eq!(decoder.decode(&Packet::new(1, &[0x01])).unwrap(), QueryResponse::ColumnCount(1));
+    assert_eq!(decoder.decode(&column("a").to_packet(2)).unwrap(), QueryResponse::Column(column("a")));
+    // no EOF after the columns, rows start straight away
+    assert_eq!(decoder.decode(&text_row_packet(3, &[None])).unwrap(), QueryResponse::Row(vec![None]));
+    let end = Packet::new(4, &[0xfe, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]);
+    assert_eq!(decoder.decode(&end).unwrap(), QueryResponse::End { status_flags: 0x0002, more_results: false });
+    assert!(decoder.is_done());
+}
+
+#[test]
+fn query_response_multiple_results() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    decoder.decode(&Packet::new(1, &[0x01])).unwrap();
+    decoder.decode(&column("a").to_packet(2)).unwrap();
+    decoder.decode(&eof_packet(3, 0x0002)).unwrap();
+    let end = decoder.decode(&eof_packet(4, 0x000a)).unwrap();
+    assert_eq!(end, QueryResponse::End { status_flags: 0x000a, more_results: true });
+    assert!(!decoder.is_done());
+
+    // the second result is a plain OK
+    let ok = Packet::new(5, &[0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]);
+    assert!(matches!(decoder.decode(&ok).unwrap(), QueryResponse::Ok(_)));
+    assert!(decoder.is_done());
+}
+
+#[test]
+fn query_response_local_infile() {
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    let request = decoder.decode(&Packet::new(1, b"\xfb/tmp/data.csv")).unwrap();
+    assert_eq!(request, QueryResponse::LocalInfile("/tmp/data.csv".to_string()));
+    assert!(!decoder.is_done());
+    decoder.decode(&ok_packet(3)).unwrap();
+    assert!(decoder.is_done());
+}
+
+#[test]
+fn long_payloads_are_split_into_packets() {
+    let long = vec![7_u8; MAX_PAYLOAD_LEN + 10];
+    let exact = vec![8_u8; MAX_PAYLOAD_LEN * 2];
+    let response = vec![Packet::new(1, b"\x01"), Packet::new(2, &long), Packet::new(3, &exact), eof_packet(4, 0x0002)];
+    let chunked = chunk_response(response);
+    let lengths: Vec<usize> = chunked.iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lengths, vec![1, MAX_PAYLOAD_LEN, 10, MAX_PAYLOAD_LEN, MAX_PAYLOAD_LEN, 0, 5]);
+    let sequence_ids: Vec<u8> = chunked.iter().map(|p| p.sequence_id()).collect();
+    assert_eq!(sequence_ids, vec![1, 2, 3, 4, 5, 6, 7]);
+    for p in &chunked {
+        assert_eq!(parse_packet_length(&p.bytes), p.payload().len());
+    }
+
+    // responses that fit are left alone
+    let short = vec![Packet::new(1, b"\x01"), eof_packet(5, 0x0002)];
+    assert_eq!(chunk_response(short), vec![Packet::new(1, b"\x01"), eof_packet(5, 0x0002)]);
+}
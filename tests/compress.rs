@@ -0,0 +1,42 @@
+extern crate bytes;
+extern crate mysql_proxy;
+
+use bytes::BytesMut;
+
+use mysql_proxy::Packet;
+use mysql_proxy::compress::{CompressedSequence, CompressionConfig, Compressor, Decompressor};
+
+#[test]
+fn compressed_packets_round_trip() {
+    let config = CompressionConfig { client: true, backend: false, level: 6, min_size: 50 };
+    let (proxy, client) = (CompressedSequence::new(), CompressedSequence::new());
+    let compressor = Compressor::new(&config, proxy.clone());
+    let mut decompressor = Decompressor::new(client);
+
+    // a command starts the sequence over, and short payloads aren't compressed
+    let query = Packet::new(0, b"\x03SELECT 1");
+    let mut wire = BytesMut::new();
+    compressor.compress(&query.bytes, &mut wire);
+    assert_eq!(&wire[..7], &[13, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&wire[7..], &query.bytes[..]);
+
+    let row = Packet::new(1, &[b'x'; 1000]);
+    let mut packets = row.bytes.clone();
+    packets.extend_from_slice(&Packet::new(2, &[0xfe, 0, 0, 2, 0]).bytes);
+    let mut compressed = BytesMut::new();
+    compressor.compress(&packets, &mut compressed);
+    assert_eq!(compressed[3], 1);
+    assert!(compressed.len() < 100);
+
+    // whatever way the bytes arrive
+    let mut plain = BytesMut::new();
+    let (first, second) = compressed.split_at(10);
+    decompressor.decompress(first, &mut plain).unwrap();
+    assert!(plain.is_empty());
+    decompressor.decompress(second, &mut plain).unwrap();
+    assert_eq!(&plain[..], &packets[..]);
+
+    let mut corrupt = compressed.to_vec();
+    corrupt[9] ^= 0xff;
+    assert!(Decompressor::new(CompressedSequence::new()).decompress(&corrupt, &mut BytesMut::new()).is_err());
+}
@@ -0,0 +1,109 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::config::{ProxyConfig, DEFAULT_CONFIG};
+
+#[test]
+fn default_config_is_valid() {
+    let config = ProxyConfig::parse(DEFAULT_CONFIG).unwrap();
+    assert_eq!(config.listeners.len(), 1);
+    assert_eq!(config.validate(), Vec::<String>::new());
+}
+
+#[test]
+fn validate_reports_every_problem() {
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+        [[users]]
+        user = "app"
+        default_group = "replicas"
+        [[row_filters]]
+        table = "orders"
+        column = "tenant_id"
+        value = "{tenant_id"
+        [listeners.rw]
+        listen = ["127.0.0.1:3307"]
+        [listeners.ro]
+        listen = ["127.0.0.1:3307"]
+    "#).unwrap();
+    assert_eq!(config.validate(), vec![
+        "User 'app': unknown routing group 'replicas'".to_string(),
+        "User 'app': no password, and no [auth] authenticator to check clients with".to_string(),
+        "Row filter: unclosed or empty placeholder in value '{tenant_id'".to_string(),
+        "127.0.0.1:3307 is used by both listener 'ro' and listener 'rw'".to_string(),
+    ]);
+}
+
+#[test]
+fn discovery_needs_one_source() {
+    let config = ProxyConfig::parse(r#"
+        [groups.registry.discovery]
+        consul = { service = "mysql", tag = "primary" }
+        [groups.both.discovery]
+        host = "mysql.db.local"
+        etcd = { prefix = "/services/mysql/" }
+    "#).unwrap();
+    let consul = config.groups["registry"].discovery.as_ref().unwrap().consul.as_ref().unwrap();
+    assert_eq!(consul.addr, "http://127.0.0.1:8500");
+    assert_eq!(config.groups["registry"].discovery.as_ref().unwrap().validate(), Ok(()));
+    assert_eq!(config.groups["both"].discovery.as_ref().unwrap().validate(),
+               Err("discovery needs one of host, srv, consul, etcd or group_replication".to_string()));
+}
+
+#[test]
+fn certificate_identities_map_to_proxy_users() {
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+        [groups.replicas]
+        backends = ["127.0.0.1:3307"]
+        [[users]]
+        user = "reports"
+        password = "secret"
+        default_group = "primary"
+        access = { identities = ["batch@example.com"] }
+        [tls]
+        cert = "server-cert.pem"
+        key = "server-key.pem"
+        client_ca = "client-ca.pem"
+        cert_auth = true
+        [[tls.users]]
+        identity = "batch@example.com"
+        user = "reports"
+        group = "replicas"
+    "#).unwrap();
+    assert_eq!(config.validate(), Vec::<String>::new());
+    let tls = config.tls.as_ref().unwrap();
+    let batch = tls.cert_user("batch@example.com");
+    assert_eq!((&batch.user[..], batch.group.as_ref().map(|g| &g[..])), ("reports", Some("replicas")));
+    // identities that aren't listed stand for the user of the same name
+    let reports = tls.cert_user("reports");
+    assert_eq!((&reports.user[..], reports.group), ("reports", None));
+
+    let config = ProxyConfig::parse(r#"
+        access = { identities = ["admin"] }
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+        [[users]]
+        user = "app"
+        password = "secret"
+        default_group = "primary"
+        access = { identities = ["app"] }
+        [tls]
+        cert = "server-cert.pem"
+        key = "server-key.pem"
+        [[tls.users]]
+        identity = "batch"
+        user = "batch"
+        [[tls.users]]
+        identity = "batch"
+        user = "app"
+        group = "replicas"
+    "#).unwrap();
+    assert_eq!(config.validate(), vec![
+        "Access: the listener's access list can't require certificate identities".to_string(),
+        "TLS: identity 'batch' maps to unknown user 'batch'".to_string(),
+        "TLS: identity 'batch' is mapped more than once".to_string(),
+        "TLS: identity 'batch': unknown routing group 'replicas'".to_string(),
+    ]);
+}
@@ -0,0 +1,63 @@
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+
+use tokio_core::reactor::Core;
+
+use mysql_proxy::connect::{self, connect_backend, interleave, BackendAddr};
+
+fn addrs(s: &[&str]) -> Vec<SocketAddr> {
+    s.iter().map(|a| a.parse().unwrap()).collect()
+}
+
+/// An address nothing listens on
+fn refused() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[test]
+fn backend_addresses_are_host_names_or_ips_and_a_port() {
+    let named: BackendAddr = "db1.internal:3306".parse().unwrap();
+    assert_eq!((named.host(), named.port()), ("db1.internal", 3306));
+    let v6: BackendAddr = "[fd00::1]:3307".parse().unwrap();
+    assert_eq!((v6.host(), v6.port()), ("fd00::1", 3307));
+    assert_eq!(v6.to_string(), "[fd00::1]:3307");
+    assert_eq!("10.0.0.1:3306".parse::<BackendAddr>().unwrap().to_string(), "10.0.0.1:3306");
+
+    for invalid in &["db1.internal", "db1.internal:port", ":3306", "fd00::1:3306"] {
+        assert_eq!(invalid.parse::<BackendAddr>().unwrap_err().kind(), ErrorKind::InvalidInput, "{}", invalid);
+    }
+}
+
+#[test]
+fn addresses_alternate_between_families() {
+    let resolved = addrs(&["[fd00::1]:3306", "[fd00::2]:3306", "[fd00::3]:3306", "10.0.0.1:3306", "10.0.0.2:3306"]);
+    assert_eq!(interleave(resolved), addrs(&["[fd00::1]:3306", "10.0.0.1:3306", "[fd00::2]:3306", "10.0.0.2:3306", "[fd00::3]:3306"]));
+    // starting with the family the resolver put first
+    let resolved = addrs(&["10.0.0.1:3306", "[fd00::1]:3306", "10.0.0.2:3306"]);
+    assert_eq!(interleave(resolved), addrs(&["10.0.0.1:3306", "[fd00::1]:3306", "10.0.0.2:3306"]));
+    assert!(interleave(vec![]).is_empty());
+}
+
+#[test]
+fn connections_go_to_the_first_address_that_accepts() {
+    let mut core = Core::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stream = core.run(connect::connect(vec![refused(), refused(), addr], &core.handle())).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+
+    let stream = core.run(connect_backend(&BackendAddr::from(addr), &core.handle())).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+}
+
+#[test]
+fn connecting_fails_with_the_last_error_once_every_address_has() {
+    let mut core = Core::new().unwrap();
+    let e = core.run(connect::connect(vec![refused(), refused()], &core.handle())).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    let e = core.run(connect::connect(vec![], &core.handle())).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
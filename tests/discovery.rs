@@ -0,0 +1,27 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::discovery::{parse_srv_response, srv_query, SrvRecord};
+
+#[test]
+fn parse_srv_answers() {
+    let query = srv_query(0x1234, "_mysql._tcp.db.local.").unwrap();
+    let mut response = query.clone();
+    response[2..4].copy_from_slice(&[0x81, 0x80]);
+    response[7] = 2;
+    let answer = |priority: u8, weight: u8, target: &[u8]| {
+        // the owner name and the target's domain point back into the question
+        let mut rr = vec![0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 30, 0, 6 + target.len() as u8 + 2];
+        rr.extend_from_slice(&[0, priority, 0, weight, 0x0c, 0xea]);
+        rr.extend_from_slice(target);
+        rr.extend_from_slice(&[0xc0, 24]);
+        rr
+    };
+    response.extend(answer(20, 0, b"\x03db2"));
+    response.extend(answer(10, 5, b"\x03db1"));
+
+    let record = |priority, weight, target: &str| SrvRecord { priority, weight, port: 3306, target: target.to_string() };
+    assert_eq!(parse_srv_response(0x1234, &response).unwrap(),
+               vec![record(10, 5, "db1.db.local"), record(20, 0, "db2.db.local")]);
+    assert!(parse_srv_response(0x4321, &response).is_err());
+    assert!(parse_srv_response(0x1234, &response[..response.len() - 1]).is_err());
+}
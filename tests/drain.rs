@@ -0,0 +1,176 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Action, Packet, PacketHandler, Pipe};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+
+/// Forwards everything, counting the responses it sees
+struct Counting(Rc<Cell<usize>>);
+
+impl PacketHandler for Counting {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        self.0.set(self.0.get() + 1);
+        Action::Forward
+    }
+}
+
+/// One end of an in-memory connection, which reads as closed once `closed` is set and
+/// records being shut down
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    closed: Rc<Cell<bool>>,
+    shut_down: Rc<Cell<bool>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return match self.closed.get() {
+                true => Ok(0),
+                false => Err(io::ErrorKind::WouldBlock.into()),
+            };
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed.get() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.shut_down.set(true);
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+struct Session {
+    client: Memory,
+    server: Memory,
+    responses: Rc<Cell<usize>>,
+    pipe: Spawn<Pipe<Counting>>,
+    core: Core,
+}
+
+impl Session {
+
+    /// A session whose client has sent a query and then closed its connection
+    fn gone(drain: Option<Duration>) -> Self {
+        let core = Core::new().unwrap();
+        let (client, server, responses) = (Memory::default(), Memory::default(), Rc::new(Cell::new(0)));
+        let pipe = Pipe::from_streams(client.clone(), server.clone(), Counting(responses.clone()))
+            .with_backend_capabilities(CLIENT_PROTOCOL_41);
+        let pipe = match drain {
+            Some(timeout) => pipe.with_drain_on_close(timeout, &core.handle()),
+            None => pipe,
+        };
+        let mut session = Session { client, server, responses, pipe: executor::spawn(pipe), core };
+        session.client.send(&Packet::new(0, b"\x03UPDATE t SET a = 1"));
+        assert_eq!(session.poll().unwrap(), Async::NotReady);
+        session.client.closed.set(true);
+        session
+    }
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.pipe.poll_future_notify(&Arc::new(Ignore), 0)
+    }
+}
+
+#[test]
+fn responses_in_flight_are_read_after_the_client_closes() {
+    let mut session = Session::gone(Some(Duration::from_secs(5)));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert!(!session.server.shut_down.get());
+
+    session.server.send(&ok_packet(1));
+    assert!(session.poll().is_err());
+    assert_eq!(session.responses.get(), 1);
+    assert!(session.server.shut_down.get());
+    assert!(session.client.outgoing.borrow().is_empty());
+}
+
+#[test]
+fn draining_gives_up_after_its_timeout() {
+    let mut session = Session::gone(Some(Duration::from_millis(100)));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while session.poll().unwrap_or(Async::Ready(())) == Async::NotReady && Instant::now() < deadline {
+        session.core.turn(Some(Duration::from_millis(10)));
+    }
+    assert!(session.server.shut_down.get());
+    assert_eq!(session.responses.get(), 0);
+}
+
+#[test]
+fn without_draining_the_server_is_closed_at_once() {
+    let mut session = Session::gone(None);
+    assert!(session.poll().is_err());
+    assert!(session.server.shut_down.get());
+}
+
+#[test]
+fn linger_and_drain_are_configured() {
+    let config = ProxyConfig::parse(r#"
+        drain_on_close_ms = 2000
+
+        [backend_socket]
+        linger_secs = 5
+    "#).unwrap();
+    assert_eq!(config.drain_on_close_ms, Some(2000));
+    assert_eq!(config.backend_socket.linger_secs, Some(5));
+    assert_eq!(config.client_socket.linger_secs, None);
+}
@@ -0,0 +1,104 @@
+extern crate mysql_proxy;
+extern crate serde_json;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::{eof_packet, ok_packet};
+use mysql_proxy::dump::{Direction, DumpHandler, PacketDump, PacketDumper, HEX_EXCERPT_LEN};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+/// A writer whose output the test can read
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn packets_are_described_by_type() {
+    let (sender, dumps) = channel();
+    let dumper = PacketDumper::to_channel(sender);
+    dumper.enable();
+    let mut tester = HandlerTester::new(DumpHandler::new(dumper, "10.0.0.1:50000", Forward));
+    tester.request(Packet::com_query("SELECT c FROM t"));
+    tester.response(Packet::new(1, &[0x01]));
+    tester.response(eof_packet(2, 0x0002));
+    tester.response(Packet::new(3, b"\x01a"));
+    tester.response(eof_packet(4, 0x0002));
+    tester.request(Packet::new(0, &[0x0e]));
+    tester.response(ok_packet(1));
+    tester.request(Packet::new(0, &[0x99]));
+    tester.response(Packet::error_packet(1047, *b"08S01", "Unknown command".to_string()).with_sequence_id(1));
+
+    let described: Vec<(Direction, u8, String, Option<String>)> = dumps.try_iter()
+        .map(|d| (d.direction, d.seq, d.packet_type, d.summary))
+        .collect();
+    let request = |seq, t: &str, summary: Option<&str>| (Direction::Request, seq, t.to_string(), summary.map(|s| s.to_string()));
+    let response = |seq, t: &str, summary: Option<&str>| (Direction::Response, seq, t.to_string(), summary.map(|s| s.to_string()));
+    assert_eq!(described, vec![
+        request(0, "com_query", Some("SELECT c FROM t")),
+        response(1, "row", None),
+        response(2, "eof", None),
+        response(3, "row", None),
+        response(4, "eof", None),
+        request(0, "com_ping", None),
+        response(1, "ok", Some("affected_rows=0 last_insert_id=0")),
+        request(0, "unknown(0x99)", None),
+        response(1, "err", Some("1047 (08S01): Unknown command")),
+    ]);
+}
+
+#[test]
+fn dumps_hold_the_start_of_the_payload() {
+    let (sender, dumps) = channel();
+    let dumper = PacketDumper::to_channel(sender);
+    dumper.enable();
+    let mut tester = HandlerTester::new(DumpHandler::new(dumper, "client", Forward));
+
+    let short = Packet::com_query("SELECT 1");
+    tester.request(Packet::com_query("SELECT 1"));
+    let dump = dumps.try_recv().unwrap();
+    assert_eq!((dump.connection.as_str(), dump.length, dump.truncated), ("client", 9, false));
+    assert_eq!(dump.hex, "0353454c4543542031");
+    assert_eq!(dump.packet(), Some(short));
+
+    let long = format!("SELECT '{}'", "x".repeat(300));
+    tester.request(Packet::com_query(&long));
+    let dump = dumps.try_recv().unwrap();
+    assert_eq!((dump.length, dump.hex.len(), dump.truncated), (long.len() + 1, HEX_EXCERPT_LEN * 2, true));
+    assert_eq!(dump.packet(), None);
+    // the summary is cut short too
+    assert_eq!(dump.summary.unwrap(), format!("{}...", &long[..256]));
+}
+
+#[test]
+fn dumping_is_switched_on_and_off_at_runtime() {
+    let output = Shared::default();
+    let dumper = PacketDumper::to_writer(Box::new(output.clone()));
+    let mut tester = HandlerTester::new(DumpHandler::new(dumper.clone(), "client", Forward));
+    assert!(!dumper.is_enabled());
+    tester.request(Packet::com_query("SELECT 1"));
+    assert!(output.0.lock().unwrap().is_empty());
+
+    dumper.enable();
+    tester.request(Packet::com_query("SELECT 2"));
+    dumper.disable();
+    tester.request(Packet::com_query("SELECT 3"));
+    let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let dump: PacketDump = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(dump.summary, Some("SELECT 2".to_string()));
+    assert!(lines[0].contains(r#""type":"com_query""#), "{}", lines[0]);
+}
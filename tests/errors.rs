@@ -0,0 +1,38 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::Packet;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::errors::ErrorRules;
+
+#[test]
+fn rewrite_backend_errors() {
+    let config = ProxyConfig::parse(r#"
+        [[error_rules]]
+        code = 1045
+        sql_state = "HY000"
+        message = "Login failed for {user} ({code})"
+        [[error_rules]]
+        contains = "db1.internal"
+        redact = ["db1.internal"]
+        [[error_rules]]
+        code = 1146
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["Error rule: the rule changes nothing, expected new_code, sql_state, message or redact".to_string()]);
+    let rules = ErrorRules::new(config.error_rules);
+
+    let denied = Packet::error_packet(1045, *b"28000", "Access denied for user 'svc'@'10.0.0.5'".to_string()).with_sequence_id(2);
+    let rewritten = rules.rewrite(&denied, "alice").unwrap();
+    assert_eq!(rewritten.sequence_id(), 2);
+    assert_eq!(rewritten.payload(), &b"\xff\x15\x04#HY000Login failed for alice (1045)"[..]);
+
+    let lost = Packet::error_packet(2013, *b"HY000", "Lost connection to 'db1.internal:3306'".to_string());
+    assert_eq!(rules.rewrite(&lost, "alice").unwrap().payload(), &b"\xff\xdd\x07#HY000Lost connection to '***:3306'"[..]);
+
+    // other errors, and packets that aren't errors, stay as they are
+    let missing = Packet::error_packet(1064, *b"42000", "You have an error in your SQL syntax".to_string());
+    assert!(rules.rewrite(&missing, "alice").is_none());
+    assert!(rules.rewrite(&Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0]), "alice").is_none());
+
+    let login = rules.rewrite_fields(1045, *b"28000", "Backend login failed".to_string(), "bob");
+    assert_eq!(login, (1045, *b"HY000", "Login failed for bob (1045)".to_string()));
+}
@@ -0,0 +1,84 @@
+extern crate mysql_proxy;
+extern crate serde_json;
+
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::eventlog::EventLog;
+use mysql_proxy::events::{Event, EventBus};
+
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn publish_to_subscribers() {
+    let bus = EventBus::new();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let callback_seen = seen.clone();
+    let id = bus.subscribe(move |event| callback_seen.lock().unwrap().push(event.clone()));
+    let receiver = bus.subscribe_channel();
+
+    let up = Event::BackendMarkedUp { backend: "10.0.0.1:3306".to_string() };
+    bus.publish(up.clone());
+    assert_eq!(*seen.lock().unwrap(), vec![up.clone()]);
+    assert_eq!(receiver.try_recv().ok(), Some(up.clone()));
+
+    // ended and dropped subscriptions get nothing more
+    assert!(bus.unsubscribe(id));
+    assert!(!bus.unsubscribe(id));
+    drop(receiver);
+    bus.publish(up.clone());
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    assert!(!bus.has_subscribers());
+    bus.publish_with(|| panic!("created an event without subscribers"));
+}
+
+#[test]
+fn log_failed_logins_for_fail2ban() {
+    let siem = UdpSocket::bind("127.0.0.1:0").unwrap();
+    siem.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let config = ProxyConfig::parse(&format!("[event_log]\nudp = \"{}\"\n", siem.local_addr().unwrap())).unwrap();
+    let config = config.event_log.unwrap();
+    assert_eq!(config.validate(), Ok(()));
+
+    let bus = EventBus::new();
+    let written = Shared::default();
+    EventLog::new(&config, Box::new(written.clone())).subscribe(&bus);
+    EventLog::open(&config).unwrap().subscribe(&bus);
+    bus.publish(Event::BackendMarkedUp { backend: "10.0.0.1:3306".to_string() });
+    bus.publish(Event::AuthFailed {
+        client: "[2001:db8::7]:51234".parse().unwrap(),
+        user: Some("root".to_string()),
+        reason: "wrong password".to_string(),
+    });
+
+    // only the kinds of events asked for, with the bare address
+    let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(written.lines().count(), 1);
+    assert!(written.contains(r#""event":"auth_failed","ip":"2001:db8::7","reason":"wrong password""#));
+    let mut record: serde_json::Value = serde_json::from_str(written.trim()).unwrap();
+    assert_eq!(record["user"], "root");
+    assert!(record["timestamp_ms"].as_u64().unwrap() > 0);
+
+    // the same record, timestamped by the other log
+    let mut datagram = [0; 512];
+    let n = siem.recv(&mut datagram).unwrap();
+    let mut sent: serde_json::Value = serde_json::from_slice(&datagram[..n]).unwrap();
+    assert!(sent.as_object_mut().unwrap().remove("timestamp_ms").is_some());
+    record.as_object_mut().unwrap().remove("timestamp_ms");
+    assert_eq!(sent, record);
+}
@@ -0,0 +1,75 @@
+extern crate mysql_proxy;
+
+use std::net::TcpListener;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::explain::{ExplainConfig, ExplainHandler, Plan, PlanStep, VetAction};
+use mysql_proxy::sql::Statement;
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn config(tables: &[&str], max_rows: Option<u64>) -> ExplainConfig {
+    ExplainConfig {
+        users: vec!["analyst".to_string()],
+        tables: tables.iter().map(|t| t.to_string()).collect(),
+        backend_user: "explainer".to_string(),
+        backend_password: "secret".to_string(),
+        max_rows,
+        allow_full_scans: false,
+        action: VetAction::Reject,
+        timeout_ms: 100,
+    }
+}
+
+fn step(id: u64, table: &str, access: &str, rows: u64) -> PlanStep {
+    PlanStep { id: Some(id), table: Some(table.to_string()), access: Some(access.to_string()), rows: Some(rows) }
+}
+
+#[test]
+fn selects_by_the_configured_users_on_the_configured_tables_are_vetted() {
+    let select = Statement::parse("SELECT * FROM orders JOIN shop.customers USING (id)");
+    let everything = config(&[], None);
+    assert!(everything.covers("analyst", Some("sales"), &select));
+    assert!(!everything.covers("app", Some("sales"), &select));
+    assert!(!everything.covers("analyst", Some("sales"), &Statement::parse("DELETE FROM orders")));
+
+    // the schema comes from the statement, or else the session's
+    assert!(config(&["sales.orders"], None).covers("analyst", Some("sales"), &select));
+    assert!(!config(&["sales.orders"], None).covers("analyst", Some("archive"), &select));
+    assert!(config(&["shop.*"], None).covers("analyst", None, &select));
+    assert!(config(&["*.ORDERS"], None).covers("analyst", None, &select));
+    assert!(config(&["orders"], None).covers("analyst", Some("archive"), &select));
+    assert!(!config(&["*.invoices"], None).covers("analyst", Some("sales"), &select));
+}
+
+#[test]
+fn plans_are_assessed_by_their_full_scans_and_estimated_rows() {
+    // two joined tables in one SELECT, and a derived table in another
+    let plan = Plan { steps: vec![
+        step(1, "orders", "ref", 100),
+        step(1, "customers", "eq_ref", 3),
+        step(2, "<derived3>", "ALL", 50),
+    ] };
+    assert!(plan.full_scans().is_empty());
+    assert_eq!(plan.estimated_rows(), 350);
+    assert_eq!(config(&[], None).assess(&plan), None);
+    assert_eq!(config(&[], Some(350)).assess(&plan), None);
+    assert_eq!(config(&[], Some(300)).assess(&plan),
+               Some("The SELECT would examine about 350 rows, more than the 300 allowed".to_string()));
+
+    let scan = Plan { steps: vec![step(1, "orders", "ALL", 10)] };
+    assert_eq!(scan.full_scans(), vec!["orders"]);
+    assert_eq!(config(&[], None).assess(&scan), Some("The SELECT would read every row of table 'orders'".to_string()));
+    let mut allowed = config(&[], None);
+    allowed.allow_full_scans = true;
+    assert_eq!(allowed.assess(&scan), None);
+}
+
+#[test]
+fn queries_are_forwarded_unvetted_when_the_backend_cant_explain_them() {
+    // nothing listens here
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = listener.local_addr().unwrap();
+    drop(listener);
+    let mut tester = HandlerTester::new(ExplainHandler::new(config(&[], Some(1)), "analyst", backend, Forward));
+    assert_eq!(tester.request(Packet::com_query("SELECT * FROM orders")), Action::Forward);
+}
@@ -0,0 +1,163 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::net::{SocketAddr, TcpListener};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_core::reactor::{Core, Timeout};
+
+use mysql_proxy::{Packet, Pipe, Transport};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::failover::{self, FailoverWindow};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::resume::{Reconnect, SessionResume, SessionResumeConfig};
+use mysql_proxy::testing::{duplex, DuplexEnd, Forward};
+
+fn turn(core: &mut Core) {
+    for _ in 0..5 {
+        core.turn(Some(Duration::from_millis(1)));
+    }
+}
+
+#[test]
+fn windows_release_connections_once_they_expire() {
+    let mut core = Core::new().unwrap();
+    let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+    let window = FailoverWindow::new(primary.local_addr().unwrap());
+    window.begin(Duration::from_millis(50), &core.handle()).unwrap();
+    assert!(window.is_holding());
+
+    let started = Instant::now();
+    let stream = core.run(failover::connect(&window, &core.handle())).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(stream.peer_addr().unwrap(), primary.local_addr().unwrap());
+    assert!(!window.is_holding());
+}
+
+#[test]
+fn connections_wait_for_the_promoted_primary() {
+    let mut core = Core::new().unwrap();
+    let (old, new) = (TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap());
+    let new_addr = new.local_addr().unwrap();
+    let window = FailoverWindow::new(old.local_addr().unwrap());
+    window.begin(Duration::from_secs(10), &core.handle()).unwrap();
+
+    let promoting = window.clone();
+    let promotion = Timeout::new(Duration::from_millis(20), &core.handle()).unwrap()
+        .map(move |_| promoting.promote(new_addr));
+    let connect = failover::connect(&window, &core.handle());
+    let (stream, _) = core.run(connect.join(promotion)).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), new_addr);
+    assert_eq!(window.primary(), new_addr);
+}
+
+#[test]
+fn an_earlier_windows_timer_doesnt_close_a_later_one() {
+    let mut core = Core::new().unwrap();
+    let window = FailoverWindow::new("127.0.0.1:3306".parse().unwrap());
+    window.begin(Duration::from_millis(20), &core.handle()).unwrap();
+    window.promote("127.0.0.1:3307".parse().unwrap());
+    assert!(!window.is_holding());
+
+    window.begin(Duration::from_secs(10), &core.handle()).unwrap();
+    core.run(Timeout::new(Duration::from_millis(50), &core.handle()).unwrap()).unwrap();
+    assert!(window.is_holding());
+}
+
+struct Session {
+    core: Core,
+    client: DuplexEnd,
+    old: DuplexEnd,
+    new: DuplexEnd,
+    window: FailoverWindow,
+    /// whether the pipe ended well, once it has
+    ended: Rc<RefCell<Option<bool>>>,
+}
+
+const NEW_PRIMARY: &str = "10.0.0.2:3306";
+
+impl Session {
+
+    /// A session on an old primary, which moves to the new one with its session resume if
+    /// `resume` is set
+    fn new(resume: bool) -> Self {
+        let core = Core::new().unwrap();
+        let (client, client_end) = duplex(1 << 16);
+        let (old, old_end) = duplex(1 << 16);
+        let (new, new_end) = duplex(1 << 16);
+        let window = FailoverWindow::new("10.0.0.1:3306".parse().unwrap());
+        let mut pipe = Pipe::new(Rc::new(client_end), Rc::new(old_end), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41)
+            .with_failover(window.clone());
+        if resume {
+            let reconnect: Reconnect = Box::new(move || Box::new(future::ok(Rc::new(new_end.clone()) as Rc<dyn Transport>)));
+            pipe = pipe.with_session_resume(SessionResume::new(SessionResumeConfig::default(), reconnect, &core.handle()));
+        }
+        let ended = Rc::new(RefCell::new(None));
+        let result = ended.clone();
+        core.handle().spawn(pipe.then(move |r| {
+            *result.borrow_mut() = Some(r.is_ok());
+            Ok(())
+        }));
+        let mut session = Session { core, client, old, new, window, ended };
+        // a statement before the failover
+        session.client.send(&Packet::com_query("SELECT 1"));
+        turn(&mut session.core);
+        assert_eq!(session.old.recv(), vec![Packet::com_query("SELECT 1")]);
+        session.old.send(&ok_packet(1));
+        turn(&mut session.core);
+        assert_eq!(session.client.recv(), vec![ok_packet(1)]);
+        session
+    }
+
+    /// Open the window, and send a statement while it's open
+    fn hold(&mut self) {
+        self.window.begin(Duration::from_secs(10), &self.core.handle()).unwrap();
+        self.client.send(&Packet::com_query("INSERT INTO t VALUES (1)"));
+        turn(&mut self.core);
+        assert!(self.old.recv().is_empty());
+        assert!(self.new.recv().is_empty());
+    }
+}
+
+#[test]
+fn held_statements_are_flushed_to_the_new_primary() {
+    let mut session = Session::new(true);
+    session.hold();
+    session.window.promote(NEW_PRIMARY.parse::<SocketAddr>().unwrap());
+    turn(&mut session.core);
+    assert!(session.old.recv().is_empty());
+    assert_eq!(session.new.recv(), vec![Packet::com_query("INSERT INTO t VALUES (1)")]);
+    session.new.send(&ok_packet(1));
+    turn(&mut session.core);
+    assert_eq!(session.client.recv(), vec![ok_packet(1)]);
+    assert_eq!(*session.ended.borrow(), None);
+}
+
+#[test]
+fn held_statements_go_to_the_old_primary_if_the_window_expires() {
+    let mut session = Session::new(true);
+    session.window.begin(Duration::from_millis(20), &session.core.handle()).unwrap();
+    session.client.send(&Packet::com_query("INSERT INTO t VALUES (1)"));
+    turn(&mut session.core);
+    assert!(session.old.recv().is_empty());
+    session.core.run(Timeout::new(Duration::from_millis(40), &session.core.handle()).unwrap()).unwrap();
+    turn(&mut session.core);
+    assert_eq!(session.old.recv(), vec![Packet::com_query("INSERT INTO t VALUES (1)")]);
+    assert!(session.new.recv().is_empty());
+}
+
+#[test]
+fn sessions_that_cant_move_to_the_new_primary_are_closed() {
+    let mut session = Session::new(false);
+    session.hold();
+    session.window.promote(NEW_PRIMARY.parse::<SocketAddr>().unwrap());
+    turn(&mut session.core);
+    assert!(session.old.recv().is_empty());
+    assert_eq!(*session.ended.borrow(), Some(false));
+    assert!(session.client.is_closed());
+}
@@ -0,0 +1,182 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::flush::FlushPolicy;
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection, counting the writes made to it
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    writes: Rc<Cell<usize>>,
+}
+
+impl Memory {
+
+    fn send(&self, packets: &[Packet]) {
+        for p in packets {
+            self.incoming.borrow_mut().extend(p.bytes.iter());
+        }
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+fn poll(pipe: &mut Spawn<Pipe<Forward>>) {
+    assert_eq!(pipe.poll_future_notify(&std::sync::Arc::new(Ignore), 0).unwrap(), Async::NotReady);
+}
+
+/// Run the reactor, firing any timers due meanwhile
+fn wait(core: &mut Core, duration: Duration) {
+    let until = Instant::now() + duration;
+    while let Some(left) = until.checked_duration_since(Instant::now()) {
+        core.turn(Some(left));
+    }
+}
+
+/// The start and the rest of a result set with one row
+fn result_set() -> (Vec<Packet>, Vec<Packet>) {
+    (vec![
+        Packet::new(1, &[0x01]),
+        Packet::new(2, b"\x03def\x00\x00\x00\x01a\x00\x0c\x21\x00\x01\x00\x00\x00\xfd\x00\x00\x00\x00\x00"),
+    ], vec![
+        Packet::new(3, &[0xfe, 0x00, 0x00, 0x02, 0x00]),
+        Packet::new(4, b"\x011"),
+        Packet::new(5, &[0xfe, 0x00, 0x00, 0x02, 0x00]),
+    ])
+}
+
+fn pipe(policy: FlushPolicy, core: &Core) -> (Memory, Memory, Spawn<Pipe<Forward>>) {
+    let (client, server) = (Memory::default(), Memory::default());
+    let pipe = Pipe::from_streams(client.clone(), server.clone(), Forward)
+        .with_flush_policy(policy, &core.handle());
+    (client, server, executor::spawn(pipe))
+}
+
+#[test]
+fn per_response_writes_a_result_set_to_the_client_once_it_is_complete() {
+    let core = Core::new().unwrap();
+    let (start, rest) = result_set();
+
+    // by default each part is written as it arrives
+    let (client, server, mut immediate) = pipe(FlushPolicy::Immediate, &core);
+    client.send(&[Packet::com_query("SELECT 1")]);
+    server.send(&start);
+    poll(&mut immediate);
+    server.send(&rest);
+    poll(&mut immediate);
+    assert_eq!(client.writes.get(), 2);
+
+    let (client, server, mut per_response) = pipe(FlushPolicy::PerResponse { max_bytes: 65536 }, &core);
+    client.send(&[Packet::com_query("SELECT 1")]);
+    server.send(&start);
+    poll(&mut per_response);
+    assert_eq!(server.written(), Packet::com_query("SELECT 1").bytes);
+    assert!(client.written().is_empty());
+    server.send(&rest);
+    poll(&mut per_response);
+    assert_eq!(client.writes.get(), 1);
+    let expected: Vec<u8> = start.iter().chain(rest.iter()).flat_map(|p| p.bytes.clone()).collect();
+    assert_eq!(client.written(), expected);
+}
+
+#[test]
+fn a_batch_is_written_once_it_has_waited_or_grown_large_enough() {
+    let mut core = Core::new().unwrap();
+    let (client, server, mut batched) = pipe(FlushPolicy::Batch { delay_us: 20_000, max_bytes: 65536 }, &core);
+    client.send(&[Packet::com_ping()]);
+    poll(&mut batched);
+    assert!(server.written().is_empty());
+
+    // the reactor fires the batch's timer
+    wait(&mut core, Duration::from_millis(30));
+    poll(&mut batched);
+    assert_eq!(server.written(), Packet::com_ping().bytes);
+    server.send(&[ok_packet(1)]);
+    poll(&mut batched);
+    assert!(client.written().is_empty());
+    wait(&mut core, Duration::from_millis(30));
+    poll(&mut batched);
+    assert_eq!(client.written(), ok_packet(1).bytes);
+
+    // large enough, it doesn't wait
+    let (client, server, mut batched) = pipe(FlushPolicy::Batch { delay_us: 1_000_000, max_bytes: ok_packet(1).bytes.len() }, &core);
+    client.send(&[Packet::com_ping()]);
+    poll(&mut batched);
+    server.send(&[ok_packet(1)]);
+    poll(&mut batched);
+    assert_eq!(client.written(), ok_packet(1).bytes);
+}
+
+#[test]
+fn policies_are_configured_by_name() {
+    let config = ProxyConfig::parse(r#"
+        [flush]
+        policy = "batch"
+        delay_us = 500
+    "#).unwrap();
+    assert_eq!(config.flush, FlushPolicy::Batch { delay_us: 500, max_bytes: 65536 });
+    assert_eq!(ProxyConfig::parse("").unwrap().flush, FlushPolicy::Immediate);
+    assert_eq!(FlushPolicy::Batch { delay_us: 0, max_bytes: 1 }.validate(),
+               Err("delay_us must be between 1 and 1000000".to_string()));
+}
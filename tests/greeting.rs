@@ -0,0 +1,55 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::codec::HandshakeV10;
+use mysql_proxy::greeting::{GreetingConfig, GreetingHandler};
+use mysql_proxy::protocol::{CLIENT_COMPRESS, CLIENT_LOCAL_FILES, CLIENT_PLUGIN_AUTH, CLIENT_PROTOCOL_41, CLIENT_SECURE_CONNECTION};
+use mysql_proxy::testing::Forward;
+
+fn greeting(server_version: &str, capability_flags: u32) -> HandshakeV10 {
+    HandshakeV10 {
+        server_version: server_version.to_string(),
+        connection_id: 7,
+        capability_flags,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some("mysql_native_password".to_string()),
+    }
+}
+
+const FLAGS: u32 = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH | CLIENT_LOCAL_FILES;
+
+#[test]
+fn greetings_present_the_configured_version_and_capabilities() {
+    let config = GreetingConfig {
+        server_version: Some("8.0.36-proxy".to_string()),
+        clear_capabilities: CLIENT_LOCAL_FILES,
+        set_capabilities: CLIENT_COMPRESS,
+    };
+    let rewritten = config.rewrite(&greeting("5.7.44-log", FLAGS).to_packet(0)).unwrap();
+    let expected = greeting("8.0.36-proxy", (FLAGS | CLIENT_COMPRESS) & !CLIENT_LOCAL_FILES);
+    assert_eq!(HandshakeV10::parse(&rewritten).unwrap(), expected);
+
+    // only greetings are rewritten
+    assert_eq!(config.rewrite(&greeting("5.7.44-log", FLAGS).to_packet(1)), None);
+    assert_eq!(config.rewrite(&Packet::new(0, &[0xff, 0x15, 0x04])), None);
+
+    // and what isn't configured is left as the backend sent it
+    let mut unchanged = greeting("5.7.44-log", FLAGS);
+    GreetingConfig::default().apply(&mut unchanged);
+    assert_eq!(unchanged, greeting("5.7.44-log", FLAGS));
+}
+
+#[test]
+fn only_the_first_packet_from_the_server_is_rewritten() {
+    let config = GreetingConfig { server_version: Some("8.0.36-proxy".to_string()), ..GreetingConfig::default() };
+    let mut handler = GreetingHandler::new(config, Forward);
+    let original = greeting("5.7.44-log", FLAGS).to_packet(0);
+    assert_eq!(handler.handle_response(&original), Action::Mutate(greeting("8.0.36-proxy", FLAGS).to_packet(0)));
+    assert_eq!(handler.handle_response(&original), Action::Forward);
+
+    // a server that doesn't greet is passed through
+    let mut handler = GreetingHandler::new(GreetingConfig::default(), Forward);
+    assert_eq!(handler.handle_response(&Packet::new(0, &[0xff, 0x15, 0x04])), Action::Forward);
+}
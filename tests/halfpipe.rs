@@ -0,0 +1,132 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{future, Async, Poll};
+use futures::executor::{self, Notify};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{AsyncTransport, HalfPipe, HalfPipeStats, Packet, Pipe};
+use mysql_proxy::budget::PollBudget;
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Counts the times the task is woken
+#[derive(Default)]
+struct Wakeups(AtomicUsize);
+
+impl Notify for Wakeups {
+    fn notify(&self, _: usize) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn a_half_pipe_relays_one_direction_on_its_own() {
+    let (from, to) = (Memory::default(), Memory::default());
+    let mut half = HalfPipe::new(Rc::new(AsyncTransport::new(from.clone())), Rc::new(AsyncTransport::new(to.clone())));
+    let control = half.control();
+    let wakeups = Arc::new(Wakeups::default());
+    let mut relay = executor::spawn(future::poll_fn(move || half.relay(&mut PollBudget::default().start())));
+
+    from.send(&Packet::com_query("SELECT 1"));
+    from.send(&Packet::com_ping());
+    assert_eq!(relay.poll_future_notify(&wakeups, 0).unwrap(), Async::NotReady);
+    assert_eq!(to.written(), [Packet::com_query("SELECT 1").bytes, Packet::com_ping().bytes].concat());
+    let stats = control.stats();
+    assert_eq!((stats.packets_read, stats.bytes_read), (2, 13 + 5));
+    assert_eq!((stats.packets_written, stats.bytes_written), (2, 13 + 5));
+
+    // paused, nothing is read until it's resumed, which wakes the task
+    control.pause();
+    from.send(&Packet::com_quit());
+    assert_eq!(relay.poll_future_notify(&wakeups, 0).unwrap(), Async::NotReady);
+    assert!(to.written().is_empty());
+    assert_eq!(wakeups.0.load(Ordering::SeqCst), 0);
+    control.resume();
+    assert_eq!(wakeups.0.load(Ordering::SeqCst), 1);
+    assert_eq!(relay.poll_future_notify(&wakeups, 0).unwrap(), Async::NotReady);
+    assert_eq!(to.written(), Packet::com_quit().bytes);
+    assert_eq!(control.stats().packets_read, 3);
+}
+
+#[test]
+fn each_direction_of_a_pipe_pauses_on_its_own() {
+    let (client, server) = (Memory::default(), Memory::default());
+    let pipe = Pipe::from_streams(client.clone(), server.clone(), Forward);
+    let (requests, responses) = (pipe.requests(), pipe.responses());
+    let wakeups = Arc::new(Wakeups::default());
+    let mut pipe = executor::spawn(pipe);
+    let ok = Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0]);
+
+    responses.pause();
+    client.send(&Packet::com_query("SELECT 1"));
+    server.send(&ok);
+    assert_eq!(pipe.poll_future_notify(&wakeups, 0).unwrap(), Async::NotReady);
+    assert_eq!(server.written(), Packet::com_query("SELECT 1").bytes);
+    assert!(client.written().is_empty());
+
+    responses.resume();
+    assert_eq!(pipe.poll_future_notify(&wakeups, 0).unwrap(), Async::NotReady);
+    assert_eq!(client.written(), ok.bytes);
+    assert_eq!(requests.stats(), HalfPipeStats { packets_read: 1, bytes_read: 13, packets_written: 1, bytes_written: 13 });
+    assert_eq!(responses.stats().packets_written, 1);
+}
@@ -0,0 +1,123 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::health::{check_backend, serve, HealthMonitor, PingHandler};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+/// The bytes of the corpus's MySQL 8.0 greeting
+fn greeting() -> Vec<u8> {
+    let hex: String = include_str!("corpus/mysql-8.0-greeting.hex").lines().filter(|line| !line.starts_with('#')).collect();
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect()
+}
+
+/// A backend that greets one connection with `greeting`
+fn backend(greeting: Vec<u8>) -> BackendAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&greeting).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    });
+    BackendAddr::from(addr)
+}
+
+fn refused() -> BackendAddr {
+    BackendAddr::from(net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap())
+}
+
+#[test]
+fn pings_are_answered_by_the_proxy() {
+    let mut tester = HandlerTester::new(PingHandler::new(Forward));
+    assert_eq!(tester.request(Packet::com_ping()), Action::Respond(vec![ok_packet(1)]));
+    assert!(tester.to_server().is_empty());
+    assert_eq!(tester.request(Packet::com_query("SELECT 1")), Action::Forward);
+}
+
+#[test]
+fn backends_are_up_once_they_greet_a_check() {
+    let mut core = Core::new().unwrap();
+    let timeout = Duration::from_secs(2);
+    core.run(check_backend(&backend(greeting()), None, None, timeout, &core.handle())).unwrap();
+
+    let refusal = Packet::error_packet(1130, *b"HY000", "Host is not allowed to connect".to_string()).with_sequence_id(0);
+    let e = core.run(check_backend(&backend(refusal.bytes), None, None, timeout, &core.handle())).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+
+    // a server that never greets
+    let silent = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_addr = BackendAddr::from(silent.local_addr().unwrap());
+    let e = core.run(check_backend(&silent_addr, None, None, Duration::from_millis(50), &core.handle())).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn the_proxy_is_healthy_while_a_backend_is_up() {
+    let monitor = HealthMonitor::new();
+    assert!(monitor.is_healthy());
+    let (up, down) = (BackendAddr::new("db1", 3306), BackendAddr::new("db2", 3306));
+    monitor.record(&up, &Ok(()));
+    monitor.record(&down, &Err(Error::new(ErrorKind::ConnectionRefused, "refused")));
+    assert!(monitor.is_healthy());
+    let report = monitor.report();
+    assert_eq!(report.status, "ok");
+    assert!(report.backends["db1:3306"].up);
+    assert_eq!(report.backends["db2:3306"].error, Some("refused".to_string()));
+
+    monitor.record(&up, &Err(Error::new(ErrorKind::TimedOut, "timed out")));
+    assert!(!monitor.is_healthy());
+    assert_eq!(monitor.report().status, "down");
+}
+
+/// What the endpoint answers `request` with
+fn ask(addr: SocketAddr, request: &str) -> String {
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn health_checks_are_answered_over_http_and_plain_tcp() {
+    let monitor = HealthMonitor::new();
+    monitor.record(&refused(), &Err(Error::new(ErrorKind::ConnectionRefused, "refused")));
+    let std_listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let (sender, answers) = mpsc::channel();
+    thread::spawn(move || {
+        let live = ask(addr, "GET /healthz HTTP/1.1\r\n\r\n");
+        let ready = ask(addr, "GET /readyz HTTP/1.1\r\n\r\n");
+        let report = ask(addr, "GET / HTTP/1.1\r\n\r\n");
+        let plain = ask(addr, "\n");
+        sender.send((live, ready, report, plain)).unwrap();
+    });
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::from_listener(std_listener, &addr, &handle).unwrap();
+    handle.spawn(serve(listener, monitor, &handle).map_err(|_| ()));
+    let (live, ready, report, plain) = loop {
+        core.turn(Some(Duration::from_millis(10)));
+        if let Ok(answers) = answers.try_recv() {
+            break answers;
+        }
+    };
+    assert!(live.starts_with("HTTP/1.1 200 OK\r\n") && live.ends_with(r#"{"status":"ok"}"#), "{}", live);
+    assert!(ready.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", ready);
+    assert!(report.starts_with("HTTP/1.1 503") && report.contains(r#""status":"down""#), "{}", report);
+    assert_eq!(plain, "DOWN\n");
+}
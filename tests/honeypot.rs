@@ -0,0 +1,107 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::events::{Event, EventBus};
+use mysql_proxy::honeypot::{self, HoneypotAnswer, HoneypotConfig};
+use mysql_proxy::sidechannel::{SideChannel, SideChannelConfig};
+
+/// A honeypot listening on a reactor thread of its own, and the events it publishes
+fn honeypot(answer: HoneypotAnswer) -> (SocketAddr, Receiver<Event>) {
+    let config = HoneypotConfig {
+        server_version: "5.7.44-log".to_string(),
+        greeting_delay_ms: 10,
+        idle_timeout_secs: 5,
+        answer,
+    };
+    let events = EventBus::new();
+    let received = events.subscribe_channel();
+    let (addr_sender, addr) = channel();
+    thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        addr_sender.send(listener.local_addr().unwrap()).unwrap();
+        core.run(listener.incoming().for_each(|(socket, peer)| {
+            handle.spawn(honeypot::serve_client(socket, peer, &config, events.clone(), &handle).then(|_| Ok(())));
+            Ok(())
+        })).unwrap();
+    });
+    (addr.recv().unwrap(), received)
+}
+
+fn channel_as(user: &str) -> SideChannel {
+    SideChannel::new(&SideChannelConfig {
+        backend_user: user.to_string(),
+        backend_password: "hunter2".to_string(),
+        timeout_ms: 2000,
+        max_concurrent: 1,
+        max_idle: 0,
+        max_rows: 10,
+        max_lifetime_secs: None,
+        max_idle_secs: None,
+    })
+}
+
+#[test]
+fn any_login_is_accepted_and_recorded() {
+    let (addr, events) = honeypot(HoneypotAnswer::Empty);
+    let channel = channel_as("root");
+
+    let answer = channel.query_blocking(addr, "SELECT @@version").unwrap();
+    assert_eq!(answer.columns[0].name, "@@version");
+    assert_eq!(answer.rows, vec![vec![Some(b"5.7.44-log".to_vec())]]);
+    let answer = channel.query_blocking(addr, "show databases").unwrap();
+    assert_eq!(answer.rows.len(), 4);
+    let answer = channel.query_blocking(addr, "SELECT password FROM mysql.user").unwrap();
+    assert_eq!(answer.columns[0].name, "password");
+    assert!(answer.rows.is_empty());
+
+    let logins: Vec<_> = events.try_iter().collect();
+    assert!(logins.iter().any(|e| match *e {
+        Event::HoneypotLogin { ref user, .. } => user == "root",
+        _ => false,
+    }));
+    assert!(logins.iter().any(|e| match *e {
+        Event::HoneypotCommand { ref command, ref statement, .. } => {
+            command == "COM_QUERY" && statement.as_ref().map(|s| &s[..]) == Some("SELECT password FROM mysql.user")
+        },
+        _ => false,
+    }));
+}
+
+#[test]
+fn denied_honeypots_refuse_statements() {
+    let (addr, _) = honeypot(HoneypotAnswer::Denied);
+    let channel = channel_as("admin");
+
+    let answer = channel.query_blocking(addr, "SELECT @@version_comment").unwrap();
+    assert_eq!(answer.rows, vec![vec![Some(b"MySQL Community Server - GPL".to_vec())]]);
+    let error = channel.query_blocking(addr, "DROP TABLE customers").unwrap_err();
+    assert!(error.to_string().contains("DROP command denied to user 'admin'@'127.0.0.1'"), "{}", error);
+}
+
+#[test]
+fn configs_are_checked() {
+    let config = HoneypotConfig { server_version: String::new(), greeting_delay_ms: 0, idle_timeout_secs: 1, answer: HoneypotAnswer::Empty };
+    assert_eq!(config.validate(), Err("server_version must be a non-empty string without NUL bytes".to_string()));
+
+    // a honeypot needs no backends or users
+    let config = ProxyConfig::parse(r#"
+        [listeners.trap]
+        listen = ["0.0.0.0:3306"]
+        [listeners.trap.honeypot]
+        answer = "denied"
+    "#).unwrap();
+    assert_eq!(config.listeners[0].config.honeypot.as_ref().map(|h| h.answer), Some(HoneypotAnswer::Denied));
+    assert_eq!(config.validate(), Vec::<String>::new());
+}
@@ -0,0 +1,109 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::codec::{ok_packet, ok_packet_with_status, SERVER_STATUS_AUTOCOMMIT};
+use mysql_proxy::idle::{IdleAction, IdleTransactionConfig, IdleTransactionGuard};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::state::SERVER_STATUS_IN_TRANS;
+use mysql_proxy::testing::{duplex, DuplexEnd, Forward};
+
+fn turn(core: &mut Core) {
+    for _ in 0..5 {
+        core.turn(Some(Duration::from_millis(1)));
+    }
+}
+
+/// An OK packet with SERVER_STATUS_IN_TRANS set
+fn ok_in_transaction(seq: u8) -> Packet {
+    ok_packet_with_status(seq, SERVER_STATUS_AUTOCOMMIT | SERVER_STATUS_IN_TRANS)
+}
+
+struct Session {
+    core: Core,
+    client: DuplexEnd,
+    server: DuplexEnd,
+    /// whether the pipe ended well, once it has
+    ended: Rc<RefCell<Option<bool>>>,
+}
+
+impl Session {
+
+    /// A session whose transactions may idle for `idle_secs`, which has just sent `BEGIN`
+    /// and been answered with `answer`
+    fn new(idle_secs: u64, action: IdleAction, answer: Packet) -> Self {
+        let mut core = Core::new().unwrap();
+        let (client, client_end) = duplex(1 << 16);
+        let (server, server_end) = duplex(1 << 16);
+        let guard = IdleTransactionGuard::new(IdleTransactionConfig { idle_secs, action }, "alice", &core.handle());
+        let pipe = Pipe::new(Rc::new(client_end), Rc::new(server_end), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41)
+            .with_idle_transaction_guard(guard);
+        let ended = Rc::new(RefCell::new(None));
+        let result = ended.clone();
+        core.handle().spawn(pipe.then(move |r| {
+            *result.borrow_mut() = Some(r.is_ok());
+            Ok(())
+        }));
+        client.send(&Packet::com_query("BEGIN"));
+        turn(&mut core);
+        assert_eq!(server.recv(), vec![Packet::com_query("BEGIN")]);
+        server.send(&answer);
+        turn(&mut core);
+        assert_eq!(client.recv(), vec![answer]);
+        Session { core, client, server, ended }
+    }
+}
+
+#[test]
+fn sessions_idle_in_a_transaction_are_killed() {
+    let mut session = Session::new(0, IdleAction::Kill, ok_in_transaction(1));
+    turn(&mut session.core);
+    assert_eq!(*session.ended.borrow(), Some(true));
+    assert!(session.client.is_closed());
+    assert!(session.server.is_closed());
+}
+
+#[test]
+fn idle_transactions_are_rolled_back_and_the_client_told_so() {
+    let mut session = Session::new(0, IdleAction::Rollback, ok_in_transaction(1));
+    turn(&mut session.core);
+    assert_eq!(session.server.recv(), vec![Packet::com_query("ROLLBACK")]);
+    // the client doesn't see the answer to the proxy's own ROLLBACK
+    session.server.send(&ok_packet(1));
+    turn(&mut session.core);
+    assert!(session.client.recv().is_empty());
+
+    // its next command is refused, and the one after goes through
+    session.client.send(&Packet::com_query("UPDATE t SET x = 1"));
+    turn(&mut session.core);
+    assert!(session.server.recv().is_empty());
+    let error = session.client.recv();
+    assert_eq!(error.len(), 1);
+    assert_eq!(&error[0].payload()[..3], &[0xff, 0x51, 0x04]);
+    session.client.send(&Packet::com_query("SELECT 1"));
+    turn(&mut session.core);
+    assert_eq!(session.server.recv(), vec![Packet::com_query("SELECT 1")]);
+    assert_eq!(*session.ended.borrow(), None);
+}
+
+#[test]
+fn sessions_arent_touched_outside_transactions_or_before_the_limit() {
+    let mut session = Session::new(60, IdleAction::Kill, ok_in_transaction(1));
+    turn(&mut session.core);
+    assert_eq!(*session.ended.borrow(), None);
+
+    // a BEGIN that didn't open a transaction
+    let mut session = Session::new(0, IdleAction::Kill, ok_packet(1));
+    turn(&mut session.core);
+    assert_eq!(*session.ended.borrow(), None);
+    assert!(!session.client.is_closed());
+}
@@ -0,0 +1,123 @@
+extern crate mysql_proxy;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Write};
+use std::sync::{Arc, Mutex};
+
+use mysql_proxy::admin::{Admin, Reply};
+use mysql_proxy::audit::{self, AuditLog};
+use mysql_proxy::balance::{BackendPool, BackendWeights};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::labels::{Labels, LabelsConfig};
+use mysql_proxy::management::Management;
+use mysql_proxy::stats::{Stats, StatsSnapshot, UserStats};
+
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    Labels::new(pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect())
+}
+
+#[test]
+fn labels_come_from_config_attrs_and_users() {
+    let config = ProxyConfig::parse(r#"
+        [labels]
+        fixed = { team = "payments" }
+        connect_attrs = { app = "program_name", team = "_client_name" }
+        user_attributes = { tenant = "tenant_id" }
+    "#).unwrap();
+    let labels_config = config.labels.clone().unwrap();
+    let attrs = vec![("program_name".to_string(), "x".repeat(100)), ("_client_name".to_string(), "libmysql".to_string())];
+    let attributes: HashMap<String, String> = vec![("tenant_id".to_string(), "42".to_string())].into_iter().collect();
+    let session_labels = labels_config.labels_for(&attrs, &attributes);
+    assert_eq!(session_labels.get("app"), Some("x".repeat(64)));
+    // the listener's labels win over the client's
+    assert_eq!(session_labels.get("team").as_ref().map(|t| &t[..]), Some("payments"));
+    assert_eq!(session_labels.get("tenant").as_ref().map(|t| &t[..]), Some("42"));
+    assert_eq!(config.validate(), Vec::<String>::new());
+
+    // copies share the labels a handler sets later
+    let copy = session_labels.clone();
+    copy.set("feature", "checkout");
+    assert_eq!(session_labels.get("feature").as_ref().map(|f| &f[..]), Some("checkout"));
+    assert_eq!(labels(&[("a", "1"), ("b", "2")]).to_string(), "a=1 b=2");
+
+    let bad = LabelsConfig { fixed: vec![("team-name".to_string(), "x".to_string())].into_iter().collect(), ..LabelsConfig::default() };
+    assert!(bad.validate().unwrap_err().contains("'team-name' isn't a label name"));
+}
+
+#[test]
+fn statistics_by_label_are_bounded() {
+    let stats = Stats::new().with_labels(&["app".to_string()], 2);
+    let query = UserStats { queries: 1, bytes_returned: 10, ..UserStats::default() };
+    for app in &["billing", "search", "reports", "etl"] {
+        stats.record_labelled(&labels(&[("app", app), ("team", "ignored")]), &query);
+    }
+    stats.record_labelled(&labels(&[("app", "billing")]), &query);
+
+    let snapshot = stats.snapshot();
+    let by_app: BTreeMap<String, u64> = snapshot.labelled.iter().map(|l| (l.labels["app"].clone(), l.totals.queries)).collect();
+    assert_eq!(by_app, vec![("billing".to_string(), 2), ("other".to_string(), 2), ("search".to_string(), 1)].into_iter().collect());
+    let metrics = snapshot.prometheus();
+    assert!(metrics.contains("# TYPE mysql_proxy_labelled_queries_total counter\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_labelled_queries_total{app=\"other\"} 2\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_labelled_bytes_returned_total{app=\"billing\"} 20\n"), "{}", metrics);
+
+    // saved totals keep counting against the bound
+    let saved: StatsSnapshot = saved_and_loaded(&snapshot);
+    let restored = Stats::new();
+    restored.restore(saved);
+    let restored = restored.with_labels(&["app".to_string()], 2);
+    restored.record_labelled(&labels(&[("app", "etl")]), &query);
+    let other = restored.snapshot().labelled.into_iter().find(|l| l.labels["app"] == "other").unwrap();
+    assert_eq!(other.totals.queries, 3);
+}
+
+fn saved_and_loaded(snapshot: &StatsSnapshot) -> StatsSnapshot {
+    let dir = std::env::temp_dir().join(format!("mysql-proxy-labels-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("stats.json");
+    let stats = Stats::new();
+    stats.restore(snapshot.clone());
+    stats.save(&path).unwrap();
+    let loaded = Stats::load(&path).unwrap().snapshot();
+    std::fs::remove_dir_all(&dir).unwrap();
+    loaded
+}
+
+#[test]
+fn audit_records_and_listings_carry_labels() {
+    let written = Shared::default();
+    let log = AuditLog::new(Box::new(written.clone()));
+    log.record("app", "DROP TABLE t").unwrap();
+    let session_labels: BTreeMap<String, String> = vec![("team".to_string(), "payments".to_string())].into_iter().collect();
+    log.record_labelled("app", &session_labels, "DROP TABLE u").unwrap();
+    let text = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(!lines[0].contains("labels"), "{}", lines[0]);
+    assert!(lines[1].contains("\"labels\":{\"team\":\"payments\"}"), "{}", lines[1]);
+    assert_eq!(audit::verify(Cursor::new(text.as_bytes())).unwrap(), 2);
+
+    let management = Management::new(BackendPool::default(), BackendWeights::new());
+    let session = labels(&[("app", "billing")]);
+    let _registered = management.connections.register("main", "app", "10.0.0.5:51234".parse().unwrap(), "db1:3306", session.clone());
+    session.set("team", "payments");
+    assert_eq!(management.connections.list()[0].labels.len(), 2);
+    let reply = Admin::new(management).execute("SELECT user, labels FROM stats_connections").unwrap();
+    assert_eq!(reply, Reply::Rows {
+        columns: vec!["user".to_string(), "labels".to_string()],
+        rows: vec![vec![Some("app".to_string()), Some("app=billing,team=payments".to_string())]],
+    });
+}
@@ -0,0 +1,61 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::legacy::LegacyEofHandler;
+use mysql_proxy::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn tester() -> HandlerTester<LegacyEofHandler<Forward>> {
+    HandlerTester::new(LegacyEofHandler::new(Forward)).with_backend_capabilities(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF)
+}
+
+fn column(seq: u8) -> Packet {
+    Packet::new(seq, b"\x03def\x04shop\x01t\x01t\x01c\x01c\x0c\x21\x00\x28\x00\x00\x00\xfd\x00\x00\x00\x00\x00")
+}
+
+fn eof(seq: u8, status_flags: u16) -> Packet {
+    let status = status_flags.to_le_bytes();
+    Packet::new(seq, &[0xfe, 0x00, 0x00, status[0], status[1]])
+}
+
+/// The packets of a single action, one after the other
+fn packets(packets: &[Packet]) -> Action {
+    Action::Mutate(Packet { bytes: packets.iter().flat_map(|p| p.bytes.clone()).collect() })
+}
+
+#[test]
+fn result_sets_get_their_eof_packets_back() {
+    let mut tester = tester();
+    assert_eq!(tester.request(Packet::com_query("SELECT c FROM t")), Action::Forward);
+    assert_eq!(tester.response(Packet::new(1, &[0x01])), Action::Forward);
+    // the columns are followed by an EOF, and later packets renumbered
+    assert_eq!(tester.response(column(2)), packets(&[column(2), eof(3, 0x0002)]));
+    assert_eq!(tester.response(Packet::new(3, b"\x01a")), Action::Mutate(Packet::new(4, b"\x01a")));
+    // the OK ending the rows becomes an EOF, keeping its status and warnings
+    let ok = Packet::new(4, &[0xfe, 0x00, 0x00, 0x22, 0x00, 0x01, 0x00]);
+    assert_eq!(tester.response(ok), Action::Mutate(Packet::new(5, &[0xfe, 0x01, 0x00, 0x22, 0x00])));
+    assert_eq!(tester.in_flight(), 0);
+
+    // other responses pass as they are
+    assert_eq!(tester.request(Packet::com_query("DO 1")), Action::Forward);
+    assert_eq!(tester.response(ok_packet(1)), Action::Forward);
+    assert_eq!(tester.request(Packet::com_query("SELECT x")), Action::Forward);
+    let error = Packet::error_packet(1054, *b"42S22", "Unknown column 'x'".to_string());
+    assert_eq!(tester.response(Packet::new(1, error.payload())), Action::Forward);
+}
+
+#[test]
+fn prepared_statements_get_eof_packets_after_their_definitions() {
+    let mut tester = tester();
+    tester.request(Packet::new(0, b"\x16SELECT c FROM t WHERE c = ?"));
+    // statement 1, with a column and a parameter
+    let prepare_ok = Packet::new(1, &[0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(tester.response(prepare_ok), Action::Forward);
+    assert_eq!(tester.response(column(2)), packets(&[column(2), eof(3, 0x0002)]));
+    assert_eq!(tester.response(column(3)), packets(&[column(4), eof(5, 0x0002)]));
+
+    // and the next response is numbered as sent
+    tester.request(Packet::com_query("DO 1"));
+    assert_eq!(tester.response(ok_packet(1)), Action::Forward);
+}
@@ -0,0 +1,174 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::codec::{ok_packet, ok_packet_with_status};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::lifetime::{ConnectionLifetimeConfig, LifetimeGuard};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection, which records being shut down
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    shut_down: Rc<Cell<bool>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.shut_down.set(true);
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+struct Session {
+    client: Memory,
+    server: Memory,
+    pipe: Spawn<Pipe<Forward>>,
+    core: Core,
+}
+
+impl Session {
+
+    fn new(config: ConnectionLifetimeConfig) -> Self {
+        let core = Core::new().unwrap();
+        let (client, server) = (Memory::default(), Memory::default());
+        let pipe = Pipe::from_streams(client.clone(), server.clone(), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41)
+            .with_lifetime_guard(LifetimeGuard::new(config, "app", &core.handle()));
+        Session { client, server, pipe: executor::spawn(pipe), core }
+    }
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.pipe.poll_future_notify(&Arc::new(Ignore), 0)
+    }
+
+    /// Send a command and answer it with an OK packet with these status flags
+    fn run(&mut self, command: &[u8], status_flags: u16) {
+        self.client.send(&Packet::new(0, command));
+        assert_eq!(self.poll().unwrap(), Async::NotReady);
+        self.server.send(&ok_packet_with_status(1, status_flags));
+        assert_eq!(self.poll().unwrap(), Async::NotReady);
+        assert_eq!(self.server.written(), Packet::new(0, command).bytes);
+        assert_eq!(self.client.written(), ok_packet_with_status(1, status_flags).bytes);
+    }
+
+    /// Turn the reactor for `duration`, returning whether the pipe finished meanwhile
+    fn finishes_within(&mut self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.core.turn(Some(Duration::from_millis(10)));
+            if self.poll().unwrap().is_ready() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+const AUTOCOMMIT: u16 = 0x0002;
+const IN_TRANS: u16 = 0x0001;
+
+#[test]
+fn idle_sessions_are_closed_between_statements() {
+    let mut session = Session::new(ConnectionLifetimeConfig { max_lifetime_secs: None, max_idle_secs: Some(1) });
+    session.run(b"\x03SELECT 1", AUTOCOMMIT);
+
+    // a command awaiting its response keeps the session open
+    session.client.send(&Packet::new(0, b"\x03SELECT SLEEP(2)"));
+    assert!(!session.finishes_within(Duration::from_millis(1500)));
+    session.server.send(&ok_packet(1));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    session.server.written();
+
+    assert!(session.finishes_within(Duration::from_secs(3)));
+    assert_eq!(session.server.written(), Packet::com_quit().bytes);
+    assert!(session.client.shut_down.get() && session.server.shut_down.get());
+}
+
+#[test]
+fn sessions_past_their_lifetime_are_closed_once_their_transaction_ends() {
+    let mut session = Session::new(ConnectionLifetimeConfig { max_lifetime_secs: Some(1), max_idle_secs: None });
+    session.run(b"\x03BEGIN", AUTOCOMMIT | IN_TRANS);
+    assert!(!session.finishes_within(Duration::from_millis(1500)));
+    assert!(!session.client.shut_down.get());
+
+    session.client.send(&Packet::new(0, b"\x03COMMIT"));
+    session.server.send(&ok_packet(1));
+    assert_eq!(session.poll().unwrap(), Async::Ready(()));
+    let mut written = Packet::new(0, b"\x03COMMIT").bytes;
+    written.extend(Packet::com_quit().bytes);
+    assert_eq!(session.server.written(), written);
+    assert_eq!(session.client.written(), ok_packet(1).bytes);
+}
+
+#[test]
+fn connection_lifetimes_are_configured() {
+    let config = ProxyConfig::parse(r#"
+        [connection_lifetime]
+        max_lifetime_secs = 3600
+    "#).unwrap();
+    assert_eq!(config.connection_lifetime, Some(ConnectionLifetimeConfig { max_lifetime_secs: Some(3600), max_idle_secs: None }));
+    assert_eq!(ConnectionLifetimeConfig::default().validate(), Err("needs max_lifetime_secs, max_idle_secs or both".to_string()));
+}
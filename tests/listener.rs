@@ -0,0 +1,97 @@
+extern crate futures;
+extern crate mysql_proxy;
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Stream};
+
+use mysql_proxy::listener::{self, bind_reuseport, ListenerControl, ER_CON_COUNT_ERROR};
+use mysql_proxy::maintenance::ER_SERVER_SHUTDOWN;
+
+/// The code of an error packet
+fn code(error: &mysql_proxy::Packet) -> u16 {
+    u16::from_le_bytes([error.payload()[1], error.payload()[2]])
+}
+
+#[test]
+fn listeners_can_be_paused_and_limited() {
+    let control = ListenerControl::new("apps").with_max_connections(Some(2));
+    let first = control.admit().unwrap();
+    let _second = control.admit().unwrap();
+    assert_eq!(control.active(), 2);
+    let refused = control.admit().unwrap_err();
+    assert_eq!((code(&refused), refused.sequence_id()), (ER_CON_COUNT_ERROR, 0));
+    assert_eq!(control.active(), 2);
+
+    // a connection that closes makes room for another
+    drop(first);
+    assert_eq!(control.active(), 1);
+    let _third = control.admit().unwrap();
+    control.set_max_connections(None);
+    let _fourth = control.admit().unwrap();
+
+    control.pause();
+    assert!(control.is_paused());
+    assert_eq!(code(&control.admit().unwrap_err()), ER_SERVER_SHUTDOWN);
+    control.resume();
+    assert!(control.admit().is_ok());
+}
+
+#[test]
+fn reuseport_sockets_share_an_address() {
+    let first = bind_reuseport(&"127.0.0.1:0".parse().unwrap(), 16).unwrap();
+    let addr = first.local_addr().unwrap();
+    let second = bind_reuseport(&addr, 16).unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr);
+
+    // but not with a socket that didn't ask to
+    let plain = TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(bind_reuseport(&plain.local_addr().unwrap(), 16).is_err());
+}
+
+#[test]
+fn workers_serve_the_connections_they_accept() {
+    let addr: SocketAddr = {
+        let free = TcpListener::bind("127.0.0.1:0").unwrap();
+        free.local_addr().unwrap()
+    };
+    let client = thread::spawn(move || {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                return stream.local_addr().unwrap();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the listener never came up");
+    });
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counted = accepted.clone();
+    listener::run(&[addr], 1, 16, move |connections, _| {
+        let counted = counted.clone();
+        connections.take(1).for_each(move |(_, peer)| {
+            assert!(peer.ip().is_loopback());
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+    }).unwrap();
+    client.join().unwrap();
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    // every worker is served, on its own thread
+    let workers = Arc::new(AtomicUsize::new(0));
+    let started = workers.clone();
+    listener::run(&["127.0.0.1:0".parse().unwrap()], 3, 16, move |_, _| {
+        assert!(thread::current().name().unwrap().starts_with("mysql-proxy-worker-"));
+        started.fetch_add(1, Ordering::SeqCst);
+        future::ok(())
+    }).unwrap();
+    assert_eq!(workers.load(Ordering::SeqCst), 3);
+
+    let e = listener::run(&[], 1, 16, |_, _| future::ok(())).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
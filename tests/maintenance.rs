@@ -0,0 +1,45 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::maintenance::{MaintenanceHandler, MaintenanceMode, MaintenancePolicy, ER_SERVER_SHUTDOWN};
+use mysql_proxy::testing::Forward;
+
+#[test]
+fn queries_are_rejected_while_maintenance_is_on() {
+    let mode = MaintenanceMode::new();
+    let mut handler = MaintenanceHandler::new(mode.clone(), Forward);
+    assert_eq!(handler.handle_request(&Packet::com_query("SELECT 1")), Action::Forward);
+
+    mode.enable(MaintenancePolicy::reject_queries("Down for an upgrade"));
+    assert!(mode.is_enabled());
+    assert_eq!(handler.handle_request(&Packet::com_query("SELECT 1")), Action::Error {
+        code: ER_SERVER_SHUTDOWN,
+        state: *b"08S01",
+        msg: "Down for an upgrade".to_string(),
+    });
+    // clients can still leave
+    assert_eq!(handler.handle_request(&Packet::new(0, &[0x01])), Action::Forward);
+
+    mode.disable();
+    assert_eq!(mode.policy(), None);
+    assert_eq!(handler.handle_request(&Packet::com_query("SELECT 1")), Action::Forward);
+}
+
+#[test]
+fn connections_are_refused_at_the_handshake() {
+    let mode = MaintenanceMode::new();
+    mode.enable(MaintenancePolicy::refuse_connections("Down for an upgrade"));
+    let mut handler = MaintenanceHandler::new(mode.clone(), Forward);
+    let response = Packet::new(1, &[0x8d, 0xa2, 0x0a, 0x00]);
+    let refusal = Packet::error_packet(ER_SERVER_SHUTDOWN, *b"08S01", "Down for an upgrade".to_string()).with_sequence_id(2);
+    assert_eq!(handler.handle_request(&response), Action::Respond(vec![refusal]));
+
+    // connections already past it carry on
+    let mut handler = MaintenanceHandler::new(mode.clone(), Forward);
+    mode.disable();
+    assert_eq!(handler.handle_request(&response), Action::Forward);
+    assert_eq!(handler.handle_response(&ok_packet(2)), Action::Forward);
+    mode.enable(MaintenancePolicy::refuse_connections("Down for an upgrade"));
+    assert_eq!(handler.handle_request(&Packet::com_query("SELECT 1")), Action::Forward);
+}
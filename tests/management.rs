@@ -0,0 +1,58 @@
+extern crate mysql_proxy;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql_proxy::balance::{BackendPool, BackendWeights};
+use mysql_proxy::management::{self, Management, ManagementConfig};
+
+fn serve() -> SocketAddr {
+    let config = ManagementConfig { listen: "127.0.0.1:0".parse().unwrap(), token: None, admin: None };
+    management::run_in_thread(&config, Management::new(BackendPool::default(), BackendWeights::new())).unwrap()
+}
+
+fn request(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn requests_with_too_many_headers_are_refused() {
+    let addr = serve();
+    let headers: String = (0..33).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+    let response = request(addr, &format!("GET /backends HTTP/1.1\r\n{}\r\n", headers));
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    assert!(response.contains("More than 32 headers"), "{}", response);
+
+    let headers: String = (0..32).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+    let response = request(addr, &format!("GET /backends HTTP/1.1\r\n{}\r\n", headers));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+}
+
+#[test]
+fn requests_sent_slowly_are_cut_off() {
+    let addr = serve();
+    let started = Instant::now();
+    let mut slow = TcpStream::connect(addr).unwrap();
+    slow.write_all(b"GET /backends HTTP/1.1\r\n").unwrap();
+    // a byte a second is within any one read's timeout, but not the whole request's
+    let cut_off = loop {
+        thread::sleep(Duration::from_secs(1));
+        if slow.write_all(b"X").is_err() || started.elapsed() > Duration::from_secs(10) {
+            break started.elapsed();
+        }
+        slow.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        if let Ok(0) = slow.read(&mut [0; 1]) {
+            break started.elapsed();
+        }
+    };
+    assert!(cut_off < Duration::from_secs(8), "{:?}", cut_off);
+
+    let response = request(addr, "GET /backends HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+}
@@ -0,0 +1,152 @@
+extern crate mysql_proxy;
+
+use std::thread;
+use std::time::Duration;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::metacache::{CacheKey, MetadataCache, MetadataCacheConfig, SessionMetadataCache};
+use mysql_proxy::pipeline::Correlator;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+
+/// A session's connection to a backend, sending its commands through the cache
+struct Connection {
+    correlator: Correlator,
+    cache: SessionMetadataCache,
+}
+
+impl Connection {
+
+    fn new(cache: &MetadataCache, backend: &str) -> Self {
+        let key = CacheKey {
+            backend: backend.parse().unwrap(),
+            user: "app".to_string(),
+            character_set: 33,
+            backend_capabilities: CLIENT_PROTOCOL_41,
+            database: Some("shop".to_string()),
+            settings: vec![],
+            query: vec![],
+        };
+        let mut correlator = Correlator::default();
+        correlator.set_capabilities(CLIENT_PROTOCOL_41);
+        Connection { correlator, cache: SessionMetadataCache::new(cache.clone(), key) }
+    }
+
+    /// Send a query, returning the kept result if there is one, or the backend's response
+    fn query(&mut self, sql: &str, response: &[Packet]) -> (bool, Vec<Packet>) {
+        let p = Packet::new(0, &[&[0x03], sql.as_bytes()].concat());
+        let cached = self.cache.request(&p, self.correlator.depth(), self.correlator.issued());
+        self.correlator.request(&p);
+        let (hit, packets) = match cached {
+            Some(packets) => (true, packets),
+            None => (false, response.iter().map(|p| Packet { bytes: p.bytes.clone() }).collect()),
+        };
+        for p in &packets {
+            let command = self.correlator.completed() + 1;
+            let answered = self.correlator.response(p);
+            self.cache.response(p, answered, command);
+        }
+        (hit, packets)
+    }
+}
+
+fn error(msg: &str) -> Packet {
+    let mut payload = b"\xff\x19\x04#42000".to_vec();
+    payload.extend_from_slice(msg.as_bytes());
+    Packet::new(1, &payload)
+}
+
+/// A result set of one column and one row
+fn result(value: &str) -> Vec<Packet> {
+    let mut column = vec![3];
+    column.extend_from_slice(b"def");
+    column.extend_from_slice(&[0, 0, 0, 1, b'v', 1, b'v', 0x0c, 33, 0, 0xff, 0, 0, 0, 0xfd, 0, 0, 0, 0]);
+    let mut row = vec![value.len() as u8];
+    row.extend_from_slice(value.as_bytes());
+    vec![
+        Packet::new(1, &[1]),
+        Packet::new(2, &column),
+        Packet::new(3, &[0xfe, 0, 0, 2, 0]),
+        Packet::new(4, &row),
+        Packet::new(5, &[0xfe, 0, 0, 2, 0]),
+    ]
+}
+
+#[test]
+fn sessions_in_the_same_state_share_results() {
+    let cache = MetadataCache::new(&MetadataCacheConfig::default());
+    let mut first = Connection::new(&cache, "db1:3306");
+    assert_eq!(first.query("SELECT @@version_comment LIMIT 1", &result("MySQL Community Server")),
+               (false, result("MySQL Community Server")));
+    // ignored: other queries, and errors
+    first.query("SELECT * FROM products", &result("toys"));
+    first.query("SHOW VARIABLES LIKE 'nope", &[error("You have an error in your SQL syntax")]);
+    assert_eq!(cache.len(), 1);
+
+    let mut second = Connection::new(&cache, "db1:3306");
+    assert_eq!(second.query("SELECT @@version_comment LIMIT 1", &[]), (true, result("MySQL Community Server")));
+    assert!(!second.query("select @@version_comment limit 1", &result("MySQL Community Server")).0);
+
+    // each backend has results of its own
+    let mut other = Connection::new(&cache, "db2:3306");
+    assert!(!other.query("SELECT @@version_comment LIMIT 1", &result("Percona Server")).0);
+}
+
+#[test]
+fn sessions_that_changed_their_state_get_results_of_their_own() {
+    let cache = MetadataCache::new(&MetadataCacheConfig::default());
+    let mut first = Connection::new(&cache, "db:3306");
+    first.query("SELECT DATABASE()", &result("shop"));
+    first.query("SHOW VARIABLES LIKE 'sql_mode'", &result("STRICT_TRANS_TABLES"));
+
+    let mut second = Connection::new(&cache, "db:3306");
+    // a failed SET changes nothing
+    second.query("SET sql_mode = 'NOPE'", &[error("Variable 'sql_mode' can't be set to the value of 'NOPE'")]);
+    assert!(second.query("SHOW VARIABLES LIKE 'sql_mode'", &[]).0);
+    second.query("USE billing", &[ok_packet(1)]);
+    assert_eq!(second.query("SELECT DATABASE()", &result("billing")), (false, result("billing")));
+    second.query("SET sql_mode = 'ANSI'", &[ok_packet(1)]);
+    assert_eq!(second.query("SHOW VARIABLES LIKE 'sql_mode'", &result("ANSI")), (false, result("ANSI")));
+
+    // and a third session making the same changes shares the second's result
+    let mut third = Connection::new(&cache, "db:3306");
+    third.query("USE billing", &[ok_packet(1)]);
+    third.query("SET sql_mode = 'ANSI'", &[ok_packet(1)]);
+    assert_eq!(third.query("SHOW VARIABLES LIKE 'sql_mode'", &[]), (true, result("ANSI")));
+    third.query("CALL reconfigure()", &[ok_packet(1)]);
+    assert!(!third.query("SHOW VARIABLES LIKE 'sql_mode'", &result("ANSI")).0);
+}
+
+#[test]
+fn results_expire() {
+    let cache = MetadataCache::new(&MetadataCacheConfig { ttl_ms: 50, ..MetadataCacheConfig::default() });
+    Connection::new(&cache, "db:3306").query("SELECT VERSION()", &result("8.0.36"));
+    assert!(Connection::new(&cache, "db:3306").query("SELECT VERSION()", &[]).0);
+    thread::sleep(Duration::from_millis(60));
+    assert!(!Connection::new(&cache, "db:3306").query("SELECT VERSION()", &result("8.0.37")).0);
+}
+
+#[test]
+fn identical_results_are_kept_once() {
+    let cache = MetadataCache::new(&MetadataCacheConfig { ttl_ms: 50, ..MetadataCacheConfig::default() });
+    Connection::new(&cache, "db:3306").query("SHOW VARIABLES LIKE 'max_connections'", &result("151"));
+    thread::sleep(Duration::from_millis(60));
+    assert!(!Connection::new(&cache, "db:3306").query("SHOW VARIABLES LIKE 'max_connections'", &result("151")).0);
+    assert_eq!(cache.repeats(), 1);
+    assert_eq!(Connection::new(&cache, "db:3306").query("SHOW VARIABLES LIKE 'max_connections'", &[]),
+               (true, result("151")));
+
+    thread::sleep(Duration::from_millis(60));
+    Connection::new(&cache, "db:3306").query("SHOW VARIABLES LIKE 'max_connections'", &result("500"));
+    assert_eq!(cache.repeats(), 1);
+    assert_eq!(Connection::new(&cache, "db:3306").query("SHOW VARIABLES LIKE 'max_connections'", &[]),
+               (true, result("500")));
+}
+
+#[test]
+fn queries_match_by_their_beginning() {
+    let config = MetadataCacheConfig { queries: vec!["show  variables like".to_string()], ..MetadataCacheConfig::default() };
+    assert!(config.is_cached("/* ORM */ SHOW VARIABLES\n LIKE 'lower_case_table_names';"));
+    assert!(!config.is_cached("SHOW VARIABLES LIKE 'a'; DROP TABLE t"));
+    assert!(!config.is_cached("SELECT @@version_comment"));
+}
@@ -0,0 +1,173 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::codec::*;
+use mysql_proxy::legacy::LegacyEofHandler;
+use mysql_proxy::pipeline::*;
+use mysql_proxy::protocol::*;
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn eof(seq: u8, status_flags: u16) -> Packet {
+    Packet::new(seq, &[0xfe, 0x00, 0x00, status_flags as u8, (status_flags >> 8) as u8])
+}
+
+#[test]
+fn pipelined_commands_are_answered_in_order() {
+    let mut correlator = Correlator::new(CLIENT_PROTOCOL_41);
+    correlator.request(&Packet::new(0, b"\x03SELECT 1"));
+    correlator.request(&Packet::new(0, &[0x19, 1, 0, 0, 0])); // COM_STMT_CLOSE has no response
+    correlator.request(&Packet::new(0, &[0x0e]));
+    assert_eq!(correlator.depth(), 2);
+
+    let select = [
+        (Packet::new(1, &[0x01]), ResponseKind::Data),
+        (Packet::new(2, b"\x03def\x00\x00\x00\x01a\x00\x0c\x21\x00\x01\x00\x00\x00\xfd\x00\x00\x00\x00\x00"), ResponseKind::Data),
+        (eof(3, 0x0002), ResponseKind::Eof),
+        (Packet::new(4, b"\x011"), ResponseKind::Data),
+    ];
+    for (p, kind) in select.iter() {
+        assert_eq!(correlator.response(p), Some(ResponsePacket { command: 0x03, kind: *kind, last: false }));
+    }
+    assert_eq!(correlator.response(&eof(5, 0x0002)),
+               Some(ResponsePacket { command: 0x03, kind: ResponseKind::Eof, last: true }));
+    assert_eq!(correlator.response(&ok_packet(1)),
+               Some(ResponsePacket { command: 0x0e, kind: ResponseKind::Ok, last: true }));
+    assert_eq!(correlator.depth(), 0);
+    assert_eq!((correlator.issued(), correlator.completed()), (2, 2));
+    assert_eq!(correlator.response(&ok_packet(1)), None);
+}
+
+#[test]
+fn bulk_execute_results_continue_until_the_last() {
+    let mut correlator = Correlator::new(CLIENT_PROTOCOL_41);
+    correlator.request(&Packet::new(0, &[0xfa, 1, 0, 0, 0, 0x80, 0x00]));
+    // OK with SERVER_MORE_RESULTS_EXISTS, then a final OK
+    let more = Packet::new(1, &[0x00, 0x01, 0x00, 0x0a, 0x00, 0x00, 0x00]);
+    assert_eq!(correlator.response(&more).map(|r| r.last), Some(false));
+    assert_eq!(correlator.response(&ok_packet(2)).map(|r| r.last), Some(true));
+}
+
+#[test]
+fn set_option_and_debug_are_answered_by_one_status_packet() {
+    // COM_SET_OPTION, COM_DEBUG, then a ping to show nothing is left over
+    let commands = [Packet::new(0, &[0x1b, 0x00, 0x00]), Packet::new(0, &[0x0d]), Packet::new(0, &[0x0e])];
+
+    let mut correlator = Correlator::new(CLIENT_PROTOCOL_41);
+    for p in commands.iter() {
+        correlator.request(p);
+    }
+    assert_eq!(correlator.response(&eof(1, 0x0002)),
+               Some(ResponsePacket { command: 0x1b, kind: ResponseKind::Eof, last: true }));
+    assert_eq!(correlator.response(&Packet::new(1, b"\xff\xcb\x04#42000Access denied")),
+               Some(ResponsePacket { command: 0x0d, kind: ResponseKind::Err, last: true }));
+    assert_eq!(correlator.response(&ok_packet(1)).map(|r| r.command), Some(0x0e));
+
+    // the EOF is an OK with an EOF header, here with session state changes
+    let mut correlator = Correlator::new(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF | CLIENT_SESSION_TRACK);
+    for p in commands.iter() {
+        correlator.request(p);
+    }
+    let ok_as_eof = Packet::new(1, &[0xfe, 0x00, 0x00, 0x02, 0x40, 0x00, 0x00, 0x00, 0x05, 0x00, 0x03, 0x02, 0x01, 0x31]);
+    assert_eq!(correlator.response(&ok_as_eof),
+               Some(ResponsePacket { command: 0x1b, kind: ResponseKind::Ok, last: true }));
+    assert_eq!(correlator.response(&ok_packet(1)),
+               Some(ResponsePacket { command: 0x0d, kind: ResponseKind::Ok, last: true }));
+    assert_eq!(correlator.response(&ok_packet(1)).map(|r| r.command), Some(0x0e));
+    assert_eq!(correlator.depth(), 0);
+}
+
+#[test]
+fn legacy_clients_get_an_eof_for_set_option() {
+    let mut tester = HandlerTester::new(LegacyEofHandler::new(Forward));
+    tester.request(Packet::new(0, &[0x1b, 0x01, 0x00]));
+    assert_eq!(tester.response(Packet::new(1, &[0xfe, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00])),
+               Action::Mutate(eof(1, 0x0002)));
+    assert_eq!(tester.in_flight(), 0);
+}
+
+/// COM_STMT_EXECUTE of statement 1 asking for a read-only cursor
+fn execute_with_cursor() -> Packet {
+    Packet::new(0, &[0x17, 1, 0, 0, 0, 0x01, 1, 0, 0, 0])
+}
+
+/// COM_STMT_FETCH of 2 rows of statement 1
+fn fetch() -> Packet {
+    Packet::new(0, &[0x1c, 1, 0, 0, 0, 2, 0, 0, 0])
+}
+
+fn column(seq: u8) -> Packet {
+    Packet::new(seq, b"\x03def\x00\x00\x00\x01a\x00\x0c\x21\x00\x01\x00\x00\x00\xfd\x00\x00\x00\x00\x00")
+}
+
+/// An OK with an EOF header, as sent in place of an EOF under CLIENT_DEPRECATE_EOF
+fn ok_as_eof(seq: u8, status_flags: u16) -> Packet {
+    Packet::new(seq, &[0xfe, 0x00, 0x00, status_flags as u8, (status_flags >> 8) as u8, 0x00, 0x00])
+}
+
+#[test]
+fn cursor_results_end_with_the_columns_and_rows_are_fetched() {
+    for &deprecate_eof in [false, true].iter() {
+        let (flags, end): (u32, fn(u8, u16) -> Packet) = if deprecate_eof {
+            (CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF, ok_as_eof)
+        } else {
+            (CLIENT_PROTOCOL_41, eof)
+        };
+        let end_kind = if deprecate_eof { ResponseKind::Ok } else { ResponseKind::Eof };
+        let mut correlator = Correlator::new(flags);
+        correlator.request(&execute_with_cursor());
+        assert_eq!(correlator.response(&Packet::new(1, &[0x01])).map(|r| r.last), Some(false));
+        assert_eq!(correlator.response(&column(2)).map(|r| r.last), Some(false));
+        // SERVER_STATUS_AUTOCOMMIT | SERVER_STATUS_CURSOR_EXISTS
+        assert_eq!(correlator.response(&end(3, 0x0042)),
+                   Some(ResponsePacket { command: 0x17, kind: end_kind, last: true }));
+        assert_eq!(correlator.depth(), 0);
+
+        correlator.request(&fetch());
+        assert!(correlator.expects_row());
+        assert_eq!(correlator.response(&Packet::new(1, &[0x00, 0x00, 0x01])),
+                   Some(ResponsePacket { command: 0x1c, kind: ResponseKind::Data, last: false }));
+        assert_eq!(correlator.response(&Packet::new(2, &[0x00, 0x00, 0x02])).map(|r| r.kind), Some(ResponseKind::Data));
+        // ... | SERVER_STATUS_LAST_ROW_SENT
+        assert_eq!(correlator.response(&end(3, 0x00c2)),
+                   Some(ResponsePacket { command: 0x1c, kind: end_kind, last: true }));
+        assert_eq!((correlator.issued(), correlator.completed()), (2, 2));
+    }
+}
+
+#[test]
+fn rows_follow_the_columns_when_the_server_opens_no_cursor() {
+    let mut correlator = Correlator::new(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+    correlator.request(&execute_with_cursor());
+    correlator.response(&Packet::new(1, &[0x01]));
+    correlator.response(&column(2));
+    assert!(correlator.expects_row());
+    assert_eq!(correlator.response(&Packet::new(3, &[0x00, 0x00, 0x01])),
+               Some(ResponsePacket { command: 0x17, kind: ResponseKind::Data, last: false }));
+    assert_eq!(correlator.response(&ok_as_eof(4, 0x0002)),
+               Some(ResponsePacket { command: 0x17, kind: ResponseKind::Ok, last: true }));
+}
+
+#[test]
+fn legacy_clients_get_the_eof_ending_cursor_columns() {
+    let mut tester = HandlerTester::new(LegacyEofHandler::new(Forward));
+    tester.request(execute_with_cursor());
+    assert_eq!(tester.response(Packet::new(1, &[0x01])), Action::Forward);
+    assert_eq!(tester.response(column(2)), Action::Forward);
+    assert_eq!(tester.response(ok_as_eof(3, 0x0042)), Action::Mutate(eof(3, 0x0042)));
+    assert_eq!(tester.in_flight(), 0);
+
+    tester.request(fetch());
+    assert_eq!(tester.response(Packet::new(1, &[0x00, 0x00, 0x01])), Action::Forward);
+    assert_eq!(tester.response(ok_as_eof(2, 0x00c2)), Action::Mutate(eof(2, 0x00c2)));
+    assert_eq!(tester.in_flight(), 0);
+
+    // without a cursor, the EOF the backend left out goes before the first row
+    tester.request(execute_with_cursor());
+    tester.response(Packet::new(1, &[0x01]));
+    tester.response(column(2));
+    let mut bytes = eof(3, 0x0002).bytes;
+    bytes.extend_from_slice(&Packet::new(4, &[0x00, 0x00, 0x01]).bytes);
+    assert_eq!(tester.response(Packet::new(3, &[0x00, 0x00, 0x01])), Action::Mutate(Packet { bytes }));
+    assert_eq!(tester.response(ok_as_eof(4, 0x0002)), Action::Mutate(eof(5, 0x0002)));
+    assert_eq!(tester.in_flight(), 0);
+}
@@ -0,0 +1,53 @@
+extern crate mysql_proxy;
+
+use std::thread;
+
+use mysql_proxy::pool::{BufferPool, PoolConfig, PoolStats};
+
+fn pool(max_buffers: usize) -> BufferPool {
+    BufferPool::new(PoolConfig { buffer_size: 64, max_buffers, max_buffer_size: 1024 })
+}
+
+#[test]
+fn released_buffers_are_reused() {
+    let pool = pool(2);
+    let buf = pool.acquire(10);
+    assert!(buf.capacity() >= 64);
+    assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 1, discarded: 0, pooled: 0 });
+
+    let mut buf = buf;
+    buf.extend_from_slice(b"stale");
+    let addr = buf.as_ptr();
+    pool.release(buf);
+    assert_eq!(pool.stats().pooled, 1);
+
+    // the same allocation comes back, emptied
+    let buf = pool.acquire(32);
+    assert_eq!(buf.as_ptr(), addr);
+    assert!(buf.is_empty());
+    assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 1, discarded: 0, pooled: 0 });
+
+    // and grows if asked for more than it has
+    pool.release(buf);
+    assert!(pool.acquire(512).capacity() >= 512);
+}
+
+#[test]
+fn pools_only_keep_so_many_buffers_of_a_reasonable_size() {
+    let pool = pool(1);
+    pool.release(Vec::with_capacity(64));
+    pool.release(Vec::with_capacity(64));
+    pool.release(Vec::with_capacity(4096));
+    pool.release(vec![]);
+    assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 0, discarded: 3, pooled: 1 });
+}
+
+#[test]
+fn clones_share_their_buffers_across_threads() {
+    let pool = pool(8);
+    let clone = pool.clone();
+    thread::spawn(move || clone.release(clone.acquire(10))).join().unwrap();
+    assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 1, discarded: 0, pooled: 1 });
+    assert_eq!(pool.buffer_size(), 64);
+    assert_eq!(BufferPool::default().buffer_size(), 4096);
+}
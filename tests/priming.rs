@@ -0,0 +1,183 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::codec::{ok_packet, ok_packet_with_status};
+use mysql_proxy::priming::{Priming, SessionPriming, SessionPrimingConfig};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+struct Session {
+    client: Memory,
+    server: Memory,
+    pipe: Spawn<Pipe<Forward>>,
+}
+
+impl Session {
+
+    fn new(priming: &Priming) -> Self {
+        let (client, server) = (Memory::default(), Memory::default());
+        let pipe = Pipe::from_streams(client.clone(), server.clone(), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41)
+            .with_session_priming(SessionPriming::new(priming.clone(), "app"));
+        Session { client, server, pipe: executor::spawn(pipe) }
+    }
+
+    fn poll(&mut self) {
+        assert_eq!(self.pipe.poll_future_notify(&Arc::new(Ignore), 0).unwrap(), Async::NotReady);
+    }
+}
+
+fn query(sql: &str) -> Packet {
+    Packet::new(0, &[&[0x03], sql.as_bytes()].concat())
+}
+
+fn bytes(packets: &[Packet]) -> Vec<u8> {
+    packets.iter().flat_map(|p| p.bytes.clone()).collect()
+}
+
+const SETUP: &[&str] = &["SET NAMES utf8mb4", "SET autocommit = 1"];
+
+/// A `Priming` that has seen `sessions` sessions start with the setup statements
+fn learnt(sessions: u32) -> Priming {
+    let priming = Priming::new(SessionPrimingConfig { sessions: 2, ..SessionPrimingConfig::default() });
+    for _ in 0..sessions {
+        priming.learn("app", SETUP.iter().map(|s| s.to_string()).collect());
+    }
+    priming
+}
+
+#[test]
+fn sessions_learn_their_setup_statements() {
+    let priming = learnt(0);
+    for _ in 0..2 {
+        let mut session = Session::new(&priming);
+        session.poll();
+        assert!(session.server.written().is_empty());
+        for statement in SETUP {
+            session.client.send(&query(statement));
+            session.poll();
+            assert_eq!(session.server.written(), query(statement).bytes);
+            session.server.send(&ok_packet(1));
+            session.poll();
+        }
+        session.client.send(&query("SELECT 1"));
+        session.poll();
+    }
+    assert_eq!(priming.statements("app"), SETUP.to_vec());
+    assert!(priming.statements("other").is_empty());
+}
+
+#[test]
+fn primed_statements_are_answered_without_a_round_trip() {
+    let mut session = Session::new(&learnt(2));
+    session.poll();
+    assert_eq!(session.server.written(), bytes(&[query(SETUP[0]), query(SETUP[1])]));
+    // the backend answers before the client gets to send them
+    session.server.send(&ok_packet(1));
+    session.server.send(&ok_packet_with_status(1, 0));
+    session.poll();
+    assert!(session.client.written().is_empty());
+
+    session.client.send(&query(SETUP[0]));
+    session.client.send(&query(SETUP[1]));
+    session.poll();
+    assert_eq!(session.client.written(), bytes(&[ok_packet(1), ok_packet_with_status(1, 0)]));
+    assert!(session.server.written().is_empty());
+
+    session.client.send(&query("SELECT 1"));
+    session.poll();
+    assert_eq!(session.server.written(), query("SELECT 1").bytes);
+}
+
+#[test]
+fn a_session_that_starts_differently_is_reset() {
+    let priming = learnt(2);
+    let mut session = Session::new(&priming);
+    session.poll();
+    session.server.written();
+    session.client.send(&query(SETUP[0]));
+    session.client.send(&query("SET time_zone = '+00:00'"));
+    session.poll();
+    // the backend forgets the statement the client didn't send
+    assert_eq!(session.server.written(), bytes(&[Packet::new(0, b"\x1f"), query(SETUP[0]), query("SET time_zone = '+00:00'")]));
+    for _ in 0..5 {
+        session.server.send(&ok_packet(1));
+    }
+    session.poll();
+    assert_eq!(session.client.written(), bytes(&[ok_packet(1), ok_packet(1)]));
+
+    session.client.send(&query("SELECT 1"));
+    session.poll();
+    // and the user's setup is learnt again
+    assert!(priming.statements("app").is_empty());
+}
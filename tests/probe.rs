@@ -0,0 +1,81 @@
+extern crate mysql_proxy;
+
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::{text_result_set, ColumnDefinition, HandshakeV10};
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::probe;
+use mysql_proxy::protocol::{CLIENT_PLUGIN_AUTH, CLIENT_PROTOCOL_41, CLIENT_SECURE_CONNECTION, NATIVE_PASSWORD_PLUGIN};
+use mysql_proxy::sidechannel::read_packet;
+
+/// A backend accepting any login and answering every query with `answer`
+fn backend(answer: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            thread::spawn(move || serve(stream.unwrap(), answer));
+        }
+    });
+    addr
+}
+
+fn serve(mut stream: TcpStream, answer: &[u8]) {
+    let greeting = HandshakeV10 {
+        server_version: "8.0.36".to_string(),
+        connection_id: 7,
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    stream.write_all(&greeting.to_packet(0).bytes).unwrap();
+    read_packet(&mut stream).unwrap();
+    stream.write_all(&Packet::new(2, &[0x00, 0, 0, 2, 0, 0, 0]).bytes).unwrap();
+    let columns = vec![ColumnDefinition {
+        catalog: "def".to_string(),
+        schema: String::new(),
+        table: String::new(),
+        org_table: String::new(),
+        name: "1".to_string(),
+        org_name: String::new(),
+        character_set: 0x3f,
+        column_length: 1,
+        column_type: 0x08,
+        flags: 0x81,
+        decimals: 0,
+    }];
+    while read_packet(&mut stream).is_ok() {
+        for p in text_result_set(&columns, &[vec![Some(answer.to_vec())]], CLIENT_PROTOCOL_41) {
+            stream.write_all(&p.bytes).unwrap();
+        }
+    }
+}
+
+fn backend_addr(addr: SocketAddr) -> BackendAddr {
+    BackendAddr::new(&addr.ip().to_string(), addr.port())
+}
+
+#[test]
+fn every_backend_is_probed_end_to_end() {
+    let good = backend(b"1");
+    let wrong = backend(b"2");
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let backends = vec![backend_addr(good), backend_addr(wrong), backend_addr(closed), BackendAddr::new("nowhere.invalid", 3306)];
+    let results = probe::probe_all(&backends, "deploy", "secret", Duration::from_millis(1000));
+
+    assert_eq!(results.iter().map(|r| r.backend.clone()).collect::<Vec<_>>(), backends);
+    assert!(results[0].is_ok(), "{:?}", results[0]);
+    assert_eq!(results[0].addr, Some(good));
+    let e = results[1].result.as_ref().unwrap_err();
+    assert!(e.to_string().contains("Unexpected answer to SELECT 1"), "{}", e);
+    assert_eq!(results[2].addr, Some(closed));
+    assert!(!results[2].is_ok());
+    assert_eq!(results[3].addr, None);
+    assert!(!results[3].is_ok());
+}
@@ -0,0 +1,63 @@
+extern crate mysql_proxy;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::profile::{self, Stage, StageHook, StageTotals};
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<(&'static str, Stage)>>);
+
+impl StageHook for Recorder {
+
+    fn enter(&self, stage: Stage) {
+        self.0.lock().unwrap().push(("enter", stage));
+    }
+
+    fn exit(&self, stage: Stage, _: Duration) {
+        self.0.lock().unwrap().push(("exit", stage));
+    }
+}
+
+#[test]
+fn hooks_see_a_sample_of_stages() {
+    let recorder = Arc::new(Recorder::default());
+    profile::set_hook(recorder.clone(), 2);
+    for &stage in Stage::ALL.iter() {
+        let _stage = profile::enter(stage);
+    }
+    profile::clear_hook();
+    drop(profile::enter(Stage::Reader));
+
+    let seen = recorder.0.lock().unwrap().clone();
+    if cfg!(feature = "profiling") {
+        assert_eq!(seen, vec![("enter", Stage::Codec), ("exit", Stage::Codec), ("enter", Stage::Writer), ("exit", Stage::Writer)]);
+    } else {
+        assert!(seen.is_empty());
+    }
+}
+
+#[test]
+fn stage_totals_count_samples_by_stage() {
+    let totals = StageTotals::default();
+    totals.exit(Stage::Handler, Duration::from_micros(40));
+    totals.exit(Stage::Handler, Duration::from_micros(2));
+    let snapshot = totals.snapshot();
+    assert_eq!(snapshot.iter().map(|t| t.stage).collect::<Vec<_>>(), ["reader", "codec", "handler", "writer"]);
+    assert_eq!((snapshot[2].samples, snapshot[2].total_us), (2, 42));
+    assert_eq!(snapshot[0].samples, 0);
+}
+
+#[test]
+fn profiling_is_validated() {
+    let config = ProxyConfig::parse(r#"
+        [profiling]
+        sample_every = 0
+    "#).unwrap();
+    let mut expected = vec!["Profiling: sample_every must be at least 1".to_string()];
+    if !cfg!(feature = "profiling") {
+        expected.push("Profiling: requires the 'profiling' feature".to_string());
+    }
+    assert_eq!(config.validate(), expected);
+}
@@ -0,0 +1,307 @@
+//! Round trips through every parser and serializer of the codec: the packets in `corpus/`,
+//! in the shapes MySQL 5.7 and 8.0 and MariaDB send them, and generated ones.
+
+extern crate bytes;
+extern crate mysql_proxy;
+extern crate proptest;
+extern crate tokio_io;
+
+use bytes::BytesMut;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use tokio_io::codec::{Decoder, Encoder};
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::*;
+use mysql_proxy::compress::{CompressedSequence, CompressionConfig, Compressor, Decompressor};
+use mysql_proxy::framed::MySqlPacketCodec;
+use mysql_proxy::protocol::*;
+
+/// The packets of a corpus file, one per line in hex, header included
+fn corpus(file: &str) -> Vec<Packet> {
+    let mut decoder = PacketDecoder::new();
+    for line in file.lines().filter(|line| !line.starts_with('#')) {
+        let bytes: Vec<u8> = (0..line.len() / 2).map(|i| u8::from_str_radix(&line[i * 2..i * 2 + 2], 16).unwrap()).collect();
+        decoder.extend(&bytes);
+    }
+    let packets: Vec<Packet> = std::iter::from_fn(|| decoder.next_packet()).collect();
+    assert_eq!(decoder.buffered(), 0);
+    packets
+}
+
+fn one(file: &str) -> Packet {
+    let mut packets = corpus(file);
+    assert_eq!(packets.len(), 1);
+    packets.remove(0)
+}
+
+#[test]
+fn greetings() {
+    let mysql80 = one(include_str!("corpus/mysql-8.0-greeting.hex"));
+    let greeting = HandshakeV10::parse(&mysql80).unwrap();
+    assert_eq!(greeting.server_version, "8.0.36");
+    assert_eq!(greeting.auth_plugin_data.len(), 20);
+    assert_eq!(greeting.auth_plugin_name, Some("caching_sha2_password".to_string()));
+    assert_ne!(greeting.capability_flags & CLIENT_DEPRECATE_EOF, 0);
+    assert_eq!(greeting.to_packet(0), mysql80);
+
+    let mysql57 = one(include_str!("corpus/mysql-5.7-greeting.hex"));
+    let greeting = HandshakeV10::parse(&mysql57).unwrap();
+    assert_eq!((&greeting.server_version[..], greeting.character_set), ("5.7.44-log", 0x08));
+    assert_eq!(greeting.auth_plugin_name, Some("mysql_native_password".to_string()));
+    assert_eq!(greeting.to_packet(0), mysql57);
+
+    // the extended capabilities in the reserved bytes aren't kept
+    let mariadb = one(include_str!("corpus/mariadb-10.11-greeting.hex"));
+    let greeting = HandshakeV10::parse(&mariadb).unwrap();
+    assert_eq!(greeting.server_version, "5.5.5-10.11.6-MariaDB-0+deb12u1");
+    assert_eq!(greeting.capability_flags & CLIENT_LONG_PASSWORD, 0);
+    assert_eq!(greeting.auth_plugin_name, Some("mysql_native_password".to_string()));
+    assert_eq!(HandshakeV10::parse(&greeting.to_packet(0)).unwrap(), greeting);
+}
+
+#[test]
+fn handshake_response_and_auth_switch() {
+    let packet = one(include_str!("corpus/mysql-8.0-handshake-response.hex"));
+    let response = HandshakeResponse::parse(&packet).unwrap();
+    assert_eq!(response.username, "app");
+    assert_eq!(response.database, Some("shop".to_string()));
+    assert_eq!(response.auth_response.len(), 32);
+    let attrs = response.parse_connect_attrs().unwrap();
+    assert_eq!(attrs[0], ("_client_name".to_string(), "libmysql".to_string()));
+    assert_eq!(attrs.len(), 5);
+    assert_eq!(response.to_packet(1), packet);
+
+    let packet = one(include_str!("corpus/auth-switch-request.hex"));
+    let switch = AuthSwitchRequest::parse(&packet).unwrap();
+    assert_eq!(switch.plugin_name, "mysql_native_password");
+    assert_eq!(switch.plugin_data.len(), 20);
+    assert_eq!(switch.to_packet(2), packet);
+}
+
+#[test]
+fn ok_and_err_packets() {
+    let ok = OkPacket::parse(&one(include_str!("corpus/ok-update.hex")), CLIENT_PROTOCOL_41).unwrap();
+    assert_eq!((ok.affected_rows, ok.status_flags), (1, SERVER_STATUS_AUTOCOMMIT));
+
+    let packet = one(include_str!("corpus/ok-session-track.hex"));
+    let ok = OkPacket::parse(&packet, CLIENT_PROTOCOL_41 | CLIENT_SESSION_TRACK).unwrap();
+    assert_eq!(ok.state_changes, vec![StateChange::Schema("shop".to_string())]);
+
+    let packet = one(include_str!("corpus/err-no-such-table.hex"));
+    let err = ErrPacket::parse(&packet).unwrap();
+    assert_eq!((err.code, err.state), (1146, Some(*b"42S02")));
+    assert_eq!(Packet::error_packet(err.code, err.state.unwrap(), err.message), packet);
+}
+
+#[test]
+fn result_sets() {
+    let packets = corpus(include_str!("corpus/result-set.hex"));
+    let mut decoder = QueryResponseDecoder::new(CLIENT_PROTOCOL_41);
+    let mut columns = vec![];
+    let mut rows = vec![];
+    for p in &packets {
+        match decoder.decode(p).unwrap() {
+            QueryResponse::Column(column) => {
+                assert_eq!(&column.to_packet(p.sequence_id()), p);
+                columns.push(column);
+            },
+            QueryResponse::Row(row) => {
+                assert_eq!(&text_row_packet(p.sequence_id(), &row), p);
+                rows.push(row);
+            },
+            _ => {},
+        }
+    }
+    assert!(decoder.is_done());
+    assert_eq!(rows, vec![vec![Some(b"1".to_vec()), Some(b"gift wrap".to_vec())], vec![Some(b"2".to_vec()), None]]);
+    assert_eq!(columns[1].name, "note");
+    assert_eq!(parse_text_row(&packets[5], 2).unwrap(), rows[1]);
+}
+
+/// Strings without NULs, for the fields sent NUL-terminated
+#[test]
+fn scrambles_are_random_printable_ascii() {
+    let scrambles: Vec<Vec<u8>> = (0..100).map(|_| generate_scramble().unwrap()).collect();
+    for scramble in &scrambles {
+        assert_eq!(scramble.len(), 20);
+        assert!(scramble.iter().all(|&b| (0x21..0x7f).contains(&b) && b != b'$'), "{:?}", scramble);
+    }
+    let mut distinct = scrambles.clone();
+    distinct.sort();
+    distinct.dedup();
+    assert_eq!(distinct.len(), scrambles.len());
+}
+
+#[test]
+fn native_passwords_are_checked_against_their_hash() {
+    let hash = native_password_hash("password");
+    assert_eq!(hash, "*2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19");
+    let scramble = generate_scramble().unwrap();
+    assert!(verify_native_password(&hash, &scramble, &native_password_auth("password", &scramble)));
+    assert!(verify_native_password(&hash.to_lowercase(), &scramble, &native_password_auth("password", &scramble)));
+
+    // another password, another scramble's response, or a malformed one
+    assert!(!verify_native_password(&hash, &scramble, &native_password_auth("passwore", &scramble)));
+    let other = generate_scramble().unwrap();
+    assert!(!verify_native_password(&hash, &scramble, &native_password_auth("password", &other)));
+    assert!(!verify_native_password(&hash, &scramble, &native_password_auth("password", &scramble)[..19]));
+    assert!(!verify_native_password(&hash, &scramble, &[]));
+    assert!(!verify_native_password("2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19", &scramble, &native_password_auth("password", &scramble)));
+    assert!(!verify_native_password("*XX70C0C06DEE42FD1618BB99005ADCA2EC9D1E19", &scramble, &native_password_auth("password", &scramble)));
+
+    // clients send an empty response for an empty password
+    assert!(native_password_auth("", &scramble).is_empty());
+}
+
+#[test]
+fn old_passwords_are_checked_against_their_hash() {
+    let hash = format_old_password_hash(old_password_hash(b"password"));
+    assert_eq!(hash, "5d2e19393cc5ef67");
+    let scramble = &generate_scramble().unwrap()[..8];
+    let response = old_password_auth(old_password_hash(b"password"), scramble);
+    assert!(verify_old_password(&hash, scramble, &response));
+    assert!(!verify_old_password(&hash, scramble, &old_password_auth(old_password_hash(b"passwore"), scramble)));
+}
+
+fn name() -> impl Strategy<Value = String> {
+    "[^\u{0}]{0,40}"
+}
+
+fn greeting() -> impl Strategy<Value = HandshakeV10> {
+    (name(), any::<u32>(), any::<u32>(), any::<u8>(), any::<u16>(), vec(1..=255_u8, 20), name()).prop_map(
+        |(server_version, connection_id, capability_flags, character_set, status_flags, auth_plugin_data, plugin)| HandshakeV10 {
+            server_version,
+            connection_id,
+            capability_flags: capability_flags | CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+            character_set,
+            status_flags,
+            auth_plugin_data,
+            auth_plugin_name: Some(plugin),
+        })
+}
+
+fn handshake_response() -> impl Strategy<Value = HandshakeResponse> {
+    (any::<u32>(), any::<u32>(), any::<u8>(), name(), vec(1..=255_u8, 0..64), name(), name(), vec(any::<u8>(), 0..300))
+        .prop_map(|(flags, max_packet_size, character_set, username, auth_response, database, plugin, attrs)| {
+            let capability_flags = flags | CLIENT_PROTOCOL_41;
+            let has = |flag: u32| capability_flags & flag != 0;
+            HandshakeResponse {
+                capability_flags,
+                max_packet_size,
+                character_set,
+                username,
+                auth_response,
+                database: if has(CLIENT_CONNECT_WITH_DB) { Some(database) } else { None },
+                auth_plugin_name: if has(CLIENT_PLUGIN_AUTH) { Some(plugin) } else { None },
+                connect_attrs: if has(CLIENT_CONNECT_ATTRS) { Some(attrs) } else { None },
+            }
+        })
+}
+
+fn column() -> impl Strategy<Value = ColumnDefinition> {
+    ((".{0,20}", ".{0,20}", ".{0,20}", ".{0,20}", ".{0,20}", ".{0,20}"), (any::<u16>(), any::<u32>(), any::<u8>(), any::<u16>(), any::<u8>()))
+        .prop_map(|((catalog, schema, table, org_table, name, org_name), (character_set, column_length, column_type, flags, decimals))| {
+            ColumnDefinition { catalog, schema, table, org_table, name, org_name, character_set, column_length, column_type, flags, decimals }
+        })
+}
+
+proptest! {
+    #[test]
+    fn lenenc_round_trip(n in any::<u64>(), s in vec(any::<u8>(), 0..1000)) {
+        let mut buf = vec![];
+        write_lenenc_int(&mut buf, n);
+        write_lenenc_str(&mut buf, &s);
+        let mut r = PayloadReader::new(&buf);
+        prop_assert_eq!(r.lenenc_int().unwrap(), n);
+        prop_assert_eq!(r.lenenc_bytes().unwrap(), &s[..]);
+        prop_assert!(r.is_empty());
+    }
+
+    #[test]
+    fn packets_survive_any_split(payloads in vec(vec(any::<u8>(), 0..600), 1..5), chunk in 1..64_usize) {
+        let packets: Vec<Packet> = payloads.iter().enumerate().map(|(i, p)| Packet::new(i as u8, p)).collect();
+        let bytes: Vec<u8> = packets.iter().flat_map(|p| p.bytes.clone()).collect();
+        let mut decoder = PacketDecoder::new();
+        let mut decoded = vec![];
+        for piece in bytes.chunks(chunk) {
+            decoder.extend(piece);
+            decoded.extend(std::iter::from_fn(|| decoder.next_packet()));
+        }
+        prop_assert_eq!(&decoded, &packets);
+
+        let mut codec = MySqlPacketCodec::new();
+        let mut buf = BytesMut::new();
+        for p in &packets {
+            codec.encode(Packet { bytes: p.bytes.clone() }, &mut buf).unwrap();
+        }
+        let mut framed = vec![];
+        while let Some(p) = codec.decode(&mut buf).unwrap() {
+            framed.push(p);
+        }
+        prop_assert_eq!(framed, packets);
+    }
+
+    #[test]
+    fn greeting_round_trip(greeting in greeting(), sequence_id in any::<u8>()) {
+        prop_assert_eq!(HandshakeV10::parse(&greeting.to_packet(sequence_id)).unwrap(), greeting);
+    }
+
+    #[test]
+    fn handshake_response_round_trip(response in handshake_response()) {
+        prop_assert_eq!(HandshakeResponse::parse(&response.to_packet(1)).unwrap(), response);
+    }
+
+    #[test]
+    fn connect_attrs_round_trip(attrs in vec((".{0,20}", ".{0,40}"), 1..8)) {
+        let mut response = HandshakeResponse::parse(&one(include_str!("corpus/mysql-8.0-handshake-response.hex"))).unwrap();
+        response.set_connect_attrs(&attrs);
+        let parsed = HandshakeResponse::parse(&response.to_packet(1)).unwrap();
+        prop_assert_eq!(parsed.parse_connect_attrs().unwrap(), attrs);
+    }
+
+    #[test]
+    fn auth_switch_round_trip(plugin_name in name(), plugin_data in vec(any::<u8>(), 0..40)) {
+        let switch = AuthSwitchRequest { plugin_name, plugin_data };
+        prop_assert_eq!(AuthSwitchRequest::parse(&switch.to_packet(2)).unwrap(), switch);
+    }
+
+    #[test]
+    fn column_round_trip(column in column()) {
+        prop_assert_eq!(ColumnDefinition::parse(&column.to_packet(2)).unwrap(), column);
+    }
+
+    #[test]
+    fn text_row_round_trip(row in vec(proptest::option::of(vec(any::<u8>(), 0..300)), 0..8)) {
+        prop_assert_eq!(parse_text_row(&text_row_packet(4, &row), row.len()).unwrap(), row);
+    }
+
+    #[test]
+    fn err_round_trip(code in any::<u16>(), state in "[0-9A-Z]{5}", message in ".{0,100}") {
+        let mut sql_state = [0; 5];
+        sql_state.copy_from_slice(state.as_bytes());
+        let err = ErrPacket::parse(&Packet::error_packet(code, sql_state, message.clone())).unwrap();
+        prop_assert_eq!(err, ErrPacket { code, state: Some(sql_state), message });
+    }
+
+    #[test]
+    fn eof_round_trip(status_flags in any::<u16>()) {
+        prop_assert_eq!(EofPacket::parse(&eof_packet(5, status_flags)).unwrap(), EofPacket { warnings: 0, status_flags });
+    }
+
+    #[test]
+    fn compressed_round_trip(payloads in vec(vec(any::<u8>(), 0..2000), 1..4), level in 1..=9_u32, min_size in 0..100_usize) {
+        let config = CompressionConfig { client: true, backend: false, level, min_size };
+        let compressor = Compressor::new(&config, CompressedSequence::new());
+        let mut decompressor = Decompressor::new(CompressedSequence::new());
+        let mut plain = BytesMut::new();
+        for (i, payload) in payloads.iter().enumerate() {
+            let packet = Packet::new(i as u8, payload);
+            let mut wire = BytesMut::new();
+            compressor.compress(&packet.bytes, &mut wire);
+            decompressor.decompress(&wire, &mut plain).unwrap();
+        }
+        let expected: Vec<u8> = payloads.iter().enumerate().flat_map(|(i, p)| Packet::new(i as u8, p).bytes).collect();
+        prop_assert_eq!(&plain[..], &expected[..]);
+    }
+}
@@ -0,0 +1,47 @@
+extern crate mysql_proxy;
+extern crate serde_json;
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mysql_proxy::querylog::{QueryLog, QueryLogConfig, QuerySample};
+
+#[derive(Clone,Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Lines {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn sample_each_fingerprint() {
+    let config = QueryLogConfig { path: PathBuf::from("unused"), sample_rate: 0.5, min_time_ms: 10, scrub: true };
+    let lines = Lines::default();
+    let log = QueryLog::new(&config, Box::new(lines.clone()));
+    let slow = Duration::from_millis(20);
+    for id in 0..4 {
+        log.offer("app", &format!("SELECT * FROM users WHERE id = {}", id), slow, None).unwrap();
+    }
+    log.offer("app", "DELETE FROM sessions WHERE token = 'secret'", slow, Some(1205)).unwrap();
+    // too fast to be considered at all
+    log.offer("app", "SELECT * FROM users WHERE id = 9", Duration::from_millis(1), None).unwrap();
+
+    let written = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+    let samples: Vec<QuerySample> = written.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let queries: Vec<&str> = samples.iter().map(|s| &s.query[..]).collect();
+    assert_eq!(queries, vec!["SELECT * FROM users WHERE id = ?", "SELECT * FROM users WHERE id = ?",
+                             "DELETE FROM sessions WHERE token = ?"]);
+    assert_eq!(samples[2].fingerprint, "DELETE FROM sessions WHERE token = ?");
+    assert_eq!((samples[2].elapsed_us, samples[2].error), (20000, Some(1205)));
+    assert_eq!(config.validate(), Ok(()));
+    assert!(QueryLogConfig { sample_rate: 1.5, ..config }.validate().is_err());
+}
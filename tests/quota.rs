@@ -0,0 +1,50 @@
+extern crate mysql_proxy;
+
+use std::sync::Arc;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::quota::{QuotaConfig, QuotaHandler, Quotas, ER_USER_LIMIT_REACHED};
+use mysql_proxy::testing::Forward;
+
+#[test]
+fn limit_connections_and_queries() {
+    let quotas = Quotas::new();
+    let limits = QuotaConfig { max_connections: Some(2), max_concurrent_queries: Some(1), max_queries_per_hour: Some(2) };
+    let first = Arc::new(quotas.connect("app", &limits).unwrap());
+    let second = quotas.connect("app", &limits).unwrap();
+    assert_eq!(quotas.connect("app", &limits).unwrap_err(),
+               "User app already has more than 'max_user_connections' active connections");
+    drop(second);
+    assert_eq!(quotas.usage()["app"].connections, 1);
+
+    let mut handler = QuotaHandler::new(first.clone(), Forward);
+    let query = Packet::new(0, b"\x03SELECT 1");
+    let ok = Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(handler.handle_request(&query), Action::Forward);
+    // a second query while the first is in flight is one too many
+    match handler.handle_request(&query) {
+        Action::Error { code, msg, .. } => {
+            assert_eq!(code, ER_USER_LIMIT_REACHED);
+            assert_eq!(msg, "User 'app' has exceeded the 'max_concurrent_queries' resource (current value: 1)");
+        },
+        action => panic!("unexpected {:?}", action),
+    }
+    assert_eq!(handler.handle_response(&ok), Action::Forward);
+    assert_eq!(quotas.usage()["app"].queries_in_flight, 0);
+    assert_eq!(handler.handle_request(&query), Action::Forward);
+    assert_eq!(handler.handle_response(&ok), Action::Forward);
+    match handler.handle_request(&query) {
+        Action::Error { msg, .. } => assert_eq!(msg, "User 'app' has exceeded the 'max_questions' resource (current value: 2)"),
+        action => panic!("unexpected {:?}", action),
+    }
+    // COM_QUIT is never answered, so it doesn't count
+    assert_eq!(handler.handle_request(&Packet::new(0, b"\x01")), Action::Forward);
+
+    // a session ending mid-query releases it
+    let mut usage = quotas.usage()["app"].clone();
+    assert_eq!((usage.connections, usage.queries_in_flight, usage.queries_this_hour), (1, 0, 2));
+    drop(handler);
+    drop(first);
+    usage = quotas.usage()["app"].clone();
+    assert_eq!((usage.connections, usage.limits), (0, limits));
+}
@@ -0,0 +1,130 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Action, Packet, PacketHandler, Pipe};
+use mysql_proxy::codec::*;
+
+/// Answers every query with a result of one row holding one very long value
+struct LongResult;
+
+impl PacketHandler for LongResult {
+
+    fn handle_request(&mut self, _: &Packet) -> Action {
+        let column = ColumnDefinition {
+            catalog: "def".to_string(),
+            schema: String::new(),
+            table: String::new(),
+            org_table: String::new(),
+            name: "blob".to_string(),
+            org_name: String::new(),
+            character_set: 0x3f,
+            column_length: 0xffff_ffff,
+            column_type: 0xfc,
+            flags: 0,
+            decimals: 0,
+        };
+        let row = vec![Some(vec![b'x'; MAX_PAYLOAD_LEN + 1000])];
+        Action::Respond(text_result_set(&[column], &[row], 0))
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+}
+
+/// One end of an in-memory connection, which takes at most `room` bytes until given more
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    room: Rc<Cell<usize>>,
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.room.get());
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.room.set(self.room.get() - n);
+        self.outgoing.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+#[test]
+fn long_responses_are_split_and_written_as_the_client_reads() {
+    let (client, server) = (Memory::default(), Memory::default());
+    let mut pipe = executor::spawn(Pipe::from_streams(client.clone(), server.clone(), LongResult));
+    let notify = Arc::new(Ignore);
+
+    client.incoming.borrow_mut().extend(Packet::new(0, b"\x03SELECT blob FROM cache").bytes.iter());
+    client.room.set(1 << 16);
+    assert_eq!(pipe.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    assert_eq!(client.outgoing.borrow().len(), 1 << 16);
+    while client.room.get() == 0 {
+        client.room.set(1 << 20);
+        assert_eq!(pipe.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    }
+    assert!(server.outgoing.borrow().is_empty());
+
+    // the column count, column, EOF, the row in two packets and the final EOF, in sequence
+    let written = client.outgoing.borrow();
+    let mut packets = vec![];
+    let mut at = 0;
+    while at < written.len() {
+        let len = parse_packet_length(&written[at..]);
+        packets.push((written[at + 3], len));
+        at += 4 + len;
+    }
+    assert_eq!(at, written.len());
+    let sequence_ids: Vec<u8> = packets.iter().map(|&(seq, _)| seq).collect();
+    assert_eq!(sequence_ids, vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(packets[3].1, MAX_PAYLOAD_LEN);
+    // the value's length takes 9 bytes ahead of it
+    assert_eq!(packets[4].1, 9 + 1000);
+}
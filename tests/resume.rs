@@ -0,0 +1,192 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::{future, Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{AsyncTransport, Packet, Pipe, Transport};
+use mysql_proxy::codec::{ok_packet, ok_packet_with_status};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::resume::{Reconnect, SessionResume, SessionResumeConfig};
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection, which reads as closed once `closed` is set
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    closed: Rc<Cell<bool>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+
+    fn written(&self) -> Vec<u8> {
+        self.outgoing.borrow_mut().split_off(0)
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return match self.closed.get() {
+                true => Ok(0),
+                false => Err(io::ErrorKind::WouldBlock.into()),
+            };
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+struct Session {
+    client: Memory,
+    server: Memory,
+    /// the backend the session moves to
+    next: Memory,
+    reconnects: Rc<Cell<u32>>,
+    pipe: Spawn<Pipe<Forward>>,
+    _core: Core,
+}
+
+impl Session {
+
+    fn new() -> Self {
+        let core = Core::new().unwrap();
+        let (client, server, next) = (Memory::default(), Memory::default(), Memory::default());
+        let reconnects = Rc::new(Cell::new(0));
+        let (counter, backend) = (reconnects.clone(), next.clone());
+        let reconnect: Reconnect = Box::new(move || {
+            counter.set(counter.get() + 1);
+            Box::new(future::ok(Rc::new(AsyncTransport::new(backend.clone())) as Rc<dyn Transport>))
+        });
+        let pipe = Pipe::from_streams(client.clone(), server.clone(), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41)
+            .with_session_resume(SessionResume::new(SessionResumeConfig::default(), reconnect, &core.handle()));
+        Session { client, server, next, reconnects, pipe: executor::spawn(pipe), _core: core }
+    }
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.pipe.poll_future_notify(&Arc::new(Ignore), 0)
+    }
+
+    /// Send a command and answer it with an OK packet with these status flags
+    fn run(&mut self, command: &[u8], status_flags: u16) {
+        self.client.send(&Packet::new(0, command));
+        assert_eq!(self.poll().unwrap(), Async::NotReady);
+        self.server.send(&ok_packet_with_status(1, status_flags));
+        assert_eq!(self.poll().unwrap(), Async::NotReady);
+        self.server.written();
+        assert_eq!(self.client.written(), ok_packet_with_status(1, status_flags).bytes);
+    }
+}
+
+const AUTOCOMMIT: u16 = 0x0002;
+const IN_TRANS: u16 = 0x0001;
+
+#[test]
+fn an_idle_session_moves_to_another_backend_with_its_state() {
+    let mut session = Session::new();
+    session.run(b"\x03USE app", AUTOCOMMIT);
+    session.run(b"\x03SET NAMES 'utf8mb4'", AUTOCOMMIT);
+    session.run(b"\x03SET GLOBAL max_connections = 500", AUTOCOMMIT);
+    session.run(b"\x03SET @@sql_mode = 'ANSI';", AUTOCOMMIT);
+    session.run(b"\x03SELECT 1", AUTOCOMMIT);
+
+    session.server.closed.set(true);
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.reconnects.get(), 1);
+    let mut replay = Packet::new(0, b"\x03SET NAMES 'utf8mb4'").bytes;
+    replay.extend(Packet::new(0, b"\x03SET @@sql_mode = 'ANSI'").bytes);
+    replay.extend(Packet::new(0, b"\x02app").bytes);
+    assert_eq!(session.next.written(), replay);
+
+    // commands wait for the state to be restored, whose responses the client doesn't see
+    session.client.send(&Packet::new(0, b"\x03SELECT 2"));
+    session.next.send(&ok_packet(1));
+    session.next.send(&ok_packet(1));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert!(session.next.written().is_empty());
+    session.next.send(&ok_packet(1));
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.next.written(), Packet::new(0, b"\x03SELECT 2").bytes);
+    assert!(session.client.written().is_empty());
+}
+
+#[test]
+fn a_session_mid_transaction_is_disconnected() {
+    let mut session = Session::new();
+    session.run(b"\x03BEGIN", AUTOCOMMIT | IN_TRANS);
+    session.server.closed.set(true);
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.reconnects.get(), 0);
+    // the pipe ends once the client closes its end too
+    session.client.closed.set(true);
+    assert!(session.poll().is_err());
+}
+
+#[test]
+fn a_session_with_state_that_cant_be_replayed_is_disconnected() {
+    let mut session = Session::new();
+    session.run(b"\x03CREATE TEMPORARY TABLE scratch (id INT)", AUTOCOMMIT);
+    session.server.closed.set(true);
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    assert_eq!(session.reconnects.get(), 0);
+    // the pipe ends once the client closes its end too
+    session.client.closed.set(true);
+    assert!(session.poll().is_err());
+}
+
+#[test]
+fn a_failed_replay_ends_the_session() {
+    let mut session = Session::new();
+    session.run(b"\x03USE app", AUTOCOMMIT);
+    session.server.closed.set(true);
+    assert_eq!(session.poll().unwrap(), Async::NotReady);
+    session.next.send(&Packet::new(1, b"\xff\x19\x04#42000Unknown database 'app'"));
+    let error = session.poll().unwrap_err();
+    assert!(error.to_string().contains("Unknown database 'app'"), "{}", error);
+}
@@ -0,0 +1,164 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::cell::Cell;
+use std::io::{Error, ErrorKind};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::retry::{retry_blocking, retry_connect, ConnectFailure, ConnectRetryPolicy, RetryPolicy, ER_LOCK_DEADLOCK};
+use mysql_proxy::testing::{duplex, Forward};
+
+fn policy() -> ConnectRetryPolicy {
+    ConnectRetryPolicy { max_attempts: 3, base_delay_ms: 20, max_delay_ms: 100, ..ConnectRetryPolicy::default() }
+}
+
+#[test]
+fn connects_are_retried_after_failures_that_may_pass() {
+    let mut core = Core::new().unwrap();
+    let attempts = Rc::new(Cell::new(0));
+    let counted = attempts.clone();
+    let started = Instant::now();
+    let connect = retry_connect(Some(&policy()), "db1:3306", &core.handle(), move || {
+        counted.set(counted.get() + 1);
+        match counted.get() {
+            1 => Box::new(future::err(Error::new(ErrorKind::ConnectionRefused, "refused"))),
+            2 => Box::new(future::err(Error::new(ErrorKind::TimedOut, "timed out"))),
+            n => Box::new(future::ok(n)),
+        }
+    });
+    assert_eq!(core.run(connect).unwrap(), 3);
+    // backing off 10-20ms, then 20-40ms
+    assert!(started.elapsed() >= Duration::from_millis(30));
+
+    // but only up to max_attempts in all
+    attempts.set(0);
+    let attempts_left = attempts.clone();
+    let connect = retry_connect(Some(&policy()), "db1:3306", &core.handle(), move || -> Box<dyn futures::Future<Item = (), Error = Error>> {
+        attempts_left.set(attempts_left.get() + 1);
+        Box::new(future::err(Error::new(ErrorKind::ConnectionReset, "reset")))
+    });
+    assert_eq!(core.run(connect).unwrap_err().kind(), ErrorKind::ConnectionReset);
+    assert_eq!(attempts.get(), 3);
+}
+
+#[test]
+fn other_failures_and_connects_without_a_policy_fail_at_once() {
+    let mut attempts = 0;
+    let result: Result<(), Error> = retry_blocking(Some(&policy()), "db1:3306", || {
+        attempts += 1;
+        Err(Error::new(ErrorKind::PermissionDenied, "Access denied"))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+
+    let mut attempts = 0;
+    let result: Result<(), Error> = retry_blocking(None, "db1:3306", || {
+        attempts += 1;
+        Err(Error::new(ErrorKind::ConnectionRefused, "refused"))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+
+    // unless the policy retries them too
+    let everything = ConnectRetryPolicy { retry_on: vec![ConnectFailure::Other], ..policy() };
+    let mut attempts = 0;
+    let result = retry_blocking(Some(&everything), "db1:3306", || {
+        attempts += 1;
+        if attempts < 2 { Err(Error::other("no such host")) } else { Ok(attempts) }
+    });
+    assert_eq!(result.unwrap(), 2);
+}
+
+#[test]
+fn connect_failures_are_classified() {
+    let failure = |kind| ConnectFailure::of(&Error::new(kind, "failed"));
+    assert_eq!(failure(ErrorKind::ConnectionRefused), ConnectFailure::Refused);
+    assert_eq!(failure(ErrorKind::UnexpectedEof), ConnectFailure::Reset);
+    assert_eq!(failure(ErrorKind::TimedOut), ConnectFailure::TimedOut);
+    assert_eq!(ConnectFailure::of(&Error::from_raw_os_error(113)), ConnectFailure::Unreachable);
+    assert_eq!(failure(ErrorKind::InvalidData), ConnectFailure::Other);
+}
+
+#[test]
+fn connect_retries_are_configured() {
+    let config = ProxyConfig::parse(r#"
+        [connect_retry]
+        max_attempts = 5
+        retry_on = ["refused", "other"]
+    "#).unwrap();
+    assert_eq!(config.connect_retry, Some(ConnectRetryPolicy {
+        max_attempts: 5,
+        base_delay_ms: 100,
+        max_delay_ms: 2000,
+        retry_on: vec![ConnectFailure::Refused, ConnectFailure::Other],
+    }));
+    assert!(config.validate().is_empty());
+
+    let config = ProxyConfig::parse(r#"
+        [connect_retry]
+        max_attempts = 0
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["Connect retry: max_attempts must be between 1 and 10".to_string()]);
+    assert!(ProxyConfig::parse("[connect_retry]\nretry_on = [\"dns\"]").is_err());
+}
+
+fn turn(core: &mut Core) {
+    for _ in 0..5 {
+        core.turn(Some(Duration::from_millis(5)));
+    }
+}
+
+#[test]
+fn statement_backoffs_double_up_to_the_maximum() {
+    let policy = RetryPolicy { max_retries: 3, base_delay_ms: 20, max_delay_ms: 50 };
+    for _ in 0..20 {
+        let (first, second, third) = (policy.delay(0), policy.delay(1), policy.delay(2));
+        assert!(first >= Duration::from_millis(10) && first <= Duration::from_millis(20), "{:?}", first);
+        assert!(second >= Duration::from_millis(20) && second <= Duration::from_millis(40), "{:?}", second);
+        assert!(third >= Duration::from_millis(25) && third <= Duration::from_millis(50), "{:?}", third);
+    }
+}
+
+#[test]
+fn autocommit_statements_that_lose_a_deadlock_are_sent_again() {
+    let mut core = Core::new().unwrap();
+    let (client, client_end) = duplex(1 << 16);
+    let (server, server_end) = duplex(1 << 16);
+    let policy = RetryPolicy { max_retries: 1, base_delay_ms: 1, max_delay_ms: 1 };
+    let pipe = Pipe::new(Rc::new(client_end), Rc::new(server_end), Forward)
+        .with_backend_capabilities(CLIENT_PROTOCOL_41)
+        .with_deadlock_retry(policy, &core.handle());
+    core.handle().spawn(pipe.map_err(|_| ()));
+    let deadlock = || Packet::error_packet(ER_LOCK_DEADLOCK, *b"40001", "Deadlock found".to_string()).with_sequence_id(1);
+
+    // the server reports autocommit on
+    client.send(&Packet::com_query("SELECT 1"));
+    turn(&mut core);
+    server.recv();
+    server.send(&ok_packet(1));
+    turn(&mut core);
+    assert_eq!(client.recv(), vec![ok_packet(1)]);
+
+    client.send(&Packet::com_query("UPDATE t SET x = 1"));
+    turn(&mut core);
+    assert_eq!(server.recv(), vec![Packet::com_query("UPDATE t SET x = 1")]);
+    server.send(&deadlock());
+    turn(&mut core);
+    assert!(client.recv().is_empty());
+    assert_eq!(server.recv(), vec![Packet::com_query("UPDATE t SET x = 1")]);
+
+    // up to max_retries times, then the client gets the error
+    server.send(&deadlock());
+    turn(&mut core);
+    assert_eq!(client.recv(), vec![deadlock()]);
+    assert!(server.recv().is_empty());
+}
@@ -0,0 +1,180 @@
+extern crate mysql_proxy;
+
+use std::collections::HashMap;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::rowfilter::{RowFilter, RowFilterHandler, ER_NOT_SUPPORTED_YET};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn filters() -> Vec<RowFilter> {
+    vec![RowFilter {
+        table: "shop.orders".to_string(),
+        column: "tenant_id".to_string(),
+        value: "{tenant_id}".to_string(),
+        exempt_users: vec!["admin".to_string()],
+    }]
+}
+
+fn tester(user: &str, tenant_id: Option<&str>) -> HandlerTester<RowFilterHandler<Forward>> {
+    let attributes: HashMap<String, String> = tenant_id.iter().map(|t| ("tenant_id".to_string(), t.to_string())).collect();
+    let handler = RowFilterHandler::new(&filters(), user, &attributes, Forward).with_capabilities(CLIENT_PROTOCOL_41);
+    HandlerTester::new(handler).with_backend_capabilities(CLIENT_PROTOCOL_41)
+}
+
+/// The statement the handler forwards for `sql`, or its action if it doesn't
+fn forwarded(tester: &mut HandlerTester<RowFilterHandler<Forward>>, sql: &str) -> Result<String, Action> {
+    let mut payload = vec![0x03];
+    payload.extend_from_slice(sql.as_bytes());
+    match tester.request(Packet::new(0, &payload)) {
+        Action::Forward => Ok(sql.to_string()),
+        Action::Mutate(p) => Ok(String::from_utf8(p.payload()[1..].to_vec()).unwrap()),
+        action => Err(action),
+    }
+}
+
+fn refused(action: Result<String, Action>) -> bool {
+    matches!(action, Err(Action::Error { code: ER_NOT_SUPPORTED_YET, .. }))
+}
+
+fn column(seq: u8, name: &str) -> Packet {
+    let mut payload = b"\x03def\x04shop\x06orders\x06orders".to_vec();
+    for _ in 0..2 {
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name.as_bytes());
+    }
+    payload.extend_from_slice(&[0x0c, 0x21, 0x00, 0x0b, 0x00, 0x00, 0x00, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    Packet::new(seq, &payload)
+}
+
+fn eof(seq: u8) -> Packet {
+    Packet::new(seq, &[0xfe, 0x00, 0x00, 0x02, 0x00])
+}
+
+#[test]
+fn queries_read_a_derived_table_of_the_users_rows() {
+    let mut tester = tester("app", Some("42"));
+    assert_eq!(forwarded(&mut tester, "SELECT o.id FROM shop.orders o JOIN shop.items i ON i.order_id = o.id"),
+               Ok("SELECT o.id FROM (SELECT * FROM shop.orders WHERE `tenant_id` = '42') AS `o` JOIN shop.items i ON i.order_id = o.id".to_string()));
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM items"), Ok("SELECT * FROM items".to_string()));
+
+    // unqualified tables are in the current schema
+    assert_eq!(forwarded(&mut tester, "USE shop"), Ok("USE shop".to_string()));
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM orders"),
+               Ok("SELECT * FROM (SELECT * FROM orders WHERE `tenant_id` = '42') AS `orders`".to_string()));
+
+    // values are quoted, and users without one see no rows
+    let mut tester = self::tester("app", Some("4'2"));
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM shop.orders"),
+               Ok("SELECT * FROM (SELECT * FROM shop.orders WHERE `tenant_id` = '4''2') AS `orders`".to_string()));
+    let mut tester = self::tester("app", None);
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM shop.orders"),
+               Ok("SELECT * FROM (SELECT * FROM shop.orders WHERE FALSE) AS `orders`".to_string()));
+
+    let mut tester = self::tester("admin", None);
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM shop.orders"), Ok("SELECT * FROM shop.orders".to_string()));
+}
+
+#[test]
+fn updates_and_deletes_only_change_the_users_rows() {
+    let mut tester = tester("app", Some("42"));
+    assert_eq!(forwarded(&mut tester, "DELETE FROM shop.orders"),
+               Ok("DELETE FROM shop.orders WHERE `orders`.`tenant_id` = '42'".to_string()));
+    assert_eq!(forwarded(&mut tester, "DELETE FROM shop.orders WHERE id = 1 OR id = 2 ORDER BY id LIMIT 1"),
+               Ok("DELETE FROM shop.orders WHERE (`orders`.`tenant_id` = '42') AND (id = 1 OR id = 2) ORDER BY id LIMIT 1".to_string()));
+    assert_eq!(forwarded(&mut tester, "UPDATE shop.orders o SET total = 0 WHERE id IN (SELECT id FROM x)"),
+               Ok("UPDATE shop.orders o SET total = 0 WHERE (`o`.`tenant_id` = '42') AND (id IN (SELECT id FROM x))".to_string()));
+
+    // statements the filter can't be added to
+    assert!(refused(forwarded(&mut tester, "UPDATE shop.orders, shop.items SET total = 0")));
+    assert!(refused(forwarded(&mut tester, "TRUNCATE shop.orders")));
+}
+
+#[test]
+fn upserts_and_prepared_statements_of_filtered_tables_are_refused() {
+    let mut tester = tester("app", Some("42"));
+    assert!(refused(forwarded(&mut tester, "REPLACE INTO shop.orders (id, tenant_id) VALUES (1, '42')")));
+    assert!(refused(forwarded(&mut tester, "REPLACE LOW_PRIORITY shop.orders SET id = 1")));
+    assert!(refused(forwarded(&mut tester, "INSERT INTO shop.orders (id) VALUES (1) ON DUPLICATE KEY UPDATE total = 0")));
+    assert!(refused(forwarded(&mut tester, "INSERT IGNORE shop.orders (id) VALUES (1) ON DUPLICATE KEY UPDATE total = 0")));
+    assert!(refused(forwarded(&mut tester, "PREPARE s FROM 'DELETE FROM shop.orders'")));
+    assert!(refused(forwarded(&mut tester, "PREPARE s FROM @q")));
+    assert!(refused(forwarded(&mut tester, "SELECT 1; PREPARE s FROM 'SELECT * FROM shop.orders'")));
+    assert!(refused(forwarded(&mut tester, "SELECT 1; PREPARE s FROM @q")));
+
+    // nor are other tables' upserts and prepared statements changed
+    for sql in &["INSERT INTO shop.orders (id, tenant_id) VALUES (1, '42')",
+                 "REPLACE INTO shop.items SELECT * FROM shop.archived_items",
+                 "INSERT INTO shop.items (id) VALUES (1) ON DUPLICATE KEY UPDATE total = 0",
+                 "PREPARE s FROM 'SELECT * FROM shop.items'",
+                 "EXECUTE s"] {
+        assert_eq!(forwarded(&mut tester, sql), Ok(sql.to_string()));
+    }
+
+    // and users without filters may prepare what they like
+    let mut tester = self::tester("admin", None);
+    assert_eq!(forwarded(&mut tester, "PREPARE s FROM @q"), Ok("PREPARE s FROM @q".to_string()));
+}
+
+#[test]
+fn multi_statements_that_change_filtered_rows_are_refused() {
+    let mut tester = tester("app", Some("42"));
+    assert!(refused(forwarded(&mut tester, "SELECT 1; DELETE FROM shop.orders")));
+    assert!(refused(forwarded(&mut tester, "SELECT 1; UPDATE shop.orders SET total = 0")));
+    assert!(refused(forwarded(&mut tester, "USE shop; DELETE FROM orders")));
+    assert!(refused(forwarded(&mut tester, "USE; SELECT * FROM orders")));
+    assert!(refused(forwarded(&mut tester, "SELECT * FROM shop.orders; SELECT * FROM shop.orders")));
+    assert_eq!(tester.in_flight(), 0);
+
+    // a refused query's USE isn't followed
+    assert_eq!(forwarded(&mut tester, "SELECT * FROM orders"), Ok("SELECT * FROM orders".to_string()));
+
+    // nor do multi-statements without filtered tables need to be
+    assert_eq!(forwarded(&mut tester, "SELECT 1; DELETE FROM shop.items"), Ok("SELECT 1; DELETE FROM shop.items".to_string()));
+    assert_eq!(forwarded(&mut tester, "SELECT 1; USE shop"), Ok("SELECT 1; USE shop".to_string()));
+    assert!(refused(forwarded(&mut tester, "SELECT 1; DELETE FROM orders")));
+
+    // queries of one filtered table are forwarded to have their rows checked
+    assert_eq!(forwarded(&mut tester, "SELECT 1; SELECT * FROM orders"), Ok("SELECT 1; SELECT * FROM orders".to_string()));
+}
+
+#[test]
+fn other_tenants_rows_are_dropped_from_results_that_cant_be_rewritten() {
+    let mut tester = tester("app", Some("42"));
+    for sql in &["SELECT * FROM shop.orders FORCE INDEX (created)", "SELECT tenant_id, id FROM shop.orders IGNORE INDEX (created)"] {
+        assert_eq!(forwarded(&mut tester, sql), Ok(sql.to_string()));
+    }
+
+    // the rows of the first query's result don't include the column, so none can be shown
+    assert_eq!(tester.response(Packet::new(1, &[0x01])), Action::Forward);
+    assert_eq!(tester.response(column(2, "id")), Action::Forward);
+    assert_eq!(tester.response(eof(3)), Action::Forward);
+    assert_eq!(tester.response(Packet::new(4, b"\x011")), Action::Drop);
+    assert_eq!(tester.response(eof(5)), Action::Mutate(eof(4)));
+
+    assert_eq!(tester.response(Packet::new(1, &[0x02])), Action::Forward);
+    assert_eq!(tester.response(column(2, "tenant_id")), Action::Forward);
+    assert_eq!(tester.response(column(3, "id")), Action::Forward);
+    assert_eq!(tester.response(eof(4)), Action::Forward);
+    assert_eq!(tester.response(Packet::new(5, b"\x017\x011")), Action::Drop);
+    assert_eq!(tester.response(Packet::new(6, b"\x0242\x012")), Action::Mutate(Packet::new(5, b"\x0242\x012")));
+    assert_eq!(tester.response(Packet::new(7, b"\x017\x013")), Action::Drop);
+    assert_eq!(tester.response(Packet::new(8, b"\x0242\x014")), Action::Mutate(Packet::new(6, b"\x0242\x014")));
+    assert_eq!(tester.response(eof(9)), Action::Mutate(eof(7)));
+    assert_eq!(tester.in_flight(), 0);
+}
+
+#[test]
+fn row_filters_are_validated() {
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+
+        [[row_filters]]
+        table = "shop.orders"
+        column = "tenant_id"
+        value = "{tenant_id"
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["Row filter: unclosed or empty placeholder in value '{tenant_id'".to_string()]);
+}
@@ -0,0 +1,80 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::honeypot::ER_DBACCESS_DENIED_ERROR;
+use mysql_proxy::rowfilter::ER_NOT_SUPPORTED_YET;
+use mysql_proxy::schemas::{SchemaHandler, SchemaPolicy};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn policy() -> SchemaPolicy {
+    SchemaPolicy { default: Some("shop".to_string()), allowed: vec!["shop_archive".to_string()] }
+}
+
+fn denied(schema: &str) -> Action {
+    Action::Error {
+        code: ER_DBACCESS_DENIED_ERROR,
+        state: *b"42000",
+        msg: format!("Access denied for user 'reports'@'10.1.2.3' to database '{}'", schema),
+    }
+}
+
+#[test]
+fn logins_start_in_the_default_schema_or_an_allowed_one() {
+    let policy = policy();
+    assert_eq!(policy.login_schema(None), Ok(Some("shop".to_string())));
+    assert_eq!(policy.login_schema(Some("shop_archive")), Ok(Some("shop_archive".to_string())));
+    assert_eq!(policy.login_schema(Some("billing")), Err("billing".to_string()));
+
+    // without a default, logins naming no schema are left without one
+    let policy = SchemaPolicy { default: None, allowed: vec!["shop".to_string()] };
+    assert_eq!(policy.login_schema(None), Ok(None));
+}
+
+#[test]
+fn switching_to_other_schemas_is_refused() {
+    let mut tester = HandlerTester::new(SchemaHandler::new(policy(), "reports", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x02shop_archive")), Action::Forward);
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE `shop`")), Action::Forward);
+    assert_eq!(tester.request(Packet::new(0, b"\x02billing")), denied("billing"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03use billing")), denied("billing"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03/* x */ USE `Shop`")), denied("Shop"));
+    assert_eq!(tester.in_flight(), 2);
+
+    // only the current schema is checked
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT * FROM billing.invoices")), Action::Forward);
+}
+
+fn not_supported(action: Action) -> bool {
+    matches!(action, Action::Error { code: ER_NOT_SUPPORTED_YET, .. })
+}
+
+#[test]
+fn every_statement_of_a_multi_statement_query_is_checked() {
+    let mut tester = HandlerTester::new(SchemaHandler::new(policy(), "reports", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT 1; USE billing")), denied("billing"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE shop; SELECT 1;\nuse `billing`; SELECT 2")), denied("billing"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE shop; USE shop_archive")), Action::Forward);
+    // a USE within a literal switches nothing
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT 'x; USE billing'")), Action::Forward);
+
+    // nor may the session switch in ways the handler can't follow
+    assert!(not_supported(tester.request(Packet::new(0, b"\x03SELECT 1; USE \"billing\""))));
+    assert!(not_supported(tester.request(Packet::new(0, b"\x11root\x00\x00billing\x00"))));
+    assert_eq!(tester.in_flight(), 2);
+}
+
+#[test]
+fn schema_policies_are_validated() {
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+
+        [[users]]
+        user = "reports"
+        password = "secret"
+        default_group = "primary"
+        schemas = {}
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["User 'reports': no default or allowed schemas".to_string()]);
+}
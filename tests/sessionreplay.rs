@@ -0,0 +1,176 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::{ok_packet, ok_packet_with_status};
+use mysql_proxy::pipeline::Correlator;
+use mysql_proxy::sessionreplay::SessionReplay;
+
+/// A session's connection to a backend, whose state a `SessionReplay` follows
+struct Connection {
+    correlator: Correlator,
+    replay: SessionReplay,
+}
+
+impl Connection {
+
+    fn new(replay: SessionReplay) -> Self {
+        Connection { correlator: Correlator::default(), replay }
+    }
+
+    /// Send a command, returning it as the backend gets it
+    fn send(&mut self, payload: &[u8]) -> Packet {
+        let p = self.replay.request(Packet::new(0, payload));
+        self.correlator.request(&p);
+        p
+    }
+
+    /// Receive a response packet, returning it as the client gets it
+    fn receive(&mut self, p: Packet) -> Result<Option<Packet>, String> {
+        let answered = self.correlator.response(&p);
+        self.replay.response(p, answered)
+    }
+
+    fn run(&mut self, payload: &[u8], response: Packet) -> Option<Packet> {
+        self.send(payload);
+        self.receive(response).unwrap()
+    }
+
+    fn replay(&mut self) -> Vec<Vec<u8>> {
+        let commands = self.replay.replay();
+        for command in &commands {
+            self.correlator.request(command);
+        }
+        commands.iter().map(|c| c.payload().to_vec()).collect()
+    }
+}
+
+fn error(msg: &str) -> Packet {
+    let mut payload = b"\xff\x19\x04#42000".to_vec();
+    payload.extend_from_slice(msg.as_bytes());
+    Packet::new(1, &payload)
+}
+
+/// The response to COM_STMT_PREPARE for a statement without parameters or columns
+fn prepared(id: u32) -> Packet {
+    let id = id.to_le_bytes();
+    Packet::new(1, &[0x00, id[0], id[1], id[2], id[3], 0, 0, 0, 0, 0, 0, 0])
+}
+
+fn statement_id(p: &Packet) -> u32 {
+    let payload = p.payload();
+    u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]])
+}
+
+fn execute(id: u32) -> Vec<u8> {
+    let mut payload = vec![0x17];
+    payload.extend_from_slice(&id.to_le_bytes());
+    payload.extend_from_slice(&[0, 1, 0, 0, 0]);
+    payload
+}
+
+const AUTOCOMMIT: u16 = 0x0002;
+const IN_TRANS: u16 = 0x0001;
+
+#[test]
+fn statements_replay_in_the_order_they_last_ran() {
+    let mut session = Connection::new(SessionReplay::default());
+    session.run(b"\x03SET @a = 1", ok_packet(1));
+    session.run(b"\x03SET NAMES 'latin1'", ok_packet(1));
+    session.run(b"\x03SET @a = 2", ok_packet(1));
+    session.run(b"\x03SET @a = 1", ok_packet(1));
+    // neither failed statements nor server-wide settings are part of the session
+    session.run(b"\x03SET sql_mode = 'NOPE'", error("Variable 'sql_mode' can't be set to the value of 'NOPE'"));
+    session.run(b"\x03SET GLOBAL max_connections = 500", ok_packet(1));
+    session.run(b"\x02app", ok_packet(1));
+    session.run(b"\x03USE `reports`", ok_packet(1));
+    session.run(b"\x03USE missing", error("Unknown database 'missing'"));
+
+    assert_eq!(session.replay.statements(), &["SET NAMES 'latin1'", "SET @a = 2", "SET @a = 1"]);
+    assert_eq!(session.replay(), vec![
+        b"\x03SET NAMES 'latin1'".to_vec(),
+        b"\x03SET @a = 2".to_vec(),
+        b"\x03SET @a = 1".to_vec(),
+        b"\x02reports".to_vec(),
+    ]);
+}
+
+#[test]
+fn replaying_again_rebuilds_the_same_state() {
+    let mut session = Connection::new(SessionReplay::default().with_schema(Some("app".to_string())));
+    session.run(b"\x03SET time_zone = '+00:00'", ok_packet(1));
+    // the schema the session logged in with needs no command
+    let first = session.replay();
+    assert_eq!(first, vec![b"\x03SET time_zone = '+00:00'".to_vec()]);
+    assert!(session.replay.replaying());
+    assert_eq!(session.receive(ok_packet(1)), Ok(None));
+    assert!(!session.replay.replaying());
+
+    let second = session.replay();
+    assert_eq!(second, first);
+    for _ in &second {
+        assert_eq!(session.receive(ok_packet(1)), Ok(None));
+    }
+    assert_eq!(session.replay.statements().len(), 1);
+    assert_eq!(session.replay.schema(), Some("app"));
+}
+
+#[test]
+fn prepared_statements_keep_their_ids_on_the_new_connection() {
+    let mut session = Connection::new(SessionReplay::default().with_schema(Some("app".to_string())));
+    session.run(b"\x16UPDATE t SET x = 1", prepared(1));
+    session.run(b"\x16UPDATE t SET x = 2", prepared(2));
+    session.run(b"\x02other", ok_packet(1));
+    session.run(b"\x16UPDATE u SET y = 3", prepared(3));
+    session.send(b"\x19\x02\x00\x00\x00");
+
+    // each statement is prepared in its schema, then the session's schema is restored
+    assert_eq!(session.replay(), vec![
+        b"\x16UPDATE t SET x = 1".to_vec(),
+        b"\x02other".to_vec(),
+        b"\x16UPDATE u SET y = 3".to_vec(),
+    ]);
+    assert_eq!(session.receive(prepared(7)), Ok(None));
+    assert_eq!(session.receive(ok_packet(1)), Ok(None));
+    assert_eq!(session.receive(prepared(8)), Ok(None));
+
+    assert_eq!(statement_id(&session.send(&execute(1))), 7);
+    session.receive(ok_packet(1)).unwrap();
+    assert_eq!(statement_id(&session.send(&execute(3))), 8);
+    session.receive(ok_packet(1)).unwrap();
+
+    // a statement prepared now gets an id the client hasn't seen, not the backend's
+    let response = session.run(b"\x16UPDATE v SET z = 4", prepared(9)).unwrap();
+    assert_eq!(statement_id(&response), 4);
+    assert_eq!(statement_id(&session.send(&execute(4))), 9);
+}
+
+#[test]
+fn state_that_cant_be_replayed_is_reported() {
+    let mut session = Connection::new(SessionReplay::default());
+    assert!(session.replay.replayable());
+    session.run(b"\x03BEGIN", ok_packet_with_status(1, AUTOCOMMIT | IN_TRANS));
+    assert!(!session.replay.replayable());
+    session.run(b"\x03COMMIT", ok_packet(1));
+    assert!(session.replay.replayable());
+
+    session.run(b"\x03LOCK TABLES t READ", ok_packet(1));
+    assert!(!session.replay.replayable());
+    session.run(b"\x03UNLOCK TABLES", ok_packet(1));
+    assert!(session.replay.replayable());
+
+    session.run(b"\x03CREATE TEMPORARY TABLE scratch (id INT)", ok_packet(1));
+    assert!(!session.replay.replayable());
+    session.run(b"\x1f", ok_packet(1));
+    assert!(session.replay.replayable());
+
+    session.run(b"\x03SELECT @n := COUNT(*) FROM t", ok_packet(1));
+    assert!(!session.replay.replayable());
+}
+
+#[test]
+fn a_replayed_command_that_fails_is_reported() {
+    let mut session = Connection::new(SessionReplay::default());
+    session.run(b"\x02app", ok_packet(1));
+    session.replay();
+    assert_eq!(session.receive(error("Unknown database 'app'")), Err("Unknown database 'app'".to_string()));
+}
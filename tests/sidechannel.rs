@@ -0,0 +1,133 @@
+extern crate futures;
+extern crate mysql_proxy;
+
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::{text_result_set, ColumnDefinition, HandshakeV10};
+use mysql_proxy::protocol::{CLIENT_PLUGIN_AUTH, CLIENT_PROTOCOL_41, CLIENT_SECURE_CONNECTION, NATIVE_PASSWORD_PLUGIN};
+use mysql_proxy::sidechannel::{read_packet, SideChannel, SideChannelConfig};
+
+fn column(name: &str) -> ColumnDefinition {
+    ColumnDefinition {
+        catalog: "def".to_string(),
+        schema: "routing".to_string(),
+        table: "tenants".to_string(),
+        org_table: "tenants".to_string(),
+        name: name.to_string(),
+        org_name: name.to_string(),
+        character_set: 0x21,
+        column_length: 64,
+        column_type: 0xfd,
+        flags: 0,
+        decimals: 0,
+    }
+}
+
+/// A backend answering every query with the tenants' shards, or an error for `SELECT nope`,
+/// counting the logins it accepts
+fn backend() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let logins = Arc::new(AtomicUsize::new(0));
+    let counted = logins.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let counted = counted.clone();
+            thread::spawn(move || serve(stream.unwrap(), &counted));
+        }
+    });
+    (addr, logins)
+}
+
+fn serve(mut stream: TcpStream, logins: &AtomicUsize) {
+    let greeting = HandshakeV10 {
+        server_version: "8.0.36".to_string(),
+        connection_id: 7,
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    stream.write_all(&greeting.to_packet(0).bytes).unwrap();
+    read_packet(&mut stream).unwrap();
+    stream.write_all(&Packet::new(2, &[0x00, 0, 0, 2, 0, 0, 0]).bytes).unwrap();
+    logins.fetch_add(1, Ordering::SeqCst);
+    while let Ok(query) = read_packet(&mut stream) {
+        let response = if query.payload() == b"\x03SELECT nope" {
+            vec![Packet::new(1, b"\xff\x7a\x04#42S02Table 'routing.nope' doesn't exist")]
+        } else {
+            let rows = vec![
+                vec![Some(b"acme".to_vec()), Some(b"shard1".to_vec())],
+                vec![Some(b"globex".to_vec()), None],
+            ];
+            text_result_set(&[column("tenant"), column("shard")], &rows, CLIENT_PROTOCOL_41)
+        };
+        for p in response {
+            stream.write_all(&p.bytes).unwrap();
+        }
+    }
+}
+
+fn config() -> SideChannelConfig {
+    SideChannelConfig {
+        backend_user: "lookup".to_string(),
+        backend_password: "secret".to_string(),
+        timeout_ms: 1000,
+        max_concurrent: 2,
+        max_idle: 1,
+        max_rows: 10,
+        max_lifetime_secs: None,
+        max_idle_secs: None,
+    }
+}
+
+#[test]
+fn side_queries_reuse_their_connections() {
+    let (addr, logins) = backend();
+    let channel = SideChannel::new(&config());
+    let result = channel.query(addr, "SELECT tenant, shard FROM routing.tenants").wait().unwrap();
+    assert_eq!(result.rows.len(), 2);
+    assert_eq!(result.value(0, "SHARD"), Some("shard1".to_string()));
+    assert_eq!(result.value(1, "shard"), None);
+    assert_eq!(result.value(0, "missing"), None);
+
+    let again = channel.query_blocking(addr, "SELECT tenant, shard FROM routing.tenants").unwrap();
+    assert_eq!(again, result);
+    assert_eq!(logins.load(Ordering::SeqCst), 1);
+    assert_eq!(channel.idle_connections(), 1);
+}
+
+#[test]
+fn errors_and_large_results_fail_the_query() {
+    let (addr, _) = backend();
+    let channel = SideChannel::new(&config());
+    let e = channel.query_blocking(addr, "SELECT nope").unwrap_err();
+    assert!(e.to_string().contains("1146 (42S02): Table 'routing.nope' doesn't exist"), "{}", e);
+
+    let channel = SideChannel::new(&SideChannelConfig { max_rows: 1, ..config() });
+    let e = channel.query_blocking(addr, "SELECT tenant, shard FROM routing.tenants").unwrap_err();
+    assert!(e.to_string().contains("more than 1 rows"), "{}", e);
+    assert_eq!(channel.idle_connections(), 0);
+}
+
+#[test]
+fn connections_kept_too_long_are_replaced() {
+    let (addr, logins) = backend();
+    let channel = SideChannel::new(&SideChannelConfig { max_idle_secs: Some(1), ..config() });
+    channel.query_blocking(addr, "SELECT tenant, shard FROM routing.tenants").unwrap();
+    channel.query_blocking(addr, "SELECT tenant, shard FROM routing.tenants").unwrap();
+    assert_eq!(logins.load(Ordering::SeqCst), 1);
+    thread::sleep(Duration::from_millis(1100));
+    channel.query_blocking(addr, "SELECT tenant, shard FROM routing.tenants").unwrap();
+    assert_eq!(logins.load(Ordering::SeqCst), 2);
+    assert_eq!(channel.idle_connections(), 1);
+}
@@ -0,0 +1,237 @@
+extern crate mysql_proxy;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use mysql_proxy::audit::AuditLog;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::events::{Event, EventBus};
+use mysql_proxy::sink::{EventSink, EventSinkConfig, Message, Publisher};
+
+fn config() -> EventSinkConfig {
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+
+        [event_sink]
+        nats = "127.0.0.1:4222"
+        events = ["auth_failed"]
+        audit_subject = "proxy.audit"
+        retry_ms = 1
+        max_retry_ms = 4
+    "#).unwrap();
+    config.event_sink.unwrap()
+}
+
+/// Fails the first `failures` batches with `error`, then passes the others on
+struct Flaky {
+    failures: usize,
+    error: io::ErrorKind,
+    attempts: Arc<Mutex<usize>>,
+    published: Sender<Vec<Message>>,
+}
+
+impl Publisher for Flaky {
+
+    fn publish(&mut self, batch: &[Message]) -> io::Result<()> {
+        *self.attempts.lock().unwrap() += 1;
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(io::Error::new(self.error, "failed"));
+        }
+        self.published.send(batch.to_vec()).unwrap();
+        Ok(())
+    }
+}
+
+fn flaky(failures: usize) -> (Flaky, Arc<Mutex<usize>>, Receiver<Vec<Message>>) {
+    let attempts = Arc::new(Mutex::new(0));
+    let (published, receiver) = mpsc::channel();
+    (Flaky { failures, error: io::ErrorKind::ConnectionRefused, attempts: attempts.clone(), published }, attempts, receiver)
+}
+
+fn auth_failed() -> Event {
+    Event::AuthFailed { client: "203.0.113.9:51234".parse().unwrap(), user: Some("root".to_string()), reason: "wrong password".to_string() }
+}
+
+#[test]
+fn failed_batches_are_retried() {
+    let (publisher, attempts, published) = flaky(2);
+    let sink = EventSink::new(&config(), Box::new(publisher)).unwrap();
+    let bus = EventBus::new();
+    sink.subscribe(&bus);
+    bus.publish(auth_failed());
+    bus.publish(Event::BackendMarkedUp { backend: "db1:3306".to_string() });
+
+    let batch = published.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(*attempts.lock().unwrap(), 3);
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].subject, "mysql_proxy.events.auth_failed");
+    let payload = String::from_utf8(batch[0].payload.clone()).unwrap();
+    assert!(payload.starts_with(r#"{"client":"203.0.113.9:51234","event":"auth_failed","reason":"wrong password","timestamp_ms":"#), "{}", payload);
+}
+
+#[test]
+fn batches_that_cant_succeed_are_dropped_rather_than_retried() {
+    let (mut publisher, attempts, published) = flaky(1);
+    publisher.error = io::ErrorKind::InvalidInput;
+    let sink = EventSink::new(&config(), Box::new(publisher)).unwrap();
+    sink.send_audit("first");
+    let batch = published.recv_timeout(Duration::from_millis(200));
+    assert!(batch.is_err(), "{:?}", batch);
+    assert_eq!(*attempts.lock().unwrap(), 1);
+
+    sink.send_audit("second");
+    let batch = published.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(batch[0].payload, b"second");
+    assert_eq!(*attempts.lock().unwrap(), 2);
+}
+
+#[test]
+fn audit_records_are_published_as_written() {
+    let (publisher, _, published) = flaky(0);
+    let sink = EventSink::new(&config(), Box::new(publisher)).unwrap();
+    let written = Arc::new(Mutex::new(vec![]));
+    let log = AuditLog::new(Box::new(SharedBuf(written.clone()))).with_sink(sink);
+    log.record("app", "DELETE FROM orders").unwrap();
+
+    let batch = published.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(batch[0].subject, "proxy.audit");
+    let mut line = batch[0].payload.clone();
+    line.push(b'\n');
+    assert_eq!(line, *written.lock().unwrap());
+}
+
+#[test]
+fn messages_beyond_the_queue_are_dropped() {
+    let mut config = config();
+    config.max_queued = 1;
+    config.batch_size = 1;
+    // a publisher that never gets through
+    let (publisher, _, _published) = flaky(usize::MAX);
+    let sink = EventSink::new(&config, Box::new(publisher)).unwrap();
+    for _ in 0..10 {
+        sink.send_event(&auth_failed());
+    }
+    // one taken by the publishing thread, one queued
+    assert!(sink.dropped() >= 8, "{}", sink.dropped());
+}
+
+#[test]
+fn event_sinks_are_validated() {
+    let mut config = config();
+    config.subject = "proxy.*".to_string();
+    assert_eq!(config.validate(), Err("invalid subject 'proxy.*'".to_string()));
+
+    let config = ProxyConfig::parse(r#"
+        [groups.primary]
+        backends = ["127.0.0.1:3306"]
+
+        [event_sink]
+        nats = "127.0.0.1:4222"
+        events = ["auth_failed", "login"]
+    "#).unwrap();
+    let mut problems = vec!["Event sink: unknown event 'login', expected one of connection_opened, connection_closed, query_executed, backend_marked_down, backend_marked_up, auth_failed, protocol_violation, rule_matched, honeypot_login, honeypot_command".to_string()];
+    if !cfg!(feature = "nats") {
+        problems.push("Event sink: requires the 'nats' feature".to_string());
+    }
+    assert_eq!(config.validate(), problems);
+}
+
+#[cfg(feature = "nats")]
+#[test]
+fn events_are_published_to_nats() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = config();
+    config.nats = server.local_addr().unwrap().to_string();
+    let sink = EventSink::open(&config).unwrap();
+    sink.send_event(&auth_failed());
+
+    let (stream, _) = server.accept().unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    writer.write_all(b"INFO {\"server_id\":\"test\"}\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("CONNECT {"), "{}", line);
+
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    assert_eq!(parts.next(), Some("PUB"));
+    assert_eq!(parts.next(), Some("mysql_proxy.events.auth_failed"));
+    let len: usize = parts.next().unwrap().parse().unwrap();
+    let mut payload = vec![0; len + 2];
+    reader.read_exact(&mut payload).unwrap();
+    assert!(payload.starts_with(b"{\"client\""));
+
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "PING\r\n");
+    writer.write_all(b"PONG\r\n").unwrap();
+}
+
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuf {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nats")]
+#[test]
+fn nats_limits_are_kept_to() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = config();
+    config.nats = server.local_addr().unwrap().to_string();
+    let sink = EventSink::open(&config).unwrap();
+    sink.send_audit(&"x".repeat(65));
+    sink.send_audit("fits");
+
+    let (stream, _) = server.accept().unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    writer.write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":64}\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("CONNECT {"), "{}", line);
+
+    // the message over max_payload is left out of its batch
+    let mut lines = vec![];
+    while lines.last().map(|l| l != "PING\r\n").unwrap_or(true) {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        lines.push(line.clone());
+    }
+    assert_eq!(lines.iter().filter(|l| l.starts_with("PUB ")).cloned().collect::<Vec<_>>(), vec!["PUB proxy.audit 4\r\n".to_string()]);
+
+    // and a batch refused for a reason retrying doesn't change isn't sent again
+    writer.write_all(b"PONG\r\n").unwrap();
+    sink.send_audit("forbidden");
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "PUB proxy.audit 9\r\n");
+    reader.read_exact(&mut [0; 11]).unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "PING\r\n");
+    writer.write_all(b"-ERR 'Permissions Violation for Publish to \"proxy.audit\"'\r\n").unwrap();
+    server.set_nonblocking(true).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
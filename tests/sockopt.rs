@@ -0,0 +1,76 @@
+extern crate futures;
+extern crate libc;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::sockopt::{Keepalive, SocketOptions};
+
+/// A connected pair of streams
+fn connection(core: &mut Core) -> (TcpStream, TcpStream) {
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let client = TcpStream::connect(&listener.local_addr().unwrap(), &handle);
+    let accepted = listener.incoming().take(1).collect();
+    let (client, mut accepted) = core.run(client.join(accepted)).unwrap();
+    (client, accepted.remove(0).0)
+}
+
+fn tcp_option(stream: &TcpStream, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    assert_eq!(ret, 0);
+    value
+}
+
+#[test]
+fn options_are_applied_to_connections() {
+    let mut core = Core::new().unwrap();
+    let (client, _server) = connection(&mut core);
+    let options = SocketOptions {
+        keepalive: Some(Keepalive { time_secs: 60, interval_secs: Some(10), probes: Some(4) }),
+        nodelay: Some(true),
+        send_buffer_size: Some(64 * 1024),
+        recv_buffer_size: None,
+        linger_secs: Some(0),
+    };
+    options.apply(&client).unwrap();
+    assert!(client.nodelay().unwrap());
+    assert_eq!(client.keepalive().unwrap(), Some(Duration::from_secs(60)));
+    assert_eq!(client.linger().unwrap(), Some(Duration::from_secs(0)));
+    // the kernel doubles what it's asked for, for its own bookkeeping
+    assert!(client.send_buffer_size().unwrap() >= 64 * 1024);
+    if cfg!(target_os = "linux") {
+        assert_eq!(tcp_option(&client, libc::TCP_KEEPINTVL), 10);
+        assert_eq!(tcp_option(&client, libc::TCP_KEEPCNT), 4);
+    }
+}
+
+#[test]
+fn unset_options_keep_the_system_defaults() {
+    let mut core = Core::new().unwrap();
+    let (client, _server) = connection(&mut core);
+    let (nodelay, keepalive, linger) = (client.nodelay().unwrap(), client.keepalive().unwrap(), client.linger().unwrap());
+    SocketOptions::default().apply(&client).unwrap();
+    assert_eq!((client.nodelay().unwrap(), client.keepalive().unwrap(), client.linger().unwrap()), (nodelay, keepalive, linger));
+}
+
+#[test]
+fn options_are_read_from_the_configuration() {
+    let config = ProxyConfig::parse("[client_socket]\nnodelay = true\nkeepalive = { time_secs = 30 }\n").unwrap();
+    assert_eq!(config.client_socket, SocketOptions {
+        keepalive: Some(Keepalive { time_secs: 30, interval_secs: None, probes: None }),
+        nodelay: Some(true),
+        ..SocketOptions::default()
+    });
+}
@@ -0,0 +1,149 @@
+extern crate mysql_proxy;
+
+use std::sync::Arc;
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::rules::{self, RuleAction, TableRule, TableRulesHandler, ER_TABLEACCESS_DENIED_ERROR};
+use mysql_proxy::sql::{self, PreparedFrom, Statement, StatementKind, TableRef};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+fn table(schema: Option<&str>, table: &str) -> TableRef {
+    TableRef { schema: schema.map(|s| s.to_string()), table: table.to_string() }
+}
+
+#[test]
+fn table_rules_match_the_tables_a_statement_uses() {
+    let statement = Statement::parse("SELECT c.number FROM orders o, `payments`.cards AS c \
+                                      JOIN (SELECT id FROM users) u ON u.id = o.user_id WHERE x = 'FROM secret'");
+    assert_eq!(statement.command, "SELECT");
+    assert_eq!(statement.tables, vec![table(None, "orders"), table(Some("payments"), "cards"), table(None, "users")]);
+    assert_eq!(Statement::parse("/*!40000 DELETE FROM payments.cards */").tables, vec![table(Some("payments"), "cards")]);
+    assert_eq!(Statement::parse("INSERT INTO t (a) VALUES (1) ON DUPLICATE KEY UPDATE a = 2").tables, vec![table(None, "t")]);
+    assert_eq!(Statement::parse("REPLACE DELAYED s.t VALUES (1)").tables, vec![table(Some("s"), "t")]);
+    assert_eq!(Statement::parse("INSERT LOW_PRIORITY IGNORE INTO t SELECT * FROM u").tables, vec![table(None, "t"), table(None, "u")]);
+
+    let rules = vec![
+        TableRule { table: "payments.cards".to_string(), columns: vec![], allow_users: vec!["billing_svc".to_string()], action: RuleAction::Block },
+        TableRule { table: "*.users".to_string(), columns: vec!["password_hash".to_string()], allow_users: vec![], action: RuleAction::Audit },
+    ];
+    let check = |user: &str, schema: Option<&str>, sql: &str| rules::check(&rules, user, schema, &Statement::parse(sql)).map(|v| v.action);
+    assert_eq!(check("app", None, "select * from payments.cards"), Some(RuleAction::Block));
+    assert_eq!(check("app", Some("payments"), "select number from cards"), Some(RuleAction::Block));
+    assert_eq!(check("app", Some("shop"), "select number from cards"), None);
+    assert_eq!(check("billing_svc", None, "select * from payments.cards"), None);
+    assert_eq!(check("app", Some("shop"), "select name from users"), None);
+    assert_eq!(check("app", Some("shop"), "select * from users"), Some(RuleAction::Audit));
+}
+
+#[test]
+fn multi_statements_are_split_outside_literals_and_comments() {
+    assert_eq!(sql::split("SELECT 1; USE payments ;SELECT * FROM cards;"), vec!["SELECT 1", " USE payments ", "SELECT * FROM cards"]);
+    assert_eq!(sql::split("SELECT ';', `a;b` /* ; */ FROM t; -- ;\n"), vec!["SELECT ';', `a;b` /* ; */ FROM t"]);
+    assert_eq!(sql::split(";;"), Vec::<&str>::new());
+}
+
+#[test]
+fn table_rules_follow_use_through_multi_statements() {
+    let rules = Arc::new(vec![
+        TableRule { table: "payments.cards".to_string(), columns: vec![], allow_users: vec!["billing_svc".to_string()], action: RuleAction::Block },
+    ]);
+    let denied = || Action::Error {
+        code: ER_TABLEACCESS_DENIED_ERROR,
+        state: *b"42000",
+        msg: "SELECT command denied to user 'app'@'10.1.2.3' for table 'cards'".to_string(),
+    };
+    let mut tester = HandlerTester::new(TableRulesHandler::new(rules.clone(), "app", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT * FROM payments.cards")), denied());
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT 1; USE payments; SELECT * FROM cards")), denied());
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE shop; SELECT * FROM cards")), Action::Forward);
+
+    // the schema of a refused query's USE isn't kept
+    let mut tester = HandlerTester::new(TableRulesHandler::new(rules.clone(), "app", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE payments; SELECT * FROM payments.cards")), denied());
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT * FROM cards")), Action::Forward);
+
+    // nor can a USE the rules can't read hide the schema of the statements after it
+    match tester.request(Packet::new(0, b"\x03USE; SELECT * FROM cards")) {
+        Action::Error { code, .. } => assert_eq!(code, 1235),
+        action => panic!("{:?}", action),
+    }
+    let mut tester = HandlerTester::new(TableRulesHandler::new(rules, "billing_svc", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03SELECT 1; USE payments; SELECT * FROM cards")), Action::Forward);
+}
+
+#[test]
+fn table_rules_check_prepared_statements_and_handlers() {
+    let rules = Arc::new(vec![
+        TableRule { table: "payments.cards".to_string(), columns: vec![], allow_users: vec!["billing_svc".to_string()], action: RuleAction::Block },
+    ]);
+    let denied = |command: &str| Action::Error {
+        code: ER_TABLEACCESS_DENIED_ERROR,
+        state: *b"42000",
+        msg: format!("{} command denied to user 'app'@'10.1.2.3' for table 'cards'", command),
+    };
+    let mut tester = HandlerTester::new(TableRulesHandler::new(rules.clone(), "app", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03PREPARE s FROM 'SELECT * FROM payments.cards'")), denied("SELECT"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03USE payments; PREPARE s FROM \"SELECT * FROM `cards`\"")), denied("SELECT"));
+    assert_eq!(tester.request(Packet::new(0, b"\x03PREPARE s FROM 'SELECT * FROM shop.orders'")), Action::Forward);
+    assert_eq!(tester.request(Packet::new(0, b"\x03HANDLER payments.cards OPEN")), denied("HANDLER"));
+    for sql in &["PREPARE s FROM @q", "PREPARE s FROM 'SELECT * FROM pay' 'ments.cards'"] {
+        match tester.request(Packet::new(0, format!("\x03{}", sql).as_bytes())) {
+            Action::Error { code, .. } => assert_eq!(code, 1235, "{}", sql),
+            action => panic!("{}: {:?}", sql, action),
+        }
+    }
+
+    let mut tester = HandlerTester::new(TableRulesHandler::new(rules, "billing_svc", "10.1.2.3", Forward));
+    assert_eq!(tester.request(Packet::new(0, b"\x03PREPARE s FROM @q")), Action::Forward);
+    assert_eq!(tester.request(Packet::new(0, b"\x03PREPARE s FROM 'SELECT * FROM payments.cards'")), Action::Forward);
+}
+
+#[test]
+fn prepared_statement_text_is_read_from_literals() {
+    assert_eq!(sql::prepared_from("PREPARE s FROM 'SELECT ''a'', \\'b\\''"), Some(PreparedFrom::Literal("SELECT 'a', 'b'".to_string())));
+    assert_eq!(sql::prepared_from("prepare s from \"SELECT 1\" "), Some(PreparedFrom::Literal("SELECT 1".to_string())));
+    assert_eq!(sql::prepared_from("PREPARE s FROM @q"), Some(PreparedFrom::Expression));
+    assert_eq!(sql::prepared_from("PREPARE s FROM 'SELECT' ' 1'"), Some(PreparedFrom::Expression));
+    assert_eq!(sql::prepared_from("EXECUTE s"), None);
+    assert_eq!(sql::prepared_from("SELECT 'PREPARE s FROM'"), None);
+}
+
+#[test]
+fn fingerprints_leave_out_values() {
+    let cases = [
+        ("SELECT * FROM t WHERE id = 42 AND name = 'it''s' -- trailing", "SELECT * FROM t WHERE id = ? AND name = ?"),
+        ("select a,\n  b from `t 1` where x in (1, 2.5e-3, 0x1F) /* hint */ ;", "select a, b from `t 1` where x in (...)"),
+        ("INSERT INTO t (a, b) VALUES (1, 'x'), (2, \"y\"),(3,'z')", "INSERT INTO t (a, b) VALUES (...)"),
+        ("SELECT t2.c FROM t2 LIMIT 10", "SELECT t2.c FROM t2 LIMIT ?"),
+    ];
+    for &(sql, expected) in &cases {
+        assert_eq!(sql::fingerprint(sql), expected, "{}", sql);
+    }
+}
+
+#[test]
+fn scrub_only_replaces_literals() {
+    let cases = [
+        ("SELECT * FROM t2 WHERE email = 'a@b.c' AND id IN (1, 0x1F)", "SELECT * FROM t2 WHERE email = ? AND id IN (?, ?)"),
+        ("UPDATE `t 1` SET note = \"it\"\"s\", n = n - 2.5e-3 -- 'kept'\n", "UPDATE `t 1` SET note = ?, n = n - ? -- 'kept'\n"),
+        ("SELECT /*! SQL_NO_CACHE 'x' */ 1", "SELECT /*! SQL_NO_CACHE ? */ ?"),
+    ];
+    for &(sql, expected) in &cases {
+        assert_eq!(sql::scrub(sql), expected, "{}", sql);
+    }
+}
+
+#[test]
+fn statements_are_classified() {
+    let corpus = include_str!("corpus/statements.tsv");
+    for line in corpus.lines().filter(|l| !l.starts_with('#')) {
+        let fields: Vec<&str> = line.splitn(3, '\t').collect();
+        let (kind, access, sql) = (fields[0], fields[1], fields[2]);
+        assert_eq!(format!("{:?}", sql::classify(sql)), kind, "{}", sql);
+        assert_eq!(sql::is_read_only(sql), access == "read", "{}", sql);
+    }
+
+    let statement = Statement::parse("WITH t AS (SELECT id FROM a) DELETE FROM b WHERE id IN (SELECT id FROM t)");
+    assert_eq!((statement.kind, statement.read_only), (StatementKind::Delete, false));
+    assert!(statement.kind.modifies_data() && !StatementKind::Ddl.modifies_data());
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "ssh")]
+
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::read_to_end;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::upstream::connect_through;
+
+/// A stand-in for ssh that sends the tunnel its arguments, one per line, and exits with
+/// `status`
+fn fake_ssh(name: &str, status: u8) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mysql-proxy-fake-ssh-{}-{}", name, std::process::id()));
+    fs::write(&path, format!("#!/bin/sh\nfor arg in \"$@\"; do echo \"$arg\"; done\necho oops >&2\nexit {}\n", status)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn tunnels_run_ssh_to_the_jump_host() {
+    let ssh = fake_ssh("args", 0);
+    let config = ProxyConfig::parse(&format!(r#"
+        [upstream]
+        kind = "ssh"
+        addr = "jump.internal:2222"
+        username = "tunnel"
+        identity_file = "/etc/mysql-proxy/id_ed25519"
+        known_hosts = "/etc/mysql-proxy/known_hosts"
+        ssh_command = "{}"
+    "#, ssh.display())).unwrap();
+
+    let mut core = Core::new().unwrap();
+    let tunnel = connect_through(config.upstream.as_ref(), &"db.internal:3306".parse().unwrap(), &core.handle())
+        .and_then(|stream| read_to_end(stream, vec![]));
+    let (_, args) = core.run(tunnel).unwrap();
+    fs::remove_file(&ssh).unwrap();
+    assert_eq!(String::from_utf8(args).unwrap().lines().collect::<Vec<_>>(), vec![
+        "-W", "db.internal:3306", "-p", "2222",
+        "-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes", "-o", "StrictHostKeyChecking=yes",
+        "-i", "/etc/mysql-proxy/id_ed25519", "-o", "IdentitiesOnly=yes",
+        "-o", "UserKnownHostsFile=/etc/mysql-proxy/known_hosts",
+        "-l", "tunnel",
+        "jump.internal",
+    ]);
+}
+
+#[test]
+fn missing_ssh_clients_fail_the_connection() {
+    let config = ProxyConfig::parse(r#"
+        [upstream]
+        kind = "ssh"
+        addr = "jump.internal:22"
+        ssh_command = "/nonexistent/ssh"
+    "#).unwrap();
+    let core = Core::new().unwrap();
+    let e = connect_through(config.upstream.as_ref(), &"db.internal:3306".parse().unwrap(), &core.handle()).wait().unwrap_err();
+    assert!(e.to_string().starts_with("Failed to run ssh: "), "{}", e);
+}
@@ -0,0 +1,72 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::Packet;
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::pipeline::Correlator;
+use mysql_proxy::protocol::CLIENT_DEPRECATE_EOF;
+use mysql_proxy::statements::{Reply, StatementCache, ER_UNKNOWN_STMT_HANDLER};
+
+/// PREPARE_OK for a statement without columns or parameters
+fn prepare_ok(id: u32) -> Packet {
+    let mut payload = vec![0x00];
+    payload.extend_from_slice(&id.to_le_bytes());
+    payload.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+    Packet::new(1, &payload)
+}
+
+fn execute(id: u32) -> Packet {
+    let mut payload = vec![0x17];
+    payload.extend_from_slice(&id.to_le_bytes());
+    payload.extend_from_slice(&[0, 1, 0, 0, 0]);
+    Packet::new(0, &payload)
+}
+
+fn send(correlators: &mut [Correlator], backend: usize, packets: Vec<Packet>) -> Vec<Packet> {
+    for p in &packets {
+        correlators[backend].request(p);
+    }
+    packets
+}
+
+#[test]
+fn statements_follow_the_session_between_connections() {
+    let mut correlators = vec![Correlator::new(CLIENT_DEPRECATE_EOF), Correlator::new(CLIENT_DEPRECATE_EOF)];
+    let mut cache = StatementCache::new(0);
+
+    // prepared on the first connection, which calls it 7
+    let prepare = || Packet::new(0, b"\x16DELETE FROM sessions");
+    assert_eq!(send(&mut correlators, 0, cache.request(prepare()).unwrap()), vec![prepare()]);
+    let answered = correlators[0].response(&prepare_ok(7));
+    assert_eq!(cache.response(prepare_ok(7), answered), Reply::Client(prepare_ok(1)));
+    assert_eq!(send(&mut correlators, 0, cache.request(execute(1)).unwrap()), vec![execute(7)]);
+    let answered = correlators[0].response(&ok_packet(1));
+    assert_eq!(cache.response(ok_packet(1), answered), Reply::Client(ok_packet(1)));
+    assert!(cache.idle());
+
+    // the second connection prepares it first, calling it 3
+    assert!(cache.switch(1).is_empty());
+    let sent = send(&mut correlators, 1, cache.request(execute(1)).unwrap());
+    assert_eq!(sent, vec![prepare()]);
+    assert!(cache.holding());
+    let answered = correlators[1].response(&prepare_ok(3));
+    let held = match cache.response(prepare_ok(3), answered) {
+        Reply::Server(p) => p,
+        reply => panic!("unexpected {:?}", reply),
+    };
+    assert_eq!(held, execute(3));
+    send(&mut correlators, 1, vec![held]);
+    let answered = correlators[1].response(&ok_packet(1));
+    assert_eq!(cache.response(ok_packet(1), answered), Reply::Client(ok_packet(1)));
+
+    // closing it closes it on the first connection once the session is back
+    assert_eq!(cache.request(Packet::new(0, b"\x19\x01\x00\x00\x00")).unwrap(), vec![Packet::new(0, b"\x19\x03\x00\x00\x00")]);
+    assert!(cache.is_empty());
+    assert_eq!(cache.switch(0), vec![Packet::new(0, b"\x19\x07\x00\x00\x00")]);
+    match cache.request(execute(1)) {
+        Err(p) => {
+            assert_eq!(&p.payload()[1..3], &ER_UNKNOWN_STMT_HANDLER.to_le_bytes());
+            assert!(String::from_utf8_lossy(p.payload()).ends_with("Unknown prepared statement handler (1) given to mysqld_stmt_execute"));
+        },
+        Ok(sent) => panic!("unexpected {:?}", sent),
+    }
+}
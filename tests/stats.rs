@@ -0,0 +1,72 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::Packet;
+use mysql_proxy::admin::{Admin, Reply};
+use mysql_proxy::balance::{BackendPool, BackendWeights};
+use mysql_proxy::management::Management;
+use mysql_proxy::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+use mysql_proxy::stats::{Histogram, Stats, StatsHandler};
+use mysql_proxy::testing::{Forward, HandlerTester, Step};
+
+/// Run `SELECT name FROM t` answered with `rows` rows
+fn select(stats: &Stats, rows: usize) {
+    let capabilities = CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF;
+    let handler = StatsHandler::new("app", Forward).with_capabilities(capabilities).with_stats(stats.clone());
+    let mut tester = HandlerTester::new(handler).with_backend_capabilities(capabilities);
+    let mut steps = vec![
+        Step::Request(Packet::new(0, b"\x03SELECT name FROM t")),
+        Step::Response(Packet::new(1, &[0x01])),
+        Step::Response(Packet::new(2, b"\x03def\x00\x01t\x01t\x04name\x04name\x0c\x21\x00\x10\x00\x00\x00\xfd\x00\x00\x00\x00\x00")),
+    ];
+    for i in 0..rows {
+        steps.push(Step::Response(Packet::new(3 + i as u8, b"\x05alice")));
+    }
+    steps.push(Step::Response(Packet::new(3 + rows as u8, &[0xfe, 0, 0, 2, 0, 0, 0])));
+    tester.run(steps);
+}
+
+#[test]
+fn histograms_bound_their_quantiles() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+    for value in &[0, 3, 3, 3, 5000, 10_000_000] {
+        histogram.record(*value);
+    }
+    assert_eq!(histogram.count(), 6);
+    assert_eq!(histogram.quantile(0.1), Some(0));
+    assert_eq!(histogram.quantile(0.5), Some(4));
+    assert_eq!(histogram.quantile(0.8), Some(16384));
+    assert_eq!(histogram.quantile(1.0), Some(10_000_000));
+
+    let mut merged = Histogram::default();
+    merged.merge(&histogram);
+    merged.merge(&histogram);
+    assert_eq!((merged.count(), merged.sum, merged.max), (12, 2 * histogram.sum, 10_000_000));
+}
+
+#[test]
+fn result_sizes_are_kept_by_fingerprint() {
+    let stats = Stats::new();
+    select(&stats, 1);
+    select(&stats, 2);
+    select(&stats, 40);
+    let digest = &stats.snapshot().digests[0];
+    assert_eq!(digest.rows.count(), 3);
+    assert_eq!((digest.rows.sum, digest.rows.max), (43, 40));
+    assert_eq!(digest.bytes.max, 5 + 36 + 40 * 10 + 11);
+
+    let metrics = stats.snapshot().prometheus();
+    assert!(metrics.contains("# TYPE mysql_proxy_digest_result_rows histogram\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_digest_result_rows_bucket{digest=\"SELECT name FROM t\",le=\"4\"} 2\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_digest_result_rows_bucket{digest=\"SELECT name FROM t\",le=\"+Inf\"} 3\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_digest_result_bytes_sum{digest=\"SELECT name FROM t\"} "), "{}", metrics);
+
+    // and listed on the admin interface
+    let management = Management::new(BackendPool::default(), BackendWeights::new()).with_stats(Some(stats.clone()));
+    let reply = Admin::new(management).execute("SELECT count, rows_p50, rows_max FROM stats_digests").unwrap();
+    let row = |values: &[&str]| values.iter().map(|v| Some(v.to_string())).collect::<Vec<_>>();
+    assert_eq!(reply, Reply::Rows {
+        columns: vec!["count".to_string(), "rows_p50".to_string(), "rows_max".to_string()],
+        rows: vec![row(&["3", "4", "40"])],
+    });
+}
@@ -0,0 +1,158 @@
+extern crate mysql_proxy;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mysql_proxy::{Action, Packet};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::protocol::{CLIENT_DEPRECATE_EOF, CLIENT_PROTOCOL_41};
+use mysql_proxy::tap::{csv_field, Tap, TapConfig, TapHandler, TapWriter};
+use mysql_proxy::testing::{Forward, HandlerTester};
+
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+
+    /// What has been written once it has `lines` lines
+    fn lines(&self, lines: usize) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            if text.lines().count() >= lines || Instant::now() > deadline {
+                return text;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+fn config(query: &str, users: &[&str]) -> TapConfig {
+    TapConfig {
+        query: query.to_string(),
+        users: users.iter().map(|u| u.to_string()).collect(),
+        path: "unused.csv".into(),
+        max_queued_rows: 100,
+    }
+}
+
+fn column(seq: u8, name: &str) -> Packet {
+    let mut payload = b"\x03def\x04shop\x06orders\x06orders".to_vec();
+    for _ in 0..2 {
+        payload.push(name.len() as u8);
+        payload.extend_from_slice(name.as_bytes());
+    }
+    payload.extend_from_slice(&[0x0c, 0x21, 0x00, 0x0b, 0x00, 0x00, 0x00, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    Packet::new(seq, &payload)
+}
+
+fn ok_as_eof(seq: u8) -> Packet {
+    Packet::new(seq, &[0xfe, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00])
+}
+
+#[test]
+fn rows_of_matching_queries_are_written_as_csv() {
+    let buf = Shared::default();
+    let writer = TapWriter::new(Box::new(buf.clone()), 100, true).unwrap();
+    let taps = vec![Tap::new(&config("SELECT id, note FROM shop.orders WHERE id > 10", &["reports"]), writer)];
+    let handler = TapHandler::new(taps, "reports", Forward).with_capabilities(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+    let mut tester = HandlerTester::new(handler).with_backend_capabilities(CLIENT_PROTOCOL_41 | CLIENT_DEPRECATE_EOF);
+
+    // another query isn't tapped
+    tester.request(Packet::new(0, b"\x03SELECT 1"));
+    tester.response(Packet::new(1, &[0x01]));
+    tester.response(column(2, "1"));
+    tester.response(Packet::new(3, b"\x011"));
+    tester.response(ok_as_eof(4));
+
+    tester.request(Packet::new(0, b"\x03select id, note from shop.orders where id > 500"));
+    tester.response(Packet::new(1, &[0x02]));
+    tester.response(column(2, "id"));
+    tester.response(column(3, "note"));
+    tester.response(Packet::new(4, b"\x03501\x0ahello, \"x\""));
+    tester.response(Packet::new(5, b"\x03502\xfb"));
+    tester.response(Packet::new(6, b"\x03503\x00"));
+    tester.response(ok_as_eof(7));
+    assert_eq!(tester.in_flight(), 0);
+
+    let text = buf.lines(4);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "timestamp_ms,user,id,note");
+    let rows: Vec<&str> = lines[1..].iter().map(|l| l.split_once(',').unwrap().1).collect();
+    assert_eq!(rows, ["reports,501,\"hello, \"\"x\"\"\"", "reports,502,", "reports,503,\"\""]);
+}
+
+#[test]
+fn taps_match_by_fingerprint_and_user() {
+    let writer = TapWriter::new(Box::new(io::sink()), 1, false).unwrap();
+    let tap = Tap::new(&config("SELECT * FROM t WHERE id = 1", &["reports"]), writer.clone());
+    assert!(tap.matches("reports", "select *  from t where id = 42"));
+    assert!(!tap.matches("reports", "SELECT * FROM t WHERE name = 'x'"));
+    assert!(!tap.matches("app", "SELECT * FROM t WHERE id = 2"));
+    assert!(Tap::new(&config("SELECT 1", &[]), writer).matches("anyone", "SELECT 2"));
+}
+
+/// Blocks writing until told to go on
+struct Gate(Receiver<()>);
+
+impl Write for Gate {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.0.recv();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn rows_beyond_the_queue_are_dropped() {
+    let (open, gate) = mpsc::channel();
+    let writer = TapWriter::new(Box::new(Gate(gate)), 1, false).unwrap();
+    let taps = vec![Tap::new(&config("SELECT id FROM t", &[]), writer.clone())];
+    let mut tester = HandlerTester::new(TapHandler::new(taps, "app", Forward)).with_backend_capabilities(CLIENT_PROTOCOL_41);
+    tester.request(Packet::new(0, b"\x03SELECT id FROM t"));
+    tester.response(Packet::new(1, &[0x01]));
+    tester.response(column(2, "id"));
+    tester.response(Packet::new(3, &[0xfe, 0x00, 0x00, 0x02, 0x00]));
+    for i in 0..6 {
+        // the session goes on while the writer is stuck
+        assert_eq!(tester.response(Packet::new(4 + i, b"\x011")), Action::Forward);
+    }
+    assert!(writer.dropped() >= 4, "{}", writer.dropped());
+    drop(open);
+}
+
+#[test]
+fn csv_fields_are_quoted_when_needed() {
+    assert_eq!(csv_field(None), "");
+    assert_eq!(csv_field(Some(b"")), "\"\"");
+    assert_eq!(csv_field(Some(b"plain")), "plain");
+    assert_eq!(csv_field(Some(b"a\nb")), "\"a\nb\"");
+}
+
+#[test]
+fn taps_are_validated() {
+    let config = ProxyConfig::parse(r#"
+        [[taps]]
+        query = " "
+        path = "taps.csv"
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["Tap: empty query".to_string()]);
+}
@@ -0,0 +1,65 @@
+extern crate mysql_proxy;
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::tarpit::{Admission, Tarpit, TarpitConfig};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn failed_logins_slow_down_then_block_an_address() {
+    let tarpit = Tarpit::new(TarpitConfig { max_failures: 5, delay_ms: 100, max_delay_ms: 500, ..TarpitConfig::default() });
+    let attacker = ip("203.0.113.9");
+    assert_eq!(tarpit.admit(attacker), Admission::Now);
+
+    let mut delays = vec![];
+    for _ in 0..4 {
+        tarpit.failed(attacker);
+        delays.push(tarpit.admit(attacker));
+    }
+    assert_eq!(delays, [100, 200, 400, 500].iter().map(|&ms| Admission::After(Duration::from_millis(ms))).collect::<Vec<_>>());
+
+    tarpit.failed(attacker);
+    assert_eq!(tarpit.admit(attacker),
+               Admission::Blocked("Host '203.0.113.9' is blocked because of many connection errors".to_string()));
+    // other addresses aren't affected
+    assert_eq!(tarpit.admit(ip("203.0.113.10")), Admission::Now);
+}
+
+#[test]
+fn a_successful_login_clears_failures() {
+    let tarpit = Tarpit::new(TarpitConfig::default());
+    let client = ip("10.1.2.3");
+    tarpit.failed(client);
+    tarpit.failed(client);
+    tarpit.succeeded(client);
+    assert_eq!(tarpit.admit(client), Admission::Now);
+}
+
+#[test]
+fn exempt_networks_are_never_slowed_down() {
+    let config = ProxyConfig::parse(r#"
+        [tarpit]
+        max_failures = 1
+        exempt = ["10.0.5.0/24"]
+    "#).unwrap();
+    let tarpit = Tarpit::new(config.tarpit.unwrap());
+    tarpit.failed(ip("10.0.5.20"));
+    assert_eq!(tarpit.admit(ip("10.0.5.20")), Admission::Now);
+    tarpit.failed(ip("10.0.6.20"));
+    assert!(matches!(tarpit.admit(ip("10.0.6.20")), Admission::Blocked(_)));
+}
+
+#[test]
+fn invalid_tarpit_settings_are_reported() {
+    let config = ProxyConfig::parse(r#"
+        [tarpit]
+        delay_ms = 1000
+        max_delay_ms = 100
+    "#).unwrap();
+    assert_eq!(config.validate(), vec!["Tarpit: max_delay_ms 100 is less than delay_ms 1000".to_string()]);
+}
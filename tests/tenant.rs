@@ -0,0 +1,22 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::tenant::TenantSchemas;
+
+#[test]
+fn rewrite_tenant_schemas() {
+    let tenant = TenantSchemas { prefix: "tenant_{user}_".to_string(), schemas: vec!["app".to_string()] }.for_user("42");
+    let cases = [
+        ("USE app", Some("USE `tenant_42_app`")),
+        ("select o.app, `app`.`orders`.id from app.orders o where x = 'app.orders' -- app.x",
+         Some("select o.app, `tenant_42_app`.`orders`.id from `tenant_42_app`.orders o where x = 'app.orders' -- app.x")),
+        ("DROP DATABASE IF EXISTS app", Some("DROP DATABASE IF EXISTS `tenant_42_app`")),
+        ("SHOW COLUMNS FROM orders IN app", Some("SHOW COLUMNS FROM orders IN `tenant_42_app`")),
+        ("SELECT * FROM app", None),
+        ("SELECT * FROM other.orders", None),
+    ];
+    for &(sql, expected) in &cases {
+        assert_eq!(tenant.rewrite_query(sql).as_ref().map(|s| &s[..]), expected, "{}", sql);
+    }
+    assert_eq!(tenant.to_client("tenant_42_app"), "app");
+    assert_eq!(tenant.to_client("tenant_43_app"), "tenant_43_app");
+}
@@ -0,0 +1,158 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate serde_json;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Action, ConnectionPhase, Packet, Pipe};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::dump::{Direction, PacketDump};
+use mysql_proxy::health::PingHandler;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::{duplex, read_script, DuplexEnd, Forward, HandlerTester, Step};
+
+#[test]
+fn responses_to_pipelined_commands_stay_in_order() {
+    let mut tester = HandlerTester::new(PingHandler::new(Forward));
+    let actions = tester.run(vec![
+        Step::Request(Packet::new(0, b"\x03DO SLEEP(1)")),
+        Step::Request(Packet::new(0, &[0x0e])),
+    ]);
+    assert_eq!(actions[0], Action::Forward);
+    assert_eq!(actions[1], Action::Respond(vec![ok_packet(1)]));
+    assert_eq!(tester.to_server(), vec![Packet::new(0, b"\x03DO SLEEP(1)")]);
+    // the ping is answered by the proxy, but only after the query before it
+    assert!(tester.to_client().is_empty());
+    assert_eq!(tester.in_flight(), 1);
+
+    assert_eq!(tester.response(ok_packet(1)), Action::Forward);
+    assert_eq!(tester.to_client(), vec![ok_packet(1), ok_packet(1)]);
+}
+
+#[test]
+fn scripts_from_packet_dumps() {
+    let packets = vec![
+        (Direction::Request, Packet::new(0, b"\x03SELECT 1")),
+        (Direction::Response, ok_packet(1)),
+        (Direction::Request, Packet::new(0, b"\x03SELECT 2")),
+    ];
+    let mut dump = String::new();
+    for (direction, p) in &packets {
+        for connection in &["c1", "c2"] {
+            dump.push_str(&serde_json::to_string(&PacketDump::new(connection, *direction, ConnectionPhase::Command, p)).unwrap());
+            dump.push('\n');
+        }
+    }
+
+    let script = read_script(Cursor::new(&dump), Some("c2")).unwrap();
+    assert_eq!(script, vec![
+        Step::Request(Packet::new(0, b"\x03SELECT 1")),
+        Step::Response(ok_packet(1)),
+        Step::Request(Packet::new(0, b"\x03SELECT 2")),
+    ]);
+    assert!(read_script(Cursor::new(&dump), None).is_err());
+
+    let mut tester = HandlerTester::new(Forward);
+    assert_eq!(tester.run(script), vec![Action::Forward, Action::Forward, Action::Forward]);
+    assert_eq!(tester.to_server().len(), 2);
+    assert_eq!(tester.to_client(), vec![ok_packet(1)]);
+}
+
+/// A client and a server connected through a pipe running on `core`, and how it ended
+struct Scenario {
+    core: Core,
+    client: DuplexEnd,
+    server: DuplexEnd,
+    ended: Rc<RefCell<Option<String>>>,
+}
+
+impl Scenario {
+
+    fn new(capacity: usize, drain: Option<Duration>) -> Self {
+        let core = Core::new().unwrap();
+        let (client, proxy_client) = duplex(capacity);
+        let (proxy_server, server) = duplex(capacity);
+        let pipe = Pipe::new(Rc::new(proxy_client), Rc::new(proxy_server), Forward)
+            .with_backend_capabilities(CLIENT_PROTOCOL_41);
+        let pipe = match drain {
+            Some(timeout) => pipe.with_drain_on_close(timeout, &core.handle()),
+            None => pipe,
+        };
+        let ended = Rc::new(RefCell::new(None));
+        let result = ended.clone();
+        core.handle().spawn(pipe.then(move |r| {
+            *result.borrow_mut() = Some(r.err().map(|e| e.to_string()).unwrap_or_default());
+            Ok(())
+        }));
+        Scenario { core, client, server, ended }
+    }
+
+    /// Turn the reactor until the pipe has done what it can
+    fn settle(&mut self) {
+        for _ in 0..10 {
+            self.core.turn(Some(Duration::from_millis(0)));
+        }
+    }
+}
+
+#[test]
+fn sessions_run_over_in_memory_connections() {
+    let mut scenario = Scenario::new(100, None);
+    scenario.client.send(&Packet::com_query("SELECT name FROM t"));
+    scenario.settle();
+    assert_eq!(scenario.server.recv(), vec![Packet::com_query("SELECT name FROM t")]);
+
+    // a result larger than the client takes at once is written to it in parts
+    let rows: Vec<Packet> = (0..10).map(|i| Packet::new(i + 1, &[b'x'; 40])).collect();
+    for row in &rows {
+        scenario.server.send(row);
+    }
+    scenario.settle();
+    assert_eq!(scenario.client.pending(), 100);
+    assert_eq!(scenario.server.pending(), 0);
+    let mut received = vec![];
+    while received.len() < rows.len() {
+        received.extend(scenario.client.recv());
+        scenario.settle();
+    }
+    assert_eq!(received, rows);
+
+    // and a client that stops reading holds the rest up
+    scenario.client.set_capacity(0);
+    scenario.client.send(&Packet::com_query("SELECT 2"));
+    scenario.settle();
+    scenario.server.recv();
+    scenario.server.send(&ok_packet(1));
+    scenario.settle();
+    assert_eq!(scenario.client.pending(), 0);
+    scenario.client.set_capacity(100);
+    scenario.settle();
+    assert_eq!(scenario.client.recv(), vec![ok_packet(1)]);
+
+    scenario.client.close();
+    scenario.settle();
+    assert!(scenario.server.is_closed());
+    assert_eq!(scenario.ended.borrow().as_ref().map(|e| &e[..]), Some("connection closed"));
+}
+
+#[test]
+fn timeouts_run_over_in_memory_connections() {
+    let mut scenario = Scenario::new(1024, Some(Duration::from_millis(50)));
+    scenario.client.send(&Packet::com_query("DO SLEEP(10)"));
+    scenario.settle();
+    scenario.client.close();
+    let started = Instant::now();
+    while scenario.ended.borrow().is_none() && started.elapsed() < Duration::from_secs(5) {
+        scenario.core.turn(Some(Duration::from_millis(10)));
+    }
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert!(scenario.ended.borrow().is_some());
+    assert!(scenario.server.is_closed());
+}
@@ -0,0 +1,167 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify, Spawn};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Action, Packet, PacketHandler, Pipe};
+use mysql_proxy::codec::ok_packet;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::stats::Stats;
+use mysql_proxy::timing::ExchangeTimer;
+
+/// Answers COM_PING itself and forwards everything else
+struct Pong;
+
+impl PacketHandler for Pong {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        match p.payload().first() {
+            Some(&0x0e) => Action::Respond(vec![ok_packet(1)]),
+            _ => Action::Forward,
+        }
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+}
+
+/// One end of an in-memory connection, which can't be written to while `blocked` is set
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+    blocked: Rc<Cell<bool>>,
+}
+
+impl Memory {
+
+    fn send(&self, p: &Packet) {
+        self.incoming.borrow_mut().extend(p.bytes.iter());
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.blocked.get() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+fn poll(pipe: &mut Spawn<Pipe<Pong>>) {
+    assert_eq!(pipe.poll_future_notify(&Arc::new(Ignore), 0).unwrap(), Async::NotReady);
+}
+
+#[test]
+fn exchanges_are_timed_by_stage() {
+    let stats = Stats::new();
+    let (client, server) = (Memory::default(), Memory::default());
+    let pipe = Pipe::from_streams(client.clone(), server.clone(), Pong)
+        .with_backend_capabilities(CLIENT_PROTOCOL_41)
+        .with_exchange_timing(ExchangeTimer::new(stats.clone()));
+    let mut pipe = executor::spawn(pipe);
+
+    // a query the backend takes a while to answer, for a client that's slow to read it
+    client.send(&Packet::new(0, b"\x03SELECT SLEEP(0.05)"));
+    poll(&mut pipe);
+    assert_eq!(server.outgoing.borrow().len(), 4 + 19);
+    thread::sleep(Duration::from_millis(50));
+    client.blocked.set(true);
+    server.send(&ok_packet(1));
+    poll(&mut pipe);
+    assert_eq!(stats.snapshot().exchanges.backend_us.count(), 0);
+    thread::sleep(Duration::from_millis(20));
+    client.blocked.set(false);
+    poll(&mut pipe);
+    assert_eq!(client.outgoing.borrow().len(), 11);
+
+    let exchanges = stats.snapshot().exchanges;
+    assert_eq!((exchanges.queued_us.count(), exchanges.backend_us.count(), exchanges.client_write_us.count()), (1, 1, 1));
+    assert!(exchanges.queued_us.sum < 50_000, "{:?}", exchanges);
+    assert!(exchanges.backend_us.sum >= 50_000, "{:?}", exchanges);
+    assert!(exchanges.client_write_us.sum >= 20_000, "{:?}", exchanges);
+
+    // commands the proxy answers itself never reach a backend, so aren't timed
+    client.send(&Packet::new(0, b"\x0e"));
+    poll(&mut pipe);
+    assert_eq!(client.outgoing.borrow().len(), 22);
+    assert_eq!(stats.snapshot().exchanges.backend_us.count(), 1);
+
+    let metrics = stats.snapshot().prometheus();
+    assert!(metrics.contains("# TYPE mysql_proxy_exchange_time_us histogram\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_exchange_time_us_count{stage=\"backend\"} 1\n"), "{}", metrics);
+    assert!(metrics.contains("mysql_proxy_exchange_time_us_bucket{stage=\"backend\",le=\"16384\"} 0\n"), "{}", metrics);
+}
+
+#[test]
+fn pipelined_exchanges_are_timed_in_order() {
+    let stats = Stats::new();
+    let (client, server) = (Memory::default(), Memory::default());
+    let pipe = Pipe::from_streams(client.clone(), server.clone(), Pong)
+        .with_backend_capabilities(CLIENT_PROTOCOL_41)
+        .with_exchange_timing(ExchangeTimer::new(stats.clone()));
+    let mut pipe = executor::spawn(pipe);
+
+    client.send(&Packet::new(0, b"\x03SELECT 1"));
+    client.send(&Packet::new(0, b"\x03SELECT 2"));
+    poll(&mut pipe);
+    server.send(&ok_packet(1));
+    poll(&mut pipe);
+    assert_eq!(stats.snapshot().exchanges.backend_us.count(), 1);
+    server.send(&ok_packet(1));
+    poll(&mut pipe);
+    assert_eq!(stats.snapshot().exchanges.backend_us.count(), 2);
+
+    // and the totals carry on from a snapshot
+    let restored = Stats::new();
+    restored.restore(stats.snapshot());
+    restored.restore(stats.snapshot());
+    assert_eq!(restored.snapshot().exchanges.client_write_us.count(), 4);
+}
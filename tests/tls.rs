@@ -0,0 +1,165 @@
+#![cfg(feature = "tls")]
+
+extern crate mysql_proxy;
+extern crate rustls;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use mysql_proxy::authenticator::{Authenticator, LdapAuthenticator, LdapTls, WebhookAuthenticator};
+use mysql_proxy::config::{CertIdentity, ProxyConfig, TlsConfig};
+use mysql_proxy::tls::{certificate_identity, server_config, ServerCertificates};
+
+/// The certificate in the corpus, from its hex lines
+fn client_cert() -> Vec<u8> {
+    let hex: String = include_str!("corpus/client-cert.der.hex").lines().filter(|line| !line.starts_with('#')).collect();
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn identities_come_from_the_common_name_or_subject_alternative_names() {
+    let cert = client_cert();
+    assert_eq!(certificate_identity(&cert, CertIdentity::Cn), Some("app".to_string()));
+    assert_eq!(certificate_identity(&cert, CertIdentity::SanDns), Some("app.example.com".to_string()));
+    assert_eq!(certificate_identity(&cert, CertIdentity::SanEmail), Some("app@example.com".to_string()));
+    assert_eq!(certificate_identity(&cert, CertIdentity::SanUri), Some("spiffe://example.com/app".to_string()));
+}
+
+#[test]
+fn malformed_certificates_have_no_identity() {
+    let cert = client_cert();
+    assert_eq!(certificate_identity(&cert[..cert.len() / 2], CertIdentity::Cn), None);
+    assert_eq!(certificate_identity(b"", CertIdentity::SanEmail), None);
+}
+
+/// A copy of the corpus's server certificate and key, in a directory of the test's own
+fn certificate_files(test: &str) -> (PathBuf, TlsConfig) {
+    let dir = env::temp_dir().join(format!("mysql-proxy-tls-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy("tests/corpus/server-cert.pem", dir.join("cert.pem")).unwrap();
+    fs::copy("tests/corpus/server-key.pem", dir.join("key.pem")).unwrap();
+    let config = ProxyConfig::parse(&format!("[tls]\ncert = {:?}\nkey = {:?}\n", dir.join("cert.pem"), dir.join("key.pem"))).unwrap();
+    (dir, config.tls.unwrap())
+}
+
+/// Replace `to` with `from`, modified later than the file it replaces
+fn replace(from: &str, to: &Path, later_by: u64) {
+    fs::copy(from, to).unwrap();
+    File::options().write(true).open(to).unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(later_by)).unwrap();
+}
+
+#[test]
+fn certificates_are_reloaded_when_their_files_change() {
+    let (dir, config) = certificate_files("reload");
+    let certificates = ServerCertificates::new(&config).unwrap();
+    let loaded = certificates.current();
+    assert!(!certificates.reload_if_changed().unwrap());
+    assert!(Arc::ptr_eq(&certificates.current(), &loaded));
+
+    replace("tests/corpus/renewed-server-cert.pem", &dir.join("cert.pem"), 10);
+    replace("tests/corpus/renewed-server-key.pem", &dir.join("key.pem"), 10);
+    assert!(certificates.reload_if_changed().unwrap());
+    let renewed = certificates.current();
+    assert!(!Arc::ptr_eq(&renewed, &loaded));
+
+    // a key that doesn't load keeps the configuration in use
+    fs::write(dir.join("key.pem"), "not a key").unwrap();
+    assert_eq!(certificates.reload().unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(Arc::ptr_eq(&certificates.current(), &renewed));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn watched_certificates_are_reloaded_in_the_background() {
+    let (dir, config) = certificate_files("watch");
+    let certificates = ServerCertificates::new(&config).unwrap();
+    let loaded = certificates.current();
+    certificates.watch(Duration::from_millis(10)).unwrap();
+    replace("tests/corpus/renewed-server-key.pem", &dir.join("key.pem"), 20);
+    replace("tests/corpus/renewed-server-cert.pem", &dir.join("cert.pem"), 20);
+    for _ in 0..200 {
+        if !Arc::ptr_eq(&certificates.current(), &loaded) {
+            fs::remove_dir_all(dir).unwrap();
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the renewed certificate was never loaded");
+}
+
+#[test]
+fn certificates_that_cant_be_loaded_are_reported() {
+    let (dir, config) = certificate_files("missing");
+    fs::remove_file(dir.join("key.pem")).unwrap();
+    assert_eq!(ServerCertificates::new(&config).unwrap_err().kind(), ErrorKind::NotFound);
+    fs::write(dir.join("key.pem"), "").unwrap();
+    assert_eq!(ServerCertificates::new(&config).unwrap_err().kind(), ErrorKind::InvalidData);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Accept one connection, reading `plain` bytes before starting TLS with the corpus's
+/// localhost certificate, and answer the first request read over TLS with `reply`
+fn serve_tls_once(plain: Vec<(usize, Vec<u8>)>, reply: Vec<u8>) -> (String, thread::JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let config = ProxyConfig::parse("[tls]\ncert = \"tests/corpus/localhost-cert.pem\"\nkey = \"tests/corpus/localhost-key.pem\"\n").unwrap();
+    let config = server_config(&config.tls.unwrap()).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for (len, answer) in plain {
+            stream.read_exact(&mut vec![0; len]).unwrap();
+            stream.write_all(&answer).unwrap();
+        }
+        let mut tls = rustls::StreamOwned::new(rustls::ServerConnection::new(config).unwrap(), stream);
+        let mut request = vec![0; 4096];
+        let n = tls.read(&mut request).unwrap();
+        request.truncate(n);
+        tls.write_all(&reply).unwrap();
+        tls.conn.send_close_notify();
+        tls.flush().unwrap();
+        request
+    });
+    (addr, server)
+}
+
+/// An LDAP response of type `op` with `code`
+fn ldap_response(op: u8, code: u8) -> Vec<u8> {
+    vec![0x30, 0x0c, 0x02, 0x01, 0x01, op, 0x07, 0x0a, 0x01, code, 0x04, 0x00, 0x04, 0x00]
+}
+
+#[test]
+fn authenticators_reach_their_servers_over_tls() {
+    let ca = Path::new("tests/corpus/test-ca.pem");
+    let (addr, server) = serve_tls_once(vec![], ldap_response(0x61, 0));
+    let ldap = LdapAuthenticator::new(&addr, "uid={user}").with_tls(LdapTls::Ldaps, Some(ca));
+    assert!(ldap.authenticate("alice", "secret").unwrap());
+    assert!(server.join().unwrap().ends_with(b"\x80\x06secret"));
+
+    // the StartTLS request and its response are sent in the clear, the bind after them isn't
+    let start_tls = b"\x30\x1d\x02\x01\x01\x77\x18\x80\x161.3.6.1.4.1.1466.20037".to_vec();
+    let (addr, server) = serve_tls_once(vec![(start_tls.len(), ldap_response(0x78, 0))], ldap_response(0x61, 49));
+    let ldap = LdapAuthenticator::new(&addr, "uid={user}").with_tls(LdapTls::StartTls, Some(ca));
+    assert!(!ldap.authenticate("alice", "secret").unwrap());
+    // a BindRequest with message id 2
+    assert_eq!(&server.join().unwrap()[2..5], &[0x02, 0x01, 0x02]);
+
+    let (addr, server) = serve_tls_once(vec![], b"HTTP/1.0 204 No Content\r\n\r\n".to_vec());
+    let webhook = WebhookAuthenticator::new(&format!("https://{}/mysql-login", addr)).with_ca(ca);
+    assert!(webhook.authenticate("alice", "secret").unwrap());
+    assert!(server.join().unwrap().starts_with(b"POST /mysql-login HTTP/1.0\r\n"));
+}
+
+#[test]
+fn authenticators_refuse_servers_their_ca_didnt_sign() {
+    let (addr, server) = serve_tls_once(vec![], ldap_response(0x61, 0));
+    let ldap = LdapAuthenticator::new(&addr, "uid={user}").with_tls(LdapTls::Ldaps, Some(Path::new("tests/corpus/server-cert.pem")));
+    assert_eq!(ldap.authenticate("alice", "secret").unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(server.join().is_err());
+}
@@ -0,0 +1,97 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::codec::ColumnDefinition;
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::discovery::MemberRole;
+use mysql_proxy::sidechannel::ResultSet;
+use mysql_proxy::topology::{GroupMember, ReplicaStatus, Variables};
+
+fn result_set(columns: &[&str], rows: &[&[Option<&str>]]) -> ResultSet {
+    ResultSet {
+        columns: columns.iter().map(|name| ColumnDefinition::varchar(name, 255)).collect(),
+        rows: rows.iter().map(|row| row.iter().map(|v| v.map(|v| v.as_bytes().to_vec())).collect()).collect(),
+    }
+}
+
+#[test]
+fn replica_status_is_read_by_either_name() {
+    let current = result_set(
+        &["Replica_IO_Running", "Source_Host", "Source_Port", "Replica_SQL_Running", "Seconds_Behind_Source",
+          "Last_IO_Error", "Last_SQL_Error", "Channel_Name", "Executed_Gtid_Set"],
+        &[&[Some("Yes"), Some("db1"), Some("3306"), Some("Yes"), Some("4"), Some(""), Some(""), Some(""), Some("3e11fa47:1-5")]]);
+    let status = ReplicaStatus::from_result_set(&current).unwrap();
+    assert_eq!(status, vec![ReplicaStatus {
+        channel: String::new(),
+        source_host: "db1".to_string(),
+        source_port: 3306,
+        io_running: true,
+        sql_running: true,
+        seconds_behind_source: Some(4),
+        last_io_error: None,
+        last_sql_error: None,
+        executed_gtid_set: Some("3e11fa47:1-5".to_string()),
+    }]);
+    assert!(status[0].is_replicating());
+
+    let legacy = result_set(
+        &["Master_Host", "Master_Port", "Slave_IO_Running", "Slave_SQL_Running", "Seconds_Behind_Master", "Last_SQL_Error"],
+        &[&[Some("db2"), Some("3307"), Some("Yes"), Some("No"), None, Some("Duplicate entry '1' for key 'PRIMARY'")]]);
+    let status = ReplicaStatus::from_result_set(&legacy).unwrap();
+    assert_eq!(status[0].source_host, "db2");
+    assert_eq!(status[0].source_port, 3307);
+    assert_eq!(status[0].seconds_behind_source, None);
+    assert_eq!(status[0].last_sql_error.as_ref().map(|e| &e[..]), Some("Duplicate entry '1' for key 'PRIMARY'"));
+    assert!(!status[0].is_replicating());
+
+    // a server that isn't a replica answers with no rows
+    assert_eq!(ReplicaStatus::from_result_set(&result_set(&["Source_Host"], &[])).unwrap(), vec![]);
+    assert!(ReplicaStatus::from_result_set(&result_set(&["Channel_Name"], &[&[Some("")]])).is_err());
+}
+
+#[test]
+fn variables_are_looked_up_by_name() {
+    let variables = Variables::from_result_set(&result_set(&["Variable_name", "Value"], &[
+        &[Some("read_only"), Some("ON")],
+        &[Some("super_read_only"), Some("0")],
+        &[Some("server_id"), Some("42")],
+        &[Some("gtid_mode"), Some("ON_PERMISSIVE")],
+    ])).unwrap();
+    assert_eq!(variables.len(), 4);
+    assert_eq!(variables.flag("READ_ONLY"), Some(true));
+    assert_eq!(variables.flag("super_read_only"), Some(false));
+    assert_eq!(variables.flag("gtid_mode"), None);
+    assert_eq!(variables.get("gtid_mode"), Some("ON_PERMISSIVE"));
+    assert_eq!(variables.number("server_id"), Some(42));
+    assert_eq!(variables.number("port"), None);
+
+    assert!(Variables::from_result_set(&result_set(&["Value"], &[&[Some("ON")]])).is_err());
+}
+
+#[test]
+fn group_members_and_discovery_configs() {
+    let members = GroupMember::from_result_set(&result_set(
+        &["MEMBER_ID", "MEMBER_HOST", "MEMBER_PORT", "MEMBER_STATE", "MEMBER_ROLE"],
+        &[&[Some("a"), Some("db1"), Some("3306"), Some("ONLINE"), Some("PRIMARY")],
+          &[Some("b"), Some("db2"), Some("3306"), Some("RECOVERING"), Some("SECONDARY")],
+          &[Some("c"), Some("db3"), None, Some("ONLINE"), Some("SECONDARY")]])).unwrap();
+    assert_eq!(members.len(), 3);
+    assert!(members[0].is_online() && members[0].is_primary());
+    assert!(!members[1].is_online());
+    assert_eq!((&members[2].host[..], members[2].port), ("db3", 3306));
+
+    let config = ProxyConfig::parse(r#"
+        [groups.reads.discovery]
+        group_replication = { seeds = ["db1:3306"], user = "monitor", password = "secret", role = "secondary" }
+    "#).unwrap();
+    let group = config.groups["reads"].discovery.as_ref().and_then(|d| d.group_replication.as_ref()).unwrap();
+    assert_eq!(group.role, MemberRole::Secondary);
+    assert_eq!(group.timeout_ms, 2000);
+    assert_eq!(config.groups["reads"].discovery.as_ref().unwrap().validate(), Ok(()));
+
+    let config = ProxyConfig::parse(r#"
+        [groups.reads.discovery]
+        group_replication = { seeds = [], user = "monitor", password = "secret" }
+    "#).unwrap();
+    assert_eq!(config.groups["reads"].discovery.as_ref().unwrap().validate(),
+               Err("group_replication needs at least one seed".to_string()));
+}
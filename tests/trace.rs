@@ -0,0 +1,138 @@
+extern crate mysql_proxy;
+
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+use mysql_proxy::{Action, ConnectionPhase, Packet, PacketHandler};
+use mysql_proxy::anomaly::{Anomaly, AnomalyKind, Verdict};
+use mysql_proxy::dump::Direction;
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::trace::{PacketKind, Trace, TraceHandler, TraceRecord, TraceWriter, TracedAction};
+
+/// Forwards everything but answers pings itself
+struct AnswerPing;
+
+impl PacketHandler for AnswerPing {
+
+    fn handle_request(&mut self, p: &Packet) -> Action {
+        match p.payload().first() {
+            Some(&0x0e) => Action::Respond(vec![Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0])]),
+            _ => Action::Forward,
+        }
+    }
+
+    fn handle_response(&mut self, _: &Packet) -> Action {
+        Action::Forward
+    }
+
+    fn handle_anomaly(&mut self, _: &Anomaly) -> Verdict {
+        Verdict::Kill
+    }
+}
+
+#[derive(Clone,Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn packets(trace: &Trace) -> Vec<(Direction, u8, u32, PacketKind, Option<u8>, TracedAction)> {
+    trace.records.iter().filter_map(|r| match *r {
+        TraceRecord::Packet(ref p) => Some((p.direction, p.seq, p.length, p.kind, p.command, p.action)),
+        _ => None,
+    }).collect()
+}
+
+fn traced_session() -> Vec<u8> {
+    let buf = Shared::default();
+    let writer = TraceWriter::new(Box::new(buf.clone()), "app@127.0.0.1:50000").unwrap();
+    let mut handler = TraceHandler::new(writer, AnswerPing).with_capabilities(CLIENT_PROTOCOL_41);
+    handler.handle_request(&Packet::new(0, b"\x03SELECT secret FROM t"));
+    handler.handle_response(&Packet::new(1, &[0x01]));
+    handler.handle_response(&Packet::new(2, b"\x03def\x00\x01t\x01t\x06secret\x06secret\x0c\x21\x00\x0b\x00\x00\x00\x03\x00\x00\x00\x00\x00"));
+    handler.handle_response(&Packet::new(3, &[0xfe, 0, 0, 2, 0]));
+    handler.handle_response(&Packet::new(4, b"\x05hello"));
+    handler.handle_response(&Packet::new(5, b"\x05hello"));
+    handler.handle_response(&Packet::new(6, &[0xfe, 0, 0, 2, 0]));
+    handler.handle_request(&Packet::new(0, &[0x0e]));
+    handler.handle_response(&Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0]));
+    let verdict = handler.handle_anomaly(&Anomaly::UnexpectedPacket { from: Direction::Response, phase: ConnectionPhase::Command });
+    assert_eq!(verdict, Verdict::Kill);
+    drop(handler);
+    let bytes = buf.0.lock().unwrap();
+    bytes.clone()
+}
+
+#[test]
+fn trace_the_shape_of_a_session() {
+    let bytes = traced_session();
+    let trace = Trace::read(&bytes[..]).unwrap();
+    assert_eq!(trace.label, "app@127.0.0.1:50000");
+    assert!(trace.started_ms > 0);
+    assert_eq!(packets(&trace), vec![
+        (Direction::Request, 0, 21, PacketKind::Command, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 1, 1, PacketKind::Data, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 2, 36, PacketKind::Data, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 3, 5, PacketKind::Eof, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 4, 6, PacketKind::Data, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 5, 6, PacketKind::Data, Some(0x03), TracedAction::Forward),
+        (Direction::Response, 6, 5, PacketKind::Eof, Some(0x03), TracedAction::Forward),
+        (Direction::Request, 0, 1, PacketKind::Command, Some(0x0e), TracedAction::Respond),
+        // the ping was answered by the proxy, so the backend had nothing to answer
+        (Direction::Response, 1, 7, PacketKind::Unsolicited, None, TracedAction::Forward),
+    ]);
+    match trace.records.last() {
+        Some(&TraceRecord::Anomaly { kind, verdict, .. }) => {
+            assert_eq!(kind, AnomalyKind::UnexpectedPacket);
+            assert_eq!(verdict, Verdict::Kill);
+        },
+        other => panic!("Expected an anomaly, got {:?}", other),
+    }
+}
+
+#[test]
+fn payloads_are_only_kept_as_hashes() {
+    let bytes = traced_session();
+    assert!(!bytes.windows(6).any(|w| w == b"secret"));
+    let trace = Trace::read(&bytes[..]).unwrap();
+    let hashes: Vec<[u8; 8]> = trace.records.iter().filter_map(|r| match *r {
+        TraceRecord::Packet(ref p) => Some(p.hash),
+        _ => None,
+    }).collect();
+    // the same row twice, and the same EOF twice
+    assert_eq!(hashes[4], hashes[5]);
+    assert_eq!(hashes[3], hashes[6]);
+    assert_ne!(hashes[0], hashes[1]);
+}
+
+#[test]
+fn a_trace_cut_short_ends_at_its_last_whole_record() {
+    let bytes = traced_session();
+    let whole = Trace::read(&bytes[..]).unwrap();
+    let cut = Trace::read(&bytes[..bytes.len() - 3]).unwrap();
+    assert_eq!(cut.records.len(), whole.records.len() - 1);
+    assert_eq!(cut.records[..], whole.records[..whole.records.len() - 1]);
+}
+
+#[test]
+fn other_files_are_not_traces() {
+    let e = Trace::read(&b"{\"timestamp_ms\": 1}\n"[..]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn records_print_as_a_timeline() {
+    let trace = Trace::read(&traced_session()[..]).unwrap();
+    let line = trace.records[0].to_string();
+    assert!(line.contains(" us -> Command seq=0 len=21 Command COM_QUERY Forward "), "{}", line);
+    let line = trace.records.last().unwrap().to_string();
+    assert!(line.ends_with(" us !! unexpected_packet Kill"), "{}", line);
+}
@@ -0,0 +1,80 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::{Async, Poll};
+use futures::executor::{self, Notify};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use mysql_proxy::{Packet, Pipe};
+use mysql_proxy::testing::Forward;
+
+/// One end of an in-memory connection: what the pipe reads, and what it has written
+#[derive(Clone,Default)]
+struct Memory {
+    incoming: Rc<RefCell<VecDeque<u8>>>,
+    outgoing: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.borrow_mut();
+        if incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(incoming.len());
+        for (b, byte) in buf.iter_mut().zip(incoming.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Memory {}
+
+impl AsyncWrite for Memory {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct Ignore;
+
+impl Notify for Ignore {
+    fn notify(&self, _: usize) {}
+}
+
+#[test]
+fn relay_over_any_stream() {
+    let (client, server) = (Memory::default(), Memory::default());
+    let mut pipe = executor::spawn(Pipe::from_streams(client.clone(), server.clone(), Forward));
+    let notify = Arc::new(Ignore);
+
+    let query = Packet::new(0, b"\x03SELECT 1");
+    client.incoming.borrow_mut().extend(query.bytes.iter());
+    assert_eq!(pipe.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    assert_eq!(*server.outgoing.borrow(), query.bytes);
+
+    let ok = Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0]);
+    server.incoming.borrow_mut().extend(ok.bytes.iter());
+    assert_eq!(pipe.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    assert_eq!(*client.outgoing.borrow(), ok.bytes);
+}
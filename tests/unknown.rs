@@ -0,0 +1,58 @@
+extern crate mysql_proxy;
+
+use std::collections::HashMap;
+
+use mysql_proxy::{Action, Packet, PacketType};
+use mysql_proxy::testing::{Forward, HandlerTester, Step};
+use mysql_proxy::unknown::{UnknownCommandHandler, ER_UNKNOWN_COM_ERROR};
+
+#[test]
+fn every_command_byte_has_a_packet_type() {
+    for byte in 0..=255_u8 {
+        let packet_type = Packet::new(0, &[byte]).packet_type().unwrap();
+        assert_eq!(packet_type.byte(), byte);
+        assert_eq!(PacketType::from_u8(byte), packet_type);
+    }
+    assert_eq!(Packet::new(0, &[0x03]).packet_type().unwrap(), PacketType::ComQuery);
+    assert_eq!(Packet::new(0, &[0xfa]).packet_type().unwrap(), PacketType::ComStmtBulkExecute);
+    assert_eq!(Packet::new(0, &[0x20]).packet_type().unwrap(), PacketType::ComClone);
+    assert_eq!(Packet::new(0, &[0x40]).packet_type().unwrap(), PacketType::Unknown(0x40));
+    assert!(Packet::new(0, &[]).packet_type().is_err());
+}
+
+#[test]
+fn packet_types_are_named_as_mysql_names_them() {
+    assert_eq!(PacketType::ComQuery.as_str(), "COM_QUERY");
+    assert_eq!(PacketType::ComStmtFetch.to_string(), "COM_STMT_FETCH");
+    assert_eq!(PacketType::ComSetOption.to_string(), "COM_SET_OPTION");
+    assert_eq!(PacketType::ComBinlogDumpGtid.to_string(), "COM_BINLOG_DUMP_GTID");
+    assert_eq!(PacketType::Unknown(0x40).as_str(), "COM_UNKNOWN");
+    assert_eq!(PacketType::Unknown(0x40).to_string(), "COM_UNKNOWN(0x40)");
+
+    let counts = [PacketType::ComQuery, PacketType::ComPing, PacketType::ComQuery].iter()
+        .fold(HashMap::new(), |mut counts, t| {
+            *counts.entry(*t).or_insert(0) += 1;
+            counts
+        });
+    assert_eq!(counts[&PacketType::ComQuery], 2);
+}
+
+#[test]
+fn unknown_commands_are_forwarded_unless_rejected() {
+    let command = Packet::new(0, &[0x40, 1, 2, 3]);
+    let mut tester = HandlerTester::new(Forward);
+    assert_eq!(tester.run(vec![Step::Request(Packet::new(0, &[0x40, 1, 2, 3]))]), vec![Action::Forward]);
+    assert_eq!(tester.to_server(), vec![command]);
+
+    let mut tester = HandlerTester::new(UnknownCommandHandler::new(Forward));
+    let actions = tester.run(vec![
+        Step::Request(Packet::new(0, &[0x40, 1, 2, 3])),
+        Step::Request(Packet::new(0, b"\x03SELECT 1")),
+    ]);
+    match actions[0] {
+        Action::Error { code, .. } => assert_eq!(code, ER_UNKNOWN_COM_ERROR),
+        ref other => panic!("unexpected action {:?}", other),
+    }
+    assert_eq!(actions[1], Action::Forward);
+    assert_eq!(tester.to_server(), vec![Packet::new(0, b"\x03SELECT 1")]);
+}
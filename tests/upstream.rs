@@ -0,0 +1,151 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::read_exact;
+
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::upstream::{base64, connect_through, UpstreamKind, UpstreamProxy};
+
+fn upstream_config(kind: UpstreamKind, addr: &str, credentials: Option<(&str, &str)>) -> UpstreamProxy {
+    let mut config = format!("[upstream]\nkind = \"{}\"\naddr = \"{}\"\n", match kind {
+        UpstreamKind::Socks5 => "socks5",
+        UpstreamKind::Http => "http",
+        UpstreamKind::Ssh => "ssh",
+    }, addr);
+    if let Some((username, password)) = credentials {
+        config.push_str(&format!("username = \"{}\"\npassword = \"{}\"\n", username, password));
+    }
+    ProxyConfig::parse(&config).unwrap().upstream.unwrap()
+}
+
+/// Accept one connection on a thread, playing the upstream proxy with `serve`, which returns
+/// what it read
+fn fake_proxy<F>(serve: F) -> (String, thread::JoinHandle<Vec<u8>>)
+    where F: FnOnce(&mut std::net::TcpStream) -> Vec<u8> + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (addr, thread::spawn(move || serve(&mut listener.accept().unwrap().0)))
+}
+
+fn read_n(stream: &mut std::net::TcpStream, n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+/// Connect to `backend` through `upstream`, and read what the backend says first
+fn greeting_through(upstream: &UpstreamProxy, backend: &str) -> std::io::Result<Vec<u8>> {
+    let mut core = Core::new().unwrap();
+    let backend: BackendAddr = backend.parse().unwrap();
+    let connect = connect_through(Some(upstream), &backend, &core.handle())
+        .and_then(|stream| read_exact(stream, vec![0; 5]))
+        .map(|(_, greeting)| greeting);
+    core.run(connect)
+}
+
+#[test]
+fn socks5_proxies_are_asked_for_a_tunnel() {
+    let (addr, proxy) = fake_proxy(|stream| {
+        let mut seen = read_n(stream, 4);
+        stream.write_all(&[0x05, 0x02]).unwrap();
+        seen.extend(read_n(stream, 2 + 3 + 1 + 6));
+        stream.write_all(&[0x01, 0x00]).unwrap();
+        seen.extend(read_n(stream, 5 + 11 + 2));
+        // bound to an IPv4 address, and then the backend speaks
+        stream.write_all(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x0c, 0xea]).unwrap();
+        stream.write_all(b"hello").unwrap();
+        seen
+    });
+    let upstream = upstream_config(UpstreamKind::Socks5, &addr, Some(("app", "secret")));
+    assert_eq!(greeting_through(&upstream, "db.internal:3306").unwrap(), b"hello");
+
+    let mut expected = vec![0x05, 0x02, 0x00, 0x02];
+    expected.extend_from_slice(b"\x01\x03app\x06secret");
+    // the backend's name is resolved by the proxy
+    expected.extend_from_slice(b"\x05\x01\x00\x03\x0bdb.internal\x0c\xea");
+    assert_eq!(proxy.join().unwrap(), expected);
+}
+
+#[test]
+fn socks5_failures_are_reported() {
+    let (addr, proxy) = fake_proxy(|stream| {
+        let seen = read_n(stream, 3);
+        stream.write_all(&[0x05, 0x00]).unwrap();
+        read_n(stream, 10);
+        stream.write_all(&[0x05, 0x05, 0x00, 0x01]).unwrap();
+        seen
+    });
+    let e = greeting_through(&upstream_config(UpstreamKind::Socks5, &addr, None), "10.0.0.1:3306").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+    assert_eq!(e.to_string(), "SOCKS5 proxy failed to connect: connection refused");
+    assert_eq!(proxy.join().unwrap(), vec![0x05, 0x01, 0x00]);
+
+    // a proxy that wants credentials the proxy doesn't have
+    let (addr, proxy) = fake_proxy(|stream| {
+        let seen = read_n(stream, 3);
+        stream.write_all(&[0x05, 0xff]).unwrap();
+        seen
+    });
+    let e = greeting_through(&upstream_config(UpstreamKind::Socks5, &addr, None), "10.0.0.1:3306").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    proxy.join().unwrap();
+}
+
+/// Read an HTTP request's headers
+fn read_request(stream: &mut std::net::TcpStream) -> Vec<u8> {
+    let mut request = vec![];
+    while !request.ends_with(b"\r\n\r\n") {
+        request.extend(read_n(stream, 1));
+    }
+    request
+}
+
+#[test]
+fn http_proxies_are_sent_connect_requests() {
+    let (addr, proxy) = fake_proxy(|stream| {
+        let request = read_request(stream);
+        // the backend's first bytes arrive with the response
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello").unwrap();
+        request
+    });
+    let upstream = upstream_config(UpstreamKind::Http, &addr, Some(("app", "secret")));
+    assert_eq!(greeting_through(&upstream, "db.internal:3306").unwrap(), b"hello");
+    let request = String::from_utf8(proxy.join().unwrap()).unwrap();
+    assert_eq!(request, "CONNECT db.internal:3306 HTTP/1.1\r\nHost: db.internal:3306\r\nProxy-Authorization: Basic YXBwOnNlY3JldA==\r\n\r\n");
+
+    let (addr, proxy) = fake_proxy(|stream| {
+        let request = read_request(stream);
+        stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n").unwrap();
+        request
+    });
+    let e = greeting_through(&upstream_config(UpstreamKind::Http, &addr, None), "db.internal:3306").unwrap_err();
+    assert_eq!(e.to_string(), "HTTP proxy refused to connect: HTTP/1.1 407 Proxy Authentication Required");
+    assert!(!String::from_utf8(proxy.join().unwrap()).unwrap().contains("Proxy-Authorization"));
+}
+
+#[test]
+fn base64_pads_to_whole_groups() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foob"), "Zm9vYg==");
+    assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+}
+
+#[test]
+#[cfg(not(feature = "ssh"))]
+fn ssh_upstreams_need_the_ssh_feature() {
+    let e = greeting_through(&upstream_config(UpstreamKind::Ssh, "jump.internal:22", None), "db.internal:3306").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+}
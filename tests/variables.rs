@@ -0,0 +1,51 @@
+extern crate mysql_proxy;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::codec::{ok_packet, text_result_set, ColumnDefinition};
+use mysql_proxy::protocol::CLIENT_PROTOCOL_41;
+use mysql_proxy::testing::{Forward, HandlerTester};
+use mysql_proxy::variables::{VariablesHandler, ER_UNKNOWN_SYSTEM_VARIABLE, PROXY_VERSION};
+
+fn tester() -> HandlerTester<VariablesHandler<Forward>> {
+    let handler = VariablesHandler::new(Forward)
+        .with_variable("proxy_backend", "10.0.0.1:3306")
+        .with_capabilities(CLIENT_PROTOCOL_41);
+    HandlerTester::new(handler).with_backend_capabilities(CLIENT_PROTOCOL_41)
+}
+
+/// The result set with a row of `values` in columns named `columns`
+fn result(columns: &[&str], values: &[&str]) -> Action {
+    let columns: Vec<ColumnDefinition> = columns.iter().zip(values)
+        .map(|(name, value)| ColumnDefinition::varchar(name, value.len() as u32 * 4))
+        .collect();
+    let row = values.iter().map(|value| Some(value.as_bytes().to_vec())).collect();
+    Action::Respond(text_result_set(&columns, &[row], CLIENT_PROTOCOL_41))
+}
+
+#[test]
+fn proxy_variables_are_answered_by_the_proxy() {
+    let mut tester = tester();
+    assert_eq!(tester.request(Packet::com_query("SELECT @@proxy_version")), result(&["@@proxy_version"], &[PROXY_VERSION]));
+    assert_eq!(tester.request(Packet::com_query("select @@PROXY_VERSION as v, @@proxy_backend `backend`;")),
+               result(&["v", "backend"], &[PROXY_VERSION, "10.0.0.1:3306"]));
+
+    match tester.request(Packet::com_query("SELECT @@proxy_nonsense")) {
+        Action::Error { code, msg, .. } => {
+            assert_eq!(code, ER_UNKNOWN_SYSTEM_VARIABLE);
+            assert_eq!(msg, "Unknown system variable 'proxy_nonsense'");
+        },
+        action => panic!("{:?}", action),
+    }
+}
+
+#[test]
+fn anything_else_goes_to_the_backend() {
+    let mut tester = tester();
+    for sql in &["SELECT @@version", "SELECT @@proxy_version, 1", "SELECT @@proxy_version + 1", "SELECTED @@proxy_version", "SET @@proxy_version = 1"] {
+        assert_eq!(tester.request(Packet::com_query(sql)), Action::Forward, "{}", sql);
+        tester.response(ok_packet(1));
+    }
+    // nor is anything answered during the handshake
+    let mut handler = VariablesHandler::new(Forward);
+    assert_eq!(handler.handle_request(&Packet::new(1, b"\x03SELECT @@proxy_version")), Action::Forward);
+}
@@ -0,0 +1,176 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use tokio_core::net::TcpListener as ProxyListener;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::Packet;
+use mysql_proxy::auth::{ProxyAuth, Session};
+use mysql_proxy::codec::{HandshakeResponse, HandshakeV10};
+use mysql_proxy::config::ProxyConfig;
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::protocol::*;
+use mysql_proxy::sidechannel::read_packet;
+use mysql_proxy::users::{UserMap, UserMapping};
+use mysql_proxy::warmpool::{WarmKey, WarmPool, WarmPoolConfig};
+
+/// What the backend saw: its logins, and the schemas it was switched to
+#[derive(Debug,Default)]
+struct Seen {
+    logins: usize,
+    init_db: Vec<String>,
+}
+
+/// A backend accepting any login, and COM_INIT_DB for any schema but `nope`
+fn backend() -> (SocketAddr, Arc<Mutex<Seen>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Seen::default()));
+    let recorded = seen.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let recorded = recorded.clone();
+            thread::spawn(move || serve(stream.unwrap(), &recorded));
+        }
+    });
+    (addr, seen)
+}
+
+fn serve(mut stream: TcpStream, seen: &Mutex<Seen>) {
+    let greeting = HandshakeV10 {
+        server_version: "8.0.36".to_string(),
+        connection_id: 7,
+        capability_flags: CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH | CLIENT_CONNECT_WITH_DB,
+        character_set: 0x21,
+        status_flags: 0x0002,
+        auth_plugin_data: b"abcdefghijklmnopqrst".to_vec(),
+        auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+    };
+    stream.write_all(&greeting.to_packet(0).bytes).unwrap();
+    read_packet(&mut stream).unwrap();
+    stream.write_all(&Packet::new(2, &[0x00, 0, 0, 2, 0, 0, 0]).bytes).unwrap();
+    seen.lock().unwrap().logins += 1;
+    while let Ok(command) = read_packet(&mut stream) {
+        if command.payload().first() != Some(&0x02) {
+            continue;
+        }
+        let schema = String::from_utf8_lossy(&command.payload()[1..]).into_owned();
+        let response = if schema == "nope" {
+            Packet::new(1, b"\xff\x19\x04#42000Unknown database 'nope'")
+        } else {
+            Packet::new(1, &[0x00, 0, 0, 2, 0, 0, 0])
+        };
+        seen.lock().unwrap().init_db.push(schema);
+        stream.write_all(&response.bytes).unwrap();
+    }
+}
+
+const CAPABILITIES: u32 = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH | CLIENT_CONNECT_WITH_DB;
+
+/// Log alice in through the proxy, asking for `database`, and return the session
+fn session(core: &mut Core, auth: &ProxyAuth, backend: SocketAddr, database: &str) -> io::Result<Session> {
+    let handle = core.handle();
+    let listener = ProxyListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let database = database.to_string();
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let greeting = HandshakeV10::parse(&read_packet(&mut stream).unwrap()).unwrap();
+        let response = HandshakeResponse {
+            capability_flags: CAPABILITIES,
+            max_packet_size: 1 << 24,
+            character_set: 0x21,
+            username: "alice".to_string(),
+            auth_response: native_password_auth("secret", &greeting.auth_plugin_data),
+            database: Some(database),
+            auth_plugin_name: Some(NATIVE_PASSWORD_PLUGIN.to_string()),
+            connect_attrs: None,
+        };
+        stream.write_all(&response.to_packet(1).bytes).unwrap();
+        let _ = read_packet(&mut stream);
+    });
+    let (socket, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e)).unwrap().0.unwrap();
+    let backend = BackendAddr::new("127.0.0.1", backend.port());
+    let result = core.run(auth.establish(socket, move |_| Some(backend), &handle));
+    client.join().unwrap();
+    result.map(|(_, _, session)| session)
+}
+
+/// Turn the reactor until `done`, for at most 5 seconds
+fn wait_until<F: Fn() -> bool>(core: &mut Core, done: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() && Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+    assert!(done(), "timed out");
+}
+
+fn auth(pool: &WarmPool) -> ProxyAuth {
+    let alice = UserMapping {
+        user: "alice".to_string(),
+        password: Some("secret".to_string()),
+        backend_user: "app".to_string(),
+        default_group: "main".to_string(),
+        ..UserMapping::default()
+    };
+    ProxyAuth::new(Arc::new(UserMap::new(vec![alice]))).with_warm_pool(pool.clone())
+}
+
+#[test]
+fn sessions_take_warm_connections() {
+    let (backend_addr, seen) = backend();
+    let pool = WarmPool::new(&WarmPoolConfig { size: 2, max_age_secs: 1 });
+    let auth = auth(&pool);
+    let mut core = Core::new().unwrap();
+    let key = WarmKey {
+        reactor: core.id(),
+        backend: BackendAddr::new("127.0.0.1", backend_addr.port()),
+        user: "app".to_string(),
+        password: String::new(),
+        capability_flags: CAPABILITIES,
+        character_set: 0x21,
+    };
+
+    // the first session logs in itself, and the pool warms up behind it
+    let cold = session(&mut core, &auth, backend_addr, "shop").unwrap();
+    assert_eq!((pool.hits(), pool.misses()), (0, 1));
+    wait_until(&mut core, || pool.idle(&key) == 2);
+    assert_eq!(seen.lock().unwrap().logins, 3);
+
+    // the next takes a warm connection, switched to its schema, which is replaced
+    let warm = session(&mut core, &auth, backend_addr, "shop").unwrap();
+    assert_eq!((pool.hits(), pool.misses()), (1, 1));
+    assert_eq!(warm.backend_capabilities, cold.backend_capabilities);
+    wait_until(&mut core, || pool.idle(&key) == 2);
+    assert_eq!(seen.lock().unwrap().init_db, vec!["shop".to_string()]);
+    assert_eq!(seen.lock().unwrap().logins, 4);
+
+    // a schema the backend doesn't have fails the login, as it would without the pool
+    let error = session(&mut core, &auth, backend_addr, "nope").unwrap_err();
+    assert_eq!(error.to_string(), "Backend login failed: Unknown database 'nope'");
+
+    // and connections idle for too long are replaced
+    wait_until(&mut core, || pool.idle(&key) == 2);
+    let logins = seen.lock().unwrap().logins;
+    wait_until(&mut core, || seen.lock().unwrap().logins >= logins + 2);
+    wait_until(&mut core, || pool.idle(&key) == 2);
+}
+
+#[test]
+fn warm_pools_are_configured() {
+    let config = ProxyConfig::parse(r#"
+        [warm_pool]
+        size = 4
+    "#).unwrap();
+    assert_eq!(config.warm_pool, Some(WarmPoolConfig { size: 4, max_age_secs: 300 }));
+    assert_eq!(WarmPoolConfig { size: 0, max_age_secs: 300 }.validate(), Err("size must be between 1 and 100".to_string()));
+}
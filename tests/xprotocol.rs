@@ -0,0 +1,92 @@
+extern crate futures;
+extern crate mysql_proxy;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+use futures::Future;
+use futures::sync::oneshot;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+
+use mysql_proxy::{Action, Packet, PacketHandler};
+use mysql_proxy::connect::BackendAddr;
+use mysql_proxy::testing::Forward;
+use mysql_proxy::xprotocol::{self, is_x_protocol, wrong_port_error, XProtocolGuard, ER_HANDSHAKE_ERROR};
+
+/// CON_CAPABILITIES_GET, the first message MySQL Shell sends
+fn capabilities_get() -> Packet {
+    Packet { bytes: vec![0x01, 0x00, 0x00, 0x00, 0x01] }
+}
+
+#[test]
+fn x_protocol_clients_are_recognised_by_their_first_message() {
+    assert!(is_x_protocol(&capabilities_get()));
+    // SESS_AUTHENTICATE_START with a payload
+    assert!(is_x_protocol(&Packet { bytes: vec![0x05, 0x00, 0x00, 0x00, 0x04, 0x0a, 0x02, 0x4f, 0x4b] }));
+    // a handshake response has sequence id 1
+    assert!(!is_x_protocol(&Packet::new(1, &[0x01, 0x00, 0x00, 0x00])));
+    assert!(!is_x_protocol(&Packet { bytes: vec![0x01, 0x00, 0x00, 0x00, 0x0c] }));
+    assert!(!is_x_protocol(&Packet { bytes: vec![0x00, 0x00, 0x00, 0x00] }));
+}
+
+#[test]
+fn x_protocol_clients_on_the_classic_port_are_told_so() {
+    let error = wrong_port_error();
+    let msg = "This port only accepts the classic MySQL protocol, use the proxy's X Protocol port instead";
+    // the length covers the type and the message, and the type is Mysqlx.Error
+    assert_eq!(&error.bytes[..4], &(error.bytes.len() as u32 - 4).to_le_bytes());
+    assert_eq!(error.bytes[4], 0x01);
+    let mut expected = vec![0x08, 0x01, 0x10];
+    expected.extend_from_slice(&[(ER_HANDSHAKE_ERROR as u8) | 0x80, (ER_HANDSHAKE_ERROR >> 7) as u8]);
+    expected.extend_from_slice(&[0x1a, msg.len() as u8]);
+    expected.extend_from_slice(msg.as_bytes());
+    expected.extend_from_slice(b"\x22\x0508S01");
+    assert_eq!(&error.bytes[5..], &expected[..]);
+
+    let mut guard = XProtocolGuard::new(Forward);
+    assert_eq!(guard.handle_request(&capabilities_get()), Action::Respond(vec![wrong_port_error()]));
+
+    // only the client's first packet is looked at
+    let mut guard = XProtocolGuard::new(Forward);
+    assert_eq!(guard.handle_request(&Packet::new(1, &[0x01, 0x00, 0x00, 0x00])), Action::Forward);
+    assert_eq!(guard.handle_request(&capabilities_get()), Action::Forward);
+}
+
+#[test]
+fn x_protocol_traffic_is_relayed_to_the_first_backend_that_accepts() {
+    // nothing listens on the first backend
+    let gone = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let gone_port = gone.local_addr().unwrap().port();
+    drop(gone);
+    let backend = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = backend.accept().unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).unwrap();
+        stream.write_all(b"echo: ").unwrap();
+        stream.write_all(&received).unwrap();
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let backends = vec![BackendAddr::new("127.0.0.1", gone_port), BackendAddr::new("127.0.0.1", backend_port)];
+    handle.spawn(xprotocol::serve(listener, backends, &handle).map_err(|e| panic!("{}", e)));
+
+    let (done, received) = oneshot::channel();
+    thread::spawn(move || {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&capabilities_get().bytes).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).unwrap();
+        done.send(reply).unwrap();
+    });
+    let reply = core.run(received).unwrap();
+    assert_eq!(reply, [&b"echo: "[..], &capabilities_get().bytes].concat());
+}